@@ -0,0 +1,79 @@
+// The classic Lox benchmarks, adapted to the subset of Lox that clox-rs
+// implements, with compiling and running measured apart:
+//
+//   cargo bench
+//   cargo bench -- run/stack/fib
+//
+// `compile` is the source to a chunk, with the options that `clox` compiles
+// with, and `run` is the chunk on each of the backends, without compiling or
+// checking it again. What the programs print goes nowhere.
+
+use std::hint::black_box;
+
+use clox::{
+    bytecode,
+    compiler::{CompileOptions, Compiler},
+    io::Sink,
+    object::Heap,
+    register::Backend,
+    vm::VM,
+};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+
+const PROGRAMS: [(&str, &str); 4] = [
+    ("fib", include_str!("lox/fib.lox")),
+    ("binary_trees", include_str!("lox/binary_trees.lox")),
+    ("zoo", include_str!("lox/zoo.lox")),
+    ("string_equality", include_str!("lox/string_equality.lox")),
+];
+
+fn compile(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compile");
+    for (name, source) in PROGRAMS {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut heap = Heap::new();
+                Compiler::compile(black_box(source), &mut heap, CompileOptions::default())
+                    .expect("no compile errors")
+            })
+        });
+    }
+    group.finish();
+}
+
+fn run(c: &mut Criterion) {
+    for (backend, group) in [
+        (Backend::Stack, "run/stack"),
+        (Backend::Register, "run/register"),
+    ] {
+        let mut group = c.benchmark_group(group);
+        for (name, source) in PROGRAMS {
+            group.bench_function(name, |b| {
+                b.iter_batched(
+                    // a VM of its own for every run, with the chunk on its heap
+                    || {
+                        let mut vm = VM::with_output(Sink);
+                        vm.set_backend(backend);
+                        let chunk =
+                            Compiler::compile(source, vm.heap_mut(), CompileOptions::default())
+                                .expect("no compile errors");
+                        // the checks of run_chunk, which aren't timed
+                        assert!(vm.heap_mut().owns_constants(&chunk));
+                        bytecode::verify_script(&chunk).expect("valid bytecode");
+                        (vm, chunk)
+                    },
+                    |(mut vm, chunk)| {
+                        // SAFETY: checked as it was set up
+                        unsafe { vm.run_chunk_unchecked(chunk) }.expect("no runtime errors");
+                        vm
+                    },
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compile, run);
+criterion_main!(benches);
@@ -0,0 +1,83 @@
+/* The C API of clox, from src/capi.rs, for embedding its VM. Build the
+ * library with:
+ *
+ *   cargo rustc --lib --release --features capi --crate-type cdylib
+ *
+ * Strings and other objects are handles to the heap of the VM that made
+ * them, which stay valid until the VM is freed. Strings are UTF-8. */
+
+#ifndef CLOX_H
+#define CLOX_H
+
+#include <stdbool.h>
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct CloxVM CloxVM;
+
+typedef enum CloxKind {
+  CLOX_NIL,
+  CLOX_BOOL,
+  CLOX_NUMBER,
+  CLOX_STRING,
+  /* any other object, like a function or a decimal */
+  CLOX_OBJECT,
+} CloxKind;
+
+/* A value of Lox, where only the field for its kind means anything. */
+typedef struct CloxValue {
+  CloxKind kind;
+  bool boolean;
+  double number;
+  const void *object;
+} CloxValue;
+
+/* Called with each line that a script prints, with its newline, which isn't
+ * NUL-terminated. */
+typedef void (*CloxPrint)(const char *chars, size_t length, void *userdata);
+
+/* A function that scripts can call. It writes what it returns to `result`,
+ * which starts out as nil, and returns NULL, or else the message of a
+ * runtime error, which is copied. */
+typedef const char *(*CloxNative)(const CloxValue *args, int arg_count,
+                                  CloxValue *result, void *userdata);
+
+/* A VM that prints to stdout, and reports errors to stderr. */
+CloxVM *clox_vm_new(void);
+/* Frees the VM, along with every string and object that it made. */
+void clox_vm_free(CloxVM *vm);
+
+/* Hands what scripts print to `print`, or to stdout again if it is NULL. */
+void clox_set_print(CloxVM *vm, CloxPrint print, void *userdata);
+
+/* Runs `source`, returning the exit code that `clox` would have: 0 if it
+ * ran, 65 for a compile error and 70 for a runtime error. */
+int clox_interpret(CloxVM *vm, const char *source);
+/* The message of the runtime error that stopped the last script, or NULL,
+ * which stays valid until the next script runs. */
+const char *clox_last_error(const CloxVM *vm);
+
+/* Defines the global `name` as a function with `arity` arguments. */
+void clox_register_native(CloxVM *vm, const char *name, int arity,
+                          CloxNative function, void *userdata);
+
+/* Writes the value of the global `name` to `value`, returning whether it
+ * is defined. */
+bool clox_get_global(const CloxVM *vm, const char *name, CloxValue *value);
+/* Defines the global `name` as `value`, returning whether it could. */
+bool clox_set_global(CloxVM *vm, const char *name, CloxValue value);
+
+/* A string of `length` bytes at `chars`, copied to the heap of the VM. */
+CloxValue clox_string(CloxVM *vm, const char *chars, size_t length);
+/* The bytes of the string `value`, which aren't NUL-terminated, with their
+ * length written to `length`, or NULL if it isn't a string. */
+const char *clox_string_chars(CloxValue value, size_t *length);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
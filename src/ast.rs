@@ -0,0 +1,262 @@
+// The syntax tree that the parser produces and the compiler generates
+// bytecode from.
+//
+// Nodes keep the tokens that their bytecode is attributed to (operators,
+// keywords, closing delimiters), so that runtime errors and diagnostics can
+// point at the same places in the source as they would in a single pass
+// compiler.
+
+use alloc::{borrow::Cow, boxed::Box, vec::Vec};
+use core::mem;
+
+use crate::scanner::{Token, TokenKind};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Literal {
+    Nil,
+    True,
+    False,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr<'src> {
+    Literal {
+        value: Literal,
+        token: Token<'src>,
+    },
+    Number {
+        value: f64,
+        token: Token<'src>,
+    },
+    String {
+        // without the quotation marks, which is only owned when the raw
+        // string has doubled quotation marks
+        value: Cow<'src, str>,
+        token: Token<'src>,
+    },
+    Variable {
+        name: Token<'src>,
+    },
+    Assign {
+        name: Token<'src>,
+        equal: Token<'src>,
+        value: Box<Expr<'src>>,
+    },
+    Unary {
+        operator: Token<'src>,
+        operand: Box<Expr<'src>>,
+    },
+    Binary {
+        operator: Token<'src>,
+        left: Box<Expr<'src>>,
+        right: Box<Expr<'src>>,
+    },
+    // `and` and `or`, which only evaluate `right` when they have to
+    Logical {
+        operator: Token<'src>,
+        left: Box<Expr<'src>>,
+        right: Box<Expr<'src>>,
+    },
+    Grouping {
+        open: Token<'src>,
+        expr: Box<Expr<'src>>,
+        close: Token<'src>,
+    },
+    Call {
+        callee: Box<Expr<'src>>,
+        // the opening parenthesis, which is where a failed call is reported
+        paren: Token<'src>,
+        arguments: Vec<Expr<'src>>,
+        close: Token<'src>,
+    },
+}
+
+impl<'src> Expr<'src> {
+    /// The first token of the expression.
+    pub fn first_token(&self) -> &Token<'src> {
+        // a chain like `a + b + c` nests on its left operand as deeply as it
+        // is long, so it is walked down in a loop rather than recursively
+        let mut expr = self;
+        loop {
+            match expr {
+                Expr::Literal { token, .. }
+                | Expr::Number { token, .. }
+                | Expr::String { token, .. } => return token,
+                Expr::Variable { name } | Expr::Assign { name, .. } => return name,
+                Expr::Unary { operator, .. } => return operator,
+                Expr::Binary { left, .. } | Expr::Logical { left, .. } => expr = left,
+                Expr::Grouping { open, .. } => return open,
+                Expr::Call { callee, .. } => expr = callee,
+            }
+        }
+    }
+
+    /// The last token of the expression.
+    pub fn last_token(&self) -> &Token<'src> {
+        match self {
+            Expr::Literal { token, .. }
+            | Expr::Number { token, .. }
+            | Expr::String { token, .. } => token,
+            Expr::Variable { name } => name,
+            Expr::Assign { value, .. } => value.last_token(),
+            Expr::Unary { operand, .. } => operand.last_token(),
+            Expr::Binary { right, .. } | Expr::Logical { right, .. } => right.last_token(),
+            Expr::Grouping { close, .. } | Expr::Call { close, .. } => close,
+        }
+    }
+}
+
+// a chain nests on its left operand as deeply as it is long, so it is taken
+// apart in a loop, or dropping a long one would overflow the stack
+impl Drop for Expr<'_> {
+    fn drop(&mut self) {
+        let Some(left) = self.left_mut() else {
+            return;
+        };
+        if left.left_mut().is_none() {
+            return;
+        }
+
+        let mut next = mem::replace(left, Expr::placeholder());
+        while let Some(left) = next.left_mut() {
+            // `next` is dropped without its left operand, so it doesn't
+            // recurse
+            next = mem::replace(left, Expr::placeholder());
+        }
+    }
+}
+
+impl<'src> Expr<'src> {
+    // the operand that a chain nests on
+    fn left_mut(&mut self) -> Option<&mut Box<Expr<'src>>> {
+        match self {
+            Expr::Binary { left, .. } | Expr::Logical { left, .. } => Some(left),
+            Expr::Call { callee, .. } => Some(callee),
+            _ => None,
+        }
+    }
+
+    // what is left in place of an operand that is taken out
+    fn placeholder() -> Box<Expr<'src>> {
+        Box::new(Expr::Literal {
+            value: Literal::Nil,
+            token: Token {
+                kind: TokenKind::Nil,
+                lexeme: "nil",
+                line: 0,
+                column: 0,
+                start: 0,
+                end: 0,
+            },
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Function<'src> {
+    pub keyword: Token<'src>,
+    pub name: Token<'src>,
+    pub params: Vec<Token<'src>>,
+    pub body: Vec<Stmt<'src>>,
+    // the closing brace of the body
+    pub close: Token<'src>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Stmt<'src> {
+    Expression {
+        expr: Expr<'src>,
+        semicolon: Token<'src>,
+    },
+    Print {
+        keyword: Token<'src>,
+        expr: Expr<'src>,
+        semicolon: Token<'src>,
+    },
+    Var {
+        keyword: Token<'src>,
+        name: Token<'src>,
+        initializer: Option<Expr<'src>>,
+        semicolon: Token<'src>,
+    },
+    Function(Function<'src>),
+    Block {
+        open: Token<'src>,
+        statements: Vec<Stmt<'src>>,
+        close: Token<'src>,
+    },
+    If {
+        keyword: Token<'src>,
+        condition: Expr<'src>,
+        // the parenthesis after the condition
+        paren: Token<'src>,
+        then_branch: Box<Stmt<'src>>,
+        else_branch: Option<Box<Stmt<'src>>>,
+    },
+    While {
+        keyword: Token<'src>,
+        condition: Expr<'src>,
+        // the parenthesis after the condition
+        paren: Token<'src>,
+        body: Box<Stmt<'src>>,
+    },
+    For {
+        keyword: Token<'src>,
+        initializer: Option<Box<Stmt<'src>>>,
+        condition: Option<Expr<'src>>,
+        // the semicolon after the condition (which might be left out)
+        semicolon: Token<'src>,
+        increment: Option<Expr<'src>>,
+        // the parenthesis after the clauses
+        paren: Token<'src>,
+        body: Box<Stmt<'src>>,
+    },
+    Return {
+        keyword: Token<'src>,
+        value: Option<Expr<'src>>,
+        semicolon: Token<'src>,
+    },
+}
+
+impl<'src> Stmt<'src> {
+    /// The first token of the statement.
+    pub fn first_token(&self) -> &Token<'src> {
+        match self {
+            Stmt::Expression { expr, .. } => expr.first_token(),
+            Stmt::Print { keyword, .. }
+            | Stmt::Var { keyword, .. }
+            | Stmt::Function(Function { keyword, .. })
+            | Stmt::If { keyword, .. }
+            | Stmt::While { keyword, .. }
+            | Stmt::For { keyword, .. }
+            | Stmt::Return { keyword, .. } => keyword,
+            Stmt::Block { open, .. } => open,
+        }
+    }
+
+    /// The last token of the statement.
+    pub fn last_token(&self) -> &Token<'src> {
+        match self {
+            Stmt::Expression { semicolon, .. }
+            | Stmt::Print { semicolon, .. }
+            | Stmt::Var { semicolon, .. }
+            | Stmt::Return { semicolon, .. } => semicolon,
+            Stmt::Function(Function { close, .. }) | Stmt::Block { close, .. } => close,
+            Stmt::If {
+                then_branch,
+                else_branch,
+                ..
+            } => else_branch.as_ref().unwrap_or(then_branch).last_token(),
+            Stmt::While { body, .. } | Stmt::For { body, .. } => body.last_token(),
+        }
+    }
+}
+
+/// A whole script.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program<'src> {
+    pub statements: Vec<Stmt<'src>>,
+    // where the implicit return of the script is: the end of the source, or
+    // the last token if the end is on a line after it, which has no code
+    pub end: Token<'src>,
+}
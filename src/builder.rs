@@ -0,0 +1,438 @@
+// Builds the code of a chunk one instruction at a time, so that a code
+// generator never writes raw bytes: operands are checked against their
+// instruction, jumps go to labels whose offsets are filled in once the label
+// is defined, and jumps whose offset does not fit in their one-byte
+// placeholder are made longer when the chunk is finished.
+
+use alloc::{vec, vec::Vec};
+use core::fmt;
+
+use crate::{
+    chunk::{Chunk, LocalName, OpCode, Operand, Span},
+    instruction::{Instruction, varint_len},
+    object::ObjRef,
+    relocate,
+    scanner::Token,
+    table::Table,
+    value::{Value, ValueArray},
+};
+
+impl From<&Token<'_>> for Span {
+    fn from(token: &Token) -> Self {
+        Self {
+            line: token.line as u32,
+            column: token.column as u32,
+            start: token.start,
+            end: token.end,
+        }
+    }
+}
+
+/// A place in the code that jumps can go to, which can be used before it is
+/// defined.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Label(usize);
+
+/// An instruction, along with its operands.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Op {
+    // an instruction without operands, e.g. OP_ADD
+    Simple(OpCode),
+    // an instruction with a single byte operand, which is the slot of a
+    // local, or the argument count of a call
+    Byte(OpCode, u8),
+    // OP_CONSTANT, whose index is as long as it needs to be
+    Constant(usize),
+    // OP_DEFINE_GLOBAL, OP_GET_GLOBAL or OP_SET_GLOBAL, with the index of
+    // the constant of the name, which is as long as OP_CONSTANT's
+    Global(OpCode, usize),
+    // forwards only, to a label that is not defined yet
+    Jump(Label),
+    JumpIfFalse(Label),
+    // backwards only, to a label that is already defined
+    Loop(Label),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BuildError {
+    // the instruction takes a different number of operands
+    Operands(OpCode),
+    TooManyConstants,
+    JumpTooFar,
+    LoopTooFar,
+    // a jump that goes backwards, or a loop that goes forwards
+    WrongDirection,
+    Redefined,
+    // a label that is jumped to, but never defined
+    Undefined,
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Operands(op) => write!(f, "Wrong operands for {:?}.", op),
+            BuildError::TooManyConstants => write!(f, "Too many constants in one chunk."),
+            BuildError::JumpTooFar => write!(f, "Too much code to jump over."),
+            BuildError::LoopTooFar => write!(f, "Loop body too large."),
+            BuildError::WrongDirection => {
+                write!(f, "Jumps can only go forwards, and loops backwards.")
+            }
+            BuildError::Redefined => write!(f, "Label is defined more than once."),
+            BuildError::Undefined => write!(f, "Jump to a label that is never defined."),
+        }
+    }
+}
+
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    // the offset that each label is defined at
+    labels: Vec<Option<usize>>,
+    // forward jumps whose label is not defined yet, as (where the jump is,
+    // where it goes)
+    pending: Vec<(usize, Label)>,
+    // forward jumps that are too long for their one-byte placeholder, as
+    // (where the jump is, where it lands), which are made longer at the end
+    long_jumps: Vec<(usize, usize)>,
+    // the constant of each name that was added with `add_name`, so that a
+    // global is only in the constants once however often it is used
+    names: Table<usize>,
+}
+
+impl Default for ChunkBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkBuilder {
+    pub fn new() -> Self {
+        Self {
+            chunk: Chunk::new(),
+            labels: vec![],
+            pending: vec![],
+            long_jumps: vec![],
+            names: Table::new(),
+        }
+    }
+
+    /// The chunk so far, with the offsets of pending jumps left as
+    /// placeholders.
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    pub fn code_len(&self) -> usize {
+        self.chunk.code_len()
+    }
+
+    /// Adds `value` to the constants, returning its index for
+    /// [`Op::Constant`].
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        self.chunk.constants_mut().add(value)
+    }
+
+    /// Adds the string `name` to the constants, unless it was added already,
+    /// returning its index for [`Op::Global`].
+    pub fn add_name(&mut self, name: ObjRef) -> usize {
+        // the constant may have been truncated since
+        if let Some(&index) = self.names.get(name)
+            && self.chunk.constants().try_get(index) == Some(Value::Obj(name))
+        {
+            return index;
+        }
+
+        let index = self.add_constant(Value::Obj(name));
+        self.names.insert(name, index);
+        index
+    }
+
+    pub fn add_local_name(&mut self, local: LocalName) {
+        self.chunk.add_local_name(local);
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// A label that is defined at the end of the code so far.
+    pub fn here(&mut self) -> Label {
+        let label = self.new_label();
+        self.labels[label.0] = Some(self.code_len());
+        label
+    }
+
+    /// Defines `label` at the end of the code so far, which is where the
+    /// jumps to it land.
+    pub fn define_label(&mut self, label: Label) -> Result<(), BuildError> {
+        if self.labels[label.0].is_some() {
+            return Err(BuildError::Redefined);
+        }
+        let target = self.code_len();
+        self.labels[label.0] = Some(target);
+
+        let mut result = Ok(());
+        let (jumps, pending) = self.pending.iter().partition(|(_, to)| *to == label);
+        self.pending = pending;
+        for (at, _) in jumps {
+            // +2 to adjust for the jump itself
+            let jump = target - at - 2;
+            if jump > u32::MAX as usize {
+                result = Err(BuildError::JumpTooFar);
+            } else if varint_len(jump as u32) > 1 {
+                // the jump cannot be made longer here without moving the
+                // code after it, which other jumps might already point into
+                self.long_jumps.push((at, target));
+                continue;
+            }
+
+            self.chunk.set_code(at + 1, jump as u8);
+        }
+        result
+    }
+
+    /// Appends `op` to the code. Nothing is appended if it returns an error.
+    pub fn emit(&mut self, op: Op, span: Span) -> Result<(), BuildError> {
+        let instruction = match op {
+            Op::Simple(op) => {
+                Instruction::from_operands(op, &[]).ok_or(BuildError::Operands(op))?
+            }
+            // a one-byte varint would be read the same, but jumps,
+            // constants and globals have their own ops
+            Op::Byte(op, operand) if op.operands() == [Operand::Byte] => {
+                Instruction::from_operands(op, &[operand]).ok_or(BuildError::Operands(op))?
+            }
+            Op::Byte(op, _) => return Err(BuildError::Operands(op)),
+            Op::Constant(index) if index >= ValueArray::MAX_LEN => {
+                return Err(BuildError::TooManyConstants);
+            }
+            Op::Constant(index) => Instruction::Constant(index as u32),
+            Op::Global(_, index) if index >= ValueArray::MAX_LEN => {
+                return Err(BuildError::TooManyConstants);
+            }
+            Op::Global(OpCode::DefineGlobal, index) => Instruction::DefineGlobal(index as u32),
+            Op::Global(OpCode::GetGlobal, index) => Instruction::GetGlobal(index as u32),
+            Op::Global(OpCode::SetGlobal, index) => Instruction::SetGlobal(index as u32),
+            Op::Global(op, _) => return Err(BuildError::Operands(op)),
+            Op::Jump(label) => self.jump(Instruction::Jump, label)?,
+            Op::JumpIfFalse(label) => self.jump(Instruction::JumpIfFalse, label)?,
+            Op::Loop(label) => {
+                let target = self.labels[label.0].ok_or(BuildError::WrongDirection)?;
+                // the offset also jumps back over OP_LOOP and its operand,
+                // whose length depends on the offset, so the shortest
+                // operand that the offset fits in is the one used
+                (1..=5)
+                    .find_map(|len| {
+                        let offset = u32::try_from(self.code_len() - target + 1 + len).ok()?;
+                        (varint_len(offset) == len).then_some(Instruction::Loop(offset))
+                    })
+                    .ok_or(BuildError::LoopTooFar)?
+            }
+        };
+
+        instruction.encode(&mut self.chunk, span);
+        Ok(())
+    }
+
+    // a jump with a placeholder offset, which is filled in when `label` is
+    // defined
+    fn jump(
+        &mut self,
+        jump: fn(u32) -> Instruction,
+        label: Label,
+    ) -> Result<Instruction, BuildError> {
+        if self.labels[label.0].is_some() {
+            return Err(BuildError::WrongDirection);
+        }
+
+        self.pending.push((self.code_len(), label));
+        Ok(jump(0))
+    }
+
+    /// Throws away the code from `code` onwards and the constants from
+    /// `constants` onwards, along with the jumps and labels inside of them.
+    pub fn truncate(&mut self, code: usize, constants: usize) {
+        self.chunk.truncate(code);
+        self.chunk.constants_mut().truncate(constants);
+
+        self.pending.retain(|(at, _)| *at < code);
+        self.long_jumps.retain(|(at, _)| *at < code);
+        self.labels
+            .iter_mut()
+            .filter(|label| label.is_some_and(|offset| offset > code))
+            .for_each(|label| *label = None);
+    }
+
+    /// The finished chunk, once every label that is jumped to is defined.
+    pub fn finish(mut self) -> Result<Chunk, BuildError> {
+        if !self.pending.is_empty() {
+            return Err(BuildError::Undefined);
+        }
+
+        if !self.long_jumps.is_empty() && !relocate::widen_jumps(&mut self.chunk, &self.long_jumps)
+        {
+            return Err(BuildError::JumpTooFar);
+        }
+        Ok(self.chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPAN: Span = Span {
+        line: 1,
+        column: 1,
+        start: 0,
+        end: 0,
+    };
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        code.iter().for_each(|byte| chunk.write(*byte, 1, 1));
+        chunk
+    }
+
+    #[test]
+    fn test_builder_emit() {
+        let mut builder = ChunkBuilder::new();
+        let constant = builder.add_constant(Value::Number(1.0));
+        builder.emit(Op::Constant(constant), SPAN).unwrap();
+        builder
+            .emit(
+                Op::Byte(OpCode::SetLocal, 1),
+                Span {
+                    line: 2,
+                    column: 3,
+                    start: 4,
+                    end: 9,
+                },
+            )
+            .unwrap();
+        builder.emit(Op::Simple(OpCode::Return), SPAN).unwrap();
+
+        let chunk = builder.finish().expect("valid");
+        let mut expected = self::chunk(&[OpCode::Constant as u8, 0]);
+        let span = Span {
+            line: 2,
+            column: 3,
+            start: 4,
+            end: 9,
+        };
+        expected.write_span(OpCode::SetLocal as u8, span);
+        expected.write_span(1, span);
+        expected.write(OpCode::Return as u8, 1, 1);
+        expected.constants_mut().add(Value::Number(1.0));
+        assert_eq!(chunk, expected);
+
+        // an index that does not fit in 7 bits
+        let mut builder = ChunkBuilder::new();
+        builder.emit(Op::Constant(256), SPAN).unwrap();
+        assert_eq!(
+            builder.finish(),
+            Ok(self::chunk(&[OpCode::Constant as u8, 0x80, 0x02]))
+        );
+    }
+
+    #[test]
+    fn test_builder_labels() {
+        // `while (nil) print nil;`
+        let mut builder = ChunkBuilder::new();
+        let start = builder.here();
+        let exit = builder.new_label();
+        builder.emit(Op::Simple(OpCode::Nil), SPAN).unwrap();
+        builder.emit(Op::JumpIfFalse(exit), SPAN).unwrap();
+        builder.emit(Op::Simple(OpCode::Pop), SPAN).unwrap();
+        builder.emit(Op::Simple(OpCode::Nil), SPAN).unwrap();
+        builder.emit(Op::Simple(OpCode::Print), SPAN).unwrap();
+        builder.emit(Op::Loop(start), SPAN).unwrap();
+        builder.define_label(exit).unwrap();
+        builder.emit(Op::Simple(OpCode::Pop), SPAN).unwrap();
+
+        let expected = [
+            OpCode::Nil as u8,
+            OpCode::JumpIfFalse as u8,
+            5,
+            OpCode::Pop as u8,
+            OpCode::Nil as u8,
+            OpCode::Print as u8,
+            OpCode::Loop as u8,
+            8,
+            OpCode::Pop as u8,
+        ];
+        assert_eq!(builder.finish(), Ok(chunk(&expected)));
+    }
+
+    #[test]
+    fn test_builder_long_jumps() {
+        let mut builder = ChunkBuilder::new();
+        let end = builder.new_label();
+        builder.emit(Op::Jump(end), SPAN).unwrap();
+        let start = builder.here();
+        for _ in 0..200 {
+            builder.emit(Op::Simple(OpCode::Nil), SPAN).unwrap();
+        }
+        builder.emit(Op::Loop(start), SPAN).unwrap();
+        builder.define_label(end).unwrap();
+        builder.emit(Op::Simple(OpCode::Return), SPAN).unwrap();
+
+        // the jump is made longer at the end, and the loop gets the longer
+        // operand right away, as it jumps back over it as well
+        let chunk = builder.finish().expect("valid");
+        let jump = (0..3).map(|i| chunk.get_code(i)).collect::<Vec<_>>();
+        assert_eq!(jump, vec![OpCode::Jump as u8, 0xcb, 0x01]);
+        let loop_at = 3 + 200;
+        let back = (loop_at..loop_at + 3)
+            .map(|i| chunk.get_code(i))
+            .collect::<Vec<_>>();
+        assert_eq!(back, vec![OpCode::Loop as u8, 0xcb, 0x01]);
+    }
+
+    #[test]
+    fn test_builder_errors() {
+        let mut builder = ChunkBuilder::new();
+        assert_eq!(
+            builder.emit(Op::Simple(OpCode::GetLocal), SPAN),
+            Err(BuildError::Operands(OpCode::GetLocal))
+        );
+        assert_eq!(
+            builder.emit(Op::Byte(OpCode::Add, 0), SPAN),
+            Err(BuildError::Operands(OpCode::Add))
+        );
+        assert_eq!(
+            builder.emit(Op::Byte(OpCode::Jump, 0), SPAN),
+            Err(BuildError::Operands(OpCode::Jump))
+        );
+        assert_eq!(builder.code_len(), 0);
+
+        let start = builder.here();
+        assert_eq!(
+            builder.emit(Op::Jump(start), SPAN),
+            Err(BuildError::WrongDirection)
+        );
+        assert_eq!(builder.define_label(start), Err(BuildError::Redefined));
+        let end = builder.new_label();
+        assert_eq!(
+            builder.emit(Op::Loop(end), SPAN),
+            Err(BuildError::WrongDirection)
+        );
+
+        builder.emit(Op::Jump(end), SPAN).unwrap();
+        assert_eq!(builder.finish(), Err(BuildError::Undefined));
+    }
+
+    #[test]
+    fn test_builder_truncate() {
+        let mut builder = ChunkBuilder::new();
+        builder.emit(Op::Simple(OpCode::Nil), SPAN).unwrap();
+        let end = builder.new_label();
+        let constant = builder.add_constant(Value::Nil);
+        builder.emit(Op::Jump(end), SPAN).unwrap();
+        builder.emit(Op::Constant(constant), SPAN).unwrap();
+
+        // the jump is thrown away, so its label does not have to be defined
+        builder.truncate(1, 0);
+        assert_eq!(builder.finish(), Ok(chunk(&[OpCode::Nil as u8])));
+    }
+}
@@ -0,0 +1,802 @@
+// The `.loxc` format, which holds the compiled script so that it can be run
+// without compiling it again:
+//
+//   "LOXC" version:u8 function
+//
+//   function  = arity:u8 name code lines columns starts ends locals constants
+//   name      = 0 | 1 string             (the script has no name)
+//   string    = len:u32 bytes            (UTF-8)
+//   code      = len:u32 bytes
+//   lines     = count:u32 (start:u32 value:u32)*, same for columns, and for
+//               the starts and ends of the source ranges, as in the
+//               run-length encoding of `Chunk`
+//   locals    = count:u32 (string slot:u8 start:u32 end:u32)*, the names of
+//               the locals for the debugger
+//   constants = count:u32 constant*
+//   constant  = 0 (nil) | 1 (false) | 2 (true) | 3 f64 | 4 string | 5 function
+//             | 6 string                (a decimal, in its digits)
+//
+// with every integer in little-endian, besides the operands in the code.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt;
+
+use crate::{
+    chunk::{Chunk, LocalName, OpCode, Span},
+    decimal::Decimal,
+    instruction::{Instruction, read_varint},
+    object::{Heap, ObjFunction},
+    value::Value,
+};
+
+const MAGIC: &[u8; 4] = b"LOXC";
+const VERSION: u8 = 7;
+
+// how deeply functions can be declared inside of each other, so that loading
+// them does not overflow the stack
+const MAX_NESTING: usize = 256;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LoadError {
+    NotBytecode,
+    UnsupportedVersion(u8),
+    // the bytes end in the middle of something
+    Truncated,
+    Malformed(&'static str),
+    // the code of a function would make the VM misbehave
+    Invalid {
+        function: String,
+        offset: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "Not a compiled Lox script."),
+            LoadError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported bytecode version {}.", version)
+            }
+            LoadError::Truncated => write!(f, "Unexpected end of bytecode."),
+            LoadError::Malformed(message) => write!(f, "{}", message),
+            LoadError::Invalid {
+                function,
+                offset,
+                message,
+            } => write!(f, "{} (at {:04} in {})", message, offset, function),
+        }
+    }
+}
+
+/// Writes the script in `chunk` in the `.loxc` format.
+pub fn serialize(chunk: &Chunk) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+    write_function(&mut bytes, 0, None, chunk);
+    bytes
+}
+
+fn write_u32(bytes: &mut Vec<u8>, value: usize) {
+    let value = u32::try_from(value).expect("ICE: Chunk too large to serialize");
+    bytes.extend(value.to_le_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len());
+    bytes.extend(string.as_bytes());
+}
+
+fn write_runs(bytes: &mut Vec<u8>, chunk: &Chunk, get: fn(&Chunk, usize) -> usize) {
+    let runs = (0..chunk.code_len())
+        .filter(|&i| i == 0 || get(chunk, i) != get(chunk, i - 1))
+        .collect::<Vec<_>>();
+
+    write_u32(bytes, runs.len());
+    for start in runs {
+        write_u32(bytes, start);
+        write_u32(bytes, get(chunk, start));
+    }
+}
+
+fn write_function(bytes: &mut Vec<u8>, arity: usize, name: Option<&str>, chunk: &Chunk) {
+    bytes.push(u8::try_from(arity).expect("ICE: Too many parameters to serialize"));
+    match name {
+        Some(name) => {
+            bytes.push(1);
+            write_string(bytes, name);
+        }
+        None => bytes.push(0),
+    }
+
+    write_u32(bytes, chunk.code_len());
+    bytes.extend((0..chunk.code_len()).map(|i| chunk.get_code(i)));
+    write_runs(bytes, chunk, |chunk, i| chunk.get_line(i) as usize);
+    write_runs(bytes, chunk, |chunk, i| chunk.get_column(i) as usize);
+    write_runs(bytes, chunk, |chunk, i| chunk.get_span(i).start);
+    write_runs(bytes, chunk, |chunk, i| chunk.get_span(i).end);
+
+    write_u32(bytes, chunk.local_names().len());
+    for local in chunk.local_names() {
+        write_string(bytes, &local.name);
+        bytes.push(local.slot);
+        write_u32(bytes, local.start);
+        write_u32(bytes, local.end);
+    }
+
+    write_u32(bytes, chunk.constants().len());
+    for constant in chunk.constants().iter() {
+        match constant {
+            Value::Nil => bytes.push(0),
+            Value::Bool(false) => bytes.push(1),
+            Value::Bool(true) => bytes.push(2),
+            Value::Number(number) => {
+                bytes.push(3);
+                bytes.extend(number.to_le_bytes());
+            }
+            Value::Obj(obj) => {
+                if let Some(string) = obj.as_string() {
+                    bytes.push(4);
+                    write_string(bytes, string.as_str());
+                } else if let Some(function) = obj.as_function() {
+                    bytes.push(5);
+                    write_function(bytes, function.arity, function.name(), &function.chunk);
+                } else if let Some(decimal) = obj.as_decimal() {
+                    // written out in full, the same as in the source
+                    bytes.push(6);
+                    write_string(bytes, &decimal.to_string());
+                } else {
+                    panic!("ICE: Unhandled constant {:?}", obj);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `bytes` start like a script written by [`serialize`], of any
+/// version, rather than like source code.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Reads a script written by [`serialize`], allocating its strings and
+/// functions on `heap`. The code of every function is verified, so that
+/// running it can only fail with a runtime error, and never crash the VM.
+pub fn load(bytes: &[u8], heap: &mut Heap) -> Result<Chunk, LoadError> {
+    let mut reader = Reader { bytes, offset: 0 };
+
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(LoadError::NotBytecode);
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let (arity, name, chunk) = reader.function(heap, 0)?;
+    if arity != 0 || name.is_some() {
+        return Err(LoadError::Malformed("The script must not be a function."));
+    }
+    if reader.offset != bytes.len() {
+        return Err(LoadError::Malformed("Unexpected bytes after the script."));
+    }
+
+    Ok(chunk)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], LoadError> {
+        let end = self.offset.checked_add(len).ok_or(LoadError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or(LoadError::Truncated)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<usize, LoadError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let len = self.u32()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| LoadError::Malformed("Invalid UTF-8."))
+    }
+
+    // the value of every byte of code, from its run-length encoding
+    fn runs(&mut self, code_len: usize) -> Result<Vec<u32>, LoadError> {
+        let count = self.u32()?;
+        let mut runs = vec![];
+        for _ in 0..count {
+            runs.push((self.u32()?, self.u32()? as u32));
+        }
+
+        // the runs have to cover every byte, in order
+        let first = runs.first().map(|(start, _)| *start);
+        let is_sorted = runs.windows(2).all(|pair| pair[0].0 < pair[1].0);
+        let last = runs.last().map(|(start, _)| *start);
+        if first != (code_len > 0).then_some(0) || !is_sorted || last >= Some(code_len) {
+            return Err(LoadError::Malformed("Invalid source positions."));
+        }
+
+        let mut values = Vec::with_capacity(code_len);
+        for (i, (_, value)) in runs.iter().enumerate() {
+            let end = runs.get(i + 1).map_or(code_len, |(start, _)| *start);
+            values.resize(end, *value);
+        }
+        Ok(values)
+    }
+
+    fn function(
+        &mut self,
+        heap: &mut Heap,
+        depth: usize,
+    ) -> Result<(usize, Option<String>, Chunk), LoadError> {
+        if depth > MAX_NESTING {
+            return Err(LoadError::Malformed("Functions are nested too deeply."));
+        }
+
+        let arity = self.u8()? as usize;
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            _ => return Err(LoadError::Malformed("Invalid function name.")),
+        };
+
+        let code_len = self.u32()?;
+        let code = self.take(code_len)?.to_vec();
+        let lines = self.runs(code_len)?;
+        let columns = self.runs(code_len)?;
+        let starts = self.runs(code_len)?;
+        let ends = self.runs(code_len)?;
+
+        let mut chunk = Chunk::new();
+        for (i, byte) in code.into_iter().enumerate() {
+            let span = Span {
+                line: lines[i],
+                column: columns[i],
+                start: starts[i] as usize,
+                end: ends[i] as usize,
+            };
+            chunk.write_span(byte, span);
+        }
+
+        // only the debugger looks at them, and it checks the slots against
+        // the stack
+        let count = self.u32()?;
+        for _ in 0..count {
+            let local = LocalName {
+                name: self.string()?,
+                slot: self.u8()?,
+                start: self.u32()?,
+                end: self.u32()?,
+            };
+            chunk.add_local_name(local);
+        }
+
+        let count = self.u32()?;
+        for _ in 0..count {
+            let constant = match self.u8()? {
+                0 => Value::Nil,
+                1 => Value::Bool(false),
+                2 => Value::Bool(true),
+                3 => {
+                    let bytes = self.take(8)?;
+                    let mut number = [0; 8];
+                    number.copy_from_slice(bytes);
+                    Value::Number(f64::from_le_bytes(number))
+                }
+                4 => Value::Obj(heap.alloc_string(self.string()?)),
+                5 => {
+                    let (arity, name, chunk) = self.function(heap, depth + 1)?;
+                    let Some(name) = name else {
+                        return Err(LoadError::Malformed("A function must have a name."));
+                    };
+                    let name = heap.alloc_string(name);
+                    Value::Obj(heap.alloc_function(ObjFunction {
+                        arity,
+                        chunk,
+                        name: Some(name),
+                    }))
+                }
+                6 => {
+                    let decimal = Decimal::parse(&self.string()?)
+                        .ok_or(LoadError::Malformed("Invalid decimal."))?;
+                    Value::Obj(heap.alloc_decimal(decimal))
+                }
+                _ => return Err(LoadError::Malformed("Invalid constant.")),
+            };
+            chunk.constants_mut().add(constant);
+        }
+
+        verify(&chunk, arity).map_err(|error| invalid(name.as_deref(), error))?;
+
+        Ok((arity, name, chunk))
+    }
+}
+
+/// Verifies the code of a script that was built in memory instead of loaded,
+/// along with the functions among its constants, the same way that [`load`]
+/// does.
+pub fn verify_script(chunk: &Chunk) -> Result<(), LoadError> {
+    verify_function(chunk, 0, None, 0)
+}
+
+fn verify_function(
+    chunk: &Chunk,
+    arity: usize,
+    name: Option<&str>,
+    depth: usize,
+) -> Result<(), LoadError> {
+    if depth > MAX_NESTING {
+        return Err(LoadError::Malformed("Functions are nested too deeply."));
+    }
+
+    for constant in chunk.constants().iter() {
+        if let Value::Obj(obj) = constant
+            && let Some(function) = obj.as_function()
+        {
+            if function.name().is_none() {
+                return Err(LoadError::Malformed("A function must have a name."));
+            }
+            verify_function(&function.chunk, function.arity, function.name(), depth + 1)?;
+        }
+    }
+
+    verify(chunk, arity).map_err(|error| invalid(name, error))
+}
+
+fn invalid(name: Option<&str>, (offset, message): (usize, String)) -> LoadError {
+    LoadError::Invalid {
+        function: name.unwrap_or("script").to_string(),
+        offset,
+        message,
+    }
+}
+
+/// How many values an instruction pops off the stack, and how many it pushes.
+pub fn stack_effect(op: OpCode, operands: &[u8]) -> (usize, usize) {
+    match op {
+        OpCode::Constant
+        | OpCode::Nil
+        | OpCode::True
+        | OpCode::False
+        | OpCode::Zero
+        | OpCode::One
+        | OpCode::MinusOne
+        | OpCode::GetGlobal
+        | OpCode::GetLocal
+        | OpCode::GetLocalAddConstant
+        | OpCode::GetGlobalCached => (0, 1),
+        OpCode::Dup => (1, 2),
+        OpCode::Negate
+        | OpCode::Not
+        | OpCode::SetGlobal
+        | OpCode::SetLocal
+        | OpCode::SetGlobalCached
+        | OpCode::AddConstant
+        | OpCode::JumpIfFalse => (1, 1),
+        OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::GreaterEqual
+        | OpCode::LessEqual
+        | OpCode::AddNumbers
+        | OpCode::SubtractNumbers
+        | OpCode::LessNumbers => (2, 1),
+        OpCode::Return | OpCode::Print | OpCode::Pop | OpCode::DefineGlobal => (1, 0),
+        OpCode::Jump | OpCode::Loop => (0, 0),
+        OpCode::Call => (operands[0] as usize + 1, 1),
+        OpCode::PopN => (operands[0] as usize, 0),
+        OpCode::ConcatN => {
+            let count = read_varint(|i| operands.get(i).copied()).map_or(0, |(count, _)| count);
+            (count as usize, 1)
+        }
+    }
+}
+
+/// How many values the code of a function pushes past the slots that it
+/// starts with, at the most, along any path through it. The code has to be
+/// valid, as the compiler makes it and [`verify`] checks it, although it can
+/// have been quickened since.
+pub fn stack_growth(chunk: &Chunk) -> usize {
+    let mut reached = vec![false; chunk.code_len()];
+    let mut worklist: Vec<(usize, usize)> = vec![(0, 0)];
+    let mut growth = 0;
+
+    while let Some((offset, height)) = worklist.pop() {
+        if offset >= chunk.code_len() || reached[offset] {
+            continue;
+        }
+        reached[offset] = true;
+
+        let (instruction, after) = Instruction::fetch(chunk, offset);
+        let (popped, pushed) = stack_effect(instruction.opcode(), &instruction.operands());
+        // the parameters are never popped, and if they were, stopping at
+        // zero would only make the growth larger than it is
+        let height = height.saturating_sub(popped) + pushed;
+        growth = growth.max(height);
+
+        match instruction {
+            Instruction::Jump(jump) | Instruction::JumpIfFalse(jump) => {
+                worklist.push((after + jump as usize, height));
+            }
+            Instruction::Loop(jump) => worklist.push((after - jump as usize, height)),
+            _ => {}
+        }
+        if !matches!(
+            instruction,
+            Instruction::Return | Instruction::Jump(_) | Instruction::Loop(_)
+        ) {
+            worklist.push((after, height));
+        }
+    }
+    growth
+}
+
+/// Checks that the code of a function with `arity` parameters only has valid
+/// instructions with operands in range, that its jumps land on instructions,
+/// and that every path through it keeps the stack the same height where paths
+/// meet, never pops below the function's own slot, and ends with a return.
+fn verify(chunk: &Chunk, arity: usize) -> Result<(), (usize, String)> {
+    // the height of the stack (from the function's slot up) before each
+    // instruction, once a path to it has been found
+    let mut heights = vec![None; chunk.code_len()];
+    let mut worklist = vec![(0, arity + 1)];
+
+    if chunk.code_len() == 0 {
+        return Err((0, "The function has no code.".to_string()));
+    }
+
+    while let Some((offset, height)) = worklist.pop() {
+        match heights[offset] {
+            Some(known) if known == height => continue,
+            Some(known) => {
+                return Err((
+                    offset,
+                    format!(
+                        "Stack height is {} on one path and {} on another.",
+                        known, height
+                    ),
+                ));
+            }
+            None => heights[offset] = Some(height),
+        }
+
+        let Ok(op) = OpCode::try_from(chunk.get_code(offset)) else {
+            return Err((
+                offset,
+                format!("Invalid opcode {}.", chunk.get_code(offset)),
+            ));
+        };
+        if op.is_quickened() {
+            return Err((offset, format!("{} is only made by the VM.", op.mnemonic())));
+        }
+        let Some((instruction, after)) = Instruction::decode(chunk, offset) else {
+            return Err((offset, "Missing or malformed operands.".to_string()));
+        };
+        let operands = instruction.operands();
+
+        verify_operands(chunk, instruction, height).map_err(|message| (offset, message))?;
+
+        let (popped, pushed) = stack_effect(op, &operands);
+        // the function's own slot is never popped, so that the VM can always
+        // find the frame's values under the ones being popped
+        if height < popped + 1 {
+            return Err((
+                offset,
+                "Pops more values than are on the stack.".to_string(),
+            ));
+        }
+        let height = height - popped + pushed;
+
+        let target = match instruction {
+            Instruction::Jump(jump) | Instruction::JumpIfFalse(jump) => Some(after + jump as usize),
+            Instruction::Loop(jump) => Some(
+                after
+                    .checked_sub(jump as usize)
+                    .ok_or((offset, "Loops back past the start of the code.".to_string()))?,
+            ),
+            _ => None,
+        };
+        if let Some(target) = target {
+            if target >= chunk.code_len() {
+                return Err((offset, "Jumps past the end of the code.".to_string()));
+            }
+            worklist.push((target, height));
+        }
+
+        let falls_through = !matches!(op, OpCode::Return | OpCode::Jump | OpCode::Loop);
+        if falls_through {
+            if after == chunk.code_len() {
+                return Err((offset, "Runs past the end of the code.".to_string()));
+            }
+            worklist.push((after, height));
+        }
+    }
+
+    // a jump into the middle of an instruction is found by checking that
+    // none of the instructions that were reached overlap
+    let mut end = 0;
+    for (offset, height) in heights.iter().enumerate() {
+        if height.is_none() {
+            continue;
+        }
+        if offset < end {
+            return Err((
+                offset,
+                "Jumps into the middle of an instruction.".to_string(),
+            ));
+        }
+        let (_, after) = Instruction::decode(chunk, offset).expect("checked above");
+        end = after;
+    }
+
+    Ok(())
+}
+
+fn verify_operands(chunk: &Chunk, instruction: Instruction, height: usize) -> Result<(), String> {
+    let constant = |index: usize| {
+        chunk
+            .constants()
+            .try_get(index)
+            .ok_or_else(|| format!("Constant {} does not exist.", index))
+    };
+    let local = |slot: u8| {
+        if (slot as usize) < height {
+            Ok(())
+        } else {
+            Err(format!("Local slot {} is not on the stack.", slot))
+        }
+    };
+
+    match instruction {
+        Instruction::Constant(index) | Instruction::AddConstant(index) => {
+            constant(index as usize)?;
+        }
+        Instruction::DefineGlobal(name)
+        | Instruction::GetGlobal(name)
+        | Instruction::SetGlobal(name)
+            if constant(name as usize)?.as_string().is_none() =>
+        {
+            return Err("The name of a global must be a string.".to_string());
+        }
+        Instruction::GetLocal(slot) | Instruction::SetLocal(slot) => {
+            local(slot)?;
+        }
+        Instruction::GetLocalAddConstant(slot, index) => {
+            local(slot)?;
+            constant(index as usize)?;
+        }
+        Instruction::ConcatN(count) if count < 2 => {
+            return Err("OP_CONCAT_N adds at least two values.".to_string());
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{CompileOptions, Compiler},
+        object::ObjRef,
+    };
+
+    fn compile(source: &str, heap: &mut Heap) -> Chunk {
+        Compiler::compile(source, heap, Default::default()).expect("no error")
+    }
+
+    // a script whose code is `code`, with `constants`
+    fn script(code: &[u8], constants: &[Value]) -> Vec<u8> {
+        let mut chunk = Chunk::new();
+        code.iter().for_each(|byte| chunk.write(*byte, 1, 1));
+        constants.iter().for_each(|constant| {
+            chunk.constants_mut().add(*constant);
+        });
+        serialize(&chunk)
+    }
+
+    fn load_error(bytes: &[u8]) -> LoadError {
+        load(bytes, &mut Heap::new()).expect_err("invalid")
+    }
+
+    fn invalid(bytes: &[u8]) -> String {
+        match load_error(bytes) {
+            LoadError::Invalid { message, .. } => message,
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_bytecode_roundtrip() {
+        let mut heap = Heap::new();
+        let chunk = compile("var s = \"sum\";\nprint -1.5 >= 0 and !nil;", &mut heap);
+        assert_eq!(load(&serialize(&chunk), &mut heap), Ok(chunk));
+
+        // decimals are written out in their digits
+        let options = CompileOptions {
+            decimal: true,
+            ..Default::default()
+        };
+        let chunk = Compiler::compile("print 0.1 + 12345678901234567890.5;", &mut heap, options)
+            .expect("no error");
+        assert_eq!(load(&serialize(&chunk), &mut heap), Ok(chunk));
+
+        // functions are compared by reference, so their contents are
+        // compared instead
+        fn function(chunk: &Chunk) -> ObjRef {
+            (0..chunk.constants().len())
+                .find_map(|i| match chunk.constants().get(i) {
+                    Value::Obj(obj) if obj.as_function().is_some() => Some(obj),
+                    _ => None,
+                })
+                .expect("has a function")
+        }
+        let chunk = compile(
+            "fun add(a, b) { return a + \"b\"; }\nprint add(1, 2);",
+            &mut heap,
+        );
+        let loaded = load(&serialize(&chunk), &mut heap).expect("valid");
+        assert_eq!(loaded.code_len(), chunk.code_len());
+        let (loaded, function) = (function(&loaded), function(&chunk));
+        let (loaded, function) = (
+            loaded.as_function().expect("function"),
+            function.as_function().expect("function"),
+        );
+        assert_eq!(loaded.arity, function.arity);
+        assert_eq!(loaded.name(), function.name());
+        assert_eq!(loaded.chunk, function.chunk);
+    }
+
+    #[test]
+    fn test_bytecode_malformed() {
+        let valid = script(&[OpCode::Nil as u8, OpCode::Return as u8], &[]);
+        assert!(load(&valid, &mut Heap::new()).is_ok());
+
+        assert_eq!(load_error(b"print 1;"), LoadError::NotBytecode);
+        assert!(!is_bytecode(b"print 1;"));
+        let mut version = valid.clone();
+        version[4] = 9;
+        assert!(is_bytecode(&version));
+        assert_eq!(load_error(&version), LoadError::UnsupportedVersion(9));
+        assert_eq!(load_error(&valid[..valid.len() - 1]), LoadError::Truncated);
+        let mut trailing = valid.clone();
+        trailing.push(0);
+        assert!(matches!(load_error(&trailing), LoadError::Malformed(_)));
+    }
+
+    #[test]
+    fn test_bytecode_verify() {
+        const CONSTANT: u8 = OpCode::Constant as u8;
+        const GET_GLOBAL: u8 = OpCode::GetGlobal as u8;
+        const GET_LOCAL: u8 = OpCode::GetLocal as u8;
+        const JUMP: u8 = OpCode::Jump as u8;
+        const JUMP_IF_FALSE: u8 = OpCode::JumpIfFalse as u8;
+        const NIL: u8 = OpCode::Nil as u8;
+        const POP: u8 = OpCode::Pop as u8;
+        const RETURN: u8 = OpCode::Return as u8;
+
+        assert_eq!(invalid(&script(&[], &[])), "The function has no code.");
+        assert_eq!(invalid(&script(&[200], &[])), "Invalid opcode 200.");
+        assert_eq!(
+            invalid(&script(&[CONSTANT], &[])),
+            "Missing or malformed operands."
+        );
+        // a varint that does not fit in 32 bits
+        assert_eq!(
+            invalid(&script(
+                &[CONSTANT, 0xff, 0xff, 0xff, 0xff, 0x7f, RETURN],
+                &[]
+            )),
+            "Missing or malformed operands."
+        );
+        assert_eq!(
+            invalid(&script(&[CONSTANT, 0x80, 0x01, RETURN], &[Value::Nil])),
+            "Constant 128 does not exist."
+        );
+        assert_eq!(
+            invalid(&script(&[CONSTANT, 0, RETURN], &[])),
+            "Constant 0 does not exist."
+        );
+        assert_eq!(
+            invalid(&script(&[GET_GLOBAL, 0, RETURN], &[Value::Nil])),
+            "The name of a global must be a string."
+        );
+        assert_eq!(
+            invalid(&script(&[GET_LOCAL, 1, RETURN], &[])),
+            "Local slot 1 is not on the stack."
+        );
+        assert_eq!(
+            invalid(&script(&[POP, NIL, RETURN], &[])),
+            "Pops more values than are on the stack."
+        );
+        assert_eq!(
+            invalid(&script(&[NIL], &[])),
+            "Runs past the end of the code."
+        );
+        assert_eq!(
+            invalid(&script(&[JUMP, 5, NIL, RETURN], &[])),
+            "Jumps past the end of the code."
+        );
+        assert_eq!(
+            invalid(&script(
+                &[NIL, JUMP_IF_FALSE, 2, JUMP, 0x80, 0x00, RETURN],
+                &[]
+            )),
+            "Jumps into the middle of an instruction."
+        );
+        // one path pushes a value that the other one does not
+        assert_eq!(
+            invalid(&script(&[NIL, JUMP_IF_FALSE, 1, NIL, NIL, RETURN], &[])),
+            "Stack height is 3 on one path and 2 on another."
+        );
+        // the slots of quickened globals only mean something to the VM that
+        // made them
+        assert_eq!(
+            invalid(&script(
+                &[OpCode::GetGlobalCached as u8, 0, POP, NIL, RETURN],
+                &[]
+            )),
+            "OP_GET_GLOBAL_CACHED is only made by the VM."
+        );
+        assert_eq!(
+            invalid(&script(&[NIL, OpCode::ConcatN as u8, 1, RETURN], &[])),
+            "OP_CONCAT_N adds at least two values."
+        );
+    }
+
+    #[test]
+    fn test_stack_growth() {
+        const NIL: u8 = OpCode::Nil as u8;
+        const POP: u8 = OpCode::Pop as u8;
+
+        // the branch that is taken pushes the most
+        let mut chunk = Chunk::new();
+        let code = [
+            NIL,
+            OpCode::JumpIfFalse as u8,
+            4,
+            NIL,
+            NIL,
+            POP,
+            POP,
+            OpCode::Return as u8,
+        ];
+        code.iter().for_each(|byte| chunk.write(*byte, 1, 1));
+        assert_eq!(stack_growth(&chunk), 3);
+
+        // the chunk works it out again once its code changes
+        assert_eq!(chunk.stack_growth(), 3);
+        chunk.set_code(5, NIL);
+        assert_eq!(chunk.stack_growth(), 4);
+
+        // the arguments of a call are pushed on top of the callee
+        let mut heap = Heap::new();
+        let chunk = compile("fun f(a, b, c) {}\nf(1, 2, 3);", &mut heap);
+        assert_eq!(stack_growth(&chunk), 4);
+    }
+}
@@ -0,0 +1,437 @@
+// The C API, for embedding the VM in C, C++ or anything else that can call
+// C, as declared in include/clox.h. It is built as a shared library with:
+//
+//   cargo rustc --lib --release --features capi --crate-type cdylib
+//
+// A host makes a VM with `clox_vm_new`, runs scripts in it with
+// `clox_interpret`, and frees it with `clox_vm_free`. Values cross over as
+// a `CloxValue`, whose strings and other objects are handles to the heap of
+// the VM that made them, which stay valid until it is freed.
+
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    io, ptr, slice,
+};
+
+use crate::{
+    object::{Obj, ObjRef},
+    value::Value,
+    vm::VM,
+};
+
+/// The kind of a [`CloxValue`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloxKind {
+    Nil,
+    Bool,
+    Number,
+    String,
+    /// Any other object, like a function or a decimal.
+    Object,
+}
+
+/// A value of Lox, where only the field for its kind means anything.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CloxValue {
+    pub kind: CloxKind,
+    pub boolean: bool,
+    pub number: f64,
+    /// The handle of a string or another object.
+    pub object: *const c_void,
+}
+
+impl CloxValue {
+    fn new(kind: CloxKind) -> Self {
+        Self {
+            kind,
+            boolean: false,
+            number: 0.0,
+            object: ptr::null(),
+        }
+    }
+
+    fn from_value(value: Value) -> Self {
+        match value {
+            Value::Nil => Self::new(CloxKind::Nil),
+            Value::Bool(boolean) => Self {
+                boolean,
+                ..Self::new(CloxKind::Bool)
+            },
+            Value::Number(number) => Self {
+                number,
+                ..Self::new(CloxKind::Number)
+            },
+            Value::Obj(obj) => {
+                let kind = match obj.as_string() {
+                    Some(_) => CloxKind::String,
+                    None => CloxKind::Object,
+                };
+                Self {
+                    object: obj.as_ptr().cast(),
+                    ..Self::new(kind)
+                }
+            }
+        }
+    }
+
+    // the value, or `None` for an object without a handle
+    //
+    // SAFETY: the handle of an object has to be from the VM that takes it
+    unsafe fn to_value(self) -> Option<Value> {
+        match self.kind {
+            CloxKind::Nil => Some(Value::Nil),
+            CloxKind::Bool => Some(Value::Bool(self.boolean)),
+            CloxKind::Number => Some(Value::Number(self.number)),
+            CloxKind::String | CloxKind::Object => {
+                // SAFETY: see above
+                unsafe { ObjRef::from_ptr(self.object.cast::<Obj>()) }.map(Value::Obj)
+            }
+        }
+    }
+}
+
+/// Called with each line that a script prints, with its newline, which
+/// isn't NUL-terminated.
+pub type CloxPrint =
+    unsafe extern "C" fn(chars: *const c_char, length: usize, userdata: *mut c_void);
+
+/// A function of the host that scripts can call, with its arguments. It
+/// writes what it returns to `result`, which starts out as nil, and returns
+/// NULL, or else the message of a runtime error, which is copied.
+pub type CloxNative = unsafe extern "C" fn(
+    args: *const CloxValue,
+    arg_count: c_int,
+    result: *mut CloxValue,
+    userdata: *mut c_void,
+) -> *const c_char;
+
+// where a VM of the C API prints: to the host, or else stdout
+struct HostOutput {
+    print: Option<(CloxPrint, *mut c_void)>,
+}
+
+impl io::Write for HostOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.print {
+            Some((print, userdata)) => {
+                // SAFETY: the host only reads the text while it is called
+                unsafe { print(buf.as_ptr().cast(), buf.len(), userdata) };
+                Ok(buf.len())
+            }
+            None => io::stdout().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.print {
+            Some(_) => Ok(()),
+            None => io::stdout().flush(),
+        }
+    }
+}
+
+/// A VM of the C API.
+pub struct CloxVM {
+    vm: VM<io::LineWriter<HostOutput>>,
+    // the message of the runtime error of the last script, for the host
+    last_error: Option<CString>,
+}
+
+// the string that a C string points to, or `None` for NULL or invalid UTF-8
+//
+// SAFETY: `chars` has to be NULL or a NUL-terminated string
+unsafe fn str_from<'a>(chars: *const c_char) -> Option<&'a str> {
+    if chars.is_null() {
+        return None;
+    }
+    // SAFETY: see above
+    unsafe { CStr::from_ptr(chars) }.to_str().ok()
+}
+
+/// A VM that prints to stdout, and reports errors to stderr.
+#[unsafe(no_mangle)]
+pub extern "C" fn clox_vm_new() -> *mut CloxVM {
+    let vm = CloxVM {
+        vm: VM::with_output(io::LineWriter::new(HostOutput { print: None })),
+        last_error: None,
+    };
+    Box::into_raw(Box::new(vm))
+}
+
+/// Frees `vm`, along with every string and object that it made.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`], and not used again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_vm_free(vm: *mut CloxVM) {
+    if !vm.is_null() {
+        // SAFETY: see above
+        drop(unsafe { Box::from_raw(vm) });
+    }
+}
+
+/// Hands what scripts print to `print` instead of stdout, or to stdout again
+/// if it is NULL.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_set_print(
+    vm: *mut CloxVM,
+    print: Option<CloxPrint>,
+    userdata: *mut c_void,
+) {
+    // SAFETY: see above
+    let vm = unsafe { &mut *vm };
+    vm.vm.output_mut().get_mut().print = print.map(|print| (print, userdata));
+}
+
+/// Runs `source` in `vm`, returning the exit code that `clox` would have,
+/// which is 0 if it ran without an error.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`], and `source` NUL-terminated.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_interpret(vm: *mut CloxVM, source: *const c_char) -> c_int {
+    // SAFETY: see above
+    let vm = unsafe { &mut *vm };
+    let Some(source) = (unsafe { str_from(source) }) else {
+        return 65;
+    };
+    let result = vm.vm.interpret(source);
+    vm.last_error = vm
+        .vm
+        .last_error()
+        .and_then(|error| CString::new(error.message.as_str()).ok());
+    result.map_or_else(|error| error.exit_code(), |_| 0)
+}
+
+/// The message of the runtime error that stopped the last script, or NULL,
+/// which stays valid until the next script runs.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`].
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_last_error(vm: *const CloxVM) -> *const c_char {
+    // SAFETY: see above
+    let vm = unsafe { &*vm };
+    vm.last_error
+        .as_ref()
+        .map_or(ptr::null(), |error| error.as_ptr())
+}
+
+/// Defines the global `name` as a function that calls `function` with
+/// `arity` arguments, and `userdata`.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`], and `name` NUL-terminated.
+/// `function` is called with `userdata` for as long as the VM is alive.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_register_native(
+    vm: *mut CloxVM,
+    name: *const c_char,
+    arity: c_int,
+    function: CloxNative,
+    userdata: *mut c_void,
+) {
+    // SAFETY: see above
+    let vm = unsafe { &mut *vm };
+    let Some(name) = (unsafe { str_from(name) }) else {
+        return;
+    };
+    vm.vm
+        .define_native(name, arity.max(0) as usize, move |args| {
+            let args = args
+                .iter()
+                .map(|&arg| CloxValue::from_value(arg))
+                .collect::<Vec<_>>();
+            let mut result = CloxValue::new(CloxKind::Nil);
+            // SAFETY: the host said that `function` takes `userdata`
+            let error =
+                unsafe { function(args.as_ptr(), args.len() as c_int, &mut result, userdata) };
+            if !error.is_null() {
+                // SAFETY: the host returns a message that is NUL-terminated
+                let message = unsafe { CStr::from_ptr(error) };
+                return Err(message.to_string_lossy().into_owned());
+            }
+            // SAFETY: the objects that the host has are from this VM
+            unsafe { result.to_value() }.ok_or_else(|| "Native returned a null object.".to_string())
+        });
+}
+
+/// Writes the value of the global `name` to `value`, returning whether it
+/// is defined.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`], `name` NUL-terminated, and `value`
+/// writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_get_global(
+    vm: *const CloxVM,
+    name: *const c_char,
+    value: *mut CloxValue,
+) -> bool {
+    // SAFETY: see above
+    let vm = unsafe { &*vm };
+    let global = unsafe { str_from(name) }.and_then(|name| vm.vm.global_value(name));
+    match global {
+        Some(global) => {
+            // SAFETY: see above
+            unsafe { value.write(CloxValue::from_value(global)) };
+            true
+        }
+        None => false,
+    }
+}
+
+/// Defines the global `name` as `value`, returning whether it could, which
+/// it can't if the object of `value` is of another VM.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`], `name` NUL-terminated, and the
+/// object of `value` from the same VM.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_set_global(
+    vm: *mut CloxVM,
+    name: *const c_char,
+    value: CloxValue,
+) -> bool {
+    // SAFETY: see above
+    let vm = unsafe { &mut *vm };
+    match (unsafe { str_from(name) }, unsafe { value.to_value() }) {
+        (Some(name), Some(value)) => vm.vm.set_global(name, value),
+        _ => false,
+    }
+}
+
+/// A string of `length` bytes of UTF-8 at `chars`, which are copied to the
+/// heap of `vm`. Invalid UTF-8 is replaced.
+///
+/// # Safety
+///
+/// `vm` has to be from [`clox_vm_new`], and `chars` readable for `length`
+/// bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_string(
+    vm: *mut CloxVM,
+    chars: *const c_char,
+    length: usize,
+) -> CloxValue {
+    // SAFETY: see above
+    let vm = unsafe { &mut *vm };
+    let bytes = match length {
+        0 => &[],
+        _ => unsafe { slice::from_raw_parts(chars.cast::<u8>(), length) },
+    };
+    let string = vm
+        .vm
+        .heap_mut()
+        .copy_string(&String::from_utf8_lossy(bytes));
+    CloxValue::from_value(Value::Obj(string))
+}
+
+/// The UTF-8 of the string `value`, which isn't NUL-terminated, with its
+/// length written to `length`, or NULL if it isn't a string.
+///
+/// # Safety
+///
+/// The object of `value` has to be from a VM that is alive, and `length`
+/// writable.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn clox_string_chars(value: CloxValue, length: *mut usize) -> *const c_char {
+    if value.kind != CloxKind::String {
+        return ptr::null();
+    }
+    // SAFETY: see above
+    let Some(obj) = (unsafe { ObjRef::from_ptr(value.object.cast::<Obj>()) }) else {
+        return ptr::null();
+    };
+    match obj.as_string() {
+        Some(string) => {
+            // SAFETY: see above
+            unsafe { length.write(string.as_str().len()) };
+            string.as_str().as_ptr().cast()
+        }
+        None => ptr::null(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe extern "C" fn collect(chars: *const c_char, length: usize, userdata: *mut c_void) {
+        let output = unsafe { &mut *userdata.cast::<Vec<u8>>() };
+        output.extend_from_slice(unsafe { slice::from_raw_parts(chars.cast(), length) });
+    }
+
+    // `add(a, b)`, which adds `userdata` to the sum of two numbers
+    unsafe extern "C" fn add(
+        args: *const CloxValue,
+        arg_count: c_int,
+        result: *mut CloxValue,
+        userdata: *mut c_void,
+    ) -> *const c_char {
+        let args = unsafe { slice::from_raw_parts(args, arg_count as usize) };
+        if args.iter().any(|arg| arg.kind != CloxKind::Number) {
+            return c"Expected numbers.".as_ptr();
+        }
+        let extra = unsafe { *userdata.cast::<f64>() };
+        unsafe {
+            (*result).kind = CloxKind::Number;
+            (*result).number = args[0].number + args[1].number + extra;
+        }
+        ptr::null()
+    }
+
+    #[test]
+    fn test_capi() {
+        let mut output = Vec::<u8>::new();
+        let mut extra = 0.5;
+        unsafe {
+            let vm = clox_vm_new();
+            clox_set_print(vm, Some(collect), (&raw mut output).cast());
+            clox_register_native(vm, c"add".as_ptr(), 2, add, (&raw mut extra).cast());
+            let greeting = clox_string(vm, c"hi".as_ptr(), 2);
+            assert!(clox_set_global(vm, c"greeting".as_ptr(), greeting));
+
+            assert_eq!(
+                clox_interpret(
+                    vm,
+                    c"print add(1, 2); print greeting + \"!\"; var s = greeting + \" there\";"
+                        .as_ptr()
+                ),
+                0
+            );
+            assert!(clox_last_error(vm).is_null());
+
+            let mut s = CloxValue::new(CloxKind::Nil);
+            assert!(clox_get_global(vm, c"s".as_ptr(), &mut s));
+            let mut length = 0;
+            let chars = clox_string_chars(s, &mut length);
+            assert_eq!(
+                slice::from_raw_parts(chars.cast::<u8>(), length),
+                b"hi there"
+            );
+            assert!(!clox_get_global(vm, c"missing".as_ptr(), &mut s));
+
+            assert_eq!(clox_interpret(vm, c"add(1, nil);".as_ptr()), 70);
+            assert_eq!(
+                CStr::from_ptr(clox_last_error(vm)).to_str(),
+                Ok("Expected numbers.")
+            );
+            assert_eq!(clox_interpret(vm, c"print;".as_ptr()), 65);
+            clox_vm_free(vm);
+        }
+        assert_eq!(String::from_utf8(output).expect("valid utf8"), "3.5\nhi!\n");
+    }
+}
@@ -1,57 +1,205 @@
-use crate::value::ValueArray;
+use alloc::{string::String, vec, vec::Vec};
+use core::cell::{Cell, OnceCell};
 
+use crate::{
+    bytecode,
+    value::{Value, ValueArray},
+};
+
+// declares every opcode once, along with its mnemonic and the operands that
+// follow it. the opcodes are numbered in the order that they are declared
+macro_rules! opcodes {
+    ($($name:ident => $mnemonic:literal, [$($operand:ident),*];)*) => {
+        #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name,)*
+        }
+
+        impl OpCode {
+            /// Every opcode, in the order of their bytes.
+            pub const ALL: &[OpCode] = &[$(OpCode::$name,)*];
+
+            /// The name that the disassembler prints, e.g. `OP_RETURN`.
+            pub fn mnemonic(self) -> &'static str {
+                match self {
+                    $(OpCode::$name => $mnemonic,)*
+                }
+            }
+
+            /// The operands that come after the opcode, in order.
+            pub fn operands(self) -> &'static [Operand] {
+                match self {
+                    $(OpCode::$name => &[$(Operand::$operand),*],)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Return => "OP_RETURN", [];
+    Constant => "OP_CONSTANT", [Varint];
+    Negate => "OP_NEGATE", [];
+    Add => "OP_ADD", [];
+    Subtract => "OP_SUBTRACT", [];
+    Multiply => "OP_MULTIPLY", [];
+    Divide => "OP_DIVIDE", [];
+    Nil => "OP_NIL", [];
+    True => "OP_TRUE", [];
+    False => "OP_FALSE", [];
+    Not => "OP_NOT", [];
+    Equal => "OP_EQUAL", [];
+    Greater => "OP_GREATER", [];
+    Less => "OP_LESS", [];
+    // unlike `!(a < b)` of the book, these are false when a number is NaN
+    GreaterEqual => "OP_GREATER_EQUAL", [];
+    LessEqual => "OP_LESS_EQUAL", [];
+    Print => "OP_PRINT", [];
+    Pop => "OP_POP", [];
+    DefineGlobal => "OP_DEFINE_GLOBAL", [Varint];
+    GetGlobal => "OP_GET_GLOBAL", [Varint];
+    SetGlobal => "OP_SET_GLOBAL", [Varint];
+    GetLocal => "OP_GET_LOCAL", [Byte];
+    SetLocal => "OP_SET_LOCAL", [Byte];
+    JumpIfFalse => "OP_JUMP_IF_FALSE", [Varint];
+    Jump => "OP_JUMP", [Varint];
+    Loop => "OP_LOOP", [Varint];
+    Call => "OP_CALL", [Byte];
+    // superinstructions, which only the optimizer emits
+    AddConstant => "OP_ADD_CONSTANT", [Varint];
+    GetLocalAddConstant => "OP_GET_LOCAL_ADD_CONSTANT", [Byte, Varint];
+    // the numbers that are common enough to be loaded without a constant
+    Zero => "OP_ZERO", [];
+    One => "OP_ONE", [];
+    MinusOne => "OP_MINUS_ONE", [];
+    // stack manipulation: push a copy of the top value, and pop the number
+    // of values in the operand
+    Dup => "OP_DUP", [];
+    PopN => "OP_POP_N", [Byte];
+    // the forms that the VM rewrites instructions into once it has run them,
+    // which are never compiled or loaded: globals by their slot in the VM,
+    // and arithmetic on operands that have been numbers so far
+    // the slot of a global is padded to the length of the name constant that
+    // it replaces, so that the instruction is quickened in place
+    GetGlobalCached => "OP_GET_GLOBAL_CACHED", [Varint];
+    SetGlobalCached => "OP_SET_GLOBAL_CACHED", [Varint];
+    AddNumbers => "OP_ADD_NUMBERS", [];
+    SubtractNumbers => "OP_SUBTRACT_NUMBERS", [];
+    LessNumbers => "OP_LESS_NUMBERS", [];
+    // a superinstruction for a chain of OP_ADDs, which adds the number of
+    // values in the operand left to right, so that a string is built by
+    // concatenating all of its parts at once. the operand is padded to a
+    // byte for every OP_ADD, which keeps the span that its errors are
+    // reported at
+    ConcatN => "OP_CONCAT_N", [Varint];
+}
+
+/// The kinds of operand that follow an opcode.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-#[repr(u8)]
-pub enum OpCode {
-    Return,
-    Constant,
-    Negate,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Nil,
-    True,
-    False,
-    Not,
-    Equal,
-    Greater,
-    Less,
-    // remember to modify the following areas when adding
-    // a new enum variant:
-    //      - OpCode::try_from()
-    //      - tests::test_opcode_try_from()
+pub enum Operand {
+    // a single byte, e.g. the slot of a local
+    Byte,
+    // an unsigned LEB128 number of 1 to 5 bytes, for constant indices and
+    // jump offsets: 7 bits per byte, least significant first, with the high
+    // bit set on every byte but the last. small numbers stay one byte, while
+    // large programs are not capped by the width of the operand
+    Varint,
+}
+
+impl OpCode {
+    /// Whether only the VM makes the opcode, when it quickens an instruction.
+    pub fn is_quickened(self) -> bool {
+        matches!(
+            self,
+            OpCode::GetGlobalCached
+                | OpCode::SetGlobalCached
+                | OpCode::AddNumbers
+                | OpCode::SubtractNumbers
+                | OpCode::LessNumbers
+        )
+    }
 }
 
 impl TryFrom<u8> for OpCode {
     type Error = ();
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(OpCode::Return),
-            1 => Ok(OpCode::Constant),
-            2 => Ok(OpCode::Negate),
-            3 => Ok(OpCode::Add),
-            4 => Ok(OpCode::Subtract),
-            5 => Ok(OpCode::Multiply),
-            6 => Ok(OpCode::Divide),
-            7 => Ok(OpCode::Nil),
-            8 => Ok(OpCode::True),
-            9 => Ok(OpCode::False),
-            10 => Ok(OpCode::Not),
-            11 => Ok(OpCode::Equal),
-            12 => Ok(OpCode::Greater),
-            13 => Ok(OpCode::Less),
-            _ => Err(()),
-        }
+        OpCode::ALL.get(value as usize).copied().ok_or(())
+    }
+}
+
+/// Where in the source a byte of code comes from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    // the position that errors are reported at
+    pub line: u32,
+    pub column: u32,
+    // byte offsets of the source that the byte was compiled from, which can
+    // be more than the token at `line` and `column`, e.g. the whole of
+    // `a + b` for the OP_ADD of its `+`
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The name of a local variable, for the debugger to find it by. It is in
+/// scope while the code that runs comes from its range of the source, which
+/// stays true of the code whatever the optimizations do to it.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LocalName {
+    pub name: String,
+    // the slot in the frame of the function
+    pub slot: u8,
+    // byte offsets of the source, from the end of the declaration to the end
+    // of the scope
+    pub start: usize,
+    pub end: usize,
+}
+
+// a run of consecutive bytes in `code` that all share the same value (a line,
+// a column or a range of the source). the run ends where the next one starts
+// (or at the end of the code)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct Run<T> {
+    start: usize,
+    value: T,
+}
+
+fn push_run<T: PartialEq + Copy>(runs: &mut Vec<Run<T>>, start: usize, value: T) {
+    if runs.last().map(|run| run.value) != Some(value) {
+        runs.push(Run { start, value });
     }
 }
 
+fn find_run<T: Copy>(runs: &[Run<T>], i: usize) -> T {
+    // the runs are sorted by their start, so the run that contains `i`
+    // is the last one that starts at or before it
+    let run = runs.partition_point(|run| run.start <= i) - 1;
+    runs[run].value
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Chunk {
-    code: Vec<u8>,
+    // in cells, since the VM quickens the code of functions that it only has
+    // a shared reference to
+    code: Vec<Cell<u8>>,
     constants: ValueArray,
-    lines: Vec<u32>,
+    // run-length encoded, since consecutive bytes usually share the same line
+    lines: Vec<Run<u32>>,
+    // run-length encoded as well, for the same reason
+    columns: Vec<Run<u32>>,
+    // the start and end of the source range of each byte, likewise
+    ranges: Vec<Run<(usize, usize)>>,
+    local_names: Vec<LocalName>,
+    // how far the code grows the stack, once the VM has called it, which is
+    // forgotten whenever the code changes
+    stack_growth: OnceCell<usize>,
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -60,20 +208,110 @@ impl Chunk {
             code: vec![],
             constants: ValueArray::new(),
             lines: vec![],
+            columns: vec![],
+            ranges: vec![],
+            local_names: vec![],
+            stack_growth: OnceCell::new(),
         }
     }
 
-    pub fn write(&mut self, byte: u8, line: u32) {
-        self.code.push(byte);
-        self.lines.push(line);
+    // writes a byte whose range of the source is not known, which is left
+    // empty. the compiler always knows the range, so only tests do this
+    #[cfg(test)]
+    pub fn write(&mut self, byte: u8, line: u32, column: u32) {
+        let span = Span {
+            line,
+            column,
+            start: 0,
+            end: 0,
+        };
+        self.write_span(byte, span);
+    }
+
+    pub fn write_span(&mut self, byte: u8, span: Span) {
+        push_run(&mut self.lines, self.code.len(), span.line);
+        push_run(&mut self.columns, self.code.len(), span.column);
+        push_run(&mut self.ranges, self.code.len(), (span.start, span.end));
+        self.code.push(Cell::new(byte));
+        self.stack_growth.take();
+    }
+
+    /// The bytes of the buffers that the chunk has allocated, for the heap
+    /// dump.
+    pub fn heap_size(&self) -> usize {
+        self.code.capacity() * size_of::<Cell<u8>>()
+            + self.constants.capacity() * size_of::<Value>()
+            + self.lines.capacity() * size_of::<Run<u32>>()
+            + self.columns.capacity() * size_of::<Run<u32>>()
+            + self.ranges.capacity() * size_of::<Run<(usize, usize)>>()
+            + self.local_names.capacity() * size_of::<LocalName>()
+            + self
+                .local_names
+                .iter()
+                .map(|local| local.name.capacity())
+                .sum::<usize>()
     }
 
     pub fn get_code(&self, i: usize) -> u8 {
-        self.code[i]
+        self.code[i].get()
+    }
+
+    pub fn set_code(&mut self, i: usize, byte: u8) {
+        self.code[i].set(byte);
+        self.stack_growth.take();
+    }
+
+    // overwrites a byte while the chunk is running, see `Instruction::quicken`.
+    // the quickened forms have the same stack effects, so the growth stays
+    // the same
+    pub fn quicken_code(&self, i: usize, byte: u8) {
+        self.code[i].set(byte);
+    }
+
+    // drops every byte from `len` onwards, along with its position
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.stack_growth.take();
+        self.lines.retain(|run| run.start < len);
+        self.columns.retain(|run| run.start < len);
+        self.ranges.retain(|run| run.start < len);
     }
 
     pub fn get_line(&self, i: usize) -> u32 {
-        self.lines[i]
+        assert!(i < self.code.len(), "ICE: No line for offset {}", i);
+        find_run(&self.lines, i)
+    }
+
+    pub fn get_column(&self, i: usize) -> u32 {
+        assert!(i < self.code.len(), "ICE: No column for offset {}", i);
+        find_run(&self.columns, i)
+    }
+
+    /// Where in the source the byte at `i` comes from. Every byte of an
+    /// instruction has the same span.
+    pub fn get_span(&self, i: usize) -> Span {
+        assert!(i < self.code.len(), "ICE: No span for offset {}", i);
+        let (start, end) = find_run(&self.ranges, i);
+        Span {
+            line: find_run(&self.lines, i),
+            column: find_run(&self.columns, i),
+            start,
+            end,
+        }
+    }
+
+    // whether the byte at `i` is on a different line than the byte before it
+    pub fn starts_new_line(&self, i: usize) -> bool {
+        self.lines.binary_search_by_key(&i, |run| run.start).is_ok()
+    }
+
+    /// How many values the code pushes past the slots that it starts with,
+    /// at the most (see [`bytecode::stack_growth`]), which is only worked out
+    /// the first time that it is asked for.
+    pub fn stack_growth(&self) -> usize {
+        *self
+            .stack_growth
+            .get_or_init(|| bytecode::stack_growth(self))
     }
 
     pub fn code_len(&self) -> usize {
@@ -87,6 +325,30 @@ impl Chunk {
     pub fn constants_mut(&mut self) -> &mut ValueArray {
         &mut self.constants
     }
+
+    pub fn add_local_name(&mut self, local: LocalName) {
+        self.local_names.push(local);
+    }
+
+    pub fn local_names(&self) -> &[LocalName] {
+        &self.local_names
+    }
+
+    pub fn local_names_mut(&mut self) -> &mut Vec<LocalName> {
+        &mut self.local_names
+    }
+
+    /// The locals that are in scope at the instruction at `i`, by slot.
+    pub fn locals_at(&self, i: usize) -> Vec<&LocalName> {
+        let start = self.get_span(i).start;
+        let mut locals = self
+            .local_names
+            .iter()
+            .filter(|local| (local.start..local.end).contains(&start))
+            .collect::<Vec<_>>();
+        locals.sort_by_key(|local| local.slot);
+        locals
+    }
 }
 
 #[cfg(test)]
@@ -97,24 +359,7 @@ mod tests {
 
     #[test]
     fn test_opcode_try_from() {
-        [
-            OpCode::Return,
-            OpCode::Constant,
-            OpCode::Negate,
-            OpCode::Add,
-            OpCode::Subtract,
-            OpCode::Multiply,
-            OpCode::Divide,
-            OpCode::Nil,
-            OpCode::True,
-            OpCode::False,
-            OpCode::Not,
-            OpCode::Equal,
-            OpCode::Greater,
-            OpCode::Less,
-        ]
-        .into_iter()
-        .for_each(|opcode| {
+        OpCode::ALL.iter().for_each(|&opcode| {
             assert_eq!(
                 <u8 as TryInto::<OpCode>>::try_into(opcode as u8),
                 Ok(opcode),
@@ -123,19 +368,71 @@ mod tests {
             );
         });
 
+        assert_eq!(
+            <u8 as TryInto::<OpCode>>::try_into(OpCode::ALL.len() as u8),
+            Err(())
+        );
         assert_eq!(<u8 as TryInto::<OpCode>>::try_into(255), Err(()));
     }
 
+    #[test]
+    fn test_opcode_table() {
+        assert_eq!(OpCode::Return.mnemonic(), "OP_RETURN");
+        assert_eq!(
+            OpCode::GetLocalAddConstant.mnemonic(),
+            "OP_GET_LOCAL_ADD_CONSTANT"
+        );
+        assert_eq!(OpCode::Constant.operands(), [Operand::Varint]);
+        assert_eq!(
+            OpCode::GetLocalAddConstant.operands(),
+            [Operand::Byte, Operand::Varint]
+        );
+        assert_eq!(OpCode::Return.operands(), []);
+
+        // the mnemonics are the names of the variants in screaming snake case
+        OpCode::ALL.iter().for_each(|opcode| {
+            let name = format!("{:?}", opcode);
+            let mut expected = "OP".to_string();
+            name.chars().for_each(|c| {
+                if c.is_uppercase() {
+                    expected.push('_');
+                }
+                expected.push(c.to_ascii_uppercase());
+            });
+            assert_eq!(opcode.mnemonic(), expected);
+        });
+    }
+
     #[test]
     fn test_chunk_write() {
         let mut chunk = Chunk::new();
-        chunk.write(8, 155);
-        chunk.write(9, 156);
-        chunk.write(15, 156);
-        chunk.write(2, 157);
+        chunk.write(8, 155, 1);
+        chunk.write(9, 156, 1);
+        chunk.write(15, 156, 1);
+        chunk.write(2, 157, 1);
 
-        assert_eq!(chunk.code, vec![8, 9, 15, 2]);
-        assert_eq!(chunk.lines, vec![155, 156, 156, 157]);
+        assert_eq!(
+            chunk.code.iter().map(Cell::get).collect::<Vec<_>>(),
+            vec![8, 9, 15, 2]
+        );
+        assert_eq!(
+            chunk.lines,
+            vec![
+                Run {
+                    start: 0,
+                    value: 155
+                },
+                Run {
+                    start: 1,
+                    value: 156
+                },
+                Run {
+                    start: 3,
+                    value: 157
+                },
+            ]
+        );
+        assert_eq!(chunk.columns, vec![Run { start: 0, value: 1 }]);
 
         assert_eq!(chunk.get_code(0), 8);
         assert_eq!(chunk.get_code(1), 9);
@@ -150,6 +447,88 @@ mod tests {
         assert_eq!(chunk.code_len(), 4);
     }
 
+    #[test]
+    fn test_chunk_lines() {
+        let mut chunk = Chunk::new();
+        (0..100).for_each(|_| chunk.write(0, 1, 1));
+        chunk.write(0, 3, 1);
+        (0..50).for_each(|_| chunk.write(0, 2, 1));
+        // a line can appear again in a later run
+        chunk.write(0, 1, 1);
+
+        // one run per change of line, not one entry per byte
+        assert_eq!(chunk.lines.len(), 4);
+
+        assert_eq!(chunk.get_line(0), 1);
+        assert_eq!(chunk.get_line(99), 1);
+        assert_eq!(chunk.get_line(100), 3);
+        assert_eq!(chunk.get_line(101), 2);
+        assert_eq!(chunk.get_line(150), 2);
+        assert_eq!(chunk.get_line(151), 1);
+
+        assert!(chunk.starts_new_line(0));
+        assert!(!chunk.starts_new_line(1));
+        assert!(!chunk.starts_new_line(99));
+        assert!(chunk.starts_new_line(100));
+        assert!(chunk.starts_new_line(101));
+        assert!(!chunk.starts_new_line(102));
+        assert!(chunk.starts_new_line(151));
+    }
+
+    #[test]
+    fn test_chunk_columns() {
+        let mut chunk = Chunk::new();
+        chunk.write(0, 1, 1);
+        chunk.write(0, 1, 1);
+        chunk.write(0, 1, 5);
+        chunk.write(0, 2, 5);
+        chunk.write(0, 2, 1);
+
+        assert_eq!(chunk.columns.len(), 3);
+        assert_eq!(chunk.lines.len(), 2);
+
+        assert_eq!(chunk.get_column(0), 1);
+        assert_eq!(chunk.get_column(1), 1);
+        assert_eq!(chunk.get_column(2), 5);
+        assert_eq!(chunk.get_column(3), 5);
+        assert_eq!(chunk.get_column(4), 1);
+
+        assert_eq!(chunk.get_line(2), 1);
+        assert_eq!(chunk.get_line(3), 2);
+    }
+
+    #[test]
+    fn test_chunk_spans() {
+        let span = |line, column, start, end| Span {
+            line,
+            column,
+            start,
+            end,
+        };
+
+        let mut chunk = Chunk::new();
+        chunk.write_span(0, span(1, 3, 0, 5));
+        chunk.write_span(0, span(1, 3, 0, 5));
+        chunk.write_span(0, span(1, 3, 2, 3));
+        chunk.write(0, 2, 1);
+
+        assert_eq!(chunk.ranges.len(), 3);
+        assert_eq!(chunk.get_span(1), span(1, 3, 0, 5));
+        assert_eq!(chunk.get_span(2), span(1, 3, 2, 3));
+        assert_eq!(chunk.get_span(3), span(2, 1, 0, 0));
+
+        chunk.truncate(2);
+        assert_eq!(chunk.ranges.len(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_chunk_get_line_out_of_bounds() {
+        let mut chunk = Chunk::new();
+        chunk.write(0, 1, 1);
+        chunk.get_line(1);
+    }
+
     #[test]
     fn test_chunk_constants() {
         let mut chunk = Chunk::new();
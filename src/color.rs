@@ -0,0 +1,115 @@
+// The colors of the REPL on a terminal: the source as it is typed, the
+// values that it prints, and its errors. They are ANSI escape codes, which
+// show up as they are anywhere else.
+
+use alloc::{format, string::String};
+use core::fmt;
+
+use crate::{
+    scanner::{Scanner, TokenKind, TriviaKind},
+    value::Value,
+};
+
+const RESET: &str = "\x1b[0m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const MAGENTA: &str = "\x1b[35m";
+const CYAN: &str = "\x1b[36m";
+const GREY: &str = "\x1b[90m";
+
+// the colors of the source: keywords, literals and comments
+const KEYWORD: &str = MAGENTA;
+const LITERAL: &str = YELLOW;
+const STRING: &str = GREEN;
+const COMMENT: &str = GREY;
+const ERROR: &str = RED;
+
+/// `text` in the color of an error.
+pub fn error(text: impl fmt::Display) -> String {
+    paint(ERROR, text)
+}
+
+/// `value` as `print` shows it, in the color of its type.
+pub fn value(value: &Value) -> String {
+    let color = match value {
+        Value::Nil | Value::Bool(_) | Value::Number(_) => LITERAL,
+        _ if value.as_decimal().is_some() => LITERAL,
+        _ if value.as_string().is_some() => STRING,
+        _ => CYAN,
+    };
+    paint(color, value)
+}
+
+/// `source` with its keywords, literals and comments in color. Whatever the
+/// scanner can't make sense of is in the color of an error, other than a
+/// string that is still being typed.
+pub fn highlight(source: &str) -> String {
+    let mut highlighted = String::new();
+    for token in Scanner::new(source).lossless() {
+        for trivia in &token.leading {
+            match trivia.kind {
+                TriviaKind::Comment => highlighted.push_str(&paint(COMMENT, trivia.text)),
+                _ => highlighted.push_str(trivia.text),
+            }
+        }
+
+        let color = match token.token.kind {
+            TokenKind::And
+            | TokenKind::Class
+            | TokenKind::Else
+            | TokenKind::For
+            | TokenKind::Fun
+            | TokenKind::If
+            | TokenKind::Or
+            | TokenKind::Print
+            | TokenKind::Return
+            | TokenKind::Super
+            | TokenKind::This
+            | TokenKind::Var
+            | TokenKind::While => Some(KEYWORD),
+            TokenKind::False | TokenKind::Nil | TokenKind::True | TokenKind::Number => {
+                Some(LITERAL)
+            }
+            TokenKind::String | TokenKind::RawString => Some(STRING),
+            TokenKind::Error if token.token.lexeme == "Unterminated string." => Some(STRING),
+            TokenKind::Error => Some(ERROR),
+            _ => None,
+        };
+        match color {
+            Some(color) if !token.text.is_empty() => {
+                highlighted.push_str(&paint(color, token.text))
+            }
+            _ => highlighted.push_str(token.text),
+        }
+    }
+    highlighted
+}
+
+fn paint(color: &str, text: impl fmt::Display) -> String {
+    format!("{}{}{}", color, text, RESET)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(
+            highlight("var a = \"hi\"; // greet\nprint a or nil;"),
+            format!(
+                "{KEYWORD}var{RESET} a = {STRING}\"hi\"{RESET}; {COMMENT}// greet{RESET}\n{KEYWORD}print{RESET} a {KEYWORD}or{RESET} {LITERAL}nil{RESET};"
+            )
+        );
+        assert_eq!(
+            highlight("1 @ \"a"),
+            format!("{LITERAL}1{RESET} {ERROR}@{RESET} {STRING}\"a{RESET}")
+        );
+        // the text is the same without the colors
+        assert_eq!(
+            highlight("  fun f() {}  \n"),
+            format!("  {KEYWORD}fun{RESET} f() {{}}  \n")
+        );
+    }
+}
@@ -0,0 +1,237 @@
+// Differential testing against another implementation of Lox, like jlox or
+// the clox of the book, for `clox run --compare-with <command>`. The script
+// runs on both, and what each printed to stdout and the exit codes that they
+// ended with are compared:
+//
+//   clox run --compare-with "java -cp build com.craftinginterpreters.lox.Lox" test.lox
+//
+// The command is split on whitespace, and gets the path of the script and
+// its arguments after its own. A script on stdin is piped to it instead.
+
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+    thread,
+};
+
+use crate::vm::InterpretError;
+
+// how many pairs of lines the differing part of the outputs can have before
+// they are shown as one block taken out and another put in
+const MAX_DIFF_CELLS: usize = 1 << 20;
+
+/// What a script printed to stdout, and the exit code it ended with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: String,
+    pub exit_code: i32,
+}
+
+impl Outcome {
+    /// The outcome of a script that clox ran, with what it printed.
+    pub fn new(output: &[u8], result: Result<(), InterpretError>) -> Self {
+        Outcome {
+            output: String::from_utf8_lossy(output).into_owned(),
+            exit_code: result.map_or_else(|error| error.exit_code(), |_| 0),
+        }
+    }
+}
+
+/// Runs the script at `path` with the reference `command`, or pipes `source`
+/// to it if the path is `-`. What it reports to stderr goes to ours.
+pub fn run_reference(
+    command: &str,
+    path: &str,
+    source: &str,
+    args: &[String],
+) -> io::Result<Outcome> {
+    let mut words = command.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+    let mut reference = Command::new(program);
+    reference.args(words).stderr(Stdio::inherit());
+    if path == "-" {
+        reference.stdin(Stdio::piped());
+    } else {
+        reference.arg(path).stdin(Stdio::null());
+    }
+    let mut child = reference.args(args).stdout(Stdio::piped()).spawn()?;
+
+    // written from a thread of its own, so that a script that prints more
+    // than the pipe holds before it has read all of the source can't stall
+    let writer = child.stdin.take().map(|mut stdin| {
+        let source = source.to_string();
+        thread::spawn(move || {
+            // it may exit without reading all of it
+            let _ = stdin.write_all(source.as_bytes());
+        })
+    });
+    let output = child.wait_with_output()?;
+    if let Some(writer) = writer {
+        writer.join().expect("ICE: The writer of stdin panicked");
+    }
+
+    Ok(Outcome {
+        output: String::from_utf8_lossy(&output.stdout).into_owned(),
+        // killed by a signal, like the shell has it
+        exit_code: output.status.code().unwrap_or_else(|| {
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::ExitStatusExt;
+                if let Some(signal) = output.status.signal() {
+                    return 128 + signal;
+                }
+            }
+            -1
+        }),
+    })
+}
+
+/// Compares the outcome of clox with the outcome of the reference `command`,
+/// returning a report of how they differ, or `None` if they are the same.
+pub fn compare(ours: &Outcome, theirs: &Outcome, command: &str) -> Option<String> {
+    if ours == theirs {
+        return None;
+    }
+
+    let mut report = String::new();
+    if ours.output != theirs.output {
+        report.push_str(&format!("--- clox\n+++ {}\n", command));
+        report.push_str(&diff(&ours.output, &theirs.output));
+    }
+    if ours.exit_code != theirs.exit_code {
+        report.push_str(&format!(
+            "Exit code {} from clox, but {} from {}.\n",
+            ours.exit_code, theirs.exit_code, command
+        ));
+    }
+    Some(report)
+}
+
+// the lines that only `ours` has with a '-', and the ones only `theirs` has
+// with a '+', after the line of the first output where they start to differ
+fn diff(ours: &str, theirs: &str) -> String {
+    let ours = lines(ours);
+    let theirs = lines(theirs);
+
+    let prefix = ours.iter().zip(&theirs).take_while(|(a, b)| a == b).count();
+    let suffix = ours[prefix..]
+        .iter()
+        .rev()
+        .zip(theirs[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let ours = &ours[prefix..ours.len() - suffix];
+    let theirs = &theirs[prefix..theirs.len() - suffix];
+
+    let mut diff = format!("@@ line {} @@\n", prefix + 1);
+    if ours.len().saturating_mul(theirs.len()) > MAX_DIFF_CELLS {
+        for line in ours {
+            diff.push_str(&format!("-{}\n", line));
+        }
+        for line in theirs {
+            diff.push_str(&format!("+{}\n", line));
+        }
+        return diff;
+    }
+
+    // the longest common subsequence of each pair of tails
+    let width = theirs.len() + 1;
+    let mut common = vec![0; (ours.len() + 1) * width];
+    for i in (0..ours.len()).rev() {
+        for j in (0..theirs.len()).rev() {
+            common[i * width + j] = if ours[i] == theirs[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < ours.len() || j < theirs.len() {
+        if i < ours.len() && j < theirs.len() && ours[i] == theirs[j] {
+            diff.push_str(&format!(" {}\n", ours[i]));
+            i += 1;
+            j += 1;
+        } else if j == theirs.len()
+            || (i < ours.len() && common[(i + 1) * width + j] >= common[i * width + j + 1])
+        {
+            diff.push_str(&format!("-{}\n", ours[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("+{}\n", theirs[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+// the lines of an output, where a last line without a newline is marked so
+// that it differs from the same line with one
+fn lines(output: &str) -> Vec<String> {
+    let mut lines = output.lines().map(str::to_string).collect::<Vec<_>>();
+    if !output.ends_with('\n')
+        && let Some(last) = lines.last_mut()
+    {
+        last.push_str(" (no newline at the end)");
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(output: &str, exit_code: i32) -> Outcome {
+        Outcome {
+            output: output.to_string(),
+            exit_code,
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(
+            compare(&outcome("1\n2\n", 0), &outcome("1\n2\n", 0), "jlox"),
+            None
+        );
+        assert_eq!(
+            compare(
+                &outcome("1\n2\n3\n4\n", 0),
+                &outcome("1\n3\n4\n5\n", 0),
+                "jlox"
+            ),
+            Some("--- clox\n+++ jlox\n@@ line 2 @@\n-2\n 3\n 4\n+5\n".to_string())
+        );
+        assert_eq!(
+            compare(&outcome("1\n", 70), &outcome("1\n", 0), "jlox"),
+            Some("Exit code 70 from clox, but 0 from jlox.\n".to_string())
+        );
+        assert_eq!(
+            compare(&outcome("1", 0), &outcome("1\n", 0), "jlox"),
+            Some("--- clox\n+++ jlox\n@@ line 1 @@\n-1 (no newline at the end)\n+1\n".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_reference() {
+        let source = "print 1;\n";
+        assert_eq!(
+            run_reference("cat", "-", source, &[]).expect("cat runs"),
+            outcome(source, 0)
+        );
+        let args = ["a".to_string(), "b".to_string()];
+        assert_eq!(
+            run_reference("echo -n", "x.lox", "", &args).expect("echo runs"),
+            outcome("x.lox a b", 0)
+        );
+        assert_eq!(
+            run_reference("sh -c", "exit 65", "", &[]).expect("sh runs"),
+            outcome("", 65)
+        );
+        assert!(run_reference("", "x.lox", "", &[]).is_err());
+        assert!(run_reference("/nonexistent/lox", "x.lox", "", &[]).is_err());
+    }
+}
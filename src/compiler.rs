@@ -1,386 +1,1931 @@
-use std::io;
+use alloc::{format, string::ToString, vec, vec::Vec};
+use core::mem;
 
 use crate::{
-    chunk::{Chunk, OpCode},
+    ast::{Expr, Function, Literal, Program, Stmt},
+    builder::{ChunkBuilder, Label, Op},
+    chunk::{Chunk, LocalName, OpCode, Span},
     debug,
+    decimal::Decimal,
+    diagnostic::{Diagnostic, Severity},
+    fold,
+    instruction::Instruction,
+    io::{self, Write},
+    object::{Heap, ObjFunction},
+    parser::Parser,
+    passes, peephole,
     scanner::{Scanner, Token, TokenKind},
     value::Value,
 };
 
-struct Parser {
-    previous: Token,
-    current: Token,
-    // whether the error has appeared at any time in the compilation
-    had_error: bool,
-    // once the parser encounters an error, panic mode is enabled and error
-    // recovery is attempted. Once error recovery is done, this is set back
-    // to false. Hence, this boolean cannot tell whether an error happened in the
-    // code at all. For that, use `had_error` instead.
-    panic_mode: bool,
+// local slots are addressed by a single byte operand
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+struct Local<'src> {
+    name: Token<'src>,
+    // `None` while the variable's initializer is still being compiled
+    depth: Option<usize>,
+    // whether the variable is ever read, for the unused variable warning
+    used: bool,
+    // where in the source the variable starts to hold its value, for the
+    // debugger
+    start: usize,
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-enum Precedence {
-    None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,
+impl Local<'_> {
+    // the name that the debugger finds the local in `slot` by, for as long as
+    // the source up to `end` runs
+    fn debug_name(&self, slot: usize, end: usize) -> LocalName {
+        LocalName {
+            name: self.name.lexeme.to_string(),
+            // always fits, since `add_local` caps the number of locals
+            slot: slot as u8,
+            start: self.start,
+            end,
+        }
+    }
 }
 
-impl Precedence {
-    fn plus_one(&self) -> Precedence {
-        match self {
-            Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
-            Precedence::Or => Precedence::And,
-            Precedence::And => Precedence::Equality,
-            Precedence::Equality => Precedence::Comparison,
-            Precedence::Comparison => Precedence::Term,
-            Precedence::Term => Precedence::Factor,
-            Precedence::Factor => Precedence::Unary,
-            Precedence::Unary => Precedence::Call,
-            Precedence::Call => Precedence::Primary,
-            Precedence::Primary => {
-                // nothing higher than Primary
-                Precedence::Primary
-            }
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum FunctionType {
+    Function,
+    Script,
+}
+
+// the state of the function that is being compiled, which is set aside
+// while a function nested inside of it is compiled
+struct FunctionState<'src> {
+    function_type: FunctionType,
+    // locals that are in scope, in the order that they are declared, which
+    // is also the order of their slots on the stack
+    locals: Vec<Local<'src>>,
+    scope_depth: usize,
+    terminated: Terminated,
+}
+
+// whether the code being compiled can no longer be reached
+#[derive(Debug, Clone, Copy, Default)]
+struct Terminated {
+    // because of a `return` earlier in the same block, or in both branches
+    // of an if. this is what warnings go by, so that they are the same
+    // whatever the options
+    returned: bool,
+    // because of that, or of a branch that the optimizer knows is never
+    // taken, which is what dead code elimination goes by
+    dead: bool,
+}
+
+impl Terminated {
+    const RETURNED: Self = Self {
+        returned: true,
+        dead: true,
+    };
+
+    // after two branches, of which either one can run
+    fn both(self, other: Self) -> Self {
+        Self {
+            returned: self.returned && other.returned,
+            dead: self.dead && other.dead,
         }
     }
-}
 
-pub struct Compiler {
-    scanner: Scanner,
-    parser: Parser,
+    fn or(self, other: Self) -> Self {
+        Self {
+            returned: self.returned || other.returned,
+            dead: self.dead || other.dead,
+        }
+    }
 }
 
-impl Compiler {
-    pub fn compile(source: String) -> Result<Chunk, ()> {
-        let mut compiler = Self {
-            scanner: Scanner::new(source),
-            parser: Parser {
-                previous: Token {
-                    kind: TokenKind::Error,
-                    lexeme: "Nothing is read yet.".to_string(),
-                    line: 0,
-                },
-                current: Token {
-                    kind: TokenKind::Error,
-                    lexeme: "Nothing is read yet.".to_string(),
+impl FunctionState<'_> {
+    fn new(function_type: FunctionType) -> Self {
+        Self {
+            function_type,
+            // the first slot holds the function that is being called
+            locals: vec![Local {
+                name: Token {
+                    kind: TokenKind::Identifier,
+                    lexeme: "",
                     line: 0,
+                    column: 0,
+                    start: 0,
+                    end: 0,
                 },
-                had_error: false,
-                panic_mode: false,
-            },
-        };
+                depth: Some(0),
+                used: true,
+                start: 0,
+            }],
+            scope_depth: 0,
+            terminated: Terminated::default(),
+        }
+    }
+}
 
-        let mut chunk = Chunk::new();
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+pub struct CompileOptions {
+    // report warnings as errors, failing the compilation
+    pub deny_warnings: bool,
+    // evaluate operators on constant operands at compile time
+    pub fold_constants: bool,
+    // leave out code that can never run
+    pub eliminate_dead_code: bool,
+    // run the passes over the control flow graph of the finished bytecode
+    pub ir_passes: bool,
+    // rewrite the finished bytecode into shorter sequences that do the same
+    pub peephole: bool,
+    // fuse common instruction sequences into superinstructions
+    pub superinstructions: bool,
+    // load 0, 1 and -1 with their own instructions instead of from the
+    // constant pool
+    pub small_constants: bool,
+    // pop the locals of a scope with a single OP_POP_N, instead of one
+    // OP_POP each
+    pub pop_n: bool,
+    // compile `a >= b` and `a <= b` into `!(a < b)` and `!(a > b)` like the
+    // book does, which makes comparisons with NaN true
+    pub book_comparisons: bool,
+    // compile number literals into decimals with as many digits as they are
+    // written with, so that the VM does exact arithmetic on them
+    pub decimal: bool,
+    // print the bytecode of every function once it is compiled, which is
+    // also turned on by setting DEBUG_PRINT_CODE=1
+    pub print_code: bool,
+    // print the value of each expression statement at the top level of the
+    // script, other than an assignment, like the REPL does
+    pub print_expressions: bool,
+    // also define each value that is printed that way as the globals `_`
+    // and `_<n>`, counting up from this number, for the REPL to keep them
+    pub first_result: Option<u32>,
+}
 
-        compiler.advance();
-        compiler.expression(&mut chunk);
-        compiler.consume(TokenKind::EndOfFile, "Expect end of expression.");
-        compiler.end_compiler(&mut chunk);
+/// How much the compiler optimizes, as picked with `-O0`, `-O1` and `-O2`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OptLevel {
+    // none at all, the bytecode is instruction for instruction the one
+    // that the book's compiler emits
+    O0,
+    // the rewrites that only look at one expression or a few instructions
+    // at a time
+    O1,
+    // every pass, including the ones over the control flow graph
+    O2,
+}
 
-        if compiler.parser.had_error {
-            Err(())
-        } else {
-            Ok(chunk)
+impl CompileOptions {
+    /// Turns on the optimizations of `level`, and turns off the ones above
+    /// it. The options that are not optimizations are kept as they are.
+    pub fn opt_level(self, level: OptLevel) -> Self {
+        let at_least = |minimum| level as u8 >= minimum as u8;
+        Self {
+            fold_constants: at_least(OptLevel::O1),
+            eliminate_dead_code: at_least(OptLevel::O1),
+            peephole: at_least(OptLevel::O1),
+            small_constants: at_least(OptLevel::O1),
+            pop_n: at_least(OptLevel::O1),
+            ir_passes: at_least(OptLevel::O2),
+            superinstructions: at_least(OptLevel::O2),
+            ..self
         }
     }
+}
 
-    fn advance(&mut self) {
-        self.parser.previous = self.parser.current.clone();
+// where the bytecode of an expression starts, so that it can be thrown away
+// and replaced when the expression is folded into a constant
+#[derive(Debug, Clone, Copy)]
+struct ExprStart {
+    code: usize,
+    constants: usize,
+}
 
-        loop {
-            self.parser.current = self.scanner.scan_token();
-            if matches!(self.parser.current.kind, TokenKind::Error) {
-                let message = self.parser.current.lexeme.clone();
-                self.error_at_current(message);
-            } else {
-                break;
-            }
-        }
+/// Generates the bytecode of a [`Program`] that was produced by the
+/// [`Parser`]. Every instruction is attributed to the same token that a
+/// single pass compiler would have been looking at when emitting it.
+pub struct Compiler<'a, 'src> {
+    // string constants are allocated here, so that they are owned by the VM
+    heap: &'a mut Heap,
+    options: CompileOptions,
+    function: FunctionState<'src>,
+    // whether the error has appeared at any time in the compilation,
+    // including the syntax errors found by the parser
+    had_error: bool,
+    // only the first error of a declaration is reported, same as the parser
+    // does for syntax errors
+    panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
+    // the number of the next printed value, see `first_result`
+    next_result: Option<u32>,
+}
+
+impl<'a, 'src> Compiler<'a, 'src> {
+    /// Compiles `source` into the chunk of the script, or returns every
+    /// diagnostic if there were errors. The warnings of a compilation that
+    /// succeeds are left out, see [`Compiler::compile_with_warnings`].
+    pub fn compile(
+        source: &'src str,
+        heap: &'a mut Heap,
+        options: CompileOptions,
+    ) -> Result<Chunk, Vec<Diagnostic>> {
+        Self::compile_with_warnings(source, heap, options).map(|(chunk, _)| chunk)
     }
 
-    fn consume<S: AsRef<str>>(&mut self, token_kind: TokenKind, message: S) {
-        if self.parser.current.kind == token_kind {
-            self.advance();
+    /// Like [`Compiler::compile`], but the warnings are returned along with
+    /// the chunk. Either way the diagnostics are in the order of the source,
+    /// and nothing is printed.
+    pub fn compile_with_warnings(
+        source: &'src str,
+        heap: &'a mut Heap,
+        options: CompileOptions,
+    ) -> Result<(Chunk, Vec<Diagnostic>), Vec<Diagnostic>> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("compile", bytes = source.len()).entered();
+        let mut parser = Parser::new(source);
+        let program = parser.parse();
+
+        let mut compiler = Self::new(heap, options);
+        // the declarations without syntax errors are still compiled, so that
+        // their errors and warnings are reported too
+        compiler.had_error = parser.had_error();
+        let builder = compiler.program(&program);
+
+        let mut diagnostics = parser.take_diagnostics();
+        diagnostics.append(&mut compiler.diagnostics);
+        // both stages report in the order that they find problems, which is
+        // not the order of the source (e.g. unused locals are only known at
+        // the end of their scope)
+        diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.column));
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            errors = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .count(),
+            warnings = diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+                .count(),
+            "compiled"
+        );
+
+        if compiler.had_error {
+            Err(diagnostics)
         } else {
-            self.error_at_current(message);
+            Ok((builder, diagnostics))
         }
     }
 
-    fn emit_byte(&self, chunk: &mut Chunk, byte: u8) {
-        chunk.write(byte, self.parser.previous.line as u32);
+    /// Runs the whole front end over `source` without keeping the bytecode,
+    /// for checking code that is not run. Returns the warnings, or every
+    /// diagnostic if there were errors, including every lexical error, and
+    /// not only the ones that the parser reports.
+    pub fn check(
+        source: &'src str,
+        options: CompileOptions,
+    ) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+        let lexical_errors = Scanner::new(source).lexical_errors();
+
+        // nothing that is allocated along the way outlives the compilation
+        Compiler::compile_with_warnings(source, &mut Heap::new(), options)
+            .map(|(_, warnings)| warnings)
+            .map_err(|mut diagnostics| {
+                for token in &lexical_errors {
+                    let error = Diagnostic::at_token(Severity::Error, token, token.lexeme, None);
+                    if !diagnostics.contains(&error) {
+                        diagnostics.push(error);
+                    }
+                }
+                diagnostics
+                    .sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.column));
+                diagnostics
+            })
     }
 
-    fn emit_bytes(&self, chunk: &mut Chunk, bytes: &[u8]) {
-        bytes.iter().for_each(|byte| self.emit_byte(chunk, *byte));
+    fn new(heap: &'a mut Heap, options: CompileOptions) -> Self {
+        Self {
+            heap,
+            options,
+            function: FunctionState::new(FunctionType::Script),
+            had_error: false,
+            panic_mode: false,
+            diagnostics: vec![],
+            next_result: options.first_result,
+        }
     }
 
-    fn end_compiler(&self, chunk: &mut Chunk) {
-        self.emit_return(chunk);
+    fn program(&mut self, program: &Program<'src>) -> Chunk {
+        let mut builder = ChunkBuilder::new();
 
-        if debug::is_debug_print_code_enabled() && !self.parser.had_error {
-            debug::disassemble_chunk(&mut io::stdout(), chunk, "code");
+        for stmt in &program.statements {
+            self.declaration(&mut builder, stmt);
         }
+        self.end_compiler(builder, "<script>", &program.end)
     }
 
-    fn binary(&mut self, chunk: &mut Chunk) {
-        let operator_type = self.parser.previous.kind;
-        self.parse_precedence(chunk, self.get_rule_precedence(operator_type).plus_one());
+    fn emit(&mut self, builder: &mut ChunkBuilder, op: Op, token: &Token) {
+        self.emit_over(builder, op, token, token, token);
+    }
 
-        match operator_type {
-            TokenKind::Plus => {
-                self.emit_byte(chunk, OpCode::Add as u8);
-            }
-            TokenKind::Minus => {
-                self.emit_byte(chunk, OpCode::Subtract as u8);
-            }
-            TokenKind::Star => {
-                self.emit_byte(chunk, OpCode::Multiply as u8);
-            }
-            TokenKind::Slash => {
-                self.emit_byte(chunk, OpCode::Divide as u8);
-            }
-            TokenKind::BangEqual => {
-                self.emit_bytes(chunk, &[OpCode::Equal as u8, OpCode::Not as u8]);
-            }
-            TokenKind::EqualEqual => {
-                self.emit_byte(chunk, OpCode::Equal as u8);
+    // like `emit`, but the instruction covers the source from `first` to
+    // `last`, e.g. the whole of `a + b` for the OP_ADD. the line and column
+    // still come from `token`
+    fn emit_over(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        op: Op,
+        token: &Token,
+        first: &Token,
+        last: &Token,
+    ) {
+        let span = Span {
+            start: first.start,
+            end: last.end,
+            ..token.into()
+        };
+        if let Err(error) = builder.emit(op, span) {
+            self.error_at(token, error.to_string());
+        }
+    }
+
+    fn emit_op(&mut self, builder: &mut ChunkBuilder, op: OpCode, token: &Token) {
+        self.emit(builder, Op::Simple(op), token);
+    }
+
+    fn emit_ops(&mut self, builder: &mut ChunkBuilder, ops: &[OpCode], token: &Token) {
+        ops.iter().for_each(|op| self.emit_op(builder, *op, token));
+    }
+
+    // `end` is the last token of the function, which its implicit return is
+    // attributed to
+    fn end_compiler(&mut self, mut builder: ChunkBuilder, name: &str, end: &Token) -> Chunk {
+        self.emit_return(&mut builder, end);
+
+        let mut chunk = match builder.finish() {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                self.error_at(end, error.to_string());
+                // it is never run, since the compilation failed
+                return Chunk::new();
             }
-            TokenKind::Greater => {
-                self.emit_byte(chunk, OpCode::Greater as u8);
+        };
+
+        if self.options.ir_passes && !self.had_error {
+            passes::pipeline().run_on_chunk(&mut chunk);
+        }
+        if self.options.peephole && !self.had_error {
+            peephole::optimize(&mut chunk);
+        }
+        if self.options.superinstructions && !self.had_error {
+            peephole::fuse(&mut chunk);
+        }
+
+        if self.print_code() && !self.had_error {
+            debug::disassemble_chunk(&mut io::stdout(), &chunk, name);
+        }
+
+        chunk
+    }
+
+    // a chain like `a + b + c` nests on its left operand, so it is compiled
+    // in a loop instead of recursively, or a long chain would overflow the stack
+    fn binary(&mut self, builder: &mut ChunkBuilder, expr: &Expr) {
+        let mut operations = vec![];
+        let mut left = expr;
+        while let Expr::Binary {
+            operator,
+            left: operand,
+            right,
+        } = left
+        {
+            operations.push((operator, right));
+            left = operand;
+        }
+
+        let start = self.expr_start(builder);
+        let first = left.first_token();
+        self.expression(builder, left);
+        for (operator, right) in operations.into_iter().rev() {
+            let right_start = builder.code_len();
+            self.expression(builder, right);
+            self.binary_operator(
+                builder,
+                operator,
+                start,
+                right_start,
+                (first, right.last_token()),
+            );
+        }
+    }
+
+    // `start` is where the left operand starts, and `right_start` is where
+    // the right one does. `tokens` are the first and last tokens of the
+    // whole expression
+    fn binary_operator(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        operator: &Token,
+        start: ExprStart,
+        right_start: usize,
+        (first, last): (&Token, &Token),
+    ) {
+        if self.options.fold_constants {
+            let left = self.constant_at(builder, start.code, right_start);
+            let right = self.constant_at(builder, right_start, builder.code_len());
+            if let (Some(a), Some(b)) = (left, right)
+                && let Some(value) = fold::fold_binary(
+                    operator.kind,
+                    a,
+                    b,
+                    self.options.book_comparisons,
+                    self.heap,
+                )
+            {
+                self.replace_with_constant(builder, start, value, (operator, first, last));
+                return;
             }
+        }
+
+        let ops: &[OpCode] = match operator.kind {
+            TokenKind::Plus => &[OpCode::Add],
+            TokenKind::Minus => &[OpCode::Subtract],
+            TokenKind::Star => &[OpCode::Multiply],
+            TokenKind::Slash => &[OpCode::Divide],
+            TokenKind::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenKind::EqualEqual => &[OpCode::Equal],
+            TokenKind::Greater => &[OpCode::Greater],
             // this desugaring means that "NaN >= 1" will be true, violating IEEE-754 where it
             // should be false. this is done intentionally by the book to make implementation
             // simpler
-            TokenKind::GreaterEqual => {
-                self.emit_bytes(chunk, &[OpCode::Less as u8, OpCode::Not as u8]);
-            }
-            TokenKind::Less => {
-                self.emit_byte(chunk, OpCode::Less as u8);
+            TokenKind::GreaterEqual if self.options.book_comparisons => {
+                &[OpCode::Less, OpCode::Not]
             }
+            TokenKind::GreaterEqual => &[OpCode::GreaterEqual],
+            TokenKind::Less => &[OpCode::Less],
             // this desugaring means that "NaN <= 1" will be true, violating IEEE-754 where it
             // should be false. this is done intentionally by the book to make implementation
             // simpler
-            TokenKind::LessEqual => {
-                self.emit_bytes(chunk, &[OpCode::Greater as u8, OpCode::Not as u8]);
+            TokenKind::LessEqual if self.options.book_comparisons => {
+                &[OpCode::Greater, OpCode::Not]
             }
+            TokenKind::LessEqual => &[OpCode::LessEqual],
             _ => {
                 panic!("ICE: Unhandled binary");
             }
+        };
+        for op in ops {
+            self.emit_over(builder, Op::Simple(*op), operator, first, last);
+        }
+    }
+
+    fn call(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        callee: &Expr,
+        paren: &Token,
+        arguments: &[Expr],
+        close: &Token,
+    ) {
+        self.expression(builder, callee);
+        for argument in arguments {
+            self.expression(builder, argument);
+        }
+
+        // the parser already reported more than 255 arguments
+        let arg_count = arguments.len().min(u8::MAX as usize) as u8;
+        self.emit_over(
+            builder,
+            Op::Byte(OpCode::Call, arg_count),
+            paren,
+            callee.first_token(),
+            close,
+        );
+    }
+
+    fn literal(&mut self, builder: &mut ChunkBuilder, value: Literal, token: &Token) {
+        let instruction = match value {
+            Literal::False => OpCode::False,
+            Literal::True => OpCode::True,
+            Literal::Nil => OpCode::Nil,
+        };
+        self.emit_op(builder, instruction, token);
+    }
+
+    fn string(&mut self, builder: &mut ChunkBuilder, value: &str, token: &Token) {
+        let string = self.heap.copy_string(value);
+        self.emit_constant(builder, Value::Obj(string), token);
+    }
+
+    fn unary(&mut self, builder: &mut ChunkBuilder, operator: &Token, operand: &Expr) {
+        let start = self.expr_start(builder);
+        self.expression(builder, operand);
+
+        if self.options.fold_constants
+            && let Some(constant) = self.constant_at(builder, start.code, builder.code_len())
+            && let Some(value) = fold::fold_unary(operator.kind, constant)
+        {
+            self.replace_with_constant(
+                builder,
+                start,
+                value,
+                (operator, operator, operand.last_token()),
+            );
+            return;
+        }
+
+        let op = match operator.kind {
+            TokenKind::Minus => OpCode::Negate,
+            TokenKind::Bang => OpCode::Not,
+            _ => {
+                panic!("ICE: Unhandled unary.");
+            }
+        };
+        self.emit_over(
+            builder,
+            Op::Simple(op),
+            operator,
+            operator,
+            operand.last_token(),
+        );
+    }
+
+    // emits a jump to a new label, which is defined by `patch_jump` once the
+    // target is known
+    fn emit_jump(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        instruction: OpCode,
+        token: &Token,
+    ) -> Label {
+        let label = builder.new_label();
+        let op = match instruction {
+            OpCode::Jump => Op::Jump(label),
+            OpCode::JumpIfFalse => Op::JumpIfFalse(label),
+            _ => panic!("ICE: {:?} is not a forward jump", instruction),
+        };
+        self.emit(builder, op, token);
+        label
+    }
+
+    // `token` is where the jump lands, for the error if it is too far
+    fn patch_jump(&mut self, builder: &mut ChunkBuilder, label: Label, token: &Token) {
+        if let Err(error) = builder.define_label(label) {
+            self.error_at(token, error.to_string());
+        }
+    }
+
+    fn emit_loop(&mut self, builder: &mut ChunkBuilder, loop_start: Label, token: &Token) {
+        self.emit(builder, Op::Loop(loop_start), token);
+    }
+
+    // returns nil, for when the end of a function is reached
+    fn emit_return(&mut self, builder: &mut ChunkBuilder, token: &Token) {
+        self.emit_ops(builder, &[OpCode::Nil, OpCode::Return], token);
+    }
+
+    fn emit_constant(&mut self, builder: &mut ChunkBuilder, value: Value, token: &Token) {
+        let op = self.constant_op(builder, value);
+        self.emit(builder, op, token);
+    }
+
+    // the instruction that loads `value`, which only needs a slot in the
+    // constant pool if it is not one of the small constants
+    fn constant_op(&self, builder: &mut ChunkBuilder, value: Value) -> Op {
+        match value {
+            // -0 is equal to 0, but it is a different number
+            Value::Number(number)
+                if self.options.small_constants && number == 0.0 && number.is_sign_positive() =>
+            {
+                Op::Simple(OpCode::Zero)
+            }
+            Value::Number(1.0) if self.options.small_constants => Op::Simple(OpCode::One),
+            Value::Number(-1.0) if self.options.small_constants => Op::Simple(OpCode::MinusOne),
+            _ => Op::Constant(builder.add_constant(value)),
+        }
+    }
+
+    fn print_code(&self) -> bool {
+        self.options.print_code || debug::is_debug_print_code_enabled()
+    }
+
+    fn expr_start(&self, builder: &ChunkBuilder) -> ExprStart {
+        ExprStart {
+            code: builder.code_len(),
+            constants: builder.chunk().constants().len(),
+        }
+    }
+
+    // the value loaded by the instruction that spans exactly `start..end`, if
+    // it is an instruction that loads a constant
+    fn constant_at(&self, builder: &ChunkBuilder, start: usize, end: usize) -> Option<Value> {
+        let chunk = builder.chunk();
+        if start >= end {
+            // nothing was emitted, because of a compile error
+            return None;
+        }
+
+        let (instruction, next) = Instruction::decode(chunk, start)?;
+        if next != end {
+            return None;
+        }
+        match instruction {
+            Instruction::Nil => Some(Value::Nil),
+            Instruction::True => Some(Value::Bool(true)),
+            Instruction::False => Some(Value::Bool(false)),
+            Instruction::Zero => Some(Value::Number(0.0)),
+            Instruction::One => Some(Value::Number(1.0)),
+            Instruction::MinusOne => Some(Value::Number(-1.0)),
+            Instruction::Constant(index) => Some(chunk.constants().get(index as usize)),
+            _ => None,
+        }
+    }
+
+    // throws away the bytecode (and its constants) of the expression that
+    // starts at `start`, and loads `value` instead. `tokens` are the token
+    // of the operator, and the first and last tokens of the expression
+    fn replace_with_constant(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        start: ExprStart,
+        value: Value,
+        (token, first, last): (&Token, &Token, &Token),
+    ) {
+        builder.truncate(start.code, start.constants);
+
+        let op = match value {
+            Value::Nil => Op::Simple(OpCode::Nil),
+            Value::Bool(true) => Op::Simple(OpCode::True),
+            Value::Bool(false) => Op::Simple(OpCode::False),
+            _ => self.constant_op(builder, value),
+        };
+        self.emit_over(builder, op, token, first, last);
+    }
+
+    fn identifier_constant(&mut self, builder: &mut ChunkBuilder, name: &Token) -> usize {
+        self.name_constant(builder, name.lexeme)
+    }
+
+    // like `identifier_constant`, for a name that isn't in the source
+    fn name_constant(&mut self, builder: &mut ChunkBuilder, name: &str) -> usize {
+        let string = self.heap.copy_string(name);
+        builder.add_name(string)
+    }
+
+    fn add_local(&mut self, name: &Token<'src>) {
+        if self.function.locals.len() == UINT8_COUNT {
+            self.error_at(name, "Too many local variables in function.");
+            return;
         }
+
+        self.function.locals.push(Local {
+            name: name.clone(),
+            depth: None,
+            used: false,
+            start: name.end,
+        });
+    }
+
+    fn declare_variable(&mut self, name: &Token<'src>) {
+        if self.function.scope_depth == 0 {
+            // globals are late bound, so they are not tracked here
+            return;
+        }
+
+        let is_redeclared = self
+            .function
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| {
+                local
+                    .depth
+                    .is_none_or(|depth| depth >= self.function.scope_depth)
+            })
+            .any(|local| local.name.lexeme == name.lexeme);
+        if is_redeclared {
+            self.error_at(name, "Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
     }
 
-    fn literal(&mut self, chunk: &mut Chunk) {
-        let operator_type = self.parser.previous.kind;
+    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
+        let (slot, local) = self
+            .function
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme == name.lexeme)?;
+
+        if local.depth.is_none() {
+            self.error_at(name, "Can't read local variable in its own initializer.");
+        }
+
+        // always fits, since `add_local` caps the number of locals
+        Some(slot as u8)
+    }
+
+    fn parse_variable(&mut self, builder: &mut ChunkBuilder, name: &Token<'src>) -> usize {
+        self.declare_variable(name);
+        if self.function.scope_depth > 0 {
+            // locals live on the stack, they are not looked up by name
+            return 0;
+        }
+
+        self.identifier_constant(builder, name)
+    }
+
+    fn mark_initialized(&mut self) {
+        if self.function.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = self.function.locals.last_mut() {
+            local.depth = Some(self.function.scope_depth);
+        }
+    }
 
-        match operator_type {
-            TokenKind::False => {
-                self.emit_byte(chunk, OpCode::False as u8);
+    fn define_variable(&mut self, builder: &mut ChunkBuilder, global: usize, token: &Token) {
+        if self.function.scope_depth > 0 {
+            // the value of the initializer is already in the local's slot
+            self.mark_initialized();
+            if let Some(local) = self.function.locals.last_mut() {
+                local.start = token.end;
             }
-            TokenKind::True => {
-                self.emit_byte(chunk, OpCode::True as u8);
+            return;
+        }
+
+        self.emit(builder, Op::Global(OpCode::DefineGlobal, global), token);
+    }
+
+    fn and(&mut self, builder: &mut ChunkBuilder, operator: &Token, left: &Expr, right: &Expr) {
+        self.expression(builder, left);
+        let end_jump = self.emit_jump(builder, OpCode::JumpIfFalse, operator);
+
+        self.emit_op(builder, OpCode::Pop, operator);
+        self.expression(builder, right);
+
+        self.patch_jump(builder, end_jump, right.last_token());
+    }
+
+    fn or(&mut self, builder: &mut ChunkBuilder, operator: &Token, left: &Expr, right: &Expr) {
+        self.expression(builder, left);
+        let else_jump = self.emit_jump(builder, OpCode::JumpIfFalse, operator);
+        let end_jump = self.emit_jump(builder, OpCode::Jump, operator);
+
+        self.patch_jump(builder, else_jump, operator);
+        self.emit_op(builder, OpCode::Pop, operator);
+
+        self.expression(builder, right);
+        self.patch_jump(builder, end_jump, right.last_token());
+    }
+
+    // reads the variable, or assigns `value` to it
+    fn named_variable(&mut self, builder: &mut ChunkBuilder, name: &Token, value: Option<&Expr>) {
+        let local = self.resolve_local(name);
+        let (get_op, set_op) = match local {
+            Some(slot) => (
+                Op::Byte(OpCode::GetLocal, slot),
+                Op::Byte(OpCode::SetLocal, slot),
+            ),
+            None => {
+                let global = self.identifier_constant(builder, name);
+                (
+                    Op::Global(OpCode::GetGlobal, global),
+                    Op::Global(OpCode::SetGlobal, global),
+                )
             }
-            TokenKind::Nil => {
-                self.emit_byte(chunk, OpCode::Nil as u8);
+        };
+
+        if let Some(value) = value {
+            self.expression(builder, value);
+            self.emit_over(builder, set_op, name, name, value.last_token());
+        } else {
+            if let Some(slot) = local {
+                self.function.locals[slot as usize].used = true;
             }
-            _ => {
-                panic!("ICE: Unhandled literal");
+            self.emit(builder, get_op, name);
+        }
+    }
+
+    fn expression(&mut self, builder: &mut ChunkBuilder, expr: &Expr) {
+        match expr {
+            Expr::Literal { value, token } => {
+                self.literal(builder, *value, token);
+            }
+            Expr::Number { token, .. } if self.options.decimal => {
+                let decimal = Decimal::parse(token.lexeme)
+                    .unwrap_or_else(|| panic!("ICE: Number literal is not a decimal"));
+                let decimal = self.heap.alloc_decimal(decimal);
+                self.emit_constant(builder, Value::Obj(decimal), token);
+            }
+            Expr::Number { value, token } => {
+                self.emit_constant(builder, Value::Number(*value), token);
+            }
+            Expr::String { value, token } => {
+                self.string(builder, value, token);
+            }
+            Expr::Variable { name } => {
+                self.named_variable(builder, name, None);
+            }
+            Expr::Assign { name, value, .. } => {
+                self.named_variable(builder, name, Some(value));
+            }
+            Expr::Unary { operator, operand } => {
+                self.unary(builder, operator, operand);
+            }
+            Expr::Binary { .. } => {
+                self.binary(builder, expr);
+            }
+            Expr::Logical {
+                operator,
+                left,
+                right,
+            } => match operator.kind {
+                TokenKind::And => self.and(builder, operator, left, right),
+                TokenKind::Or => self.or(builder, operator, left, right),
+                _ => panic!("ICE: Unhandled logical"),
+            },
+            Expr::Grouping { expr, .. } => {
+                self.expression(builder, expr);
+            }
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+                close,
+            } => {
+                self.call(builder, callee, paren, arguments, close);
+            }
+        }
+    }
+
+    // the condition of an if or a loop
+    fn condition(&mut self, builder: &mut ChunkBuilder, condition: &Expr) {
+        // parentheses or nested assignments (`a = b = c`) are not caught,
+        // since only the condition itself is checked
+        if let Expr::Assign { equal, .. } = condition {
+            self.warn_at(
+                equal,
+                "Assignment used as a condition.",
+                Some("use '==' to compare, or wrap the assignment in parentheses"),
+            );
+        }
+
+        self.expression(builder, condition);
+    }
+
+    fn block(&mut self, builder: &mut ChunkBuilder, statements: &[Stmt<'src>]) {
+        // if the whole block is dead, the enclosing block already took care of it
+        let is_dead = self.function.terminated;
+        let mut warned = false;
+        let mut unreachable = None;
+
+        for stmt in statements {
+            let terminated = self.function.terminated;
+            if !is_dead.returned && terminated.returned && !warned {
+                self.warn_at(stmt.first_token(), "Unreachable code.", None);
+                warned = true;
+            }
+            if !is_dead.dead && terminated.dead && unreachable.is_none() {
+                unreachable = Some(self.expr_start(builder));
+            }
+
+            self.declaration(builder, stmt);
+        }
+
+        if let Some(start) = unreachable {
+            self.remove_dead_code(builder, start);
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.function.scope_depth += 1;
+    }
+
+    // `token` ends the scope, and the pops of its locals are attributed to it
+    fn end_scope(&mut self, builder: &mut ChunkBuilder, token: &Token) {
+        self.function.scope_depth -= 1;
+
+        let mut count = 0;
+        while self.function.locals.last().is_some_and(|local| {
+            local
+                .depth
+                .is_none_or(|depth| depth > self.function.scope_depth)
+        }) {
+            let local = self.function.locals.pop().expect("ICE: Checked above");
+            let slot = self.function.locals.len();
+            builder.add_local_name(local.debug_name(slot, token.end));
+            self.warn_if_unused(local);
+            count += 1;
+        }
+
+        if self.options.pop_n && count > 1 {
+            // the slot of the function is never popped, so the rest fit
+            self.emit(builder, Op::Byte(OpCode::PopN, count as u8), token);
+        } else {
+            (0..count).for_each(|_| self.emit_op(builder, OpCode::Pop, token));
+        }
+    }
+
+    fn warn_if_unused(&mut self, local: Local) {
+        if !local.used && !local.name.lexeme.starts_with('_') {
+            let message = format!("Unused local variable '{}'.", local.name.lexeme);
+            self.warn_at(
+                &local.name,
+                message,
+                Some("prefix the name with an underscore if this is intentional"),
+            );
+        }
+    }
+
+    // `code` was never going to run, so it is dropped from the chunk (when
+    // dead code elimination is on)
+    fn remove_dead_code(&mut self, builder: &mut ChunkBuilder, start: ExprStart) {
+        if !self.options.eliminate_dead_code {
+            return;
+        }
+
+        if self.print_code() && !self.had_error {
+            let mut stdout = io::stdout();
+            writeln!(stdout, "== removed dead code ==").expect("writable");
+            let mut offset = start.code;
+            while offset < builder.code_len() {
+                offset = debug::disassemble_instruction(&mut stdout, builder.chunk(), offset);
+            }
+        }
+
+        builder.truncate(start.code, start.constants);
+    }
+
+    fn function(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        function: &Function<'src>,
+        function_type: FunctionType,
+    ) {
+        let enclosing = mem::replace(&mut self.function, FunctionState::new(function_type));
+        let mut function_builder = ChunkBuilder::new();
+
+        // the scope is never ended, because the whole frame is discarded on return
+        self.begin_scope();
+
+        for param in &function.params {
+            let constant = self.parse_variable(&mut function_builder, param);
+            self.define_variable(&mut function_builder, constant, param);
+            // callers have to pass every parameter, so it is not a mistake
+            // to leave one unused
+            if let Some(local) = self.function.locals.last_mut() {
+                local.used = true;
+            }
+        }
+        self.block(&mut function_builder, &function.body);
+        // the locals of the function's scope are there until it returns
+        for (slot, local) in self.function.locals.iter().enumerate().skip(1) {
+            function_builder.add_local_name(local.debug_name(slot, function.close.end));
+        }
+
+        let function_chunk =
+            self.end_compiler(function_builder, function.name.lexeme, &function.close);
+        let state = mem::replace(&mut self.function, enclosing);
+        state
+            .locals
+            .into_iter()
+            .filter(|local| local.depth.is_some_and(|depth| depth > 0))
+            .for_each(|local| self.warn_if_unused(local));
+
+        let name = self.heap.copy_string(function.name.lexeme);
+        let object = self.heap.alloc_function(ObjFunction {
+            arity: function.params.len(),
+            chunk: function_chunk,
+            name: Some(name),
+        });
+        self.emit_constant(builder, Value::Obj(object), &function.close);
+    }
+
+    fn fun_declaration(&mut self, builder: &mut ChunkBuilder, function: &Function<'src>) {
+        let global = self.parse_variable(builder, &function.name);
+        // a function can refer to itself, for recursion
+        self.mark_initialized();
+        self.function(builder, function, FunctionType::Function);
+        self.define_variable(builder, global, &function.close);
+    }
+
+    fn var_declaration(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        name: &Token<'src>,
+        initializer: Option<&Expr>,
+        semicolon: &Token,
+    ) {
+        let global = self.parse_variable(builder, name);
+
+        match initializer {
+            Some(initializer) => self.expression(builder, initializer),
+            None => self.emit_op(builder, OpCode::Nil, name),
+        }
+
+        self.define_variable(builder, global, semicolon);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn for_statement(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        initializer: Option<&Stmt<'src>>,
+        condition: Option<&Expr>,
+        semicolon: &Token,
+        increment: Option<&Expr>,
+        paren: &Token,
+        body: &Stmt<'src>,
+    ) {
+        let is_dead = self.function.terminated;
+        // a variable declared in the initializer is scoped to the loop
+        self.begin_scope();
+
+        if let Some(initializer) = initializer {
+            self.statement(builder, initializer);
+        }
+
+        let mut loop_start = builder.here();
+
+        let mut exit_jump = None;
+        if let Some(condition) = condition {
+            self.condition(builder, condition);
+
+            // jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(builder, OpCode::JumpIfFalse, semicolon));
+            self.emit_op(builder, OpCode::Pop, semicolon);
+        }
+
+        if let Some(increment) = increment {
+            // the increment is compiled before the body, so the body jumps
+            // over it, and the end of the body loops back to it instead
+            let body_jump = self.emit_jump(builder, OpCode::Jump, semicolon);
+            let increment_start = builder.here();
+
+            self.expression(builder, increment);
+            self.emit_op(builder, OpCode::Pop, increment.last_token());
+
+            self.emit_loop(builder, loop_start, paren);
+            loop_start = increment_start;
+            self.patch_jump(builder, body_jump, paren);
+        }
+
+        self.statement(builder, body);
+        // the body might not run at all
+        self.function.terminated = is_dead;
+        let end = body.last_token();
+        self.emit_loop(builder, loop_start, end);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(builder, exit_jump, end);
+            self.emit_op(builder, OpCode::Pop, end);
+        }
+
+        self.end_scope(builder, end);
+    }
+
+    fn if_statement(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        condition: &Expr,
+        paren: &Token,
+        then_branch: &Stmt<'src>,
+        else_branch: Option<&Stmt<'src>>,
+    ) {
+        let is_dead = self.function.terminated;
+
+        let condition_start = self.expr_start(builder);
+        self.condition(builder, condition);
+
+        let condition = if self.options.eliminate_dead_code {
+            self.constant_at(builder, condition_start.code, builder.code_len())
+        } else {
+            None
+        };
+        if let Some(condition) = condition {
+            // only one of the branches can ever run, so the other one and the
+            // jumps around it are left out
+            self.remove_dead_code(builder, condition_start);
+
+            let then_start = self.expr_start(builder);
+            self.statement(builder, then_branch);
+            let then_terminated = mem::replace(&mut self.function.terminated, is_dead);
+            if condition.is_falsey() {
+                self.remove_dead_code(builder, then_start);
+            }
+
+            let mut else_terminated = is_dead;
+            if let Some(else_branch) = else_branch {
+                let else_start = self.expr_start(builder);
+                self.statement(builder, else_branch);
+                else_terminated = mem::replace(&mut self.function.terminated, is_dead);
+                if !condition.is_falsey() {
+                    self.remove_dead_code(builder, else_start);
+                }
+            }
+
+            let taken_terminated = if condition.is_falsey() {
+                else_terminated
+            } else {
+                then_terminated
+            };
+            // a warning still needs both branches to return, as without -O
+            self.function.terminated = Terminated {
+                returned: then_terminated.both(else_terminated).returned,
+                dead: taken_terminated.dead,
+            }
+            .or(is_dead);
+            return;
+        }
+
+        let then_jump = self.emit_jump(builder, OpCode::JumpIfFalse, paren);
+        self.emit_op(builder, OpCode::Pop, paren);
+        self.statement(builder, then_branch);
+        let then_terminated = mem::replace(&mut self.function.terminated, is_dead);
+
+        let then_end = then_branch.last_token();
+        let else_jump = self.emit_jump(builder, OpCode::Jump, then_end);
+
+        self.patch_jump(builder, then_jump, then_end);
+        self.emit_op(builder, OpCode::Pop, then_end);
+
+        let mut else_terminated = is_dead;
+        if let Some(else_branch) = else_branch {
+            self.statement(builder, else_branch);
+            else_terminated = mem::replace(&mut self.function.terminated, is_dead);
+            self.patch_jump(builder, else_jump, else_branch.last_token());
+        } else {
+            self.patch_jump(builder, else_jump, then_end);
+        }
+
+        // the code after the if is only unreachable if both branches return
+        self.function.terminated = then_terminated.both(else_terminated).or(is_dead);
+    }
+
+    fn return_statement(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        keyword: &Token,
+        value: Option<&Expr>,
+        semicolon: &Token,
+    ) {
+        if self.function.function_type == FunctionType::Script {
+            self.error_at(keyword, "Can't return from top-level code.");
+        }
+
+        match value {
+            Some(value) => {
+                self.expression(builder, value);
+                self.emit_op(builder, OpCode::Return, semicolon);
+            }
+            None => {
+                self.emit_return(builder, semicolon);
+            }
+        }
+
+        self.function.terminated = Terminated::RETURNED;
+    }
+
+    fn while_statement(
+        &mut self,
+        builder: &mut ChunkBuilder,
+        condition: &Expr,
+        paren: &Token,
+        body: &Stmt<'src>,
+    ) {
+        let is_dead = self.function.terminated;
+        let start = self.expr_start(builder);
+        let loop_start = builder.here();
+        self.condition(builder, condition);
+
+        let condition = if self.options.eliminate_dead_code {
+            self.constant_at(builder, start.code, builder.code_len())
+        } else {
+            None
+        };
+        if condition.is_some_and(|condition| condition.is_falsey()) {
+            // the body never runs, so neither does the loop around it
+            self.statement(builder, body);
+            self.function.terminated = is_dead;
+            self.remove_dead_code(builder, start);
+            return;
+        }
+
+        let exit_jump = self.emit_jump(builder, OpCode::JumpIfFalse, paren);
+        self.emit_op(builder, OpCode::Pop, paren);
+        self.statement(builder, body);
+        // the body might not run at all
+        self.function.terminated = is_dead;
+        let end = body.last_token();
+        self.emit_loop(builder, loop_start, end);
+
+        self.patch_jump(builder, exit_jump, end);
+        self.emit_op(builder, OpCode::Pop, end);
+    }
+
+    fn declaration(&mut self, builder: &mut ChunkBuilder, stmt: &Stmt<'src>) {
+        self.statement(builder, stmt);
+        self.panic_mode = false;
+    }
+
+    // whether the value of the expression statement `expr` is printed
+    // rather than thrown away
+    fn prints_expression(&self, expr: &Expr<'src>) -> bool {
+        self.options.print_expressions
+            && self.function.function_type == FunctionType::Script
+            && self.function.scope_depth == 0
+            && !matches!(expr, Expr::Assign { .. })
+    }
+
+    // defines the value on top of the stack, which is about to be printed, as
+    // `_` and the next numbered result, if they are kept
+    fn bind_result(&mut self, builder: &mut ChunkBuilder, token: &Token) {
+        let Some(number) = self.next_result else {
+            return;
+        };
+        self.next_result = Some(number + 1);
+        for name in ["_".to_string(), format!("_{}", number)] {
+            let global = self.name_constant(builder, &name);
+            self.emit_op(builder, OpCode::Dup, token);
+            self.emit(builder, Op::Global(OpCode::DefineGlobal, global), token);
+        }
+    }
+
+    fn statement(&mut self, builder: &mut ChunkBuilder, stmt: &Stmt<'src>) {
+        match stmt {
+            Stmt::Expression { expr, semicolon } => {
+                self.expression(builder, expr);
+                if self.prints_expression(expr) {
+                    self.bind_result(builder, semicolon);
+                    self.emit_op(builder, OpCode::Print, semicolon);
+                } else {
+                    self.emit_op(builder, OpCode::Pop, semicolon);
+                }
+            }
+            Stmt::Print { keyword, expr, .. } => {
+                self.expression(builder, expr);
+                self.emit_op(builder, OpCode::Print, keyword);
+            }
+            Stmt::Var {
+                name,
+                initializer,
+                semicolon,
+                ..
+            } => {
+                self.var_declaration(builder, name, initializer.as_ref(), semicolon);
+            }
+            Stmt::Function(function) => {
+                self.fun_declaration(builder, function);
+            }
+            Stmt::Block {
+                statements, close, ..
+            } => {
+                self.begin_scope();
+                self.block(builder, statements);
+                self.end_scope(builder, close);
+            }
+            Stmt::If {
+                condition,
+                paren,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.if_statement(
+                    builder,
+                    condition,
+                    paren,
+                    then_branch,
+                    else_branch.as_deref(),
+                );
+            }
+            Stmt::While {
+                condition,
+                paren,
+                body,
+                ..
+            } => {
+                self.while_statement(builder, condition, paren, body);
             }
+            Stmt::For {
+                initializer,
+                condition,
+                semicolon,
+                increment,
+                paren,
+                body,
+                ..
+            } => {
+                self.for_statement(
+                    builder,
+                    initializer.as_deref(),
+                    condition.as_ref(),
+                    semicolon,
+                    increment.as_ref(),
+                    paren,
+                    body,
+                );
+            }
+            Stmt::Return {
+                keyword,
+                value,
+                semicolon,
+            } => {
+                self.return_statement(builder, keyword, value.as_ref(), semicolon);
+            }
+        }
+    }
+
+    fn error_at<S: AsRef<str>>(&mut self, token: &Token, message: S) {
+        if self.panic_mode {
+            // prevent error cascade
+            return;
+        }
+
+        self.panic_mode = true;
+        self.report(Severity::Error, token, message, None);
+        self.had_error = true;
+    }
+
+    // warnings never put the compiler in panic mode, since the code is valid
+    fn warn_at<S: AsRef<str>>(&mut self, token: &Token, message: S, help: Option<&str>) {
+        if self.options.deny_warnings {
+            self.report(Severity::Error, token, message, help);
+            self.had_error = true;
+        } else {
+            self.report(Severity::Warning, token, message, help);
+        }
+    }
+
+    fn report<S: AsRef<str>>(
+        &mut self,
+        severity: Severity,
+        token: &Token,
+        message: S,
+        help: Option<&str>,
+    ) {
+        self.diagnostics
+            .push(Diagnostic::at_token(severity, token, message, help));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // compiles `source` without the ranges of the source that the
+    // instructions cover, so that the chunk can be compared with one that is
+    // written by hand
+    fn compile_lines(
+        source: &str,
+        heap: &mut Heap,
+        options: CompileOptions,
+    ) -> Result<Chunk, Vec<Diagnostic>> {
+        let compiled = Compiler::compile(source, heap, options)?;
+
+        let mut chunk = Chunk::new();
+        for i in 0..compiled.code_len() {
+            chunk.write(
+                compiled.get_code(i),
+                compiled.get_line(i),
+                compiled.get_column(i),
+            );
+        }
+        for constant in compiled.constants().iter() {
+            chunk.constants_mut().add(constant);
         }
+        Ok(chunk)
+    }
+
+    // the messages of the diagnostics of a compilation that fails
+    fn errors(source: &str, options: CompileOptions) -> Vec<String> {
+        let diagnostics =
+            Compiler::compile(source, &mut Heap::new(), options).expect_err("compile error");
+        diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_compiler_warnings() {
+        fn is_warned(source: &str) -> bool {
+            let options = CompileOptions {
+                deny_warnings: true,
+                ..Default::default()
+            };
+            // warnings still compile, unless they are denied
+            assert!(
+                compile_lines(source, &mut Heap::new(), CompileOptions::default()).is_ok(),
+                "{}",
+                source
+            );
+            let warned = compile_lines(source, &mut Heap::new(), options).is_err();
+            // whatever the optimizer knows, the warnings are the same
+            for level in [OptLevel::O1, OptLevel::O2] {
+                assert_eq!(
+                    compile_lines(source, &mut Heap::new(), options.opt_level(level)).is_err(),
+                    warned,
+                    "{} at {:?}",
+                    source,
+                    level
+                );
+            }
+            warned
+        }
+
+        assert!(!is_warned("var a = 1; { var b = 2; print b; }"));
+
+        // unused locals
+        assert!(is_warned("{ var a = 1; }"));
+        // assigning does not count as using
+        assert!(is_warned("{ var a = 1; a = 2; }"));
+        assert!(!is_warned("{ var _a = 1; }"));
+        // globals may be used by later code, so they are never unused
+        assert!(!is_warned("var a = 1;"));
+
+        // assignment in a condition
+        assert!(is_warned("var a; if (a = true) {}"));
+        assert!(is_warned("var a; while (a = false) {}"));
+        assert!(is_warned("var a; for (; a = false;) {}"));
+        assert!(is_warned("{ var a; if (a = true) {} print a; }"));
+        // parentheses show that the assignment is intended
+        assert!(!is_warned("var a; if ((a = true)) {}"));
+        assert!(!is_warned("var a; if (a == true) {}"));
+        // only the condition itself is checked, not the body
+        assert!(!is_warned("var a; if (true) a = false;"));
+        // neither are the arguments of a call in it
+        assert!(!is_warned("fun f(x) { return x; } var a; if (f(a = 1)) {}"));
+
+        // locals of a function body
+        assert!(is_warned("fun f() { var a; }"));
+        assert!(!is_warned("fun f(a) {}"));
+
+        // unreachable code
+        assert!(is_warned("fun f() { return; print 1; }"));
+        assert!(is_warned("fun f() { { return 1; } print 1; }"));
+        assert!(is_warned(
+            "fun f(a) { if (a) return 1; else return 2; print 1; }"
+        ));
+        assert!(!is_warned("fun f(a) { if (a) return 1; print 1; }"));
+        assert!(!is_warned("fun f(a) { while (a) return 1; print 1; }"));
+        assert!(!is_warned("fun f() { return; }"));
+        // even if the condition is constant
+        assert!(!is_warned("fun f() { if (true) return 1; print 1; }"));
+        assert!(!is_warned("fun f() { if (1 < 2) return 1; print 1; }"));
+        assert!(is_warned("fun f() { if (false) { return; print 1; } }"));
+    }
+
+    #[test]
+    fn test_compiler_spans() {
+        fn ranges(source: &str, options: CompileOptions) -> Vec<(usize, usize)> {
+            let mut heap = Heap::new();
+            let chunk = Compiler::compile(source, &mut heap, options).expect("no error");
+            (0..chunk.code_len())
+                .map(|i| (chunk.get_span(i).start, chunk.get_span(i).end))
+                .collect()
+        }
+
+        // an operator covers both of its operands, and a call covers the
+        // callee up to the closing parenthesis
+        assert_eq!(
+            ranges("print -a + f(1) * 2;", CompileOptions::default()),
+            vec![
+                (7, 8),
+                (7, 8),
+                (6, 8),
+                (11, 12),
+                (11, 12),
+                (13, 14),
+                (13, 14),
+                (11, 15),
+                (11, 15),
+                (18, 19),
+                (18, 19),
+                (11, 19),
+                (6, 19),
+                (0, 5),
+                (20, 20),
+                (20, 20),
+            ]
+        );
+
+        // an assignment covers its value
+        assert_eq!(
+            ranges("a = 1;", CompileOptions::default())[..4],
+            [(4, 5), (4, 5), (0, 5), (0, 5)]
+        );
+
+        // a folded constant covers what it replaced
+        let options = CompileOptions {
+            fold_constants: true,
+            ..Default::default()
+        };
+        assert_eq!(ranges("-(1 + 2);", options)[..2], [(0, 8), (0, 8)]);
+
+        // the line and column are still the ones of the operator
+        let mut heap = Heap::new();
+        let chunk =
+            Compiler::compile("1 +\n 2;", &mut heap, CompileOptions::default()).expect("no error");
+        assert_eq!(
+            chunk.get_span(4),
+            Span {
+                line: 1,
+                column: 3,
+                start: 0,
+                end: 6
+            }
+        );
     }
 
-    fn grouping(&mut self, chunk: &mut Chunk) {
-        self.expression(chunk);
-        self.consume(TokenKind::RightParen, "Expect ')' after expression.");
+    #[test]
+    fn test_compiler_local_names() {
+        let mut heap = Heap::new();
+        let chunk = Compiler::compile(
+            "{ var a = 1; { var b = a; } }",
+            &mut heap,
+            CompileOptions::default(),
+        )
+        .expect("no error");
+
+        // in scope from the end of their declaration to the end of the block
+        let local = |name: &str, slot, start, end| LocalName {
+            name: name.to_string(),
+            slot,
+            start,
+            end,
+        };
+        assert_eq!(
+            chunk.local_names(),
+            [local("b", 2, 25, 27), local("a", 1, 12, 29)]
+        );
+        let names = |offset| {
+            chunk
+                .locals_at(offset)
+                .into_iter()
+                .map(|local| local.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(0), Vec::<&str>::new());
+        assert_eq!(names(2), ["a"]);
+        assert_eq!(names(4), ["a", "b"]);
     }
 
-    fn number(&self, chunk: &mut Chunk) {
-        let value = self
-            .parser
-            .previous
-            .lexeme
-            .parse::<f64>()
-            .expect("ICE: Non-number stored in number token?");
-        self.emit_constant(chunk, Value::Number(value));
+    #[test]
+    fn test_compiler_opt_level() {
+        let options = CompileOptions {
+            deny_warnings: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            options.opt_level(OptLevel::O2).opt_level(OptLevel::O0),
+            options
+        );
+
+        // folding and the peephole optimizer, without the passes over the
+        // control flow graph or the superinstructions
+        let o1 = options.opt_level(OptLevel::O1);
+        assert!(o1.deny_warnings && o1.fold_constants && o1.peephole);
+        assert!(!o1.ir_passes && !o1.superinstructions);
+
+        let source = "var a = 1; a = a + 2 * 3; print a >= 1;";
+        let mut heap = Heap::new();
+        assert_eq!(
+            Compiler::compile(
+                source,
+                &mut heap,
+                CompileOptions::default().opt_level(OptLevel::O0)
+            ),
+            Compiler::compile(source, &mut heap, CompileOptions::default())
+        );
     }
 
-    fn unary(&mut self, chunk: &mut Chunk) {
-        let operator_type = self.parser.previous.kind;
+    #[test]
+    fn test_compiler_small_constants() {
+        let options = CompileOptions {
+            small_constants: true,
+            fold_constants: true,
+            ..Default::default()
+        };
+        let mut heap = Heap::new();
+        let chunk = compile_lines("0; 1; -1; -0; 2;", &mut heap, options).expect("no error");
+
+        let mut expected = Chunk::new();
+        [
+            (OpCode::Zero as u8, 1),
+            (OpCode::Pop as u8, 2),
+            (OpCode::One as u8, 4),
+            (OpCode::Pop as u8, 5),
+            (OpCode::MinusOne as u8, 7),
+            (OpCode::Pop as u8, 9),
+            // -0 is not 0, so it is still a constant
+            (OpCode::Constant as u8, 11),
+            (0, 11),
+            (OpCode::Pop as u8, 13),
+            (OpCode::Constant as u8, 15),
+            (1, 15),
+            (OpCode::Pop as u8, 16),
+            (OpCode::Nil as u8, 17),
+            (OpCode::Return as u8, 17),
+        ]
+        .iter()
+        .for_each(|(byte, column)| expected.write(*byte, 1, *column));
+        expected.constants_mut().add(Value::Number(-0.0));
+        expected.constants_mut().add(Value::Number(2.0));
+        assert_eq!(chunk, expected);
+    }
 
-        self.parse_precedence(chunk, Precedence::Unary);
+    #[test]
+    fn test_compiler_pop_n() {
+        let options = CompileOptions {
+            pop_n: true,
+            ..Default::default()
+        };
+        let compile = |source: &str| {
+            let chunk = compile_lines(source, &mut Heap::new(), options).expect("no error");
+            (0..chunk.code_len())
+                .map(|i| chunk.get_code(i))
+                .collect::<Vec<_>>()
+        };
 
-        match operator_type {
-            TokenKind::Minus => {
-                self.emit_byte(chunk, OpCode::Negate as u8);
-            }
-            TokenKind::Bang => {
-                self.emit_byte(chunk, OpCode::Not as u8);
-            }
-            _ => {
-                panic!("ICE: Unhandled unary.");
-            }
-        }
+        // a single local is still popped with an OP_POP
+        assert_eq!(
+            compile("{ var _a; }"),
+            [OpCode::Nil, OpCode::Pop, OpCode::Nil, OpCode::Return].map(|op| op as u8)
+        );
+        assert_eq!(
+            compile("{ var _a; var _b; }"),
+            [
+                OpCode::Nil as u8,
+                OpCode::Nil as u8,
+                OpCode::PopN as u8,
+                2,
+                OpCode::Nil as u8,
+                OpCode::Return as u8
+            ]
+        );
     }
 
-    fn emit_return(&self, chunk: &mut Chunk) {
-        self.emit_byte(chunk, OpCode::Return as u8);
+    #[test]
+    fn test_compiler_check() {
+        let options = CompileOptions::default();
+        assert_eq!(
+            Compiler::check("fun f() { return 1; } print f();", options),
+            Ok(vec![])
+        );
+        // errors from the parser, and from generating the bytecode
+        assert!(Compiler::check("print ;", options).is_err());
+        assert!(Compiler::check("return 1;", options).is_err());
+
+        // every lexical error, even the ones after the first of a statement
+        let errors = Compiler::check("print ~ $;\nprint \"a", options).expect_err("lexical errors");
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.message.as_str(), error.span.column))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Unexpected character.", 7),
+                ("Unexpected character.", 9),
+                ("Unterminated string.", 7)
+            ]
+        );
+
+        // warnings are returned, unless they are denied
+        let warnings = Compiler::check("{ var a; }", options).expect("no error");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].severity, Severity::Warning);
+
+        let options = CompileOptions {
+            deny_warnings: true,
+            ..Default::default()
+        };
+        let errors = Compiler::check("{ var a; }", options).expect_err("denied");
+        assert_eq!(errors[0].severity, Severity::Error);
     }
 
-    fn make_constant(&self, chunk: &mut Chunk, value: Value) -> u8 {
-        let constant = chunk.constants_mut().add(value);
-        TryInto::<u8>::try_into(constant)
-            .unwrap_or_else(|_| panic!("ICE: Too many constants in one chunk."))
-    }
+    #[test]
+    fn test_compiler_eliminate_dead_code() {
+        fn compile(source: &str) -> Chunk {
+            let options = CompileOptions {
+                eliminate_dead_code: true,
+                ..Default::default()
+            };
+            compile_lines(source, &mut Heap::new(), options).expect("no error")
+        }
 
-    fn emit_constant(&self, chunk: &mut Chunk, value: Value) {
-        let constant_index = self.make_constant(chunk, value);
-        self.emit_bytes(chunk, &[OpCode::Constant as u8, constant_index]);
-    }
+        fn print_only(value: f64, constant_column: u32, print_column: u32, end: u32) -> Chunk {
+            let mut chunk = Chunk::new();
+            let constant = chunk.constants_mut().add(Value::Number(value));
+            chunk.write(OpCode::Constant as u8, 1, constant_column);
+            chunk.write(constant as u8, 1, constant_column);
+            chunk.write(OpCode::Print as u8, 1, print_column);
+            chunk.write(OpCode::Nil as u8, 1, end);
+            chunk.write(OpCode::Return as u8, 1, end);
+            chunk
+        }
 
-    fn expression(&mut self, chunk: &mut Chunk) {
-        self.parse_precedence(chunk, Precedence::Assignment);
-    }
+        assert_eq!(
+            compile("if (false) print 1; print 2;"),
+            print_only(2.0, 27, 21, 29)
+        );
+        assert_eq!(
+            compile("if (nil) print 1; else print 2;"),
+            print_only(2.0, 30, 24, 32)
+        );
+        assert_eq!(
+            compile("if (0) print 1; else { print 2; }"),
+            print_only(1.0, 14, 8, 34)
+        );
 
-    fn parse_precedence(&mut self, chunk: &mut Chunk, precedence: Precedence) {
-        self.advance();
-        self.do_rule_prefix(chunk, self.parser.previous.kind);
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Nil as u8, 1, 23);
+        chunk.write(OpCode::Return as u8, 1, 23);
+
+        assert_eq!(compile("while (false) print 1;"), chunk);
+
+        // conditions that are not constant are kept (the heap has to outlive
+        // both chunks, since the name of `a` is compared)
+        let mut heap = Heap::new();
+        let source = "var a; if (a) print 1;";
+        let options = CompileOptions {
+            eliminate_dead_code: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            compile_lines(source, &mut heap, options),
+            compile_lines(source, &mut heap, CompileOptions::default())
+        );
+    }
 
-        while precedence <= self.get_rule_precedence(self.parser.current.kind) {
-            self.advance();
-            self.do_rule_infix(chunk, self.parser.previous.kind);
+    #[test]
+    fn test_compiler_fold_constants() {
+        fn compile(source: &str, heap: &mut Heap) -> Chunk {
+            let options = CompileOptions {
+                fold_constants: true,
+                ..Default::default()
+            };
+            compile_lines(source, heap, options).expect("no error")
         }
-    }
 
-    fn get_rule_precedence(&self, kind: TokenKind) -> Precedence {
-        match kind {
-            TokenKind::Minus | TokenKind::Plus => Precedence::Term,
-            TokenKind::Slash | TokenKind::Star => Precedence::Factor,
-            TokenKind::BangEqual | TokenKind::EqualEqual => Precedence::Equality,
-            TokenKind::Greater
-            | TokenKind::GreaterEqual
-            | TokenKind::Less
-            | TokenKind::LessEqual => Precedence::Comparison,
-            _ => Precedence::None,
+        {
+            let mut chunk = Chunk::new();
+
+            // the constant is attributed to the last operator that was folded
+            let constant = chunk.constants_mut().add(Value::Number(10.0));
+            chunk.write(OpCode::Constant as u8, 1, 7);
+            chunk.write(constant as u8, 1, 7);
+            chunk.write(OpCode::Pop as u8, 1, 10);
+            chunk.write(OpCode::Nil as u8, 1, 11);
+            chunk.write(OpCode::Return as u8, 1, 11);
+
+            assert_eq!(compile("2 * 3 + 4;", &mut Heap::new()), chunk);
         }
-    }
 
-    fn do_rule_prefix(&mut self, chunk: &mut Chunk, kind: TokenKind) {
-        match kind {
-            TokenKind::LeftParen => {
-                self.grouping(chunk);
-            }
-            TokenKind::Minus | TokenKind::Bang => {
-                self.unary(chunk);
-            }
-            TokenKind::Number => {
-                self.number(chunk);
-            }
-            TokenKind::False | TokenKind::True | TokenKind::Nil => {
-                self.literal(chunk);
-            }
-            _ => {
-                self.error("Expect expression.");
-            }
+        {
+            let mut chunk = Chunk::new();
+
+            chunk.write(OpCode::False as u8, 1, 1);
+            chunk.write(OpCode::Pop as u8, 1, 6);
+            chunk.write(OpCode::Nil as u8, 1, 7);
+            chunk.write(OpCode::Return as u8, 1, 7);
+
+            assert_eq!(compile("!true;", &mut Heap::new()), chunk);
         }
-    }
 
-    fn do_rule_infix(&mut self, chunk: &mut Chunk, kind: TokenKind) {
-        match kind {
-            TokenKind::Minus
-            | TokenKind::Plus
-            | TokenKind::Slash
-            | TokenKind::Star
-            | TokenKind::BangEqual
-            | TokenKind::EqualEqual
-            | TokenKind::Greater
-            | TokenKind::GreaterEqual
-            | TokenKind::Less
-            | TokenKind::LessEqual => {
-                self.binary(chunk);
-            }
-            _ => {
-                self.error("Expect expression.");
-            }
+        {
+            let mut chunk = Chunk::new();
+
+            chunk.write(OpCode::True as u8, 1, 10);
+            chunk.write(OpCode::Pop as u8, 1, 14);
+            chunk.write(OpCode::Nil as u8, 1, 15);
+            chunk.write(OpCode::Return as u8, 1, 15);
+
+            assert_eq!(compile("-(1 - 2) == 1;", &mut Heap::new()), chunk);
         }
-    }
 
-    fn error_at_current<S: AsRef<str>>(&mut self, message: S) {
-        let token = self.parser.current.clone();
-        self.error_at(token, message);
-    }
+        {
+            let mut heap = Heap::new();
+            let mut chunk = Chunk::new();
 
-    fn error<S: AsRef<str>>(&mut self, message: S) {
-        let token = self.parser.previous.clone();
-        self.error_at(token, message);
-    }
+            let string = heap.alloc_string("ab".to_string());
+            let constant = chunk.constants_mut().add(Value::Obj(string));
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
+            chunk.write(OpCode::Pop as u8, 1, 10);
+            chunk.write(OpCode::Nil as u8, 1, 11);
+            chunk.write(OpCode::Return as u8, 1, 11);
 
-    fn error_at<S: AsRef<str>>(&mut self, token: Token, message: S) {
-        if self.parser.panic_mode {
-            // prevent error cascade
-            return;
+            assert_eq!(compile(r#""a" + "b";"#, &mut heap), chunk);
         }
 
-        self.parser.panic_mode = true;
-        eprint!("[line {}] Error", token.line);
+        // only the constant part of an expression is folded
+        {
+            let mut heap = Heap::new();
+            let mut chunk = Chunk::new();
 
-        match token.kind {
-            TokenKind::EndOfFile => {
-                eprint!(" at end");
-            }
-            TokenKind::Error => {
-                // nothing
-            }
-            _ => {
-                eprint!(" at '{}'", token.lexeme);
-            }
+            let name = heap.alloc_string("a".to_string());
+            let name = chunk.constants_mut().add(Value::Obj(name));
+            chunk.write(OpCode::GetGlobal as u8, 1, 1);
+            chunk.write(name as u8, 1, 1);
+
+            let constant = chunk.constants_mut().add(Value::Number(6.0));
+            chunk.write(OpCode::Constant as u8, 1, 7);
+            chunk.write(constant as u8, 1, 7);
+
+            chunk.write(OpCode::Add as u8, 1, 3);
+            chunk.write(OpCode::Pop as u8, 1, 10);
+            chunk.write(OpCode::Nil as u8, 1, 11);
+            chunk.write(OpCode::Return as u8, 1, 11);
+
+            assert_eq!(compile("a + 2 * 3;", &mut heap), chunk);
         }
 
-        eprintln!(": {}", message.as_ref());
-        self.parser.had_error = true;
-    }
-}
+        // type errors are left for the runtime to report
+        {
+            let mut heap = Heap::new();
+            let mut chunk = Chunk::new();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            let string = heap.alloc_string("a".to_string());
+            let constant = chunk.constants_mut().add(Value::Obj(string));
+            chunk.write(OpCode::Constant as u8, 1, 2);
+            chunk.write(constant as u8, 1, 2);
+            chunk.write(OpCode::Negate as u8, 1, 1);
+            chunk.write(OpCode::Pop as u8, 1, 5);
+            chunk.write(OpCode::Nil as u8, 1, 6);
+            chunk.write(OpCode::Return as u8, 1, 6);
+
+            assert_eq!(compile(r#"-"a";"#, &mut heap), chunk);
+        }
+    }
 
     #[test]
     fn test_compiler_compile() {
         // test error
-        assert_eq!(Compiler::compile("1 +".to_string()), Err(()));
+        assert_eq!(
+            errors("1 +;", CompileOptions::default()),
+            vec!["Expect expression."]
+        );
 
         // test unary ops
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(3.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 2);
+            chunk.write(constant as u8, 1, 2);
+
+            chunk.write(OpCode::Negate as u8, 1, 1);
+
+            chunk.write(OpCode::Pop as u8, 1, 3);
+
+            chunk.write(OpCode::Nil as u8, 1, 4);
+
+            chunk.write(OpCode::Return as u8, 1, 4);
+
+            assert_eq!(
+                compile_lines("-3;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
+        }
+
+        {
+            let mut chunk = Chunk::new();
+
+            chunk.write(OpCode::True as u8, 1, 2);
+            chunk.write(OpCode::Not as u8, 1, 1);
+            chunk.write(OpCode::Pop as u8, 1, 6);
 
-            chunk.write(OpCode::Negate as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 7);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Return as u8, 1, 7);
 
-            assert_eq!(Compiler::compile("-3".to_string()), Ok(chunk));
+            assert_eq!(
+                compile_lines("!true;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
+        // test strings
         {
+            let mut heap = Heap::new();
             let mut chunk = Chunk::new();
 
-            chunk.write(OpCode::True as u8, 1);
-            chunk.write(OpCode::Not as u8, 1);
-            chunk.write(OpCode::Return as u8, 1);
+            let string = heap.alloc_string("hello world".to_string());
+            let constant = chunk.constants_mut().add(Value::Obj(string));
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
+
+            chunk.write(OpCode::Pop as u8, 1, 14);
+
+            chunk.write(OpCode::Nil as u8, 1, 15);
+
+            chunk.write(OpCode::Return as u8, 1, 15);
 
-            assert_eq!(Compiler::compile("!true".to_string()), Ok(chunk));
+            assert_eq!(
+                compile_lines(r#""hello world";"#, &mut heap, CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         // test binary ops
@@ -388,181 +1933,315 @@ mod tests {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(1.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(2.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
+
+            chunk.write(OpCode::Add as u8, 1, 3);
 
-            chunk.write(OpCode::Add as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 6);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 7);
 
-            assert_eq!(Compiler::compile("1 + 2".to_string()), Ok(chunk));
+            chunk.write(OpCode::Return as u8, 1, 7);
+
+            assert_eq!(
+                compile_lines("1 + 2;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(8.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(3.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
 
-            chunk.write(OpCode::Subtract as u8, 1);
+            chunk.write(OpCode::Subtract as u8, 1, 3);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 6);
 
-            assert_eq!(Compiler::compile("8 - 3".to_string()), Ok(chunk));
+            chunk.write(OpCode::Nil as u8, 1, 7);
+
+            chunk.write(OpCode::Return as u8, 1, 7);
+
+            assert_eq!(
+                compile_lines("8 - 3;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(5.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(6.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
+
+            chunk.write(OpCode::Multiply as u8, 1, 3);
 
-            chunk.write(OpCode::Multiply as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 6);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 7);
 
-            assert_eq!(Compiler::compile("5 * 6".to_string()), Ok(chunk));
+            chunk.write(OpCode::Return as u8, 1, 7);
+
+            assert_eq!(
+                compile_lines("5 * 6;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(28.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 6);
+            chunk.write(constant as u8, 1, 6);
+
+            chunk.write(OpCode::Divide as u8, 1, 4);
+
+            chunk.write(OpCode::Pop as u8, 1, 7);
+
+            chunk.write(OpCode::Nil as u8, 1, 8);
+
+            chunk.write(OpCode::Return as u8, 1, 8);
+
+            assert_eq!(
+                compile_lines("28 / 4;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
+        }
+
+        {
+            let mut chunk = Chunk::new();
+
+            chunk.write(OpCode::True as u8, 1, 1);
+
+            chunk.write(OpCode::Nil as u8, 1, 9);
 
-            chunk.write(OpCode::Divide as u8, 1);
+            chunk.write(OpCode::Equal as u8, 1, 6);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 12);
 
-            assert_eq!(Compiler::compile("28 / 4".to_string()), Ok(chunk));
+            chunk.write(OpCode::Nil as u8, 1, 13);
+
+            chunk.write(OpCode::Return as u8, 1, 13);
+
+            assert_eq!(
+                compile_lines("true == nil;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
-            chunk.write(OpCode::True as u8, 1);
+            chunk.write(OpCode::False as u8, 1, 1);
+
+            chunk.write(OpCode::Nil as u8, 1, 10);
 
-            chunk.write(OpCode::Nil as u8, 1);
+            chunk.write(OpCode::Equal as u8, 1, 7);
+            chunk.write(OpCode::Not as u8, 1, 7);
 
-            chunk.write(OpCode::Equal as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 13);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 14);
 
-            assert_eq!(Compiler::compile("true == nil".to_string()), Ok(chunk));
+            chunk.write(OpCode::Return as u8, 1, 14);
+
+            assert_eq!(
+                compile_lines("false != nil;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
-            chunk.write(OpCode::False as u8, 1);
+            let constant = chunk.constants_mut().add(Value::Number(3.0));
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
+
+            let constant = chunk.constants_mut().add(Value::Number(4.0));
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
+
+            chunk.write(OpCode::Greater as u8, 1, 3);
 
-            chunk.write(OpCode::Nil as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 6);
 
-            chunk.write(OpCode::Equal as u8, 1);
-            chunk.write(OpCode::Not as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 7);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Return as u8, 1, 7);
 
-            assert_eq!(Compiler::compile("false != nil".to_string()), Ok(chunk));
+            assert_eq!(
+                compile_lines("3 > 4;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(3.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 6);
+            chunk.write(constant as u8, 1, 6);
+
+            chunk.write(OpCode::GreaterEqual as u8, 1, 3);
 
-            chunk.write(OpCode::Greater as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 7);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 8);
 
-            assert_eq!(Compiler::compile("3 > 4".to_string()), Ok(chunk));
+            chunk.write(OpCode::Return as u8, 1, 8);
+
+            assert_eq!(
+                compile_lines("3 >= 4;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(3.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 6);
+            chunk.write(constant as u8, 1, 6);
 
             // this desugaring means that "NaN >= 1" will be true, violating IEEE-754 where it
             // should be false. this is done intentionally by the book to make implementation
             // simpler
-            chunk.write(OpCode::Less as u8, 1);
-            chunk.write(OpCode::Not as u8, 1);
+            chunk.write(OpCode::Less as u8, 1, 3);
+            chunk.write(OpCode::Not as u8, 1, 3);
+
+            chunk.write(OpCode::Pop as u8, 1, 7);
+
+            chunk.write(OpCode::Nil as u8, 1, 8);
+
+            chunk.write(OpCode::Return as u8, 1, 8);
+
+            assert_eq!(
+                compile_lines(
+                    "3 >= 4;",
+                    &mut Heap::new(),
+                    CompileOptions {
+                        book_comparisons: true,
+                        ..Default::default()
+                    }
+                ),
+                Ok(chunk)
+            );
+        }
+
+        {
+            let mut chunk = Chunk::new();
+
+            let constant = chunk.constants_mut().add(Value::Number(3.0));
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
+
+            let constant = chunk.constants_mut().add(Value::Number(4.0));
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
+
+            chunk.write(OpCode::Less as u8, 1, 3);
+
+            chunk.write(OpCode::Pop as u8, 1, 6);
+
+            chunk.write(OpCode::Nil as u8, 1, 7);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Return as u8, 1, 7);
 
-            assert_eq!(Compiler::compile("3 >= 4".to_string()), Ok(chunk));
+            assert_eq!(
+                compile_lines("3 < 4;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(3.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 6);
+            chunk.write(constant as u8, 1, 6);
+
+            chunk.write(OpCode::LessEqual as u8, 1, 3);
 
-            chunk.write(OpCode::Less as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 7);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 8);
 
-            assert_eq!(Compiler::compile("3 < 4".to_string()), Ok(chunk));
+            chunk.write(OpCode::Return as u8, 1, 8);
+
+            assert_eq!(
+                compile_lines("3 <= 4;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(3.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 6);
+            chunk.write(constant as u8, 1, 6);
 
             // this desugaring means that "NaN <= 1" will be true, violating IEEE-754 where it
             // should be false. this is done intentionally by the book to make implementation
             // simpler
-            chunk.write(OpCode::Greater as u8, 1);
-            chunk.write(OpCode::Not as u8, 1);
+            chunk.write(OpCode::Greater as u8, 1, 3);
+            chunk.write(OpCode::Not as u8, 1, 3);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 7);
 
-            assert_eq!(Compiler::compile("3 <= 4".to_string()), Ok(chunk));
+            chunk.write(OpCode::Nil as u8, 1, 8);
+
+            chunk.write(OpCode::Return as u8, 1, 8);
+
+            assert_eq!(
+                compile_lines(
+                    "3 <= 4;",
+                    &mut Heap::new(),
+                    CompileOptions {
+                        book_comparisons: true,
+                        ..Default::default()
+                    }
+                ),
+                Ok(chunk)
+            );
         }
 
         // test complex expressions
@@ -570,35 +2249,43 @@ mod tests {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(1.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 3);
+            chunk.write(constant as u8, 1, 3);
 
-            chunk.write(OpCode::Negate as u8, 1);
+            chunk.write(OpCode::Negate as u8, 1, 2);
 
             let constant = chunk.constants_mut().add(Value::Number(2.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 7);
+            chunk.write(constant as u8, 1, 7);
 
-            chunk.write(OpCode::Add as u8, 1);
+            chunk.write(OpCode::Add as u8, 1, 5);
 
             let constant = chunk.constants_mut().add(Value::Number(3.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 12);
+            chunk.write(constant as u8, 1, 12);
 
-            chunk.write(OpCode::Multiply as u8, 1);
+            chunk.write(OpCode::Multiply as u8, 1, 10);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 17);
+            chunk.write(constant as u8, 1, 17);
+
+            chunk.write(OpCode::Negate as u8, 1, 16);
 
-            chunk.write(OpCode::Negate as u8, 1);
+            chunk.write(OpCode::Subtract as u8, 1, 14);
 
-            chunk.write(OpCode::Subtract as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 18);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 19);
+
+            chunk.write(OpCode::Return as u8, 1, 19);
 
             assert_eq!(
-                Compiler::compile("(-1 + 2) * 3 - -4".to_string()),
+                compile_lines(
+                    "(-1 + 2) * 3 - -4;",
+                    &mut Heap::new(),
+                    CompileOptions::default()
+                ),
                 Ok(chunk)
             );
         }
@@ -608,20 +2295,128 @@ mod tests {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(5.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(6.0));
-            chunk.write(OpCode::Constant as u8, 3);
-            chunk.write(constant as u8, 3);
+            chunk.write(OpCode::Constant as u8, 3, 1);
+            chunk.write(constant as u8, 3, 1);
+
+            // the book attributes this to line 3 (the last operand), but
+            // we use the position of the operator instead
+            chunk.write(OpCode::Multiply as u8, 2, 1);
+
+            chunk.write(OpCode::Pop as u8, 3, 2);
+
+            chunk.write(OpCode::Nil as u8, 3, 3);
+
+            chunk.write(OpCode::Return as u8, 3, 3);
+
+            assert_eq!(
+                compile_lines("5\n*\n6;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
+        }
+
+        // test statements
+        {
+            let mut heap = Heap::new();
+            let mut chunk = Chunk::new();
+
+            let name = chunk
+                .constants_mut()
+                .add(Value::Obj(heap.alloc_string("a".to_string())));
+            let constant = chunk.constants_mut().add(Value::Number(1.0));
+            chunk.write(OpCode::Constant as u8, 1, 9);
+            chunk.write(constant as u8, 1, 9);
+            chunk.write(OpCode::DefineGlobal as u8, 1, 10);
+            chunk.write(name as u8, 1, 10);
+
+            // the name is only a constant once
+            chunk.write(OpCode::GetGlobal as u8, 2, 7);
+            chunk.write(name as u8, 2, 7);
+            chunk.write(OpCode::Print as u8, 2, 1);
+
+            chunk.write(OpCode::Nil as u8, 3, 5);
+            chunk.write(OpCode::SetGlobal as u8, 3, 1);
+            chunk.write(name as u8, 3, 1);
+            chunk.write(OpCode::Pop as u8, 3, 8);
+
+            let name = chunk
+                .constants_mut()
+                .add(Value::Obj(heap.alloc_string("b".to_string())));
+            chunk.write(OpCode::Nil as u8, 4, 5);
+            chunk.write(OpCode::DefineGlobal as u8, 4, 6);
+            chunk.write(name as u8, 4, 6);
+
+            // the implicit return is on the last line with code, rather than
+            // on the empty one after the newline at the end
+            chunk.write(OpCode::Nil as u8, 4, 6);
+            chunk.write(OpCode::Return as u8, 4, 6);
+
+            assert_eq!(
+                compile_lines(
+                    "var a = 1;\nprint a;\na = nil;\nvar b;\n",
+                    &mut heap,
+                    CompileOptions::default()
+                ),
+                Ok(chunk)
+            );
+        }
+
+        // test statement errors
+        assert_eq!(
+            errors("print 1", CompileOptions::default()),
+            vec!["Expect ';' after value."]
+        );
+        assert_eq!(
+            errors("var 1 = 2;", CompileOptions::default()),
+            vec!["Expect variable name."]
+        );
+        assert_eq!(
+            errors("var a; var b; a + b = 1;", CompileOptions::default()),
+            vec!["Invalid assignment target."]
+        );
+
+        // test constant pools larger than a byte operand can address
+        {
+            let source = (0..300)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(" + ")
+                + ";";
 
-            // NOTE: line = 3 is deliberate, the book acknowledge this
-            // flaw, and we are too lazy to come up with a solution
-            chunk.write(OpCode::Multiply as u8, 3);
+            let mut chunk = Chunk::new();
+            let mut column = 1;
+
+            (0..300).for_each(|i| {
+                let constant = chunk.constants_mut().add(Value::Number(i as f64));
+                chunk.write(OpCode::Constant as u8, 1, column);
+                if constant <= 127 {
+                    chunk.write(constant as u8, 1, column);
+                } else {
+                    chunk.write(constant as u8 | 0x80, 1, column);
+                    chunk.write((constant >> 7) as u8, 1, column);
+                }
+
+                if i > 0 {
+                    // the "+" is two columns before the number
+                    chunk.write(OpCode::Add as u8, 1, column - 2);
+                }
+
+                column += i.to_string().len() as u32 + " + ".len() as u32;
+            });
 
-            chunk.write(OpCode::Return as u8, 3);
+            chunk.write(OpCode::Pop as u8, 1, source.len() as u32);
 
-            assert_eq!(Compiler::compile("5\n*\n6".to_string()), Ok(chunk));
+            chunk.write(OpCode::Nil as u8, 1, source.len() as u32 + 1);
+
+            chunk.write(OpCode::Return as u8, 1, source.len() as u32 + 1);
+
+            assert_eq!(
+                compile_lines(&source, &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         // test basic arithmetic precedences
@@ -629,48 +2424,124 @@ mod tests {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(1.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
 
             let constant = chunk.constants_mut().add(Value::Number(6.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 9);
+            chunk.write(constant as u8, 1, 9);
+
+            chunk.write(OpCode::Multiply as u8, 1, 7);
 
-            chunk.write(OpCode::Multiply as u8, 1);
+            chunk.write(OpCode::Subtract as u8, 1, 3);
 
-            chunk.write(OpCode::Subtract as u8, 1);
+            chunk.write(OpCode::Pop as u8, 1, 10);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 11);
 
-            assert_eq!(Compiler::compile("1 - 4 * 6".to_string()), Ok(chunk));
+            chunk.write(OpCode::Return as u8, 1, 11);
+
+            assert_eq!(
+                compile_lines("1 - 4 * 6;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
         }
 
         {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(1.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 1);
+            chunk.write(constant as u8, 1, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(4.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 5);
+            chunk.write(constant as u8, 1, 5);
 
-            chunk.write(OpCode::Multiply as u8, 1);
+            chunk.write(OpCode::Multiply as u8, 1, 3);
 
             let constant = chunk.constants_mut().add(Value::Number(6.0));
-            chunk.write(OpCode::Constant as u8, 1);
-            chunk.write(constant as u8, 1);
+            chunk.write(OpCode::Constant as u8, 1, 9);
+            chunk.write(constant as u8, 1, 9);
+
+            chunk.write(OpCode::Subtract as u8, 1, 7);
+
+            chunk.write(OpCode::Pop as u8, 1, 10);
 
-            chunk.write(OpCode::Subtract as u8, 1);
+            chunk.write(OpCode::Nil as u8, 1, 11);
 
-            chunk.write(OpCode::Return as u8, 1);
+            chunk.write(OpCode::Return as u8, 1, 11);
 
-            assert_eq!(Compiler::compile("1 * 4 - 6".to_string()), Ok(chunk));
+            assert_eq!(
+                compile_lines("1 * 4 - 6;", &mut Heap::new(), CompileOptions::default()),
+                Ok(chunk)
+            );
+        }
+
+        // test locals
+        {
+            let mut chunk = Chunk::new();
+
+            let constant = chunk.constants_mut().add(Value::Number(1.0));
+            chunk.write(OpCode::Constant as u8, 1, 11);
+            chunk.write(constant as u8, 1, 11);
+
+            // the first slot is taken by the script itself
+            chunk.write(OpCode::GetLocal as u8, 1, 20);
+            chunk.write(1, 1, 20);
+            chunk.write(OpCode::Print as u8, 1, 14);
+
+            chunk.write(OpCode::Pop as u8, 1, 23);
+
+            chunk.write(OpCode::Nil as u8, 1, 24);
+
+            chunk.write(OpCode::Return as u8, 1, 24);
+
+            assert_eq!(
+                compile_lines(
+                    "{ var a = 1; print a; }",
+                    &mut Heap::new(),
+                    CompileOptions::default()
+                ),
+                Ok(chunk)
+            );
+        }
+
+        // test jumps
+        {
+            let mut chunk = Chunk::new();
+
+            chunk.write(OpCode::True as u8, 1, 5);
+
+            chunk.write(OpCode::JumpIfFalse as u8, 1, 9);
+            chunk.write(6, 1, 9);
+            chunk.write(OpCode::Pop as u8, 1, 9);
+
+            let constant = chunk.constants_mut().add(Value::Number(1.0));
+            chunk.write(OpCode::Constant as u8, 1, 17);
+            chunk.write(constant as u8, 1, 17);
+            chunk.write(OpCode::Print as u8, 1, 11);
+
+            chunk.write(OpCode::Jump as u8, 1, 18);
+            chunk.write(1, 1, 18);
+            chunk.write(OpCode::Pop as u8, 1, 18);
+
+            chunk.write(OpCode::Nil as u8, 1, 19);
+
+            chunk.write(OpCode::Return as u8, 1, 19);
+
+            assert_eq!(
+                compile_lines(
+                    "if (true) print 1;",
+                    &mut Heap::new(),
+                    CompileOptions::default()
+                ),
+                Ok(chunk)
+            );
         }
     }
 }
@@ -0,0 +1,237 @@
+// The runner of `clox test`, for the test files of the book's repository,
+// which say what they should do in comments:
+//
+//     print 1; // expect: 1
+//     -nil;    // expect runtime error: Operand must be a number.
+//     print;   // Error at ';': Expect expression.
+//     // [line 3] Error at end: Expect '}' after block.
+//
+// A compile error can be for the line of the comment, or for the line that
+// it names, and the messages are compared without the "Error at" part,
+// which the diagnostics show differently. The expectations that are only
+// for jlox, `// [java line 3] ...`, are left out.
+
+use crate::{
+    compiler::{CompileOptions, Compiler},
+    diagnostic::Severity,
+    vm::{InterpretError, VM},
+};
+
+/// What a test file expects of running it.
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    /// The lines that the script prints, in order.
+    pub output: Vec<String>,
+    /// The line and message of each compile error.
+    pub compile_errors: Vec<(u32, String)>,
+    /// The line and message of the runtime error that stops the script.
+    pub runtime_error: Option<(u32, String)>,
+}
+
+impl Expectations {
+    /// The expectations in the comments of `source`, or `None` for a file
+    /// that says `// nontest`, which isn't a test.
+    pub fn parse(source: &str) -> Option<Self> {
+        if source.contains("// nontest") {
+            return None;
+        }
+
+        let mut expectations = Self::default();
+        for (line, text) in (1..).zip(source.lines()) {
+            let Some((_, comment)) = text.split_once("// ") else {
+                continue;
+            };
+            if let Some(output) = comment.strip_prefix("expect: ") {
+                expectations.output.push(output.to_string());
+            } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+                expectations.runtime_error = Some((line, message.to_string()));
+            } else if let Some(error) = comment.strip_prefix("Error") {
+                expectations.compile_errors.push((line, message(error)));
+            } else if let Some((line, error)) = explicit_line(comment) {
+                expectations.compile_errors.push((line, message(error)));
+            }
+        }
+        Some(expectations)
+    }
+}
+
+// the line and the error of `[line 3] Error ...` or `[c line 3] Error ...`
+fn explicit_line(comment: &str) -> Option<(u32, &str)> {
+    let rest = comment.strip_prefix('[')?;
+    let rest = rest.strip_prefix("c ").unwrap_or(rest);
+    let (line, rest) = rest.strip_prefix("line ")?.split_once("] ")?;
+    Some((line.parse().ok()?, rest.strip_prefix("Error")?))
+}
+
+// the message of what comes after "Error", like ` at 'x': Expect ...`
+fn message(error: &str) -> String {
+    match error.split_once(": ") {
+        Some((_, message)) => message.to_string(),
+        None => error.trim().to_string(),
+    }
+}
+
+/// Runs the test in `source`, returning what went differently than its
+/// comments expect, which is nothing when it passes. `None` if it isn't a
+/// test.
+pub fn run(source: &str, options: CompileOptions) -> Option<Vec<String>> {
+    let expected = Expectations::parse(source)?;
+    let mut failures = vec![];
+
+    let mut vm = VM::with_output(Vec::new());
+    vm.set_report_errors(false);
+    let result = Compiler::compile_with_warnings(source, vm.heap_mut(), options);
+    let (Ok((_, diagnostics)) | Err(diagnostics)) = &result;
+    let compile_errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .map(|diagnostic| (diagnostic.span.line, diagnostic.message.clone()))
+        .collect::<Vec<_>>();
+    for (line, message) in &expected.compile_errors {
+        if !compile_errors.contains(&(*line, message.clone())) {
+            failures.push(format!(
+                "Expected the compile error '{}' on line {}.",
+                message, line
+            ));
+        }
+    }
+    for (line, message) in &compile_errors {
+        if !expected.compile_errors.contains(&(*line, message.clone())) {
+            failures.push(format!(
+                "Unexpected compile error '{}' on line {}.",
+                message, line
+            ));
+        }
+    }
+
+    let result = match result {
+        Ok((chunk, _)) => vm.run_chunk(chunk),
+        Err(_) => Err(InterpretError::CompileError),
+    };
+    let runtime_error = vm.last_error().map(|error| {
+        let line = error.trace.first().map_or(0, |frame| frame.line);
+        (line, error.message.clone())
+    });
+    match (&expected.runtime_error, &runtime_error) {
+        (Some(expected), Some(actual)) if expected != actual => failures.push(format!(
+            "Expected the runtime error '{}' on line {}, but got '{}' on line {}.",
+            expected.1, expected.0, actual.1, actual.0
+        )),
+        (Some((line, message)), None) => failures.push(format!(
+            "Expected the runtime error '{}' on line {}.",
+            message, line
+        )),
+        (None, Some((line, message))) => failures.push(format!(
+            "Unexpected runtime error '{}' on line {}.",
+            message, line
+        )),
+        _ => {}
+    }
+    let expected_code = if !expected.compile_errors.is_empty() {
+        65
+    } else if expected.runtime_error.is_some() {
+        70
+    } else {
+        0
+    };
+    let exit_code = result
+        .as_ref()
+        .map_or_else(InterpretError::exit_code, |_| 0);
+    if exit_code != expected_code {
+        failures.push(format!(
+            "Expected exit code {}, but got {}.",
+            expected_code, exit_code
+        ));
+    }
+
+    let output = String::from_utf8_lossy(vm.output()).into_owned();
+    let output = output.lines().collect::<Vec<_>>();
+    for (i, expected) in expected.output.iter().enumerate() {
+        match output.get(i) {
+            Some(actual) if actual == expected => {}
+            Some(actual) => failures.push(format!(
+                "Expected output '{}' on line {} of the output, but got '{}'.",
+                expected,
+                i + 1,
+                actual
+            )),
+            None => failures.push(format!("Missing expected output '{}'.", expected)),
+        }
+    }
+    for extra in output.iter().skip(expected.output.len()) {
+        failures.push(format!("Unexpected output '{}'.", extra));
+    }
+    Some(failures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expectations() {
+        let source = "print 1; // expect: 1\n\
+                      -nil; // expect runtime error: Operand must be a number.\n\
+                      print; // Error at ';': Expect expression.\n\
+                      // [line 7] Error at end: Expect '}' after block.\n\
+                      // [c line 8] Error: Too many constants in one chunk.\n\
+                      // [java line 9] Error: Only in jlox.\n";
+        assert_eq!(
+            Expectations::parse(source),
+            Some(Expectations {
+                output: vec!["1".to_string()],
+                compile_errors: vec![
+                    (3, "Expect expression.".to_string()),
+                    (7, "Expect '}' after block.".to_string()),
+                    (8, "Too many constants in one chunk.".to_string()),
+                ],
+                runtime_error: Some((2, "Operand must be a number.".to_string())),
+            })
+        );
+        assert_eq!(Expectations::parse("// nontest\nprint 1;"), None);
+    }
+
+    #[test]
+    fn test_run() {
+        let options = CompileOptions::default();
+        let failures = |source: &str| run(source, options).expect("a test");
+
+        assert!(failures("print 1; // expect: 1\nprint \"a\"; // expect: a").is_empty());
+        assert!(
+            failures("var a = 1;\n-nil; // expect runtime error: Operand must be a number.")
+                .is_empty()
+        );
+        assert!(failures("print 1;\nprint; // Error at ';': Expect expression.").is_empty());
+
+        assert_eq!(
+            failures("print 2; // expect: 1\nprint 3;"),
+            [
+                "Expected output '1' on line 1 of the output, but got '2'.",
+                "Unexpected output '3'."
+            ]
+        );
+        assert_eq!(
+            failures("print 1; // expect runtime error: Operand must be a number."),
+            [
+                "Expected the runtime error 'Operand must be a number.' on line 1.",
+                "Expected exit code 70, but got 0.",
+                "Unexpected output '1'."
+            ]
+        );
+        assert_eq!(
+            failures("\n-nil; // expect runtime error: Undefined variable 'a'."),
+            [
+                "Expected the runtime error 'Undefined variable 'a'.' on line 2, but got 'Operand must be a number.' on line 2."
+            ]
+        );
+        assert_eq!(
+            failures("print; // expect: 1"),
+            [
+                "Unexpected compile error 'Expect expression.' on line 1.",
+                "Expected exit code 0, but got 65.",
+                "Missing expected output '1'."
+            ]
+        );
+        assert_eq!(run("// nontest", options), None);
+    }
+}
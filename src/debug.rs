@@ -1,21 +1,819 @@
-use std::io;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::time::Duration;
 
-use crate::chunk::{Chunk, OpCode};
+use crate::{
+    chunk::{Chunk, OpCode},
+    instruction::Instruction,
+    io::{self, Write},
+    object::{Heap, ObjRef, ObjType},
+    value::Value,
+};
 
-pub fn is_debug_trace_execution_enabled() -> bool {
-    match std::env::var("DEBUG_TRACE_EXECUTION") {
-        Ok(value) => value == "1",
-        Err(_) => false,
+/// A point in time that the VM times from, which is `std::time::Instant`
+/// with the standard library. Without it there is no clock, so no time ever
+/// passes since one.
+#[cfg(feature = "std")]
+pub use std::time::Instant;
+
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy)]
+pub struct Instant;
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    pub fn now() -> Self {
+        Instant
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::ZERO
     }
 }
 
+pub fn is_debug_trace_execution_enabled() -> bool {
+    is_env_set("DEBUG_TRACE_EXECUTION")
+}
+
 pub fn is_debug_print_code_enabled() -> bool {
-    match std::env::var("DEBUG_PRINT_CODE") {
+    is_env_set("DEBUG_PRINT_CODE")
+}
+
+// whether the environment variable `name` is 1, which there are none of
+// without the standard library
+#[cfg(feature = "std")]
+fn is_env_set(name: &str) -> bool {
+    match std::env::var(name) {
         Ok(value) => value == "1",
         Err(_) => false,
     }
 }
 
+#[cfg(not(feature = "std"))]
+fn is_env_set(_: &str) -> bool {
+    false
+}
+
+/// Where the VM writes the stack and each instruction before running it.
+pub enum Trace {
+    /// Printed to stdout along with the output of the script, as the book
+    /// does.
+    Stdout,
+    /// Written to e.g. a file, away from the output of the script.
+    Writer(Box<dyn io::Write>),
+    /// Only the last `len` instructions are kept, which are printed to
+    /// stderr if the script fails with a runtime error.
+    Last {
+        len: usize,
+        entries: VecDeque<String>,
+    },
+}
+
+impl Trace {
+    pub fn last(len: usize) -> Self {
+        Trace::Last {
+            len,
+            entries: VecDeque::with_capacity(len),
+        }
+    }
+
+    /// Records the trace of one instruction, which is the stack on a line
+    /// and then the instruction on the next.
+    pub fn record(&mut self, entry: String) {
+        match self {
+            Trace::Stdout => write!(io::stdout(), "{}", entry).expect("writable"),
+            Trace::Writer(w) => w.write_all(entry.as_bytes()).expect("writable"),
+            Trace::Last { len, entries } => {
+                if *len == 0 {
+                    return;
+                }
+                if entries.len() == *len {
+                    entries.pop_front();
+                }
+                entries.push_back(entry);
+            }
+        }
+    }
+
+    /// Writes out what is recorded but not written yet. This is called when
+    /// a script stops, and `failed` is whether it was a runtime error, which
+    /// is when the last instructions are printed.
+    pub fn finish(&mut self, failed: bool) {
+        match self {
+            Trace::Stdout => {}
+            Trace::Writer(w) => w.flush().expect("writable"),
+            Trace::Last { entries, .. } => {
+                if failed && !entries.is_empty() {
+                    let mut stderr = io::stderr();
+                    writeln!(stderr, "== last {} instructions ==", entries.len())
+                        .expect("writable");
+                    for entry in entries.iter() {
+                        write!(stderr, "{}", entry).expect("writable");
+                    }
+                }
+                entries.clear();
+            }
+        }
+    }
+}
+
+// how wide the bar of the most frequent opcode is in the histogram
+const HISTOGRAM_WIDTH: u64 = 40;
+
+/// How many times the VM has run each opcode, for finding out which
+/// instructions are worth making faster or fusing together.
+#[derive(Debug, Clone)]
+pub struct OpcodeCounts {
+    // indexed by the byte of the opcode
+    counts: Vec<u64>,
+}
+
+impl Default for OpcodeCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OpcodeCounts {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; OpCode::ALL.len()],
+        }
+    }
+
+    pub fn record(&mut self, opcode: OpCode) {
+        self.counts[opcode as usize] += 1;
+    }
+
+    pub fn get(&self, opcode: OpCode) -> u64 {
+        self.counts[opcode as usize]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The opcodes that have run, the most frequent first, and in the order
+    /// of their bytes when they ran as often.
+    pub fn sorted(&self) -> Vec<(OpCode, u64)> {
+        let mut sorted = OpCode::ALL
+            .iter()
+            .map(|&opcode| (opcode, self.get(opcode)))
+            .filter(|&(_, count)| count > 0)
+            .collect::<Vec<_>>();
+        sorted.sort_by(|(_, a), (_, b)| b.cmp(a));
+        sorted
+    }
+
+    /// Prints [`OpcodeCounts::sorted`] with the share of each opcode and a
+    /// bar that is as long relative to the others.
+    pub fn write_histogram<W: io::Write>(&self, w: &mut W) {
+        let total = self.total();
+        writeln!(w, "== opcode counts ({} instructions) ==", total).expect("writable");
+
+        let sorted = self.sorted();
+        let max = sorted.first().map_or(0, |&(_, count)| count);
+        for (opcode, count) in sorted {
+            writeln!(
+                w,
+                "{:<26} {:>12} {:>6.2}% {}",
+                opcode.mnemonic(),
+                count,
+                count as f64 * 100.0 / total as f64,
+                "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(max) as usize)
+            )
+            .expect("writable");
+        }
+    }
+}
+
+/// What refers to an object on the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retainer {
+    /// The value of the global with this name.
+    Global(ObjRef),
+    /// The name of a global.
+    GlobalName,
+    /// A slot on the VM's stack.
+    Stack(usize),
+    /// The tiered up copy of this function, which is run instead of it.
+    TieredUp(ObjRef),
+    /// Another object, e.g. a function that has it as its name or one of
+    /// its constants, or a string that was concatenated from it.
+    Object(ObjRef),
+}
+
+/// An object in a [`HeapDump`].
+#[derive(Debug, Clone)]
+pub struct HeapObject {
+    pub obj: ObjRef,
+    /// The bytes that the object takes up, along with the buffers that it
+    /// owns, but without the objects that it refers to.
+    pub size: usize,
+    pub retainers: Vec<Retainer>,
+    /// Whether it can be reached from the roots that the dump was made
+    /// with, and so is still in use. The others are garbage that the heap
+    /// keeps until it is dropped.
+    pub reachable: bool,
+}
+
+/// The objects on a heap along with their sizes and what refers to them,
+/// for finding out what a script holds onto.
+#[derive(Debug, Clone)]
+pub struct HeapDump {
+    /// The oldest first.
+    pub objects: Vec<HeapObject>,
+}
+
+impl HeapDump {
+    /// `roots` are the references to the objects from outside the heap, such
+    /// as the globals of a VM.
+    pub fn new<I: IntoIterator<Item = (Retainer, ObjRef)>>(heap: &Heap, roots: I) -> Self {
+        let mut objects = heap
+            .iter()
+            .map(|obj| HeapObject {
+                obj,
+                size: obj.size(),
+                retainers: vec![],
+                reachable: false,
+            })
+            .collect::<Vec<_>>();
+        objects.reverse();
+        let index = objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.obj, i))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut reached = vec![];
+        for (retainer, obj) in roots {
+            if let Some(&i) = index.get(&obj) {
+                objects[i].retainers.push(retainer);
+                reached.push(i);
+            }
+        }
+
+        for i in 0..objects.len() {
+            let obj = objects[i].obj;
+            for referent in references(obj) {
+                // a constant can be in the pool more than once
+                if let Some(&j) = index.get(&referent)
+                    && !objects[j].retainers.contains(&Retainer::Object(obj))
+                {
+                    objects[j].retainers.push(Retainer::Object(obj));
+                }
+            }
+        }
+
+        while let Some(i) = reached.pop() {
+            if core::mem::replace(&mut objects[i].reachable, true) {
+                continue;
+            }
+            reached.extend(
+                references(objects[i].obj)
+                    .iter()
+                    .filter_map(|obj| index.get(obj)),
+            );
+        }
+
+        Self { objects }
+    }
+
+    /// How many objects there are of each type and how many bytes they take
+    /// up, the types with the most bytes first.
+    pub fn by_type(&self) -> Vec<(ObjType, usize, usize)> {
+        let mut types: Vec<(ObjType, usize, usize)> = vec![];
+        for object in &self.objects {
+            let obj_type = object.obj.obj_type();
+            match types.iter_mut().find(|(t, _, _)| *t == obj_type) {
+                Some((_, count, size)) => {
+                    *count += 1;
+                    *size += object.size;
+                }
+                None => types.push((obj_type, 1, object.size)),
+            }
+        }
+        types.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+        types
+    }
+
+    /// Prints the totals of each type, and then every object with its
+    /// index, type, size, and what refers to it by index.
+    pub fn write<W: io::Write>(&self, w: &mut W) {
+        let index = self
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.obj, i))
+            .collect::<BTreeMap<_, _>>();
+        let describe = |obj: &ObjRef| match index.get(obj) {
+            Some(i) => format!("#{}", i),
+            None => format!("{:?}", obj),
+        };
+
+        writeln!(
+            w,
+            "== heap ({} objects, {} bytes) ==",
+            self.objects.len(),
+            self.objects.iter().map(|object| object.size).sum::<usize>()
+        )
+        .expect("writable");
+        for (obj_type, count, size) in self.by_type() {
+            writeln!(w, "{:<10} {:>8} {:>12}", type_name(obj_type), count, size).expect("writable");
+        }
+
+        writeln!(w, "-- objects --").expect("writable");
+        for (i, object) in self.objects.iter().enumerate() {
+            let retainers = object
+                .retainers
+                .iter()
+                .map(|retainer| match retainer {
+                    Retainer::Global(name) => format!("global {}", name),
+                    Retainer::GlobalName => "global name".to_string(),
+                    Retainer::Stack(slot) => format!("stack slot {}", slot),
+                    Retainer::TieredUp(function) => format!("tiered up {}", describe(function)),
+                    Retainer::Object(obj) => describe(obj),
+                })
+                .collect::<Vec<_>>();
+            writeln!(
+                w,
+                "#{:<5} {:<10} {:>8} {}{}{}",
+                i,
+                type_name(object.obj.obj_type()),
+                object.size,
+                preview(object.obj),
+                if retainers.is_empty() {
+                    String::new()
+                } else {
+                    format!(" <- {}", retainers.join(", "))
+                },
+                if object.reachable {
+                    ""
+                } else {
+                    " (unreachable)"
+                }
+            )
+            .expect("writable");
+        }
+    }
+}
+
+// the objects that `obj` refers to
+fn references(obj: ObjRef) -> Vec<ObjRef> {
+    if let Some(string) = obj.as_string() {
+        return string.parts().to_vec();
+    }
+    let Some(function) = obj.as_function() else {
+        return vec![];
+    };
+
+    let constants = function
+        .chunk
+        .constants()
+        .iter()
+        .filter_map(|constant| match constant {
+            Value::Obj(obj) => Some(obj),
+            _ => None,
+        });
+    function.name.into_iter().chain(constants).collect()
+}
+
+fn type_name(obj_type: ObjType) -> &'static str {
+    match obj_type {
+        ObjType::String => "string",
+        ObjType::Function => "function",
+        ObjType::Decimal => "decimal",
+        ObjType::Native => "native",
+    }
+}
+
+// how an object is shown in the heap dump, where long strings are cut short
+fn preview(obj: ObjRef) -> String {
+    const PREVIEW_LEN: usize = 32;
+
+    match obj.as_string() {
+        Some(string) if string.as_str().chars().count() > PREVIEW_LEN => {
+            let start = string
+                .as_str()
+                .chars()
+                .take(PREVIEW_LEN)
+                .collect::<String>();
+            format!("{:?}...", start)
+        }
+        Some(string) => format!("{:?}", string.as_str()),
+        None => format!("{}", obj),
+    }
+}
+
+/// How many instructions the VM has run in each call stack, which
+/// [`StackProfile::write_folded`] writes in the folded format that
+/// flamegraph tools such as inferno read. It counts instructions instead of
+/// timing them, so unlike the `profile` feature it does not need a clock.
+#[derive(Debug, Clone)]
+pub struct StackProfile {
+    // the tree of the calls that have been made, where the root is outside
+    // of any function and every other node is a call from its parent
+    nodes: Vec<StackNode>,
+    // the node of each function that has been called from a node
+    children: BTreeMap<(usize, ObjRef), usize>,
+    // the node of the call that is running
+    current: usize,
+}
+
+#[derive(Debug, Clone)]
+struct StackNode {
+    parent: usize,
+    function: Option<ObjRef>,
+    // the instructions run in the function itself, and not in its callees
+    instructions: u64,
+}
+
+impl Default for StackProfile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StackProfile {
+    pub fn new() -> Self {
+        Self {
+            nodes: vec![StackNode {
+                parent: 0,
+                function: None,
+                instructions: 0,
+            }],
+            children: BTreeMap::new(),
+            current: 0,
+        }
+    }
+
+    /// Counts an instruction that ran in the call that is running.
+    pub fn record(&mut self) {
+        self.nodes[self.current].instructions += 1;
+    }
+
+    /// Starts a call to `function` from the call that is running.
+    pub fn enter(&mut self, function: ObjRef) {
+        let next = self.nodes.len();
+        let node = *self
+            .children
+            .entry((self.current, function))
+            .or_insert(next);
+        if node == next {
+            self.nodes.push(StackNode {
+                parent: self.current,
+                function: Some(function),
+                instructions: 0,
+            });
+        }
+        self.current = node;
+    }
+
+    /// Returns from the call that is running to its caller.
+    pub fn exit(&mut self) {
+        self.current = self.nodes[self.current].parent;
+    }
+
+    /// Goes back outside of every call, e.g. after a runtime error has
+    /// unwound them all at once.
+    pub fn reset(&mut self) {
+        self.current = 0;
+    }
+
+    /// The call stacks that ran instructions, as the names of their
+    /// functions from the outermost, along with how many instructions ran
+    /// in them. They are sorted, and the stacks through the tiered up copies
+    /// of a function are merged with the ones through the function.
+    pub fn folded(&self) -> Vec<(String, u64)> {
+        let mut stacks = BTreeMap::<String, u64>::new();
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.instructions == 0 {
+                continue;
+            }
+
+            let mut names = vec![];
+            let mut current = i;
+            while let Some(function) = &self.nodes[current].function {
+                names.push(
+                    function
+                        .as_function()
+                        .and_then(|function| function.name())
+                        .unwrap_or("<script>"),
+                );
+                current = self.nodes[current].parent;
+            }
+            names.reverse();
+            *stacks.entry(names.join(";")).or_default() += node.instructions;
+        }
+
+        let mut stacks = stacks.into_iter().collect::<Vec<_>>();
+        stacks.sort();
+        stacks
+    }
+
+    /// Writes [`StackProfile::folded`] with one stack on each line, which
+    /// is its functions separated by `;`, then a space and the count.
+    pub fn write_folded<W: io::Write>(&self, w: &mut W) -> io::Result<()> {
+        for (stack, instructions) in self.folded() {
+            writeln!(w, "{} {}", stack, instructions)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which lines of a script have run and how many times, for
+/// `clox --coverage`. A line runs each time that the VM gets to it from
+/// another line of the same call, or calls a function, so a loop that is all
+/// on one line only runs it once.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    // how many times each line with code has run, which stays zero for the
+    // lines that never do
+    lines: BTreeMap<u32, u64>,
+    // the line that each call is on, from the outermost
+    calls: Vec<u32>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the lines of the code in `chunk`, and of the functions declared
+    /// in it, as the lines that can run.
+    pub fn add_chunk(&mut self, chunk: &Chunk) {
+        for i in 0..chunk.code_len() {
+            self.lines.entry(chunk.get_line(i)).or_default();
+        }
+        for constant in chunk.constants().iter() {
+            if let Value::Obj(obj) = constant
+                && let Some(function) = obj.as_function()
+            {
+                self.add_chunk(&function.chunk);
+            }
+        }
+    }
+
+    /// Counts an instruction on `line`, in a call `depth` deep, where the
+    /// script is 1 deep.
+    pub fn record(&mut self, depth: usize, line: u32) {
+        // returning from a call usually goes back to the middle of a line
+        // that has already been counted
+        self.calls.truncate(depth);
+        if self.calls.len() == depth && self.calls[depth - 1] == line {
+            return;
+        }
+        self.calls.resize(depth, 0);
+        self.calls[depth - 1] = line;
+        *self.lines.entry(line).or_default() += 1;
+    }
+
+    /// Forgets the calls, e.g. once a script has finished.
+    pub fn reset(&mut self) {
+        self.calls.clear();
+    }
+
+    /// How many times the line has run, or `None` if it has no code.
+    pub fn hits(&self, line: u32) -> Option<u64> {
+        self.lines.get(&line).copied()
+    }
+
+    /// How many lines have code, and how many of them have run.
+    pub fn summary(&self) -> (usize, usize) {
+        let hit = self.lines.values().filter(|&&hits| hits > 0).count();
+        (self.lines.len(), hit)
+    }
+
+    /// Writes the coverage of the script at `path` as an lcov tracefile, for
+    /// tools such as genhtml.
+    pub fn write_lcov<W: io::Write>(&self, w: &mut W, path: &str) -> io::Result<()> {
+        writeln!(w, "TN:")?;
+        writeln!(w, "SF:{}", path)?;
+        for (line, hits) in &self.lines {
+            writeln!(w, "DA:{},{}", line, hits)?;
+        }
+        let (found, hit) = self.summary();
+        writeln!(w, "LF:{}", found)?;
+        writeln!(w, "LH:{}", hit)?;
+        writeln!(w, "end_of_record")
+    }
+
+    /// Writes every line of `source` after how many times it ran, like gcov
+    /// does: `-` for a line without code and `#####` for one that never ran.
+    pub fn write_annotated<W: io::Write>(&self, w: &mut W, source: &str) {
+        let mut lines = source.lines().collect::<Vec<_>>();
+        // without the source, e.g. of a compiled script, there are only the
+        // line numbers
+        let last = self.lines.keys().next_back().copied().unwrap_or(0);
+        lines.resize(lines.len().max(last as usize), "");
+
+        for (i, text) in lines.into_iter().enumerate() {
+            let line = i as u32 + 1;
+            let hits = match self.hits(line) {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(hits) => hits.to_string(),
+            };
+            writeln!(w, "{:>9}:{:>5}:{}", hits, line, text).expect("writable");
+        }
+
+        let (found, hit) = self.summary();
+        let percent = if found == 0 {
+            100.0
+        } else {
+            hit as f64 * 100.0 / found as f64
+        };
+        writeln!(w, "Lines executed: {:.2}% of {}", percent, found).expect("writable");
+    }
+}
+
+/// How long the scripts took to compile and to run, and how much work
+/// running them was, for `clox --time`. Unlike [`Profile`], the clock is
+/// only read around each script, so it is always built.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timing {
+    /// Compiling the source, or loading the bytecode.
+    pub compile: Duration,
+    pub execute: Duration,
+    pub instructions: u64,
+    /// The most values that were on the stack at once.
+    pub peak_stack: usize,
+    /// The objects that were allocated on the heap, other than strings that
+    /// were interned already.
+    pub allocations: usize,
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an instruction that runs with `stack` values on the stack.
+    pub fn record(&mut self, stack: usize) {
+        self.instructions += 1;
+        self.peak_stack = self.peak_stack.max(stack);
+    }
+
+    pub fn write<W: io::Write>(&self, w: &mut W) {
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        writeln!(w, "== time ==").expect("writable");
+        writeln!(w, "{:<13} {:>12.3} ms", "compile", millis(self.compile)).expect("writable");
+        writeln!(w, "{:<13} {:>12.3} ms", "execute", millis(self.execute)).expect("writable");
+        writeln!(w, "{:<13} {:>12}", "instructions", self.instructions).expect("writable");
+        writeln!(w, "{:<13} {:>12} values", "peak stack", self.peak_stack).expect("writable");
+        writeln!(w, "{:<13} {:>12} objects", "allocations", self.allocations).expect("writable");
+    }
+}
+
+/// How long the VM has spent running each opcode, and in the code of each
+/// function, for `clox --profile`. The clock is read once per instruction,
+/// and the time until the next reading goes to the instruction that ran in
+/// between, which is why this is only built with the `profile` feature.
+#[cfg(feature = "profile")]
+#[derive(Debug, Clone)]
+pub struct Profile {
+    // how many times each opcode ran and for how long, indexed by its byte
+    opcodes: Vec<(u64, Duration)>,
+    // the time spent in each function itself, without the functions that it
+    // calls
+    functions: BTreeMap<ObjRef, Duration>,
+    // the instruction that is running, the function that it is in, and
+    // when it started
+    running: Option<(OpCode, ObjRef, Instant)>,
+}
+
+#[cfg(feature = "profile")]
+impl Default for Profile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "profile")]
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            opcodes: vec![(0, Duration::ZERO); OpCode::ALL.len()],
+            functions: BTreeMap::new(),
+            running: None,
+        }
+    }
+
+    /// Ends the instruction that was running, and starts timing `opcode`,
+    /// which runs in `function`.
+    pub fn start(&mut self, opcode: OpCode, function: ObjRef) {
+        let now = Instant::now();
+        self.finish(now);
+        self.running = Some((opcode, function, now));
+    }
+
+    /// Ends the instruction that was running, when the VM stops.
+    pub fn stop(&mut self) {
+        self.finish(Instant::now());
+    }
+
+    fn finish(&mut self, now: Instant) {
+        if let Some((opcode, function, started)) = self.running.take() {
+            let elapsed = now - started;
+            let (count, total) = &mut self.opcodes[opcode as usize];
+            *count += 1;
+            *total += elapsed;
+            *self.functions.entry(function).or_default() += elapsed;
+        }
+    }
+
+    /// How many times `opcode` ran, and for how long in total.
+    pub fn opcode(&self, opcode: OpCode) -> (u64, Duration) {
+        self.opcodes[opcode as usize]
+    }
+
+    /// The time spent in the code of each function, by name, the longest
+    /// first. The copies of a function that were tiered up count as the
+    /// function.
+    pub fn functions(&self) -> Vec<(String, Duration)> {
+        let mut functions = BTreeMap::<String, Duration>::new();
+        for (function, &elapsed) in &self.functions {
+            let name = function
+                .as_function()
+                .and_then(|function| function.name())
+                .unwrap_or("<script>");
+            *functions.entry(name.to_string()).or_default() += elapsed;
+        }
+
+        let mut functions = functions.into_iter().collect::<Vec<_>>();
+        functions.sort_by(|(a_name, a), (b_name, b)| b.cmp(a).then(a_name.cmp(b_name)));
+        functions
+    }
+
+    /// Prints the opcodes that ran and the functions that they ran in, the
+    /// ones that took the longest first.
+    pub fn write_table<W: io::Write>(&self, w: &mut W) {
+        let mut opcodes = OpCode::ALL
+            .iter()
+            .map(|&opcode| (opcode, self.opcode(opcode)))
+            .filter(|&(_, (count, _))| count > 0)
+            .collect::<Vec<_>>();
+        opcodes.sort_by(|(_, (_, a)), (_, (_, b))| b.cmp(a));
+        let total = opcodes
+            .iter()
+            .map(|(_, (_, elapsed))| *elapsed)
+            .sum::<Duration>();
+
+        writeln!(w, "== profile ({:.3?}) ==", total).expect("writable");
+        writeln!(
+            w,
+            "{:<26} {:>12} {:>12} {:>7} {:>10}",
+            "opcode", "count", "total", "share", "average"
+        )
+        .expect("writable");
+        for (opcode, (count, elapsed)) in opcodes {
+            writeln!(
+                w,
+                "{:<26} {:>12} {:>12.3?} {:>6.2}% {:>10.1?}",
+                opcode.mnemonic(),
+                count,
+                elapsed,
+                share(elapsed, total),
+                elapsed.div_f64(count as f64)
+            )
+            .expect("writable");
+        }
+
+        writeln!(
+            w,
+            "{:<26} {:>12} {:>12} {:>7}",
+            "function", "", "total", "share"
+        )
+        .expect("writable");
+        for (name, elapsed) in self.functions() {
+            writeln!(
+                w,
+                "{:<26} {:>12} {:>12.3?} {:>6.2}%",
+                name,
+                "",
+                elapsed,
+                share(elapsed, total)
+            )
+            .expect("writable");
+        }
+    }
+}
+
+// `part` as a percentage of `total`
+#[cfg(feature = "profile")]
+fn share(part: Duration, total: Duration) -> f64 {
+    if total.is_zero() {
+        return 0.0;
+    }
+    part.as_secs_f64() * 100.0 / total.as_secs_f64()
+}
+
 pub fn disassemble_chunk<S: AsRef<str>, W: io::Write>(w: &mut W, chunk: &Chunk, name: S) {
     writeln!(w, "== {} ==", name.as_ref()).expect("writable");
 
@@ -25,66 +823,308 @@ pub fn disassemble_chunk<S: AsRef<str>, W: io::Write>(w: &mut W, chunk: &Chunk,
     }
 }
 
+/// [`disassemble_chunk`], into a string rather than a writer.
+pub fn disassemble_chunk_to_string<S: AsRef<str>>(chunk: &Chunk, name: S) -> String {
+    let mut output = Vec::new();
+    disassemble_chunk(&mut output, chunk, name);
+    String::from_utf8(output).expect("ICE: Disassembly is not valid UTF-8")
+}
+
+/// What `disassemble_program` prints for each chunk besides its code.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DisassembleOptions {
+    // every constant, including the ones that no instruction loads
+    pub constants: bool,
+    // the line, column and range of the source that each run of bytes
+    // comes from
+    pub lines: bool,
+}
+
+/// Disassembles `chunk` along with the chunks of the functions among its
+/// constants, which come before the chunk that they are declared in, the
+/// same as when the compiler prints them.
+pub fn disassemble_program<S: AsRef<str>, W: io::Write>(
+    w: &mut W,
+    chunk: &Chunk,
+    name: S,
+    options: DisassembleOptions,
+) {
+    for constant in chunk.constants().iter() {
+        if let Value::Obj(obj) = constant
+            && let Some(function) = obj.as_function()
+        {
+            let name = function.name().unwrap_or("<script>");
+            disassemble_program(w, &function.chunk, name, options);
+        }
+    }
+
+    disassemble_chunk(w, chunk, name);
+
+    if options.constants {
+        writeln!(w, "-- constants --").expect("writable");
+        for (i, constant) in chunk.constants().iter().enumerate() {
+            writeln!(w, "{:4} '{}'", i, constant).expect("writable");
+        }
+    }
+
+    if options.lines {
+        writeln!(w, "-- lines --").expect("writable");
+        for i in 0..chunk.code_len() {
+            let span = chunk.get_span(i);
+            if i == 0 || span != chunk.get_span(i - 1) {
+                writeln!(
+                    w,
+                    "{:04} {}:{} {}..{}",
+                    i, span.line, span.column, span.start, span.end
+                )
+                .expect("writable");
+            }
+        }
+    }
+}
+
 pub fn disassemble_instruction<W: io::Write>(w: &mut W, chunk: &Chunk, offset: usize) -> usize {
     write!(w, "{:04} ", offset).expect("writable");
 
-    if offset > 0 && chunk.get_line(offset) == chunk.get_line(offset - 1) {
+    if !chunk.starts_new_line(offset) {
         write!(w, "   | ").expect("writable");
     } else {
         write!(w, "{:4} ", chunk.get_line(offset)).expect("writable");
     }
 
-    let instruction = chunk.get_code(offset);
-    match OpCode::try_from(instruction) {
-        Ok(code) => match code {
-            OpCode::Return => simple_instruction(w, "OP_RETURN", offset),
-            OpCode::Constant => constant_instruction(w, "OP_CONSTANT", chunk, offset),
-            OpCode::Negate => simple_instruction(w, "OP_NEGATE", offset),
-            OpCode::Add => simple_instruction(w, "OP_ADD", offset),
-            OpCode::Subtract => simple_instruction(w, "OP_SUBTRACT", offset),
-            OpCode::Multiply => simple_instruction(w, "OP_MULTIPLY", offset),
-            OpCode::Divide => simple_instruction(w, "OP_DIVIDE", offset),
-            OpCode::Nil => simple_instruction(w, "OP_NIL", offset),
-            OpCode::True => simple_instruction(w, "OP_TRUE", offset),
-            OpCode::False => simple_instruction(w, "OP_FALSE", offset),
-            OpCode::Not => simple_instruction(w, "OP_NOT", offset),
-            OpCode::Equal => simple_instruction(w, "OP_EQUAL", offset),
-            OpCode::Greater => simple_instruction(w, "OP_GREATER", offset),
-            OpCode::Less => simple_instruction(w, "OP_LESS", offset),
-        },
-        Err(_) => {
-            writeln!(w, "Unknown opcode {}", instruction).expect("writable");
-            offset + 1
-        }
-    }
-}
-
-fn simple_instruction<S: AsRef<str>, W: io::Write>(w: &mut W, name: S, offset: usize) -> usize {
+    let Some((instruction, next)) = Instruction::decode(chunk, offset) else {
+        writeln!(w, "Unknown opcode {}", chunk.get_code(offset)).expect("writable");
+        return offset + 1;
+    };
+
+    let name = instruction.opcode().mnemonic();
+    match instruction {
+        Instruction::Constant(constant) | Instruction::AddConstant(constant) => {
+            constant_instruction(w, name, chunk, constant as usize)
+        }
+        Instruction::DefineGlobal(constant)
+        | Instruction::GetGlobal(constant)
+        | Instruction::SetGlobal(constant) => {
+            constant_instruction(w, name, chunk, constant as usize)
+        }
+        Instruction::GetLocalAddConstant(slot, constant) => {
+            local_constant_instruction(w, name, chunk, slot, constant)
+        }
+        Instruction::Jump(jump) | Instruction::JumpIfFalse(jump) => {
+            jump_instruction(w, name, offset, next as isize + jump as isize)
+        }
+        Instruction::Loop(jump) => jump_instruction(w, name, offset, next as isize - jump as isize),
+        Instruction::GetLocal(slot)
+        | Instruction::SetLocal(slot)
+        | Instruction::Call(slot)
+        | Instruction::PopN(slot) => byte_instruction(w, name, slot as u32),
+        Instruction::GetGlobalCached(slot)
+        | Instruction::SetGlobalCached(slot)
+        | Instruction::ConcatN(slot) => byte_instruction(w, name, slot),
+        _ => simple_instruction(w, name),
+    }
+
+    next
+}
+
+/// [`disassemble_instruction`], into a string rather than a writer. Returns
+/// the line, with its newline, and the offset of the next instruction.
+pub fn disassemble_instruction_to_string(chunk: &Chunk, offset: usize) -> (String, usize) {
+    let mut output = Vec::new();
+    let next = disassemble_instruction(&mut output, chunk, offset);
+    (
+        String::from_utf8(output).expect("ICE: Disassembly is not valid UTF-8"),
+        next,
+    )
+}
+
+/// Disassembles `chunk` and the chunks of its functions as a JSON array,
+/// in the same order as [`disassemble_program`], for visualizers and for
+/// diffing the code that the compiler makes. Each instruction is an object
+/// on a line of its own, from [`instruction_to_json`].
+pub fn disassemble_program_json<S: AsRef<str>, W: io::Write>(w: &mut W, chunk: &Chunk, name: S) {
+    write!(w, "[").expect("writable");
+    chunk_json(w, chunk, name.as_ref(), &mut true);
+    writeln!(w, "\n]").expect("writable");
+}
+
+// writes the functions among the constants and then `chunk` itself, with a
+// comma before every chunk but the first
+fn chunk_json<W: io::Write>(w: &mut W, chunk: &Chunk, name: &str, first: &mut bool) {
+    for constant in chunk.constants().iter() {
+        if let Value::Obj(obj) = constant
+            && let Some(function) = obj.as_function()
+        {
+            let name = function.name().unwrap_or("<script>");
+            chunk_json(w, &function.chunk, name, first);
+        }
+    }
+
+    if !core::mem::take(first) {
+        write!(w, ",").expect("writable");
+    }
+    writeln!(w, "\n  {{").expect("writable");
+    writeln!(w, "    \"name\": {},", json_string(name)).expect("writable");
+    writeln!(w, "    \"code\": [").expect("writable");
+    let mut offset = 0;
+    while offset < chunk.code_len() {
+        let (json, next) = instruction_to_json(chunk, offset);
+        let comma = if next < chunk.code_len() { "," } else { "" };
+        writeln!(w, "      {}{}", json, comma).expect("writable");
+        offset = next;
+    }
+    writeln!(w, "    ]").expect("writable");
+    write!(w, "  }}").expect("writable");
+}
+
+/// The instruction at `offset` as a JSON object, along with the offset of
+/// the next instruction. The object has the `offset`, `line`, `mnemonic`
+/// and decoded `operands` of the instruction, as well as the `constant`
+/// that it loads, printed the way that Lox prints it (`null` if it is not in
+/// the pool), and the `target` of a jump. A byte that is not an opcode is
+/// `{"offset": ..., "line": ..., "unknown": <byte>}`.
+pub fn instruction_to_json(chunk: &Chunk, offset: usize) -> (String, usize) {
+    let line = chunk.get_line(offset);
+    let Some((instruction, next)) = Instruction::decode(chunk, offset) else {
+        let json = format!(
+            r#"{{"offset": {}, "line": {}, "unknown": {}}}"#,
+            offset,
+            line,
+            chunk.get_code(offset)
+        );
+        return (json, offset + 1);
+    };
+
+    let (operands, constant, target) = match instruction {
+        Instruction::Constant(constant) | Instruction::AddConstant(constant) => {
+            (vec![constant], Some(constant), None)
+        }
+        Instruction::DefineGlobal(constant)
+        | Instruction::GetGlobal(constant)
+        | Instruction::SetGlobal(constant) => (vec![constant], Some(constant), None),
+        Instruction::GetLocalAddConstant(slot, constant) => {
+            (vec![slot as u32, constant], Some(constant), None)
+        }
+        Instruction::Jump(jump) | Instruction::JumpIfFalse(jump) => {
+            (vec![jump], None, Some(next as isize + jump as isize))
+        }
+        Instruction::Loop(jump) => (vec![jump], None, Some(next as isize - jump as isize)),
+        Instruction::GetLocal(slot)
+        | Instruction::SetLocal(slot)
+        | Instruction::Call(slot)
+        | Instruction::PopN(slot) => (vec![slot as u32], None, None),
+        Instruction::GetGlobalCached(slot)
+        | Instruction::SetGlobalCached(slot)
+        | Instruction::ConcatN(slot) => (vec![slot], None, None),
+        _ => (vec![], None, None),
+    };
+
+    let mut json = format!(
+        r#"{{"offset": {}, "line": {}, "mnemonic": "{}", "operands": [{}]"#,
+        offset,
+        line,
+        instruction.opcode().mnemonic(),
+        operands
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    if let Some(constant) = constant {
+        let value = match chunk.constants().try_get(constant as usize) {
+            Some(value) => json_string(&value.to_string()),
+            None => "null".to_string(),
+        };
+        json.push_str(&format!(r#", "constant": {}"#, value));
+    }
+    if let Some(target) = target {
+        json.push_str(&format!(r#", "target": {}"#, target));
+    }
+    json.push('}');
+
+    (json, next)
+}
+
+/// `s` as a JSON string, quoted and escaped.
+pub fn json_string(s: &str) -> String {
+    let mut json = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn simple_instruction<S: AsRef<str>, W: io::Write>(w: &mut W, name: S) {
     writeln!(w, "{}", name.as_ref()).expect("writable");
-    offset + 1
+}
+
+fn byte_instruction<S: AsRef<str>, W: io::Write>(w: &mut W, name: S, slot: u32) {
+    writeln!(w, "{:<16} {:4}", name.as_ref(), slot).expect("writable");
+}
+
+fn jump_instruction<S: AsRef<str>, W: io::Write>(w: &mut W, name: S, offset: usize, target: isize) {
+    writeln!(w, "{:<16} {:4} -> {}", name.as_ref(), offset, target).expect("writable");
 }
 
 fn constant_instruction<S: AsRef<str>, W: io::Write>(
     w: &mut W,
     name: S,
     chunk: &Chunk,
-    offset: usize,
-) -> usize {
-    let constant = chunk.get_code(offset + 1);
+    constant: usize,
+) {
+    writeln!(
+        w,
+        "{:<16} {:4} {}",
+        name.as_ref(),
+        constant,
+        constant_value(chunk, constant)
+    )
+    .expect("writable");
+}
+
+fn local_constant_instruction<S: AsRef<str>, W: io::Write>(
+    w: &mut W,
+    name: S,
+    chunk: &Chunk,
+    slot: u8,
+    constant: u32,
+) {
     writeln!(
         w,
-        "{:<16} {:4} '{:?}'",
+        "{:<16} {:4} {:4} {}",
         name.as_ref(),
+        slot,
         constant,
-        chunk.constants().get(constant as usize)
+        constant_value(chunk, constant as usize)
     )
     .expect("writable");
-    offset + 2
+}
+
+// the constant at `index` as it is printed next to an instruction, which
+// does not have to exist in code that is being debugged
+fn constant_value(chunk: &Chunk, index: usize) -> String {
+    match chunk.constants().try_get(index) {
+        Some(constant) => format!("'{}'", constant),
+        None => "<missing>".to_string(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::value::Value;
+    use crate::{
+        chunk::OpCode,
+        compiler::{CompileOptions, Compiler},
+        object::Heap,
+    };
 
     use super::*;
 
@@ -94,42 +1134,38 @@ mod tests {
             let mut chunk = Chunk::new();
 
             let constant = chunk.constants_mut().add(Value::Number(1.2));
-            chunk.write(OpCode::Constant as u8, 123);
-            chunk.write(constant as u8, 123);
+            chunk.write(OpCode::Constant as u8, 123, 1);
+            chunk.write(constant as u8, 123, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(3.4));
-            chunk.write(OpCode::Constant as u8, 123);
-            chunk.write(constant as u8, 123);
+            chunk.write(OpCode::Constant as u8, 123, 1);
+            chunk.write(constant as u8, 123, 1);
 
-            chunk.write(OpCode::Add as u8, 123);
+            chunk.write(OpCode::Add as u8, 123, 1);
 
             let constant = chunk.constants_mut().add(Value::Number(5.6));
-            chunk.write(OpCode::Constant as u8, 123);
-            chunk.write(constant as u8, 123);
+            chunk.write(OpCode::Constant as u8, 123, 1);
+            chunk.write(constant as u8, 123, 1);
 
-            chunk.write(OpCode::Divide as u8, 123);
-            chunk.write(OpCode::Negate as u8, 123);
+            chunk.write(OpCode::Divide as u8, 123, 1);
+            chunk.write(OpCode::Negate as u8, 123, 1);
 
-            chunk.write(OpCode::Return as u8, 123);
+            chunk.write(OpCode::Return as u8, 123, 1);
 
-            chunk.write(OpCode::Subtract as u8, 124);
-            chunk.write(OpCode::Multiply as u8, 125);
-            chunk.write(255, 125); // invalid opcode
-
-            let mut output = Vec::new();
-            disassemble_chunk(&mut output, &chunk, "test chunk");
+            chunk.write(OpCode::Subtract as u8, 124, 1);
+            chunk.write(OpCode::Multiply as u8, 125, 1);
+            chunk.write(255, 125, 1); // invalid opcode
 
             assert_eq!(
-                String::from_utf8(output)
-                    .expect("valid utf8")
+                disassemble_chunk_to_string(&chunk, "test chunk")
                     .lines()
                     .collect::<Vec<_>>(),
                 vec![
                     "== test chunk ==",
-                    "0000  123 OP_CONSTANT         0 'Number(1.2)'",
-                    "0002    | OP_CONSTANT         1 'Number(3.4)'",
+                    "0000  123 OP_CONSTANT         0 '1.2'",
+                    "0002    | OP_CONSTANT         1 '3.4'",
                     "0004    | OP_ADD",
-                    "0005    | OP_CONSTANT         2 'Number(5.6)'",
+                    "0005    | OP_CONSTANT         2 '5.6'",
                     "0007    | OP_DIVIDE",
                     "0008    | OP_NEGATE",
                     "0009    | OP_RETURN",
@@ -143,20 +1179,18 @@ mod tests {
         {
             let mut chunk = Chunk::new();
 
-            chunk.write(OpCode::Nil as u8, 123);
-            chunk.write(OpCode::True as u8, 123);
-            chunk.write(OpCode::False as u8, 123);
-            chunk.write(OpCode::Not as u8, 123);
-            chunk.write(OpCode::Equal as u8, 123);
-            chunk.write(OpCode::Greater as u8, 123);
-            chunk.write(OpCode::Less as u8, 123);
-
-            let mut output = Vec::new();
-            disassemble_chunk(&mut output, &chunk, "test chunk");
+            chunk.write(OpCode::Nil as u8, 123, 1);
+            chunk.write(OpCode::True as u8, 123, 1);
+            chunk.write(OpCode::False as u8, 123, 1);
+            chunk.write(OpCode::Not as u8, 123, 1);
+            chunk.write(OpCode::Equal as u8, 123, 1);
+            chunk.write(OpCode::Greater as u8, 123, 1);
+            chunk.write(OpCode::Less as u8, 123, 1);
+            chunk.write(OpCode::GreaterEqual as u8, 123, 1);
+            chunk.write(OpCode::LessEqual as u8, 123, 1);
 
             assert_eq!(
-                String::from_utf8(output)
-                    .expect("valid utf8")
+                disassemble_chunk_to_string(&chunk, "test chunk")
                     .lines()
                     .collect::<Vec<_>>(),
                 vec![
@@ -168,8 +1202,380 @@ mod tests {
                     "0004    | OP_EQUAL",
                     "0005    | OP_GREATER",
                     "0006    | OP_LESS",
+                    "0007    | OP_GREATER_EQUAL",
+                    "0008    | OP_LESS_EQUAL",
+                ],
+            );
+        }
+
+        {
+            let mut heap = Heap::new();
+            let mut chunk = Chunk::new();
+
+            let name = chunk
+                .constants_mut()
+                .add(Value::Obj(heap.alloc_string("a".to_string())));
+            chunk.write(OpCode::Nil as u8, 123, 1);
+            chunk.write(OpCode::DefineGlobal as u8, 123, 1);
+            chunk.write(name as u8, 123, 1);
+            chunk.write(OpCode::GetGlobal as u8, 123, 1);
+            chunk.write(name as u8, 123, 1);
+            chunk.write(OpCode::SetGlobal as u8, 123, 1);
+            chunk.write(name as u8, 123, 1);
+            chunk.write(OpCode::Pop as u8, 123, 1);
+            chunk.write(OpCode::Print as u8, 123, 1);
+
+            assert_eq!(
+                disassemble_chunk_to_string(&chunk, "test chunk")
+                    .lines()
+                    .collect::<Vec<_>>(),
+                vec![
+                    "== test chunk ==",
+                    "0000  123 OP_NIL",
+                    "0001    | OP_DEFINE_GLOBAL    0 'a'",
+                    "0003    | OP_GET_GLOBAL       0 'a'",
+                    "0005    | OP_SET_GLOBAL       0 'a'",
+                    "0007    | OP_POP",
+                    "0008    | OP_PRINT",
+                ],
+            );
+        }
+
+        {
+            let mut chunk = Chunk::new();
+
+            chunk.write(OpCode::Nil as u8, 123, 1);
+            chunk.write(OpCode::GetLocal as u8, 123, 1);
+            chunk.write(1, 123, 1);
+            chunk.write(OpCode::SetLocal as u8, 123, 1);
+            chunk.write(1, 123, 1);
+            chunk.write(OpCode::JumpIfFalse as u8, 123, 1);
+            chunk.write(3, 123, 1);
+            chunk.write(OpCode::Jump as u8, 124, 1);
+            chunk.write(0x80, 124, 1);
+            chunk.write(0x02, 124, 1);
+            chunk.write(OpCode::Loop as u8, 124, 1);
+            chunk.write(12, 124, 1);
+            chunk.write(OpCode::Call as u8, 124, 1);
+            chunk.write(2, 124, 1);
+
+            // varints of three bytes, and of two bytes with padding
+            chunk.write(OpCode::Jump as u8, 124, 1);
+            [0x80, 0x80, 0x04]
+                .iter()
+                .for_each(|byte| chunk.write(*byte, 124, 1));
+            chunk.write(OpCode::Loop as u8, 124, 1);
+            [0x95, 0x00]
+                .iter()
+                .for_each(|byte| chunk.write(*byte, 124, 1));
+
+            let constant = chunk.constants_mut().add(Value::Number(1.0));
+            chunk.write(OpCode::AddConstant as u8, 125, 1);
+            chunk.write(constant as u8, 125, 1);
+            chunk.write(OpCode::GetLocalAddConstant as u8, 125, 1);
+            chunk.write(1, 125, 1);
+            chunk.write(constant as u8, 125, 1);
+
+            assert_eq!(
+                disassemble_chunk_to_string(&chunk, "test chunk")
+                    .lines()
+                    .collect::<Vec<_>>(),
+                vec![
+                    "== test chunk ==",
+                    "0000  123 OP_NIL",
+                    "0001    | OP_GET_LOCAL        1",
+                    "0003    | OP_SET_LOCAL        1",
+                    "0005    | OP_JUMP_IF_FALSE    5 -> 10",
+                    "0007  124 OP_JUMP             7 -> 266",
+                    "0010    | OP_LOOP            10 -> 0",
+                    "0012    | OP_CALL             2",
+                    "0014    | OP_JUMP            14 -> 65554",
+                    "0018    | OP_LOOP            18 -> 0",
+                    "0021  125 OP_ADD_CONSTANT     0 '1'",
+                    "0023    | OP_GET_LOCAL_ADD_CONSTANT    1    0 '1'",
                 ],
             );
         }
+
+        {
+            let mut chunk = Chunk::new();
+
+            (0..=256).for_each(|i| {
+                chunk.constants_mut().add(Value::Number(i as f64));
+            });
+
+            chunk.write(OpCode::Constant as u8, 123, 1);
+            chunk.write(0x80, 123, 1);
+            chunk.write(0x02, 123, 1);
+            // a constant that is not in the pool
+            chunk.write(OpCode::Constant as u8, 123, 1);
+            chunk.write(0x81, 123, 1);
+            chunk.write(0x02, 123, 1);
+            chunk.write(OpCode::Return as u8, 123, 1);
+
+            assert_eq!(
+                disassemble_chunk_to_string(&chunk, "test chunk")
+                    .lines()
+                    .collect::<Vec<_>>(),
+                vec![
+                    "== test chunk ==",
+                    "0000  123 OP_CONSTANT       256 '256'",
+                    "0003    | OP_CONSTANT       257 <missing>",
+                    "0006    | OP_RETURN",
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn test_disassemble_instruction_to_string() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.constants_mut().add(Value::Number(1.2));
+        chunk.write(OpCode::Constant as u8, 123, 1);
+        chunk.write(constant as u8, 123, 1);
+        chunk.write(OpCode::Return as u8, 123, 1);
+
+        assert_eq!(
+            disassemble_instruction_to_string(&chunk, 0),
+            ("0000  123 OP_CONSTANT         0 '1.2'\n".to_string(), 2)
+        );
+        assert_eq!(
+            disassemble_instruction_to_string(&chunk, 2),
+            ("0002    | OP_RETURN\n".to_string(), 3)
+        );
+    }
+
+    #[test]
+    fn test_trace_last() {
+        let mut trace = Trace::last(2);
+        (0..5).for_each(|i| trace.record(format!("{}\n", i)));
+        let Trace::Last { entries, .. } = &trace else {
+            unreachable!()
+        };
+        assert_eq!(entries, &["3\n", "4\n"]);
+
+        trace.finish(false);
+        let Trace::Last { entries, .. } = &trace else {
+            unreachable!()
+        };
+        assert!(entries.is_empty());
+
+        // nothing is kept at all
+        let mut trace = Trace::last(0);
+        trace.record("0\n".to_string());
+        let Trace::Last { entries, .. } = &trace else {
+            unreachable!()
+        };
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_counts() {
+        let mut counts = OpcodeCounts::new();
+        (0..3).for_each(|_| counts.record(OpCode::Pop));
+        (0..4).for_each(|_| counts.record(OpCode::Nil));
+        counts.record(OpCode::Return);
+        counts.record(OpCode::Constant);
+
+        assert_eq!(counts.get(OpCode::Pop), 3);
+        assert_eq!(counts.get(OpCode::Add), 0);
+        assert_eq!(counts.total(), 9);
+        assert_eq!(
+            counts.sorted(),
+            vec![
+                (OpCode::Nil, 4),
+                (OpCode::Pop, 3),
+                (OpCode::Return, 1),
+                (OpCode::Constant, 1),
+            ]
+        );
+
+        let mut output = Vec::new();
+        counts.write_histogram(&mut output);
+        assert_eq!(
+            String::from_utf8(output)
+                .expect("valid utf8")
+                .lines()
+                .collect::<Vec<_>>(),
+            vec![
+                "== opcode counts (9 instructions) ==",
+                "OP_NIL                                4  44.44% ########################################",
+                "OP_POP                                3  33.33% ##############################",
+                "OP_RETURN                             1  11.11% ##########",
+                "OP_CONSTANT                           1  11.11% ##########",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut chunk = Chunk::new();
+        [1, 1, 2, 4]
+            .iter()
+            .for_each(|&line| chunk.write(0, line, 1));
+
+        let mut coverage = Coverage::new();
+        coverage.add_chunk(&chunk);
+        // a line counts once however many of its instructions run, and a
+        // call coming back to it does not count again
+        for (depth, line) in [(1, 1), (1, 1), (2, 4), (1, 1), (1, 2), (1, 1)] {
+            coverage.record(depth, line);
+        }
+        assert_eq!(coverage.hits(1), Some(2));
+        assert_eq!(coverage.hits(3), None);
+        assert_eq!(coverage.summary(), (3, 3));
+
+        let mut coverage = Coverage::new();
+        coverage.add_chunk(&chunk);
+        coverage.record(1, 1);
+        let mut lcov = Vec::new();
+        coverage.write_lcov(&mut lcov, "a.lox").expect("writable");
+        assert_eq!(
+            String::from_utf8(lcov).expect("valid utf8"),
+            "TN:\nSF:a.lox\nDA:1,1\nDA:2,0\nDA:4,0\nLF:3\nLH:1\nend_of_record\n"
+        );
+        let mut annotated = Vec::new();
+        coverage.write_annotated(&mut annotated, "one;\ntwo;\n\nfour;");
+        assert_eq!(
+            String::from_utf8(annotated).expect("valid utf8"),
+            "        1:    1:one;\n\
+             \x20   #####:    2:two;\n\
+             \x20       -:    3:\n\
+             \x20   #####:    4:four;\n\
+             Lines executed: 33.33% of 3\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_program() {
+        let mut heap = Heap::new();
+        let source = "fun f() {\n  return 1;\n}\nprint f;";
+        let chunk =
+            Compiler::compile(source, &mut heap, CompileOptions::default()).expect("no error");
+
+        let options = DisassembleOptions {
+            constants: true,
+            lines: true,
+        };
+        let mut output = Vec::new();
+        disassemble_program(&mut output, &chunk, "<script>", options);
+
+        assert_eq!(
+            String::from_utf8(output)
+                .expect("valid utf8")
+                .lines()
+                .collect::<Vec<_>>(),
+            vec![
+                "== f ==",
+                "0000    2 OP_CONSTANT         0 '1'",
+                "0002    | OP_RETURN",
+                "0003    3 OP_NIL",
+                "0004    | OP_RETURN",
+                "-- constants --",
+                "   0 '1'",
+                "-- lines --",
+                "0000 2:10 19..20",
+                "0002 2:11 20..21",
+                "0003 3:1 22..23",
+                "== <script> ==",
+                "0000    3 OP_CONSTANT         1 '<fn f>'",
+                "0002    | OP_DEFINE_GLOBAL    0 'f'",
+                "0004    4 OP_GET_GLOBAL       0 'f'",
+                "0006    | OP_PRINT",
+                "0007    | OP_NIL",
+                "0008    | OP_RETURN",
+                "-- constants --",
+                "   0 'f'",
+                "   1 '<fn f>'",
+                "-- lines --",
+                "0000 3:1 22..23",
+                "0004 4:7 30..31",
+                "0006 4:1 24..29",
+                "0007 4:9 32..32",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_disassemble_program_json() {
+        let mut heap = Heap::new();
+        let source = "fun f() {\n  return \"a\\\";\n}\nwhile (f) f();";
+        let chunk =
+            Compiler::compile(source, &mut heap, CompileOptions::default()).expect("no error");
+
+        let mut output = Vec::new();
+        disassemble_program_json(&mut output, &chunk, "<script>");
+
+        assert_eq!(
+            String::from_utf8(output)
+                .expect("valid utf8")
+                .lines()
+                .collect::<Vec<_>>(),
+            vec![
+                "[",
+                "  {",
+                r#"    "name": "f","#,
+                r#"    "code": ["#,
+                r#"      {"offset": 0, "line": 2, "mnemonic": "OP_CONSTANT", "operands": [0], "constant": "a\\"},"#,
+                r#"      {"offset": 2, "line": 2, "mnemonic": "OP_RETURN", "operands": []},"#,
+                r#"      {"offset": 3, "line": 3, "mnemonic": "OP_NIL", "operands": []},"#,
+                r#"      {"offset": 4, "line": 3, "mnemonic": "OP_RETURN", "operands": []}"#,
+                "    ]",
+                "  },",
+                "  {",
+                r#"    "name": "<script>","#,
+                r#"    "code": ["#,
+                r#"      {"offset": 0, "line": 3, "mnemonic": "OP_CONSTANT", "operands": [1], "constant": "<fn f>"},"#,
+                r#"      {"offset": 2, "line": 3, "mnemonic": "OP_DEFINE_GLOBAL", "operands": [0], "constant": "f"},"#,
+                r#"      {"offset": 4, "line": 4, "mnemonic": "OP_GET_GLOBAL", "operands": [0], "constant": "f"},"#,
+                r#"      {"offset": 6, "line": 4, "mnemonic": "OP_JUMP_IF_FALSE", "operands": [8], "target": 16},"#,
+                r#"      {"offset": 8, "line": 4, "mnemonic": "OP_POP", "operands": []},"#,
+                r#"      {"offset": 9, "line": 4, "mnemonic": "OP_GET_GLOBAL", "operands": [0], "constant": "f"},"#,
+                r#"      {"offset": 11, "line": 4, "mnemonic": "OP_CALL", "operands": [0]},"#,
+                r#"      {"offset": 13, "line": 4, "mnemonic": "OP_POP", "operands": []},"#,
+                r#"      {"offset": 14, "line": 4, "mnemonic": "OP_LOOP", "operands": [12], "target": 4},"#,
+                r#"      {"offset": 16, "line": 4, "mnemonic": "OP_POP", "operands": []},"#,
+                r#"      {"offset": 17, "line": 4, "mnemonic": "OP_NIL", "operands": []},"#,
+                r#"      {"offset": 18, "line": 4, "mnemonic": "OP_RETURN", "operands": []}"#,
+                "    ]",
+                "  }",
+                "]",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_instruction_to_json() {
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::GetLocalAddConstant as u8, 123, 1);
+        chunk.write(1, 123, 1);
+        chunk.write(0, 123, 1);
+        chunk.write(OpCode::Loop as u8, 123, 1);
+        chunk.write(5, 123, 1);
+        chunk.write(255, 124, 1);
+
+        assert_eq!(
+            instruction_to_json(&chunk, 0),
+            (
+                r#"{"offset": 0, "line": 123, "mnemonic": "OP_GET_LOCAL_ADD_CONSTANT", "operands": [1, 0], "constant": null}"#
+                    .to_string(),
+                3
+            )
+        );
+        assert_eq!(
+            instruction_to_json(&chunk, 3),
+            (
+                r#"{"offset": 3, "line": 123, "mnemonic": "OP_LOOP", "operands": [5], "target": 0}"#
+                    .to_string(),
+                5
+            )
+        );
+        assert_eq!(
+            instruction_to_json(&chunk, 5),
+            (
+                r#"{"offset": 5, "line": 124, "unknown": 255}"#.to_string(),
+                6
+            )
+        );
     }
 }
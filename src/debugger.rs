@@ -0,0 +1,964 @@
+// The debuggers of `clox debug`. The step debugger pauses a script before
+// its instructions the way that DEBUG_TRACE_EXECUTION prints them, and
+// reads commands until it is told to go on. The source debugger pauses at
+// lines of the source instead, and shows the variables by their names.
+//
+// Both pause before the first instruction, and then while stepping, or at
+// the breakpoints while continuing. The VM should not tier functions up
+// while they are being debugged, since tiered code does not pause.
+
+use std::{
+    fmt,
+    io::{self, BufRead},
+    ops::ControlFlow,
+};
+
+use crate::{
+    debug,
+    value::{OwnedValue, Value},
+    vm::{Debugger, Paused},
+};
+
+const HELP: &str = "\
+step, s             run the next instruction (also an empty line)
+continue, c         run until the next breakpoint
+break, b <offset> [function]
+                    pause before the instruction at the offset in the function,
+                    or in the one that is running
+delete, d <offset> [function]
+                    remove the breakpoint at the offset in the function
+globals, g          print the globals
+list, l             disassemble this function
+quit, q             stop the script
+help, h             print this";
+
+pub struct StepDebugger<R: BufRead, W: io::Write> {
+    input: R,
+    output: W,
+    // whether to pause before the next instruction wherever it is
+    stepping: bool,
+    // the function, `None` for the script, and the offset of each breakpoint
+    breakpoints: Vec<(Option<String>, usize)>,
+}
+
+impl<R: BufRead, W: io::Write> StepDebugger<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input,
+            output,
+            stepping: true,
+            breakpoints: vec![],
+        }
+    }
+
+    // shows where the script is, the same as the trace
+    fn show(&mut self, paused: &Paused<'_>) {
+        write!(self.output, "          ").expect("writable");
+        for value in paused.stack {
+            write!(self.output, "[ {} ]", value).expect("writable");
+        }
+        writeln!(self.output).expect("writable");
+        let (instruction, _) =
+            debug::disassemble_instruction_to_string(paused.chunk, paused.offset);
+        write!(self.output, "{}", instruction).expect("writable");
+    }
+
+    // runs the command on `line`, and returns what the VM is to do once the
+    // debugger stops reading commands
+    fn command(&mut self, paused: &Paused<'_>, line: &str) -> Option<ControlFlow<()>> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or("step");
+        let offset = words.next().map(str::parse::<usize>);
+        let function = words.next().or(paused.function).map(str::to_string);
+
+        match (command, offset) {
+            ("step" | "s", None) => {
+                self.stepping = true;
+                return Some(ControlFlow::Continue(()));
+            }
+            ("continue" | "c", None) => {
+                self.stepping = false;
+                return Some(ControlFlow::Continue(()));
+            }
+            // the chunks of other functions are not at hand to check the
+            // offset against
+            ("break" | "b", Some(Ok(offset))) => {
+                if function.as_deref() == paused.function && offset >= paused.chunk.code_len() {
+                    writeln!(self.output, "No instruction at {}.", offset).expect("writable");
+                } else if !self.breakpoints.contains(&(function.clone(), offset)) {
+                    self.breakpoints.push((function, offset));
+                }
+            }
+            ("delete" | "d", Some(Ok(offset))) => {
+                let breakpoint = (function, offset);
+                if !self.breakpoints.contains(&breakpoint) {
+                    writeln!(self.output, "No breakpoint at {}.", offset).expect("writable");
+                }
+                self.breakpoints.retain(|other| *other != breakpoint);
+            }
+            ("globals" | "g", None) => {
+                for (name, value) in paused.globals() {
+                    writeln!(self.output, "{} = {}", name, value).expect("writable");
+                }
+            }
+            ("list" | "l", None) => {
+                let name = paused.function.unwrap_or("<script>");
+                debug::disassemble_chunk(&mut self.output, paused.chunk, name);
+            }
+            ("quit" | "q", None) => return Some(ControlFlow::Break(())),
+            ("help" | "h", None) => writeln!(self.output, "{}", HELP).expect("writable"),
+            _ => writeln!(
+                self.output,
+                "Unknown command '{}', try 'help'.",
+                line.trim()
+            )
+            .expect("writable"),
+        }
+        None
+    }
+}
+
+impl<R: BufRead, W: io::Write> Debugger for StepDebugger<R, W> {
+    fn before_instruction(&mut self, paused: &Paused<'_>) -> ControlFlow<()> {
+        let breakpoint = (paused.function.map(str::to_string), paused.offset);
+        if !self.stepping && !self.breakpoints.contains(&breakpoint) {
+            return ControlFlow::Continue(());
+        }
+
+        self.show(paused);
+        loop {
+            write!(self.output, "(debug) ").expect("writable");
+            self.output.flush().expect("writable");
+
+            let mut line = String::new();
+            match self.input.read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    // without any more commands, the rest of the script runs
+                    writeln!(self.output).expect("writable");
+                    self.stepping = false;
+                    self.breakpoints.clear();
+                    return ControlFlow::Continue(());
+                }
+                Ok(_) => {
+                    if let Some(flow) = self.command(paused, &line) {
+                        return flow;
+                    }
+                }
+            }
+        }
+    }
+}
+
+const SOURCE_HELP: &str = "\
+step, s             run to the next line, into calls as well (also an empty line)
+next, n             run to the next line of this function
+finish, f           run until this function returns
+continue, c         run until the next breakpoint
+break, b [file:]<line> [if <condition>]
+                    pause at the line, only when the condition holds if there
+                    is one, such as 'n > 3'
+watch, w <name>     pause after the global is set
+delete, d <line>|<name>
+                    remove the breakpoint at the line, or the watchpoint
+breakpoints         print the breakpoints and watchpoints
+print, p <name>     print the variable
+locals              print the locals of this function
+globals, g          print the globals
+list, l             print the source around this line
+quit, q             stop the script
+help, h             print this";
+
+// how many lines `list` prints on either side of the one the script is at
+const LIST_CONTEXT: u32 = 2;
+
+/// How the [`SourceDebugger`] goes on after it pauses.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Resume {
+    /// Pause at the next line, which may be in a function that this one calls.
+    Step,
+    /// Pause at the next line of this function, or of its caller once it
+    /// returns.
+    Next,
+    /// Pause once this function returns.
+    Finish,
+    /// Pause at the next breakpoint.
+    Continue,
+    /// Stop the script.
+    Quit,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Comparison {
+    // longer operators first, so that `<=` is not taken for `<`
+    const ALL: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessEqual),
+        (">=", Comparison::GreaterEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ];
+
+    fn symbol(self) -> &'static str {
+        Self::ALL
+            .iter()
+            .find(|(_, comparison)| *comparison == self)
+            .map(|(symbol, _)| *symbol)
+            .expect("ICE: Comparison without a symbol")
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Literal {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Literal::Nil => write!(f, "nil"),
+            Literal::Bool(value) => write!(f, "{}", value),
+            Literal::Number(value) => write!(f, "{}", value),
+            Literal::String(value) => write!(f, "\"{}\"", value),
+        }
+    }
+}
+
+/// What a conditional breakpoint checks before it pauses: a variable
+/// compared with a literal, such as `n > 3` or `name == "lox"`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Condition {
+    name: String,
+    comparison: Comparison,
+    value: Literal,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let error = || {
+            format!(
+                "Expected a condition like 'n > 3', not '{}'.",
+                source.trim()
+            )
+        };
+
+        let source = source.trim();
+        let name_len = source
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(source.len());
+        let (name, rest) = source.split_at(name_len);
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(error());
+        }
+
+        let rest = rest.trim_start();
+        let (symbol, comparison) = Comparison::ALL
+            .iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+            .ok_or_else(error)?;
+        let value = match rest[symbol.len()..].trim() {
+            "nil" => Literal::Nil,
+            "true" => Literal::Bool(true),
+            "false" => Literal::Bool(false),
+            value if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') => {
+                Literal::String(value[1..value.len() - 1].to_string())
+            }
+            value => Literal::Number(value.parse().map_err(|_| error())?),
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            comparison: *comparison,
+            value,
+        })
+    }
+
+    /// Whether the condition holds where the script paused. It does not if
+    /// there is no such variable, or if it cannot be compared with the
+    /// literal.
+    pub fn holds(&self, paused: &Paused<'_>) -> bool {
+        let Some(value) = paused.variable(&self.name) else {
+            return false;
+        };
+
+        let equal = match (&self.value, &value) {
+            (Literal::Nil, OwnedValue::Nil) => true,
+            (Literal::Bool(a), OwnedValue::Bool(b)) => a == b,
+            (Literal::Number(a), OwnedValue::Number(b)) => a == b,
+            (Literal::String(a), OwnedValue::String(b)) => a == b,
+            _ => false,
+        };
+        // like in Lox, only numbers are ordered
+        let ordering = match (&self.value, &value) {
+            (Literal::Number(a), OwnedValue::Number(b)) => b.partial_cmp(a),
+            _ => None,
+        };
+
+        match self.comparison {
+            Comparison::Equal => equal,
+            Comparison::NotEqual => !equal,
+            Comparison::Less => ordering.is_some_and(|ordering| ordering.is_lt()),
+            Comparison::LessEqual => ordering.is_some_and(|ordering| ordering.is_le()),
+            Comparison::Greater => ordering.is_some_and(|ordering| ordering.is_gt()),
+            Comparison::GreaterEqual => ordering.is_some_and(|ordering| ordering.is_ge()),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {}",
+            self.name,
+            self.comparison.symbol(),
+            self.value
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Breakpoint {
+    pub line: u32,
+    pub condition: Option<Condition>,
+}
+
+/// Why the [`SourceDebugger`] paused.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Stop<'a> {
+    /// At the first line of the script, or where stepping ended.
+    Step,
+    /// At a breakpoint on the line.
+    Breakpoint,
+    /// Right after the instruction that set a watched global, from `old`,
+    /// which is `None` when it was not defined yet, to `new`.
+    Watch {
+        name: &'a str,
+        old: Option<Value>,
+        new: Value,
+    },
+}
+
+/// The line breakpoints of a [`SourceDebugger`], at most one on each line,
+/// and the globals that it watches.
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    breakpoints: Vec<Breakpoint>,
+    watched: Vec<String>,
+}
+
+impl Breakpoints {
+    /// Sets a breakpoint on `line`, in place of any that was there.
+    pub fn add(&mut self, line: u32, condition: Option<Condition>) {
+        self.remove(line);
+        self.breakpoints.push(Breakpoint { line, condition });
+        self.breakpoints.sort_by_key(|breakpoint| breakpoint.line);
+    }
+
+    /// Removes the breakpoint on `line`, and returns whether there was one.
+    pub fn remove(&mut self, line: u32) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints
+            .retain(|breakpoint| breakpoint.line != line);
+        self.breakpoints.len() != len
+    }
+
+    /// Pauses after the script sets the global `name`, even to the value it
+    /// already had.
+    pub fn watch(&mut self, name: &str) {
+        if !self.is_watched(name) {
+            self.watched.push(name.to_string());
+        }
+    }
+
+    /// Stops watching the global `name`, and returns whether it was watched.
+    pub fn unwatch(&mut self, name: &str) -> bool {
+        let len = self.watched.len();
+        self.watched.retain(|watched| watched != name);
+        self.watched.len() != len
+    }
+
+    pub fn is_watched(&self, name: &str) -> bool {
+        self.watched.iter().any(|watched| watched == name)
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.watched.clear();
+    }
+
+    /// The breakpoints, in the order of their lines.
+    pub fn iter(&self) -> impl Iterator<Item = &Breakpoint> {
+        self.breakpoints.iter()
+    }
+
+    /// The watched globals, in the order that they were watched.
+    pub fn watched(&self) -> impl Iterator<Item = &str> {
+        self.watched.iter().map(String::as_str)
+    }
+
+    fn hit(&self, paused: &Paused<'_>) -> bool {
+        let line = paused.line();
+        self.breakpoints.iter().any(|breakpoint| {
+            breakpoint.line == line
+                && breakpoint
+                    .condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.holds(paused))
+        })
+    }
+}
+
+/// A debugger that pauses at lines of the source, and hands the script to
+/// `on_pause` each time, which inspects it and says how to go on. It can
+/// change the breakpoints while it does.
+///
+/// It pauses at the first line of the script, and after watched globals are
+/// set. A callback for every global that is set is rather
+/// [`Debugger::global_written`].
+pub struct SourceDebugger<F> {
+    on_pause: F,
+    breakpoints: Breakpoints,
+    resume: Resume,
+    // how many calls were running when it last paused, which `next` and
+    // `finish` are measured from
+    depth: usize,
+    // the depth and line of the instruction before, since it only pauses
+    // where a line starts
+    last: Option<(usize, u32)>,
+}
+
+impl<F: FnMut(&Paused<'_>, Stop<'_>, &mut Breakpoints) -> Resume> SourceDebugger<F> {
+    pub fn new(on_pause: F) -> Self {
+        Self {
+            on_pause,
+            breakpoints: Breakpoints::default(),
+            resume: Resume::Step,
+            depth: 0,
+            last: None,
+        }
+    }
+
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+}
+
+impl<F: FnMut(&Paused<'_>, Stop<'_>, &mut Breakpoints) -> Resume> Debugger for SourceDebugger<F> {
+    fn before_instruction(&mut self, paused: &Paused<'_>) -> ControlFlow<()> {
+        // the rest of a line, or a call returning to it, does not start a new
+        // one, but a call to a function on the same line does
+        let here = (paused.depth, paused.line());
+        if self.last.replace(here) == Some(here) {
+            return ControlFlow::Continue(());
+        }
+
+        let stop = match self.resume {
+            Resume::Step => true,
+            Resume::Next => paused.depth <= self.depth,
+            Resume::Finish => paused.depth < self.depth,
+            Resume::Continue | Resume::Quit => false,
+        };
+        if stop {
+            self.pause(paused, Stop::Step)
+        } else if self.breakpoints.hit(paused) {
+            self.pause(paused, Stop::Breakpoint)
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+
+    fn global_written(
+        &mut self,
+        paused: &Paused<'_>,
+        name: &str,
+        old: Option<Value>,
+        new: Value,
+    ) -> ControlFlow<()> {
+        if !self.breakpoints.is_watched(name) {
+            return ControlFlow::Continue(());
+        }
+
+        // stepping goes on from the rest of the line
+        self.last = Some((paused.depth, paused.line()));
+        self.pause(paused, Stop::Watch { name, old, new })
+    }
+}
+
+impl<F: FnMut(&Paused<'_>, Stop<'_>, &mut Breakpoints) -> Resume> SourceDebugger<F> {
+    fn pause(&mut self, paused: &Paused<'_>, stop: Stop<'_>) -> ControlFlow<()> {
+        self.depth = paused.depth;
+        self.resume = (self.on_pause)(paused, stop, &mut self.breakpoints);
+        if self.resume == Resume::Quit {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+/// The commands of the source debugger in `clox debug`, which shows where
+/// the script at `path` paused and reads commands from `input` until it is
+/// told to go on. Its [`DebugRepl::pause`] is the `on_pause` of a
+/// [`SourceDebugger`].
+pub struct DebugRepl<R: BufRead, W: io::Write> {
+    path: String,
+    lines: Vec<String>,
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead, W: io::Write> DebugRepl<R, W> {
+    pub fn new(path: &str, source: &str, input: R, output: W) -> Self {
+        Self {
+            path: path.to_string(),
+            lines: source.lines().map(str::to_string).collect(),
+            input,
+            output,
+        }
+    }
+
+    pub fn pause(
+        &mut self,
+        paused: &Paused<'_>,
+        stop: Stop<'_>,
+        breakpoints: &mut Breakpoints,
+    ) -> Resume {
+        if let Stop::Watch { name, old, new } = stop {
+            let old = old.map_or("undefined".to_string(), |old| old.to_string());
+            writeln!(self.output, "{}: {} -> {}", name, old, new).expect("writable");
+        }
+        let line = paused.line();
+        let function = match paused.function {
+            Some(name) => format!("{}()", name),
+            None => "script".to_string(),
+        };
+        writeln!(self.output, "{}:{} in {}", self.path, line, function).expect("writable");
+        self.show_line(line, false);
+
+        loop {
+            write!(self.output, "(debug) ").expect("writable");
+            self.output.flush().expect("writable");
+
+            let mut command = String::new();
+            match self.input.read_line(&mut command) {
+                Ok(0) | Err(_) => {
+                    // without any more commands, the rest of the script runs
+                    writeln!(self.output).expect("writable");
+                    breakpoints.clear();
+                    return Resume::Continue;
+                }
+                Ok(_) => {
+                    if let Some(resume) = self.command(paused, breakpoints, &command) {
+                        return resume;
+                    }
+                }
+            }
+        }
+    }
+
+    fn show_line(&mut self, line: u32, marked: bool) {
+        let Some(text) = self.lines.get(line as usize - 1) else {
+            return;
+        };
+        let marker = if marked { ">" } else { " " };
+        writeln!(self.output, "{}{:4} | {}", marker, line, text).expect("writable");
+    }
+
+    // the line of a breakpoint, as `12` or `file.lox:12`, where the file is
+    // the one being debugged
+    fn parse_line(&mut self, location: &str) -> Option<u32> {
+        let line = match location.rsplit_once(':') {
+            Some((file, line)) => {
+                let is_script = file == self.path || self.path.ends_with(&format!("/{}", file));
+                if !is_script {
+                    writeln!(self.output, "No file '{}'.", file).expect("writable");
+                    return None;
+                }
+                line
+            }
+            None => location,
+        };
+
+        match line.parse::<u32>() {
+            Ok(line) if line >= 1 && line as usize <= self.lines.len() => Some(line),
+            _ => {
+                writeln!(self.output, "No line {} in {}.", line, self.path).expect("writable");
+                None
+            }
+        }
+    }
+
+    // runs the command on `line`, and returns how the script is to go on
+    // once the debugger stops reading commands
+    fn command(
+        &mut self,
+        paused: &Paused<'_>,
+        breakpoints: &mut Breakpoints,
+        line: &str,
+    ) -> Option<Resume> {
+        let line = line.trim();
+        let (command, argument) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let argument = argument.trim();
+
+        match (command, argument) {
+            ("" | "step" | "s", "") => return Some(Resume::Step),
+            ("next" | "n", "") => return Some(Resume::Next),
+            ("finish" | "f", "") => return Some(Resume::Finish),
+            ("continue" | "c", "") => return Some(Resume::Continue),
+            ("break" | "b", argument) if !argument.is_empty() => {
+                let (location, condition) = match argument.split_once(" if ") {
+                    Some((location, condition)) => (location.trim(), Some(condition)),
+                    None => (argument, None),
+                };
+                let condition = match condition.map(Condition::parse).transpose() {
+                    Ok(condition) => condition,
+                    Err(error) => {
+                        writeln!(self.output, "{}", error).expect("writable");
+                        return None;
+                    }
+                };
+                if let Some(line) = self.parse_line(location) {
+                    breakpoints.add(line, condition);
+                    writeln!(self.output, "Breakpoint at {}:{}.", self.path, line)
+                        .expect("writable");
+                }
+            }
+            ("watch" | "w", name) if !name.is_empty() => breakpoints.watch(name),
+            ("delete" | "d", argument) if !argument.is_empty() => match argument.parse::<u32>() {
+                Ok(line) if !breakpoints.remove(line) => {
+                    writeln!(self.output, "No breakpoint at line {}.", line).expect("writable");
+                }
+                Err(_) if !breakpoints.unwatch(argument) => {
+                    writeln!(self.output, "No watchpoint on '{}'.", argument).expect("writable");
+                }
+                _ => {}
+            },
+            ("breakpoints", "") => {
+                for breakpoint in breakpoints.iter() {
+                    match &breakpoint.condition {
+                        Some(condition) => writeln!(
+                            self.output,
+                            "{}:{} if {}",
+                            self.path, breakpoint.line, condition
+                        ),
+                        None => writeln!(self.output, "{}:{}", self.path, breakpoint.line),
+                    }
+                    .expect("writable");
+                }
+                for name in breakpoints.watched() {
+                    writeln!(self.output, "watch {}", name).expect("writable");
+                }
+            }
+            ("print" | "p", name) if !name.is_empty() => match paused.variable(name) {
+                Some(value) => writeln!(self.output, "{} = {}", name, value).expect("writable"),
+                None => writeln!(self.output, "No variable '{}'.", name).expect("writable"),
+            },
+            ("locals", "") => {
+                for (name, value) in paused.locals() {
+                    writeln!(self.output, "{} = {}", name, value).expect("writable");
+                }
+            }
+            ("globals" | "g", "") => {
+                for (name, value) in paused.globals() {
+                    writeln!(self.output, "{} = {}", name, value).expect("writable");
+                }
+            }
+            ("list" | "l", "") => {
+                let line = paused.line();
+                for other in line.saturating_sub(LIST_CONTEXT).max(1)..=line + LIST_CONTEXT {
+                    self.show_line(other, other == line);
+                }
+            }
+            ("quit" | "q", "") => return Some(Resume::Quit),
+            ("help" | "h", "") => writeln!(self.output, "{}", SOURCE_HELP).expect("writable"),
+            _ => {
+                writeln!(self.output, "Unknown command '{}', try 'help'.", line).expect("writable")
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::vm::{InterpretError, VM};
+
+    // output that the test can still read once the VM owns the debugger
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn debug(source: &str, commands: &str) -> (Result<(), InterpretError>, String) {
+        let output = Shared::default();
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_tiering(false);
+        vm.set_debugger(Box::new(StepDebugger::new(
+            io::Cursor::new(commands.to_string()),
+            output.clone(),
+        )));
+        let result = vm.interpret(source);
+        let output = String::from_utf8(output.0.take()).expect("valid utf8");
+        (result, output)
+    }
+
+    #[test]
+    fn test_step_debugger() {
+        let source = "var a = 1;\nfun f() {\n  return a;\n}\nprint f();";
+
+        let (result, output) = debug(source, "s\n\nb 2 f\nc\ng\nc\n");
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec![
+                "          [ <script> ]",
+                "0000    1 OP_CONSTANT         1 '1'",
+                "(debug)           [ <script> ][ 1 ]",
+                "0002    | OP_DEFINE_GLOBAL    0 'a'",
+                "(debug)           [ <script> ]",
+                "0004    4 OP_CONSTANT         3 '<fn f>'",
+                "(debug) (debug)           [ <script> ][ <fn f> ][ 1 ]",
+                "0002    | OP_RETURN",
+                "(debug) a = 1",
+                "f = <fn f>",
+                "(debug) ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_step_debugger_commands() {
+        let (result, output) = debug("fun f() {}\nprint 1;", "b 99\nd 1\nx\nq\n");
+        assert_eq!(result, Err(InterpretError::Interrupted));
+        assert!(output.contains("No instruction at 99."));
+        assert!(output.contains("No breakpoint at 1."));
+        assert!(output.contains("Unknown command 'x', try 'help'."));
+
+        // the rest of the script runs once there are no more commands
+        let (result, output) = debug("print 1;", "l\n");
+        assert_eq!(result, Ok(()));
+        assert!(output.contains("== <script> =="));
+        assert_eq!(output.matches("(debug)").count(), 2);
+    }
+
+    const SOURCE: &str = "\
+var total = 0;
+fun add(n) {
+  var doubled = n * 2;
+  total = total + doubled;
+  return doubled;
+}
+for (var i = 0; i < 5; i = i + 1) {
+  add(i);
+}
+print total;";
+
+    // runs `SOURCE`, resuming with each of `resumes` in turn, and returns
+    // the function, line and locals at each pause
+    fn debug_lines(
+        resumes: Vec<Resume>,
+        breakpoints: &[(u32, Option<&str>)],
+    ) -> (Result<(), InterpretError>, Vec<String>) {
+        let pauses = Rc::new(RefCell::new(vec![]));
+        let mut resumes = resumes.into_iter();
+        let mut debugger = SourceDebugger::new({
+            let pauses = pauses.clone();
+            move |paused: &Paused<'_>, _: Stop<'_>, _: &mut Breakpoints| {
+                let locals = paused
+                    .locals()
+                    .iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect::<Vec<_>>();
+                pauses.borrow_mut().push(format!(
+                    "{}:{} {}",
+                    paused.function.unwrap_or("script"),
+                    paused.line(),
+                    locals.join(" ")
+                ));
+                resumes.next().unwrap_or(Resume::Continue)
+            }
+        });
+        for (line, condition) in breakpoints {
+            let condition = condition.map(|condition| Condition::parse(condition).expect("valid"));
+            debugger.breakpoints_mut().add(*line, condition);
+        }
+
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_tiering(false);
+        vm.set_debugger(Box::new(debugger));
+        let result = vm.interpret(SOURCE);
+        (result, pauses.take())
+    }
+
+    #[test]
+    fn test_source_debugger() {
+        use Resume::*;
+
+        let (result, pauses) = debug_lines(vec![Step, Next, Step, Step, Step, Finish, Quit], &[]);
+        assert_eq!(result, Err(InterpretError::Interrupted));
+        // a function is defined at the end of its declaration
+        assert_eq!(
+            pauses,
+            [
+                "script:1 ",
+                "script:6 ",
+                "script:7 ",
+                "script:8 i=0",
+                "add:3 n=0",
+                "add:4 n=0 doubled=0",
+                "script:8 i=0",
+            ]
+        );
+
+        // a breakpoint only pauses while its condition holds
+        let (result, pauses) = debug_lines(vec![Continue], &[(4, Some("n >= 3")), (10, None)]);
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            pauses,
+            [
+                "script:1 ",
+                "add:4 n=3 doubled=6",
+                "add:4 n=4 doubled=8",
+                "script:10 ",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_debugger_watch() {
+        let stops = Rc::new(RefCell::new(vec![]));
+        let mut debugger = SourceDebugger::new({
+            let stops = stops.clone();
+            move |paused: &Paused<'_>, stop: Stop<'_>, breakpoints: &mut Breakpoints| {
+                stops
+                    .borrow_mut()
+                    .push(format!("{}: {:?}", paused.line(), stop));
+                // stops watching after the third write
+                if stops.borrow().len() == 4 {
+                    breakpoints.unwatch("total");
+                }
+                Resume::Continue
+            }
+        });
+        debugger.breakpoints_mut().watch("total");
+
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_tiering(false);
+        vm.set_debugger(Box::new(debugger));
+        assert_eq!(vm.interpret(SOURCE), Ok(()));
+        assert_eq!(
+            stops.take(),
+            [
+                "1: Step",
+                "1: Watch { name: \"total\", old: None, new: Number(0.0) }",
+                "4: Watch { name: \"total\", old: Some(Number(0.0)), new: Number(0.0) }",
+                "4: Watch { name: \"total\", old: Some(Number(0.0)), new: Number(2.0) }",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_condition() {
+        let condition = Condition::parse("  count<=3 ").expect("valid");
+        assert_eq!(condition.to_string(), "count <= 3");
+        assert_eq!(
+            Condition::parse("name == \"lox\"").map(|c| c.to_string()),
+            Ok("name == \"lox\"".to_string())
+        );
+        assert_eq!(
+            Condition::parse("done != nil").map(|c| c.to_string()),
+            Ok("done != nil".to_string())
+        );
+        for invalid in ["", "3 > n", "n", "n ~ 3", "n > three"] {
+            assert_eq!(
+                Condition::parse(invalid),
+                Err(format!(
+                    "Expected a condition like 'n > 3', not '{}'.",
+                    invalid
+                ))
+            );
+        }
+    }
+
+    fn debug_source(commands: &str) -> (Result<(), InterpretError>, String) {
+        let output = Shared::default();
+        let mut repl = DebugRepl::new(
+            "dir/test.lox",
+            SOURCE,
+            io::Cursor::new(commands.to_string()),
+            output.clone(),
+        );
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_tiering(false);
+        vm.set_debugger(Box::new(SourceDebugger::new(
+            move |paused: &Paused<'_>, stop: Stop<'_>, breakpoints: &mut Breakpoints| {
+                repl.pause(paused, stop, breakpoints)
+            },
+        )));
+        let result = vm.interpret(SOURCE);
+        let output = String::from_utf8(output.0.take()).expect("valid utf8");
+        (result, output)
+    }
+
+    #[test]
+    fn test_debug_repl() {
+        let (result, output) = debug_source(
+            "b test.lox:5 if n == 1\nbreakpoints\nc\np doubled\np total\nlocals\nl\nd 5\nc\n",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            vec![
+                "dir/test.lox:1 in script",
+                "    1 | var total = 0;",
+                "(debug) Breakpoint at dir/test.lox:5.",
+                "(debug) dir/test.lox:5 if n == 1",
+                "(debug) dir/test.lox:5 in add()",
+                "    5 |   return doubled;",
+                "(debug) doubled = 2",
+                "(debug) total = 2",
+                "(debug) n = 1",
+                "doubled = 2",
+                "(debug)     3 |   var doubled = n * 2;",
+                "    4 |   total = total + doubled;",
+                ">   5 |   return doubled;",
+                "    6 | }",
+                "    7 | for (var i = 0; i < 5; i = i + 1) {",
+                "(debug) (debug) ",
+            ]
+        );
+
+        let (result, output) = debug_source("b other.lox:2\nb 99\nd 3\nd total\np nothing\nx\nq\n");
+        assert_eq!(result, Err(InterpretError::Interrupted));
+        assert!(output.contains("No file 'other.lox'."));
+        assert!(output.contains("No line 99 in dir/test.lox."));
+        assert!(output.contains("No breakpoint at line 3."));
+        assert!(output.contains("No watchpoint on 'total'."));
+        assert!(output.contains("No variable 'nothing'."));
+        assert!(output.contains("Unknown command 'x', try 'help'."));
+    }
+}
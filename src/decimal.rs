@@ -0,0 +1,410 @@
+// Numbers with as many digits as they need, for the `decimal` compile
+// option, where arithmetic is exact instead of rounding to the nearest f64:
+// 0.1 + 0.2 is 0.3. Division is the only operation whose result can have
+// endless digits, so its quotient is cut off (towards zero) after
+// DIVISION_DIGITS more digits past the point than its operands have.
+//
+// A number is a magnitude with a decimal scale, `magnitude × 10^-scale`,
+// where the magnitude is in base 10^9 limbs, least significant first. Numbers
+// are kept normalized (no zeros at the end of the fraction, no limbs of zeros
+// at the top, and zero is positive), so that equal numbers are represented
+// the same way.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, Mul, Neg, Sub},
+};
+
+const BASE: u64 = 1_000_000_000;
+const BASE_DIGITS: u32 = 9;
+const DIVISION_DIGITS: u32 = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decimal {
+    negative: bool,
+    magnitude: Vec<u32>,
+    // how many of the digits are after the point
+    scale: u32,
+}
+
+impl Decimal {
+    /// The number that `text` is written as: digits, with an optional
+    /// fraction after a point, like a Lox number literal, and an optional
+    /// minus sign in front.
+    pub fn parse(text: &str) -> Option<Decimal> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(text) => (true, text),
+            None => (false, text),
+        };
+        let (integer, fraction) = match text.split_once('.') {
+            Some((_, "")) => return None,
+            Some(parts) => parts,
+            None => (text, ""),
+        };
+        let is_digits = |part: &str| part.bytes().all(|byte| byte.is_ascii_digit());
+        if integer.is_empty() || !is_digits(integer) || !is_digits(fraction) {
+            return None;
+        }
+
+        let mut magnitude = vec![];
+        for byte in integer.bytes().chain(fraction.bytes()) {
+            mul_small(&mut magnitude, 10, (byte - b'0') as u32);
+        }
+        Some(Decimal::new(negative, magnitude, fraction.len() as u32))
+    }
+
+    fn new(negative: bool, mut magnitude: Vec<u32>, mut scale: u32) -> Self {
+        trim(&mut magnitude);
+        while scale > 0 && magnitude.first().is_some_and(|limb| limb % 10 == 0) {
+            div_small(&mut magnitude, 10);
+            scale -= 1;
+        }
+        if magnitude.is_empty() {
+            return Decimal {
+                negative: false,
+                magnitude,
+                scale: 0,
+            };
+        }
+
+        Decimal {
+            negative,
+            magnitude,
+            scale,
+        }
+    }
+
+    /// The bytes of the digits, which are kept apart from the number.
+    pub fn heap_size(&self) -> usize {
+        self.magnitude.capacity() * size_of::<u32>()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    /// `self / other`, or `None` when `other` is zero.
+    pub fn checked_div(&self, other: &Decimal) -> Option<Decimal> {
+        if other.is_zero() {
+            return None;
+        }
+
+        // (a × 10^-sa) / (b × 10^-sb) is (a × 10^(scale - sa + sb) / b) × 10^-scale
+        let scale = self.scale.max(other.scale) + DIVISION_DIGITS;
+        let mut dividend = self.magnitude.clone();
+        shift(&mut dividend, scale - self.scale + other.scale);
+        let quotient = div_mag(&dividend, &other.magnitude);
+        Some(Decimal::new(
+            self.negative != other.negative,
+            quotient,
+            scale,
+        ))
+    }
+
+    // the magnitudes of both numbers at the larger of their scales
+    fn aligned(&self, other: &Decimal) -> (Vec<u32>, Vec<u32>, u32) {
+        let scale = self.scale.max(other.scale);
+        let mut a = self.magnitude.clone();
+        let mut b = other.magnitude.clone();
+        shift(&mut a, scale - self.scale);
+        shift(&mut b, scale - other.scale);
+        (a, b, scale)
+    }
+}
+
+impl Add for &Decimal {
+    type Output = Decimal;
+
+    fn add(self, other: &Decimal) -> Decimal {
+        let (a, b, scale) = self.aligned(other);
+        if self.negative == other.negative {
+            return Decimal::new(self.negative, add_mag(&a, &b), scale);
+        }
+
+        // the sign is the one of the larger magnitude
+        match cmp_mag(&a, &b) {
+            Ordering::Less => Decimal::new(other.negative, sub_mag(&b, &a), scale),
+            _ => Decimal::new(self.negative, sub_mag(&a, &b), scale),
+        }
+    }
+}
+
+impl Sub for &Decimal {
+    type Output = Decimal;
+
+    fn sub(self, other: &Decimal) -> Decimal {
+        self + &-other
+    }
+}
+
+impl Mul for &Decimal {
+    type Output = Decimal;
+
+    fn mul(self, other: &Decimal) -> Decimal {
+        Decimal::new(
+            self.negative != other.negative,
+            mul_mag(&self.magnitude, &other.magnitude),
+            self.scale + other.scale,
+        )
+    }
+}
+
+impl Neg for &Decimal {
+    type Output = Decimal;
+
+    fn neg(self) -> Decimal {
+        Decimal::new(!self.negative, self.magnitude.clone(), self.scale)
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self.negative != other.negative {
+            return if self.negative {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+        }
+
+        let (a, b, _) = self.aligned(other);
+        let ordering = cmp_mag(&a, &b);
+        if self.negative {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Every digit of the number, without an exponent, e.g. `-0.001`.
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = mag_to_string(&self.magnitude);
+        let scale = self.scale as usize;
+        if self.negative {
+            write!(f, "-")?;
+        }
+        if scale == 0 {
+            return write!(f, "{}", digits);
+        }
+
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}.{}", integer, fraction)
+    }
+}
+
+fn trim(magnitude: &mut Vec<u32>) {
+    while magnitude.last() == Some(&0) {
+        magnitude.pop();
+    }
+}
+
+// `magnitude × factor + carry`, in place
+fn mul_small(magnitude: &mut Vec<u32>, factor: u32, carry: u32) {
+    let mut carry = carry as u64;
+    for limb in magnitude.iter_mut() {
+        let value = *limb as u64 * factor as u64 + carry;
+        *limb = (value % BASE) as u32;
+        carry = value / BASE;
+    }
+    while carry > 0 {
+        magnitude.push((carry % BASE) as u32);
+        carry /= BASE;
+    }
+    trim(magnitude);
+}
+
+// `magnitude / divisor` in place, for a divisor that divides it
+fn div_small(magnitude: &mut Vec<u32>, divisor: u32) {
+    let mut remainder = 0;
+    for limb in magnitude.iter_mut().rev() {
+        let value = remainder * BASE + *limb as u64;
+        *limb = (value / divisor as u64) as u32;
+        remainder = value % divisor as u64;
+    }
+    trim(magnitude);
+}
+
+// `magnitude × 10^digits`, in place
+fn shift(magnitude: &mut Vec<u32>, digits: u32) {
+    if magnitude.is_empty() {
+        return;
+    }
+    let limbs = (digits / BASE_DIGITS) as usize;
+    magnitude.splice(0..0, core::iter::repeat_n(0, limbs));
+    mul_small(magnitude, 10u32.pow(digits % BASE_DIGITS), 0);
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    for i in 0..a.len().max(b.len()) {
+        let value = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        sum.push((value % BASE) as u32);
+        carry = value / BASE;
+    }
+    if carry > 0 {
+        sum.push(carry as u32);
+    }
+    sum
+}
+
+// `a - b`, where `a` is at least `b`
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut difference = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    for (i, &limb) in a.iter().enumerate() {
+        let subtrahend = *b.get(i).unwrap_or(&0) as i64 + borrow;
+        let mut value = limb as i64 - subtrahend;
+        borrow = 0;
+        if value < 0 {
+            value += BASE as i64;
+            borrow = 1;
+        }
+        difference.push(value as u32);
+    }
+    trim(&mut difference);
+    difference
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return vec![];
+    }
+
+    let mut product = vec![0; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0;
+        for (j, &y) in b.iter().enumerate() {
+            let value = product[i + j] as u64 + x as u64 * y as u64 + carry;
+            product[i + j] = (value % BASE) as u32;
+            carry = value / BASE;
+        }
+        product[i + b.len()] = carry as u32;
+    }
+    trim(&mut product);
+    product
+}
+
+// `a / b` rounded towards zero, by long division one decimal digit of `a`
+// at a time
+fn div_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut quotient = vec![];
+    let mut remainder = vec![];
+    for byte in mag_to_string(a).bytes() {
+        mul_small(&mut remainder, 10, (byte - b'0') as u32);
+        let mut digit = 0;
+        while cmp_mag(&remainder, b) != Ordering::Less {
+            remainder = sub_mag(&remainder, b);
+            digit += 1;
+        }
+        mul_small(&mut quotient, 10, digit);
+    }
+    quotient
+}
+
+fn mag_to_string(magnitude: &[u32]) -> String {
+    let Some((top, rest)) = magnitude.split_last() else {
+        return "0".to_string();
+    };
+    let mut digits = top.to_string();
+    for limb in rest.iter().rev() {
+        digits.push_str(&format!("{:09}", limb));
+    }
+    digits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimal(text: &str) -> Decimal {
+        Decimal::parse(text).expect("valid decimal")
+    }
+
+    #[test]
+    fn test_decimal_parse() {
+        let cases = [
+            ("0", "0"),
+            ("-0", "0"),
+            ("007", "7"),
+            ("1.50", "1.5"),
+            ("-0.001", "-0.001"),
+            ("10.0", "10"),
+            (
+                "123456789012345678901234567890.123456789",
+                "123456789012345678901234567890.123456789",
+            ),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(decimal(text).to_string(), expected, "{}", text);
+        }
+
+        for text in ["", "-", ".5", "1.", "1.2.3", "1e3", "--1", "one"] {
+            assert_eq!(Decimal::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_decimal_arithmetic() {
+        assert_eq!(&decimal("0.1") + &decimal("0.2"), decimal("0.3"));
+        assert_eq!(&decimal("1") - &decimal("1.01"), decimal("-0.01"));
+        assert_eq!(&decimal("-2.5") + &decimal("2.5"), decimal("0"));
+        assert_eq!(&decimal("1.1") * &decimal("-1.1"), decimal("-1.21"));
+        assert_eq!(
+            &decimal("999999999999999999") + &decimal("1"),
+            decimal("1000000000000000000")
+        );
+        assert_eq!(
+            &decimal("123456789123456789") * &decimal("987654321987654321"),
+            decimal("121932631356500531347203169112635269")
+        );
+        assert_eq!(-&decimal("0"), decimal("0"));
+
+        assert_eq!(
+            decimal("1").checked_div(&decimal("4")),
+            Some(decimal("0.25"))
+        );
+        assert_eq!(
+            decimal("-1").checked_div(&decimal("3")),
+            Some(decimal("-0.33333333333333333333"))
+        );
+        assert_eq!(
+            decimal("0.001").checked_div(&decimal("0.3")),
+            Some(decimal("0.00333333333333333333333"))
+        );
+        assert_eq!(decimal("1").checked_div(&decimal("0.0")), None);
+    }
+
+    #[test]
+    fn test_decimal_ordering() {
+        let mut numbers = ["1.5", "-2", "0", "1.49", "-0.5", "10"].map(decimal);
+        numbers.sort();
+        assert_eq!(
+            numbers,
+            ["-2", "-0.5", "0", "1.49", "1.5", "10"].map(decimal)
+        );
+        assert_eq!(decimal("2.0"), decimal("2"));
+    }
+}
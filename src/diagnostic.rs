@@ -0,0 +1,224 @@
+use alloc::string::{String, ToString};
+
+use crate::{
+    chunk::Span,
+    io,
+    scanner::{Token, TokenKind},
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A compile error or warning pointing at a span of the source, rendered in the same
+/// shape as rustc's diagnostics:
+///
+/// ```text
+/// error: Expect expression.
+///  --> 1:11
+///   |
+/// 1 | print 1 + ;
+///   |           ^
+///   = help: ...
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // the column is 1-based (in bytes), and at least one caret is always
+    // printed, even for an empty span
+    pub span: Span,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    /// A diagnostic that underlines `token`.
+    pub fn at_token<S: AsRef<str>>(
+        severity: Severity,
+        token: &Token,
+        message: S,
+        help: Option<&str>,
+    ) -> Self {
+        let length = match token.kind {
+            // the caret goes right after the last character
+            TokenKind::EndOfFile => 0,
+            // the lexeme is the message, the offending character is at the start
+            TokenKind::Error => 1,
+            _ => token.lexeme.len(),
+        };
+        Self {
+            severity,
+            message: message.as_ref().to_string(),
+            span: Span {
+                line: token.line as u32,
+                column: token.column as u32,
+                start: token.start,
+                end: token.start + length,
+            },
+            help: help.map(str::to_string),
+        }
+    }
+
+    pub fn render<W: io::Write + ?Sized>(&self, w: &mut W, source: &str) {
+        let Span { line, column, .. } = self.span;
+        let gutter = line.to_string().len();
+        let text = source
+            .lines()
+            .nth(line.saturating_sub(1) as usize)
+            .unwrap_or("");
+
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        writeln!(w, "{}: {}", severity, self.message).expect("writable");
+        writeln!(w, "{:gutter$}--> {}:{}", "", line, column).expect("writable");
+        writeln!(w, "{:gutter$} |", "").expect("writable");
+        writeln!(w, "{} | {}", line, text).expect("writable");
+
+        let start = (column.max(1) as usize - 1).min(text.len());
+        let prefix = text.get(..start).unwrap_or(text);
+        // keep tabs so that the caret lines up with the source line above it
+        let padding = prefix
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        // a span that continues onto the next lines is only underlined up to
+        // the end of its first line
+        let end = (start + self.span.end - self.span.start).min(text.len());
+        let carets = text
+            .get(start..end)
+            .map_or(1, |span| span.chars().count().max(1));
+        writeln!(w, "{:gutter$} | {}{}", "", padding, "^".repeat(carets)).expect("writable");
+
+        if let Some(help) = &self.help {
+            writeln!(w, "{:gutter$} = help: {}", "", help).expect("writable");
+        }
+
+        // keep consecutive diagnostics apart
+        writeln!(w).expect("writable");
+    }
+}
+
+/// Renders every one of `diagnostics`, in the order that they are given.
+pub fn render_all<W: io::Write + ?Sized>(w: &mut W, diagnostics: &[Diagnostic], source: &str) {
+    diagnostics
+        .iter()
+        .for_each(|diagnostic| diagnostic.render(w, source));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a span of `length` bytes, where the offsets are not rendered
+    fn span(line: u32, column: u32, length: usize) -> Span {
+        Span {
+            line,
+            column,
+            start: 0,
+            end: length,
+        }
+    }
+
+    fn render(diagnostic: &Diagnostic, source: &str) -> String {
+        let mut output = Vec::new();
+        diagnostic.render(&mut output, source);
+        String::from_utf8(output).expect("valid utf8")
+    }
+
+    #[test]
+    fn test_diagnostic_render() {
+        let source = "print 1;\nprint 1 +\t;\n";
+
+        assert_eq!(
+            render(
+                &Diagnostic {
+                    severity: Severity::Error,
+                    message: "Expect expression.".to_string(),
+                    span: span(2, 11, 1),
+                    help: None,
+                },
+                source
+            ),
+            "error: Expect expression.\n --> 2:11\n  |\n2 | print 1 +\t;\n  |          \t^\n\n"
+        );
+
+        assert_eq!(
+            render(
+                &Diagnostic {
+                    severity: Severity::Error,
+                    message: "Invalid assignment target.".to_string(),
+                    span: span(1, 7, 2),
+                    help: Some("only variables can be assigned to".to_string()),
+                },
+                "print 12 = 3;"
+            ),
+            "error: Invalid assignment target.\n --> 1:7\n  |\n1 | print 12 = 3;\n  |       ^^\n  = help: only variables can be assigned to\n\n"
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_render_warning() {
+        assert_eq!(
+            render(
+                &Diagnostic {
+                    severity: Severity::Warning,
+                    message: "Unused local variable 'a'.".to_string(),
+                    span: span(1, 7, 1),
+                    help: None,
+                },
+                "{ var a = 1; }"
+            ),
+            "warning: Unused local variable 'a'.\n --> 1:7\n  |\n1 | { var a = 1; }\n  |       ^\n\n"
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_render_edges() {
+        // the gutter grows with the line number
+        let source = "\n".repeat(9) + "var x = ;";
+        assert_eq!(
+            render(
+                &Diagnostic {
+                    severity: Severity::Error,
+                    message: "Expect expression.".to_string(),
+                    span: span(10, 9, 1),
+                    help: None,
+                },
+                &source
+            ),
+            "error: Expect expression.\n  --> 10:9\n   |\n10 | var x = ;\n   |         ^\n\n"
+        );
+
+        // the end of the source is past the last character of a line
+        assert_eq!(
+            render(
+                &Diagnostic {
+                    severity: Severity::Error,
+                    message: "Expect ';' after value.".to_string(),
+                    span: span(1, 8, 0),
+                    help: None,
+                },
+                "print 1"
+            ),
+            "error: Expect ';' after value.\n --> 1:8\n  |\n1 | print 1\n  |        ^\n\n"
+        );
+
+        // multi-line spans are cut off at the end of the first line
+        assert_eq!(
+            render(
+                &Diagnostic {
+                    severity: Severity::Error,
+                    message: "Invalid assignment target.".to_string(),
+                    span: span(1, 1, 11),
+                    help: None,
+                },
+                "\"two\nlines\" = 1;"
+            ),
+            "error: Invalid assignment target.\n --> 1:1\n  |\n1 | \"two\n  | ^^^^\n\n"
+        );
+    }
+}
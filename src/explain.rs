@@ -0,0 +1,294 @@
+// The trace of `clox --explain`, for reading along with the book: every
+// instruction that runs is a row of a table, with the stack before and after
+// it and what it does in plain English.
+//
+// It is a debugger that never pauses, which sees each instruction before it
+// runs. The stack after an instruction is the one before the next, so each
+// row is written once the next instruction comes, or the script stops.
+
+use std::{io, ops::ControlFlow};
+
+use crate::{
+    chunk::Chunk,
+    instruction::Instruction,
+    value::Value,
+    vm::{Debugger, InterpretError, Paused},
+};
+
+// the widths of the columns, which longer stacks run past
+const INSTRUCTION_WIDTH: usize = 26;
+const STACK_WIDTH: usize = 24;
+
+/// What the instruction at `paused.offset` does, e.g. "pushes constant 3".
+pub fn describe(paused: &Paused<'_>) -> String {
+    let chunk = paused.chunk;
+    let Some((instruction, next)) = Instruction::decode(chunk, paused.offset) else {
+        return "is not an instruction".to_string();
+    };
+    let global = |index: u32| match chunk.constants().try_get(index as usize) {
+        Some(name) => format!("'{}'", name),
+        None => "<missing>".to_string(),
+    };
+    let cached = |slot: u32| match paused.global_name(slot as usize) {
+        Some(name) => format!("'{}'", name),
+        None => format!("in slot {}", slot),
+    };
+    let local = |slot: u8| {
+        let name = chunk
+            .locals_at(paused.offset)
+            .into_iter()
+            .rfind(|local| local.slot == slot)
+            .map(|local| local.name.clone());
+        match name {
+            Some(name) => format!("the local '{}'", name),
+            None => format!("local slot {}", slot),
+        }
+    };
+
+    match instruction {
+        Instruction::Return => "returns the top value from the function".to_string(),
+        Instruction::Constant(index) => format!("pushes constant {}", constant(chunk, index)),
+        Instruction::Negate => "negates the top value".to_string(),
+        Instruction::Add => "adds the top two values, or joins two strings".to_string(),
+        Instruction::Subtract => "subtracts the top value from the one below it".to_string(),
+        Instruction::Multiply => "multiplies the top two values".to_string(),
+        Instruction::Divide => "divides the value below the top by the top value".to_string(),
+        Instruction::Nil => "pushes nil".to_string(),
+        Instruction::True => "pushes true".to_string(),
+        Instruction::False => "pushes false".to_string(),
+        Instruction::Not => "replaces the top value with whether it is falsey".to_string(),
+        Instruction::Equal => "pushes whether the top two values are equal".to_string(),
+        Instruction::Greater => comparison(">"),
+        Instruction::Less => comparison("<"),
+        Instruction::GreaterEqual => comparison(">="),
+        Instruction::LessEqual => comparison("<="),
+        Instruction::Print => "pops the top value and prints it".to_string(),
+        Instruction::Pop => "pops the top value".to_string(),
+        Instruction::DefineGlobal(index) => {
+            format!("pops the top value into the new global {}", global(index))
+        }
+        Instruction::GetGlobal(index) => format!("pushes the global {}", global(index)),
+        Instruction::SetGlobal(index) => {
+            format!("sets the global {} to the top value", global(index))
+        }
+        Instruction::GetGlobalCached(slot) => format!("pushes the global {}", cached(slot)),
+        Instruction::SetGlobalCached(slot) => {
+            format!("sets the global {} to the top value", cached(slot))
+        }
+        Instruction::GetLocal(slot) => format!("pushes {}", local(slot)),
+        Instruction::SetLocal(slot) => format!("sets {} to the top value", local(slot)),
+        Instruction::JumpIfFalse(jump) => format!(
+            "jumps to {:04} if the top value is falsey",
+            next + jump as usize
+        ),
+        Instruction::Jump(jump) => format!("jumps forward to {:04}", next + jump as usize),
+        Instruction::Loop(jump) => format!("jumps back to {:04}", next - jump as usize),
+        Instruction::Call(count) => match count {
+            1 => "calls the function below the top value with it".to_string(),
+            count => format!(
+                "calls the function below the top {} values with them",
+                count
+            ),
+        },
+        Instruction::AddConstant(index) => {
+            format!("adds constant {} to the top value", constant(chunk, index))
+        }
+        Instruction::GetLocalAddConstant(slot, index) => format!(
+            "pushes {} plus constant {}",
+            local(slot),
+            constant(chunk, index)
+        ),
+        Instruction::Zero => "pushes 0".to_string(),
+        Instruction::One => "pushes 1".to_string(),
+        Instruction::MinusOne => "pushes -1".to_string(),
+        Instruction::Dup => "pushes a copy of the top value".to_string(),
+        Instruction::PopN(count) => format!("pops the top {} values", count),
+        Instruction::AddNumbers => "adds the top two values, numbers so far".to_string(),
+        Instruction::SubtractNumbers => {
+            "subtracts the top value from the one below it, numbers so far".to_string()
+        }
+        Instruction::LessNumbers => format!("{}, numbers so far", comparison("<")),
+        Instruction::ConcatN(count) => format!("adds the top {} values, left to right", count),
+    }
+}
+
+fn comparison(operator: &str) -> String {
+    format!(
+        "pushes whether the value below the top is {} the top value",
+        operator
+    )
+}
+
+// the constant at `index`, with strings in quotes to tell them apart
+fn constant(chunk: &Chunk, index: u32) -> String {
+    match chunk.constants().try_get(index as usize) {
+        Some(value) => match value.as_string() {
+            Some(string) => format!("{:?}", string.as_str()),
+            None => value.to_string(),
+        },
+        None => "<missing>".to_string(),
+    }
+}
+
+fn stack(values: &[Value]) -> String {
+    values
+        .iter()
+        .map(|value| format!("[ {} ]", value))
+        .collect()
+}
+
+// an instruction whose row waits for the stack after it
+struct Row {
+    offset: usize,
+    line: Option<u32>,
+    mnemonic: &'static str,
+    before: String,
+    description: String,
+}
+
+/// Writes the table of `clox --explain` to `output`.
+pub struct Explain<W: io::Write> {
+    output: W,
+    pending: Option<Row>,
+    // the line and depth of the row before, to leave out a line that stays
+    // the same
+    last: Option<(usize, u32)>,
+}
+
+impl<W: io::Write> Explain<W> {
+    pub fn new(mut output: W) -> Self {
+        writeln!(
+            output,
+            "{:<4} {:>4} {:<iw$} {:<sw$} {:<sw$} explanation",
+            "byte",
+            "line",
+            "instruction",
+            "stack before",
+            "stack after",
+            iw = INSTRUCTION_WIDTH,
+            sw = STACK_WIDTH
+        )
+        .expect("writable");
+        Self {
+            output,
+            pending: None,
+            last: None,
+        }
+    }
+
+    fn write_row(&mut self, after: &str) {
+        let Some(row) = self.pending.take() else {
+            return;
+        };
+        let line = row.line.map_or("|".to_string(), |line| line.to_string());
+        writeln!(
+            self.output,
+            "{:04} {:>4} {:<iw$} {:<sw$} {:<sw$} {}",
+            row.offset,
+            line,
+            row.mnemonic,
+            row.before,
+            after,
+            row.description,
+            iw = INSTRUCTION_WIDTH,
+            sw = STACK_WIDTH
+        )
+        .expect("writable");
+        self.output.flush().expect("writable");
+    }
+}
+
+impl<W: io::Write> Debugger for Explain<W> {
+    fn before_instruction(&mut self, paused: &Paused<'_>) -> ControlFlow<()> {
+        let before = stack(paused.stack);
+        self.write_row(&before);
+
+        let Some((instruction, _)) = Instruction::decode(paused.chunk, paused.offset) else {
+            return ControlFlow::Continue(());
+        };
+        let here = (paused.depth, paused.line());
+        let line = (self.last.replace(here) != Some(here)).then_some(here.1);
+        self.pending = Some(Row {
+            offset: paused.offset,
+            line,
+            mnemonic: instruction.opcode().mnemonic(),
+            before,
+            description: describe(paused),
+        });
+        ControlFlow::Continue(())
+    }
+
+    fn finished(&mut self, result: &Result<(), InterpretError>) {
+        // a runtime error throws away the stack, rather than leave one after
+        let after = if result.is_ok() { "" } else { "(error)" };
+        self.write_row(after);
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::vm::VM;
+
+    // output that the test can still read once the VM owns the debugger
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn explain(source: &str) -> (Result<(), InterpretError>, Vec<String>) {
+        let output = Shared::default();
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_tiering(false);
+        vm.set_debugger(Box::new(Explain::new(output.clone())));
+        let result = vm.interpret(source);
+        let table = String::from_utf8(output.0.take()).expect("valid utf8");
+        (
+            result,
+            table
+                .lines()
+                .map(str::trim_end)
+                .map(str::to_string)
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_explain() {
+        let (result, table) = explain("{\n  var a = \"hi\";\n  print a;\n}");
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            table,
+            [
+                "byte line instruction                stack before             stack after              explanation",
+                "0000    2 OP_CONSTANT                [ <script> ]             [ <script> ][ hi ]       pushes constant \"hi\"",
+                "0002    3 OP_GET_LOCAL               [ <script> ][ hi ]       [ <script> ][ hi ][ hi ] pushes the local 'a'",
+                "0004    | OP_PRINT                   [ <script> ][ hi ][ hi ] [ <script> ][ hi ]       pops the top value and prints it",
+                "0005    4 OP_POP                     [ <script> ][ hi ]       [ <script> ]             pops the top value",
+                "0006    | OP_NIL                     [ <script> ]             [ <script> ][ nil ]      pushes nil",
+                "0007    | OP_RETURN                  [ <script> ][ nil ]                               returns the top value from the function",
+            ]
+        );
+
+        // the instruction that failed is the last row
+        let (result, table) = explain("-nil;");
+        assert_eq!(result, Err(InterpretError::RuntimeError));
+        assert_eq!(
+            table.last().map(String::as_str),
+            Some(
+                "0001    | OP_NEGATE                  [ <script> ][ nil ]      (error)                  negates the top value"
+            )
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use alloc::format;
+
+use crate::{object::Heap, scanner::TokenKind, value::Value};
+
+// evaluates `operator operand` at compile time, or returns `None` if that
+// would be a runtime error, which is left for the VM to report
+pub fn fold_unary(operator: TokenKind, operand: Value) -> Option<Value> {
+    match (operator, operand) {
+        (TokenKind::Minus, Value::Number(a)) => Some(Value::Number(-a)),
+        (TokenKind::Bang, a) => Some(Value::Bool(a.is_falsey())),
+        _ => None,
+    }
+}
+
+// evaluates `a operator b` at compile time, or returns `None` if that would
+// be a runtime error, which is left for the VM to report. `book_comparisons`
+// has to match how the compiler emits `>=` and `<=`
+pub fn fold_binary(
+    operator: TokenKind,
+    a: Value,
+    b: Value,
+    book_comparisons: bool,
+    heap: &mut Heap,
+) -> Option<Value> {
+    if let (TokenKind::Plus, Some(a), Some(b)) = (operator, a.as_string(), b.as_string()) {
+        let result = heap.alloc_string(format!("{}{}", a.as_str(), b.as_str()));
+        return Some(Value::Obj(result));
+    }
+
+    match (operator, a, b) {
+        (TokenKind::EqualEqual, a, b) => Some(Value::Bool(a == b)),
+        (TokenKind::BangEqual, a, b) => Some(Value::Bool(a != b)),
+        (_, Value::Number(a), Value::Number(b)) => match operator {
+            TokenKind::Plus => Some(Value::Number(a + b)),
+            TokenKind::Minus => Some(Value::Number(a - b)),
+            TokenKind::Star => Some(Value::Number(a * b)),
+            TokenKind::Slash => Some(Value::Number(a / b)),
+            TokenKind::Greater => Some(Value::Bool(a > b)),
+            TokenKind::Less => Some(Value::Bool(a < b)),
+            // the book's `!(a < b)` and `!(a > b)`, which differ from IEEE-754 for NaN
+            #[allow(clippy::neg_cmp_op_on_partial_ord)]
+            TokenKind::GreaterEqual if book_comparisons => Some(Value::Bool(!(a < b))),
+            #[allow(clippy::neg_cmp_op_on_partial_ord)]
+            TokenKind::LessEqual if book_comparisons => Some(Value::Bool(!(a > b))),
+            TokenKind::GreaterEqual => Some(Value::Bool(a >= b)),
+            TokenKind::LessEqual => Some(Value::Bool(a <= b)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold_unary() {
+        assert_eq!(
+            fold_unary(TokenKind::Minus, Value::Number(3.0)),
+            Some(Value::Number(-3.0))
+        );
+        assert_eq!(
+            fold_unary(TokenKind::Bang, Value::Bool(true)),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(
+            fold_unary(TokenKind::Bang, Value::Nil),
+            Some(Value::Bool(true))
+        );
+        assert_eq!(fold_unary(TokenKind::Minus, Value::Bool(true)), None);
+    }
+
+    #[test]
+    fn test_fold_binary() {
+        let mut heap = Heap::new();
+
+        assert_eq!(
+            fold_binary(
+                TokenKind::Star,
+                Value::Number(2.0),
+                Value::Number(3.0),
+                false,
+                &mut heap
+            ),
+            Some(Value::Number(6.0))
+        );
+        assert_eq!(
+            fold_binary(
+                TokenKind::EqualEqual,
+                Value::Nil,
+                Value::Bool(false),
+                false,
+                &mut heap
+            ),
+            Some(Value::Bool(false))
+        );
+        let nan = Value::Number(f64::NAN);
+        assert_eq!(
+            fold_binary(
+                TokenKind::GreaterEqual,
+                nan,
+                Value::Number(1.0),
+                false,
+                &mut heap
+            ),
+            Some(Value::Bool(false))
+        );
+        assert_eq!(
+            fold_binary(
+                TokenKind::GreaterEqual,
+                nan,
+                Value::Number(1.0),
+                true,
+                &mut heap
+            ),
+            Some(Value::Bool(true))
+        );
+
+        let a = Value::Obj(heap.alloc_string("a".to_string()));
+        let b = Value::Obj(heap.alloc_string("b".to_string()));
+        let ab = Value::Obj(heap.alloc_string("ab".to_string()));
+        assert_eq!(
+            fold_binary(TokenKind::Plus, a, b, false, &mut heap),
+            Some(ab)
+        );
+
+        // type errors are left for the runtime
+        assert_eq!(
+            fold_binary(TokenKind::Plus, a, Value::Number(1.0), false, &mut heap),
+            None
+        );
+        assert_eq!(fold_binary(TokenKind::Less, a, b, false, &mut heap), None);
+    }
+}
@@ -0,0 +1,204 @@
+// The formatter of `clox fmt`, which lays out the tokens of a script the
+// same way whatever way they were written: a statement to a line, blocks
+// indented by two spaces with the `{` at the end of the line, and one space
+// around binary operators and after commas and keywords.
+//
+// It works on the tokens of the lossless mode rather than the syntax tree,
+// so that comments stay where they were: at the end of the line that they
+// followed, or on a line of their own. Blank lines between statements are
+// kept, but no more than one in a row. Lines are never wrapped.
+
+use crate::{
+    diagnostic::Diagnostic,
+    parser::Parser,
+    scanner::{Scanner, TokenKind, TriviaKind},
+};
+
+const INDENT: &str = "  ";
+
+/// `source` formatted, or the diagnostics of its syntax errors, since code
+/// that doesn't parse can't be laid out.
+pub fn format(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut parser = Parser::new(source);
+    parser.parse();
+    if parser.had_error() {
+        return Err(parser.take_diagnostics());
+    }
+
+    let mut formatter = Formatter::default();
+    for token in Scanner::new(source).lossless() {
+        let mut newlines = 0;
+        for trivia in &token.leading {
+            match trivia.kind {
+                TriviaKind::Whitespace => newlines += trivia.text.matches('\n').count(),
+                TriviaKind::Comment => {
+                    formatter.comment(trivia.text, newlines);
+                    newlines = 0;
+                }
+                TriviaKind::ByteOrderMark => {}
+            }
+        }
+        if token.token.kind == TokenKind::EndOfFile {
+            break;
+        }
+        formatter.token(token.token.kind, token.text, newlines);
+    }
+
+    let mut formatted = formatter.output;
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+#[derive(Default)]
+struct Formatter {
+    output: String,
+    depth: usize,
+    // how deep the tokens are in parentheses, where `;` doesn't end a line
+    // like it does in the clauses of `for`
+    parens: usize,
+    // the kind of the last token, and whether it was a unary operator
+    last: Option<(TokenKind, bool)>,
+    // whether the next token starts a new line
+    line_ended: bool,
+}
+
+impl Formatter {
+    fn comment(&mut self, text: &str, newlines: usize) {
+        if self.last.is_some() && newlines == 0 && !self.output.is_empty() {
+            self.output.push(' ');
+        } else {
+            self.new_line(newlines, false);
+        }
+        self.output.push_str(text.trim_end());
+        self.line_ended = true;
+    }
+
+    fn token(&mut self, kind: TokenKind, text: &str, newlines: usize) {
+        use TokenKind::*;
+
+        let last = self.last.map(|(last, _)| last);
+        if kind == RightBrace {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        let empty_block = kind == RightBrace && last == Some(LeftBrace);
+        let joins_else = kind == Else && last == Some(RightBrace);
+        if self.line_ended && !empty_block && !joins_else {
+            self.new_line(newlines, kind == RightBrace);
+        } else if self.spaced(kind) {
+            self.output.push(' ');
+        }
+        self.output.push_str(text);
+
+        let unary = matches!(kind, Bang | Minus) && self.expects_operand();
+        self.last = Some((kind, unary));
+        self.line_ended = match kind {
+            LeftBrace => {
+                self.depth += 1;
+                true
+            }
+            RightBrace => true,
+            Semicolon => self.parens == 0,
+            _ => false,
+        };
+        match kind {
+            LeftParen => self.parens += 1,
+            RightParen => self.parens = self.parens.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    // starts a line at the depth of the block, after a blank line if there
+    // were any in the source, other than at the start or end of a block
+    fn new_line(&mut self, newlines: usize, closes_block: bool) {
+        if self.output.is_empty() {
+            return;
+        }
+        let opens_block = self
+            .last
+            .is_some_and(|(last, _)| last == TokenKind::LeftBrace);
+        if newlines > 1 && !opens_block && !closes_block {
+            self.output.push('\n');
+        }
+        self.output.push('\n');
+        self.output.push_str(&INDENT.repeat(self.depth));
+    }
+
+    // whether a space goes between the last token and one of `kind`
+    fn spaced(&self, kind: TokenKind) -> bool {
+        use TokenKind::*;
+
+        let Some((last, unary)) = self.last else {
+            return false;
+        };
+        match (last, kind) {
+            (_, Semicolon | Comma | Dot | RightParen) => false,
+            (LeftParen | Dot, _) | (LeftBrace, RightBrace) => false,
+            (Bang | Minus, _) if unary => false,
+            // a call, or the parameters of a function
+            (Identifier | RightParen | This | Super, LeftParen) => false,
+            _ => true,
+        }
+    }
+
+    // whether the next token starts an operand, which makes a `-` unary
+    fn expects_operand(&self) -> bool {
+        use TokenKind::*;
+
+        !matches!(
+            self.last,
+            Some((
+                Identifier | Number | String | RawString | RightParen | True | False | Nil | This,
+                _
+            ))
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn formatted(source: &str) -> String {
+        format(source).expect("no syntax errors")
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            formatted("var a=1;var b  =  -a*(2+ -3);print !true;print a  -1;"),
+            "var a = 1;\nvar b = -a * (2 + -3);\nprint !true;\nprint a - 1;\n"
+        );
+        assert_eq!(
+            formatted("fun add (a,b){return a+b;}\nprint add( 1 , 2 );"),
+            "fun add(a, b) {\n  return a + b;\n}\nprint add(1, 2);\n"
+        );
+        assert_eq!(
+            formatted(
+                "for(var i=0;i<3;i=i+1){if(i==1){print i;}else{}}\nwhile (true)\n    print 1;"
+            ),
+            "for (var i = 0; i < 3; i = i + 1) {\n  if (i == 1) {\n    print i;\n  } else {}\n}\nwhile (true) print 1;\n"
+        );
+        assert_eq!(formatted("for (;;) {}"), "for (;;) {}\n");
+        assert_eq!(formatted(""), "");
+        assert_eq!(formatted("\n\n"), "");
+    }
+
+    #[test]
+    fn test_format_comments() {
+        let source = "// a script\n\n\n\nvar a = 1;   // one\n{\n\n    // inside\n  print a;\n\n\n  print a;\n\n}\n// the end\n";
+        assert_eq!(
+            formatted(source),
+            "// a script\n\nvar a = 1; // one\n{\n  // inside\n  print a;\n\n  print a;\n}\n// the end\n"
+        );
+        // formatting again changes nothing
+        assert_eq!(formatted(&formatted(source)), formatted(source));
+    }
+
+    #[test]
+    fn test_format_errors() {
+        let errors = format("print (1;").expect_err("a syntax error");
+        assert_eq!(errors[0].message, "Expect ')' after expression.");
+    }
+}
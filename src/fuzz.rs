@@ -0,0 +1,134 @@
+// Entry points for fuzzing, which take arbitrary bytes and have to return for
+// every one of them, without a panic, an overflow of the stack, or running
+// forever. fuzz/fuzz_targets has a cargo-fuzz target for each of them:
+//
+//   cargo +nightly fuzz run interpret
+//
+// The first byte of the input picks the options, so that the fuzzer can
+// find its way into each of them, and the rest is the source of a script,
+// which does not have to be UTF-8.
+
+use alloc::{borrow::Cow, boxed::Box, string::String};
+
+use crate::{
+    compiler::{CompileOptions, Compiler, OptLevel},
+    io,
+    object::Heap,
+    parser::Parser,
+    register::Backend,
+    scanner::Scanner,
+    vm::VM,
+};
+
+// how many instructions a script can run, so that loops end
+const INSTRUCTION_LIMIT: u64 = 100_000;
+// how many bytes the objects of a script can take up
+const MEMORY_LIMIT: usize = 1 << 20;
+
+// the options that the bits of the first byte turn on, after the
+// optimizations of -O2 and the decimals
+const REGISTER_BACKEND: u8 = 1 << 2;
+const NO_TIERING: u8 = 1 << 3;
+
+/// Scans the source into tokens, in both of the modes of the scanner.
+pub fn scan(data: &[u8]) {
+    let source = source(data);
+    Scanner::new(&source).for_each(drop);
+    Scanner::new(&source).lossless().for_each(drop);
+}
+
+/// Parses the source.
+pub fn parse(data: &[u8]) {
+    let source = source(data);
+    let mut parser = Parser::new(&source);
+    parser.parse();
+    parser.take_diagnostics();
+}
+
+/// Compiles the source with the options of the first byte.
+pub fn compile(data: &[u8]) {
+    let (options, source) = options(data);
+    let _ = Compiler::compile(&source, &mut Heap::new(), options);
+}
+
+/// Runs the source with the options of the first byte, in a VM whose
+/// output goes nowhere, with limits on how long it runs and how much it
+/// allocates.
+pub fn interpret(data: &[u8]) {
+    let (options, source) = options(data);
+    let flags = data.first().copied().unwrap_or(0);
+
+    let mut vm = VM::with_output(io::Sink);
+    vm.set_error_output(Box::new(io::Sink));
+    vm.set_compile_options(options);
+    if flags & REGISTER_BACKEND != 0 {
+        vm.set_backend(Backend::Register);
+    }
+    vm.set_tiering(flags & NO_TIERING == 0);
+    vm.set_instruction_limit(Some(INSTRUCTION_LIMIT));
+    vm.set_memory_limit(Some(MEMORY_LIMIT));
+    let _ = vm.interpret(source.as_ref());
+}
+
+/// Loads the bytes as a compiled script, and runs it if it is valid, since
+/// the loader promises that running it can't make the VM misbehave.
+pub fn load(data: &[u8]) {
+    let mut vm = VM::with_output(io::Sink);
+    vm.set_error_output(Box::new(io::Sink));
+    vm.set_instruction_limit(Some(INSTRUCTION_LIMIT));
+    vm.set_memory_limit(Some(MEMORY_LIMIT));
+    let _ = vm.interpret_chunk_bytes(data);
+}
+
+fn source(data: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(data)
+}
+
+// the options of the first byte, and the source after it
+fn options(data: &[u8]) -> (CompileOptions, Cow<'_, str>) {
+    let Some((&flags, rest)) = data.split_first() else {
+        return (CompileOptions::default(), Cow::Borrowed(""));
+    };
+
+    let level = if flags & 1 != 0 {
+        OptLevel::O2
+    } else {
+        OptLevel::O0
+    };
+    let options = CompileOptions {
+        decimal: flags & 2 != 0,
+        ..CompileOptions::default().opt_level(level)
+    };
+    (options, source(rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzz_inputs() {
+        // inputs that used to crash or run forever
+        let inputs: [&[u8]; 6] = [
+            b"\x00print ((((((((((((((((((((((((((((((((((((((((1;",
+            b"\x01var s = \"a\"; while (true) s = s + s;",
+            b"\x05while (true) {}",
+            b"\x02var a = 1; while (true) a = a * 12345678901234567890;",
+            b"\x00\xff\xfe print \"\xc3\x28\";",
+            b"",
+        ];
+        for input in inputs {
+            scan(input);
+            parse(input);
+            compile(input);
+            interpret(input);
+            load(input);
+        }
+
+        let deep = alloc::format!("\x00print {}1;", "(".repeat(100_000));
+        parse(deep.as_bytes());
+        compile(deep.as_bytes());
+        let chain = alloc::format!("\x01print 1{};", " + 1".repeat(100_000));
+        interpret(chain.as_bytes());
+    }
+}
@@ -0,0 +1,547 @@
+use alloc::{vec, vec::Vec};
+
+use crate::chunk::{Chunk, OpCode, Span};
+
+/// An instruction along with its operands, so that bytecode can be read and
+/// written without putting the bytes of the operands together by hand.
+///
+/// The offsets of the jumps are relative to the end of the jump, the same as
+/// in the bytecode. Constant indices (names of globals included), the slots
+/// of globals, jump offsets and the counts of OP_CONCAT_N are varints (see
+/// [`Operand::Varint`]), and the rest of the operands are a byte each.
+///
+/// [`Operand::Varint`]: crate::chunk::Operand::Varint
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Instruction {
+    Return,
+    Constant(u32),
+    Negate,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    // the constant of the name of the global
+    DefineGlobal(u32),
+    GetGlobal(u32),
+    SetGlobal(u32),
+    GetLocal(u8),
+    SetLocal(u8),
+    JumpIfFalse(u32),
+    Jump(u32),
+    Loop(u32),
+    Call(u8),
+    AddConstant(u32),
+    // the slot of the local, and the constant that is added to it
+    GetLocalAddConstant(u8, u32),
+    GreaterEqual,
+    LessEqual,
+    Zero,
+    One,
+    MinusOne,
+    Dup,
+    PopN(u8),
+    // the slot of the global in the VM
+    GetGlobalCached(u32),
+    SetGlobalCached(u32),
+    AddNumbers,
+    SubtractNumbers,
+    LessNumbers,
+    // the number of values that are added
+    ConcatN(u32),
+}
+
+impl Instruction {
+    /// The instruction that `op` makes with `operands`, or `None` if they
+    /// are not the operands that `op` takes.
+    pub fn from_operands(op: OpCode, operands: &[u8]) -> Option<Instruction> {
+        let (instruction, len) = Instruction::read(op, |i| operands.get(i).copied())?;
+        (len == operands.len()).then_some(instruction)
+    }
+
+    // the instruction that `op` makes with the operands that `byte` returns
+    // one byte at a time, along with how many bytes of them it takes
+    fn read(op: OpCode, byte: impl Fn(usize) -> Option<u8>) -> Option<(Instruction, usize)> {
+        let mut operands = Bytes { byte, len: 0 };
+        let instruction = Instruction::read_operands(op, &mut operands)?;
+        Some((instruction, operands.len))
+    }
+
+    // the instruction that `op` makes with the operands that come after it,
+    // which is the one place that knows the operands of each instruction,
+    // for both the checked and the unchecked way of reading them
+    #[inline(always)]
+    fn read_operands(op: OpCode, operands: &mut impl Operands) -> Option<Instruction> {
+        let instruction = match op {
+            OpCode::Return => Instruction::Return,
+            OpCode::Constant => Instruction::Constant(operands.varint()?),
+            OpCode::Negate => Instruction::Negate,
+            OpCode::Add => Instruction::Add,
+            OpCode::Subtract => Instruction::Subtract,
+            OpCode::Multiply => Instruction::Multiply,
+            OpCode::Divide => Instruction::Divide,
+            OpCode::Nil => Instruction::Nil,
+            OpCode::True => Instruction::True,
+            OpCode::False => Instruction::False,
+            OpCode::Not => Instruction::Not,
+            OpCode::Equal => Instruction::Equal,
+            OpCode::Greater => Instruction::Greater,
+            OpCode::Less => Instruction::Less,
+            OpCode::Print => Instruction::Print,
+            OpCode::Pop => Instruction::Pop,
+            OpCode::DefineGlobal => Instruction::DefineGlobal(operands.varint()?),
+            OpCode::GetGlobal => Instruction::GetGlobal(operands.varint()?),
+            OpCode::SetGlobal => Instruction::SetGlobal(operands.varint()?),
+            OpCode::GetLocal => Instruction::GetLocal(operands.byte()?),
+            OpCode::SetLocal => Instruction::SetLocal(operands.byte()?),
+            OpCode::JumpIfFalse => Instruction::JumpIfFalse(operands.varint()?),
+            OpCode::Jump => Instruction::Jump(operands.varint()?),
+            OpCode::Loop => Instruction::Loop(operands.varint()?),
+            OpCode::Call => Instruction::Call(operands.byte()?),
+            OpCode::AddConstant => Instruction::AddConstant(operands.varint()?),
+            OpCode::GetLocalAddConstant => {
+                Instruction::GetLocalAddConstant(operands.byte()?, operands.varint()?)
+            }
+            OpCode::GreaterEqual => Instruction::GreaterEqual,
+            OpCode::LessEqual => Instruction::LessEqual,
+            OpCode::Zero => Instruction::Zero,
+            OpCode::One => Instruction::One,
+            OpCode::MinusOne => Instruction::MinusOne,
+            OpCode::Dup => Instruction::Dup,
+            OpCode::PopN => Instruction::PopN(operands.byte()?),
+            OpCode::GetGlobalCached => Instruction::GetGlobalCached(operands.varint()?),
+            OpCode::SetGlobalCached => Instruction::SetGlobalCached(operands.varint()?),
+            OpCode::AddNumbers => Instruction::AddNumbers,
+            OpCode::SubtractNumbers => Instruction::SubtractNumbers,
+            OpCode::LessNumbers => Instruction::LessNumbers,
+            OpCode::ConcatN => Instruction::ConcatN(operands.varint()?),
+        };
+        Some(instruction)
+    }
+
+    pub fn opcode(self) -> OpCode {
+        match self {
+            Instruction::Return => OpCode::Return,
+            Instruction::Constant(_) => OpCode::Constant,
+            Instruction::Negate => OpCode::Negate,
+            Instruction::Add => OpCode::Add,
+            Instruction::Subtract => OpCode::Subtract,
+            Instruction::Multiply => OpCode::Multiply,
+            Instruction::Divide => OpCode::Divide,
+            Instruction::Nil => OpCode::Nil,
+            Instruction::True => OpCode::True,
+            Instruction::False => OpCode::False,
+            Instruction::Not => OpCode::Not,
+            Instruction::Equal => OpCode::Equal,
+            Instruction::Greater => OpCode::Greater,
+            Instruction::Less => OpCode::Less,
+            Instruction::Print => OpCode::Print,
+            Instruction::Pop => OpCode::Pop,
+            Instruction::DefineGlobal(_) => OpCode::DefineGlobal,
+            Instruction::GetGlobal(_) => OpCode::GetGlobal,
+            Instruction::SetGlobal(_) => OpCode::SetGlobal,
+            Instruction::GetLocal(_) => OpCode::GetLocal,
+            Instruction::SetLocal(_) => OpCode::SetLocal,
+            Instruction::JumpIfFalse(_) => OpCode::JumpIfFalse,
+            Instruction::Jump(_) => OpCode::Jump,
+            Instruction::Loop(_) => OpCode::Loop,
+            Instruction::Call(_) => OpCode::Call,
+            Instruction::AddConstant(_) => OpCode::AddConstant,
+            Instruction::GetLocalAddConstant(_, _) => OpCode::GetLocalAddConstant,
+            Instruction::GreaterEqual => OpCode::GreaterEqual,
+            Instruction::LessEqual => OpCode::LessEqual,
+            Instruction::Zero => OpCode::Zero,
+            Instruction::One => OpCode::One,
+            Instruction::MinusOne => OpCode::MinusOne,
+            Instruction::Dup => OpCode::Dup,
+            Instruction::PopN(_) => OpCode::PopN,
+            Instruction::GetGlobalCached(_) => OpCode::GetGlobalCached,
+            Instruction::SetGlobalCached(_) => OpCode::SetGlobalCached,
+            Instruction::AddNumbers => OpCode::AddNumbers,
+            Instruction::SubtractNumbers => OpCode::SubtractNumbers,
+            Instruction::LessNumbers => OpCode::LessNumbers,
+            Instruction::ConcatN(_) => OpCode::ConcatN,
+        }
+    }
+
+    /// The bytes of the operands, with the varints as short as they can be.
+    pub fn operands(self) -> Vec<u8> {
+        self.padded_operands(1)
+    }
+
+    // the same, with the varints padded to at least `len` bytes
+    fn padded_operands(self, len: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        match self {
+            Instruction::GetLocal(byte)
+            | Instruction::SetLocal(byte)
+            | Instruction::Call(byte)
+            | Instruction::PopN(byte) => bytes.push(byte),
+            Instruction::Constant(varint)
+            | Instruction::DefineGlobal(varint)
+            | Instruction::GetGlobal(varint)
+            | Instruction::SetGlobal(varint)
+            | Instruction::GetGlobalCached(varint)
+            | Instruction::SetGlobalCached(varint)
+            | Instruction::AddConstant(varint)
+            | Instruction::JumpIfFalse(varint)
+            | Instruction::Jump(varint)
+            | Instruction::Loop(varint) => write_varint(&mut bytes, varint, len),
+            Instruction::GetLocalAddConstant(slot, constant) => {
+                bytes.push(slot);
+                write_varint(&mut bytes, constant, len);
+            }
+            // a byte for each of the additions, as far as a varint goes
+            Instruction::ConcatN(count) => {
+                let additions = (count as usize).saturating_sub(1).min(MAX_VARINT_LEN);
+                write_varint(&mut bytes, count, len.max(additions));
+            }
+            _ => {}
+        }
+        bytes
+    }
+
+    /// Appends the instruction to `chunk`, with all of its bytes coming from
+    /// `span`.
+    pub fn encode(self, chunk: &mut Chunk, span: Span) {
+        chunk.write_span(self.opcode() as u8, span);
+        self.operands()
+            .into_iter()
+            .for_each(|byte| chunk.write_span(byte, span));
+    }
+
+    /// Overwrites the instruction at `offset` of a running chunk with this
+    /// one, so that the VM can specialize an instruction after running it and
+    /// turn it back when it has to. A varint operand is padded to the length
+    /// of the one that it replaces, and the instruction is left as it is if
+    /// the operand does not fit in that, which is what this returns.
+    ///
+    /// # Panics
+    ///
+    /// If the operands of the two instructions are not of the same kinds.
+    pub fn quicken(self, chunk: &Chunk, offset: usize) -> bool {
+        let (old, next) = Instruction::decode(chunk, offset)
+            .unwrap_or_else(|| panic!("ICE: Quickening an invalid instruction at {}", offset));
+        assert_eq!(
+            old.opcode().operands(),
+            self.opcode().operands(),
+            "ICE: Quickening into an instruction with other operands"
+        );
+        let operands = self.padded_operands(next - offset - 1);
+        if offset + 1 + operands.len() != next {
+            return false;
+        }
+
+        chunk.quicken_code(offset, self.opcode() as u8);
+        operands
+            .into_iter()
+            .enumerate()
+            .for_each(|(i, byte)| chunk.quicken_code(offset + 1 + i, byte));
+        true
+    }
+
+    /// The instruction at `offset`, along with the offset of the one after
+    /// it. Returns `None` if there is no valid instruction at `offset`, e.g.
+    /// when its operands are cut off by the end of the code.
+    pub fn decode(chunk: &Chunk, offset: usize) -> Option<(Instruction, usize)> {
+        let byte = |i: usize| (i < chunk.code_len()).then(|| chunk.get_code(i));
+        let op = OpCode::try_from(byte(offset)?).ok()?;
+
+        let (instruction, len) = Instruction::read(op, |i| byte(offset + 1 + i))?;
+        Some((instruction, offset + 1 + len))
+    }
+
+    /// The same as [`Instruction::decode`], for code that is known to be
+    /// valid, which is all that the VM runs. The opcode is matched on once,
+    /// and its operands are read in place, a single byte at a time for the
+    /// varints that fit in one.
+    ///
+    /// # Panics
+    ///
+    /// If there is no valid instruction at `offset`.
+    #[inline]
+    pub fn fetch(chunk: &Chunk, offset: usize) -> (Instruction, usize) {
+        let byte = chunk.get_code(offset);
+        let Some(&op) = OpCode::ALL.get(byte as usize) else {
+            panic!("ICE: Invalid opcode {} at {}", byte, offset);
+        };
+
+        let mut operands = Code {
+            chunk,
+            offset: offset + 1,
+        };
+        let instruction = Instruction::read_operands(op, &mut operands)
+            .unwrap_or_else(|| panic!("ICE: Invalid instruction at {}", offset));
+        (instruction, operands.offset)
+    }
+}
+
+// where the operands of an instruction are read from, in order
+trait Operands {
+    fn byte(&mut self) -> Option<u8>;
+    fn varint(&mut self) -> Option<u32>;
+}
+
+// the operands that a function returns one byte at a time, which can be cut
+// off or malformed
+struct Bytes<F> {
+    byte: F,
+    // how many bytes have been read
+    len: usize,
+}
+
+impl<F: Fn(usize) -> Option<u8>> Operands for Bytes<F> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = (self.byte)(self.len)?;
+        self.len += 1;
+        Some(byte)
+    }
+
+    fn varint(&mut self) -> Option<u32> {
+        let (value, len) = read_varint(|i| (self.byte)(self.len + i))?;
+        self.len += len;
+        Some(value)
+    }
+}
+
+// the operands in the code of a chunk that is known to be valid, which are
+// read in place, a single byte at a time for the varints that fit in one
+struct Code<'a> {
+    chunk: &'a Chunk,
+    // where the next operand is
+    offset: usize,
+}
+
+impl Operands for Code<'_> {
+    #[inline(always)]
+    fn byte(&mut self) -> Option<u8> {
+        let byte = self.chunk.get_code(self.offset);
+        self.offset += 1;
+        Some(byte)
+    }
+
+    #[inline(always)]
+    fn varint(&mut self) -> Option<u32> {
+        let first = self.chunk.get_code(self.offset);
+        if first & 0x80 == 0 {
+            self.offset += 1;
+            return Some(first as u32);
+        }
+
+        let (chunk, offset) = (self.chunk, self.offset);
+        let byte = |i: usize| (offset + i < chunk.code_len()).then(|| chunk.get_code(offset + i));
+        let (value, len) =
+            read_varint(byte).unwrap_or_else(|| panic!("ICE: Invalid varint at {}", offset));
+        self.offset += len;
+        Some(value)
+    }
+}
+
+/// The most bytes that a varint can take, which is what a 32-bit value needs.
+pub const MAX_VARINT_LEN: usize = 5;
+
+/// How many bytes `value` takes as a varint.
+pub fn varint_len(value: u32) -> usize {
+    let bits = 32 - value.leading_zeros() as usize;
+    bits.div_ceil(7).max(1)
+}
+
+/// Appends `value` as a varint of at least `len` bytes. A shorter value is
+/// padded with continuation bytes that add nothing, so that an operand keeps
+/// its length when it is patched with a smaller value.
+pub fn write_varint(bytes: &mut Vec<u8>, value: u32, len: usize) {
+    let len = len.max(varint_len(value));
+    for i in 0..len {
+        let group = (value.checked_shr(7 * i as u32).unwrap_or(0) & 0x7f) as u8;
+        bytes.push(if i + 1 < len { group | 0x80 } else { group });
+    }
+}
+
+/// Reads a varint from the bytes that `byte` returns, along with how many of
+/// them it takes. Returns `None` if it is cut off, or does not fit in 32 bits.
+pub fn read_varint(byte: impl Fn(usize) -> Option<u8>) -> Option<(u32, usize)> {
+    let mut value = 0;
+    for i in 0..MAX_VARINT_LEN {
+        let group = byte(i)?;
+        // only the low 4 bits of the fifth byte are left for a 32-bit value
+        if i == 4 && group > 0x0f {
+            return None;
+        }
+        value |= ((group & 0x7f) as u32) << (7 * i);
+        if group & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Operand;
+
+    const SPAN: Span = Span {
+        line: 1,
+        column: 1,
+        start: 0,
+        end: 0,
+    };
+
+    #[test]
+    fn test_instruction_roundtrip() {
+        let instructions = [
+            Instruction::Constant(7),
+            Instruction::Constant(0x012345),
+            Instruction::GetLocalAddConstant(1, 2),
+            Instruction::Jump(0x0102),
+            Instruction::Loop(0x01020304),
+            Instruction::Return,
+        ];
+
+        let mut chunk = Chunk::new();
+        instructions
+            .iter()
+            .for_each(|instruction| instruction.encode(&mut chunk, SPAN));
+        assert_eq!(
+            (0..chunk.code_len())
+                .map(|i| chunk.get_code(i))
+                .collect::<Vec<_>>(),
+            [
+                OpCode::Constant as u8,
+                7,
+                OpCode::Constant as u8,
+                0xc5,
+                0xc6,
+                0x04,
+                OpCode::GetLocalAddConstant as u8,
+                1,
+                2,
+                OpCode::Jump as u8,
+                0x82,
+                0x02,
+                OpCode::Loop as u8,
+                0x84,
+                0x86,
+                0x88,
+                0x08,
+                OpCode::Return as u8,
+            ]
+        );
+
+        let mut offset = 0;
+        for expected in instructions {
+            let (instruction, next) = Instruction::decode(&chunk, offset).expect("valid");
+            assert_eq!(instruction, expected);
+            offset = next;
+        }
+        assert_eq!(Instruction::decode(&chunk, offset), None);
+    }
+
+    #[test]
+    fn test_fetch() {
+        // every opcode, with operands of each length and a padded varint
+        let mut chunk = Chunk::new();
+        let mut offsets = vec![];
+        for &op in OpCode::ALL {
+            for operands in [[200, 0x85, 0x80, 0x00], [3, 0xc5, 0xc6, 0x04]] {
+                offsets.push(chunk.code_len());
+                chunk.write_span(op as u8, SPAN);
+                let operands = match op.operands() {
+                    [] => &operands[..0],
+                    [Operand::Byte] => &operands[..1],
+                    [Operand::Varint] => &operands[1..],
+                    _ => &operands[..],
+                };
+                operands
+                    .iter()
+                    .for_each(|&byte| chunk.write_span(byte, SPAN));
+            }
+        }
+
+        // both take the operands that `opcodes!` declares
+        offsets.push(chunk.code_len());
+        for offset in offsets.windows(2) {
+            let decoded = Instruction::decode(&chunk, offset[0]).expect("valid");
+            assert_eq!(decoded.1, offset[1], "{:?}", decoded.0);
+            assert_eq!(Instruction::fetch(&chunk, offset[0]), decoded);
+        }
+    }
+
+    #[test]
+    fn test_quicken() {
+        let mut chunk = Chunk::new();
+        Instruction::GetGlobal(200).encode(&mut chunk, SPAN);
+        Instruction::SetGlobal(1).encode(&mut chunk, SPAN);
+
+        // the slot is padded to the two bytes of the name
+        assert!(Instruction::GetGlobalCached(3).quicken(&chunk, 0));
+        assert_eq!(
+            Instruction::decode(&chunk, 0),
+            Some((Instruction::GetGlobalCached(3), 3))
+        );
+        // and a slot that doesn't fit leaves the instruction as it is
+        assert!(!Instruction::SetGlobalCached(200).quicken(&chunk, 3));
+        assert_eq!(
+            Instruction::decode(&chunk, 3),
+            Some((Instruction::SetGlobal(1), 5))
+        );
+    }
+
+    #[test]
+    fn test_varint() {
+        for (value, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (16383, 2),
+            (16384, 3),
+            (u32::MAX, 5),
+        ] {
+            assert_eq!(varint_len(value), len);
+
+            let mut bytes = vec![];
+            write_varint(&mut bytes, value, 1);
+            assert_eq!(bytes.len(), len);
+            assert_eq!(read_varint(|i| bytes.get(i).copied()), Some((value, len)));
+        }
+
+        // padded to keep the length of a placeholder
+        let mut bytes = vec![];
+        write_varint(&mut bytes, 5, 3);
+        assert_eq!(bytes, [0x85, 0x80, 0x00]);
+        assert_eq!(read_varint(|i| bytes.get(i).copied()), Some((5, 3)));
+
+        // cut off, and too large for 32 bits
+        assert_eq!(read_varint(|i| [0x80].get(i).copied()), None);
+        assert_eq!(
+            read_varint(|i| [0xff, 0xff, 0xff, 0xff, 0x1f].get(i).copied()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_instruction_invalid() {
+        // an unknown opcode, and an operand that is cut off
+        let mut chunk = Chunk::new();
+        chunk.write(255, 1, 1);
+        chunk.write(OpCode::Jump as u8, 1, 1);
+        chunk.write(0x80, 1, 1);
+        assert_eq!(Instruction::decode(&chunk, 0), None);
+        assert_eq!(Instruction::decode(&chunk, 1), None);
+
+        assert_eq!(Instruction::from_operands(OpCode::Nil, &[1]), None);
+        assert_eq!(Instruction::from_operands(OpCode::Constant, &[1, 2]), None);
+        assert_eq!(
+            Instruction::from_operands(OpCode::Call, &[1]),
+            Some(Instruction::Call(1))
+        );
+    }
+}
@@ -0,0 +1,219 @@
+// Where the compiler and the VM write to. With the `std` feature, that is
+// anything that implements `std::io::Write`; without it, a host implements
+// this `Write` for its console, or its log, or whatever it prints to.
+
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::{cell::RefCell, fmt};
+
+/// A failed write, which is all that the VM needs to know about it.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Error;
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// The part of `std::io::Write` that the VM uses, which works the same with
+/// `write!` and `writeln!`.
+pub trait Write {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> Result<()> {
+        // writes the pieces of `args` as they are formatted, keeping the
+        // error of the first one that fails
+        struct Adapter<'a, W: ?Sized> {
+            inner: &'a mut W,
+            result: Result<()>,
+        }
+
+        impl<W: Write + ?Sized> fmt::Write for Adapter<'_, W> {
+            fn write_str(&mut self, s: &str) -> fmt::Result {
+                self.result = self.inner.write_all(s.as_bytes());
+                self.result.map_err(|_| fmt::Error)
+            }
+        }
+
+        let mut adapter = Adapter {
+            inner: self,
+            result: Ok(()),
+        };
+        match fmt::write(&mut adapter, args) {
+            Ok(()) => Ok(()),
+            Err(_) => adapter.result.and(Err(Error)),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> Write for W {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        std::io::Write::write_all(self, buf).map_err(|_| Error)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        std::io::Write::flush(self).map_err(|_| Error)
+    }
+
+    fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> Result<()> {
+        std::io::Write::write_fmt(self, args).map_err(|_| Error)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for alloc::vec::Vec<u8> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<W: Write + ?Sized> Write for &mut W {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        (**self).write_all(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<W: Write + ?Sized> Write for alloc::boxed::Box<W> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        (**self).write_all(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Throws away what is written to it, which is where the errors of the VM
+/// go without the standard library, until the host gives it somewhere.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sink;
+
+#[cfg(not(feature = "std"))]
+impl Write for Sink {
+    fn write_all(&mut self, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps what is written to it, for a host that shows it once the VM is
+/// done, like the debugger's view and the playground. A clone writes to the
+/// same place.
+#[derive(Debug, Clone, Default)]
+pub struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for SharedOutput {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.0.borrow_mut().write_all(buf)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::io::Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The standard output, where the VM prints by default and traces to,
+/// which is a [`Sink`] without the standard library.
+#[cfg(feature = "std")]
+pub type Stdout = std::io::Stdout;
+#[cfg(not(feature = "std"))]
+pub type Stdout = Sink;
+
+/// The standard error, where the VM reports by default, which is a
+/// [`Sink`] without the standard library.
+#[cfg(feature = "std")]
+pub type Stderr = std::io::Stderr;
+#[cfg(not(feature = "std"))]
+pub type Stderr = Sink;
+
+#[cfg(feature = "std")]
+pub fn stdout() -> Stdout {
+    std::io::stdout()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn stdout() -> Stdout {
+    Sink
+}
+
+#[cfg(feature = "std")]
+pub fn stderr() -> Stderr {
+    std::io::stderr()
+}
+
+#[cfg(not(feature = "std"))]
+pub fn stderr() -> Stderr {
+    Sink
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // takes `room` bytes, and fails to write any more
+    struct Full {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for Full {
+        fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+            if self.written.len() + buf.len() > self.room {
+                return Err(Error);
+            }
+            self.written.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_fmt() {
+        let mut full = Full {
+            written: vec![],
+            room: 8,
+        };
+        assert_eq!(write!(full, "{} + {}", 1, 2), Ok(()));
+        assert_eq!(full.written, b"1 + 2");
+        assert_eq!(writeln!(full, " = {}", 3), Err(Error));
+
+        let mut sink = Sink;
+        assert_eq!(Write::write_fmt(&mut sink, format_args!("{}", 1)), Ok(()));
+
+        let shared = SharedOutput::default();
+        let mut clone = shared.clone();
+        assert_eq!(write!(clone, "{} + {}", 1, 2), Ok(()));
+        assert_eq!(shared.text(), "1 + 2");
+    }
+}
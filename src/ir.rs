@@ -0,0 +1,413 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::mem;
+
+use crate::{
+    chunk::{Chunk, OpCode, Span},
+    relocate::{self, Instruction},
+};
+
+pub type BlockId = usize;
+
+/// How control leaves a basic block.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Terminator {
+    // runs into the block right after it, without a jump
+    Fallthrough(BlockId),
+    // OP_JUMP or OP_LOOP, depending on which way `target` is
+    Jump {
+        target: BlockId,
+        span: Span,
+    },
+    // OP_JUMP_IF_FALSE, which leaves the condition on the stack either way
+    JumpIfFalse {
+        target: BlockId,
+        otherwise: BlockId,
+        span: Span,
+    },
+    Return(Span),
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match *self {
+            Terminator::Fallthrough(next) => vec![next],
+            Terminator::Jump { target, .. } => vec![target],
+            Terminator::JumpIfFalse {
+                target, otherwise, ..
+            } => vec![otherwise, target],
+            Terminator::Return { .. } => vec![],
+        }
+    }
+}
+
+/// A run of instructions that is only ever entered at its start, and only
+/// left at its end.
+#[derive(Debug)]
+pub struct Block {
+    // never jumps or OP_RETURN, those are the terminator
+    pub instructions: Vec<Instruction>,
+    pub terminator: Terminator,
+    // removed blocks keep their id, so that the other ids stay the same
+    pub removed: bool,
+}
+
+/// The control flow graph of the bytecode of one function. The blocks are in
+/// the order that they are laid out in when turned back into bytecode.
+#[derive(Debug)]
+pub struct Function {
+    pub blocks: Vec<Block>,
+}
+
+impl Function {
+    /// Splits the code of `chunk` into basic blocks, with block 0 as the
+    /// entry. Returns `None` if the chunk is malformed, or if its code can
+    /// run off the end.
+    pub fn from_chunk(chunk: &Chunk) -> Option<Self> {
+        let instructions = relocate::decode(chunk, &[])?;
+
+        // the instructions that start a block
+        let mut leaders = vec![false; instructions.len() + 1];
+        leaders[0] = true;
+        for (i, instruction) in instructions.iter().enumerate() {
+            if let Some(target) = instruction.target {
+                leaders[target] = true;
+            }
+            if instruction.target.is_some() || instruction.op == OpCode::Return {
+                leaders[i + 1] = true;
+            }
+        }
+        // the block that each instruction is in
+        let block_ids = leaders[..instructions.len()]
+            .iter()
+            .scan(0, |count, &leader| {
+                *count += leader as usize;
+                Some(*count - 1)
+            })
+            .collect::<Vec<_>>();
+
+        let mut blocks = vec![];
+        let mut current = vec![];
+        let mut instructions = instructions.into_iter().enumerate().peekable();
+        while let Some((i, instruction)) = instructions.next() {
+            let terminator = match (instruction.op, instruction.target) {
+                (OpCode::Jump | OpCode::Loop, Some(target)) => Some(Terminator::Jump {
+                    target: block_ids[target],
+                    span: instruction.span,
+                }),
+                (OpCode::JumpIfFalse, Some(target)) => Some(Terminator::JumpIfFalse {
+                    target: block_ids[target],
+                    // the code must not end right after it
+                    otherwise: *block_ids.get(i + 1)?,
+                    span: instruction.span,
+                }),
+                (OpCode::Return, _) => Some(Terminator::Return(instruction.span)),
+                _ => {
+                    current.push(instruction);
+                    None
+                }
+            };
+
+            let terminator = match terminator {
+                Some(terminator) => terminator,
+                None if instructions.peek().is_some() && leaders[i + 1] => {
+                    Terminator::Fallthrough(block_ids[i + 1])
+                }
+                None if instructions.peek().is_some() => continue,
+                // the code runs off the end of the chunk
+                None => return None,
+            };
+            blocks.push(Block {
+                instructions: mem::take(&mut current),
+                terminator,
+                removed: false,
+            });
+        }
+
+        Some(Self { blocks })
+    }
+
+    /// Writes the blocks back as the code of `chunk`, keeping its constants.
+    ///
+    /// Returns `false` and leaves the chunk as it is if that is not possible,
+    /// e.g. when a conditional jump would have to go backwards.
+    pub fn lower(&self, chunk: &mut Chunk) -> bool {
+        // where every block starts in the lowered instructions
+        let mut starts = vec![0; self.blocks.len()];
+        let mut len = 0;
+        for (id, block) in self.blocks.iter().enumerate() {
+            starts[id] = len;
+            if block.removed {
+                continue;
+            }
+            len += block
+                .instructions
+                .iter()
+                .filter(|instruction| !instruction.removed)
+                .count();
+            if !matches!(block.terminator, Terminator::Fallthrough(_)) {
+                len += 1;
+            }
+        }
+
+        let mut instructions = Vec::with_capacity(len);
+        for (id, block) in self.blocks.iter().enumerate() {
+            if block.removed {
+                continue;
+            }
+
+            let kept = block
+                .instructions
+                .iter()
+                .filter(|instruction| !instruction.removed);
+            instructions.extend(kept.map(|instruction| Instruction {
+                op: instruction.op,
+                operands: instruction.operands.clone(),
+                span: instruction.span,
+                operand_spans: instruction.operand_spans.clone(),
+                target: None,
+                removed: false,
+            }));
+
+            // the block after it in the layout must be the one that it runs
+            // into, as there is no jump to get there
+            let next = (id + 1..self.blocks.len()).find(|&next| !self.blocks[next].removed);
+            let (op, target, span) = match block.terminator {
+                Terminator::Fallthrough(fallthrough) => {
+                    if next != Some(fallthrough) {
+                        return false;
+                    }
+                    continue;
+                }
+                Terminator::Jump { target, span } => (OpCode::Jump, Some(starts[target]), span),
+                Terminator::JumpIfFalse {
+                    target,
+                    otherwise,
+                    span,
+                } => {
+                    if next != Some(otherwise) {
+                        return false;
+                    }
+                    (OpCode::JumpIfFalse, Some(starts[target]), span)
+                }
+                Terminator::Return(span) => (OpCode::Return, None, span),
+            };
+            instructions.push(Instruction {
+                op,
+                operands: vec![],
+                span,
+                operand_spans: vec![],
+                target,
+                removed: false,
+            });
+        }
+
+        relocate::encode(chunk, &instructions)
+    }
+
+    /// The blocks that can be reached from the entry block.
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut worklist = vec![0];
+        while let Some(id) = worklist.pop() {
+            if reachable[id] || self.blocks[id].removed {
+                continue;
+            }
+            reachable[id] = true;
+            worklist.extend(self.blocks[id].terminator.successors());
+        }
+        reachable
+    }
+
+    /// The blocks that control can come to each block from.
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut predecessors = vec![vec![]; self.blocks.len()];
+        for (id, block) in self.blocks.iter().enumerate() {
+            if block.removed {
+                continue;
+            }
+            for successor in block.terminator.successors() {
+                predecessors[successor].push(id);
+            }
+        }
+        predecessors
+    }
+}
+
+/// A transformation of a [`Function`], which can use the analyses in
+/// [`crate::passes`].
+pub trait Pass {
+    /// Returns whether anything was changed.
+    fn run(&mut self, function: &mut Function) -> bool;
+}
+
+/// Runs a list of passes over functions.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // named like the builders of the passes that it is used with, not `Add`
+    #[allow(clippy::should_implement_trait)]
+    pub fn add<P: Pass + 'static>(mut self, pass: P) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Runs every pass in order, and then all of them again for as long as
+    /// any of them changes something, since one pass can make room for
+    /// another. Returns whether anything was changed.
+    pub fn run(&mut self, function: &mut Function) -> bool {
+        let mut changed = false;
+        loop {
+            let mut round = false;
+            for pass in self.passes.iter_mut() {
+                round |= pass.run(function);
+            }
+            if !round {
+                return changed;
+            }
+            changed = true;
+        }
+    }
+
+    /// Runs the passes over the code of `chunk`, which is left as it is if
+    /// it cannot be turned into a [`Function`] and back.
+    pub fn run_on_chunk(&mut self, chunk: &mut Chunk) {
+        let Some(mut function) = Function::from_chunk(chunk) else {
+            return;
+        };
+
+        if self.run(&mut function) {
+            function.lower(chunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONSTANT: u8 = OpCode::Constant as u8;
+    const JUMP: u8 = OpCode::Jump as u8;
+    const JUMP_IF_FALSE: u8 = OpCode::JumpIfFalse as u8;
+    const LOOP: u8 = OpCode::Loop as u8;
+    const NIL: u8 = OpCode::Nil as u8;
+    const POP: u8 = OpCode::Pop as u8;
+    const PRINT: u8 = OpCode::Print as u8;
+    const RETURN: u8 = OpCode::Return as u8;
+
+    // where `chunk` puts every instruction
+    const SPAN: Span = Span {
+        line: 1,
+        column: 1,
+        start: 0,
+        end: 0,
+    };
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        code.iter().for_each(|byte| chunk.write(*byte, 1, 1));
+        chunk
+    }
+
+    // `while (nil) print nil;`
+    const WHILE: [u8; 11] = [
+        NIL,
+        JUMP_IF_FALSE,
+        5,
+        POP,
+        NIL,
+        PRINT,
+        LOOP,
+        8,
+        POP,
+        NIL,
+        RETURN,
+    ];
+
+    #[test]
+    fn test_ir_from_chunk() {
+        let function = Function::from_chunk(&chunk(&WHILE)).expect("well formed");
+
+        let terminators = function
+            .blocks
+            .iter()
+            .map(|block| block.terminator)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            terminators,
+            vec![
+                Terminator::JumpIfFalse {
+                    target: 2,
+                    otherwise: 1,
+                    span: SPAN
+                },
+                Terminator::Jump {
+                    target: 0,
+                    span: SPAN
+                },
+                Terminator::Return(SPAN),
+            ]
+        );
+        let lengths = function
+            .blocks
+            .iter()
+            .map(|block| block.instructions.len())
+            .collect::<Vec<_>>();
+        assert_eq!(lengths, vec![1, 3, 2]);
+
+        assert_eq!(function.predecessors(), vec![vec![1], vec![0], vec![0]]);
+        assert_eq!(function.reachable(), vec![true; 3]);
+
+        // a block that is only run into from the one before it
+        let code = [CONSTANT, 0, JUMP_IF_FALSE, 1, POP, POP, RETURN];
+        let function = Function::from_chunk(&chunk(&code)).expect("well formed");
+        assert_eq!(function.blocks[1].terminator, Terminator::Fallthrough(2));
+
+        // running off the end of the code
+        assert!(Function::from_chunk(&chunk(&[NIL, POP])).is_none());
+    }
+
+    #[test]
+    fn test_ir_lower() {
+        let function = Function::from_chunk(&chunk(&WHILE)).expect("well formed");
+        let mut lowered = chunk(&WHILE);
+        assert!(function.lower(&mut lowered));
+        assert_eq!(lowered, chunk(&WHILE));
+
+        // the jumps still land on the same blocks once one is left out
+        let code = [JUMP, 2, NIL, RETURN, NIL, PRINT, NIL, RETURN];
+        let mut function = Function::from_chunk(&chunk(&code)).expect("well formed");
+        assert_eq!(function.reachable(), vec![true, false, true]);
+        function.blocks[1].removed = true;
+        let mut lowered = chunk(&code);
+        assert!(function.lower(&mut lowered));
+        assert_eq!(lowered, chunk(&[JUMP, 0, NIL, PRINT, NIL, RETURN]));
+    }
+
+    #[test]
+    fn test_ir_pass_manager() {
+        // removes one instruction per run, so it has to be run until it is
+        // done
+        struct PopFirst;
+        impl Pass for PopFirst {
+            fn run(&mut self, function: &mut Function) -> bool {
+                let block = &mut function.blocks[0];
+                if block.instructions.is_empty() {
+                    return false;
+                }
+                block.instructions.remove(0);
+                true
+            }
+        }
+
+        let mut chunk = chunk(&[NIL, POP, NIL, POP, NIL, RETURN]);
+        PassManager::new().add(PopFirst).run_on_chunk(&mut chunk);
+        assert_eq!(chunk, self::chunk(&[RETURN]));
+    }
+}
@@ -0,0 +1,65 @@
+// Without the `std` feature, only the core of the interpreter is built: the
+// scanner, the parser, the compiler, the chunks and the VM, which only need
+// `alloc`, and write to an `io::Write` of their own. The tools around them
+// (the REPL, the debugger, the formatter, ...) need the standard library.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod ast;
+pub mod builder;
+pub mod bytecode;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod chunk;
+pub mod color;
+#[cfg(feature = "std")]
+pub mod compare;
+pub mod compiler;
+#[cfg(feature = "std")]
+pub mod conformance;
+pub mod debug;
+#[cfg(feature = "std")]
+pub mod debugger;
+pub mod decimal;
+pub mod diagnostic;
+#[cfg(feature = "std")]
+pub mod explain;
+pub mod fold;
+#[cfg(feature = "std")]
+pub mod formatter;
+pub mod fuzz;
+pub mod instruction;
+pub mod io;
+pub mod ir;
+#[cfg(feature = "std")]
+pub mod lint;
+pub mod object;
+pub mod parser;
+pub mod passes;
+pub mod peephole;
+#[cfg(feature = "serde")]
+pub mod persist;
+#[cfg(feature = "std")]
+pub mod playground;
+pub mod register;
+pub mod relocate;
+#[cfg(feature = "std")]
+pub mod repl;
+#[cfg(feature = "std")]
+pub mod runtime;
+pub mod scanner;
+#[cfg(feature = "std")]
+pub mod semantic;
+// private, since pushing and popping skip their checks with `fast-stack`
+pub(crate) mod stack;
+pub mod table;
+pub mod tier;
+#[cfg(feature = "std")]
+pub mod transpile;
+#[cfg(feature = "std")]
+pub mod tui;
+pub mod value;
+pub mod vm;
+#[cfg(feature = "std")]
+pub mod wasm;
@@ -0,0 +1,512 @@
+// The linter of `clox lint`, which looks at the syntax tree for code that is
+// valid, but probably not what was meant. Each rule can be turned off, or
+// made an error, in a clox-lint.toml next to the scripts or in a directory
+// above them:
+//
+//     # each rule is "off", "warn" or "error"
+//     shadowing = "off"
+//     assignment-in-condition = "error"
+//
+// Globals are looked up when the code runs, and can be used by code that
+// isn't in the script, so only the variables in blocks and functions can be
+// unused.
+
+use crate::{
+    ast::{Expr, Function, Literal, Program, Stmt},
+    diagnostic::{Diagnostic, Severity},
+    parser::Parser,
+    repl::{self, Setting},
+    scanner::{Token, TokenKind},
+};
+
+/// What the linter looks for.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rule {
+    /// A local variable that is never read.
+    UnusedVariable,
+    /// A variable with the name of one in a scope around it.
+    Shadowing,
+    /// A block without statements.
+    EmptyBlock,
+    /// `== nil` or `!= nil` in a condition, which could test truthiness.
+    NilComparison,
+    /// `=` in a condition, where `==` was probably meant.
+    AssignmentInCondition,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 5] = [
+        Rule::UnusedVariable,
+        Rule::Shadowing,
+        Rule::EmptyBlock,
+        Rule::NilComparison,
+        Rule::AssignmentInCondition,
+    ];
+
+    /// The name of the rule in the config file and the diagnostics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::UnusedVariable => "unused-variable",
+            Rule::Shadowing => "shadowing",
+            Rule::EmptyBlock => "empty-block",
+            Rule::NilComparison => "nil-comparison",
+            Rule::AssignmentInCondition => "assignment-in-condition",
+        }
+    }
+}
+
+/// What a rule reports its findings as.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Level {
+    Off,
+    Warn,
+    Error,
+}
+
+/// The level of each rule, where they all warn by default.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Config {
+    levels: [Level; Rule::ALL.len()],
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            levels: [Level::Warn; Rule::ALL.len()],
+        }
+    }
+}
+
+impl Config {
+    /// The name of the file that the config is read from.
+    pub const FILE_NAME: &str = "clox-lint.toml";
+
+    /// The levels in `text`, with the defaults for the rules it leaves out,
+    /// or the first line that is wrong and why.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        for (line, text) in (1..).zip(text.lines()) {
+            let error = |message: String| format!("line {}: {}", line, message);
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = text.split_once('=') else {
+                return Err(error("Expect '=' after the rule.".to_string()));
+            };
+            let key = key.trim();
+            let Some(rule) = Rule::ALL.into_iter().find(|rule| rule.name() == key) else {
+                return Err(error(format!("Unknown rule '{}'.", key)));
+            };
+            let level = match repl::parse_setting(value).map_err(error)? {
+                Setting::String(level) if level == "off" => Level::Off,
+                Setting::String(level) if level == "warn" => Level::Warn,
+                Setting::String(level) if level == "error" => Level::Error,
+                _ => {
+                    return Err(error(format!(
+                        "'{}' must be \"off\", \"warn\" or \"error\".",
+                        key
+                    )));
+                }
+            };
+            config.set_level(rule, level);
+        }
+        Ok(config)
+    }
+
+    pub fn level(&self, rule: Rule) -> Level {
+        self.levels[rule as usize]
+    }
+
+    pub fn set_level(&mut self, rule: Rule, level: Level) {
+        self.levels[rule as usize] = level;
+    }
+}
+
+/// The findings of the rules that `config` turns on in `source`, in the
+/// order of the source, or the diagnostics of its syntax errors.
+pub fn lint(source: &str, config: &Config) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+    let mut parser = Parser::new(source);
+    let program = parser.parse();
+    if parser.had_error() {
+        return Err(parser.take_diagnostics());
+    }
+
+    let mut linter = Linter {
+        config,
+        scopes: vec![vec![]],
+        diagnostics: vec![],
+    };
+    linter.program(&program);
+    let mut diagnostics = linter.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.span.line, diagnostic.span.column));
+    Ok(diagnostics)
+}
+
+// a variable in one of the scopes, and whether it has been read
+struct Variable<'src> {
+    name: Token<'src>,
+    used: bool,
+}
+
+struct Linter<'a, 'src> {
+    config: &'a Config,
+    // the globals, then the scope of each block and function around the
+    // code, innermost last
+    scopes: Vec<Vec<Variable<'src>>>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'src> Linter<'_, 'src> {
+    fn report(&mut self, rule: Rule, token: &Token, message: String, help: &str) {
+        let severity = match self.config.level(rule) {
+            Level::Off => return,
+            Level::Warn => Severity::Warning,
+            Level::Error => Severity::Error,
+        };
+        let message = format!("{} [{}]", message, rule.name());
+        self.diagnostics
+            .push(Diagnostic::at_token(severity, token, message, Some(help)));
+    }
+
+    fn program(&mut self, program: &Program<'src>) {
+        program
+            .statements
+            .iter()
+            .for_each(|statement| self.statement(statement));
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(vec![]);
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().expect("ICE: No scope to end");
+        for variable in scope {
+            if !variable.used && !variable.name.lexeme.starts_with('_') {
+                self.report(
+                    Rule::UnusedVariable,
+                    &variable.name,
+                    format!("Unused variable '{}'.", variable.name.lexeme),
+                    "prefix the name with an underscore if this is intentional",
+                );
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &Token<'src>, used: bool) {
+        let (scope, enclosing) = self.scopes.split_last().expect("ICE: No scope");
+        // redeclaring a global is allowed, and one in the same block is an
+        // error of the compiler
+        let shadows = !enclosing.is_empty()
+            && !scope
+                .iter()
+                .any(|variable| variable.name.lexeme == name.lexeme)
+            && enclosing
+                .iter()
+                .flatten()
+                .any(|variable| variable.name.lexeme == name.lexeme);
+        if shadows {
+            self.report(
+                Rule::Shadowing,
+                name,
+                format!("'{}' shadows a variable around it.", name.lexeme),
+                "rename one of them to tell them apart",
+            );
+        }
+        self.scopes
+            .last_mut()
+            .expect("ICE: No scope")
+            .push(Variable {
+                name: name.clone(),
+                used,
+            });
+    }
+
+    // marks the innermost variable called `name` as read
+    fn read(&mut self, name: &Token) {
+        if let Some(variable) = self
+            .scopes
+            .iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|variable| variable.name.lexeme == name.lexeme)
+        {
+            variable.used = true;
+        }
+    }
+
+    fn statement(&mut self, statement: &Stmt<'src>) {
+        match statement {
+            Stmt::Expression { expr, .. } | Stmt::Print { expr, .. } => self.expression(expr),
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.declare(name, false);
+            }
+            Stmt::Function(function) => self.function(function),
+            Stmt::Block {
+                open, statements, ..
+            } => {
+                if statements.is_empty() {
+                    self.report(
+                        Rule::EmptyBlock,
+                        open,
+                        "Empty block.".to_string(),
+                        "remove it if nothing is meant to happen there",
+                    );
+                }
+                self.begin_scope();
+                statements
+                    .iter()
+                    .for_each(|statement| self.statement(statement));
+                self.end_scope();
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.condition(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::While {
+                condition, body, ..
+            } => {
+                self.condition(condition);
+                self.statement(body);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                // the variable of the loop is in a scope of its own
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.condition(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.statement(body);
+                self.end_scope();
+            }
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function<'src>) {
+        // a local function is unused unless it is called, or passed on
+        self.declare(&function.name, false);
+        self.begin_scope();
+        // callers have to pass every parameter, so they are never unused
+        for param in &function.params {
+            self.declare(param, true);
+        }
+        function
+            .body
+            .iter()
+            .for_each(|statement| self.statement(statement));
+        self.end_scope();
+    }
+
+    // the condition of an `if` or a loop, where the rules about conditions
+    // look into the operands of `and`, `or` and `!` too
+    fn condition(&mut self, condition: &Expr<'src>) {
+        self.check_condition(condition);
+        self.expression(condition);
+    }
+
+    fn check_condition(&mut self, condition: &Expr<'src>) {
+        match condition {
+            Expr::Assign { equal, .. } => self.report(
+                Rule::AssignmentInCondition,
+                equal,
+                "Assignment in a condition.".to_string(),
+                "compare with '==', or put the assignment in parentheses if it is meant",
+            ),
+            Expr::Binary {
+                operator,
+                left,
+                right,
+            } if matches!(operator.kind, TokenKind::EqualEqual | TokenKind::BangEqual)
+                && [left, right].iter().any(|operand| is_nil(operand)) =>
+            {
+                let test = if operator.kind == TokenKind::EqualEqual {
+                    "'!x'"
+                } else {
+                    "'x'"
+                };
+                self.report(
+                    Rule::NilComparison,
+                    operator,
+                    "Comparison with nil in a condition.".to_string(),
+                    &format!(
+                        "test the truthiness with {} instead, unless false has to be told apart from nil",
+                        test
+                    ),
+                );
+            }
+            Expr::Logical { left, right, .. } => {
+                self.check_condition(left);
+                self.check_condition(right);
+            }
+            Expr::Unary { operator, operand } if operator.kind == TokenKind::Bang => {
+                self.check_condition(operand);
+            }
+            // the parentheses say that an assignment is meant
+            Expr::Grouping { expr, .. } if !matches!(**expr, Expr::Assign { .. }) => {
+                self.check_condition(expr);
+            }
+            _ => {}
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr<'src>) {
+        match expr {
+            Expr::Literal { .. } | Expr::Number { .. } | Expr::String { .. } => {}
+            Expr::Variable { name } => self.read(name),
+            Expr::Assign { value, .. } => self.expression(value),
+            Expr::Unary { operand, .. } => self.expression(operand),
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                self.expression(callee);
+                arguments
+                    .iter()
+                    .for_each(|argument| self.expression(argument));
+            }
+        }
+    }
+}
+
+fn is_nil(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::Literal {
+            value: Literal::Nil,
+            ..
+        }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the line, the severity and the message of each finding
+    fn findings(source: &str, config: &Config) -> Vec<(u32, Severity, String)> {
+        lint(source, config)
+            .expect("no syntax errors")
+            .into_iter()
+            .map(|diagnostic| {
+                (
+                    diagnostic.span.line,
+                    diagnostic.severity,
+                    diagnostic.message,
+                )
+            })
+            .collect()
+    }
+
+    fn warning(line: u32, message: &str) -> (u32, Severity, String) {
+        (line, Severity::Warning, message.to_string())
+    }
+
+    #[test]
+    fn test_lint() {
+        let source = "var a = 1;\n\
+                      {\n\
+                        var a = 2;\n\
+                        var b = 3;\n\
+                        var _c;\n\
+                        print a;\n\
+                      }\n\
+                      fun f(a) { var d; d = 1; return f(a); }\n\
+                      if (a = 2) {}\n\
+                      while ((a = nil)) print a;\n\
+                      for (var i = 0; i != nil and !(a == nil); i = i + 1) {}\n";
+        assert_eq!(
+            findings(source, &Config::default()),
+            [
+                warning(3, "'a' shadows a variable around it. [shadowing]"),
+                warning(4, "Unused variable 'b'. [unused-variable]"),
+                warning(8, "'a' shadows a variable around it. [shadowing]"),
+                warning(8, "Unused variable 'd'. [unused-variable]"),
+                warning(9, "Assignment in a condition. [assignment-in-condition]"),
+                warning(9, "Empty block. [empty-block]"),
+                warning(11, "Comparison with nil in a condition. [nil-comparison]"),
+                warning(11, "Comparison with nil in a condition. [nil-comparison]"),
+                warning(11, "Empty block. [empty-block]"),
+            ]
+        );
+
+        // globals can be used elsewhere, and redeclared
+        assert_eq!(findings("var a; var a; fun f() {}", &Config::default()), []);
+        // the variables of a block are only its own
+        assert_eq!(
+            findings(
+                "{ var a; print a; } { var a; print a; }",
+                &Config::default()
+            ),
+            []
+        );
+    }
+
+    #[test]
+    fn test_lint_config() {
+        let config = Config::parse(
+            "# mine\n\
+             shadowing = \"off\"\n\
+             empty-block = \"error\" # for now\n",
+        )
+        .expect("valid");
+        assert_eq!(config.level(Rule::Shadowing), Level::Off);
+        assert_eq!(config.level(Rule::EmptyBlock), Level::Error);
+        assert_eq!(config.level(Rule::UnusedVariable), Level::Warn);
+        assert_eq!(
+            findings("var a; { var a = 1; print a; {} }", &config),
+            [(1, Severity::Error, "Empty block. [empty-block]".to_string())]
+        );
+
+        for (text, error) in [
+            ("shadowing", "line 1: Expect '=' after the rule."),
+            ("\nunused = \"off\"", "line 2: Unknown rule 'unused'."),
+            (
+                "shadowing = true",
+                "line 1: 'shadowing' must be \"off\", \"warn\" or \"error\".",
+            ),
+            ("shadowing = \"off", "line 1: Unterminated string."),
+        ] {
+            assert_eq!(Config::parse(text), Err(error.to_string()), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_lint_errors() {
+        let errors = lint("print (1;", &Config::default()).expect_err("a syntax error");
+        assert_eq!(errors[0].message, "Expect ')' after expression.");
+    }
+}
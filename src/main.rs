@@ -1,58 +1,1361 @@
-mod chunk;
-mod compiler;
-mod debug;
-mod scanner;
-mod value;
-mod vm;
-
 use std::{
-    env, fs,
-    io::{self, Write},
-    process,
+    env,
+    ffi::c_int,
+    fs,
+    io::{self, IsTerminal, Write},
+    path, process,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
 };
 
-use crate::vm::{InterpretError, VM};
+use clox::{
+    bytecode, compare,
+    compiler::{CompileOptions, Compiler, OptLevel},
+    conformance,
+    debug::{self, DisassembleOptions, Trace},
+    debugger::{DebugRepl, SourceDebugger, StepDebugger},
+    diagnostic::{self, Diagnostic, Severity},
+    explain::Explain,
+    formatter, lint,
+    object::Heap,
+    parser::Parser,
+    register::{self, Backend},
+    repl::{self, LineEditor},
+    scanner::{Scanner, TokenKind},
+    semantic, transpile,
+    tui::Tui,
+    vm::{InterpretError, VM},
+    wasm,
+};
 
 fn main() {
-    let args = env::args().collect::<Vec<_>>();
+    let cli = match parse_args(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(message) => {
+            eprintln!("{}", message);
+            usage();
+        }
+    };
+    let Cli {
+        options,
+        vm_options,
+        disassemble_options,
+        json,
+        semantic,
+        debug_view,
+        target,
+        color,
+        compare_with,
+        command,
+    } = cli;
 
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        run_file(args[1].clone());
-    } else {
-        eprintln!("Usage: clox [path]");
-        process::exit(64);
+    match command {
+        Command::Help => print!("{}", help()),
+        Command::Version => println!("clox {}", env!("CARGO_PKG_VERSION")),
+        Command::Repl => repl(options, vm_options, color),
+        // a script that is piped in runs like a file, rather than as lines of
+        // the REPL
+        Command::Default if !io::stdin().is_terminal() => run_file(STDIN_PATH, options, vm_options),
+        Command::Default => repl(options, vm_options, color),
+        Command::Run(path) if compare_with.is_some() => {
+            let command = compare_with.expect("the reference command");
+            compare_file(&path, &command, options, vm_options)
+        }
+        Command::Run(path) if is_bytecode_file(&path) => run_bytecode_file(&path, vm_options),
+        Command::Run(path) => run_file(&path, options, vm_options),
+        Command::Watch(path) => watch_file(&path, options, vm_options, color),
+        Command::Eval(source) => eval_source(&source, options, vm_options),
+        Command::Check(path) => check_file(&path, options),
+        Command::Test(dir) => test_dir(&dir, options),
+        Command::Fmt(path, check) => format_path(&path, check),
+        Command::Lint(path) => lint_path(&path),
+        Command::Explain(path) => explain_file(&path, options, vm_options),
+        Command::Compile(path, output) => match target {
+            Target::Bytecode => compile_file(&path, &output, options),
+            Target::Wasm => compile_wasm_file(&path, &output, options),
+        },
+        Command::Transpile(path) => transpile_file(&path, options),
+        Command::Tokens(path) if semantic => print_semantic_tokens(&path, json),
+        Command::Tokens(path) => print_tokens(&path, json),
+        Command::Debug(path) => debug_file(&path, options, vm_options, debug_view),
+        Command::Disasm(path) => disassemble_file(
+            &path,
+            options,
+            disassemble_options,
+            vm_options.backend,
+            json,
+        ),
+    }
+}
+
+// what the command line asks for, once it is parsed
+struct Cli {
+    options: CompileOptions,
+    vm_options: VmOptions,
+    disassemble_options: DisassembleOptions,
+    json: bool,
+    // classify the tokens for an editor to highlight
+    semantic: bool,
+    debug_view: DebugView,
+    target: Target,
+    // color the REPL and watch on a terminal, unless NO_COLOR is set
+    color: bool,
+    // the reference implementation to compare `clox run` with
+    compare_with: Option<String>,
+    command: Command,
+}
+
+// what clox does with the scripts, which is picked by a subcommand, or by a
+// flag for the older ones
+#[derive(Debug)]
+enum Command {
+    Help,
+    Version,
+    // the REPL, or the script on stdin when it is piped in
+    Default,
+    Repl,
+    // a script, or the bytecode that `clox compile` wrote
+    Run(String),
+    // run the script again each time that it changes
+    Watch(String),
+    // the source given with -e
+    Eval(String),
+    // only compile the script, without running it
+    Check(String),
+    // run the test files of the book in a directory
+    Test(String),
+    // format a script, or the scripts in a directory, or only say which of
+    // them would change with --check
+    Fmt(String, bool),
+    // report what the linter finds in a script, or the scripts in a
+    // directory
+    Lint(String),
+    // run the script with a table of what each instruction does
+    Explain(String),
+    Compile(String, String),
+    Transpile(String),
+    Tokens(String),
+    Debug(String),
+    Disasm(String),
+}
+
+// the subcommands, and the paths that each of them takes
+const COMMANDS: [(&str, usize); 12] = [
+    ("repl", 0),
+    ("run", 1),
+    ("watch", 1),
+    ("check", 1),
+    ("test", 1),
+    ("fmt", 1),
+    ("lint", 1),
+    ("compile", 2),
+    ("transpile", 1),
+    ("tokens", 1),
+    ("debug", 1),
+    ("disasm", 1),
+];
+
+// the subcommand that `word` names, unless there is a script by that name,
+// which `clox <word>` keeps running like any other
+fn command(word: &str) -> Option<&'static (&'static str, usize)> {
+    COMMANDS
+        .iter()
+        .find(|(name, _)| *name == word)
+        .filter(|_| !path::Path::new(word).is_file())
+}
+
+// parses the arguments after the name of the program, or returns what is
+// wrong with them
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+    let mut cli = Cli {
+        options: CompileOptions::default(),
+        vm_options: VmOptions {
+            backend: Backend::default(),
+            tiering: true,
+            trace: TraceTo::Default,
+            stats: false,
+            time: false,
+            profile: false,
+            flamegraph: None,
+            dump_heap: false,
+            coverage: false,
+            lcov: None,
+            script_args: vec![],
+        },
+        disassemble_options: DisassembleOptions::default(),
+        json: false,
+        semantic: false,
+        debug_view: DebugView::Source,
+        target: Target::Bytecode,
+        color: io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        compare_with: None,
+        command: Command::Default,
+    };
+    let mut check = false;
+    let mut explain = false;
+    let mut eval = None;
+    let mut positional: Vec<String> = vec![];
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| format!("Expected a value after '{}'.", flag))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => cli.command = Command::Help,
+            "-V" | "--version" => cli.command = Command::Version,
+            "--deny-warnings" => cli.options.deny_warnings = true,
+            "--book-comparisons" => cli.options.book_comparisons = true,
+            "--decimal" => cli.options.decimal = true,
+            "--print-code" => cli.options.print_code = true,
+            "-O0" => cli.options = cli.options.opt_level(OptLevel::O0),
+            "-O1" => cli.options = cli.options.opt_level(OptLevel::O1),
+            // -O is short for the highest level
+            "-O" | "-O2" => cli.options = cli.options.opt_level(OptLevel::O2),
+            "--check" => check = true,
+            "--explain" => explain = true,
+            "-e" => eval = Some(value("-e")?),
+            "--constants" => cli.disassemble_options.constants = true,
+            "--lines" => cli.disassemble_options.lines = true,
+            "--json" => cli.json = true,
+            "--semantic" => cli.semantic = true,
+            "--no-color" => cli.color = false,
+            "--instructions" => cli.debug_view = DebugView::Instructions,
+            "--tui" => cli.debug_view = DebugView::Tui,
+            "--registers" => cli.vm_options.backend = Backend::Register,
+            "--no-tiering" => cli.vm_options.tiering = false,
+            "--trace-execution" => cli.vm_options.trace = TraceTo::Stdout,
+            "--trace-file" => cli.vm_options.trace = TraceTo::File(value("--trace-file")?),
+            "--trace-last" => {
+                let len = value("--trace-last")?;
+                let len = len.parse().map_err(|_| {
+                    format!("Expected a number after '--trace-last', not '{}'.", len)
+                })?;
+                cli.vm_options.trace = TraceTo::Last(len);
+            }
+            "--stats" => cli.vm_options.stats = true,
+            "--time" => cli.vm_options.time = true,
+            "--profile" => cli.vm_options.profile = true,
+            "--flamegraph" => cli.vm_options.flamegraph = Some(value("--flamegraph")?),
+            "--dump-heap" => cli.vm_options.dump_heap = true,
+            "--coverage" => cli.vm_options.coverage = true,
+            "--lcov" => cli.vm_options.lcov = Some(value("--lcov")?),
+            "--compare-with" => cli.compare_with = Some(value("--compare-with")?),
+            "--target" => {
+                cli.target = match value("--target")?.as_str() {
+                    "bytecode" => Target::Bytecode,
+                    "wasm" => Target::Wasm,
+                    target => return Err(format!("Unknown target '{}'.", target)),
+                };
+            }
+            // the rest are arguments of the script, even if they look like
+            // options
+            "--" => cli.vm_options.script_args.extend(args.by_ref()),
+            // a lone dash is stdin
+            flag if flag.starts_with('-') && flag != STDIN_PATH => {
+                return Err(format!("Unknown option '{}'.", flag));
+            }
+            _ => {
+                // the path of a script to run ends the options, and the
+                // rest are its arguments, even if they look like options
+                let runs = !(check || explain || eval.is_some())
+                    && match positional.as_slice() {
+                        [] => command(&arg).is_none(),
+                        [word] => command(word).is_some_and(|(name, _)| *name == "run"),
+                        _ => false,
+                    };
+                positional.push(arg);
+                if runs {
+                    let mut rest = args.by_ref().collect::<Vec<_>>();
+                    // a `--` among them is still taken as the end of the options
+                    if let Some(dashes) = rest.iter().position(|arg| arg == "--") {
+                        rest.remove(dashes);
+                    }
+                    cli.vm_options.script_args.extend(rest);
+                }
+            }
+        }
+    }
+
+    if let Command::Help | Command::Version = cli.command {
+        return Ok(cli);
+    }
+    cli.command = match (positional.as_slice(), eval) {
+        ([], Some(source)) if !check && !explain => Command::Eval(source),
+        (_, Some(_)) => return Err("-e takes the place of the path.".to_string()),
+        ([path], _) if check => Command::Check(path.clone()),
+        ([command, path], _) if check && command == "fmt" => Command::Fmt(path.clone(), true),
+        (_, _) if check => return Err("--check takes a single path.".to_string()),
+        ([path], _) if explain => Command::Explain(path.clone()),
+        (_, _) if explain => return Err("--explain takes a single path.".to_string()),
+        ([], _) => Command::Default,
+        ([command, paths @ ..], _) => match self::command(command) {
+            Some((name, count)) if paths.len() != *count => {
+                let paths = match count {
+                    0 => "no path".to_string(),
+                    1 => "a path".to_string(),
+                    count => format!("{} paths", count),
+                };
+                return Err(format!("'{}' takes {}.", name, paths));
+            }
+            Some((name, _)) => match (*name, paths) {
+                ("repl", []) => Command::Repl,
+                ("run", [path]) => Command::Run(path.clone()),
+                ("watch", [path]) => Command::Watch(path.clone()),
+                ("check", [path]) => Command::Check(path.clone()),
+                ("test", [dir]) => Command::Test(dir.clone()),
+                ("fmt", [path]) => Command::Fmt(path.clone(), false),
+                ("lint", [path]) => Command::Lint(path.clone()),
+                ("compile", [path, output]) => Command::Compile(path.clone(), output.clone()),
+                ("transpile", [path]) => Command::Transpile(path.clone()),
+                ("tokens", [path]) => Command::Tokens(path.clone()),
+                ("debug", [path]) => Command::Debug(path.clone()),
+                ("disasm", [path]) => Command::Disasm(path.clone()),
+                _ => panic!("ICE: The paths of '{}' were not counted", name),
+            },
+            // a bare path runs the script, whose arguments were taken above
+            None => Command::Run(command.clone()),
+        },
+    };
+    if cli.compare_with.is_some() && !matches!(cli.command, Command::Run(_)) {
+        return Err("--compare-with only works with run.".to_string());
+    }
+    Ok(cli)
+}
+
+const USAGE: &str = "\
+Usage: clox [options] [path | - | -e <source>] [args...] [-- args...]
+       clox [options] run <path | path.loxc> [args...] [-- args...]
+       clox [options] --compare-with <command> run <path> [args...] [-- args...]
+       clox [options] watch <path>
+       clox [options] repl
+       clox [options] check <path>
+       clox [options] test <dir>
+       clox [--check] fmt <path | dir | ->
+       clox lint <path | dir | ->
+       clox [options] --explain <path>
+       clox [options] [--target bytecode|wasm] compile <path> <output>
+       clox [options] [--constants] [--lines] [--json] disasm <path>
+       clox [options] transpile <path>
+       clox [--json] [--semantic] tokens <path>
+       clox [options] [--instructions|--tui] debug <path>
+       clox --help | --version
+";
+
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    eprintln!("Run 'clox --help' for the options.");
+    process::exit(64);
+}
+
+// the text of `clox --help`
+fn help() -> String {
+    let options: &[(&str, &str)] = &[
+        ("-h, --help", "print this help"),
+        ("-V, --version", "print the version"),
+        (
+            "-e <source>",
+            "run <source>, printing the value of an expression",
+        ),
+        ("--", "pass the rest to the script, for argc() and arg(i)"),
+        ("--deny-warnings", "fail on warnings like errors"),
+        (
+            "--book-comparisons",
+            "compile >= and <= the way the book does",
+        ),
+        (
+            "--decimal",
+            "do exact decimal arithmetic on number literals",
+        ),
+        ("-O0, -O1, -O2", "how much to optimize, -O0 by default"),
+        (
+            "--print-code",
+            "print the bytecode of each function as it is compiled",
+        ),
+        ("--registers", "run on the register VM"),
+        ("--no-tiering", "don't tier hot loops up to optimized code"),
+        ("--trace-execution", "trace every instruction to stdout"),
+        ("--trace-file <path>", "trace every instruction to <path>"),
+        (
+            "--trace-last <n>",
+            "print the last <n> instructions after a runtime error",
+        ),
+        ("--stats", "print how many times each opcode ran"),
+        (
+            "--time",
+            "print how long compiling and running took, and what it ran",
+        ),
+        ("--profile", "print how long each opcode took"),
+        (
+            "--flamegraph <path>",
+            "write the call stacks that ran to <path>",
+        ),
+        ("--dump-heap", "print the heap once the script is done"),
+        ("--coverage", "print how many times each line ran"),
+        (
+            "--lcov <path>",
+            "write the lines that ran to <path> as lcov",
+        ),
+        (
+            "--no-color",
+            "don't color the REPL or watch, like NO_COLOR=1",
+        ),
+        (
+            "--check",
+            "the same as the check command, or only check the format with fmt",
+        ),
+        (
+            "--explain",
+            "run the script with what each instruction does",
+        ),
+        (
+            "--compare-with <command>",
+            "run the script with <command> too, and diff the outputs",
+        ),
+        ("--target <target>", "compile into bytecode or wasm"),
+        ("--constants", "disassemble the constants of each function"),
+        ("--lines", "disassemble with the source lines"),
+        ("--json", "print the disassembly or the tokens as JSON"),
+        (
+            "--semantic",
+            "classify the tokens for editors, as LSP semantic tokens with --json",
+        ),
+        ("--instructions", "debug one instruction at a time"),
+        ("--tui", "debug one instruction at a time on a full screen"),
+    ];
+    let width = options
+        .iter()
+        .map(|(flag, _)| flag.len())
+        .max()
+        .unwrap_or(0);
+
+    let mut help = format!(
+        "clox {}, the interpreter of Lox from Crafting Interpreters\n\n{}\nOptions:\n",
+        env!("CARGO_PKG_VERSION"),
+        USAGE
+    );
+    for (flag, description) in options {
+        help.push_str(&format!("  {:width$}  {}\n", flag, description));
     }
+    help
 }
 
-fn repl() {
+// what `clox compile` compiles the script into
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    // the `.loxc` format that `clox run` loads
+    Bytecode,
+    // a WebAssembly module, which runs without the VM
+    Wasm,
+}
+
+// how the VM runs the scripts, besides how they are compiled
+#[derive(Debug, Clone)]
+struct VmOptions {
+    backend: Backend,
+    tiering: bool,
+    trace: TraceTo,
+    // print how many times each opcode ran once the script is done
+    stats: bool,
+    // print how long the script took to compile and to run, and how much
+    // work running it was
+    time: bool,
+    // print how long each opcode took once the script is done
+    profile: bool,
+    // where to write how many instructions ran in each call stack, for
+    // flamegraph tools
+    flamegraph: Option<String>,
+    // print the objects left on the heap once the script is done
+    dump_heap: bool,
+    // print the source with how many times each line ran once the script is
+    // done
+    coverage: bool,
+    // where to write which lines ran as an lcov tracefile
+    lcov: Option<String>,
+    // what the scripts get from `argc()` and `arg(i)`
+    script_args: Vec<String>,
+}
+
+// how `clox debug` shows the script
+#[derive(Debug, Clone, Copy)]
+enum DebugView {
+    // by lines of the source
+    Source,
+    // one instruction at a time
+    Instructions,
+    // one instruction at a time, on a full screen
+    Tui,
+}
+
+// where the VM traces the instructions that it runs
+#[derive(Debug, Clone)]
+enum TraceTo {
+    // stdout if DEBUG_TRACE_EXECUTION=1 is set, otherwise nowhere
+    Default,
+    Stdout,
+    File(String),
+    // the last instructions, printed after a runtime error
+    Last(usize),
+}
+
+fn new_vm(vm_options: &VmOptions, options: CompileOptions) -> VM {
+    configure_vm(VM::new(), vm_options, options)
+}
+
+// sets up a VM that prints somewhere else than stdout like `new_vm` does
+fn configure_vm<W: clox::io::Write>(
+    mut vm: VM<W>,
+    vm_options: &VmOptions,
+    options: CompileOptions,
+) -> VM<W> {
+    vm.set_compile_options(options);
+    vm.set_backend(vm_options.backend);
+    vm.set_tiering(vm_options.tiering);
+    vm.set_count_opcodes(vm_options.stats);
+    vm.set_timing(vm_options.time);
+    vm.set_stack_profile(vm_options.flamegraph.is_some());
+    vm.set_coverage(vm_options.coverage || vm_options.lcov.is_some());
+    vm.set_args(&vm_options.script_args);
+    if vm_options.profile {
+        #[cfg(feature = "profile")]
+        vm.set_profile(true);
+        #[cfg(not(feature = "profile"))]
+        {
+            eprintln!("--profile needs clox to be built with the profile feature");
+            process::exit(64);
+        }
+    }
+    match &vm_options.trace {
+        TraceTo::Default => {}
+        TraceTo::Stdout => vm.set_trace_execution(true),
+        TraceTo::File(path) => match fs::File::create(path) {
+            Ok(file) => vm.set_trace(Trace::Writer(Box::new(io::BufWriter::new(file)))),
+            Err(_) => {
+                eprintln!("Could not write file {}", path);
+                process::exit(74);
+            }
+        },
+        TraceTo::Last(len) => vm.set_trace(Trace::last(*len)),
+    }
+    vm
+}
+
+fn repl(options: CompileOptions, vm_options: VmOptions, color: bool) {
+    let config = read_config();
+    let color = color && config.color;
+    let mut vm = new_vm(
+        &vm_options,
+        CompileOptions {
+            print_expressions: true,
+            ..options
+        },
+    );
+    vm.set_color(color);
+    if config.trace && matches!(vm_options.trace, TraceTo::Default) {
+        vm.set_trace_execution(true);
+    }
+    handle_interrupts(&mut vm);
+    for path in &config.load {
+        load_script(&mut vm, path, options);
+    }
+    // the inputs that ran, for :save, with the results that they used
+    // written out
+    let mut transcript = vec![];
+    let prompts = (config.prompt.as_str(), config.continuation_prompt.as_str());
+    // made on the first read from a terminal, and kept for the rest of the
+    // session for its history, with stdin locked
+    let mut editor = None;
+
     loop {
-        print!("> ");
-        io::stdout().flush().unwrap_or_else(|_| {
-            panic!("cannot write to stdout");
+        // a terminal gets to complete words with Tab and go through the
+        // history with Up and Down, a pipe is read as it is. The terminal is
+        // only changed while reading, so that Ctrl+C still stops a script
+        let input = match Terminal::raw() {
+            Some(_terminal) => {
+                let globals = vm.global_names();
+                let editor = editor.get_or_insert_with(|| {
+                    let mut editor = LineEditor::new(io::stdin().lock(), io::stdout());
+                    editor.set_highlight(color);
+                    editor.set_history_size(config.history);
+                    editor
+                });
+                read_input(prompts, |prompt| {
+                    editor.read_line(prompt, |line| repl::completions(line, &globals))
+                })
+            }
+            None => read_input(prompts, read_stdin_line),
+        };
+        let Some(buffer) = input else {
+            // Ctrl+D will produce 0 bytes (even a blank line is one character due to \n)
+            println!();
+            print_stats(&vm, &vm_options);
+            break;
+        };
+
+        match repl::command(&buffer) {
+            Some(Ok(repl::Command::Heap)) => vm.heap_dump().write(&mut io::stdout()),
+            Some(Ok(repl::Command::Load(path))) => {
+                transcript.extend(load_script(&mut vm, path, options));
+            }
+            Some(Ok(repl::Command::Save(path))) => {
+                let script = transcript
+                    .iter()
+                    .map(|input| format!("{}\n", input.trim_end()))
+                    .collect::<String>();
+                if fs::write(path, script).is_err() {
+                    eprintln!("Could not write file {}", path);
+                }
+            }
+            Some(Err(message)) => eprintln!("{}", message),
+            None => {
+                // the values that are printed are kept as `_` and `_1`, `_2`...
+                vm.set_compile_options(CompileOptions {
+                    print_expressions: true,
+                    first_result: Some(repl::next_result(&vm.global_names())),
+                    ..options
+                });
+                let input = repl::with_semicolon(&buffer);
+                // the results that it uses are saved as the values that
+                // they have now, or it is left out of the script
+                let saved = repl::expand_results(&input, |name| vm.global(name));
+                if vm.interpret(&input).is_ok() {
+                    transcript.extend(saved);
+                }
+            }
+        }
+    }
+}
+
+// runs the script at `path` in the session of the REPL, returning its source
+// if it ran without an error
+fn load_script(vm: &mut VM, path: &str, options: CompileOptions) -> Option<String> {
+    let Ok(source) = fs::read_to_string(path) else {
+        eprintln!("Could not read file {}", path);
+        return None;
+    };
+    // a script doesn't print its expressions, like calls
+    vm.set_compile_options(options);
+    let result = vm.interpret(&source);
+    vm.set_compile_options(CompileOptions {
+        print_expressions: true,
+        ..options
+    });
+    result.ok().map(|_| source)
+}
+
+// the settings of the REPL in the config directory of the user, at
+// $XDG_CONFIG_HOME/clox/repl.toml or ~/.config/clox/repl.toml. The defaults
+// are used without the file, or in place of one that is wrong
+fn read_config() -> repl::Config {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(path::PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| path::Path::new(&home).join(".config")));
+    let Some(path) = dir.map(|dir| dir.join("clox").join("repl.toml")) else {
+        return repl::Config::default();
+    };
+    let Ok(text) = fs::read_to_string(&path) else {
+        return repl::Config::default();
+    };
+
+    match repl::Config::parse(&text) {
+        // the scripts to load are relative to the file
+        Ok(mut config) => {
+            let dir = path.parent().unwrap_or(path::Path::new(""));
+            for script in &mut config.load {
+                *script = dir.join(&script).to_string_lossy().into_owned();
+            }
+            config
+        }
+        Err(message) => {
+            eprintln!("Ignoring {}: {}", path.display(), message);
+            repl::Config::default()
+        }
+    }
+}
+
+// set by Ctrl+C for the VM, which stops the script that is running rather
+// than clox
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+// makes Ctrl+C interrupt the scripts that `vm` runs, which stop with an
+// "Interrupted." runtime error, instead of killing clox
+fn handle_interrupts(vm: &mut VM) {
+    const SIGINT: c_int = 2;
+    unsafe extern "C" {
+        fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    }
+    extern "C" fn on_interrupt(_signum: c_int) {
+        INTERRUPT.store(true, Ordering::Relaxed);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is safe to do in a
+    // signal handler
+    unsafe {
+        signal(SIGINT, on_interrupt);
+    }
+    vm.set_interrupt(&INTERRUPT);
+}
+
+// reads lines with `read_line` until they make a complete input for the
+// REPL, showing the first of `prompts` and then the continuation prompt. A
+// blank line ends the input as it is, to leave one that can't be completed,
+// and Ctrl+C throws it away. None is the end of stdin.
+fn read_input(
+    (prompt, continuation_prompt): (&str, &str),
+    mut read_line: impl FnMut(&str) -> io::Result<Option<String>>,
+) -> Option<String> {
+    let mut buffer = String::new();
+    loop {
+        let prompt = if buffer.is_empty() {
+            prompt
+        } else {
+            continuation_prompt
+        };
+        match read_line(prompt) {
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => buffer.clear(),
+            Ok(None) | Err(_) if buffer.is_empty() => return None,
+            // what was read so far still runs
+            Ok(None) | Err(_) => return Some(buffer),
+            Ok(Some(line)) => {
+                buffer.push_str(&line);
+                if line.trim().is_empty() || repl::is_complete(&buffer) {
+                    return Some(buffer);
+                }
+            }
+        }
+    }
+}
+
+// reads a line from stdin after showing `prompt`, as `read_input` does
+fn read_stdin_line(prompt: &str) -> io::Result<Option<String>> {
+    print!("{}", prompt);
+    io::stdout().flush().unwrap_or_else(|_| {
+        panic!("cannot write to stdout");
+    });
+
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line)? {
+        0 => Ok(None),
+        _ => Ok(Some(line)),
+    }
+}
+
+// the settings of the terminal on stdin, which are put back once this is
+// dropped
+struct Terminal {
+    settings: String,
+}
+
+impl Terminal {
+    // stops the terminal from echoing keys, waiting for the end of the line
+    // and taking Ctrl+C to stop the process, for the line editor, or None
+    // without a terminal that `stty` can change
+    fn raw() -> Option<Self> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+        let output = process::Command::new("stty")
+            .arg("-g")
+            .stdin(process::Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())?;
+        let settings = String::from_utf8(output.stdout).ok()?.trim().to_string();
+        stty(&["-icanon", "-echo", "-isig", "min", "1"]).then_some(Self { settings })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        stty(&[&self.settings]);
+    }
+}
+
+fn stty(args: &[&str]) -> bool {
+    process::Command::new("stty")
+        .args(args)
+        .stdin(process::Stdio::inherit())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+// runs `source` from the command line, printing the value of an expression
+// like the REPL does
+fn eval_source(source: &str, options: CompileOptions, vm_options: VmOptions) {
+    let mut vm = new_vm(
+        &vm_options,
+        CompileOptions {
+            print_expressions: true,
+            ..options
+        },
+    );
+
+    let source = repl::with_semicolon(source);
+    let result = vm.interpret(&source);
+    print_stats(&vm, &vm_options);
+    print_coverage(&vm, &vm_options, "-e", &source);
+    exit_on_error(result);
+}
+
+// the path that stands for stdin, which a pipe also runs as without a path
+const STDIN_PATH: &str = "-";
+
+fn read_source(path: &str) -> String {
+    if path == STDIN_PATH {
+        return io::read_to_string(io::stdin()).unwrap_or_else(|_| {
+            eprintln!("Could not read stdin");
+            process::exit(74);
         });
+    }
+
+    match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => {
+            eprintln!("Could not read file {}", path);
+            process::exit(74);
+        }
+    }
+}
+
+// how often `clox watch` looks at whether the script has changed
+const WATCH_INTERVAL: Duration = Duration::from_millis(200);
 
-        let mut buffer = String::new();
+// runs the script at `path` on a cleared screen, and again each time that it
+// is saved, until clox is stopped
+fn watch_file(path: &str, options: CompileOptions, vm_options: VmOptions, color: bool) {
+    let modified = || {
+        fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    if modified().is_none() {
+        eprintln!("Could not read file {}", path);
+        process::exit(74);
+    }
 
-        if let Ok(total_bytes) = io::stdin().read_line(&mut buffer) {
-            if total_bytes == 0 {
-                // Ctrl+D will produce 0 bytes (even a blank line is one character due to \n)
+    loop {
+        let last_modified = modified();
+        print!("\x1b[2J\x1b[H");
+        io::stdout().flush().expect("writable");
+
+        // the file can be missing for a moment while an editor saves it
+        match fs::read_to_string(path) {
+            Ok(source) => {
+                let mut vm = new_vm(&vm_options, options);
+                vm.set_color(color);
+                let status = match vm.interpret(&source) {
+                    Ok(()) => "finished",
+                    Err(InterpretError::CompileError | InterpretError::LoadError) => {
+                        "failed to compile"
+                    }
+                    Err(InterpretError::RuntimeError) => "stopped with an error",
+                    Err(InterpretError::Interrupted) => "was interrupted",
+                };
                 println!();
-                break;
+                println!("[{} {}, waiting for it to change]", path, status);
             }
+            Err(_) => eprintln!("Could not read file {}", path),
+        }
 
-            // TODO: do we to handle the result here?
-            let _ = VM::interpret(buffer);
+        while modified() == last_modified {
+            thread::sleep(WATCH_INTERVAL);
+        }
+    }
+}
+
+fn run_file<S: AsRef<str>>(path: S, options: CompileOptions, vm_options: VmOptions) {
+    let source = read_source(path.as_ref());
+
+    let mut vm = new_vm(&vm_options, options);
+    handle_interrupts(&mut vm);
+
+    let result = vm.interpret(&source);
+    print_stats(&vm, &vm_options);
+    print_coverage(&vm, &vm_options, path.as_ref(), &source);
+    exit_on_error(result);
+}
+
+// runs the script at `path` with clox and with the reference `command`, and
+// prints how what they printed and their exit codes differ, if they do
+fn compare_file(path: &str, command: &str, options: CompileOptions, vm_options: VmOptions) {
+    if is_bytecode_file(path) {
+        eprintln!("--compare-with needs the source of the script, not bytecode");
+        process::exit(64);
+    }
+    let source = read_source(path);
+
+    let mut vm = configure_vm(VM::with_output(Vec::new()), &vm_options, options);
+    let result = vm.interpret(&source);
+    let ours = compare::Outcome::new(vm.output(), result);
+
+    let theirs = match compare::run_reference(command, path, &source, &vm_options.script_args) {
+        Ok(outcome) => outcome,
+        Err(error) => {
+            eprintln!("Could not run {}: {}", command, error);
+            process::exit(74);
+        }
+    };
+    match compare::compare(&ours, &theirs, command) {
+        Some(report) => {
+            print!("{}", report);
+            process::exit(1);
+        }
+        None => println!(
+            "Same output and exit code ({}) as {}.",
+            ours.exit_code, command
+        ),
+    }
+}
+
+// runs the script at `path` in the debugger for `view`, which reads its
+// commands from stdin
+fn debug_file<S: AsRef<str>>(
+    path: S,
+    options: CompileOptions,
+    vm_options: VmOptions,
+    view: DebugView,
+) {
+    let source = read_source(path.as_ref());
+
+    // the debuggers pause the stack VM, and a breakpoint at an offset needs
+    // the bytecode to stay the same as it was compiled
+    let vm_options = VmOptions {
+        backend: Backend::Stack,
+        tiering: false,
+        ..vm_options
+    };
+    if let DebugView::Tui = view {
+        return tui_file(&source, options);
+    }
+
+    let mut vm = new_vm(&vm_options, options);
+    if let DebugView::Instructions = view {
+        vm.set_debugger(Box::new(StepDebugger::new(
+            io::stdin().lock(),
+            io::stdout(),
+        )));
+    } else {
+        let mut repl = DebugRepl::new(path.as_ref(), &source, io::stdin().lock(), io::stdout());
+        vm.set_debugger(Box::new(SourceDebugger::new(
+            move |paused: &_, stop, breakpoints: &mut _| repl.pause(paused, stop, breakpoints),
+        )));
+    }
+
+    let result = vm.interpret(source);
+    print_stats(&vm, &vm_options);
+    exit_on_error(result);
+}
+
+// the size of the screen when the shell doesn't say
+const TUI_WIDTH: usize = 120;
+const TUI_HEIGHT: usize = 30;
+
+// steps through `source` on a full screen the size of the terminal, as the
+// shell tells it, which is where the script prints as well
+fn tui_file(source: &str, options: CompileOptions) {
+    let size = |name: &str, default: usize| {
+        env::var(name)
+            .ok()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(default)
+    };
+    let tui = Tui::new(
+        source,
+        io::stdin().lock(),
+        io::stdout(),
+        size("COLUMNS", TUI_WIDTH),
+        size("LINES", TUI_HEIGHT),
+    );
+
+    let mut vm = VM::with_output(tui.script_output());
+    vm.set_backend(Backend::Stack);
+    vm.set_tiering(false);
+    vm.set_compile_options(options);
+    vm.set_debugger(Box::new(tui));
+    exit_on_error(vm.interpret(source));
+}
+
+// runs the script at `path` while explaining every instruction on stdout
+fn explain_file<S: AsRef<str>>(path: S, options: CompileOptions, vm_options: VmOptions) {
+    let source = read_source(path.as_ref());
+
+    // the explanation comes from the stack VM, without tiering up
+    let vm_options = VmOptions {
+        backend: Backend::Stack,
+        tiering: false,
+        ..vm_options
+    };
+    let mut vm = new_vm(&vm_options, options);
+    vm.set_debugger(Box::new(Explain::new(io::stdout())));
+
+    let result = vm.interpret(source);
+    print_stats(&vm, &vm_options);
+    exit_on_error(result);
+}
+
+// compiles the script at `path` to report its problems, without running it
+fn check_file<S: AsRef<str>>(path: S, options: CompileOptions) {
+    let source = read_source(path.as_ref());
+
+    let result = Compiler::check(&source, options);
+    let (Ok(diagnostics) | Err(diagnostics)) = &result;
+    report(diagnostics, &source);
+
+    if result.is_err() {
+        process::exit(65);
+    }
+}
+
+// runs the test files of the book under `dir`, printing what each one that
+// fails did differently, and exits with 1 if any of them did
+fn test_dir(dir: &str, options: CompileOptions) {
+    let mut paths = vec![];
+    if let Err(error) = find_tests(path::Path::new(dir), &mut paths) {
+        eprintln!("Could not read directory {}: {}", dir, error);
+        process::exit(74);
+    }
+    paths.sort();
+
+    let (mut passed, mut failed) = (0, 0);
+    for path in &paths {
+        let source = read_source(&path.to_string_lossy());
+        match conformance::run(&source, options) {
+            None => {}
+            Some(failures) if failures.is_empty() => passed += 1,
+            Some(failures) => {
+                failed += 1;
+                println!("FAIL {}", path.display());
+                for failure in failures {
+                    println!("     {}", failure);
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", passed, failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+}
+
+// formats the script at `path` in place, or the .lox files under it if it
+// is a directory, or prints stdin formatted. With `check`, it only prints
+// which of them would change, and exits with 1 if any would. The ones with
+// syntax errors are reported and left alone, and make it exit with 65.
+fn format_path(path: &str, check: bool) {
+    let (mut unformatted, mut invalid) = (false, false);
+    for path in &script_paths(path) {
+        let path = path.to_string_lossy();
+        let source = read_source(&path);
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(diagnostics) => {
+                report(&diagnostics, &source);
+                invalid = true;
+                continue;
+            }
+        };
+
+        if check {
+            if formatted != source {
+                println!("Would reformat {}", path);
+                unformatted = true;
+            }
+        } else if path == STDIN_PATH {
+            print!("{}", formatted);
+        } else if formatted != source && fs::write(path.as_ref(), formatted).is_err() {
+            eprintln!("Could not write file {}", path);
+            process::exit(74);
+        }
+    }
+
+    if invalid {
+        process::exit(65);
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+// reports what the linter finds in the script at `path`, or in the .lox files
+// under it if it is a directory, with the rules of the clox-lint.toml that is
+// nearest to each of them. Exits with 1 if any of the rules that were made
+// errors found something, or with 65 if a script has syntax errors.
+fn lint_path(path: &str) {
+    let (mut failed, mut invalid) = (false, false);
+    for path in &script_paths(path) {
+        let path = path.to_string_lossy();
+        let source = read_source(&path);
+        let config = read_lint_config(&path);
+        let result = lint::lint(&source, &config);
+        let (Ok(diagnostics) | Err(diagnostics)) = &result;
+        // the diagnostics only have the line, so say which file they are in
+        if !diagnostics.is_empty() {
+            eprintln!("{}:", path);
+        }
+        report(diagnostics, &source);
+        match &result {
+            Ok(diagnostics) => {
+                failed |= diagnostics
+                    .iter()
+                    .any(|diagnostic| diagnostic.severity == Severity::Error);
+            }
+            Err(_) => invalid = true,
+        }
+    }
+
+    if invalid {
+        process::exit(65);
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+// the config in the clox-lint.toml in the directory of the script at `path`
+// or the nearest one above it, or the defaults if there isn't one. A file
+// that is wrong is as bad as a wrong option, and exits with 64.
+fn read_lint_config(path: &str) -> lint::Config {
+    let start = if path == STDIN_PATH {
+        env::current_dir().unwrap_or_default()
+    } else {
+        fs::canonicalize(path)
+            .ok()
+            .and_then(|path| path.parent().map(path::Path::to_path_buf))
+            .unwrap_or_default()
+    };
+    let Some(config_path) = start
+        .ancestors()
+        .map(|dir| dir.join(lint::Config::FILE_NAME))
+        .find(|config_path| config_path.is_file())
+    else {
+        return lint::Config::default();
+    };
+
+    let text = fs::read_to_string(&config_path).unwrap_or_else(|_| {
+        eprintln!("Could not read file {}", config_path.display());
+        process::exit(74);
+    });
+    lint::Config::parse(&text).unwrap_or_else(|message| {
+        eprintln!("{}: {}", config_path.display(), message);
+        process::exit(64);
+    })
+}
+
+// the script at `path`, or the .lox files under it if it is a directory, in
+// order
+fn script_paths(path: &str) -> Vec<path::PathBuf> {
+    let mut paths = vec![];
+    if path == STDIN_PATH || !path::Path::new(path).is_dir() {
+        paths.push(path::PathBuf::from(path));
+    } else if let Err(error) = find_tests(path::Path::new(path), &mut paths) {
+        eprintln!("Could not read directory {}: {}", path, error);
+        process::exit(74);
+    }
+    paths.sort();
+    paths
+}
+
+// the .lox files in `dir` and the directories under it
+fn find_tests(dir: &path::Path, paths: &mut Vec<path::PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_tests(&path, paths)?;
+        } else if path.extension().is_some_and(|extension| extension == "lox") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+// compiles the script at `path`, and writes its bytecode to `output`
+fn compile_file<S: AsRef<str>>(path: S, output: S, options: CompileOptions) {
+    let source = read_source(path.as_ref());
+
+    // the heap owns the functions in the chunk, so it has to outlive it
+    let mut heap = Heap::new();
+    let chunk = match Compiler::compile_with_warnings(&source, &mut heap, options) {
+        Ok((chunk, warnings)) => {
+            report(&warnings, &source);
+            chunk
+        }
+        Err(diagnostics) => {
+            report(&diagnostics, &source);
+            process::exit(65);
+        }
+    };
+
+    if fs::write(output.as_ref(), bytecode::serialize(&chunk)).is_err() {
+        eprintln!("Could not write file {}", output.as_ref());
+        process::exit(74);
+    }
+}
+
+// compiles the script at `path` into a WebAssembly module, and writes it to
+// `output`. the script is compiled for the VM first, so that it gets the
+// same errors and warnings
+fn compile_wasm_file<S: AsRef<str>>(path: S, output: S, options: CompileOptions) {
+    require_f64(options);
+    let source = read_source(path.as_ref());
+
+    match Compiler::check(&source, options) {
+        Ok(warnings) => report(&warnings, &source),
+        Err(diagnostics) => {
+            report(&diagnostics, &source);
+            process::exit(65);
+        }
+    }
+
+    let program = Parser::new(&source).parse();
+    let module = wasm::compile(&program).unwrap_or_else(|diagnostics| {
+        report(&diagnostics, &source);
+        process::exit(65);
+    });
+
+    if fs::write(output.as_ref(), module).is_err() {
+        eprintln!("Could not write file {}", output.as_ref());
+        process::exit(74);
+    }
+}
+
+// prints the script at `path` as the source of a Rust program, which is
+// checked the same way as `compile_wasm_file` does
+fn transpile_file<S: AsRef<str>>(path: S, options: CompileOptions) {
+    require_f64(options);
+    let source = read_source(path.as_ref());
+
+    match Compiler::check(&source, options) {
+        Ok(warnings) => report(&warnings, &source),
+        Err(diagnostics) => {
+            report(&diagnostics, &source);
+            process::exit(65);
+        }
+    }
+
+    let program = Parser::new(&source).parse();
+    let rust = transpile::transpile(&program, path.as_ref()).unwrap_or_else(|diagnostics| {
+        report(&diagnostics, &source);
+        process::exit(65);
+    });
+    print!("{}", rust);
+}
+
+// decimals are only implemented by the VM, while WebAssembly and Rust
+// compute with f64 like the book does
+fn require_f64(options: CompileOptions) {
+    if options.decimal {
+        eprintln!("--decimal only works with the VM");
+        process::exit(64);
+    }
+}
+
+// prints the tokens of the script at `path`, without parsing it
+fn print_tokens<S: AsRef<str>>(path: S, json: bool) {
+    let source = read_source(path.as_ref());
+    let scanner = Scanner::new(&source);
+    let mut had_error = false;
+
+    if json {
+        println!("[");
+    }
+    for token in scanner {
+        had_error |= token.kind == TokenKind::Error;
+
+        if json {
+            let is_last = token.kind == TokenKind::EndOfFile;
+            println!("  {}{}", token.to_json(), if is_last { "" } else { "," });
         } else {
-            // EOF
-            break;
+            println!(
+                "{:4}:{:<4} {:<14} {:<12} '{}'",
+                token.line,
+                token.column,
+                format!("{:?}", token.kind),
+                format!("{:?}", token.range()),
+                token.lexeme
+            );
         }
     }
+    if json {
+        println!("]");
+    }
+
+    if had_error {
+        process::exit(65);
+    }
 }
 
-fn run_file<S: AsRef<str>>(path: S) {
-    let source = match fs::read_to_string(path.as_ref()) {
+// prints the tokens of the script at `path` classified for an editor to
+// highlight, which unlike the tokens of the scanner include the comments
+fn print_semantic_tokens<S: AsRef<str>>(path: S, json: bool) {
+    let source = read_source(path.as_ref());
+    let tokens = semantic::semantic_tokens(&source);
+
+    if json {
+        println!("{}", semantic::to_json(&tokens, &source));
+        return;
+    }
+    for token in &tokens {
+        println!(
+            "{:4}:{:<4} {:<10} {:<12} '{}'",
+            token.line,
+            token.column,
+            token.token_type.name(),
+            if token.declaration { "declaration" } else { "" },
+            token.text(&source)
+        );
+    }
+}
+
+// compiles the script at `path`, and prints its bytecode instead of running it
+fn disassemble_file<S: AsRef<str>>(
+    path: S,
+    options: CompileOptions,
+    disassemble_options: DisassembleOptions,
+    backend: Backend,
+    json: bool,
+) {
+    // the registers are only ever printed as text
+    if json && backend == Backend::Register {
+        eprintln!("--json only works with the stack bytecode");
+        process::exit(64);
+    }
+
+    let source = read_source(path.as_ref());
+
+    // the warnings of code that compiles are left out, since it is the
+    // bytecode that is being looked at
+    let mut heap = Heap::new();
+    let chunk = Compiler::compile(&source, &mut heap, options).unwrap_or_else(|diagnostics| {
+        report(&diagnostics, &source);
+        process::exit(65);
+    });
+
+    match backend {
+        Backend::Stack if json => {
+            debug::disassemble_program_json(&mut io::stdout(), &chunk, "<script>")
+        }
+        Backend::Stack => {
+            debug::disassemble_program(&mut io::stdout(), &chunk, "<script>", disassemble_options)
+        }
+        // the script is run as a function without parameters
+        Backend::Register => {
+            register::disassemble_program(&mut io::stdout(), &chunk, "<script>", 0)
+        }
+    }
+}
+
+// whether the file at `path` is one that `clox compile` wrote, rather than a
+// script
+fn is_bytecode_file(path: &str) -> bool {
+    fs::read(path).is_ok_and(|bytes| bytecode::is_bytecode(&bytes))
+}
+
+fn run_bytecode_file<S: AsRef<str>>(path: S, vm_options: VmOptions) {
+    let bytes = match fs::read(path.as_ref()) {
         Ok(content) => content,
         Err(_) => {
             eprintln!("Could not read file {}", path.as_ref());
@@ -60,14 +1363,75 @@ fn run_file<S: AsRef<str>>(path: S) {
         }
     };
 
-    if let Err(error) = VM::interpret(source) {
-        match error {
-            InterpretError::CompileError => {
-                process::exit(65);
-            }
-            InterpretError::RuntimeError => {
-                process::exit(70);
-            }
+    let mut vm = new_vm(&vm_options, CompileOptions::default());
+    let result = vm.interpret_chunk_bytes(&bytes);
+    print_stats(&vm, &vm_options);
+    // the source is not part of the compiled script
+    print_coverage(&vm, &vm_options, path.as_ref(), "");
+    exit_on_error(result);
+}
+
+// prints the histogram of the opcodes that ran with --stats, the time that
+// the script took with --time, how long each opcode took with --profile, and
+// the heap with --dump-heap, to stderr so that it
+// stays apart from the output of the script. the call stacks go to the file
+// given with --flamegraph
+fn print_stats(vm: &VM, vm_options: &VmOptions) {
+    if let Some(counts) = vm.opcode_counts() {
+        counts.write_histogram(&mut io::stderr());
+    }
+    if let Some(timing) = vm.timing() {
+        timing.write(&mut io::stderr());
+    }
+    #[cfg(feature = "profile")]
+    if let Some(profile) = vm.profile() {
+        profile.write_table(&mut io::stderr());
+    }
+    if vm_options.dump_heap {
+        vm.heap_dump().write(&mut io::stderr());
+    }
+    if let (Some(stack_profile), Some(path)) = (vm.stack_profile(), &vm_options.flamegraph) {
+        let written = fs::File::create(path).is_ok_and(|file| {
+            stack_profile
+                .write_folded(&mut io::BufWriter::new(file))
+                .is_ok()
+        });
+        if !written {
+            eprintln!("Could not write file {}", path);
+            process::exit(74);
         }
     }
 }
+
+// prints which lines of the script at `path` ran with --coverage, to stderr
+// like the stats, and writes them to the lcov tracefile given with --lcov
+fn print_coverage(vm: &VM, vm_options: &VmOptions, path: &str, source: &str) {
+    let Some(coverage) = vm.coverage() else {
+        return;
+    };
+    if vm_options.coverage {
+        coverage.write_annotated(&mut io::stderr(), source);
+    }
+    if let Some(lcov) = &vm_options.lcov {
+        let written = fs::File::create(lcov).is_ok_and(|file| {
+            coverage
+                .write_lcov(&mut io::BufWriter::new(file), path)
+                .is_ok()
+        });
+        if !written {
+            eprintln!("Could not write file {}", lcov);
+            process::exit(74);
+        }
+    }
+}
+
+// prints the diagnostics of compiling `source`, the same way that the VM does
+fn report(diagnostics: &[Diagnostic], source: &str) {
+    diagnostic::render_all(&mut io::stderr(), diagnostics, source);
+}
+
+fn exit_on_error(result: Result<(), InterpretError>) {
+    if let Err(error) = result {
+        process::exit(error.exit_code());
+    }
+}
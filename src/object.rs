@@ -0,0 +1,691 @@
+use alloc::{
+    boxed::Box,
+    collections::BTreeSet,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{cell::OnceCell, fmt, ptr::NonNull};
+
+use crate::{
+    chunk::Chunk,
+    decimal::Decimal,
+    table::{Table, hash_string},
+    value::Value,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ObjType {
+    String,
+    Function,
+    // the numbers of the `decimal` compile option
+    Decimal,
+    Native,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ObjData {
+    String(ObjString),
+    // boxed, as its chunk is several times the size of any other object
+    Function(Box<ObjFunction>),
+    Decimal(Decimal),
+    Native(ObjNative),
+}
+
+impl ObjData {
+    fn obj_type(&self) -> ObjType {
+        match self {
+            ObjData::String(_) => ObjType::String,
+            ObjData::Function(_) => ObjType::Function,
+            ObjData::Decimal(_) => ObjType::Decimal,
+            ObjData::Native(_) => ObjType::Native,
+        }
+    }
+}
+
+// the longest string that is kept in the object itself, which is as long as
+// most identifiers and literals are
+const INLINE_CAPACITY: usize = 22;
+
+// the characters of a string, which only have a buffer of their own when
+// they do not fit in the object
+enum Chars {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_CAPACITY],
+    },
+    Heap(String),
+    Rope(Box<Rope>),
+}
+
+// a long string that concatenating made out of other strings, whose parts
+// are only copied into a buffer once its characters are looked at, so that
+// a loop appending to a string doesn't copy all of it every time around
+struct Rope {
+    parts: Box<[ObjRef]>,
+    len: usize,
+    flat: OnceCell<String>,
+}
+
+impl Rope {
+    // walks the parts without recursing, since a loop that appends to a
+    // string makes a rope as deep as the number of times it went around.
+    // parts that have been flattened already are copied as they are
+    fn flatten(&self) -> String {
+        let mut chars = String::with_capacity(self.len);
+        let mut parts = self.parts.iter().rev().copied().collect::<Vec<_>>();
+        while let Some(part) = parts.pop() {
+            let part = part.as_string().expect("ICE: Rope of non-strings");
+            match &part.chars {
+                Chars::Rope(rope) if rope.flat.get().is_none() => {
+                    parts.extend(rope.parts.iter().rev())
+                }
+                part => chars.push_str(part.as_str()),
+            }
+        }
+        chars
+    }
+}
+
+impl Chars {
+    fn inline(chars: &str) -> Option<Self> {
+        let mut bytes = [0; INLINE_CAPACITY];
+        bytes
+            .get_mut(..chars.len())?
+            .copy_from_slice(chars.as_bytes());
+        Some(Chars::Inline {
+            len: chars.len() as u8,
+            bytes,
+        })
+    }
+
+    // the buffer of a long string is kept, instead of being copied
+    fn take(chars: String) -> Self {
+        Chars::inline(&chars).unwrap_or(Chars::Heap(chars))
+    }
+
+    fn copy(chars: &str) -> Self {
+        Chars::inline(chars).unwrap_or_else(|| Chars::Heap(chars.to_string()))
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            // SAFETY: the bytes were copied from a whole `str`
+            Chars::Inline { len, bytes } => unsafe {
+                core::str::from_utf8_unchecked(&bytes[..*len as usize])
+            },
+            Chars::Heap(chars) => chars,
+            Chars::Rope(rope) => rope.flat.get_or_init(|| rope.flatten()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Chars::Rope(rope) => rope.len,
+            chars => chars.as_str().len(),
+        }
+    }
+}
+
+pub struct ObjString {
+    chars: Chars,
+    // worked out once when the string is made, since strings never change,
+    // or once it is asked for if the string is a rope
+    hash: OnceCell<u32>,
+}
+
+impl ObjString {
+    pub fn as_str(&self) -> &str {
+        self.chars.as_str()
+    }
+
+    /// The length in bytes, which doesn't flatten a rope.
+    pub(crate) fn len(&self) -> usize {
+        self.chars.len()
+    }
+
+    /// The strings that a rope was concatenated from, or none if this isn't
+    /// one.
+    pub(crate) fn parts(&self) -> &[ObjRef] {
+        match &self.chars {
+            Chars::Rope(rope) => &rope.parts,
+            _ => &[],
+        }
+    }
+
+    /// Whether the characters are kept in the object, without a buffer of
+    /// their own.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.chars, Chars::Inline { .. })
+    }
+
+    /// The [`hash_string`] of the characters.
+    pub fn hash(&self) -> u32 {
+        *self.hash.get_or_init(|| hash_string(self.as_str()))
+    }
+}
+
+// strings with different hashes cannot have the same characters, so most
+// strings that are not equal are told apart without looking at them
+impl PartialEq for ObjString {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash() == other.hash() && self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for ObjString {}
+
+impl fmt::Debug for ObjString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjString")
+            .field("chars", &self.as_str())
+            .field("hash", &self.hash())
+            .finish()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub chunk: Chunk,
+    // `None` for the top-level script
+    pub name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub fn name(&self) -> Option<&str> {
+        self.name
+            .as_ref()
+            .and_then(|name| name.as_string())
+            .map(ObjString::as_str)
+    }
+}
+
+/// What a native function does with the arguments of a call: its result,
+/// or the message of the runtime error that it stops the script with.
+pub type NativeFn = Box<dyn Fn(&[Value]) -> Result<Value, String>>;
+
+/// A function of the VM that scripts call like their own.
+pub struct ObjNative {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjNative")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish()
+    }
+}
+
+// the functions can't be compared, so each native is only equal to itself
+impl PartialEq for ObjNative {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::eq(self, other)
+    }
+}
+
+// every object allocated on the heap starts with this header, which is
+// what the collector (mark bit) and heap walkers (next pointer) look at
+pub struct ObjHeader {
+    obj_type: ObjType,
+    // not read by anyone until there is a collector
+    #[allow(dead_code)]
+    is_marked: bool,
+    next: Option<NonNull<Obj>>,
+}
+
+pub struct Obj {
+    header: ObjHeader,
+    data: ObjData,
+}
+
+impl Obj {
+    pub fn obj_type(&self) -> ObjType {
+        self.header.obj_type
+    }
+
+    pub fn data(&self) -> &ObjData {
+        &self.data
+    }
+}
+
+/// A handle to an object owned by a [`Heap`].
+///
+/// Objects are only freed when the heap that allocated them is dropped, so a
+/// handle is only valid for as long as its heap is alive, and must not be
+/// used after that. Only the crate allocates objects and reads them through
+/// a handle, and the VM turns away objects of other heaps from hosts, with
+/// [`VM::set_global`](crate::vm::VM::set_global), and from natives. The
+/// accessors of the VM, whose heap is dropped along with it, copy the values
+/// they return out into an [`OwnedValue`](crate::value::OwnedValue) instead.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjRef(NonNull<Obj>);
+
+impl ObjRef {
+    pub(crate) fn obj(&self) -> &Obj {
+        // SAFETY: the heap never frees an object before it is dropped itself,
+        // and a handle is not used past its heap (see above)
+        unsafe { self.0.as_ref() }
+    }
+
+    pub(crate) fn obj_type(&self) -> ObjType {
+        self.obj().obj_type()
+    }
+
+    /// The address of the object, for code that isn't Rust to hold on to.
+    pub fn as_ptr(self) -> *const Obj {
+        self.0.as_ptr()
+    }
+
+    /// The handle of the object at `ptr`, or `None` if it is null.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to be from [`ObjRef::as_ptr`], of a heap that is alive.
+    pub unsafe fn from_ptr(ptr: *const Obj) -> Option<Self> {
+        NonNull::new(ptr.cast_mut()).map(Self)
+    }
+
+    /// The bytes that the object takes up, along with the buffers that it
+    /// owns, but without the objects that it refers to.
+    pub(crate) fn size(&self) -> usize {
+        let buffers = match self.obj().data() {
+            ObjData::String(string) => match &string.chars {
+                Chars::Inline { .. } => 0,
+                Chars::Heap(chars) => chars.capacity(),
+                Chars::Rope(rope) => {
+                    size_of::<Rope>()
+                        + rope.parts.len() * size_of::<ObjRef>()
+                        + rope.flat.get().map_or(0, String::capacity)
+                }
+            },
+            ObjData::Function(function) => size_of::<ObjFunction>() + function.chunk.heap_size(),
+            ObjData::Decimal(decimal) => decimal.heap_size(),
+            ObjData::Native(native) => native.name.capacity(),
+        };
+        size_of::<Obj>() + buffers
+    }
+
+    pub(crate) fn as_string(&self) -> Option<&ObjString> {
+        if self.obj_type() != ObjType::String {
+            return None;
+        }
+
+        match self.obj().data() {
+            ObjData::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_function(&self) -> Option<&ObjFunction> {
+        match self.obj().data() {
+            ObjData::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_decimal(&self) -> Option<&Decimal> {
+        match self.obj().data() {
+            ObjData::Decimal(decimal) => Some(decimal),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_native(&self) -> Option<&ObjNative> {
+        match self.obj().data() {
+            ObjData::Native(native) => Some(native),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.obj().data() {
+            ObjData::String(string) => write!(f, "String({:?})", string.as_str()),
+            ObjData::Function(function) => match function.name() {
+                Some(name) => write!(f, "<fn {}>", name),
+                None => write!(f, "<script>"),
+            },
+            ObjData::Decimal(decimal) => write!(f, "Decimal({})", decimal),
+            ObjData::Native(native) => write!(f, "<native fn {}>", native.name),
+        }
+    }
+}
+
+/// How `print` shows an object: a string as its characters, and a function
+/// by its name. A native function is shown the way the book does.
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.obj().data() {
+            ObjData::String(string) => write!(f, "{}", string.as_str()),
+            ObjData::Function(function) => match function.name() {
+                Some(name) => write!(f, "<fn {}>", name),
+                None => write!(f, "<script>"),
+            },
+            ObjData::Decimal(decimal) => write!(f, "{}", decimal),
+            ObjData::Native(_) => write!(f, "<native fn>"),
+        }
+    }
+}
+
+pub struct Heap {
+    // intrusive linked list of every object allocated by this heap, newest first
+    objects: Option<NonNull<Obj>>,
+    // every string on the heap, so that each one is only allocated once
+    strings: Table<()>,
+    // how many objects have been allocated
+    allocations: usize,
+    // how many bytes they take up, with their buffers
+    bytes: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: None,
+            strings: Table::new(),
+            allocations: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Strings are interned, so this returns the string that is already on
+    /// the heap if there is one with the same characters. Otherwise the
+    /// string takes `chars`, unless they are short enough to be kept in the
+    /// object.
+    pub(crate) fn alloc_string(&mut self, chars: String) -> ObjRef {
+        let hash = hash_string(&chars);
+        match self.strings.find_string(&chars, hash) {
+            Some(interned) => interned,
+            None => self.alloc_interned(Chars::take(chars), hash),
+        }
+    }
+
+    /// The same as [`Heap::alloc_string`], for characters that are borrowed,
+    /// which are only copied if the string is not on the heap yet.
+    pub(crate) fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let hash = hash_string(chars);
+        match self.strings.find_string(chars, hash) {
+            Some(interned) => interned,
+            None => self.alloc_interned(Chars::copy(chars), hash),
+        }
+    }
+
+    /// The string of `parts` one after another. A short one is interned
+    /// like any other string, but a longer one is a rope of the parts, which
+    /// is not interned, and only copies them once its characters are looked
+    /// at, e.g. when it's printed or compared.
+    pub(crate) fn concat(&mut self, parts: &[ObjRef]) -> ObjRef {
+        let strings = parts
+            .iter()
+            .map(|part| part.as_string().expect("ICE: Concatenating non-strings"));
+        let len = strings.clone().map(ObjString::len).sum::<usize>();
+        if len <= INLINE_CAPACITY {
+            return self.alloc_string(strings.map(ObjString::as_str).collect());
+        }
+
+        let rope = Rope {
+            parts: parts.into(),
+            len,
+            flat: OnceCell::new(),
+        };
+        self.alloc(ObjData::String(ObjString {
+            chars: Chars::Rope(Box::new(rope)),
+            hash: OnceCell::new(),
+        }))
+    }
+
+    fn alloc_interned(&mut self, chars: Chars, hash: u32) -> ObjRef {
+        let string = self.alloc(ObjData::String(ObjString {
+            chars,
+            hash: OnceCell::from(hash),
+        }));
+        self.strings.insert(string, ());
+        string
+    }
+
+    pub(crate) fn alloc_function(&mut self, function: ObjFunction) -> ObjRef {
+        self.alloc(ObjData::Function(Box::new(function)))
+    }
+
+    pub(crate) fn alloc_decimal(&mut self, decimal: Decimal) -> ObjRef {
+        self.alloc(ObjData::Decimal(decimal))
+    }
+
+    pub(crate) fn alloc_native(&mut self, native: ObjNative) -> ObjRef {
+        self.alloc(ObjData::Native(native))
+    }
+
+    fn alloc(&mut self, data: ObjData) -> ObjRef {
+        let obj = Box::new(Obj {
+            header: ObjHeader {
+                obj_type: data.obj_type(),
+                is_marked: false,
+                next: self.objects,
+            },
+            data,
+        });
+
+        let obj = NonNull::from(Box::leak(obj));
+        self.objects = Some(obj);
+        self.allocations += 1;
+        self.bytes += ObjRef(obj).size();
+        ObjRef(obj)
+    }
+
+    /// How many objects have been allocated, which is how many are on the
+    /// heap until there is a collector.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// How many bytes the objects that have been allocated take up, along
+    /// with the buffers that they own.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Whether every object among the constants of `chunk` is on this heap,
+    /// and so is every object that the functions on the heap refer to. No
+    /// object is looked at before it is known to be on the heap.
+    pub fn owns_constants(&self, chunk: &Chunk) -> bool {
+        let objects = self.iter().collect::<BTreeSet<_>>();
+        let owns = |chunk: &Chunk| {
+            chunk
+                .constants()
+                .iter()
+                .all(|constant| !matches!(constant, Value::Obj(obj) if !objects.contains(&obj)))
+        };
+
+        owns(chunk)
+            && self.iter().all(|obj| match obj.as_function() {
+                Some(function) => {
+                    owns(&function.chunk)
+                        && function.name.is_none_or(|name| objects.contains(&name))
+                }
+                None => true,
+            })
+    }
+
+    /// Whether `obj` is on this heap, which only compares its address, so a
+    /// handle from a heap that is gone can be checked without reading it.
+    pub fn owns(&self, obj: ObjRef) -> bool {
+        self.iter().any(|owned| owned == obj)
+    }
+
+    /// Walks every object on the heap, the newest first.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = ObjRef> + '_ {
+        let mut current = self.objects;
+        core::iter::from_fn(move || {
+            let obj = current?;
+            // SAFETY: every object in the list is owned by this heap
+            current = unsafe { obj.as_ref() }.header.next;
+            Some(ObjRef(obj))
+        })
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        let mut current = self.objects.take();
+        while let Some(obj) = current {
+            // SAFETY: every object in the list was leaked from a Box by
+            // `alloc` and is freed exactly once here
+            let obj = unsafe { Box::from_raw(obj.as_ptr()) };
+            current = obj.header.next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Value;
+
+    #[test]
+    fn test_heap_alloc_string() {
+        let mut heap = Heap::new();
+        let string = heap.alloc_string("hello".to_string());
+
+        assert_eq!(string.obj_type(), ObjType::String);
+        assert!(!string.obj().header.is_marked);
+        assert_eq!(string.as_string().map(ObjString::as_str), Some("hello"));
+        assert_eq!(
+            string.as_string().map(ObjString::hash),
+            Some(hash_string("hello"))
+        );
+        assert_eq!(heap.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_heap_alloc_function() {
+        let mut heap = Heap::new();
+        let name = heap.alloc_string("f".to_string());
+        let function = heap.alloc_function(ObjFunction {
+            arity: 2,
+            chunk: Chunk::new(),
+            name: Some(name),
+        });
+        let script = heap.alloc_function(ObjFunction {
+            arity: 0,
+            chunk: Chunk::new(),
+            name: None,
+        });
+
+        assert_eq!(function.obj_type(), ObjType::Function);
+        assert_eq!(function.as_function().map(|f| f.arity), Some(2));
+        assert_eq!(function.as_string(), None);
+        assert_eq!(name.as_function(), None);
+        assert_eq!(format!("{:?}", function), "<fn f>");
+        assert_eq!(format!("{:?}", script), "<script>");
+    }
+
+    #[test]
+    fn test_heap_iter() {
+        let mut heap = Heap::new();
+        assert_eq!(heap.iter().count(), 0);
+
+        let a = heap.alloc_string("a".to_string());
+        let b = heap.alloc_string("b".to_string());
+        let c = heap.alloc_string("c".to_string());
+
+        // newest objects are at the head of the list
+        assert_eq!(heap.iter().collect::<Vec<_>>(), vec![c, b, a]);
+    }
+
+    #[test]
+    fn test_heap_inline_strings() {
+        let mut heap = Heap::new();
+        let short = "a".repeat(INLINE_CAPACITY);
+        let long = "a".repeat(INLINE_CAPACITY + 1);
+        let is_inline = |string: ObjRef| string.as_string().map(ObjString::is_inline);
+
+        assert_eq!(is_inline(heap.copy_string("")), Some(true));
+        assert_eq!(is_inline(heap.copy_string(&short)), Some(true));
+        assert_eq!(is_inline(heap.copy_string(&long)), Some(false));
+        // a character that does not fit whole is not cut in half
+        let accented = format!("{}é", "a".repeat(INLINE_CAPACITY - 1));
+        let string = heap.alloc_string(accented.clone());
+        assert_eq!(is_inline(string), Some(false));
+        assert_eq!(string.as_string().map(ObjString::as_str), Some(&*accented));
+        let string = heap.alloc_string("héllo".to_string());
+        assert_eq!(string.as_string().map(ObjString::as_str), Some("héllo"));
+
+        // where the characters are kept makes no difference to interning
+        assert_eq!(heap.alloc_string(short.clone()), heap.copy_string(&short));
+        assert_eq!(heap.alloc_string(long.clone()), heap.copy_string(&long));
+    }
+
+    #[test]
+    fn test_obj_size() {
+        let mut heap = Heap::new();
+        assert_eq!(heap.copy_string("a").size(), size_of::<Obj>());
+        let long = heap.alloc_string("a".repeat(100));
+        assert!(long.size() >= size_of::<Obj>() + 100);
+
+        let mut chunk = Chunk::new();
+        chunk.constants_mut().add(Value::Nil);
+        let function = heap.alloc_function(ObjFunction {
+            arity: 0,
+            chunk,
+            name: None,
+        });
+        assert!(function.size() >= size_of::<Obj>() + size_of::<Value>());
+        // the chunk of a function is not in every object
+        assert!(size_of::<Obj>() < size_of::<Chunk>());
+    }
+
+    #[test]
+    fn test_heap_interns_strings() {
+        let mut heap = Heap::new();
+        let a = heap.alloc_string("a".to_string());
+        assert_eq!(heap.alloc_string("a".to_string()), a);
+        assert_ne!(heap.alloc_string("b".to_string()), a);
+        assert_eq!(heap.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_heap_concat() {
+        let mut heap = Heap::new();
+        let short = heap.copy_string("short");
+        let long = heap.copy_string("long enough to be kept in a buffer");
+
+        // short strings are interned as they are made
+        let twice = heap.concat(&[short, short]);
+        assert_eq!(twice, heap.copy_string("shortshort"));
+
+        // long ones are ropes, which aren't, but have the same characters
+        let rope = heap.concat(&[short, long, short]);
+        let chars = "shortlong enough to be kept in a buffershort";
+        let copy = heap.copy_string(chars);
+        assert_ne!(rope, copy);
+        assert_eq!(Value::Obj(rope), Value::Obj(copy));
+        let string = rope.as_string().expect("a string");
+        assert_eq!(string.len(), chars.len());
+        assert_eq!(string.as_str(), chars);
+        assert_eq!(string.hash(), hash_string(chars));
+        assert_eq!(string.parts(), [short, long, short]);
+
+        // a rope as deep as a long loop makes is flattened without recursing
+        let mut rope = long;
+        for _ in 0..100_000 {
+            rope = heap.concat(&[rope, short]);
+        }
+        let string = rope.as_string().expect("a string");
+        assert_eq!(
+            string.len(),
+            long.as_string().expect("a string").len() + 500_000
+        );
+        assert!(string.as_str().ends_with("shortshort"));
+    }
+}
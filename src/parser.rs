@@ -0,0 +1,908 @@
+use alloc::{borrow::Cow, boxed::Box, vec, vec::Vec};
+use core::mem;
+
+use crate::{
+    ast::{Expr, Function, Literal, Program, Stmt},
+    diagnostic::{Diagnostic, Severity},
+    scanner::{Scanner, Token, TokenKind},
+};
+
+// how deeply expressions and statements can be nested inside of each other,
+// so that parsing them, and every pass over the tree after it, does not
+// overflow the stack
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn plus_one(&self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Call,
+            Precedence::Call => Precedence::Primary,
+            Precedence::Primary => {
+                // nothing higher than Primary
+                Precedence::Primary
+            }
+        }
+    }
+}
+
+pub struct Parser<'src> {
+    scanner: Scanner<'src>,
+    previous: Token<'src>,
+    current: Token<'src>,
+    // whether the error has appeared at any time in the parse
+    had_error: bool,
+    // once the parser encounters an error, panic mode is enabled and error
+    // recovery is attempted. Once error recovery is done, this is set back
+    // to false. Hence, this boolean cannot tell whether an error happened in the
+    // code at all. For that, use `had_error` instead.
+    panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
+    // how many expressions and statements are being parsed inside of each
+    // other
+    depth: usize,
+}
+
+impl<'src> Parser<'src> {
+    pub fn new(source: &'src str) -> Self {
+        Self {
+            scanner: Scanner::new(source),
+            previous: Token {
+                kind: TokenKind::Error,
+                lexeme: "Nothing is read yet.",
+                line: 0,
+                column: 0,
+                start: 0,
+                end: 0,
+            },
+            current: Token {
+                kind: TokenKind::Error,
+                lexeme: "Nothing is read yet.",
+                line: 0,
+                column: 0,
+                start: 0,
+                end: 0,
+            },
+            had_error: false,
+            panic_mode: false,
+            diagnostics: vec![],
+            depth: 0,
+        }
+    }
+
+    /// Parses the whole source. A declaration with a syntax error is left out
+    /// of the program, and the error is kept in the diagnostics instead.
+    pub fn parse(&mut self) -> Program<'src> {
+        let mut statements = vec![];
+
+        self.advance();
+        while !self.check(TokenKind::EndOfFile) {
+            statements.extend(self.declaration());
+        }
+
+        // the end of the source is usually after a newline, on a line of its
+        // own, while nothing is read yet in a source without any tokens
+        let end = match self.previous.kind {
+            TokenKind::Error => self.current.clone(),
+            _ if self.previous.line == self.current.line => self.current.clone(),
+            _ => self.previous.clone(),
+        };
+        Program { statements, end }
+    }
+
+    pub fn had_error(&self) -> bool {
+        self.had_error
+    }
+
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        mem::take(&mut self.diagnostics)
+    }
+
+    fn advance(&mut self) {
+        self.previous = self.current.clone();
+
+        loop {
+            self.current = self.scanner.scan_token();
+            if matches!(self.current.kind, TokenKind::Error) {
+                let message = self.current.lexeme;
+                self.error_at_current(message);
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn consume<S: AsRef<str>>(&mut self, token_kind: TokenKind, message: S) {
+        if self.current.kind == token_kind {
+            self.advance();
+        } else {
+            self.error_at_current(message);
+        }
+    }
+
+    fn check(&self, token_kind: TokenKind) -> bool {
+        self.current.kind == token_kind
+    }
+
+    fn match_token(&mut self, token_kind: TokenKind) -> bool {
+        if self.check(token_kind) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn binary(&mut self, left: Expr<'src>) -> Expr<'src> {
+        let operator = self.previous.clone();
+        let right = self.parse_precedence(self.get_rule_precedence(operator.kind).plus_one());
+
+        Expr::Binary {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn call(&mut self, callee: Expr<'src>) -> Expr<'src> {
+        let paren = self.previous.clone();
+        let arguments = self.argument_list();
+
+        Expr::Call {
+            callee: Box::new(callee),
+            paren,
+            arguments,
+            close: self.previous.clone(),
+        }
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr<'src>> {
+        let mut arguments = vec![];
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                arguments.push(self.expression());
+                if arguments.len() == 256 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after arguments.");
+
+        arguments
+    }
+
+    fn literal(&mut self) -> Expr<'src> {
+        let token = self.previous.clone();
+        let value = match token.kind {
+            TokenKind::False => Literal::False,
+            TokenKind::True => Literal::True,
+            TokenKind::Nil => Literal::Nil,
+            _ => {
+                panic!("ICE: Unhandled literal");
+            }
+        };
+
+        Expr::Literal { value, token }
+    }
+
+    fn grouping(&mut self) -> Expr<'src> {
+        let open = self.previous.clone();
+        let expr = self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after expression.");
+
+        Expr::Grouping {
+            open,
+            expr: Box::new(expr),
+            close: self.previous.clone(),
+        }
+    }
+
+    fn number(&mut self) -> Expr<'src> {
+        let token = self.previous.clone();
+        let value = token
+            .lexeme
+            .parse::<f64>()
+            .expect("ICE: Non-number stored in number token?");
+
+        Expr::Number { value, token }
+    }
+
+    fn string(&mut self) -> Expr<'src> {
+        let token = self.previous.clone();
+        // trim the leading and trailing quotation marks, and the `r` of a raw
+        // string, whose doubled quotation marks stand for one
+        let value = match token.kind {
+            TokenKind::RawString => {
+                let value = &token.lexeme[2..(token.lexeme.len() - 1)];
+                if value.contains("\"\"") {
+                    Cow::Owned(value.replace("\"\"", "\""))
+                } else {
+                    Cow::Borrowed(value)
+                }
+            }
+            _ => Cow::Borrowed(&token.lexeme[1..(token.lexeme.len() - 1)]),
+        };
+
+        Expr::String { value, token }
+    }
+
+    fn unary(&mut self) -> Expr<'src> {
+        let operator = self.previous.clone();
+        let operand = self.parse_precedence(Precedence::Unary);
+
+        Expr::Unary {
+            operator,
+            operand: Box::new(operand),
+        }
+    }
+
+    fn logical(&mut self, left: Expr<'src>, precedence: Precedence) -> Expr<'src> {
+        let operator = self.previous.clone();
+        let right = self.parse_precedence(precedence);
+
+        Expr::Logical {
+            operator,
+            left: Box::new(left),
+            right: Box::new(right),
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr<'src> {
+        let name = self.previous.clone();
+
+        if can_assign && self.match_token(TokenKind::Equal) {
+            let equal = self.previous.clone();
+            let value = self.expression();
+            Expr::Assign {
+                name,
+                equal,
+                value: Box::new(value),
+            }
+        } else {
+            Expr::Variable { name }
+        }
+    }
+
+    fn expression(&mut self) -> Expr<'src> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    // parses declarations until the closing brace, which is left for the
+    // caller to consume
+    fn block(&mut self) -> Vec<Stmt<'src>> {
+        let mut statements = vec![];
+
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::EndOfFile) {
+            statements.extend(self.declaration());
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after block.");
+        statements
+    }
+
+    fn fun_declaration(&mut self) -> Stmt<'src> {
+        let keyword = self.previous.clone();
+        self.consume(TokenKind::Identifier, "Expect function name.");
+        let name = self.previous.clone();
+
+        let mut params = vec![];
+        self.consume(TokenKind::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if params.len() == 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+
+                self.consume(TokenKind::Identifier, "Expect parameter name.");
+                params.push(self.previous.clone());
+
+                if !self.match_token(TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
+        let body = self.block();
+
+        Stmt::Function(Function {
+            keyword,
+            name,
+            params,
+            body,
+            close: self.previous.clone(),
+        })
+    }
+
+    fn var_declaration(&mut self) -> Stmt<'src> {
+        let keyword = self.previous.clone();
+        self.consume(TokenKind::Identifier, "Expect variable name.");
+        let name = self.previous.clone();
+
+        let initializer = if self.match_token(TokenKind::Equal) {
+            Some(self.expression())
+        } else {
+            None
+        };
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        Stmt::Var {
+            keyword,
+            name,
+            initializer,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn expression_statement(&mut self) -> Stmt<'src> {
+        let expr = self.expression();
+        self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
+
+        Stmt::Expression {
+            expr,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn for_statement(&mut self) -> Stmt<'src> {
+        let keyword = self.previous.clone();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
+
+        let initializer = if self.match_token(TokenKind::Semicolon) {
+            None
+        } else if self.match_token(TokenKind::Var) {
+            Some(Box::new(self.var_declaration()))
+        } else {
+            Some(Box::new(self.expression_statement()))
+        };
+
+        let mut condition = None;
+        if !self.match_token(TokenKind::Semicolon) {
+            condition = Some(self.expression());
+            self.consume(TokenKind::Semicolon, "Expect ';' after loop condition.");
+        }
+        let semicolon = self.previous.clone();
+
+        let mut increment = None;
+        if !self.match_token(TokenKind::RightParen) {
+            increment = Some(self.expression());
+            self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
+        }
+        let paren = self.previous.clone();
+
+        Stmt::For {
+            keyword,
+            initializer,
+            condition,
+            semicolon,
+            increment,
+            paren,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn if_statement(&mut self) -> Stmt<'src> {
+        let keyword = self.previous.clone();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+        let paren = self.previous.clone();
+
+        let then_branch = Box::new(self.statement());
+        let else_branch = if self.match_token(TokenKind::Else) {
+            Some(Box::new(self.statement()))
+        } else {
+            None
+        };
+
+        Stmt::If {
+            keyword,
+            condition,
+            paren,
+            then_branch,
+            else_branch,
+        }
+    }
+
+    fn return_statement(&mut self) -> Stmt<'src> {
+        let keyword = self.previous.clone();
+
+        let value = if self.match_token(TokenKind::Semicolon) {
+            None
+        } else {
+            let value = self.expression();
+            self.consume(TokenKind::Semicolon, "Expect ';' after return value.");
+            Some(value)
+        };
+
+        Stmt::Return {
+            keyword,
+            value,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    fn while_statement(&mut self) -> Stmt<'src> {
+        let keyword = self.previous.clone();
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.");
+        let condition = self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after condition.");
+        let paren = self.previous.clone();
+
+        Stmt::While {
+            keyword,
+            condition,
+            paren,
+            body: Box::new(self.statement()),
+        }
+    }
+
+    fn print_statement(&mut self) -> Stmt<'src> {
+        let keyword = self.previous.clone();
+        let expr = self.expression();
+        self.consume(TokenKind::Semicolon, "Expect ';' after value.");
+
+        Stmt::Print {
+            keyword,
+            expr,
+            semicolon: self.previous.clone(),
+        }
+    }
+
+    // skips tokens until we reach something that looks like a statement
+    // boundary, so that an error in one statement does not hide the errors
+    // in the statements after it
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.kind != TokenKind::EndOfFile {
+            if self.previous.kind == TokenKind::Semicolon {
+                return;
+            }
+
+            match self.current.kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => {
+                    return;
+                }
+                _ => {
+                    self.advance();
+                }
+            }
+        }
+    }
+
+    // returns `None` if the declaration has a syntax error
+    fn declaration(&mut self) -> Option<Stmt<'src>> {
+        let declaration = if self.match_token(TokenKind::Fun) {
+            self.fun_declaration()
+        } else if self.match_token(TokenKind::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+            return None;
+        }
+
+        Some(declaration)
+    }
+
+    fn statement(&mut self) -> Stmt<'src> {
+        if !self.enter() {
+            return Stmt::Expression {
+                expr: self.too_deep(),
+                semicolon: self.previous.clone(),
+            };
+        }
+        let statement = self.statement_inner();
+        self.depth -= 1;
+        statement
+    }
+
+    fn statement_inner(&mut self) -> Stmt<'src> {
+        if self.match_token(TokenKind::Print) {
+            self.print_statement()
+        } else if self.match_token(TokenKind::For) {
+            self.for_statement()
+        } else if self.match_token(TokenKind::If) {
+            self.if_statement()
+        } else if self.match_token(TokenKind::Return) {
+            self.return_statement()
+        } else if self.match_token(TokenKind::While) {
+            self.while_statement()
+        } else if self.match_token(TokenKind::LeftBrace) {
+            let open = self.previous.clone();
+            let statements = self.block();
+            Stmt::Block {
+                open,
+                statements,
+                close: self.previous.clone(),
+            }
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr<'src> {
+        let depth = self.depth;
+        if !self.enter() {
+            return self.too_deep();
+        }
+
+        self.advance();
+        let can_assign = precedence <= Precedence::Assignment;
+        let mut expr = self.do_rule_prefix(self.previous.kind, can_assign);
+
+        while precedence <= self.get_rule_precedence(self.current.kind) {
+            // a call nests the callee one level deeper, but operators are
+            // left out, since a chain like `a + b + c` is compiled in a loop
+            if self.current.kind == TokenKind::LeftParen && !self.enter() {
+                self.too_deep();
+                break;
+            }
+            self.advance();
+            expr = self.do_rule_infix(self.previous.kind, expr);
+        }
+
+        if can_assign && self.match_token(TokenKind::Equal) {
+            self.error_with_help(
+                "Invalid assignment target.",
+                "only variables can be assigned to",
+            );
+        }
+
+        self.depth = depth;
+        expr
+    }
+
+    // goes one level deeper, unless it would be too deep
+    fn enter(&mut self) -> bool {
+        if self.depth == MAX_DEPTH {
+            return false;
+        }
+        self.depth += 1;
+        true
+    }
+
+    // reports that the source is nested too deeply, skipping the token that
+    // would have gone deeper so that the parser always moves on
+    fn too_deep(&mut self) -> Expr<'src> {
+        self.error_at_current("Too deeply nested.");
+        self.advance();
+        // a stand-in, since the declaration is thrown away anyway
+        Expr::Literal {
+            value: Literal::Nil,
+            token: self.previous.clone(),
+        }
+    }
+
+    fn get_rule_precedence(&self, kind: TokenKind) -> Precedence {
+        match kind {
+            TokenKind::Minus | TokenKind::Plus => Precedence::Term,
+            TokenKind::Slash | TokenKind::Star => Precedence::Factor,
+            TokenKind::BangEqual | TokenKind::EqualEqual => Precedence::Equality,
+            TokenKind::Greater
+            | TokenKind::GreaterEqual
+            | TokenKind::Less
+            | TokenKind::LessEqual => Precedence::Comparison,
+            TokenKind::And => Precedence::And,
+            TokenKind::Or => Precedence::Or,
+            TokenKind::LeftParen => Precedence::Call,
+            _ => Precedence::None,
+        }
+    }
+
+    fn do_rule_prefix(&mut self, kind: TokenKind, can_assign: bool) -> Expr<'src> {
+        match kind {
+            TokenKind::LeftParen => self.grouping(),
+            TokenKind::Minus | TokenKind::Bang => self.unary(),
+            TokenKind::Number => self.number(),
+            TokenKind::String | TokenKind::RawString => self.string(),
+            TokenKind::Identifier => self.variable(can_assign),
+            TokenKind::False | TokenKind::True | TokenKind::Nil => self.literal(),
+            _ => {
+                self.error("Expect expression.");
+                // a stand-in, since the declaration is thrown away anyway
+                Expr::Literal {
+                    value: Literal::Nil,
+                    token: self.previous.clone(),
+                }
+            }
+        }
+    }
+
+    fn do_rule_infix(&mut self, kind: TokenKind, left: Expr<'src>) -> Expr<'src> {
+        match kind {
+            TokenKind::Minus
+            | TokenKind::Plus
+            | TokenKind::Slash
+            | TokenKind::Star
+            | TokenKind::BangEqual
+            | TokenKind::EqualEqual
+            | TokenKind::Greater
+            | TokenKind::GreaterEqual
+            | TokenKind::Less
+            | TokenKind::LessEqual => self.binary(left),
+            TokenKind::And => self.logical(left, Precedence::And),
+            TokenKind::Or => self.logical(left, Precedence::Or),
+            TokenKind::LeftParen => self.call(left),
+            _ => {
+                self.error("Expect expression.");
+                left
+            }
+        }
+    }
+
+    fn error_at_current<S: AsRef<str>>(&mut self, message: S) {
+        let token = self.current.clone();
+        self.error_at(&token, message, None);
+    }
+
+    fn error<S: AsRef<str>>(&mut self, message: S) {
+        let token = self.previous.clone();
+        self.error_at(&token, message, None);
+    }
+
+    fn error_with_help<S: AsRef<str>>(&mut self, message: S, help: &str) {
+        let token = self.previous.clone();
+        self.error_at(&token, message, Some(help));
+    }
+
+    fn error_at<S: AsRef<str>>(&mut self, token: &Token, message: S, help: Option<&str>) {
+        if self.panic_mode {
+            // prevent error cascade
+            return;
+        }
+
+        self.panic_mode = true;
+        self.diagnostics
+            .push(Diagnostic::at_token(Severity::Error, token, message, help));
+        self.had_error = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a token on the first line of the source
+    fn token(kind: TokenKind, lexeme: &str, column: usize) -> Token<'_> {
+        Token {
+            kind,
+            lexeme,
+            line: 1,
+            column,
+            start: column - 1,
+            end: column - 1 + lexeme.len(),
+        }
+    }
+
+    #[test]
+    fn test_parser_synchronize() {
+        let mut parser = Parser::new("print 1 + ; print 2; var = 3; print 4;");
+
+        parser.advance();
+        assert_eq!(parser.declaration(), None);
+        assert!(parser.had_error);
+        // the parser recovered at the start of the next statement
+        assert!(!parser.panic_mode);
+        assert_eq!(parser.current.kind, TokenKind::Print);
+
+        assert!(parser.declaration().is_some());
+        assert!(!parser.panic_mode);
+
+        assert_eq!(parser.declaration(), None);
+        assert!(!parser.panic_mode);
+        assert_eq!(parser.current.kind, TokenKind::Print);
+
+        assert_eq!(parser.take_diagnostics().len(), 2);
+    }
+
+    #[test]
+    fn test_parser_parse() {
+        let mut parser = Parser::new("print -a + 2 * (b = 3);");
+        let program = parser.parse();
+
+        assert!(!parser.had_error());
+        assert_eq!(
+            program,
+            Program {
+                statements: vec![Stmt::Print {
+                    keyword: token(TokenKind::Print, "print", 1),
+                    expr: Expr::Binary {
+                        operator: token(TokenKind::Plus, "+", 10),
+                        left: Box::new(Expr::Unary {
+                            operator: token(TokenKind::Minus, "-", 7),
+                            operand: Box::new(Expr::Variable {
+                                name: token(TokenKind::Identifier, "a", 8),
+                            }),
+                        }),
+                        right: Box::new(Expr::Binary {
+                            operator: token(TokenKind::Star, "*", 14),
+                            left: Box::new(Expr::Number {
+                                value: 2.0,
+                                token: token(TokenKind::Number, "2", 12),
+                            }),
+                            right: Box::new(Expr::Grouping {
+                                open: token(TokenKind::LeftParen, "(", 16),
+                                expr: Box::new(Expr::Assign {
+                                    name: token(TokenKind::Identifier, "b", 17),
+                                    equal: token(TokenKind::Equal, "=", 19),
+                                    value: Box::new(Expr::Number {
+                                        value: 3.0,
+                                        token: token(TokenKind::Number, "3", 21),
+                                    }),
+                                }),
+                                close: token(TokenKind::RightParen, ")", 22),
+                            }),
+                        }),
+                    },
+                    semicolon: token(TokenKind::Semicolon, ";", 23),
+                }],
+                end: token(TokenKind::EndOfFile, "", 24),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parser_statements() {
+        let mut parser = Parser::new(
+            "fun f(a, b) { return a or b; } for (var i = 0; i < 2;) if (f(i, nil)) {} else print i;",
+        );
+        let program = parser.parse();
+        assert!(!parser.had_error());
+
+        let [
+            Stmt::Function(function),
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            },
+        ] = program.statements.as_slice()
+        else {
+            panic!("unexpected statements: {:?}", program.statements);
+        };
+
+        assert_eq!(function.name.lexeme, "f");
+        assert_eq!(
+            function
+                .params
+                .iter()
+                .map(|param| param.lexeme)
+                .collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert!(matches!(
+            function.body.as_slice(),
+            [Stmt::Return {
+                value: Some(Expr::Logical { .. }),
+                ..
+            }]
+        ));
+        assert_eq!(function.close.column, 30);
+
+        assert!(matches!(initializer.as_deref(), Some(Stmt::Var { .. })));
+        assert!(matches!(condition, Some(Expr::Binary { .. })));
+        assert_eq!(increment, &None);
+        let Stmt::If {
+            condition: Expr::Call { arguments, .. },
+            else_branch: Some(else_branch),
+            ..
+        } = body.as_ref()
+        else {
+            panic!("unexpected body: {:?}", body);
+        };
+        assert_eq!(arguments.len(), 2);
+        assert!(matches!(else_branch.as_ref(), Stmt::Print { .. }));
+        assert_eq!(body.first_token().lexeme, "if");
+        assert_eq!(body.last_token().column, 86);
+    }
+
+    #[test]
+    fn test_parser_strings() {
+        let mut parser = Parser::new(r#"print r"a\n""b""";"#);
+        let program = parser.parse();
+        assert!(!parser.had_error());
+
+        let [
+            Stmt::Print {
+                expr: Expr::String { value, .. },
+                ..
+            },
+        ] = program.statements.as_slice()
+        else {
+            panic!("unexpected statements: {:?}", program.statements);
+        };
+        assert_eq!(value, r#"a\n"b""#);
+
+        // the value of a string without doubled quotation marks is a slice
+        // of the source
+        let program = Parser::new(r#"print "a"; print r"b";"#).parse();
+        for stmt in &program.statements {
+            let Stmt::Print {
+                expr: Expr::String { value, .. },
+                ..
+            } = stmt
+            else {
+                panic!("unexpected statement: {:?}", stmt);
+            };
+            assert!(matches!(value, Cow::Borrowed(_)));
+        }
+    }
+
+    #[test]
+    fn test_parser_errors() {
+        fn errors(source: &str) -> Vec<String> {
+            let mut parser = Parser::new(source);
+            parser.parse();
+            parser
+                .take_diagnostics()
+                .into_iter()
+                .map(|diagnostic| diagnostic.message)
+                .collect()
+        }
+
+        assert_eq!(errors("print 1 = 2;"), vec!["Invalid assignment target."]);
+        assert_eq!(
+            errors("{ print 1 print 2; } var;"),
+            vec!["Expect ';' after value.", "Expect variable name."]
+        );
+        // nesting is limited, but a long chain of operators is not
+        let deep = format!("print {}1{};", "(".repeat(200), ")".repeat(200));
+        assert_eq!(errors(&deep), vec!["Too deeply nested."]);
+        assert_eq!(
+            errors(&format!("{}{}", "{".repeat(200), "}".repeat(200)))[0],
+            "Too deeply nested."
+        );
+        assert!(errors(&format!("print {}1;", "1 + ".repeat(100_000))).is_empty());
+        // a broken declaration is left out, the rest of the program is kept
+        let mut parser = Parser::new("var a = ; print 1;");
+        let program = parser.parse();
+        assert!(parser.had_error());
+        assert!(matches!(
+            program.statements.as_slice(),
+            [Stmt::Print { .. }]
+        ));
+    }
+}
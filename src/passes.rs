@@ -0,0 +1,278 @@
+use alloc::{vec, vec::Vec};
+
+use crate::{
+    chunk::OpCode,
+    ir::{Block, Function, Pass, PassManager},
+    relocate::Instruction,
+};
+
+/// The passes that are run over every function when optimizing.
+pub fn pipeline() -> PassManager {
+    PassManager::new()
+        .add(RemoveUnreachableBlocks)
+        .add(RemoveDeadStores)
+}
+
+/// A set of local slots.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct SlotSet([u64; 4]);
+
+impl SlotSet {
+    pub fn insert(&mut self, slot: u8) {
+        self.0[slot as usize / 64] |= 1 << (slot % 64);
+    }
+
+    pub fn remove(&mut self, slot: u8) {
+        self.0[slot as usize / 64] &= !(1 << (slot % 64));
+    }
+
+    pub fn contains(&self, slot: u8) -> bool {
+        self.0[slot as usize / 64] & (1 << (slot % 64)) != 0
+    }
+
+    pub fn union(&self, other: &SlotSet) -> SlotSet {
+        let mut union = *self;
+        union
+            .0
+            .iter_mut()
+            .zip(other.0)
+            .for_each(|(words, other)| *words |= other);
+        union
+    }
+}
+
+// the local slot that `instruction` reads, and the one that it writes
+fn slot_access(instruction: &Instruction) -> (Option<u8>, Option<u8>) {
+    match instruction.op {
+        OpCode::GetLocal | OpCode::GetLocalAddConstant => (Some(instruction.operands[0]), None),
+        OpCode::SetLocal => (None, Some(instruction.operands[0])),
+        _ => (None, None),
+    }
+}
+
+// the locals that are live before `block`, given the ones live after it
+fn live_before(block: &Block, mut live: SlotSet) -> SlotSet {
+    for instruction in block.instructions.iter().rev() {
+        if instruction.removed {
+            continue;
+        }
+
+        let (read, written) = slot_access(instruction);
+        if let Some(slot) = written {
+            live.remove(slot);
+        }
+        if let Some(slot) = read {
+            live.insert(slot);
+        }
+    }
+    live
+}
+
+/// The locals that are live at the start of each block, that is, the ones
+/// that might still be read before they are assigned to again.
+pub fn live_locals(function: &Function) -> Vec<SlotSet> {
+    let predecessors = function.predecessors();
+    let mut live_in = vec![SlotSet::default(); function.blocks.len()];
+
+    // a block has to be looked at again whenever a block after it changes
+    let mut worklist = (0..function.blocks.len()).collect::<Vec<_>>();
+    while let Some(id) = worklist.pop() {
+        let block = &function.blocks[id];
+        if block.removed {
+            continue;
+        }
+
+        let live = live_before(block, live_after(function, &live_in, id));
+        if live != live_in[id] {
+            live_in[id] = live;
+            worklist.extend(&predecessors[id]);
+        }
+    }
+
+    live_in
+}
+
+// the locals that are live at the end of block `id`
+fn live_after(function: &Function, live_in: &[SlotSet], id: usize) -> SlotSet {
+    function.blocks[id]
+        .terminator
+        .successors()
+        .iter()
+        .fold(SlotSet::default(), |live, &successor| {
+            live.union(&live_in[successor])
+        })
+}
+
+/// Removes the blocks that can never be reached, e.g. the jump over an else
+/// branch when the then branch always returns.
+pub struct RemoveUnreachableBlocks;
+
+impl Pass for RemoveUnreachableBlocks {
+    fn run(&mut self, function: &mut Function) -> bool {
+        let reachable = function.reachable();
+        let mut changed = false;
+
+        for (block, reachable) in function.blocks.iter_mut().zip(reachable) {
+            if !block.removed && !reachable {
+                block.removed = true;
+                changed = true;
+            }
+        }
+
+        changed
+    }
+}
+
+/// Removes assignments to locals that are never read afterwards. The value
+/// that was assigned stays on the stack, since it is also the value of the
+/// assignment expression.
+pub struct RemoveDeadStores;
+
+impl Pass for RemoveDeadStores {
+    fn run(&mut self, function: &mut Function) -> bool {
+        let live_in = live_locals(function);
+        let mut changed = false;
+
+        for id in 0..function.blocks.len() {
+            if function.blocks[id].removed {
+                continue;
+            }
+
+            let mut live = live_after(function, &live_in, id);
+            for instruction in function.blocks[id].instructions.iter_mut().rev() {
+                if instruction.removed {
+                    continue;
+                }
+
+                let (read, written) = slot_access(instruction);
+                if let Some(slot) = written {
+                    if !live.contains(slot) {
+                        instruction.removed = true;
+                        changed = true;
+                        continue;
+                    }
+                    live.remove(slot);
+                }
+                if let Some(slot) = read {
+                    live.insert(slot);
+                }
+            }
+        }
+
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Chunk;
+
+    const GET_LOCAL: u8 = OpCode::GetLocal as u8;
+    const JUMP: u8 = OpCode::Jump as u8;
+    const JUMP_IF_FALSE: u8 = OpCode::JumpIfFalse as u8;
+    const LOOP: u8 = OpCode::Loop as u8;
+    const NIL: u8 = OpCode::Nil as u8;
+    const POP: u8 = OpCode::Pop as u8;
+    const PRINT: u8 = OpCode::Print as u8;
+    const RETURN: u8 = OpCode::Return as u8;
+    const SET_LOCAL: u8 = OpCode::SetLocal as u8;
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        code.iter().for_each(|byte| chunk.write(*byte, 1, 1));
+        chunk
+    }
+
+    fn optimized(code: &[u8]) -> Chunk {
+        let mut chunk = chunk(code);
+        pipeline().run_on_chunk(&mut chunk);
+        chunk
+    }
+
+    fn slots(slots: &[u8]) -> SlotSet {
+        let mut set = SlotSet::default();
+        slots.iter().for_each(|slot| set.insert(*slot));
+        set
+    }
+
+    #[test]
+    fn test_passes_slot_set() {
+        let mut set = slots(&[1, 200]);
+        assert!(set.contains(1) && set.contains(200) && !set.contains(2));
+        set.remove(200);
+        assert_eq!(set, slots(&[1]));
+        assert_eq!(set.union(&slots(&[64])), slots(&[1, 64]));
+    }
+
+    #[test]
+    fn test_passes_live_locals() {
+        // { var a; var b; while (a) { b = a; } print b; }, where b is not
+        // live inside of the loop, since it is assigned before it is read
+        let code = [
+            NIL,
+            NIL,
+            GET_LOCAL,
+            1,
+            JUMP_IF_FALSE,
+            8,
+            POP,
+            GET_LOCAL,
+            1,
+            SET_LOCAL,
+            2,
+            POP,
+            LOOP,
+            12,
+            POP,
+            GET_LOCAL,
+            2,
+            PRINT,
+            NIL,
+            RETURN,
+        ];
+        let function = Function::from_chunk(&chunk(&code)).expect("well formed");
+        assert_eq!(
+            live_locals(&function),
+            vec![slots(&[1, 2]), slots(&[1, 2]), slots(&[1]), slots(&[2])]
+        );
+        // b is read after the loop, so none of the assignments are dead
+        assert_eq!(optimized(&code), chunk(&code));
+    }
+
+    #[test]
+    fn test_passes_remove_dead_stores() {
+        // { var a; a = nil; a = nil; print a; }
+        let code = [
+            NIL, NIL, SET_LOCAL, 1, POP, NIL, SET_LOCAL, 1, POP, GET_LOCAL, 1, PRINT, POP, NIL,
+            RETURN,
+        ];
+        let expected = [
+            NIL, NIL, POP, NIL, SET_LOCAL, 1, POP, GET_LOCAL, 1, PRINT, POP, NIL, RETURN,
+        ];
+        assert_eq!(optimized(&code), chunk(&expected));
+    }
+
+    #[test]
+    fn test_passes_remove_unreachable_blocks() {
+        // if (nil) return; else return; with the jump over the else branch
+        // left behind the first return
+        let code = [
+            NIL,
+            JUMP_IF_FALSE,
+            5,
+            POP,
+            NIL,
+            RETURN,
+            JUMP,
+            3,
+            POP,
+            NIL,
+            RETURN,
+            NIL,
+            RETURN,
+        ];
+        let expected = [NIL, JUMP_IF_FALSE, 3, POP, NIL, RETURN, POP, NIL, RETURN];
+        assert_eq!(optimized(&code), chunk(&expected));
+    }
+}
@@ -0,0 +1,619 @@
+use alloc::vec;
+
+use crate::{
+    chunk::{Chunk, OpCode},
+    instruction::{self, MAX_VARINT_LEN, read_varint, write_varint},
+    relocate::{self, Instruction, is_targeted, jump_targets, next, resolve},
+    value::Value,
+};
+
+// the most values that an OP_CONCAT_N adds, as its count is padded to a byte
+// for every OP_ADD, and a longer chain is fused into several
+const MAX_CONCAT_N: usize = MAX_VARINT_LEN + 1;
+
+/// Rewrites small patterns in `chunk` into shorter or cheaper bytecode that
+/// does the same thing:
+///
+/// - `Not Not` after an instruction that already produces a boolean
+/// - a constant or a local that is pushed only to be popped right away
+/// - `Negate` of a number constant, or of `One` and `MinusOne`
+/// - a jump that lands on another jump goes straight to where that one goes
+/// - a jump to the very next instruction
+pub fn optimize(chunk: &mut Chunk) {
+    let Some(mut instructions) = relocate::decode(chunk, &[]) else {
+        return;
+    };
+
+    while rewrite(chunk, &mut instructions) {}
+
+    relocate::encode(chunk, &instructions);
+}
+
+/// Fuses instruction sequences that are common in arithmetic into a single
+/// superinstruction, so that the VM dispatches fewer instructions for them:
+///
+/// - `Add X Add` into `X ConcatN(3)`, and so on for longer chains, where `X`
+///   pushes a constant or a local, and one of the values is a string constant
+/// - `GetLocal Constant Add` into `GetLocalAddConstant`
+/// - `Constant Add` into `AddConstant`
+///
+/// where the constant can also be one of the small constants, e.g. `One`.
+pub fn fuse(chunk: &mut Chunk) {
+    let Some(mut instructions) = relocate::decode(chunk, &[]) else {
+        return;
+    };
+    let targets = jump_targets(&instructions);
+
+    // the chains of additions go first, so that their constants are not
+    // taken by `AddConstant`
+    let mut before = None;
+    for i in 0..instructions.len() {
+        if instructions[i].removed {
+            continue;
+        }
+        let pushed = before.replace(i);
+        if instructions[i].op != OpCode::Add || is_targeted(&instructions, &targets, i) {
+            continue;
+        }
+
+        // pushing a constant or a local can neither fail nor be seen by
+        // anything, so the additions before it can wait until after it
+        let mut additions = vec![i];
+        let mut pushes = vec![];
+        while additions.len() < MAX_CONCAT_N - 1 {
+            let last = additions[additions.len() - 1];
+            let Some(j) = next(&instructions, last) else {
+                break;
+            };
+            let Some(k) = next(&instructions, j) else {
+                break;
+            };
+            if instructions[k].op != OpCode::Add
+                || !is_pure_push(instructions[j].op)
+                || is_targeted(&instructions, &targets, j)
+                || is_targeted(&instructions, &targets, k)
+            {
+                break;
+            }
+            pushes.push(j);
+            additions.push(k);
+        }
+
+        // numbers are added just as fast one OP_ADD at a time, so only a
+        // chain that builds a string is worth it
+        let is_string = pushes
+            .iter()
+            .chain(&pushed)
+            .any(|&n| is_string_constant(chunk, &instructions[n]));
+        if pushes.is_empty() || !is_string {
+            continue;
+        }
+
+        // a runtime error is reported at the `+` that fails, from the byte
+        // of the operand that has its span
+        let operand_spans = additions.iter().map(|&n| instructions[n].span).collect();
+        additions[..additions.len() - 1]
+            .iter()
+            .for_each(|&n| instructions[n].removed = true);
+        let last = additions[additions.len() - 1];
+        let count = additions.len() as u32 + 1;
+        instructions[last] = Instruction {
+            op: OpCode::ConcatN,
+            operands: instruction::Instruction::ConcatN(count).operands(),
+            span: instructions[last].span,
+            operand_spans,
+            target: None,
+            removed: false,
+        };
+    }
+
+    for i in 0..instructions.len() {
+        if instructions[i].removed {
+            continue;
+        }
+        let Some(j) = next(&instructions, i) else {
+            break;
+        };
+        let k = next(&instructions, j);
+
+        if let Some(k) = k
+            && instructions[i].op == OpCode::GetLocal
+            && instructions[k].op == OpCode::Add
+            && !is_targeted(&instructions, &targets, j)
+            && !is_targeted(&instructions, &targets, k)
+            && let Some(constant) = fusable_constant(chunk, &instructions[j])
+        {
+            // runtime errors are reported at the OP_ADD
+            let mut operands = vec![instructions[i].operands[0]];
+            write_varint(&mut operands, constant, 1);
+            instructions[i] = Instruction {
+                op: OpCode::GetLocalAddConstant,
+                operands,
+                span: instructions[k].span,
+                operand_spans: vec![],
+                target: None,
+                removed: false,
+            };
+            instructions[j].removed = true;
+            instructions[k].removed = true;
+        } else if instructions[j].op == OpCode::Add
+            && !is_targeted(&instructions, &targets, j)
+            && let Some(constant) = fusable_constant(chunk, &instructions[i])
+        {
+            let mut operands = vec![];
+            write_varint(&mut operands, constant, 1);
+            instructions[i] = Instruction {
+                op: OpCode::AddConstant,
+                operands,
+                span: instructions[j].span,
+                operand_spans: vec![],
+                target: None,
+                removed: false,
+            };
+            instructions[j].removed = true;
+        }
+    }
+
+    relocate::encode(chunk, &instructions);
+}
+
+// whether `instruction` loads a string from the constants
+fn is_string_constant(chunk: &Chunk, instruction: &Instruction) -> bool {
+    constant_index(instruction)
+        .and_then(|index| chunk.constants().try_get(index))
+        .is_some_and(|constant| constant.as_string().is_some())
+}
+
+fn is_pure_push(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Zero
+            | OpCode::One
+            | OpCode::MinusOne
+            | OpCode::GetLocal
+    )
+}
+
+// the index of the constant that `instruction` loads, if it loads one. the
+// superinstructions only take constants from the pool, so a small constant is
+// added to it
+fn fusable_constant(chunk: &mut Chunk, instruction: &Instruction) -> Option<u32> {
+    let number: f64 = match instruction.op {
+        OpCode::Constant => return constant_index(instruction).map(|index| index as u32),
+        OpCode::Zero => 0.0,
+        OpCode::One => 1.0,
+        OpCode::MinusOne => -1.0,
+        _ => return None,
+    };
+
+    let constants = chunk.constants_mut();
+    // the bits are compared, since -0 is equal to 0
+    let found = constants.iter().position(|constant| {
+        matches!(constant, Value::Number(constant) if constant.to_bits() == number.to_bits())
+    });
+    let index = found.unwrap_or_else(|| constants.add(Value::Number(number)));
+    index.try_into().ok()
+}
+
+fn constant_index(instruction: &Instruction) -> Option<usize> {
+    match instruction.op {
+        OpCode::Constant => {
+            read_varint(|i| instruction.operands.get(i).copied()).map(|(index, _)| index as usize)
+        }
+        _ => None,
+    }
+}
+
+fn set_constant_index(instruction: &mut Instruction, index: usize) {
+    instruction.operands = vec![];
+    write_varint(&mut instruction.operands, index as u32, 1);
+}
+
+// applies every pattern once, returning whether anything changed
+fn rewrite(chunk: &mut Chunk, instructions: &mut [Instruction]) -> bool {
+    let mut changed = false;
+    let targets = jump_targets(instructions);
+    // how many instructions load each constant
+    let mut constant_uses = vec![0; chunk.constants().len()];
+    instructions
+        .iter()
+        .filter(|instruction| !instruction.removed)
+        .filter_map(constant_index)
+        .for_each(|index| constant_uses[index] += 1);
+
+    for i in 0..instructions.len() {
+        if instructions[i].removed {
+            continue;
+        }
+        let Some(j) = next(instructions, i) else {
+            break;
+        };
+        // only the first instruction of a pattern may be jumped to, since
+        // the ones after it depend on it having run
+        if is_targeted(instructions, &targets, j) {
+            continue;
+        }
+
+        match (instructions[i].op, instructions[j].op) {
+            (OpCode::Not, OpCode::Not) => {
+                let produces_bool = (0..i)
+                    .rev()
+                    .find(|&k| !instructions[k].removed)
+                    .is_some_and(|k| {
+                        !is_targeted(instructions, &targets, i)
+                            && matches!(
+                                instructions[k].op,
+                                OpCode::Not
+                                    | OpCode::Equal
+                                    | OpCode::Greater
+                                    | OpCode::Less
+                                    | OpCode::GreaterEqual
+                                    | OpCode::LessEqual
+                                    | OpCode::True
+                                    | OpCode::False
+                            )
+                    });
+                if produces_bool {
+                    instructions[i].removed = true;
+                    instructions[j].removed = true;
+                    changed = true;
+                }
+            }
+            (
+                OpCode::Constant
+                | OpCode::Nil
+                | OpCode::True
+                | OpCode::False
+                | OpCode::Zero
+                | OpCode::One
+                | OpCode::MinusOne
+                | OpCode::GetLocal,
+                OpCode::Pop,
+            ) => {
+                instructions[i].removed = true;
+                instructions[j].removed = true;
+                changed = true;
+            }
+            (OpCode::One, OpCode::Negate) => {
+                instructions[i].op = OpCode::MinusOne;
+                instructions[j].removed = true;
+                changed = true;
+            }
+            (OpCode::MinusOne, OpCode::Negate) => {
+                instructions[i].op = OpCode::One;
+                instructions[j].removed = true;
+                changed = true;
+            }
+            (OpCode::Constant, OpCode::Negate) => {
+                let index = constant_index(&instructions[i]).expect("ICE: Checked above");
+                if let Value::Number(number) = chunk.constants().get(index) {
+                    if constant_uses[index] > 1 {
+                        constant_uses[index] -= 1;
+                        let index = chunk.constants_mut().add(Value::Number(-number));
+                        constant_uses.push(1);
+                        set_constant_index(&mut instructions[i], index);
+                    } else {
+                        chunk.constants_mut().set(index, Value::Number(-number));
+                    }
+                    instructions[j].removed = true;
+                    changed = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for i in 0..instructions.len() {
+        if instructions[i].removed {
+            continue;
+        }
+        let Some(target) = instructions[i].target else {
+            continue;
+        };
+        let target = resolve(instructions, target);
+
+        // jumping to the next instruction does nothing
+        if instructions[i].op == OpCode::Jump && next(instructions, i) == Some(target) {
+            instructions[i].removed = true;
+            changed = true;
+            continue;
+        }
+
+        // a jump that lands on an unconditional jump can go straight to
+        // where that one goes, as long as it does not jump to itself
+        if target < instructions.len()
+            && matches!(instructions[target].op, OpCode::Jump | OpCode::Loop)
+            && target != i
+        {
+            let Some(final_target) = instructions[target].target else {
+                continue;
+            };
+            let final_target = resolve(instructions, final_target);
+            // OP_JUMP_IF_FALSE can only jump forwards
+            let is_valid = instructions[i].op != OpCode::JumpIfFalse || final_target > i;
+            if is_valid && final_target != target {
+                instructions[i].target = Some(final_target);
+                changed = true;
+            }
+        }
+    }
+
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Heap;
+
+    fn chunk(code: &[u8], constants: &[Value]) -> Chunk {
+        let mut chunk = Chunk::new();
+        constants.iter().for_each(|constant| {
+            chunk.constants_mut().add(*constant);
+        });
+        code.iter().for_each(|byte| chunk.write(*byte, 1, 1));
+        chunk
+    }
+
+    fn assert_optimized(
+        code: &[u8],
+        constants: &[Value],
+        expected: &[u8],
+        expected_constants: &[Value],
+    ) {
+        let mut optimized = chunk(code, constants);
+        optimize(&mut optimized);
+        assert_eq!(optimized, chunk(expected, expected_constants));
+    }
+
+    const RETURN: u8 = OpCode::Return as u8;
+    const POP: u8 = OpCode::Pop as u8;
+    const NOT: u8 = OpCode::Not as u8;
+    const JUMP: u8 = OpCode::Jump as u8;
+    const JUMP_IF_FALSE: u8 = OpCode::JumpIfFalse as u8;
+    const LOOP: u8 = OpCode::Loop as u8;
+    const CONSTANT: u8 = OpCode::Constant as u8;
+    const NEGATE: u8 = OpCode::Negate as u8;
+    const GET_GLOBAL: u8 = OpCode::GetGlobal as u8;
+    const EQUAL: u8 = OpCode::Equal as u8;
+    const PRINT: u8 = OpCode::Print as u8;
+    const ADD: u8 = OpCode::Add as u8;
+    const GET_LOCAL: u8 = OpCode::GetLocal as u8;
+
+    #[test]
+    fn test_peephole_not_not() {
+        let one = Value::Number(1.0);
+
+        assert_optimized(
+            &[CONSTANT, 0, CONSTANT, 0, EQUAL, NOT, NOT, PRINT, RETURN],
+            &[one],
+            &[CONSTANT, 0, CONSTANT, 0, EQUAL, PRINT, RETURN],
+            &[one],
+        );
+        // `!!1` is true, not 1
+        assert_optimized(
+            &[CONSTANT, 0, NOT, NOT, PRINT, RETURN],
+            &[one],
+            &[CONSTANT, 0, NOT, NOT, PRINT, RETURN],
+            &[one],
+        );
+    }
+
+    #[test]
+    fn test_peephole_push_pop() {
+        let one = Value::Number(1.0);
+
+        assert_optimized(&[CONSTANT, 0, POP, RETURN], &[one], &[RETURN], &[one]);
+        assert_optimized(
+            &[
+                OpCode::Nil as u8,
+                POP,
+                OpCode::GetLocal as u8,
+                1,
+                POP,
+                RETURN,
+            ],
+            &[],
+            &[RETURN],
+            &[],
+        );
+        // reading a global can fail, so it has to stay
+        assert_optimized(
+            &[GET_GLOBAL, 0, POP, RETURN],
+            &[one],
+            &[GET_GLOBAL, 0, POP, RETURN],
+            &[one],
+        );
+    }
+
+    #[test]
+    fn test_peephole_negate_constant() {
+        assert_optimized(
+            &[CONSTANT, 0, NEGATE, PRINT, RETURN],
+            &[Value::Number(2.0)],
+            &[CONSTANT, 0, PRINT, RETURN],
+            &[Value::Number(-2.0)],
+        );
+        // a constant that is loaded elsewhere as well is not changed in place
+        assert_optimized(
+            &[CONSTANT, 0, NEGATE, PRINT, CONSTANT, 0, PRINT, RETURN],
+            &[Value::Number(2.0)],
+            &[CONSTANT, 1, PRINT, CONSTANT, 0, PRINT, RETURN],
+            &[Value::Number(2.0), Value::Number(-2.0)],
+        );
+        // negating a non-number is a runtime error
+        assert_optimized(
+            &[OpCode::Nil as u8, NEGATE, PRINT, RETURN],
+            &[],
+            &[OpCode::Nil as u8, NEGATE, PRINT, RETURN],
+            &[],
+        );
+        assert_optimized(
+            &[
+                OpCode::One as u8,
+                NEGATE,
+                OpCode::MinusOne as u8,
+                NEGATE,
+                RETURN,
+            ],
+            &[],
+            &[OpCode::MinusOne as u8, OpCode::One as u8, RETURN],
+            &[],
+        );
+    }
+
+    #[test]
+    fn test_peephole_jumps() {
+        let one = Value::Number(1.0);
+
+        // 0: JUMP_IF_FALSE -> 5, 2: POP, 3: PRINT, 4: POP, 5: JUMP -> 8, 7: PRINT, 8: RETURN
+        let code = [JUMP_IF_FALSE, 3, POP, PRINT, POP, JUMP, 1, PRINT, RETURN];
+        let mut threaded = code;
+        threaded[1] = 6;
+        assert_optimized(&code, &[], &threaded, &[]);
+
+        // a jump to the next instruction is dropped, and the jump over the
+        // removed code is shortened
+        // 0: JUMP_IF_FALSE -> 5, 2: JUMP -> 4, 4: PRINT, 5: RETURN
+        let code = [JUMP_IF_FALSE, 3, JUMP, 0, PRINT, RETURN];
+        assert_optimized(&code, &[], &[JUMP_IF_FALSE, 1, PRINT, RETURN], &[]);
+
+        // the patterns are not applied across a jump target
+        // 0: CONSTANT, 2: JUMP_IF_FALSE -> 5, 4: NIL, 5: POP, 6: RETURN
+        let code = [
+            CONSTANT,
+            0,
+            JUMP_IF_FALSE,
+            1,
+            OpCode::Nil as u8,
+            POP,
+            RETURN,
+        ];
+        assert_optimized(&code, &[one], &code, &[one]);
+
+        // a loop back to another loop
+        // 0: PRINT, 1: LOOP -> 0, 3: LOOP -> 1
+        let code = [PRINT, LOOP, 3, LOOP, 4, RETURN];
+        let mut threaded = code;
+        threaded[4] = 5;
+        assert_optimized(&code, &[], &threaded, &[]);
+    }
+
+    #[test]
+    fn test_peephole_fuse() {
+        let one = Value::Number(1.0);
+        let fuse_chunk = |code: &[u8]| {
+            let mut fused = chunk(code, &[one]);
+            fuse(&mut fused);
+            fused
+        };
+
+        assert_eq!(
+            fuse_chunk(&[GET_LOCAL, 1, CONSTANT, 0, ADD, PRINT, RETURN]),
+            chunk(
+                &[OpCode::GetLocalAddConstant as u8, 1, 0, PRINT, RETURN],
+                &[one]
+            )
+        );
+        assert_eq!(
+            fuse_chunk(&[GET_GLOBAL, 0, CONSTANT, 0, ADD, PRINT, RETURN]),
+            chunk(
+                &[GET_GLOBAL, 0, OpCode::AddConstant as u8, 0, PRINT, RETURN],
+                &[one]
+            )
+        );
+
+        // a small constant is added to the pool, unless it is already there
+        assert_eq!(
+            fuse_chunk(&[GET_LOCAL, 1, OpCode::One as u8, ADD, RETURN]),
+            chunk(&[OpCode::GetLocalAddConstant as u8, 1, 0, RETURN], &[one])
+        );
+        assert_eq!(
+            fuse_chunk(&[OpCode::Zero as u8, ADD, RETURN]),
+            chunk(
+                &[OpCode::AddConstant as u8, 1, RETURN],
+                &[one, Value::Number(0.0)]
+            )
+        );
+
+        // the fused instruction is where a runtime error in the OP_ADD is reported
+        let mut code = chunk(&[GET_LOCAL, 1, CONSTANT, 0], &[one]);
+        code.write(ADD, 2, 5);
+        fuse(&mut code);
+        let mut expected = Chunk::new();
+        expected.constants_mut().add(one);
+        [OpCode::GetLocalAddConstant as u8, 1, 0]
+            .iter()
+            .for_each(|byte| expected.write(*byte, 2, 5));
+        assert_eq!(code, expected);
+
+        // an instruction that is jumped to cannot be fused into the one before it
+        // 0: JUMP_IF_FALSE -> 4, 2: CONSTANT, 4: ADD
+        let code = [JUMP_IF_FALSE, 2, CONSTANT, 0, ADD, RETURN];
+        assert_eq!(fuse_chunk(&code), chunk(&code, &[one]));
+    }
+
+    #[test]
+    fn test_peephole_fuse_concat_n() {
+        let mut heap = Heap::new();
+        let c = Value::Obj(heap.copy_string("c"));
+        let fuse_chunk = |code: &[u8]| {
+            let mut fused = chunk(code, &[c]);
+            fuse(&mut fused);
+            fused
+        };
+        const CONCAT_N: u8 = OpCode::ConcatN as u8;
+
+        // a + b + "c" + d, where the constant is not taken by OP_ADD_CONSTANT,
+        // and the count has a byte for each OP_ADD
+        assert_eq!(
+            fuse_chunk(&[
+                GET_GLOBAL, 0, GET_LOCAL, 1, ADD, CONSTANT, 0, ADD, GET_LOCAL, 2, ADD, PRINT,
+                RETURN
+            ]),
+            chunk(
+                &[
+                    GET_GLOBAL, 0, GET_LOCAL, 1, CONSTANT, 0, GET_LOCAL, 2, CONCAT_N, 0x84, 0x80,
+                    0x00, PRINT, RETURN
+                ],
+                &[c]
+            )
+        );
+
+        // numbers are left to OP_ADD
+        let code = [GET_LOCAL, 1, GET_LOCAL, 2, ADD, GET_LOCAL, 3, ADD, RETURN];
+        assert_eq!(fuse_chunk(&code), chunk(&code, &[c]));
+
+        // a global can be undefined, so reading it has to wait for the OP_ADD
+        const GET_LOCAL_ADD_CONSTANT: u8 = OpCode::GetLocalAddConstant as u8;
+        assert_eq!(
+            fuse_chunk(&[GET_LOCAL, 1, CONSTANT, 0, ADD, GET_GLOBAL, 0, ADD, RETURN]),
+            chunk(
+                &[GET_LOCAL_ADD_CONSTANT, 1, 0, GET_GLOBAL, 0, ADD, RETURN],
+                &[c]
+            )
+        );
+
+        // each byte of the count has the span of its `+`, where a runtime
+        // error in it is reported, and the chain can go over several lines
+        let mut code = chunk(&[GET_LOCAL, 1, CONSTANT, 0], &[c]);
+        code.write(ADD, 1, 5);
+        code.write(GET_LOCAL, 2, 1);
+        code.write(2, 2, 1);
+        code.write(ADD, 2, 3);
+        code.write(RETURN, 2, 4);
+        fuse(&mut code);
+        let spans = [6, 7, 8].map(|offset| {
+            (
+                code.get_code(offset),
+                code.get_line(offset),
+                code.get_column(offset),
+            )
+        });
+        assert_eq!(spans, [(CONCAT_N, 2, 3), (0x83, 1, 5), (0x00, 2, 3)]);
+    }
+}
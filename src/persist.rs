@@ -0,0 +1,200 @@
+// Serde support, behind the `serde` feature, for hosts that keep the results
+// of scripts and the scripts they compiled with the rest of their data.
+//
+// A `Value` is written as an enum of `Nil`, `Bool`, `Number`, `String` and
+// `Decimal`, with the characters of the string or the digits of the decimal.
+// A `Chunk` is written as the bytes of its script in the `.loxc` format, so
+// that it is verified the same way when it is read back. Functions and
+// natives only mean something to the VM that they were allocated by, so they
+// can't be written.
+//
+// Strings and decimals are objects that live on a heap, so they are read
+// with a seed that holds the heap to allocate them on:
+//
+//   let value = ValueSeed(vm.heap_mut()).deserialize(&mut deserializer)?;
+//   let chunk = ChunkSeed(vm.heap_mut()).deserialize(&mut deserializer)?;
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{self, DeserializeSeed, SeqAccess, Visitor},
+    ser,
+};
+
+use crate::{bytecode, chunk::Chunk, decimal::Decimal, object::Heap, value::Value};
+
+// the form that a value is written in
+#[derive(Serialize)]
+#[serde(rename = "Value")]
+enum Repr<'a> {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(&'a str),
+    Decimal(String),
+}
+
+// the same, for reading, where the strings can't always be borrowed
+#[derive(Deserialize)]
+#[serde(rename = "Value")]
+enum OwnedRepr {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Decimal(String),
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match self {
+            Value::Nil => Repr::Nil,
+            Value::Bool(value) => Repr::Bool(*value),
+            Value::Number(value) => Repr::Number(*value),
+            Value::Obj(obj) => {
+                if let Some(string) = obj.as_string() {
+                    Repr::String(string.as_str())
+                } else if let Some(decimal) = obj.as_decimal() {
+                    Repr::Decimal(decimal.to_string())
+                } else {
+                    return Err(ser::Error::custom(format_args!("Can't serialize {}.", obj)));
+                }
+            }
+        };
+        repr.serialize(serializer)
+    }
+}
+
+/// Reads a [`Value`], allocating its string or decimal on the heap.
+pub struct ValueSeed<'a>(pub &'a mut Heap);
+
+impl<'de> DeserializeSeed<'de> for ValueSeed<'_> {
+    type Value = Value;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Ok(match OwnedRepr::deserialize(deserializer)? {
+            OwnedRepr::Nil => Value::Nil,
+            OwnedRepr::Bool(value) => Value::Bool(value),
+            OwnedRepr::Number(value) => Value::Number(value),
+            OwnedRepr::String(string) => Value::Obj(self.0.alloc_string(string)),
+            OwnedRepr::Decimal(digits) => {
+                let decimal =
+                    Decimal::parse(&digits).ok_or_else(|| de::Error::custom("Invalid decimal."))?;
+                Value::Obj(self.0.alloc_decimal(decimal))
+            }
+        })
+    }
+}
+
+impl Serialize for Chunk {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&bytecode::serialize(self))
+    }
+}
+
+/// Reads a script written as a [`Chunk`], allocating its strings and
+/// functions on the heap. It is verified like [`bytecode::load`] does.
+pub struct ChunkSeed<'a>(pub &'a mut Heap);
+
+impl<'de> DeserializeSeed<'de> for ChunkSeed<'_> {
+    type Value = Chunk;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Chunk, D::Error> {
+        let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+        bytecode::load(&bytes, self.0).map_err(de::Error::custom)
+    }
+}
+
+// bytes, or a sequence of them for the formats that have no bytes of their
+// own, like JSON
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the bytes of a compiled Lox script")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, object::ObjFunction};
+
+    fn read_value(json: &str, heap: &mut Heap) -> Result<Value, serde_json::Error> {
+        ValueSeed(heap).deserialize(&mut serde_json::Deserializer::from_str(json))
+    }
+
+    #[test]
+    fn test_serde_value() {
+        let mut heap = Heap::new();
+        let string = Value::Obj(heap.copy_string("a \"b\""));
+        let decimal = Value::Obj(heap.alloc_decimal(Decimal::parse("0.1").expect("valid")));
+        let values = [
+            (Value::Nil, r#""Nil""#),
+            (Value::Bool(true), r#"{"Bool":true}"#),
+            (Value::Number(1.5), r#"{"Number":1.5}"#),
+            (string, r#"{"String":"a \"b\""}"#),
+            (decimal, r#"{"Decimal":"0.1"}"#),
+        ];
+        for (value, json) in values {
+            assert_eq!(serde_json::to_string(&value).expect("serializable"), json);
+            let read = read_value(json, &mut heap).expect("valid");
+            assert_eq!(read.to_string(), value.to_string());
+        }
+        // strings are interned when they are read back
+        let read = read_value(r#"{"String":"a \"b\""}"#, &mut heap).expect("valid");
+        assert!(read == string);
+
+        let function = Value::Obj(heap.alloc_function(ObjFunction {
+            arity: 0,
+            chunk: Chunk::new(),
+            name: None,
+        }));
+        assert!(serde_json::to_string(&function).is_err());
+        assert!(read_value(r#"{"Decimal":"x"}"#, &mut heap).is_err());
+        assert!(read_value(r#"{"Function":1}"#, &mut heap).is_err());
+    }
+
+    #[test]
+    fn test_serde_chunk() {
+        let mut heap = Heap::new();
+        let source = "fun f(a) { return a + \"!\"; } print f(\"hi\");";
+        let chunk = Compiler::compile(source, &mut heap, Default::default()).expect("no error");
+
+        let json = serde_json::to_string(&chunk).expect("serializable");
+        let mut heap = Heap::new();
+        let read = ChunkSeed(&mut heap)
+            .deserialize(&mut serde_json::Deserializer::from_str(&json))
+            .expect("valid");
+        assert_eq!(bytecode::serialize(&read), bytecode::serialize(&chunk));
+
+        // the bytes are checked like any other bytecode
+        let error = ChunkSeed(&mut heap)
+            .deserialize(&mut serde_json::Deserializer::from_str("[1, 2, 3]"))
+            .expect_err("not bytecode");
+        assert!(error.to_string().starts_with("Not a compiled Lox script."));
+    }
+}
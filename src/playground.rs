@@ -0,0 +1,138 @@
+// The interpreter for a playground in the browser, which runs the VM itself
+// in WebAssembly, rather than compiling the script to it like `wasm` does.
+// It is built with:
+//
+//   cargo rustc --lib --release --target wasm32-unknown-unknown --crate-type cdylib
+//
+// The module imports from the host:
+//
+//   env.clox_print(ptr: i32, len: i32)  a line that the script printed
+//   env.clox_error(ptr: i32, len: i32)  a line of the errors it reported
+//
+// where the text is UTF-8 in the exported `memory`. It exports
+// `clox_alloc(len: i32) -> i32`, for the host to write the source to, and
+// `clox_interpret(ptr: i32, len: i32) -> i32`, which runs it and returns the
+// exit code that `clox` would have. web/clox.js wraps them up as
+// `interpret(source) -> { output, error }`.
+
+use crate::{
+    io::{self, SharedOutput},
+    vm::{InterpretError, VM},
+};
+
+/// What a script printed, and what it reported if it failed.
+#[derive(Debug, PartialEq)]
+pub struct Interpreted {
+    pub output: String,
+    /// The diagnostics and the runtime error, or `None` if the script ran
+    /// without an error, when its warnings are left out.
+    pub error: Option<String>,
+}
+
+/// Runs `source` in a VM of its own, which prints to `output` and reports to
+/// `errors`.
+pub fn run<W: io::Write>(
+    source: &str,
+    output: W,
+    errors: Box<dyn io::Write>,
+) -> Result<(), InterpretError> {
+    let mut vm = VM::with_output(output);
+    vm.set_error_output(errors);
+    vm.interpret(source)
+}
+
+/// Runs `source` in a VM of its own, keeping what it prints and reports.
+pub fn interpret(source: &str) -> Interpreted {
+    let (output, errors) = (SharedOutput::default(), SharedOutput::default());
+    let result = run(source, output.clone(), Box::new(errors.clone()));
+    Interpreted {
+        output: output.text(),
+        error: result.err().map(|_| errors.text()),
+    }
+}
+
+// the C API has a clox_interpret of its own
+#[cfg(all(target_arch = "wasm32", not(feature = "capi")))]
+mod host {
+    use std::{io, mem};
+
+    #[link(wasm_import_module = "env")]
+    unsafe extern "C" {
+        fn clox_print(ptr: *const u8, len: usize);
+        fn clox_error(ptr: *const u8, len: usize);
+    }
+
+    // writes to one of the imports
+    struct Host(unsafe extern "C" fn(*const u8, usize));
+
+    impl io::Write for Host {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            // SAFETY: the host only reads the bytes while it is called
+            unsafe { (self.0)(buf.as_ptr(), buf.len()) };
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[unsafe(no_mangle)]
+    pub extern "C" fn clox_alloc(len: usize) -> *mut u8 {
+        let mut bytes = Vec::<u8>::with_capacity(len);
+        let ptr = bytes.as_mut_ptr();
+        mem::forget(bytes);
+        ptr
+    }
+
+    /// Runs the source that the host has written to `ptr`, which is freed.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to be from `clox_alloc(len)`, with `len` bytes written.
+    #[unsafe(no_mangle)]
+    pub unsafe extern "C" fn clox_interpret(ptr: *mut u8, len: usize) -> i32 {
+        // SAFETY: the caller allocated it with `clox_alloc` and filled it
+        let bytes = unsafe { Vec::from_raw_parts(ptr, len, len) };
+        let source = String::from_utf8_lossy(&bytes);
+        let output = io::LineWriter::new(Host(clox_print));
+        let errors = Box::new(io::LineWriter::new(Host(clox_error)));
+        super::run(&source, output, errors).map_or_else(|error| error.exit_code(), |_| 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpret() {
+        assert_eq!(
+            interpret("print 1 + 2;\nprint \"a\";"),
+            Interpreted {
+                output: "3\na\n".to_string(),
+                error: None,
+            }
+        );
+        assert_eq!(
+            interpret("print 1;\n-nil;"),
+            Interpreted {
+                output: "1\n".to_string(),
+                error: Some(
+                    "Operand must be a number.\n[line 2, column 1] in script\n".to_string()
+                ),
+            }
+        );
+
+        let interpreted = interpret("print;");
+        assert_eq!(interpreted.output, "");
+        assert!(
+            interpreted
+                .error
+                .is_some_and(|error| error.starts_with("error: Expect expression.\n"))
+        );
+
+        // only the diagnostics of a script that fails are an error
+        assert_eq!(interpret("{ var a; }").error, None);
+    }
+}
@@ -0,0 +1,743 @@
+// An experimental register machine, for comparing against the stack machine.
+//
+// The bytecode of a function is translated into register instructions the
+// first time that the function is called. The registers are the slots of the
+// frame, in the same order as the stack machine keeps them: the callee, the
+// arguments and the locals, and then the temporaries of the expression that
+// is being evaluated, one register for each value that would have been on the
+// stack. A value that is only a copy of another register is read from that
+// register directly, so that getting a local or popping a value takes no
+// instruction at all.
+
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    bytecode,
+    chunk::{Chunk, OpCode, Span},
+    instruction::read_varint,
+    io,
+    relocate::{self, Instruction},
+    value::Value,
+};
+
+/// Which instruction set the VM runs the bytecode on.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Backend {
+    #[default]
+    Stack,
+    Register,
+}
+
+/// The index of a register, counted from the frame's first slot.
+pub type Register = u16;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RegisterInstruction {
+    LoadConstant {
+        dst: Register,
+        index: u32,
+    },
+    LoadNil {
+        dst: Register,
+    },
+    LoadBool {
+        dst: Register,
+        value: bool,
+    },
+    // the numbers that have their own opcodes in the stack machine
+    LoadNumber {
+        dst: Register,
+        value: i8,
+    },
+    Move {
+        dst: Register,
+        src: Register,
+    },
+    Negate {
+        dst: Register,
+        src: Register,
+    },
+    Not {
+        dst: Register,
+        src: Register,
+    },
+    Binary {
+        op: BinaryOp,
+        dst: Register,
+        a: Register,
+        b: Register,
+    },
+    AddConstant {
+        dst: Register,
+        src: Register,
+        index: u32,
+    },
+    Print {
+        src: Register,
+    },
+    DefineGlobal {
+        name: u32,
+        src: Register,
+    },
+    GetGlobal {
+        dst: Register,
+        name: u32,
+    },
+    SetGlobal {
+        name: u32,
+        src: Register,
+    },
+    // the targets are indices of instructions
+    Jump {
+        target: usize,
+    },
+    JumpIfFalse {
+        src: Register,
+        target: usize,
+    },
+    // the callee is in `base` and the arguments are in the registers after
+    // it, the result of the call replaces the callee
+    Call {
+        base: Register,
+        arg_count: u8,
+    },
+    Return {
+        src: Register,
+    },
+}
+
+/// The register instructions of a function.
+#[derive(Debug, PartialEq)]
+pub struct RegisterCode {
+    pub instructions: Vec<RegisterInstruction>,
+    // where in the source each instruction comes from, for runtime errors
+    pub spans: Vec<Span>,
+    // how many registers a frame of the function needs
+    pub registers: usize,
+}
+
+/// Translates the bytecode of a function with `arity` parameters into
+/// register instructions.
+///
+/// Returns an error if the bytecode is malformed, which the compiler and the
+/// loader never let through.
+pub fn translate(chunk: &Chunk, arity: usize) -> Result<RegisterCode, String> {
+    let instructions = relocate::decode(chunk, &[]).ok_or("The code is malformed.")?;
+    let heights = heights(&instructions, arity)?;
+
+    let mut targeted = vec![false; instructions.len()];
+    instructions
+        .iter()
+        .filter_map(|instruction| instruction.target)
+        .for_each(|target| targeted[target] = true);
+
+    let mut translator = Translator {
+        instructions: vec![],
+        spans: vec![],
+        stack: vec![],
+        registers: arity + 1,
+        starts: vec![0; instructions.len()],
+        jumps: vec![],
+    };
+    let mut falls_through = false;
+
+    for (i, instruction) in instructions.iter().enumerate() {
+        let Some(height) = heights[i] else {
+            // unreachable code is left out
+            falls_through = false;
+            continue;
+        };
+
+        // every path that meets here leaves each value in its own register
+        if targeted[i] && falls_through {
+            translator.materialize(0, instruction.span);
+        }
+        if targeted[i] || i == 0 {
+            translator.stack = (0..height).collect();
+        }
+
+        translator.starts[i] = translator.instructions.len();
+        translator.translate(instruction)?;
+        falls_through = !matches!(instruction.op, OpCode::Return | OpCode::Jump | OpCode::Loop);
+    }
+
+    let Translator {
+        mut instructions,
+        spans,
+        registers,
+        starts,
+        jumps,
+        ..
+    } = translator;
+    for (at, target) in jumps {
+        match &mut instructions[at] {
+            RegisterInstruction::Jump { target: to }
+            | RegisterInstruction::JumpIfFalse { target: to, .. } => *to = starts[target],
+            _ => panic!("ICE: Patching a non-jump"),
+        }
+    }
+
+    if registers > Register::MAX as usize + 1 {
+        return Err(format!("The function needs {} registers.", registers));
+    }
+
+    Ok(RegisterCode {
+        instructions,
+        spans,
+        registers,
+    })
+}
+
+// the height of the stack before each instruction, or `None` for the ones
+// that are never reached
+fn heights(instructions: &[Instruction], arity: usize) -> Result<Vec<Option<usize>>, String> {
+    let mut heights = vec![None; instructions.len()];
+    let mut worklist = vec![(0, arity + 1)];
+
+    while let Some((i, height)) = worklist.pop() {
+        if i >= instructions.len() {
+            return Err("Runs past the end of the code.".to_string());
+        }
+        match heights[i] {
+            Some(known) if known == height => continue,
+            Some(_) => return Err("The stack height differs between paths.".to_string()),
+            None => heights[i] = Some(height),
+        }
+
+        let instruction = &instructions[i];
+        let (popped, pushed) = bytecode::stack_effect(instruction.op, &instruction.operands);
+        // the function's own slot is never popped, the same as the loader
+        // checks
+        if height < popped + 1 {
+            return Err("Pops more values than are on the stack.".to_string());
+        }
+        let height = height - popped + pushed;
+
+        if let Some(target) = instruction.target {
+            worklist.push((target, height));
+        }
+        if !matches!(instruction.op, OpCode::Return | OpCode::Jump | OpCode::Loop) {
+            worklist.push((i + 1, height));
+        }
+    }
+
+    Ok(heights)
+}
+
+struct Translator {
+    instructions: Vec<RegisterInstruction>,
+    spans: Vec<Span>,
+    // the register that holds each value that would be on the stack, which
+    // is either the value's own one, or a lower one that it is a copy of
+    stack: Vec<usize>,
+    // how many registers have been written to so far
+    registers: usize,
+    // the first register instruction of each reachable instruction
+    starts: Vec<usize>,
+    // (register instruction, instruction that it jumps to)
+    jumps: Vec<(usize, usize)>,
+}
+
+impl Translator {
+    fn emit(&mut self, instruction: RegisterInstruction, span: Span) {
+        self.instructions.push(instruction);
+        self.spans.push(span);
+    }
+
+    fn top(&self) -> usize {
+        self.stack.len() - 1
+    }
+
+    // the register that the value at `position` is read from
+    fn operand(&self, position: usize) -> Register {
+        self.stack[position] as Register
+    }
+
+    // a new value on the stack, which is written to its own register
+    fn push(&mut self) -> Register {
+        let position = self.stack.len();
+        self.stack.push(position);
+        self.replace(position)
+    }
+
+    // the value at `position` after it is overwritten by an instruction
+    fn replace(&mut self, position: usize) -> Register {
+        self.stack[position] = position;
+        self.registers = self.registers.max(position + 1);
+        position as Register
+    }
+
+    // copies the values from `position` up into their own registers
+    fn materialize(&mut self, position: usize, span: Span) {
+        for position in position..self.stack.len() {
+            if self.stack[position] != position {
+                let src = self.operand(position);
+                let dst = self.replace(position);
+                self.emit(RegisterInstruction::Move { dst, src }, span);
+            }
+        }
+    }
+
+    // copies out the values that are read from `register`, before it is
+    // overwritten
+    fn materialize_copies_of(&mut self, register: usize, span: Span) {
+        for position in 0..self.stack.len() {
+            if position != register && self.stack[position] == register {
+                let dst = self.replace(position);
+                self.emit(
+                    RegisterInstruction::Move {
+                        dst,
+                        src: register as Register,
+                    },
+                    span,
+                );
+            }
+        }
+    }
+
+    fn jump(&mut self, instruction: &Instruction, jump: RegisterInstruction) {
+        let target = instruction
+            .target
+            .unwrap_or_else(|| panic!("ICE: Jump without a target"));
+        self.jumps.push((self.instructions.len(), target));
+        self.emit(jump, instruction.span);
+    }
+
+    fn translate(&mut self, instruction: &Instruction) -> Result<(), String> {
+        let span = instruction.span;
+        let operand = |i: usize| instruction.operands[i];
+        // the varint that starts at byte `at` of the operands
+        let varint = |at: usize| {
+            read_varint(|i| instruction.operands.get(at + i).copied())
+                .map(|(value, _)| value)
+                .ok_or("The operands are malformed.")
+        };
+        let binary = |op: OpCode| match op {
+            OpCode::Add | OpCode::AddNumbers => BinaryOp::Add,
+            OpCode::Subtract | OpCode::SubtractNumbers => BinaryOp::Subtract,
+            OpCode::Multiply => BinaryOp::Multiply,
+            OpCode::Divide => BinaryOp::Divide,
+            OpCode::Equal => BinaryOp::Equal,
+            OpCode::Greater => BinaryOp::Greater,
+            OpCode::Less | OpCode::LessNumbers => BinaryOp::Less,
+            OpCode::GreaterEqual => BinaryOp::GreaterEqual,
+            OpCode::LessEqual => BinaryOp::LessEqual,
+            _ => panic!("ICE: {:?} is not a binary operator", op),
+        };
+
+        match instruction.op {
+            OpCode::Constant => {
+                let index = varint(0)?;
+                let dst = self.push();
+                self.emit(RegisterInstruction::LoadConstant { dst, index }, span);
+            }
+            OpCode::Nil => {
+                let dst = self.push();
+                self.emit(RegisterInstruction::LoadNil { dst }, span);
+            }
+            OpCode::True | OpCode::False => {
+                let value = instruction.op == OpCode::True;
+                let dst = self.push();
+                self.emit(RegisterInstruction::LoadBool { dst, value }, span);
+            }
+            OpCode::Zero | OpCode::One | OpCode::MinusOne => {
+                let value = match instruction.op {
+                    OpCode::Zero => 0,
+                    OpCode::One => 1,
+                    _ => -1,
+                };
+                let dst = self.push();
+                self.emit(RegisterInstruction::LoadNumber { dst, value }, span);
+            }
+            OpCode::GetLocal => {
+                let register = self.stack[operand(0) as usize];
+                self.stack.push(register);
+            }
+            OpCode::SetLocal => {
+                let slot = operand(0) as usize;
+                self.materialize_copies_of(slot, span);
+                let src = self.operand(self.top());
+                if src as usize != slot {
+                    let dst = self.replace(slot);
+                    self.emit(RegisterInstruction::Move { dst, src }, span);
+                }
+            }
+            OpCode::Dup => {
+                let register = self.stack[self.top()];
+                self.stack.push(register);
+            }
+            OpCode::Pop => {
+                self.stack.pop();
+            }
+            OpCode::PopN => {
+                self.stack.truncate(self.stack.len() - operand(0) as usize);
+            }
+            OpCode::Negate | OpCode::Not => {
+                let src = self.operand(self.top());
+                let dst = self.replace(self.top());
+                let instruction = if instruction.op == OpCode::Negate {
+                    RegisterInstruction::Negate { dst, src }
+                } else {
+                    RegisterInstruction::Not { dst, src }
+                };
+                self.emit(instruction, span);
+            }
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEqual
+            | OpCode::LessEqual
+            | OpCode::AddNumbers
+            | OpCode::SubtractNumbers
+            | OpCode::LessNumbers => {
+                let b = self.operand(self.top());
+                self.stack.pop();
+                let a = self.operand(self.top());
+                let dst = self.replace(self.top());
+                let op = binary(instruction.op);
+                self.emit(RegisterInstruction::Binary { op, dst, a, b }, span);
+            }
+            // the values are added one at a time from the left, as the
+            // OP_ADDs that were fused would have, each with its own span
+            OpCode::ConcatN => {
+                let count = varint(0)? as usize;
+                let first = self.stack.len() - count;
+                for n in 1..count {
+                    let a = self.operand(first);
+                    let b = self.operand(first + n);
+                    let dst = self.replace(first);
+                    let op = BinaryOp::Add;
+                    let span = instruction
+                        .operand_spans
+                        .get(n - 1)
+                        .copied()
+                        .unwrap_or(span);
+                    self.emit(RegisterInstruction::Binary { op, dst, a, b }, span);
+                }
+                self.stack.truncate(first + 1);
+            }
+            OpCode::AddConstant => {
+                let src = self.operand(self.top());
+                let dst = self.replace(self.top());
+                let index = varint(0)?;
+                self.emit(RegisterInstruction::AddConstant { dst, src, index }, span);
+            }
+            OpCode::GetLocalAddConstant => {
+                let src = self.operand(operand(0) as usize);
+                let dst = self.push();
+                let index = varint(1)?;
+                self.emit(RegisterInstruction::AddConstant { dst, src, index }, span);
+            }
+            OpCode::Print => {
+                let src = self.operand(self.top());
+                self.stack.pop();
+                self.emit(RegisterInstruction::Print { src }, span);
+            }
+            OpCode::DefineGlobal => {
+                let src = self.operand(self.top());
+                self.stack.pop();
+                let name = varint(0)?;
+                self.emit(RegisterInstruction::DefineGlobal { name, src }, span);
+            }
+            OpCode::GetGlobal => {
+                let dst = self.push();
+                let name = varint(0)?;
+                self.emit(RegisterInstruction::GetGlobal { dst, name }, span);
+            }
+            OpCode::SetGlobal => {
+                let src = self.operand(self.top());
+                let name = varint(0)?;
+                self.emit(RegisterInstruction::SetGlobal { name, src }, span);
+            }
+            OpCode::Jump | OpCode::Loop => {
+                self.materialize(0, span);
+                self.jump(instruction, RegisterInstruction::Jump { target: 0 });
+            }
+            OpCode::JumpIfFalse => {
+                self.materialize(0, span);
+                let src = self.operand(self.top());
+                self.jump(
+                    instruction,
+                    RegisterInstruction::JumpIfFalse { src, target: 0 },
+                );
+            }
+            OpCode::Call => {
+                let arg_count = operand(0);
+                let base = self.stack.len() - arg_count as usize - 1;
+                // the callee's frame starts at `base`, so the arguments have
+                // to be in their own registers
+                self.materialize(base, span);
+                self.stack.truncate(base + 1);
+                self.emit(
+                    RegisterInstruction::Call {
+                        base: base as Register,
+                        arg_count,
+                    },
+                    span,
+                );
+            }
+            OpCode::Return => {
+                let src = self.operand(self.top());
+                self.emit(RegisterInstruction::Return { src }, span);
+            }
+            // the slots of the stack machine's globals are not known here
+            OpCode::GetGlobalCached | OpCode::SetGlobalCached => {
+                return Err(format!("{:?} is quickened.", instruction.op));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Disassembles the register code of `chunk` along with the code of the
+/// functions among its constants, in the same order as
+/// [`debug::disassemble_program`](crate::debug::disassemble_program).
+pub fn disassemble_program<S: AsRef<str>, W: io::Write>(
+    w: &mut W,
+    chunk: &Chunk,
+    name: S,
+    arity: usize,
+) {
+    for constant in chunk.constants().iter() {
+        if let Value::Obj(obj) = constant
+            && let Some(function) = obj.as_function()
+        {
+            let name = function.name().unwrap_or("<script>");
+            disassemble_program(w, &function.chunk, name, function.arity);
+        }
+    }
+
+    writeln!(w, "== {} ==", name.as_ref()).expect("writable");
+    match translate(chunk, arity) {
+        Ok(code) => {
+            for ip in 0..code.instructions.len() {
+                disassemble_instruction(w, &code, chunk, ip);
+            }
+        }
+        Err(error) => writeln!(w, "Cannot translate: {}", error).expect("writable"),
+    }
+}
+
+pub fn disassemble_instruction<W: io::Write>(
+    w: &mut W,
+    code: &RegisterCode,
+    chunk: &Chunk,
+    ip: usize,
+) {
+    write!(w, "{:04} ", ip).expect("writable");
+    let line = code.spans[ip].line;
+    if ip > 0 && code.spans[ip - 1].line == line {
+        write!(w, "   | ").expect("writable");
+    } else {
+        write!(w, "{:4} ", line).expect("writable");
+    }
+
+    let constant = |index: usize| format!("'{}'", chunk.constants().get(index));
+    let (name, operands) = match code.instructions[ip] {
+        RegisterInstruction::LoadConstant { dst, index } => (
+            "LOAD_CONSTANT",
+            format!("r{} {:4} {}", dst, index, constant(index as usize)),
+        ),
+        RegisterInstruction::LoadNil { dst } => ("LOAD_NIL", format!("r{}", dst)),
+        RegisterInstruction::LoadBool { dst, value } => {
+            ("LOAD_BOOL", format!("r{} {}", dst, value))
+        }
+        RegisterInstruction::LoadNumber { dst, value } => {
+            ("LOAD_NUMBER", format!("r{} {}", dst, value))
+        }
+        RegisterInstruction::Move { dst, src } => ("MOVE", format!("r{} r{}", dst, src)),
+        RegisterInstruction::Negate { dst, src } => ("NEGATE", format!("r{} r{}", dst, src)),
+        RegisterInstruction::Not { dst, src } => ("NOT", format!("r{} r{}", dst, src)),
+        RegisterInstruction::Binary { op, dst, a, b } => {
+            let name = match op {
+                BinaryOp::Add => "ADD",
+                BinaryOp::Subtract => "SUBTRACT",
+                BinaryOp::Multiply => "MULTIPLY",
+                BinaryOp::Divide => "DIVIDE",
+                BinaryOp::Equal => "EQUAL",
+                BinaryOp::Greater => "GREATER",
+                BinaryOp::Less => "LESS",
+                BinaryOp::GreaterEqual => "GREATER_EQUAL",
+                BinaryOp::LessEqual => "LESS_EQUAL",
+            };
+            (name, format!("r{} r{} r{}", dst, a, b))
+        }
+        RegisterInstruction::AddConstant { dst, src, index } => (
+            "ADD_CONSTANT",
+            format!("r{} r{} {:4} {}", dst, src, index, constant(index as usize)),
+        ),
+        RegisterInstruction::Print { src } => ("PRINT", format!("r{}", src)),
+        RegisterInstruction::DefineGlobal { name, src } => (
+            "DEFINE_GLOBAL",
+            format!("{:4} {} r{}", name, constant(name as usize), src),
+        ),
+        RegisterInstruction::GetGlobal { dst, name } => (
+            "GET_GLOBAL",
+            format!("r{} {:4} {}", dst, name, constant(name as usize)),
+        ),
+        RegisterInstruction::SetGlobal { name, src } => (
+            "SET_GLOBAL",
+            format!("{:4} {} r{}", name, constant(name as usize), src),
+        ),
+        RegisterInstruction::Jump { target } => ("JUMP", format!("-> {}", target)),
+        RegisterInstruction::JumpIfFalse { src, target } => {
+            ("JUMP_IF_FALSE", format!("r{} -> {}", src, target))
+        }
+        RegisterInstruction::Call { base, arg_count } => {
+            ("CALL", format!("r{} {}", base, arg_count))
+        }
+        RegisterInstruction::Return { src } => ("RETURN", format!("r{}", src)),
+    };
+    writeln!(w, "{:<16} {}", name, operands).expect("writable");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{CompileOptions, Compiler},
+        object::Heap,
+    };
+
+    fn translate_script(source: &str, options: CompileOptions) -> Vec<RegisterInstruction> {
+        let mut heap = Heap::new();
+        let chunk = Compiler::compile(source, &mut heap, options).expect("no error");
+        translate(&chunk, 0).expect("valid").instructions
+    }
+
+    #[test]
+    fn test_register_translate() {
+        use RegisterInstruction::*;
+
+        // the locals are read from their own registers, and nothing is popped
+        assert_eq!(
+            translate_script(
+                "{ var a = 1; var b = a + 2; print b; }",
+                CompileOptions::default()
+            ),
+            [
+                LoadConstant { dst: 1, index: 0 },
+                LoadConstant { dst: 3, index: 1 },
+                Binary {
+                    op: BinaryOp::Add,
+                    dst: 2,
+                    a: 1,
+                    b: 3
+                },
+                Print { src: 2 },
+                LoadNil { dst: 1 },
+                Return { src: 1 },
+            ]
+        );
+
+        // `b` is a copy of `a`, until `a` is assigned
+        assert_eq!(
+            translate_script(
+                "{ var a = 1; var b = a; a = 2; print b; }",
+                CompileOptions::default()
+            ),
+            [
+                LoadConstant { dst: 1, index: 0 },
+                LoadConstant { dst: 3, index: 1 },
+                Move { dst: 2, src: 1 },
+                Move { dst: 1, src: 3 },
+                Print { src: 2 },
+                LoadNil { dst: 1 },
+                Return { src: 1 },
+            ]
+        );
+
+        // the paths of the branches meet with every value in its own register
+        assert_eq!(
+            translate_script(
+                "{ var a = true; if (a) print 1; }",
+                CompileOptions::default()
+            ),
+            [
+                LoadBool {
+                    dst: 1,
+                    value: true
+                },
+                Move { dst: 2, src: 1 },
+                JumpIfFalse { src: 2, target: 6 },
+                LoadConstant { dst: 2, index: 0 },
+                Print { src: 2 },
+                Jump { target: 6 },
+                LoadNil { dst: 1 },
+                Return { src: 1 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_register_translate_calls() {
+        let mut heap = Heap::new();
+        let chunk = Compiler::compile(
+            "fun f(a, b) { return a - b; } print f(1, 2);",
+            &mut heap,
+            CompileOptions::default(),
+        )
+        .expect("no error");
+
+        let code = translate(&chunk, 0).expect("valid");
+        assert!(code.instructions.contains(&RegisterInstruction::Call {
+            base: 1,
+            arg_count: 2
+        }));
+
+        let function = (0..chunk.constants().len())
+            .find_map(|i| match chunk.constants().get(i) {
+                Value::Obj(obj) if obj.as_function().is_some() => Some(obj),
+                _ => None,
+            })
+            .expect("f is a constant");
+        let function = function.as_function().expect("a function");
+        let code = translate(&function.chunk, function.arity).expect("valid");
+        assert_eq!(
+            code.instructions[0],
+            RegisterInstruction::Binary {
+                op: BinaryOp::Subtract,
+                dst: 3,
+                a: 1,
+                b: 2
+            }
+        );
+        // `a` and `b` are read from their own registers
+        assert_eq!(code.registers, 4);
+    }
+
+    #[test]
+    fn test_register_translate_invalid() {
+        // a pop below the function's slot
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Pop as u8, 1, 1);
+        chunk.write(OpCode::Pop as u8, 1, 1);
+        chunk.write(OpCode::Return as u8, 1, 1);
+        assert!(translate(&chunk, 0).is_err());
+
+        assert!(translate(&Chunk::new(), 0).is_err());
+    }
+}
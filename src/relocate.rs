@@ -0,0 +1,320 @@
+use alloc::{vec, vec::Vec};
+use core::mem;
+
+use crate::{
+    chunk::{Chunk, OpCode, Span},
+    instruction::{self, varint_len, write_varint},
+};
+
+/// An instruction of a chunk that is being rewritten, with its jump target
+/// resolved, so that instructions can be dropped, replaced, or made longer
+/// without breaking the jumps around them.
+#[derive(Debug)]
+pub struct Instruction {
+    // jumps are always one of OP_JUMP, OP_JUMP_IF_FALSE or OP_LOOP, and the
+    // length of their operand is picked again when the chunk is encoded
+    pub op: OpCode,
+    // the bytes of the operands, which are empty for jumps, as their operand
+    // is worked out from `target`
+    pub operands: Vec<u8>,
+    pub span: Span,
+    // the spans of the bytes of the operands, where they are not all `span`,
+    // as for an OP_CONCAT_N, which has the span of each OP_ADD that it
+    // replaced
+    pub operand_spans: Vec<Span>,
+    // index of the instruction that a jump lands on
+    pub target: Option<usize>,
+    pub removed: bool,
+}
+
+/// Decodes `chunk` into its instructions. `long_jumps` holds the targets of
+/// forward jumps whose offset did not fit their operand, as `(where the jump
+/// is, where it lands)` byte offsets.
+///
+/// Returns `None` if the chunk is malformed.
+pub fn decode(chunk: &Chunk, long_jumps: &[(usize, usize)]) -> Option<Vec<Instruction>> {
+    let mut instructions = vec![];
+    // byte offset of every instruction, for resolving the jump targets
+    let mut offsets = vec![];
+    let mut targets = vec![];
+
+    let mut offset = 0;
+    while offset < chunk.code_len() {
+        let (instruction, after) = instruction::Instruction::decode(chunk, offset)?;
+
+        let jump = match instruction {
+            instruction::Instruction::Jump(jump) | instruction::Instruction::JumpIfFalse(jump) => {
+                Some(after + jump as usize)
+            }
+            instruction::Instruction::Loop(jump) => Some(after.checked_sub(jump as usize)?),
+            _ => None,
+        };
+        if let Some(jump) = jump {
+            let target = match long_jumps.iter().find(|(at, _)| *at == offset) {
+                Some((_, target)) => *target,
+                None => jump,
+            };
+            targets.push((instructions.len(), target));
+        }
+
+        let span = chunk.get_span(offset);
+        let operands = if jump.is_some() {
+            vec![]
+        } else {
+            instruction.operands()
+        };
+        let mut operand_spans = (offset + 1..after)
+            .map(|i| chunk.get_span(i))
+            .collect::<Vec<_>>();
+        if operand_spans.len() != operands.len() || operand_spans.iter().all(|&s| s == span) {
+            operand_spans = vec![];
+        }
+
+        offsets.push(offset);
+        instructions.push(Instruction {
+            op: instruction.opcode(),
+            operands,
+            span,
+            operand_spans,
+            target: None,
+            removed: false,
+        });
+        offset = after;
+    }
+
+    for (i, target) in targets {
+        instructions[i].target = Some(offsets.binary_search(&target).ok()?);
+    }
+
+    Some(instructions)
+}
+
+/// The first instruction at or after `i` that is still there, since that is
+/// where execution ends up when it reaches `i`.
+pub fn resolve(instructions: &[Instruction], i: usize) -> usize {
+    (i..instructions.len())
+        .find(|&i| !instructions[i].removed)
+        .unwrap_or(instructions.len())
+}
+
+/// The next instruction after `i` that is still there.
+pub fn next(instructions: &[Instruction], i: usize) -> Option<usize> {
+    let next = resolve(instructions, i + 1);
+    (next < instructions.len()).then_some(next)
+}
+
+/// How many jumps land on each instruction, for [`is_targeted`].
+pub fn jump_targets(instructions: &[Instruction]) -> Vec<usize> {
+    let mut targets = vec![0; instructions.len() + 1];
+    instructions
+        .iter()
+        .filter(|instruction| !instruction.removed)
+        .filter_map(|instruction| instruction.target)
+        .for_each(|target| targets[target] += 1);
+    targets
+}
+
+/// Whether any of the jumps counted in `targets` lands on instruction `i`,
+/// including the jumps to the removed instructions right before it.
+pub fn is_targeted(instructions: &[Instruction], targets: &[usize], i: usize) -> bool {
+    let mut i = i;
+    loop {
+        if targets[i] > 0 {
+            return true;
+        }
+        if i == 0 || !instructions[i - 1].removed {
+            return false;
+        }
+        i -= 1;
+    }
+}
+
+// where every instruction starts, with removed ones sharing the offset of the
+// instruction after them, and one more offset for the end of the code.
+// `sizes` are the lengths of the operands of the jumps
+fn layout(instructions: &[Instruction], sizes: &[usize]) -> Vec<usize> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for (instruction, size) in instructions.iter().zip(sizes) {
+        offsets.push(offset);
+        if instruction.removed {
+            continue;
+        }
+        offset += match instruction.target {
+            Some(_) => 1 + size,
+            None => 1 + instruction.operands.len(),
+        };
+    }
+    offsets.push(offset);
+    offsets
+}
+
+/// Writes `instructions` back as the code of `chunk`, keeping its constants.
+/// The operands of jumps are as short as their offsets allow.
+///
+/// Returns `false` and leaves the chunk as it is if a jump is too far for a
+/// 32-bit offset.
+pub fn encode(chunk: &mut Chunk, instructions: &[Instruction]) -> bool {
+    // a jump that is made longer can push other jumps past the length of
+    // their operand, so jumps are made longer until every one of them fits.
+    // they never get shorter, which makes this stop
+    let mut sizes = vec![1; instructions.len()];
+    let offsets = loop {
+        let offsets = layout(instructions, &sizes);
+        let mut widened = false;
+
+        for (i, instruction) in instructions.iter().enumerate() {
+            let Some(target) = instruction.target else {
+                continue;
+            };
+            if instruction.removed {
+                continue;
+            }
+
+            let target = offsets[resolve(instructions, target)];
+            let after = offsets[i + 1];
+            let Ok(jump) = u32::try_from(target.abs_diff(after)) else {
+                return false;
+            };
+            if varint_len(jump) > sizes[i] {
+                sizes[i] = varint_len(jump);
+                widened = true;
+            }
+        }
+
+        if !widened {
+            break offsets;
+        }
+    };
+
+    let mut encoded = Chunk::new();
+    for (i, instruction) in instructions.iter().enumerate() {
+        if instruction.removed {
+            continue;
+        }
+
+        let mut op = instruction.op;
+        let mut operands = instruction.operands.clone();
+        if let Some(target) = instruction.target {
+            let target = offsets[resolve(instructions, target)];
+            let after = offsets[i + 1];
+
+            let jump = if target >= after {
+                // a threaded loop might now go forwards
+                if op == OpCode::Loop {
+                    op = OpCode::Jump;
+                }
+                target - after
+            } else {
+                if op == OpCode::Jump {
+                    op = OpCode::Loop;
+                }
+                if op == OpCode::JumpIfFalse {
+                    return false;
+                }
+                after - target
+            };
+
+            // checked to fit when the sizes were picked
+            operands = vec![];
+            write_varint(&mut operands, jump as u32, sizes[i]);
+        }
+
+        encoded.write_span(op as u8, instruction.span);
+        operands.iter().enumerate().for_each(|(n, operand)| {
+            let span = instruction.operand_spans.get(n);
+            encoded.write_span(*operand, span.copied().unwrap_or(instruction.span));
+        });
+    }
+
+    *encoded.constants_mut() = mem::take(chunk.constants_mut());
+    *encoded.local_names_mut() = mem::take(chunk.local_names_mut());
+    *chunk = encoded;
+    true
+}
+
+/// Gives the forward jumps in `long_jumps` (see [`decode`]) their final
+/// offset, making them and the jumps around them longer where needed.
+///
+/// Returns `false` if that is not possible.
+pub fn widen_jumps(chunk: &mut Chunk, long_jumps: &[(usize, usize)]) -> bool {
+    match decode(chunk, long_jumps) {
+        Some(instructions) => encode(chunk, &instructions),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JUMP: u8 = OpCode::Jump as u8;
+    const LOOP: u8 = OpCode::Loop as u8;
+    const NIL: u8 = OpCode::Nil as u8;
+    const RETURN: u8 = OpCode::Return as u8;
+
+    fn chunk(code: &[u8]) -> Chunk {
+        let mut chunk = Chunk::new();
+        code.iter().for_each(|byte| chunk.write(*byte, 1, 1));
+        chunk
+    }
+
+    #[test]
+    fn test_relocate_roundtrip() {
+        // 0: JUMP -> 3, 2: NIL, 3: LOOP -> 2, 5: RETURN
+        let code = [JUMP, 1, NIL, LOOP, 3, RETURN];
+        let mut relocated = chunk(&code);
+        assert!(widen_jumps(&mut relocated, &[]));
+        assert_eq!(relocated, chunk(&code));
+
+        // padded operands are made as short as they can be
+        let mut relocated = chunk(&[JUMP, 0x81, 0x00, NIL, RETURN]);
+        assert!(widen_jumps(&mut relocated, &[]));
+        assert_eq!(relocated, chunk(&[JUMP, 1, NIL, RETURN]));
+    }
+
+    #[test]
+    fn test_relocate_widen_jumps() {
+        let nils = 128;
+
+        // a jump over more code than a one-byte offset can hold, with its
+        // target left to `long_jumps`
+        let mut code = vec![JUMP, 0];
+        code.extend(vec![NIL; nils]);
+        code.push(RETURN);
+        let mut widened = chunk(&code);
+        assert!(widen_jumps(&mut widened, &[(0, 2 + nils)]));
+
+        let mut expected = vec![JUMP, 0x80, 0x01];
+        expected.extend(vec![NIL; nils]);
+        expected.push(RETURN);
+        assert_eq!(widened, chunk(&expected));
+
+        // widening the jump makes the loop around it too long for a
+        // one-byte offset as well
+        let loop_start = 1;
+        let mut code = vec![NIL, JUMP, 0];
+        code.extend(vec![NIL; 123]);
+        let after_loop = code.len() + 2;
+        code.push(LOOP);
+        code.push((after_loop - loop_start) as u8);
+        code.extend(vec![NIL; 10]);
+        code.push(RETURN);
+        let target = code.len() - 1;
+        let mut widened = chunk(&code);
+        assert!(widen_jumps(&mut widened, &[(1, target)]));
+
+        // the jump and the loop both grew by a byte
+        assert_eq!(widened.code_len(), code.len() + 2);
+        let read = |at: usize| {
+            instruction::read_varint(|i| Some(widened.get_code(at + i))).expect("valid")
+        };
+        assert_eq!(read(2), (136, 2));
+        assert_eq!(4 + 136, widened.code_len() - 1);
+        let loop_at = after_loop - 2 + 1;
+        assert_eq!(widened.get_code(loop_at), LOOP);
+        let (back, len) = read(loop_at + 1);
+        assert_eq!(len, 2);
+        assert_eq!(loop_at + 3 - back as usize, loop_start);
+    }
+}
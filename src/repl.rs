@@ -0,0 +1,823 @@
+// What the REPL needs besides the VM that runs each input.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io::{self, BufRead},
+};
+
+use crate::{
+    color,
+    scanner::{Scanner, TokenKind},
+    value::OwnedValue,
+};
+
+/// Whether `input` could be compiled as it is, rather than being cut off in
+/// the middle: with a bracket or a string left open, or ending in a token
+/// that something has to follow, like an operator or `var`. The REPL keeps
+/// reading lines until the input is complete.
+///
+/// This only looks at the tokens, so complete input can still fail to
+/// compile, which is for the compiler to report.
+pub fn is_complete(input: &str) -> bool {
+    let mut depth = 0i32;
+    let mut last = None;
+    for token in Scanner::new(input) {
+        match token.kind {
+            TokenKind::LeftParen | TokenKind::LeftBrace => depth += 1,
+            TokenKind::RightParen | TokenKind::RightBrace => depth -= 1,
+            TokenKind::Error if token.lexeme == "Unterminated string." => return false,
+            TokenKind::EndOfFile => break,
+            _ => {}
+        }
+        last = Some(token.kind);
+    }
+
+    // too many closing brackets won't be fixed by more lines
+    if depth > 0 {
+        return false;
+    }
+    !matches!(
+        last,
+        Some(
+            TokenKind::Comma
+                | TokenKind::Dot
+                | TokenKind::Minus
+                | TokenKind::Plus
+                | TokenKind::Slash
+                | TokenKind::Star
+                | TokenKind::Bang
+                | TokenKind::BangEqual
+                | TokenKind::Equal
+                | TokenKind::EqualEqual
+                | TokenKind::Greater
+                | TokenKind::GreaterEqual
+                | TokenKind::Less
+                | TokenKind::LessEqual
+                | TokenKind::And
+                | TokenKind::Class
+                | TokenKind::Else
+                | TokenKind::For
+                | TokenKind::Fun
+                | TokenKind::If
+                | TokenKind::Or
+                | TokenKind::Print
+                | TokenKind::Return
+                | TokenKind::Super
+                | TokenKind::Var
+                | TokenKind::While
+        )
+    )
+}
+
+/// `input` with the semicolon that ends a statement, if it was left out at
+/// the end, so that `1 + 2` can be typed like `1 + 2;`. Input that is cut
+/// off is left for the compiler to report as it is.
+pub fn with_semicolon(input: &str) -> Cow<'_, str> {
+    if !is_complete(input) {
+        return Cow::Borrowed(input);
+    }
+    let last = Scanner::new(input)
+        .take_while(|token| token.kind != TokenKind::EndOfFile)
+        .last();
+    match last {
+        Some(token) if !matches!(token.kind, TokenKind::Semicolon | TokenKind::RightBrace) => {
+            Cow::Owned(format!("{};{}", &input[..token.end], &input[token.end..]))
+        }
+        _ => Cow::Borrowed(input),
+    }
+}
+
+/// The number of the next result that the REPL prints, which is kept as
+/// `_<n>`, after the last one in `globals`.
+pub fn next_result(globals: &[String]) -> u32 {
+    globals
+        .iter()
+        .filter_map(|name| name.strip_prefix('_')?.parse::<u32>().ok())
+        .max()
+        .map_or(1, |last| last + 1)
+}
+
+/// `input` with the results that it uses, `_` and `_<n>`, written out as
+/// the values that `result` has for them before it runs, for `:save`, since
+/// a script doesn't have the results of the REPL. `None` if one of them is
+/// declared or assigned to, or is a value that Lox can't write, like a
+/// function or a string with a `"` in it.
+pub fn expand_results(input: &str, result: impl Fn(&str) -> Option<OwnedValue>) -> Option<String> {
+    let is_result = |name: &str| {
+        name.strip_prefix('_')
+            .is_some_and(|n| n.is_empty() || n.parse::<u32>().is_ok())
+    };
+
+    let mut expanded = String::new();
+    let mut last = 0;
+    let mut previous = None;
+    let mut tokens = Scanner::new(input)
+        .take_while(|token| token.kind != TokenKind::EndOfFile)
+        .peekable();
+    while let Some(token) = tokens.next() {
+        let kind = previous.replace(token.kind);
+        if token.kind != TokenKind::Identifier || !is_result(token.lexeme) {
+            continue;
+        }
+        let Some(value) = result(token.lexeme) else {
+            continue;
+        };
+        let next = tokens.peek().map(|token| token.kind);
+        if matches!(kind, Some(TokenKind::Var | TokenKind::Fun)) || next == Some(TokenKind::Equal) {
+            return None;
+        }
+        expanded.push_str(&input[last..token.start]);
+        expanded.push_str(&literal(&value)?);
+        last = token.end;
+    }
+    expanded.push_str(&input[last..]);
+    Some(expanded)
+}
+
+// `value` written in Lox, in brackets if it is more than a single token
+fn literal(value: &OwnedValue) -> Option<String> {
+    let literal = match value {
+        OwnedValue::Nil => "nil".to_string(),
+        OwnedValue::Bool(value) => value.to_string(),
+        OwnedValue::Number(value) if value.is_nan() => return Some("(0 / 0)".to_string()),
+        OwnedValue::Number(value) if value.is_infinite() => {
+            return Some(format!("({}1 / 0)", if *value < 0.0 { "-" } else { "" }));
+        }
+        // which is never in scientific notation, which Lox doesn't have
+        OwnedValue::Number(value) => value.to_string(),
+        OwnedValue::Decimal(decimal) => decimal.to_string(),
+        OwnedValue::String(chars) if !chars.contains('"') => format!("\"{}\"", chars),
+        OwnedValue::String(_) | OwnedValue::Function(_) => return None,
+    };
+    // a negative number is negated as it runs
+    Some(if literal.starts_with('-') {
+        format!("({})", literal)
+    } else {
+        literal
+    })
+}
+
+/// A command of the REPL rather than Lox, which starts with `:`.
+#[derive(Debug, PartialEq)]
+pub enum Command<'a> {
+    /// Print the objects on the heap.
+    Heap,
+    /// Run a script into the session.
+    Load(&'a str),
+    /// Write the inputs that ran to a script.
+    Save(&'a str),
+}
+
+/// The command that `input` is, or what is wrong with it, or `None` for
+/// Lox, which never starts with `:`.
+pub fn command(input: &str) -> Option<Result<Command<'_>, String>> {
+    let input = input.trim();
+    if !input.starts_with(':') {
+        return None;
+    }
+    let (name, path) = input
+        .split_once(char::is_whitespace)
+        .map_or((input, ""), |(name, path)| (name, path.trim()));
+    let command = match (name, path) {
+        (":heap", "") => Ok(Command::Heap),
+        (":heap", _) => Err("':heap' takes no path.".to_string()),
+        (":load" | ":save", "") => Err(format!("'{}' takes a path.", name)),
+        (":load", path) => Ok(Command::Load(path)),
+        (":save", path) => Ok(Command::Save(path)),
+        _ => Err(format!("Unknown command '{}'.", name)),
+    };
+    Some(command)
+}
+
+/// The settings of the REPL, from a file in a small part of TOML:
+///
+/// ```toml
+/// # the defaults
+/// color = true
+/// trace = false
+/// prompt = "> "
+/// continuation_prompt = "... "
+/// load = []
+/// history = 100
+/// ```
+///
+/// The scripts in `load` run before the first prompt, and `history` is how
+/// many of the lines typed last the Up and Down keys go through. The command line
+/// wins over the file, so `color` only turns color off, where it would have
+/// been on, and `trace` only traces when no other trace was asked for.
+#[derive(Debug, PartialEq)]
+pub struct Config {
+    pub color: bool,
+    pub trace: bool,
+    pub prompt: String,
+    pub continuation_prompt: String,
+    pub load: Vec<String>,
+    pub history: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            color: true,
+            trace: false,
+            prompt: "> ".to_string(),
+            continuation_prompt: "... ".to_string(),
+            load: vec![],
+            history: 100,
+        }
+    }
+}
+
+// a value of a setting, which are the only kinds that the settings need
+// (here and in the linter's)
+#[derive(Debug, PartialEq)]
+pub(crate) enum Setting {
+    Bool(bool),
+    String(String),
+    Strings(Vec<String>),
+    Number(usize),
+}
+
+impl Config {
+    /// The settings in `text`, with the defaults for the ones it leaves out,
+    /// or the first line that is wrong and why.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        for (line, text) in (1..).zip(text.lines()) {
+            let error = |message: String| format!("line {}: {}", line, message);
+            let text = text.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = text.split_once('=') else {
+                return Err(error("Expect '=' after the setting.".to_string()));
+            };
+            let (key, value) = (key.trim(), parse_setting(value).map_err(error)?);
+            match (key, value) {
+                ("color", Setting::Bool(color)) => config.color = color,
+                ("trace", Setting::Bool(trace)) => config.trace = trace,
+                ("prompt", Setting::String(prompt)) => config.prompt = prompt,
+                ("continuation_prompt", Setting::String(prompt)) => {
+                    config.continuation_prompt = prompt
+                }
+                ("load", Setting::Strings(paths)) => config.load = paths,
+                ("history", Setting::Number(size)) => config.history = size,
+                ("color" | "trace", _) => {
+                    return Err(error(format!("'{}' must be true or false.", key)));
+                }
+                ("prompt" | "continuation_prompt", _) => {
+                    return Err(error(format!("'{}' must be a string.", key)));
+                }
+                ("load", _) => return Err(error("'load' must be a list of paths.".to_string())),
+                ("history", _) => return Err(error("'history' must be a number.".to_string())),
+                _ => return Err(error(format!("Unknown setting '{}'.", key))),
+            }
+        }
+        Ok(config)
+    }
+}
+
+// the value after the `=`, which can be followed by a comment
+pub(crate) fn parse_setting(text: &str) -> Result<Setting, String> {
+    let (value, rest) = parse_value(text.trim_start())?;
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("Unexpected '{}' after the value.", rest));
+    }
+    Ok(value)
+}
+
+// the value that `text` starts with, and what comes after it
+fn parse_value(text: &str) -> Result<(Setting, &str), String> {
+    if let Some(rest) = text.strip_prefix("true") {
+        return Ok((Setting::Bool(true), rest));
+    }
+    if let Some(rest) = text.strip_prefix("false") {
+        return Ok((Setting::Bool(false), rest));
+    }
+    if text.starts_with('"') {
+        let (string, rest) = parse_string(text)?;
+        return Ok((Setting::String(string), rest));
+    }
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    if digits > 0 {
+        let number = text[..digits]
+            .parse()
+            .map_err(|_| format!("'{}' is too large.", &text[..digits]))?;
+        return Ok((Setting::Number(number), &text[digits..]));
+    }
+    let Some(mut rest) = text.strip_prefix('[') else {
+        return Err(format!("Expect a value, not '{}'.", text.trim()));
+    };
+
+    let mut strings = vec![];
+    loop {
+        rest = rest.trim_start();
+        if let Some(rest) = rest.strip_prefix(']') {
+            return Ok((Setting::Strings(strings), rest));
+        }
+        let (string, after) = parse_string(rest)?;
+        strings.push(string);
+        rest = after.trim_start();
+        rest = match rest.strip_prefix(',') {
+            Some(after) => after,
+            None if rest.starts_with(']') => rest,
+            None => return Err("Expect ',' or ']' in the list.".to_string()),
+        };
+    }
+}
+
+// the string in double quotes that `text` starts with, where `\"` and `\\`
+// are a quote and a backslash, and what comes after it
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    let Some(text) = text.strip_prefix('"') else {
+        return Err("Expect a string.".to_string());
+    };
+    let mut string = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &text[i + 1..])),
+            '\\' => match chars.next() {
+                Some((_, c @ ('"' | '\\'))) => string.push(c),
+                _ => return Err("Only '\\\"' and '\\\\' can be escaped.".to_string()),
+            },
+            c => string.push(c),
+        }
+    }
+    Err("Unterminated string.".to_string())
+}
+
+// the keywords, which are completed along with the globals
+const KEYWORDS: [&str; 16] = [
+    "and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return", "super",
+    "this", "true", "var", "while",
+];
+
+/// What the word that `line` ends with could be completed to, from the
+/// keywords and `globals`, sorted, along with where the word starts. A word
+/// after a `.` names a field or a method, which Lox can't have without
+/// classes, so it has nothing to complete to.
+pub fn completions(line: &str, globals: &[String]) -> (usize, Vec<String>) {
+    let start = line
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .map_or(0, |i| i + 1);
+    let word = &line[start..];
+    let after_dot = line[..start].trim_end().ends_with('.');
+    if word.is_empty() || word.starts_with(|c: char| c.is_ascii_digit()) || after_dot {
+        return (start, vec![]);
+    }
+
+    let mut candidates = KEYWORDS
+        .iter()
+        .map(|keyword| keyword.to_string())
+        .chain(globals.iter().cloned())
+        .filter(|candidate| candidate.starts_with(word) && candidate != word)
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates.dedup();
+    (start, candidates)
+}
+
+// the longest prefix that all of `candidates` start with
+fn common_prefix(candidates: &[String]) -> &str {
+    let Some(first) = candidates.first() else {
+        return "";
+    };
+    let len = candidates.iter().fold(first.len(), |len, candidate| {
+        first
+            .bytes()
+            .zip(candidate.bytes())
+            .take(len)
+            .take_while(|(a, b)| a == b)
+            .count()
+    });
+    &first[..len]
+}
+
+const CTRL_C: u8 = 3;
+const CTRL_D: u8 = 4;
+const BACKSPACE: u8 = 8;
+const TAB: u8 = b'\t';
+const ESCAPE: u8 = 0x1b;
+const DELETE: u8 = 0x7f;
+
+/// Reads lines a key at a time from a terminal that doesn't echo them or
+/// wait for the end of the line, so that Tab can complete the word being
+/// typed, and Up and Down go through the lines that were read before. Only
+/// the end of the line can be edited.
+pub struct LineEditor<R: BufRead, W: io::Write> {
+    input: io::Bytes<R>,
+    output: W,
+    // whether the line is shown with syntax highlighting
+    highlight: bool,
+    // the lines that were read, the oldest first, without their newlines
+    history: VecDeque<String>,
+    history_size: usize,
+}
+
+impl<R: BufRead, W: io::Write> LineEditor<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self {
+            input: input.bytes(),
+            output,
+            highlight: false,
+            history: VecDeque::new(),
+            history_size: Config::default().history,
+        }
+    }
+
+    /// Shows the line with its keywords, literals and comments in color.
+    pub fn set_highlight(&mut self, highlight: bool) {
+        self.highlight = highlight;
+    }
+
+    /// Keeps the last `size` lines that were read for Up and Down, which
+    /// don't have any with 0.
+    pub fn set_history_size(&mut self, size: usize) {
+        self.history_size = size;
+        self.history
+            .drain(..self.history.len().saturating_sub(size));
+    }
+
+    /// The next line after showing `prompt`, with its newline, or `None` at
+    /// the end of the input. A line that is given up on with Ctrl+C is an
+    /// error of the kind [`io::ErrorKind::Interrupted`].
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        complete: impl Fn(&str) -> (usize, Vec<String>),
+    ) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        // the line of the history that is shown, which is past the last one
+        // while it's the line being typed, and that line meanwhile
+        let mut shown_history = self.history.len();
+        let mut typed = String::new();
+        write!(self.output, "{}", prompt)?;
+        self.output.flush()?;
+
+        loop {
+            let Some(byte) = self.input.next().transpose()? else {
+                return Ok(None);
+            };
+            match byte {
+                b'\r' | b'\n' => {
+                    writeln!(self.output)?;
+                    self.remember(&line);
+                    line.push('\n');
+                    return Ok(Some(line));
+                }
+                CTRL_D if line.is_empty() => return Ok(None),
+                CTRL_C => {
+                    writeln!(self.output, "^C")?;
+                    return Err(io::ErrorKind::Interrupted.into());
+                }
+                BACKSPACE | DELETE => {
+                    line.pop();
+                }
+                TAB => {
+                    let (start, candidates) = complete(&line);
+                    let prefix = common_prefix(&candidates);
+                    if prefix.len() > line.len() - start {
+                        line.truncate(start);
+                        line.push_str(prefix);
+                    } else if candidates.len() > 1 {
+                        writeln!(self.output)?;
+                        writeln!(self.output, "{}", candidates.join("  "))?;
+                    }
+                }
+                // Up and Down, and the other keys like them are left alone
+                ESCAPE => match self.read_escape()? {
+                    Some(b'A') if shown_history > 0 => {
+                        if shown_history == self.history.len() {
+                            typed = core::mem::take(&mut line);
+                        }
+                        shown_history -= 1;
+                        line = self.history[shown_history].clone();
+                    }
+                    Some(b'B') if shown_history < self.history.len() => {
+                        shown_history += 1;
+                        line = match self.history.get(shown_history) {
+                            Some(older) => older.clone(),
+                            None => core::mem::take(&mut typed),
+                        };
+                    }
+                    _ => {}
+                },
+                byte if byte < b' ' => {}
+                byte => line.push(self.read_char(byte)?),
+            }
+
+            let shown = if self.highlight {
+                color::highlight(&line)
+            } else {
+                line.clone()
+            };
+            write!(self.output, "\r{}{}\x1b[K", prompt, shown)?;
+            self.output.flush()?;
+        }
+    }
+
+    // the character that starts with `first`, which can take more bytes
+    fn read_char(&mut self, first: u8) -> io::Result<char> {
+        let len = match first {
+            0xf0.. => 4,
+            0xe0.. => 3,
+            0xc0.. => 2,
+            _ => 1,
+        };
+        let mut bytes = vec![first];
+        for _ in 1..len {
+            match self.input.next().transpose()? {
+                Some(byte) => bytes.push(byte),
+                None => break,
+            }
+        }
+        Ok(String::from_utf8_lossy(&bytes)
+            .chars()
+            .next()
+            .unwrap_or(char::REPLACEMENT_CHARACTER))
+    }
+
+    // reads the rest of an escape sequence like `ESC [ A` (Up), returning
+    // the byte that it ends with
+    fn read_escape(&mut self) -> io::Result<Option<u8>> {
+        if self.input.next().transpose()? != Some(b'[') {
+            return Ok(None);
+        }
+        while let Some(byte) = self.input.next().transpose()? {
+            if (0x40..=0x7e).contains(&byte) {
+                return Ok(Some(byte));
+            }
+        }
+        Ok(None)
+    }
+
+    // adds `line` to the history, unless it is blank or the same as the
+    // last one, dropping the oldest once there are too many
+    fn remember(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        self.history.push_back(line.to_string());
+        if self.history.len() > self.history_size {
+            self.history.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_complete() {
+        for input in [
+            "",
+            "print 1;",
+            "fun f() {\n  return 1;\n}",
+            "print (1 + 2);",
+            // the compiler reports these
+            "print 1",
+            "print 1);",
+            "}",
+            "print \"a\" @",
+        ] {
+            assert!(is_complete(input), "{:?}", input);
+        }
+
+        for input in [
+            "fun f() {",
+            "fun f() {\n  if (true) {\n  }",
+            "print (1 +",
+            "print 1 +",
+            "var a =",
+            "var",
+            "print a.",
+            "f(1,",
+            "print \"a",
+            "if (a) print 1; else",
+            "print a and",
+        ] {
+            assert!(!is_complete(input), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_with_semicolon() {
+        assert_eq!(with_semicolon("1 + 2\n"), "1 + 2;\n");
+        assert_eq!(
+            with_semicolon("print a // a comment\n"),
+            "print a; // a comment\n"
+        );
+        for input in ["", "\n", "a;\n", "{ a; }", "fun f() {}", "1 +", "{ a;"] {
+            assert_eq!(with_semicolon(input), input);
+        }
+    }
+
+    #[test]
+    fn test_next_result() {
+        assert_eq!(next_result(&[]), 1);
+        let globals = ["_", "_1", "_12", "_3", "a_4", "_x"].map(String::from);
+        assert_eq!(next_result(&globals), 13);
+    }
+
+    #[test]
+    fn test_expand_results() {
+        let result = |name: &str| match name {
+            "_" | "_2" => Some(OwnedValue::Number(-1.5)),
+            "_1" => Some(OwnedValue::String("a b".to_string())),
+            "_3" => Some(OwnedValue::Number(f64::INFINITY)),
+            "_4" => Some(OwnedValue::Function("<fn f>".to_string())),
+            "_5" => Some(OwnedValue::String("\"".to_string())),
+            "_6" => Some(OwnedValue::Number(1e21)),
+            _ => None,
+        };
+        let expand = |input| expand_results(input, result);
+
+        assert_eq!(expand("print 1;"), Some("print 1;".to_string()));
+        assert_eq!(
+            expand("print _ + _2 * _3;"),
+            Some("print (-1.5) + (-1.5) * (1 / 0);".to_string())
+        );
+        assert_eq!(
+            expand("print _1 + \"_1\"; // _1"),
+            Some("print \"a b\" + \"_1\"; // _1".to_string())
+        );
+        assert_eq!(expand("_6;"), Some("1000000000000000000000;".to_string()));
+        // names that aren't results, or that aren't defined, are left alone
+        assert_eq!(expand("_x + a_1 + _7;"), Some("_x + a_1 + _7;".to_string()));
+
+        for input in ["_4();", "print _5;", "var _ = 1;", "_1 = 2;", "fun _2() {}"] {
+            assert_eq!(expand(input), None, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_command() {
+        assert_eq!(command("print 1;"), None);
+        assert_eq!(command(":heap\n"), Some(Ok(Command::Heap)));
+        assert_eq!(
+            command(" :load  lib/a b.lox \n"),
+            Some(Ok(Command::Load("lib/a b.lox")))
+        );
+        assert_eq!(command(":save out.lox"), Some(Ok(Command::Save("out.lox"))));
+        assert_eq!(
+            command(":save"),
+            Some(Err("':save' takes a path.".to_string()))
+        );
+        assert_eq!(
+            command(":heap x"),
+            Some(Err("':heap' takes no path.".to_string()))
+        );
+        assert_eq!(
+            command(":quit"),
+            Some(Err("Unknown command ':quit'.".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(Config::parse(""), Ok(Config::default()));
+        assert_eq!(
+            Config::parse(
+                "# mine\n\
+                 color = false\n\
+                 trace = true # for now\n\
+                 prompt = \"lox\\\"> \"\n\
+                 continuation_prompt = \"\"\n\
+                 load = [\"prelude.lox\", \"lib/\\\\a.lox\",]\n\
+                 history = 1000\n"
+            ),
+            Ok(Config {
+                color: false,
+                trace: true,
+                prompt: "lox\"> ".to_string(),
+                continuation_prompt: "".to_string(),
+                load: vec!["prelude.lox".to_string(), "lib/\\a.lox".to_string()],
+                history: 1000,
+            })
+        );
+
+        for (text, error) in [
+            ("color", "line 1: Expect '=' after the setting."),
+            ("\nhistory = ten", "line 2: Expect a value, not 'ten'."),
+            ("history = true", "line 1: 'history' must be a number."),
+            ("history = -1", "line 1: Expect a value, not '-1'."),
+            (
+                "history = 99999999999999999999",
+                "line 1: '99999999999999999999' is too large.",
+            ),
+            ("size = 1", "line 1: Unknown setting 'size'."),
+            ("color = \"yes\"", "line 1: 'color' must be true or false."),
+            ("prompt = true", "line 1: 'prompt' must be a string."),
+            (
+                "load = \"a.lox\"",
+                "line 1: 'load' must be a list of paths.",
+            ),
+            (
+                "load = [\"a\" \"b\"]",
+                "line 1: Expect ',' or ']' in the list.",
+            ),
+            ("prompt = \"> ", "line 1: Unterminated string."),
+            (
+                "trace = true false",
+                "line 1: Unexpected 'false' after the value.",
+            ),
+        ] {
+            assert_eq!(Config::parse(text), Err(error.to_string()), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_completions() {
+        let globals = ["fib".to_string(), "first".to_string(), "x".to_string()];
+        assert_eq!(
+            completions("print fi", &globals),
+            (6, vec!["fib".to_string(), "first".to_string()])
+        );
+        assert_eq!(
+            completions("f", &globals),
+            (
+                0,
+                ["false", "fib", "first", "for", "fun"]
+                    .map(String::from)
+                    .to_vec()
+            )
+        );
+        assert_eq!(completions("wh", &globals), (0, vec!["while".to_string()]));
+        for line in ["", "print ", "x", "1 + 2", "a.f", "print 1e", "zzz"] {
+            assert_eq!(
+                completions(line, &globals).1,
+                Vec::<String>::new(),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_line_editor() {
+        fn read(input: &[u8]) -> (io::Result<Option<String>>, String) {
+            let mut output = vec![];
+            let globals = ["fib".to_string(), "first".to_string()];
+            let result = LineEditor::new(input, &mut output)
+                .read_line("> ", |line| completions(line, &globals));
+            (result, String::from_utf8(output).expect("valid utf8"))
+        }
+
+        let (line, output) = read(b"print 1\x7f2\n");
+        assert_eq!(line.ok(), Some(Some("print 2\n".to_string())));
+        assert!(output.ends_with("\r> print 2\x1b[K\n"));
+
+        // tab completes as far as the candidates agree, and lists them when
+        // they don't
+        let (line, output) = read(b"wh\t (true) f\ti\t\tb\t(2);\n");
+        assert_eq!(line.ok(), Some(Some("while (true) fib(2);\n".to_string())));
+        assert!(output.contains("\nfib  first\n"));
+
+        // the other arrow keys do nothing, and other characters are kept
+        // whole
+        let (line, _) = read("\x1b[Dé\n".as_bytes());
+        assert_eq!(line.ok(), Some(Some("é\n".to_string())));
+
+        assert_eq!(read(b"\x04").0.ok(), Some(None));
+        assert_eq!(read(b"").0.ok(), Some(None));
+        assert_eq!(
+            read(b"print\x03").0.map_err(|error| error.kind()),
+            Err(io::ErrorKind::Interrupted)
+        );
+    }
+
+    #[test]
+    fn test_line_editor_history() {
+        let input = b"a\nb\n\nb\nc\n\x1b[A\x1b[A\x1b[A\x1b[A\n\x1b[A\x1b[Bd\x1b[A\x1b[B\n\x1b[A\n";
+        let mut editor = LineEditor::new(&input[..], io::sink());
+        editor.set_history_size(3);
+        let mut read = || editor.read_line("> ", |_| (0, vec![])).expect("no error");
+        for line in ["a\n", "b\n", "\n", "b\n", "c\n"] {
+            assert_eq!(read().as_deref(), Some(line));
+        }
+        // blank lines and repeats aren't kept, and only the last three are
+        assert_eq!(read().as_deref(), Some("a\n"));
+        // Down goes back to the line being typed
+        assert_eq!(read().as_deref(), Some("d\n"));
+        assert_eq!(read().as_deref(), Some("d\n"));
+        assert_eq!(read(), None);
+
+        // without a history, Up does nothing
+        let mut editor = LineEditor::new(&b"a\n\x1b[A\n"[..], io::sink());
+        editor.set_history_size(0);
+        assert_eq!(
+            editor.read_line("> ", |_| (0, vec![])).ok(),
+            Some(Some("a\n".to_string()))
+        );
+        assert_eq!(
+            editor.read_line("> ", |_| (0, vec![])).ok(),
+            Some(Some("\n".to_string()))
+        );
+    }
+}
@@ -0,0 +1,246 @@
+// What the scripts that `clox transpile` turns into Rust link against: the
+// operators of Lox on `Value`s, with the same runtime errors as the VM, and
+// the globals and strings that the whole script shares.
+//
+// Everything takes `&self`, so that the operands of an operator can be
+// computed with the runtime in the same expression, the way that the
+// generated code is written.
+
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, Write},
+};
+
+use crate::{
+    object::Heap,
+    table::{Table, hash_string},
+    value::Value,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RuntimeError {
+    pub message: String,
+    pub line: usize,
+}
+
+impl RuntimeError {
+    pub fn new<S: AsRef<str>>(message: S, line: usize) -> Self {
+        Self {
+            message: message.as_ref().to_string(),
+            line,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}\n[line {}] in script", self.message, self.line)
+    }
+}
+
+pub struct Runtime<W: Write = io::Stdout> {
+    heap: RefCell<Heap>,
+    globals: RefCell<Table<Value>>,
+    // where the `print` statement writes to
+    output: RefCell<W>,
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Self::with_output(io::stdout())
+    }
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> Runtime<W> {
+    pub fn with_output(output: W) -> Self {
+        Self {
+            heap: RefCell::new(Heap::new()),
+            globals: RefCell::new(Table::new()),
+            output: RefCell::new(output),
+        }
+    }
+
+    pub fn into_output(self) -> W {
+        self.output.into_inner()
+    }
+
+    pub fn string(&self, chars: &str) -> Value {
+        Value::Obj(self.heap.borrow_mut().copy_string(chars))
+    }
+
+    pub fn print(&self, value: Value) {
+        writeln!(self.output.borrow_mut(), "{}", value).expect("writable");
+    }
+
+    pub fn define_global(&self, name: &str, value: Value) {
+        let name = self.heap.borrow_mut().copy_string(name);
+        self.globals.borrow_mut().insert(name, value);
+    }
+
+    pub fn get_global(&self, name: &str, line: usize) -> Result<Value, RuntimeError> {
+        let globals = self.globals.borrow();
+        globals
+            .find_string(name, hash_string(name))
+            .and_then(|key| globals.get(key))
+            .copied()
+            .ok_or_else(|| RuntimeError::new(format!("Undefined variable '{}'.", name), line))
+    }
+
+    /// Assigns to a global that is already defined, and evaluates to the value.
+    pub fn set_global(&self, name: &str, value: Value, line: usize) -> Result<Value, RuntimeError> {
+        let mut globals = self.globals.borrow_mut();
+        let key = globals.find_string(name, hash_string(name));
+        match key.and_then(|key| globals.get_mut(key)) {
+            Some(global) => {
+                *global = value;
+                Ok(value)
+            }
+            None => Err(RuntimeError::new(
+                format!("Undefined variable '{}'.", name),
+                line,
+            )),
+        }
+    }
+
+    pub fn is_truthy(&self, value: Value) -> bool {
+        !value.is_falsey()
+    }
+
+    pub fn not(&self, value: Value) -> Value {
+        Value::Bool(value.is_falsey())
+    }
+
+    pub fn equal(&self, a: Value, b: Value) -> Value {
+        Value::Bool(a == b)
+    }
+
+    pub fn not_equal(&self, a: Value, b: Value) -> Value {
+        Value::Bool(a != b)
+    }
+
+    pub fn negate(&self, value: Value, line: usize) -> Result<Value, RuntimeError> {
+        value
+            .as_number()
+            .map(|value| Value::Number(-value))
+            .map_err(|error| RuntimeError::new(error.to_string(), line))
+    }
+
+    pub fn add(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            (a, b) => match (a.as_string(), b.as_string()) {
+                (Some(a), Some(b)) => Ok(self.string(&format!("{}{}", a.as_str(), b.as_str()))),
+                _ => Err(RuntimeError::new(
+                    "Operands must be two numbers or two strings.",
+                    line,
+                )),
+            },
+        }
+    }
+
+    pub fn subtract(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        numbers(a, b, line).map(|(a, b)| Value::Number(a - b))
+    }
+
+    pub fn multiply(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        numbers(a, b, line).map(|(a, b)| Value::Number(a * b))
+    }
+
+    pub fn divide(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        numbers(a, b, line).map(|(a, b)| Value::Number(a / b))
+    }
+
+    pub fn greater(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        numbers(a, b, line).map(|(a, b)| Value::Bool(a > b))
+    }
+
+    pub fn greater_equal(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        numbers(a, b, line).map(|(a, b)| Value::Bool(a >= b))
+    }
+
+    pub fn less(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        numbers(a, b, line).map(|(a, b)| Value::Bool(a < b))
+    }
+
+    pub fn less_equal(&self, a: Value, b: Value, line: usize) -> Result<Value, RuntimeError> {
+        numbers(a, b, line).map(|(a, b)| Value::Bool(a <= b))
+    }
+}
+
+// the operands of the arithmetic and comparison operators
+fn numbers(a: Value, b: Value, line: usize) -> Result<(f64, f64), RuntimeError> {
+    match (a.as_number(), b.as_number()) {
+        (Ok(a), Ok(b)) => Ok((a, b)),
+        _ => Err(RuntimeError::new("Operands must be numbers.", line)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_runtime_operators() {
+        let rt = Runtime::with_output(Vec::new());
+        let (one, two) = (Value::Number(1.0), Value::Number(2.0));
+
+        assert_eq!(rt.add(one, two, 1), Ok(Value::Number(3.0)));
+        assert_eq!(rt.subtract(one, two, 1), Ok(Value::Number(-1.0)));
+        assert_eq!(rt.less_equal(two, two, 1), Ok(Value::Bool(true)));
+        assert_eq!(rt.negate(one, 1), Ok(Value::Number(-1.0)));
+        assert_eq!(rt.not(Value::Nil), Value::Bool(true));
+        assert_eq!(
+            rt.add(rt.string("a"), rt.string("b"), 1),
+            Ok(rt.string("ab"))
+        );
+
+        assert_eq!(
+            rt.add(one, Value::Nil, 3),
+            Err(RuntimeError::new(
+                "Operands must be two numbers or two strings.",
+                3
+            ))
+        );
+        assert_eq!(
+            rt.multiply(one, Value::Bool(true), 4),
+            Err(RuntimeError::new("Operands must be numbers.", 4))
+        );
+        assert_eq!(
+            rt.negate(Value::Nil, 5),
+            Err(RuntimeError::new("Operand must be a number.", 5))
+        );
+    }
+
+    #[test]
+    fn test_runtime_globals() {
+        let rt = Runtime::with_output(Vec::new());
+        assert_eq!(
+            rt.get_global("a", 1),
+            Err(RuntimeError::new("Undefined variable 'a'.", 1))
+        );
+        assert_eq!(
+            rt.set_global("a", Value::Nil, 2),
+            Err(RuntimeError::new("Undefined variable 'a'.", 2))
+        );
+
+        rt.define_global("a", Value::Number(1.0));
+        assert_eq!(
+            rt.set_global("a", Value::Number(2.0), 3),
+            Ok(Value::Number(2.0))
+        );
+        assert_eq!(rt.get_global("a", 4), Ok(Value::Number(2.0)));
+
+        rt.print(Value::Nil);
+        assert_eq!(
+            String::from_utf8(rt.into_output()).expect("valid utf8"),
+            "nil\n"
+        );
+    }
+}
@@ -1,8 +1,23 @@
-pub struct Scanner {
-    source: String,
+use alloc::{format, string::String, vec::Vec};
+use core::{fmt, iter, iter::FusedIterator, ops::Range};
+
+use crate::debug::json_string;
+
+// the encoding of U+FEFF, which editors on Windows put at the start of a file
+const BOM: &[u8] = "\u{feff}".as_bytes();
+
+pub struct Scanner<'src> {
+    source: &'src str,
     start: usize,
     current: usize,
     line: usize,
+    // byte offset of the first character of the current line
+    line_start: usize,
+    // line and column of the token that is currently being scanned
+    start_line: usize,
+    start_column: usize,
+    // whether the iterator has yielded the end of file token
+    finished: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -33,6 +48,8 @@ pub enum TokenKind {
     // literals
     Identifier,
     String,
+    // `r"..."`, where a backslash is literal and `""` is a quotation mark
+    RawString,
     Number,
 
     // keywords
@@ -57,27 +74,102 @@ pub enum TokenKind {
     EndOfFile,
 }
 
-#[derive(Debug, Clone)]
-pub struct Token {
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token<'src> {
     pub kind: TokenKind,
-    pub lexeme: String,
+    // a slice of the source, or the message of an error token
+    pub lexeme: &'src str,
+    // line of the first character of the token
+    pub line: usize,
+    // 1-based column (in bytes) of the first character of the token
+    pub column: usize,
+    // byte offsets of where the token starts and ends in the source, which
+    // for an error token is the text that could not be scanned
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Token<'_> {
+    /// The bytes of the source that the token covers, `start..end`.
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// The token as a JSON object, for tools that read the output of the
+    /// scanner.
+    pub fn to_json(&self) -> String {
+        format!(
+            r#"{{"kind": "{:?}", "lexeme": {}, "line": {}, "column": {}, "start": {}, "end": {}}}"#,
+            self.kind,
+            json_string(self.lexeme),
+            self.line,
+            self.column,
+            self.start,
+            self.end
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TriviaKind {
+    // spaces, tabs and line breaks
+    Whitespace,
+    // a `//` comment, without the line break that ends it
+    Comment,
+    // the U+FEFF at the start of a file that some editors write
+    ByteOrderMark,
+}
+
+/// Source text that the scanner skips between tokens.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Trivia<'src> {
+    pub kind: TriviaKind,
+    pub text: &'src str,
+    // line of the first character of the trivia
     pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A token of the lossless mode, with the trivia that comes before it.
+/// Displaying it gives back the source text that it was scanned from.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LosslessToken<'src> {
+    pub leading: Vec<Trivia<'src>>,
+    pub token: Token<'src>,
+    // the source text of the token, which is not the lexeme of an error token
+    pub text: &'src str,
+}
+
+impl fmt::Display for LosslessToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in &self.leading {
+            write!(f, "{}", trivia.text)?;
+        }
+        write!(f, "{}", self.text)
+    }
 }
 
-impl Scanner {
-    pub fn new(source: String) -> Self {
+impl<'src> Scanner<'src> {
+    pub fn new(source: &'src str) -> Self {
         Self {
             source,
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            finished: false,
         }
     }
 
-    pub fn scan_token(&mut self) -> Token {
+    pub fn scan_token(&mut self) -> Token<'src> {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.start - self.line_start + 1;
 
         if self.is_at_end() {
             return self.make_token(TokenKind::EndOfFile);
@@ -85,6 +177,9 @@ impl Scanner {
 
         let c = self.advance();
 
+        if c == 'r' && self.match_ch('"') {
+            return self.raw_string();
+        }
         if c.is_ascii_alphabetic() || c == '_' {
             return self.identifier();
         }
@@ -137,10 +232,66 @@ impl Scanner {
                 self.make_token(kind)
             }
             '"' => self.string(),
-            _ => self.error_token("Unexpected character."),
+            _ => {
+                // the whole character is skipped, so that the next token starts
+                // at a character boundary
+                let ch = self.source[self.start..].chars().next();
+                self.current = self.start + ch.map_or(1, char::len_utf8);
+                if ch == Some('\u{feff}') {
+                    self.error_token("Unexpected byte order mark.")
+                } else {
+                    self.error_token("Unexpected character.")
+                }
+            }
+        }
+    }
+
+    /// Scans the next token together with the whitespace and comments in
+    /// front of it. The trivia before the end of file token is the rest of
+    /// the source, so that the tokens add up to the whole source.
+    pub fn scan_lossless(&mut self) -> LosslessToken<'src> {
+        let leading = iter::from_fn(|| {
+            let (start, line) = (self.current, self.line);
+            self.trivia().map(|kind| Trivia {
+                kind,
+                text: &self.source[start..self.current],
+                line,
+                start,
+                end: self.current,
+            })
+        })
+        .collect();
+        let token = self.scan_token();
+        let text = &self.source[token.range()];
+
+        LosslessToken {
+            leading,
+            token,
+            text,
         }
     }
 
+    /// The scanner in lossless mode, which yields the tokens like the
+    /// iterator does, but with their trivia.
+    pub fn lossless(mut self) -> impl Iterator<Item = LosslessToken<'src>> {
+        iter::from_fn(move || {
+            if self.finished {
+                return None;
+            }
+
+            let token = self.scan_lossless();
+            self.finished = token.token.kind == TokenKind::EndOfFile;
+            Some(token)
+        })
+    }
+
+    /// Scans the whole source, and returns every error token in it, where the
+    /// parser only reports the first one of a statement.
+    pub fn lexical_errors(self) -> Vec<Token<'src>> {
+        self.filter(|token| token.kind == TokenKind::Error)
+            .collect()
+    }
+
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
@@ -175,52 +326,68 @@ impl Scanner {
         }
     }
 
-    fn make_token(&self, kind: TokenKind) -> Token {
+    fn make_token(&self, kind: TokenKind) -> Token<'src> {
         Token {
             kind,
-            lexeme: self.source[self.start..self.current].into(),
-            line: self.line,
+            lexeme: &self.source[self.start..self.current],
+            line: self.start_line,
+            column: self.start_column,
+            start: self.start,
+            end: self.current,
         }
     }
 
-    fn error_token(&self, message: &str) -> Token {
+    fn error_token(&self, message: &'static str) -> Token<'src> {
         Token {
             kind: TokenKind::Error,
-            lexeme: message.into(),
-            line: self.line,
+            lexeme: message,
+            line: self.start_line,
+            column: self.start_column,
+            start: self.start,
+            end: self.current,
         }
     }
 
+    // must be called right after consuming a '\n'
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
     fn skip_whitespace(&mut self) {
-        loop {
-            let ch = self.peek();
+        while self.trivia().is_some() {}
+    }
 
-            match ch {
-                ' ' | '\r' | '\t' => {
-                    self.advance();
-                }
-                '\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        // a comment goes until the end of the line
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+    // skips the whitespace or comment that starts at the current character
+    fn trivia(&mut self) -> Option<TriviaKind> {
+        let kind = match self.peek() {
+            ' ' | '\r' | '\t' | '\n' => {
+                while let ' ' | '\r' | '\t' | '\n' = self.peek() {
+                    if self.advance() == '\n' {
+                        self.new_line();
                     }
                 }
-                _ => {
-                    return;
+                TriviaKind::Whitespace
+            }
+            '/' if self.peek_next() == '/' => {
+                // a comment goes until the end of the line
+                while self.peek() != '\n' && !self.is_at_end() {
+                    self.advance();
                 }
+                TriviaKind::Comment
             }
-        }
+            // the first line starts after it, so that columns do not count it
+            _ if self.current == 0 && self.source.as_bytes().starts_with(BOM) => {
+                self.current = BOM.len();
+                self.line_start = self.current;
+                TriviaKind::ByteOrderMark
+            }
+            _ => return None,
+        };
+        Some(kind)
     }
 
-    fn identifier(&mut self) -> Token {
+    fn identifier(&mut self) -> Token<'src> {
         loop {
             let ch = self.peek();
             if ch.is_ascii_alphabetic() || ch.is_ascii_digit() || ch == '_' {
@@ -285,7 +452,7 @@ impl Scanner {
         }
     }
 
-    fn number(&mut self) -> Token {
+    fn number(&mut self) -> Token<'src> {
         while self.peek().is_ascii_digit() {
             self.advance();
         }
@@ -303,12 +470,11 @@ impl Scanner {
         self.make_token(TokenKind::Number)
     }
 
-    fn string(&mut self) -> Token {
+    fn string(&mut self) -> Token<'src> {
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            if self.advance() == '\n' {
+                self.new_line();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -318,8 +484,43 @@ impl Scanner {
             self.make_token(TokenKind::String)
         }
     }
+
+    fn raw_string(&mut self) -> Token<'src> {
+        loop {
+            if self.is_at_end() {
+                return self.error_token("Unterminated string.");
+            }
+
+            match self.advance() {
+                '\n' => self.new_line(),
+                // a doubled quotation mark does not end the string
+                '"' if !self.match_ch('"') => break,
+                _ => {}
+            }
+        }
+
+        self.make_token(TokenKind::RawString)
+    }
 }
 
+/// Yields the tokens of the source up to and including the end of file token,
+/// which is yielded once. Error tokens are yielded like any other token.
+impl<'src> Iterator for Scanner<'src> {
+    type Item = Token<'src>;
+
+    fn next(&mut self) -> Option<Token<'src>> {
+        if self.finished {
+            return None;
+        }
+
+        let token = self.scan_token();
+        self.finished = token.kind == TokenKind::EndOfFile;
+        Some(token)
+    }
+}
+
+impl FusedIterator for Scanner<'_> {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -327,7 +528,7 @@ mod tests {
     #[test]
     fn test_scan() {
         {
-            let mut scanner = Scanner::new("(){},.-+;/*".to_string());
+            let mut scanner = Scanner::new("(){},.-+;/*");
             assert_eq!(scanner.scan_token().kind, TokenKind::LeftParen);
             assert_eq!(scanner.scan_token().kind, TokenKind::RightParen);
             assert_eq!(scanner.scan_token().kind, TokenKind::LeftBrace);
@@ -343,7 +544,7 @@ mod tests {
         }
 
         {
-            let mut scanner = Scanner::new("! != = == > >= < <=".to_string());
+            let mut scanner = Scanner::new("! != = == > >= < <=");
             assert_eq!(scanner.scan_token().kind, TokenKind::Bang);
             assert_eq!(scanner.scan_token().kind, TokenKind::BangEqual);
             assert_eq!(scanner.scan_token().kind, TokenKind::Equal);
@@ -355,21 +556,21 @@ mod tests {
         }
 
         {
-            let mut scanner = Scanner::new("abc".to_string());
+            let mut scanner = Scanner::new("abc");
             let token = scanner.scan_token();
             assert_eq!(token.kind, TokenKind::Identifier);
             assert_eq!(token.lexeme, "abc");
         }
 
         {
-            let mut scanner = Scanner::new(r#""Quick brown fox\n over lazy dog""#.to_string());
+            let mut scanner = Scanner::new(r#""Quick brown fox\n over lazy dog""#);
             let token = scanner.scan_token();
             assert_eq!(token.kind, TokenKind::String);
             assert_eq!(token.lexeme, r#""Quick brown fox\n over lazy dog""#);
         }
 
         {
-            let mut scanner = Scanner::new("1.3".to_string());
+            let mut scanner = Scanner::new("1.3");
             let token = scanner.scan_token();
             assert_eq!(token.kind, TokenKind::Number);
             assert_eq!(token.lexeme, "1.3");
@@ -377,8 +578,7 @@ mod tests {
 
         {
             let mut scanner = Scanner::new(
-                "and class else false for fun if nil or print return super this true var while"
-                    .to_string(),
+                "and class else false for fun if nil or print return super this true var while",
             );
             assert_eq!(scanner.scan_token().kind, TokenKind::And);
             assert_eq!(scanner.scan_token().kind, TokenKind::Class);
@@ -400,7 +600,7 @@ mod tests {
         }
 
         {
-            let mut scanner = Scanner::new("~".to_string());
+            let mut scanner = Scanner::new("~");
             let token = scanner.scan_token();
             assert_eq!(token.kind, TokenKind::Error);
         }
@@ -416,8 +616,7 @@ fun hi() {
     // return!
     return; 
 }
-"#
-                .to_string(),
+"#,
             );
             assert_eq!(scanner.scan_token().kind, TokenKind::Fun);
             assert_eq!(scanner.scan_token().kind, TokenKind::Identifier);
@@ -438,8 +637,7 @@ fun hi() {
 and or
 this
 ;
-"#
-            .to_string(),
+"#,
         );
         assert_eq!(scanner.scan_token().line, 1); // var
         assert_eq!(scanner.scan_token().line, 2); // and
@@ -448,4 +646,193 @@ this
         assert_eq!(scanner.scan_token().line, 4); // ;
         assert_eq!(scanner.scan_token().line, 5); // EOF
     }
+
+    #[test]
+    fn test_column() {
+        let mut scanner = Scanner::new(
+            r#"var abc
+  and  or
+"two
+lines" this ~
+"#,
+        );
+
+        fn assert_position(token: Token, line: usize, column: usize) {
+            assert_eq!((token.line, token.column), (line, column), "{:?}", token);
+        }
+
+        assert_position(scanner.scan_token(), 1, 1); // var
+        assert_position(scanner.scan_token(), 1, 5); // abc
+        assert_position(scanner.scan_token(), 2, 3); // and
+        assert_position(scanner.scan_token(), 2, 8); // or
+        // a multi-line token keeps the position of where it started
+        assert_position(scanner.scan_token(), 3, 1); // "two\nlines"
+        assert_position(scanner.scan_token(), 4, 8); // this
+        assert_position(scanner.scan_token(), 4, 13); // ~ (error)
+        assert_position(scanner.scan_token(), 5, 1); // EOF
+    }
+
+    #[test]
+    fn test_span() {
+        let source = "var abc\n\"two\nlines\" ~";
+        let mut scanner = Scanner::new(source);
+
+        let tokens = (0..5).map(|_| scanner.scan_token()).collect::<Vec<_>>();
+        let spans = tokens
+            .iter()
+            .map(|token| (token.start, token.end))
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![(0, 3), (4, 7), (8, 19), (20, 21), (21, 21)]);
+
+        // the range slices the lexeme out of the source
+        assert_eq!(&source[tokens[2].range()], tokens[2].lexeme);
+    }
+
+    #[test]
+    fn test_raw_string() {
+        let mut scanner = Scanner::new(
+            r#"r"C:\dir\ ""a""
+" r "x" r"a"#,
+        );
+        let token = scanner.scan_token();
+        assert_eq!(token.kind, TokenKind::RawString);
+        assert_eq!(token.lexeme, "r\"C:\\dir\\ \"\"a\"\"\n\"");
+        assert_eq!(token.line, 1);
+
+        // only a quotation mark right after the `r` starts a raw string
+        let token = scanner.scan_token();
+        assert_eq!((token.kind, token.line), (TokenKind::Identifier, 2));
+        assert_eq!(scanner.scan_token().kind, TokenKind::String);
+
+        let token = scanner.scan_token();
+        assert_eq!(token.kind, TokenKind::Error);
+        assert_eq!(token.lexeme, "Unterminated string.");
+    }
+
+    #[test]
+    fn test_bom() {
+        let mut scanner = Scanner::new("\u{feff}print \u{feff};");
+        let token = scanner.scan_token();
+        assert_eq!(token.kind, TokenKind::Print);
+        assert_eq!((token.line, token.column), (1, 1));
+        assert_eq!(token.range(), 3..8);
+
+        let token = scanner.scan_token();
+        assert_eq!(token.kind, TokenKind::Error);
+        assert_eq!(token.lexeme, "Unexpected byte order mark.");
+        assert_eq!((token.column, token.range()), (7, 9..12));
+
+        assert_eq!(scanner.scan_token().kind, TokenKind::Semicolon);
+    }
+
+    #[test]
+    fn test_lossless() {
+        let source = "\u{feff}var a = 1; // one\n\t\"two\nlines\" ~ é\n// end";
+        let tokens = Scanner::new(source).lossless().collect::<Vec<_>>();
+
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| token.to_string())
+                .collect::<String>(),
+            source
+        );
+
+        assert_eq!(
+            tokens[0].leading,
+            vec![Trivia {
+                kind: TriviaKind::ByteOrderMark,
+                text: "\u{feff}",
+                line: 1,
+                start: 0,
+                end: 3,
+            }]
+        );
+        assert_eq!(tokens[0].token.column, 1);
+
+        let kinds = |token: &LosslessToken| {
+            token
+                .leading
+                .iter()
+                .map(|trivia| trivia.kind)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds(&tokens[5]),
+            vec![
+                TriviaKind::Whitespace,
+                TriviaKind::Comment,
+                TriviaKind::Whitespace
+            ]
+        );
+        assert_eq!(tokens[5].leading[1].text, "// one");
+        assert_eq!(tokens[5].leading[2].line, 1);
+
+        // error tokens keep the text they were scanned from
+        assert_eq!(tokens[6].token.lexeme, "Unexpected character.");
+        assert_eq!(tokens[6].text, "~");
+        assert_eq!(tokens[7].text, "é");
+
+        let eof = tokens.last().expect("end of file token");
+        assert_eq!(eof.token.kind, TokenKind::EndOfFile);
+        assert_eq!(
+            kinds(eof),
+            vec![TriviaKind::Whitespace, TriviaKind::Comment]
+        );
+    }
+
+    #[test]
+    fn test_lexical_errors() {
+        let errors = Scanner::new("print ~ $;\nvar s = \"abc;").lexical_errors();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|token| (token.lexeme, token.line, token.column))
+                .collect::<Vec<_>>(),
+            vec![
+                ("Unexpected character.", 1, 7),
+                ("Unexpected character.", 1, 9),
+                ("Unterminated string.", 2, 9)
+            ]
+        );
+        assert_eq!(Scanner::new("print 1;").lexical_errors(), vec![]);
+    }
+
+    #[test]
+    fn test_iterator() {
+        let kinds = Scanner::new("print ~1;")
+            .map(|token| token.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Print,
+                TokenKind::Error,
+                TokenKind::Number,
+                TokenKind::Semicolon,
+                TokenKind::EndOfFile
+            ]
+        );
+
+        let mut scanner = Scanner::new("");
+        assert_eq!(
+            scanner.next().map(|token| token.kind),
+            Some(TokenKind::EndOfFile)
+        );
+        assert_eq!(scanner.next(), None);
+        assert_eq!(scanner.next(), None);
+    }
+
+    #[test]
+    fn test_token_to_json() {
+        let mut scanner = Scanner::new("\"a\\b\tc\"");
+        assert_eq!(
+            scanner.scan_token().to_json(),
+            r#"{"kind": "String", "lexeme": "\"a\\b\tc\"", "line": 1, "column": 1, "start": 0, "end": 7}"#
+        );
+        assert_eq!(
+            scanner.scan_token().to_json(),
+            r#"{"kind": "EndOfFile", "lexeme": "", "line": 1, "column": 8, "start": 7, "end": 7}"#
+        );
+    }
 }
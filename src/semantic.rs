@@ -0,0 +1,494 @@
+// The tokens of a script classified for an editor to highlight, the way the
+// semantic tokens of the Language Server Protocol are: by what they are,
+// rather than by how they are spelled. An identifier is a function, a
+// parameter or a variable, from what the name that it refers to was
+// declared as, so that `f` in `f(1)` and `fun f(n) {}` are both functions.
+//
+// [`to_lsp_data`] encodes them the way a `textDocument/semanticTokens/full`
+// response carries them, against [`TOKEN_TYPES`] and [`TOKEN_MODIFIERS`],
+// which are the legend that a server declares in its capabilities.
+
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Expr, Function, Program, Stmt},
+    debug::json_string,
+    parser::Parser,
+    scanner::{Scanner, Token, TokenKind, TriviaKind},
+};
+
+/// What a semantic token is, as one of the standard token types of LSP.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TokenType {
+    Keyword,
+    Number,
+    String,
+    Comment,
+    Operator,
+    Function,
+    Parameter,
+    Variable,
+}
+
+/// The names of the token types, in the order of their indices in LSP data.
+pub const TOKEN_TYPES: [&str; 8] = [
+    "keyword",
+    "number",
+    "string",
+    "comment",
+    "operator",
+    "function",
+    "parameter",
+    "variable",
+];
+
+/// The names of the modifiers, in the order of their bits in LSP data.
+pub const TOKEN_MODIFIERS: [&str; 1] = ["declaration"];
+
+impl TokenType {
+    pub fn name(self) -> &'static str {
+        TOKEN_TYPES[self as usize]
+    }
+}
+
+/// A classified token, which is on one line: a string that goes on for
+/// more lines is a token for each of them.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SemanticToken {
+    /// The 1-based line and column (in bytes) where the token starts.
+    pub line: u32,
+    pub column: u32,
+    /// The length of the token in bytes.
+    pub length: u32,
+    pub token_type: TokenType,
+    /// Whether the token is the name in a declaration.
+    pub declaration: bool,
+}
+
+impl SemanticToken {
+    /// The token as a JSON object, like [`Token::to_json`].
+    pub fn to_json(&self, source: &str) -> String {
+        let modifiers = if self.declaration {
+            r#"["declaration"]"#
+        } else {
+            "[]"
+        };
+        format!(
+            r#"{{"type": "{}", "modifiers": {}, "text": {}, "line": {}, "column": {}, "length": {}}}"#,
+            self.token_type.name(),
+            modifiers,
+            json_string(self.text(source)),
+            self.line,
+            self.column,
+            self.length
+        )
+    }
+
+    /// The text of the token in `source`.
+    pub fn text<'src>(&self, source: &'src str) -> &'src str {
+        let line = source.split('\n').nth(self.line as usize - 1).unwrap_or("");
+        let start = self.column as usize - 1;
+        line.get(start..start + self.length as usize).unwrap_or("")
+    }
+}
+
+/// The semantic tokens of `source`, in order. Code with syntax errors still
+/// has its tokens, with the identifiers that the parser skipped over as
+/// variables.
+pub fn semantic_tokens(source: &str) -> Vec<SemanticToken> {
+    let mut parser = Parser::new(source);
+    let program = parser.parse();
+    let mut resolver = Resolver::default();
+    resolver.program(&program);
+
+    let mut tokens = vec![];
+    let mut push = |start: usize, text: &str, token_type, declaration| {
+        // a token for each line of the text, leaving out the line breaks
+        let mut offset = start;
+        for part in text.split('\n') {
+            let part = part.strip_suffix('\r').unwrap_or(part);
+            if !part.is_empty() {
+                let (line, column) = position(source, offset);
+                tokens.push(SemanticToken {
+                    line,
+                    column,
+                    length: part.len() as u32,
+                    token_type,
+                    declaration,
+                });
+            }
+            offset += part.len() + 1;
+        }
+    };
+
+    for token in Scanner::new(source).lossless() {
+        for trivia in &token.leading {
+            if trivia.kind == TriviaKind::Comment {
+                push(trivia.start, trivia.text, TokenType::Comment, false);
+            }
+        }
+
+        let (token_type, declaration) = match token.token.kind {
+            TokenKind::And
+            | TokenKind::Class
+            | TokenKind::Else
+            | TokenKind::False
+            | TokenKind::For
+            | TokenKind::Fun
+            | TokenKind::If
+            | TokenKind::Nil
+            | TokenKind::Or
+            | TokenKind::Print
+            | TokenKind::Return
+            | TokenKind::Super
+            | TokenKind::This
+            | TokenKind::True
+            | TokenKind::Var
+            | TokenKind::While => (TokenType::Keyword, false),
+            TokenKind::Number => (TokenType::Number, false),
+            TokenKind::String | TokenKind::RawString => (TokenType::String, false),
+            TokenKind::Minus
+            | TokenKind::Plus
+            | TokenKind::Slash
+            | TokenKind::Star
+            | TokenKind::Bang
+            | TokenKind::BangEqual
+            | TokenKind::Equal
+            | TokenKind::EqualEqual
+            | TokenKind::Greater
+            | TokenKind::GreaterEqual
+            | TokenKind::Less
+            | TokenKind::LessEqual => (TokenType::Operator, false),
+            TokenKind::Identifier => resolver
+                .roles
+                .get(&token.token.start)
+                .copied()
+                .unwrap_or((TokenType::Variable, false)),
+            _ => continue,
+        };
+        push(token.token.start, token.text, token_type, declaration);
+    }
+    tokens
+}
+
+// the 1-based line and column of the byte at `offset`
+fn position(source: &str, offset: usize) -> (u32, u32) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = offset - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+    (line as u32, column as u32)
+}
+
+/// `tokens` encoded as the `data` of LSP, five numbers for each token: its
+/// line relative to the one before, its start (relative to the one before
+/// it on the same line), its length, its type and its modifiers. The
+/// positions are in UTF-16 code units, as LSP counts them by default.
+pub fn to_lsp_data(tokens: &[SemanticToken], source: &str) -> Vec<u32> {
+    let lines = source.split('\n').collect::<Vec<_>>();
+    let utf16 = |line: &str, bytes: usize| line[..bytes].encode_utf16().count() as u32;
+
+    let mut data = Vec::with_capacity(tokens.len() * 5);
+    let (mut last_line, mut last_start) = (0, 0);
+    for token in tokens {
+        let line = token.line - 1;
+        let text = lines[line as usize];
+        let column = token.column as usize - 1;
+        let start = utf16(text, column);
+        let length = utf16(&text[column..], token.length as usize);
+        let delta_start = if line == last_line {
+            start - last_start
+        } else {
+            start
+        };
+        data.extend([
+            line - last_line,
+            delta_start,
+            length,
+            token.token_type as u32,
+            token.declaration as u32,
+        ]);
+        (last_line, last_start) = (line, start);
+    }
+    data
+}
+
+/// The legend and the data of `tokens` as the JSON of LSP, along with the
+/// tokens themselves for the tools that don't speak it.
+pub fn to_json(tokens: &[SemanticToken], source: &str) -> String {
+    let quoted = |names: &[&str]| {
+        names
+            .iter()
+            .map(|name| json_string(name))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let data = to_lsp_data(tokens, source)
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    let tokens = tokens
+        .iter()
+        .map(|token| format!("    {}", token.to_json(source)))
+        .collect::<Vec<_>>()
+        .join(",\n");
+    format!(
+        "{{\n  \"legend\": {{\"tokenTypes\": [{}], \"tokenModifiers\": [{}]}},\n  \"data\": [{}],\n  \"tokens\": [\n{}\n  ]\n}}",
+        quoted(&TOKEN_TYPES),
+        quoted(&TOKEN_MODIFIERS),
+        data,
+        tokens
+    )
+}
+
+// finds the role of each identifier in the syntax tree, by the offset that
+// it starts at
+#[derive(Default)]
+struct Resolver<'src> {
+    roles: HashMap<usize, (TokenType, bool)>,
+    // the names declared in each block and function around the code,
+    // innermost last, and what they were declared as
+    scopes: Vec<Vec<(&'src str, TokenType)>>,
+    // the globals, which can be used before they are declared
+    globals: HashMap<&'src str, TokenType>,
+}
+
+impl<'src> Resolver<'src> {
+    fn program(&mut self, program: &Program<'src>) {
+        for statement in &program.statements {
+            match statement {
+                Stmt::Var { name, .. } => {
+                    self.globals
+                        .entry(name.lexeme)
+                        .or_insert(TokenType::Variable);
+                }
+                Stmt::Function(function) => {
+                    self.globals
+                        .insert(function.name.lexeme, TokenType::Function);
+                }
+                _ => {}
+            }
+        }
+        program
+            .statements
+            .iter()
+            .for_each(|statement| self.statement(statement));
+    }
+
+    fn declare(&mut self, name: &Token<'src>, token_type: TokenType) {
+        self.roles.insert(name.start, (token_type, true));
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.push((name.lexeme, token_type));
+        }
+    }
+
+    // a name that isn't declared anywhere is a native function if it is
+    // called, or a variable
+    fn refer(&mut self, name: &Token<'src>, called: bool) {
+        let declared = self
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(declared, _)| *declared == name.lexeme)
+            .map(|(_, token_type)| *token_type)
+            .or_else(|| self.globals.get(name.lexeme).copied());
+        let token_type = match declared {
+            Some(token_type) => token_type,
+            None if called => TokenType::Function,
+            None => TokenType::Variable,
+        };
+        self.roles.insert(name.start, (token_type, false));
+    }
+
+    fn block(&mut self, statements: &[Stmt<'src>]) {
+        self.scopes.push(vec![]);
+        statements
+            .iter()
+            .for_each(|statement| self.statement(statement));
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, statement: &Stmt<'src>) {
+        match statement {
+            Stmt::Expression { expr, .. } | Stmt::Print { expr, .. } => self.expression(expr),
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.declare(name, TokenType::Variable);
+            }
+            Stmt::Function(function) => self.function(function),
+            Stmt::Block { statements, .. } => self.block(statements),
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::While {
+                condition, body, ..
+            } => {
+                self.expression(condition);
+                self.statement(body);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                self.scopes.push(vec![]);
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                for expr in [condition, increment].into_iter().flatten() {
+                    self.expression(expr);
+                }
+                self.statement(body);
+                self.scopes.pop();
+            }
+            Stmt::Return { value, .. } => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, function: &Function<'src>) {
+        self.declare(&function.name, TokenType::Function);
+        self.scopes.push(vec![]);
+        for param in &function.params {
+            self.declare(param, TokenType::Parameter);
+        }
+        self.block(&function.body);
+        self.scopes.pop();
+    }
+
+    fn expression(&mut self, expr: &Expr<'src>) {
+        match expr {
+            Expr::Literal { .. } | Expr::Number { .. } | Expr::String { .. } => {}
+            Expr::Variable { name } => self.refer(name, false),
+            Expr::Assign { name, value, .. } => {
+                self.refer(name, false);
+                self.expression(value);
+            }
+            Expr::Unary { operand, .. } => self.expression(operand),
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Call {
+                callee, arguments, ..
+            } => {
+                match &**callee {
+                    Expr::Variable { name } => self.refer(name, true),
+                    callee => self.expression(callee),
+                }
+                arguments
+                    .iter()
+                    .for_each(|argument| self.expression(argument));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the text, the type and whether it is a declaration of each token
+    fn classified(source: &str) -> Vec<(&str, &str, bool)> {
+        semantic_tokens(source)
+            .into_iter()
+            .map(|token| {
+                (
+                    token.text(source),
+                    token.token_type.name(),
+                    token.declaration,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_semantic_tokens() {
+        assert_eq!(
+            classified(
+                "fun f(n) { return g(n) + 1; }\n// later\nvar g = f;\nprint arg(0) and \"s\";"
+            ),
+            [
+                ("fun", "keyword", false),
+                ("f", "function", true),
+                ("n", "parameter", true),
+                ("return", "keyword", false),
+                ("g", "variable", false),
+                ("n", "parameter", false),
+                ("+", "operator", false),
+                ("1", "number", false),
+                ("// later", "comment", false),
+                ("var", "keyword", false),
+                ("g", "variable", true),
+                ("=", "operator", false),
+                ("f", "function", false),
+                ("print", "keyword", false),
+                ("arg", "function", false),
+                ("0", "number", false),
+                ("and", "keyword", false),
+                ("\"s\"", "string", false),
+            ]
+        );
+
+        // a local hides the global, in its own scope only
+        assert_eq!(
+            classified("fun a() {}\n{ var a; a; }\na;"),
+            [
+                ("fun", "keyword", false),
+                ("a", "function", true),
+                ("var", "keyword", false),
+                ("a", "variable", true),
+                ("a", "variable", false),
+                ("a", "function", false),
+            ]
+        );
+
+        // a string on more lines is a token on each of them
+        let tokens = semantic_tokens("print \"a\n\nbc\";");
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| (token.line, token.column, token.length))
+                .collect::<Vec<_>>(),
+            [(1, 1, 5), (1, 7, 2), (3, 1, 3)]
+        );
+    }
+
+    #[test]
+    fn test_lsp_data() {
+        let source = "var a = \"é\"; print a;\n  print a;";
+        assert_eq!(
+            to_lsp_data(&semantic_tokens(source), source),
+            [
+                0, 0, 3, 0, 0, // var
+                0, 4, 1, 7, 1, // a
+                0, 2, 1, 4, 0, // =
+                0, 2, 3, 2, 0, // "é", where é is a single code unit
+                0, 5, 5, 0, 0, // print
+                0, 6, 1, 7, 0, // a
+                1, 2, 5, 0, 0, // print
+                0, 6, 1, 7, 0, // a
+            ]
+        );
+    }
+}
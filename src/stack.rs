@@ -0,0 +1,190 @@
+// The stack of values that the VM runs on, which is allocated at its full
+// size once, the way that clox has it, rather than growing as values are
+// pushed. A call checks that the values of its frame fit before the frame
+// starts, using `Chunk::stack_growth`, so nothing that runs in the frame can
+// push past the end.
+//
+// With the `fast-stack` feature, pushing and popping rely on that and on the
+// code being valid, and skip their bounds checks like the C of the book.
+// Otherwise they panic where the C would write past the stack. Only the VM
+// keeps to that, so the stack is not public outside of the crate.
+
+use alloc::{boxed::Box, vec};
+use core::ops::{Deref, DerefMut, Index, IndexMut, RangeFrom};
+
+use crate::value::Value;
+
+/// A stack of values with a fixed capacity, which derefs to the values that
+/// are on it.
+pub struct Stack {
+    values: Box<[Value]>,
+    // where the next value is pushed to, one past the top of the stack
+    top: usize,
+}
+
+impl Stack {
+    pub fn new(capacity: usize) -> Self {
+        Stack {
+            values: vec![Value::Nil; capacity].into_boxed_slice(),
+            top: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.values.len()
+    }
+
+    // the same as through the slice, without making one first
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.top
+    }
+
+    /// Pushes `value` onto the stack.
+    ///
+    /// # Panics
+    ///
+    /// If the stack is full, unless the `fast-stack` feature is on, when the
+    /// caller has to have checked that there is room.
+    #[inline]
+    pub fn push(&mut self, value: Value) {
+        #[cfg(feature = "fast-stack")]
+        {
+            debug_assert!(self.top < self.values.len(), "ICE: Stack overflow");
+            // SAFETY: every frame checks that its values fit before it
+            // starts, and valid code never pushes more than it counted on
+            unsafe { *self.values.get_unchecked_mut(self.top) = value };
+        }
+        #[cfg(not(feature = "fast-stack"))]
+        {
+            self.values[self.top] = value;
+        }
+        self.top += 1;
+    }
+
+    /// Pops the value on top of the stack.
+    ///
+    /// # Panics
+    ///
+    /// If the stack is empty, unless the `fast-stack` feature is on, when the
+    /// caller has to know that it is not.
+    #[inline]
+    pub fn pop(&mut self) -> Value {
+        #[cfg(feature = "fast-stack")]
+        {
+            debug_assert!(self.top > 0, "Stack exhausted");
+            self.top -= 1;
+            // SAFETY: valid code never pops more than it pushed, and what it
+            // pushed is within the stack
+            unsafe { *self.values.get_unchecked(self.top) }
+        }
+        #[cfg(not(feature = "fast-stack"))]
+        {
+            self.top = self.top.checked_sub(1).expect("Stack exhausted");
+            self.values[self.top]
+        }
+    }
+
+    /// Drops the values above the first `len`, if there are more.
+    pub fn truncate(&mut self, len: usize) {
+        self.top = self.top.min(len);
+    }
+
+    pub fn clear(&mut self) {
+        self.top = 0;
+    }
+
+    /// Truncates the stack to `len` values, or pushes copies of `value` until
+    /// there are that many.
+    ///
+    /// # Panics
+    ///
+    /// If `len` is more than the capacity.
+    pub fn resize(&mut self, len: usize, value: Value) {
+        if len > self.top {
+            self.values[self.top..len].fill(value);
+        }
+        self.top = len;
+    }
+}
+
+// a value below the top, which the VM reads far more often than it takes
+// slices of the stack
+impl Index<usize> for Stack {
+    type Output = Value;
+
+    #[inline]
+    fn index(&self, i: usize) -> &Value {
+        assert!(i < self.top, "ICE: Reading above the top of the stack");
+        &self.values[i]
+    }
+}
+
+impl IndexMut<usize> for Stack {
+    #[inline]
+    fn index_mut(&mut self, i: usize) -> &mut Value {
+        assert!(i < self.top, "ICE: Writing above the top of the stack");
+        &mut self.values[i]
+    }
+}
+
+// the values from `range.start` to the top, e.g. the arguments of a call
+impl Index<RangeFrom<usize>> for Stack {
+    type Output = [Value];
+
+    fn index(&self, range: RangeFrom<usize>) -> &[Value] {
+        &self.values[range.start..self.top]
+    }
+}
+
+impl Deref for Stack {
+    type Target = [Value];
+
+    fn deref(&self) -> &[Value] {
+        &self.values[..self.top]
+    }
+}
+
+impl DerefMut for Stack {
+    fn deref_mut(&mut self) -> &mut [Value] {
+        &mut self.values[..self.top]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack() {
+        let mut stack = Stack::new(4);
+        stack.push(Value::Number(1.0));
+        stack.push(Value::Bool(true));
+        assert_eq!(&*stack, [Value::Number(1.0), Value::Bool(true)]);
+        assert_eq!(stack.pop(), Value::Bool(true));
+        assert_eq!(stack.len(), 1);
+
+        stack.resize(4, Value::Nil);
+        assert_eq!(
+            &*stack,
+            [Value::Number(1.0), Value::Nil, Value::Nil, Value::Nil]
+        );
+        stack[1] = Value::Number(2.0);
+        stack.truncate(2);
+        assert_eq!(stack.last(), Some(&Value::Number(2.0)));
+        // the values that were truncated are not visible again
+        stack.truncate(3);
+        assert_eq!(stack.len(), 2);
+
+        stack.clear();
+        assert!(stack.is_empty());
+        assert_eq!(stack.capacity(), 4);
+    }
+
+    #[cfg(not(feature = "fast-stack"))]
+    #[test]
+    #[should_panic(expected = "Stack exhausted")]
+    fn test_stack_exhausted() {
+        Stack::new(1).pop();
+    }
+}
@@ -0,0 +1,264 @@
+// The book's hash table, keyed by string objects: open addressing with
+// linear probing, where a removed entry leaves a tombstone behind so that
+// the probe sequences that went past it still find their keys.
+//
+// Strings are interned by the heap, so two keys with the same characters
+// are almost always the same object, and are compared by pointer first.
+// Keys from another heap are still found by comparing their characters.
+
+use alloc::{vec, vec::Vec};
+
+use crate::object::{ObjRef, ObjString};
+
+// grow once three quarters of the entries are used, tombstones included
+const MAX_LOAD_NUMERATOR: usize = 3;
+const MAX_LOAD_DENOMINATOR: usize = 4;
+
+/// The 32-bit FNV-1a hash of `chars`, which is what the table hashes its
+/// keys with.
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash = 2166136261u32;
+    for &byte in chars.as_bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+#[derive(Debug, Clone)]
+enum Entry<V> {
+    Empty,
+    Tombstone,
+    Occupied { key: ObjRef, hash: u32, value: V },
+}
+
+#[derive(Debug, Clone)]
+pub struct Table<V> {
+    // occupied entries and tombstones, which both make probe sequences longer
+    count: usize,
+    len: usize,
+    entries: Vec<Entry<V>>,
+}
+
+impl<V> Default for Table<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<V> Table<V> {
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            len: 0,
+            entries: vec![],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: ObjRef) -> Option<&V> {
+        let index = self.find(chars(&key), hash(&key), Some(key))?;
+        match &self.entries[index] {
+            Entry::Occupied { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, key: ObjRef) -> Option<&mut V> {
+        let index = self.find(chars(&key), hash(&key), Some(key))?;
+        match &mut self.entries[index] {
+            Entry::Occupied { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Sets `key` to `value`, and returns whether the key is new.
+    pub fn insert(&mut self, key: ObjRef, value: V) -> bool {
+        if (self.count + 1) * MAX_LOAD_DENOMINATOR > self.entries.len() * MAX_LOAD_NUMERATOR {
+            self.grow();
+        }
+
+        let hash = hash(&key);
+        let index = self.probe(chars(&key), hash, Some(key));
+        let is_new = !matches!(self.entries[index], Entry::Occupied { .. });
+        if is_new {
+            self.len += 1;
+            // a reused tombstone is already counted
+            if matches!(self.entries[index], Entry::Empty) {
+                self.count += 1;
+            }
+        }
+
+        self.entries[index] = Entry::Occupied { key, hash, value };
+        is_new
+    }
+
+    /// Removes `key`, and returns its value if it was there.
+    pub fn remove(&mut self, key: ObjRef) -> Option<V> {
+        let index = self.find(chars(&key), hash(&key), Some(key))?;
+        self.len -= 1;
+        match core::mem::replace(&mut self.entries[index], Entry::Tombstone) {
+            Entry::Occupied { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The key with the same characters as `chars`, whose [`hash_string`]
+    /// is `hash`. This is how the heap finds the string to intern without
+    /// allocating one first.
+    pub fn find_string(&self, chars: &str, hash: u32) -> Option<ObjRef> {
+        let index = self.find(chars, hash, None)?;
+        match &self.entries[index] {
+            Entry::Occupied { key, .. } => Some(*key),
+            _ => None,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, &V)> + '_ {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Occupied { key, value, .. } => Some((*key, value)),
+            _ => None,
+        })
+    }
+
+    // the index of the occupied entry for the key, if there is one
+    fn find(&self, chars: &str, hash: u32, key: Option<ObjRef>) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let index = self.probe(chars, hash, key);
+        matches!(self.entries[index], Entry::Occupied { .. }).then_some(index)
+    }
+
+    // the entry that holds the key, or else where it would be inserted: the
+    // first tombstone along the way, or the empty entry that ends the probe
+    fn probe(&self, chars: &str, hash: u32, key: Option<ObjRef>) -> usize {
+        let capacity = self.entries.len();
+        let mut index = hash as usize % capacity;
+        let mut tombstone = None;
+
+        loop {
+            match &self.entries[index] {
+                Entry::Empty => return tombstone.unwrap_or(index),
+                Entry::Tombstone => {
+                    tombstone.get_or_insert(index);
+                }
+                Entry::Occupied {
+                    key: existing,
+                    hash: existing_hash,
+                    ..
+                } => {
+                    if Some(*existing) == key
+                        || (*existing_hash == hash && self::chars(existing) == chars)
+                    {
+                        return index;
+                    }
+                }
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    // doubles the capacity, which also gets rid of the tombstones
+    fn grow(&mut self) {
+        let capacity = (self.entries.len() * 2).max(8);
+        let entries = core::mem::replace(
+            &mut self.entries,
+            (0..capacity).map(|_| Entry::Empty).collect(),
+        );
+
+        self.count = 0;
+        for entry in entries {
+            if let Entry::Occupied { key, hash, value } = entry {
+                let index = self.probe(chars(&key), hash, Some(key));
+                self.entries[index] = Entry::Occupied { key, hash, value };
+                self.count += 1;
+            }
+        }
+    }
+}
+
+fn string(key: &ObjRef) -> &ObjString {
+    key.as_string()
+        .unwrap_or_else(|| panic!("ICE: Table key is not a string"))
+}
+
+fn chars(key: &ObjRef) -> &str {
+    string(key).as_str()
+}
+
+// the hash that the string was made with, rather than hashing it again
+fn hash(key: &ObjRef) -> u32 {
+    string(key).hash()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Heap;
+
+    #[test]
+    fn test_hash_string() {
+        // the FNV-1a test vectors
+        assert_eq!(hash_string(""), 0x811c9dc5);
+        assert_eq!(hash_string("a"), 0xe40c292c);
+        assert_eq!(hash_string("foobar"), 0xbf9cf968);
+    }
+
+    #[test]
+    fn test_table() {
+        let mut heap = Heap::new();
+        let mut table = Table::new();
+        let keys = (0..100)
+            .map(|i| heap.alloc_string(format!("key{}", i)))
+            .collect::<Vec<_>>();
+
+        for (i, &key) in keys.iter().enumerate() {
+            assert!(table.insert(key, i));
+        }
+        assert!(!table.insert(keys[0], 300));
+        assert_eq!(table.len(), 100);
+        assert_eq!(table.get(keys[0]), Some(&300));
+        assert_eq!(
+            table.find_string("key99", hash_string("key99")),
+            Some(keys[99])
+        );
+        assert_eq!(table.find_string("key100", hash_string("key100")), None);
+
+        // the keys after a removed one are still found past its tombstone
+        for &key in keys.iter().step_by(2) {
+            assert!(table.remove(key).is_some());
+        }
+        assert_eq!(table.remove(keys[0]), None);
+        assert_eq!(table.len(), 50);
+        for (i, &key) in keys.iter().enumerate().skip(1).step_by(2) {
+            assert_eq!(table.get(key), Some(&i));
+        }
+        assert_eq!(table.get(keys[4]), None);
+
+        *table.get_mut(keys[1]).expect("present") = 0;
+        assert_eq!(
+            table.iter().map(|(_, &value)| value).sum::<usize>(),
+            2500 - 1
+        );
+    }
+
+    #[test]
+    fn test_table_keys_from_another_heap() {
+        let mut heap = Heap::new();
+        let mut other = Heap::new();
+        let mut table = Table::new();
+
+        table.insert(heap.alloc_string("a".to_string()), 1);
+        assert_eq!(table.get(other.alloc_string("a".to_string())), Some(&1));
+    }
+}
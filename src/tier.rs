@@ -0,0 +1,185 @@
+// Tiering up the functions that have hot loops.
+//
+// The VM counts the back edges that each call of a function takes. Once a call
+// has taken enough of them, the function is optimized as if it had been
+// compiled with every optimization, and the call carries on in the optimized
+// code from the start of the loop that it is in. Later calls of the function
+// run the optimized code from the start.
+
+use crate::{
+    chunk::{Chunk, Span},
+    instruction::Instruction,
+    passes, peephole,
+};
+
+/// How many back edges a call takes before its function is tiered up.
+pub const HOT_LOOP_THRESHOLD: u32 = 1000;
+
+// what the back edge that tiered up the function is tagged with while it is
+// optimized, which no byte of compiled code comes from
+const BACK_EDGE: Span = Span {
+    line: 0,
+    column: 0,
+    start: usize::MAX,
+    end: usize::MAX,
+};
+
+/// A copy of the code of a function that has been running, with the
+/// optimizations that work on bytecode applied to it, along with where the
+/// loop of the back edge at `back_edge` starts in the copy.
+///
+/// The arithmetic that the VM has quickened goes back to its generic form
+/// first, so that it can be fused into superinstructions. The globals that
+/// it has quickened are kept, as their slots are still the same.
+pub fn optimize(chunk: &Chunk, back_edge: usize) -> (Chunk, Option<usize>) {
+    let mut optimized = copy(chunk, |offset| {
+        if offset == back_edge {
+            BACK_EDGE
+        } else {
+            chunk.get_span(offset)
+        }
+    });
+    passes::pipeline().run_on_chunk(&mut optimized);
+    peephole::optimize(&mut optimized);
+    peephole::fuse(&mut optimized);
+
+    // the optimizations keep the span of every jump, so the back edge is
+    // found by its tag, which is then replaced by its real span
+    let start = loop_start(&optimized);
+    let span = chunk.get_span(back_edge);
+    let optimized = copy(&optimized, |offset| match optimized.get_span(offset) {
+        BACK_EDGE => span,
+        other => other,
+    });
+    (optimized, start)
+}
+
+// a copy of `chunk` with the span of each byte from `span_at`
+fn copy(chunk: &Chunk, span_at: impl Fn(usize) -> Span) -> Chunk {
+    let mut copy = Chunk::new();
+    let mut offset = 0;
+    while let Some((instruction, next)) = Instruction::decode(chunk, offset) {
+        let instruction = match instruction {
+            Instruction::AddNumbers => Instruction::Add,
+            Instruction::SubtractNumbers => Instruction::Subtract,
+            Instruction::LessNumbers => Instruction::Less,
+            _ => instruction,
+        };
+        copy.write_span(instruction.opcode() as u8, span_at(offset));
+        instruction
+            .operands()
+            .into_iter()
+            .enumerate()
+            .for_each(|(i, byte)| copy.write_span(byte, span_at(offset + 1 + i)));
+        offset = next;
+    }
+    for constant in chunk.constants().iter() {
+        copy.constants_mut().add(constant);
+    }
+    copy
+}
+
+// where the loop of the tagged back edge starts
+fn loop_start(chunk: &Chunk) -> Option<usize> {
+    let mut offset = 0;
+    while let Some((instruction, next)) = Instruction::decode(chunk, offset) {
+        let jump = match instruction {
+            Instruction::Loop(jump) => Some(jump as usize),
+            _ => None,
+        };
+        if let Some(jump) = jump
+            && chunk.get_span(offset) == BACK_EDGE
+        {
+            return next.checked_sub(jump);
+        }
+        offset = next;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{CompileOptions, Compiler},
+        object::Heap,
+    };
+
+    fn instructions(chunk: &Chunk) -> Vec<(usize, Instruction)> {
+        let mut instructions = vec![];
+        let mut offset = 0;
+        while let Some((instruction, next)) = Instruction::decode(chunk, offset) {
+            instructions.push((offset, instruction));
+            offset = next;
+        }
+        instructions
+    }
+
+    // the offset of every back edge, and of the loop start that it goes to
+    fn back_edges(chunk: &Chunk) -> Vec<(usize, usize)> {
+        instructions(chunk)
+            .into_iter()
+            .filter_map(|(offset, instruction)| match instruction {
+                Instruction::Loop(jump) => Some((offset, offset + 2 - jump as usize)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tier_optimize() {
+        let mut heap = Heap::new();
+        let chunk = Compiler::compile(
+            "{ var a = 0; while (a < 10) { a = a + 1; } print a; }",
+            &mut heap,
+            CompileOptions::default(),
+        )
+        .expect("no error");
+        let [(back_edge, _)] = back_edges(&chunk)[..] else {
+            panic!("one loop");
+        };
+
+        // the superinstructions are only selected when optimizing
+        let is_fused = |(_, instruction): &(usize, Instruction)| {
+            matches!(instruction, Instruction::GetLocalAddConstant(..))
+        };
+        let (optimized, start) = optimize(&chunk, back_edge);
+        assert!(!instructions(&chunk).iter().any(is_fused));
+        assert!(instructions(&optimized).iter().any(is_fused));
+
+        // the loop still starts at its condition, and the back edge has its
+        // span back
+        let [(optimized_back_edge, optimized_start)] = back_edges(&optimized)[..] else {
+            panic!("one loop");
+        };
+        assert_eq!(start, Some(optimized_start));
+        assert_eq!(
+            Instruction::decode(&optimized, optimized_start).map(|(instruction, _)| instruction),
+            Some(Instruction::GetLocal(1))
+        );
+        assert_eq!(
+            optimized.get_span(optimized_back_edge),
+            chunk.get_span(back_edge)
+        );
+    }
+
+    #[test]
+    fn test_tier_nested_loops() {
+        // both back edges come from the last `;`
+        let mut heap = Heap::new();
+        let chunk = Compiler::compile(
+            "{ var a = 0; while (a < 3) while (a < 2) a = a + 1; }",
+            &mut heap,
+            CompileOptions::default(),
+        )
+        .expect("no error");
+        let edges = back_edges(&chunk);
+        assert_eq!(edges.len(), 2);
+        assert_eq!(chunk.get_span(edges[0].0), chunk.get_span(edges[1].0));
+
+        for (i, (back_edge, _)) in edges.into_iter().enumerate() {
+            let (optimized, start) = optimize(&chunk, back_edge);
+            assert_eq!(start, Some(back_edges(&optimized)[i].1));
+        }
+    }
+}
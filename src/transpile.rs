@@ -0,0 +1,659 @@
+// Turns a script into Rust source that runs it with the `runtime` module,
+// for running it without compiling it at startup, and for comparing what
+// the VM does with what a compiled program does.
+//
+// Every Lox function becomes a Rust function that takes the runtime and its
+// arguments as `Value`s, and the top level of the script becomes `script`.
+// Locals become Rust variables, named like in Lox with a `lox_` prefix,
+// which shadow each other the same way that Lox's do, and globals are looked up in the runtime by their name. Like on
+// the wasm target, functions can only be called by their name, so that the
+// calls are calls of the Rust functions. A global function can be called
+// before its declaration has run, since Rust functions always exist.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    ast::{Expr, Function, Literal, Program, Stmt},
+    diagnostic::{Diagnostic, Severity},
+    scanner::{Token, TokenKind},
+};
+
+const INDENT: &str = "    ";
+
+// the Rust name of a Lox identifier. the prefix keeps it from being a
+// keyword of Rust or a name that the generated code uses itself, like `rt`,
+// without it ever being the same as the name of another identifier
+fn identifier(name: &str) -> String {
+    format!("lox_{}", name)
+}
+
+#[derive(Debug, Clone)]
+enum Binding {
+    Local(String),
+    // a function declared in the function's scope
+    Function { name: String, arity: usize },
+}
+
+#[derive(Debug, Clone)]
+enum Global {
+    Variable,
+    Function { name: String, arity: usize },
+}
+
+// the state of the function that is being transpiled, which is set aside
+// while a function nested inside of it is transpiled
+struct FunctionState {
+    // the Rust name of the function
+    name: String,
+    code: String,
+    depth: usize,
+    // innermost last, the script's outermost scope is the globals instead
+    scopes: Vec<Vec<(String, Binding)>>,
+    is_script: bool,
+}
+
+impl FunctionState {
+    fn new(name: String, is_script: bool) -> Self {
+        Self {
+            name,
+            code: String::new(),
+            depth: 1,
+            scopes: vec![vec![]],
+            is_script,
+        }
+    }
+}
+
+struct Transpiler {
+    function: FunctionState,
+    globals: HashMap<String, Global>,
+    // the Rust names of every function so far, which have to be distinct
+    // since all of them are at the top of the file
+    names: HashSet<String>,
+    // the finished functions, in the order that they are finished
+    functions: Vec<String>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Transpiles `program` into the source of a Rust program, or returns an
+/// error for every part of it that cannot be transpiled. `source_name` is
+/// only mentioned in a comment. The program is expected to have compiled
+/// without errors for the VM.
+pub fn transpile(program: &Program, source_name: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut transpiler = Transpiler {
+        function: FunctionState::new("script".to_string(), true),
+        globals: HashMap::new(),
+        names: HashSet::new(),
+        functions: vec![],
+        diagnostics: vec![],
+    };
+
+    transpiler.declare_globals(&program.statements);
+    program
+        .statements
+        .iter()
+        .for_each(|stmt| transpiler.statement(stmt));
+    let script = transpiler.finish_function(vec![]);
+
+    if !transpiler.diagnostics.is_empty() {
+        return Err(transpiler.diagnostics);
+    }
+
+    let mut rust = format!(
+        "// Transpiled from {} by `clox transpile`, and built against the clox crate.\n",
+        source_name
+    );
+    rust.push_str("#![allow(non_snake_case, unused_mut, unused_variables, unreachable_code)]\n\n");
+    rust.push_str("use std::process;\n\n");
+    rust.push_str("use clox::{\n");
+    rust.push_str("    runtime::{Runtime, RuntimeError},\n");
+    rust.push_str("    value::Value,\n");
+    rust.push_str("};\n\n");
+    rust.push_str("fn main() {\n");
+    rust.push_str("    let rt = Runtime::new();\n");
+    rust.push_str("    if let Err(error) = script(&rt) {\n");
+    rust.push_str("        eprintln!(\"{}\", error);\n");
+    rust.push_str("        process::exit(70);\n");
+    rust.push_str("    }\n");
+    rust.push_str("}\n");
+    rust.push('\n');
+    rust.push_str(&script);
+    transpiler.functions.iter().for_each(|function| {
+        rust.push('\n');
+        rust.push_str(function);
+    });
+    Ok(rust)
+}
+
+impl Transpiler {
+    fn error<S: AsRef<str>>(&mut self, token: &Token, message: S) {
+        self.diagnostics.push(Diagnostic::at_token(
+            Severity::Error,
+            token,
+            message,
+            Some("only functions that are called by their name can be transpiled"),
+        ));
+    }
+
+    // a Rust name for a function that is not taken yet
+    fn function_name(&mut self, name: String) -> String {
+        let mut unique = name.clone();
+        let mut suffix = 2;
+        while self.names.contains(&unique) {
+            unique = format!("{}_{}", name, suffix);
+            suffix += 1;
+        }
+        self.names.insert(unique.clone());
+        unique
+    }
+
+    // the functions of the script are known before it runs, so that a
+    // function can call another one that is declared after it
+    fn declare_globals(&mut self, statements: &[Stmt]) {
+        for stmt in statements {
+            match stmt {
+                Stmt::Var { name, .. } => match self.globals.get(name.lexeme) {
+                    Some(Global::Function { .. }) => {
+                        self.error(name, "A global cannot be both a function and a variable.")
+                    }
+                    _ => {
+                        self.globals
+                            .insert(name.lexeme.to_string(), Global::Variable);
+                    }
+                },
+                Stmt::Function(function) => {
+                    if self.globals.contains_key(function.name.lexeme) {
+                        self.error(&function.name, "A global function cannot be redefined.");
+                    } else {
+                        let name = self.function_name(identifier(function.name.lexeme));
+                        self.globals.insert(
+                            function.name.lexeme.to_string(),
+                            Global::Function {
+                                name,
+                                arity: function.params.len(),
+                            },
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // returns the source of the function, `params` are its Rust names
+    fn finish_function(&mut self, params: Vec<String>) -> String {
+        let function = &self.function;
+        let mut source = if function.is_script {
+            "fn script(rt: &Runtime) -> Result<(), RuntimeError> {\n".to_string()
+        } else {
+            let params = params
+                .iter()
+                .map(|param| format!(", mut {}: Value", param))
+                .collect::<String>();
+            format!(
+                "fn {}(rt: &Runtime{}) -> Result<Value, RuntimeError> {{\n",
+                function.name, params
+            )
+        };
+        source.push_str(&function.code);
+        if function.is_script {
+            source.push_str("    Ok(())\n");
+        } else {
+            // the implicit return at the end of the function
+            source.push_str("    Ok(Value::Nil)\n");
+        }
+        source.push_str("}\n");
+        source
+    }
+
+    fn line<S: AsRef<str>>(&mut self, line: S) {
+        (0..self.function.depth).for_each(|_| self.function.code.push_str(INDENT));
+        self.function.code.push_str(line.as_ref());
+        self.function.code.push('\n');
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.function.is_script && self.function.scopes.len() == 1
+    }
+
+    fn declare(&mut self, name: &str, binding: Binding) {
+        self.function
+            .scopes
+            .last_mut()
+            .unwrap_or_else(|| panic!("ICE: No scope to declare in"))
+            .push((name.to_string(), binding));
+    }
+
+    fn resolve(&self, name: &str) -> Option<Binding> {
+        self.function
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(local, _)| local == name)
+            .map(|(_, binding)| binding.clone())
+    }
+
+    fn begin_block(&mut self, opening: &str) {
+        self.line(opening);
+        self.function.depth += 1;
+        self.function.scopes.push(vec![]);
+    }
+
+    fn end_block(&mut self, closing: &str) {
+        self.function.scopes.pop();
+        self.function.depth -= 1;
+        self.line(closing);
+    }
+
+    // the statements of a block, or `stmt` itself if it is not a block
+    fn block_body(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Block { statements, .. } => {
+                statements.iter().for_each(|stmt| self.statement(stmt));
+            }
+            stmt => self.statement(stmt),
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression { expr, .. } => self.expression_statement(expr),
+            Stmt::Print { expr, .. } => {
+                let expr = self.expression(expr);
+                self.line(format!("rt.print({});", expr));
+            }
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                let value = match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => "Value::Nil".to_string(),
+                };
+                if self.is_global_scope() {
+                    self.line(format!("rt.define_global({:?}, {});", name.lexeme, value));
+                } else {
+                    let local = identifier(name.lexeme);
+                    self.line(format!("let mut {} = {};", local, value));
+                    self.declare(name.lexeme, Binding::Local(local));
+                }
+            }
+            Stmt::Function(function) => self.function_declaration(function),
+            Stmt::Block { statements, .. } => {
+                self.begin_block("{");
+                statements.iter().for_each(|stmt| self.statement(stmt));
+                self.end_block("}");
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let condition = self.expression(condition);
+                self.begin_block(&format!("if rt.is_truthy({}) {{", condition));
+                self.block_body(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.end_block("} else {");
+                    self.function.depth += 1;
+                    self.function.scopes.push(vec![]);
+                    self.block_body(else_branch);
+                }
+                self.end_block("}");
+            }
+            Stmt::While {
+                condition, body, ..
+            } => {
+                let condition = self.expression(condition);
+                self.begin_block(&format!("while rt.is_truthy({}) {{", condition));
+                self.block_body(body);
+                self.end_block("}");
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                // the variable of the loop is in a scope of its own
+                self.begin_block("{");
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                let condition = match condition {
+                    Some(condition) => format!("rt.is_truthy({})", self.expression(condition)),
+                    None => "true".to_string(),
+                };
+                self.begin_block(&format!("while {} {{", condition));
+                if declares_locals(body) {
+                    // or they would shadow the variables of the increment
+                    self.statement(body);
+                } else {
+                    self.block_body(body);
+                }
+                if let Some(increment) = increment {
+                    self.expression_statement(increment);
+                }
+                self.end_block("}");
+                self.end_block("}");
+            }
+            Stmt::Return { value, .. } => {
+                let value = match value {
+                    Some(value) => self.expression(value),
+                    None => "Value::Nil".to_string(),
+                };
+                self.line(format!("return Ok({});", value));
+            }
+        }
+    }
+
+    fn expression_statement(&mut self, expr: &Expr) {
+        match expr {
+            // the most common statements read the same as in Lox
+            Expr::Assign { name, value, .. }
+                if matches!(self.resolve(name.lexeme), Some(Binding::Local(_))) =>
+            {
+                let value = self.expression(value);
+                let name = identifier(name.lexeme);
+                self.line(format!("{} = {};", name, value));
+            }
+            Expr::Assign { .. } | Expr::Call { .. } => {
+                let expr = self.expression(expr);
+                self.line(format!("{};", expr));
+            }
+            expr => {
+                let expr = self.expression(expr);
+                self.line(format!("let _ = {};", expr));
+            }
+        }
+    }
+
+    fn function_declaration(&mut self, function: &Function) {
+        let name = match self.globals.get(function.name.lexeme) {
+            Some(Global::Function { name, .. }) if self.is_global_scope() => name.clone(),
+            _ => {
+                // nested functions are named after the function that they
+                // are declared in
+                let name = if self.function.is_script {
+                    identifier(function.name.lexeme)
+                } else {
+                    format!("{}_{}", self.function.name, function.name.lexeme)
+                };
+                self.function_name(name)
+            }
+        };
+        if !self.is_global_scope() {
+            self.declare(
+                function.name.lexeme,
+                Binding::Function {
+                    name: name.clone(),
+                    arity: function.params.len(),
+                },
+            );
+        }
+
+        let mut state = FunctionState::new(name, false);
+        let params = function
+            .params
+            .iter()
+            .map(|param| {
+                let local = identifier(param.lexeme);
+                state.scopes[0].push((param.lexeme.to_string(), Binding::Local(local.clone())));
+                local
+            })
+            .collect();
+
+        let enclosing = std::mem::replace(&mut self.function, state);
+        function.body.iter().for_each(|stmt| self.statement(stmt));
+        let source = self.finish_function(params);
+        self.function = enclosing;
+        self.functions.push(source);
+    }
+
+    fn expression(&mut self, expr: &Expr) -> String {
+        match expr {
+            Expr::Literal { value, .. } => match value {
+                Literal::Nil => "Value::Nil".to_string(),
+                Literal::True => "Value::Bool(true)".to_string(),
+                Literal::False => "Value::Bool(false)".to_string(),
+            },
+            Expr::Number { value, .. } => format!("Value::Number({:?})", value),
+            Expr::String { value, .. } => format!("rt.string({:?})", value),
+            Expr::Variable { name } => match self.resolve(name.lexeme) {
+                Some(Binding::Local(local)) => local,
+                Some(Binding::Function { .. }) => self.function_value(name),
+                None => match self.globals.get(name.lexeme) {
+                    Some(Global::Function { .. }) => self.function_value(name),
+                    _ => format!("rt.get_global({:?}, {})?", name.lexeme, name.line),
+                },
+            },
+            Expr::Assign { name, value, .. } => {
+                let value = self.expression(value);
+                match self.resolve(name.lexeme) {
+                    Some(Binding::Local(local)) => {
+                        format!("{{ {} = {}; {} }}", local, value, local)
+                    }
+                    Some(Binding::Function { .. }) => self.function_value(name),
+                    None => match self.globals.get(name.lexeme) {
+                        Some(Global::Function { .. }) => self.function_value(name),
+                        _ => format!(
+                            "rt.set_global({:?}, {}, {})?",
+                            name.lexeme, value, name.line
+                        ),
+                    },
+                }
+            }
+            Expr::Unary { operator, operand } => {
+                let operand = self.expression(operand);
+                match operator.kind {
+                    TokenKind::Minus => format!("rt.negate({}, {})?", operand, operator.line),
+                    TokenKind::Bang => format!("rt.not({})", operand),
+                    _ => panic!("ICE: Unknown unary operator {:?}", operator.kind),
+                }
+            }
+            Expr::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                let method = match operator.kind {
+                    TokenKind::EqualEqual => return format!("rt.equal({}, {})", left, right),
+                    TokenKind::BangEqual => return format!("rt.not_equal({}, {})", left, right),
+                    TokenKind::Plus => "add",
+                    TokenKind::Minus => "subtract",
+                    TokenKind::Star => "multiply",
+                    TokenKind::Slash => "divide",
+                    TokenKind::Greater => "greater",
+                    TokenKind::GreaterEqual => "greater_equal",
+                    TokenKind::Less => "less",
+                    TokenKind::LessEqual => "less_equal",
+                    _ => panic!("ICE: Unknown binary operator {:?}", operator.kind),
+                };
+                format!("rt.{}({}, {}, {})?", method, left, right, operator.line)
+            }
+            Expr::Logical {
+                operator,
+                left,
+                right,
+            } => {
+                let left = self.expression(left);
+                let right = self.expression(right);
+                // `right` is in an arm without bindings, so that `left`
+                // cannot shadow one of its variables
+                let guard = match operator.kind {
+                    TokenKind::And => "left.is_falsey()",
+                    _ => "!left.is_falsey()",
+                };
+                format!(
+                    "match {} {{ left if {} => left, _ => {} }}",
+                    left, guard, right
+                )
+            }
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+                ..
+            } => self.call(callee, paren, arguments),
+        }
+    }
+
+    fn function_value(&mut self, name: &Token) -> String {
+        self.error(
+            name,
+            "Functions can only be called by their name when transpiled.",
+        );
+        "Value::Nil".to_string()
+    }
+
+    fn call(&mut self, callee: &Expr, paren: &Token, arguments: &[Expr]) -> String {
+        let function = match callee {
+            Expr::Variable { name } => match self.resolve(name.lexeme) {
+                Some(Binding::Function { name, arity }) => Some((name, arity)),
+                Some(Binding::Local(_)) => None,
+                None => match self.globals.get(name.lexeme) {
+                    Some(Global::Function { name, arity }) => Some((name.clone(), *arity)),
+                    Some(Global::Variable) => None,
+                    // it is an error to use a global that is never defined,
+                    // which is left for the runtime to report
+                    None => {
+                        return format!("rt.get_global({:?}, {})?", name.lexeme, name.line);
+                    }
+                },
+            },
+            _ => None,
+        };
+        let Some((name, arity)) = function else {
+            self.error(
+                callee.first_token(),
+                "Only functions that are called by their name can be transpiled.",
+            );
+            return "Value::Nil".to_string();
+        };
+
+        let arguments = arguments
+            .iter()
+            .map(|argument| self.expression(argument))
+            .collect::<Vec<_>>();
+        if arguments.len() != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arguments.len());
+            // the arguments are still evaluated before the call fails
+            let arguments = arguments
+                .iter()
+                .map(|argument| format!("let _ = {}; ", argument))
+                .collect::<String>();
+            return format!(
+                "{{ {}return Err(RuntimeError::new({:?}, {})); }}",
+                arguments, message, paren.line
+            );
+        }
+
+        let arguments = arguments
+            .iter()
+            .map(|argument| format!(", {}", argument))
+            .collect::<String>();
+        format!("{}(rt{})?", name, arguments)
+    }
+}
+
+// whether the block declares variables or functions of its own
+fn declares_locals(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Block { statements, .. } => statements
+            .iter()
+            .any(|stmt| matches!(stmt, Stmt::Var { .. } | Stmt::Function(_))),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+
+    use super::*;
+
+    fn transpile_source(source: &str) -> Result<String, Vec<Diagnostic>> {
+        let program = Parser::new(source).parse();
+        transpile(&program, "test.lox")
+    }
+
+    #[test]
+    fn test_transpile() {
+        let rust = transpile_source(
+            "fun add(a, b) { return a + b; }
+             var total = 0;
+             for (var i = 0; i < 3; i = i + 1) { total = add(total, i); }
+             print total;",
+        )
+        .expect("supported program");
+
+        assert!(rust.contains(
+            "fn lox_add(rt: &Runtime, mut lox_a: Value, mut lox_b: Value) \
+             -> Result<Value, RuntimeError> {\n    \
+             return Ok(rt.add(lox_a, lox_b, 1)?);\n"
+        ));
+        assert!(rust.contains("    rt.define_global(\"total\", Value::Number(0.0));\n"));
+        assert!(rust.contains("        let mut lox_i = Value::Number(0.0);\n"));
+        assert!(
+            rust.contains("        while rt.is_truthy(rt.less(lox_i, Value::Number(3.0), 3)?) {\n")
+        );
+        assert!(rust.contains(
+            "            rt.set_global(\"total\", lox_add(rt, rt.get_global(\"total\", 3)?, lox_i)?, 3)?;\n"
+        ));
+        assert!(rust.contains("            lox_i = rt.add(lox_i, Value::Number(1.0), 3)?;\n"));
+        assert!(rust.contains("    rt.print(rt.get_global(\"total\", 4)?);\n"));
+    }
+
+    #[test]
+    fn test_transpile_names() {
+        let rust = transpile_source(
+            "fun outer() { fun inner(match) { return match; } return inner(1); }
+             { fun outer() {} }",
+        )
+        .expect("supported program");
+
+        // the nested functions are at the top of the file, with names of
+        // their own, and the Rust keywords are left alone
+        assert!(rust.contains("fn lox_outer(rt: &Runtime)"));
+        assert!(rust.contains("fn lox_outer_inner(rt: &Runtime, mut lox_match: Value)"));
+        assert!(rust.contains("    return Ok(lox_outer_inner(rt, Value::Number(1.0))?);\n"));
+        assert!(rust.contains("fn lox_outer_2(rt: &Runtime)"));
+
+        // a name that would have been changed is still not the same as one
+        // that was written that way
+        let rust =
+            transpile_source("{ var fn = 1; var fn_ = 2; var rt = 3; print fn + fn_ + rt; }")
+                .expect("supported program");
+        assert!(rust.contains("rt.add(rt.add(lox_fn, lox_fn_, 1)?, lox_rt, 1)?"));
+    }
+
+    #[test]
+    fn test_transpile_unsupported() {
+        let errors = |source: &str| {
+            transpile_source(source)
+                .expect_err("unsupported program")
+                .into_iter()
+                .map(|diagnostic| (diagnostic.span.line, diagnostic.message))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            errors("fun f() {}\nvar g = f;"),
+            vec![(
+                2,
+                "Functions can only be called by their name when transpiled.".to_string()
+            )]
+        );
+        assert_eq!(
+            errors("var f;\nf();"),
+            vec![(
+                2,
+                "Only functions that are called by their name can be transpiled.".to_string()
+            )]
+        );
+    }
+}
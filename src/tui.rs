@@ -0,0 +1,279 @@
+// The full screen view of `clox debug --tui`, for watching the VM run one
+// instruction at a time: the source with the line that is running, the
+// stack, and the disassembly of the function side by side, over what the
+// script has printed so far.
+//
+// It is drawn with plain ANSI escape codes, clearing the screen and drawing
+// it again at each step, and reads its commands a line at a time.
+
+use std::{
+    io::{self, BufRead},
+    ops::ControlFlow,
+};
+
+use crate::{
+    debug,
+    io::SharedOutput,
+    vm::{Debugger, InterpretError, Paused},
+};
+
+const CLEAR: &str = "\x1b[2J\x1b[H";
+const HIGHLIGHT: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+const SEPARATOR: &str = " | ";
+
+// the rows of the output pane, including its title
+const OUTPUT_ROWS: usize = 5;
+
+/// Steps through a script instruction by instruction, drawing it on
+/// `output` before each one. The VM should print to
+/// [`Tui::script_output`], which is written out in full once the script
+/// has stopped.
+pub struct Tui<R: BufRead, W: io::Write> {
+    lines: Vec<String>,
+    input: R,
+    output: W,
+    width: usize,
+    height: usize,
+    script_output: SharedOutput,
+    // whether to stop before the next instruction, until told to continue
+    stepping: bool,
+    // what went wrong with the last command, shown on the status line
+    message: Option<String>,
+}
+
+impl<R: BufRead, W: io::Write> Tui<R, W> {
+    pub fn new(source: &str, input: R, output: W, width: usize, height: usize) -> Self {
+        Self {
+            lines: source.lines().map(str::to_string).collect(),
+            input,
+            output,
+            width,
+            height,
+            script_output: SharedOutput::default(),
+            stepping: true,
+            message: None,
+        }
+    }
+
+    pub fn script_output(&self) -> SharedOutput {
+        self.script_output.clone()
+    }
+
+    /// The screen for `paused`, without clearing the one before.
+    pub fn render(&self, paused: &Paused<'_>) -> String {
+        let rows = self.height.saturating_sub(OUTPUT_ROWS + 2).max(1);
+        let source_width = self.width * 3 / 10;
+        let stack_width = self.width / 5;
+        let code_width = self
+            .width
+            .saturating_sub(source_width + stack_width + 2 * SEPARATOR.len());
+
+        let line = paused.line();
+        let source = self.source_pane(line, rows);
+        let stack = stack_pane(paused, rows);
+        let code = code_pane(paused, rows);
+
+        let mut screen = String::new();
+        for row in 0..rows {
+            let cells = [
+                (&source, source_width),
+                (&stack, stack_width),
+                (&code, code_width),
+            ];
+            let cells = cells
+                .iter()
+                .map(|(pane, width)| match pane.get(row) {
+                    Some((text, true)) => format!("{}{}{}", HIGHLIGHT, fit(text, *width), RESET),
+                    Some((text, false)) => fit(text, *width),
+                    None => fit("", *width),
+                })
+                .collect::<Vec<_>>();
+            screen.push_str(cells.join(SEPARATOR).trim_end());
+            screen.push('\n');
+        }
+
+        screen.push_str(&"-".repeat(self.width));
+        screen.push('\n');
+        let printed = self.script_output.text();
+        let printed = printed.lines().collect::<Vec<_>>();
+        let shown = printed.len().saturating_sub(OUTPUT_ROWS - 1);
+        for row in 0..OUTPUT_ROWS - 1 {
+            screen.push_str(fit(printed.get(shown + row).unwrap_or(&""), self.width).trim_end());
+            screen.push('\n');
+        }
+
+        let function = paused.function.unwrap_or("<script>");
+        let status = match &self.message {
+            Some(message) => message.clone(),
+            None => "enter: step, c: continue, q: quit".to_string(),
+        };
+        screen.push_str(&format!("{} line {} - {}\n", function, line, status));
+        screen
+    }
+
+    // the lines around the one that is running, which is highlighted
+    fn source_pane(&self, line: u32, rows: usize) -> Vec<(String, bool)> {
+        let current = line as usize;
+        let start = window(current.saturating_sub(1), self.lines.len(), rows - 1);
+        let mut pane = vec![("source".to_string(), false)];
+        pane.extend(
+            self.lines
+                .iter()
+                .enumerate()
+                .skip(start)
+                .take(rows - 1)
+                .map(|(i, text)| (format!("{:4} {}", i + 1, text), i + 1 == current)),
+        );
+        pane
+    }
+}
+
+// the stack from the top down, with the slots of each value
+fn stack_pane(paused: &Paused<'_>, rows: usize) -> Vec<(String, bool)> {
+    let mut pane = vec![("stack".to_string(), false)];
+    pane.extend(
+        paused
+            .stack
+            .iter()
+            .enumerate()
+            .rev()
+            .take(rows - 1)
+            .map(|(slot, value)| (format!("{:3} {}", slot, value), false)),
+    );
+    pane
+}
+
+// the disassembly of the function around the instruction that runs next,
+// which is highlighted
+fn code_pane(paused: &Paused<'_>, rows: usize) -> Vec<(String, bool)> {
+    let mut instructions = vec![];
+    let mut offset = 0;
+    while offset < paused.chunk.code_len() {
+        let (text, next) = debug::disassemble_instruction_to_string(paused.chunk, offset);
+        instructions.push((text.trim_end().to_string(), offset == paused.offset));
+        offset = next;
+    }
+
+    let current = instructions
+        .iter()
+        .position(|(_, current)| *current)
+        .unwrap_or(0);
+    let start = window(current, instructions.len(), rows - 1);
+    let mut pane = vec![(
+        format!("code of {}", paused.function.unwrap_or("<script>")),
+        false,
+    )];
+    pane.extend(instructions.into_iter().skip(start).take(rows - 1));
+    pane
+}
+
+// where to start showing `rows` of `len` items, so that `current` is in the
+// middle where it can be
+fn window(current: usize, len: usize, rows: usize) -> usize {
+    current
+        .saturating_sub(rows / 2)
+        .min(len.saturating_sub(rows))
+}
+
+// `text` cut or padded to `width` characters
+fn fit(text: &str, width: usize) -> String {
+    let text = text.chars().take(width).collect::<String>();
+    let len = text.chars().count();
+    text + &" ".repeat(width - len)
+}
+
+impl<R: BufRead, W: io::Write> Debugger for Tui<R, W> {
+    fn before_instruction(&mut self, paused: &Paused<'_>) -> ControlFlow<()> {
+        if !self.stepping {
+            return ControlFlow::Continue(());
+        }
+
+        loop {
+            let screen = self.render(paused);
+            write!(self.output, "{}{}> ", CLEAR, screen).expect("writable");
+            self.output.flush().expect("writable");
+            self.message = None;
+
+            let mut command = String::new();
+            match self.input.read_line(&mut command) {
+                // without any more commands, the rest of the script runs
+                Ok(0) | Err(_) => {
+                    self.stepping = false;
+                    return ControlFlow::Continue(());
+                }
+                Ok(_) => match command.trim() {
+                    "" | "s" => return ControlFlow::Continue(()),
+                    "c" => {
+                        self.stepping = false;
+                        return ControlFlow::Continue(());
+                    }
+                    "q" => return ControlFlow::Break(()),
+                    command => self.message = Some(format!("Unknown command '{}'.", command)),
+                },
+            }
+        }
+    }
+
+    fn finished(&mut self, _result: &Result<(), InterpretError>) {
+        // the screen goes, and what the script printed stays
+        write!(self.output, "{}{}", CLEAR, self.script_output.text()).expect("writable");
+        self.output.flush().expect("writable");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn test_tui() {
+        let source = "var a = 1;\nprint a;";
+        let screens = SharedOutput::default();
+        let tui = Tui::new(
+            source,
+            io::Cursor::new("\n\nx\n\n\nc\n"),
+            screens.clone(),
+            100,
+            12,
+        );
+        let mut vm = VM::with_output(tui.script_output());
+        vm.set_tiering(false);
+        vm.set_debugger(Box::new(tui));
+        assert_eq!(vm.interpret(source), Ok(()));
+
+        let screens = screens.text();
+        let screens = screens.split(CLEAR).collect::<Vec<_>>();
+        assert_eq!(screens.len(), 8);
+        // the values that the instructions leave are on the stack
+        assert!(screens[2].contains("|   1 1     "));
+        // the instruction about to run is highlighted, along with its line
+        assert_eq!(
+            screens[4].lines().collect::<Vec<_>>(),
+            [
+                "source                         | stack                | code of <script>",
+                "   1 var a = 1;                |   0 <script>         | 0000    1 OP_CONSTANT         1 '1'",
+                &format!(
+                    "{}   2 print a;                 {} |                      | 0002    | OP_DEFINE_GLOBAL    0 'a'",
+                    HIGHLIGHT, RESET
+                ),
+                &format!(
+                    "                               |                      | {}0004    2 OP_GET_GLOBAL       0 'a'         {}",
+                    HIGHLIGHT, RESET
+                ),
+                "                               |                      | 0006    | OP_PRINT",
+                &"-".repeat(100),
+                "",
+                "",
+                "",
+                "",
+                "<script> line 2 - Unknown command 'x'.",
+                "> ",
+            ]
+        );
+        // the script prints under the panes, and then once the view is gone
+        assert!(screens[6].contains("\n1\n"));
+        assert_eq!(screens[7], "1\n");
+    }
+}
@@ -1,14 +1,222 @@
-#[derive(Debug, Clone, Copy, PartialEq)]
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::fmt;
+
+use crate::{
+    decimal::Decimal,
+    object::{ObjData, ObjRef, ObjString, ObjType},
+};
+
+#[derive(Debug, Clone, Copy)]
 pub enum Value {
     Nil,
     Bool(bool),
     Number(f64),
+    Obj(ObjRef),
 }
 
 impl Value {
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Nil | Value::Bool(false))
     }
+
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match self {
+            Value::Obj(obj) => obj.as_string(),
+            _ => None,
+        }
+    }
+
+    pub fn as_decimal(&self) -> Option<&Decimal> {
+        match self {
+            Value::Obj(obj) => obj.as_decimal(),
+            _ => None,
+        }
+    }
+
+    /// The name of the value's type, which is what a [`TypeError`] reports.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::Obj(obj) => match obj.obj_type() {
+                ObjType::String => "string",
+                ObjType::Function | ObjType::Native => "function",
+                ObjType::Decimal => "number",
+            },
+        }
+    }
+
+    pub fn as_number(&self) -> Result<f64, TypeError> {
+        match self {
+            Value::Number(value) => Ok(*value),
+            _ => Err(TypeError::new("number", self)),
+        }
+    }
+
+    /// The boolean itself, without converting other values the way that
+    /// [`Value::is_falsey`] does.
+    pub fn as_bool(&self) -> Result<bool, TypeError> {
+        match self {
+            Value::Bool(value) => Ok(*value),
+            _ => Err(TypeError::new("boolean", self)),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, TypeError> {
+        self.as_string()
+            .map(ObjString::as_str)
+            .ok_or_else(|| TypeError::new("string", self))
+    }
+}
+
+/// A value of the wrong type was used where another type was expected, such
+/// as `-"a"`. It shows as the book's "Operand must be a number." message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeError {
+    pub expected: &'static str,
+    pub actual: &'static str,
+}
+
+impl TypeError {
+    fn new(expected: &'static str, actual: &Value) -> Self {
+        Self {
+            expected,
+            actual: actual.type_name(),
+        }
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Operand must be a {}.", self.expected)
+    }
+}
+
+/// Lox's `==`, which is also how values compare in Rust. Nil, booleans and
+/// numbers (decimals included) are compared by value (so NaN is not equal to
+/// itself), strings by their characters, and functions by identity. Values of
+/// different types are never equal.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            // strings on the same heap are interned, so they have the same
+            // characters only if they are the same object. the characters
+            // are compared for strings from different heaps, and for long
+            // ones that concatenating made, which are not interned. decimals
+            // are not interned either, so they are always compared by value
+            (Value::Obj(a), Value::Obj(b)) => {
+                a == b
+                    || match (a.obj().data(), b.obj().data()) {
+                        (ObjData::String(a), ObjData::String(b)) => a == b,
+                        (ObjData::Decimal(a), ObjData::Decimal(b)) => a == b,
+                        _ => false,
+                    }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// How `print` shows a value, which is what clox's `printValue` prints.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(value) => write!(f, "{}", format_number(*value)),
+            Value::Obj(obj) => write!(f, "{}", obj),
+        }
+    }
+}
+
+/// A value copied out of the heap that it is on, so that it stays valid
+/// after the heap is gone, for the values that the VM hands out. Functions
+/// are kept as `print` shows them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OwnedValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Decimal(Decimal),
+    Function(String),
+}
+
+impl From<Value> for OwnedValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Nil => OwnedValue::Nil,
+            Value::Bool(value) => OwnedValue::Bool(value),
+            Value::Number(value) => OwnedValue::Number(value),
+            Value::Obj(obj) => match obj.obj().data() {
+                ObjData::String(string) => OwnedValue::String(string.as_str().to_string()),
+                ObjData::Decimal(decimal) => OwnedValue::Decimal(decimal.clone()),
+                ObjData::Function(_) | ObjData::Native(_) => OwnedValue::Function(obj.to_string()),
+            },
+        }
+    }
+}
+
+/// The same as the [`Value`] that it was copied from.
+impl fmt::Display for OwnedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OwnedValue::Nil => write!(f, "nil"),
+            OwnedValue::Bool(value) => write!(f, "{}", value),
+            OwnedValue::Number(value) => write!(f, "{}", format_number(*value)),
+            OwnedValue::String(chars) | OwnedValue::Function(chars) => write!(f, "{}", chars),
+            OwnedValue::Decimal(decimal) => write!(f, "{}", decimal),
+        }
+    }
+}
+
+/// `value` the way that C's `printf("%g", value)` prints it: six significant
+/// digits, without trailing zeros, and in scientific notation when the
+/// exponent is below -4 or at least 6.
+pub fn format_number(value: f64) -> String {
+    const PRECISION: i32 = 6;
+
+    if value.is_nan() {
+        return "nan".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    if value == 0.0 {
+        return if value.is_sign_negative() { "-0" } else { "0" }.to_string();
+    }
+
+    // the exponent after rounding to the precision, which can be one more
+    // than the exponent of `value` (e.g. 999999.5 is 1e+06)
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, value);
+    let (mantissa, exponent) = scientific.split_once('e').expect("has an exponent");
+    let exponent = exponent.parse::<i32>().expect("exponent is a number");
+
+    if (-4..PRECISION).contains(&exponent) {
+        let fixed = format!("{:.*}", (PRECISION - 1 - exponent) as usize, value);
+        trim_fraction(&fixed).to_string()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_fraction(mantissa), sign, exponent.abs())
+    }
+}
+
+// drops the trailing zeros of the fraction, and the point if nothing is left
+fn trim_fraction(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -16,23 +224,72 @@ pub struct ValueArray {
     values: Vec<Value>,
 }
 
+impl Default for ValueArray {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ValueArray {
+    /// How many values the operand of OP_CONSTANT can index, as it is a
+    /// 32-bit varint.
+    pub const MAX_LEN: usize = u32::MAX as usize + 1;
+
     pub fn new() -> Self {
         Self { values: vec![] }
     }
 
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            values: Vec::with_capacity(capacity),
+        }
+    }
+
     pub fn add(&mut self, value: Value) -> usize {
         self.values.push(value);
         self.values.len() - 1
     }
 
+    /// The value at `i`, which has to be there. Indices come from bytecode
+    /// that the compiler or the loader has checked.
     pub fn get(&self, i: usize) -> Value {
         self.values[i]
     }
+
+    /// The value at `i`, or `None` if there are not that many values.
+    pub fn try_get(&self, i: usize) -> Option<Value> {
+        self.values.get(i).copied()
+    }
+
+    pub fn set(&mut self, i: usize, value: Value) {
+        self.values[i] = value;
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.values.capacity()
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = Value> + '_ {
+        self.values.iter().copied()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::{chunk::Chunk, object::Heap, object::ObjFunction};
+
     use super::*;
 
     #[test]
@@ -46,6 +303,122 @@ mod tests {
         assert!(!Value::Number(1.0).is_falsey());
         assert!(!Value::Number(-1.0).is_falsey());
         assert!(!Value::Number(0.5).is_falsey());
+
+        let mut heap = Heap::new();
+        assert!(!Value::Obj(heap.alloc_string("".to_string())).is_falsey());
+    }
+
+    #[test]
+    fn test_value_eq() {
+        assert_eq!(Value::Nil, Value::Nil);
+        assert_eq!(Value::Bool(true), Value::Bool(true));
+        assert_ne!(Value::Bool(true), Value::Bool(false));
+        assert_eq!(Value::Number(1.0), Value::Number(1.0));
+        assert_ne!(Value::Number(1.0), Value::Bool(true));
+        assert_ne!(Value::Nil, Value::Bool(false));
+
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+
+        let mut heap = Heap::new();
+        let a = Value::Obj(heap.alloc_string("abc".to_string()));
+        let b = Value::Obj(heap.alloc_string("abc".to_string()));
+        let c = Value::Obj(heap.alloc_string("abd".to_string()));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(a, Value::Nil);
+
+        // different objects, but same content
+        let mut other = Heap::new();
+        assert_eq!(a, Value::Obj(other.alloc_string("abc".to_string())));
+
+        // functions are only equal to themselves
+        let function = || ObjFunction {
+            arity: 0,
+            chunk: Chunk::new(),
+            name: None,
+        };
+        let f = Value::Obj(heap.alloc_function(function()));
+        assert_eq!(f, f);
+        assert_ne!(f, Value::Obj(heap.alloc_function(function())));
+    }
+
+    #[test]
+    fn test_value_accessors() {
+        let mut heap = Heap::new();
+        let string = Value::Obj(heap.alloc_string("a".to_string()));
+
+        assert_eq!(Value::Number(1.5).as_number(), Ok(1.5));
+        assert_eq!(Value::Bool(false).as_bool(), Ok(false));
+        assert_eq!(string.as_str(), Ok("a"));
+
+        let error = string.as_number().unwrap_err();
+        assert_eq!(
+            error,
+            TypeError {
+                expected: "number",
+                actual: "string"
+            }
+        );
+        assert_eq!(error.to_string(), "Operand must be a number.");
+        assert_eq!(Value::Nil.as_bool().unwrap_err().actual, "nil");
+        assert_eq!(Value::Number(0.0).as_str().unwrap_err().actual, "number");
+    }
+
+    #[test]
+    fn test_value_display() {
+        assert_eq!(Value::Nil.to_string(), "nil");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Bool(false).to_string(), "false");
+
+        let mut heap = Heap::new();
+        assert_eq!(
+            Value::Obj(heap.alloc_string("a \"b\"".to_string())).to_string(),
+            "a \"b\""
+        );
+    }
+
+    #[test]
+    fn test_owned_value() {
+        let (string, function) = {
+            let mut heap = Heap::new();
+            let function = heap.alloc_function(ObjFunction {
+                arity: 0,
+                chunk: Chunk::new(),
+                name: None,
+            });
+            (
+                OwnedValue::from(Value::Obj(heap.copy_string("lox"))),
+                OwnedValue::from(Value::Obj(function)),
+            )
+        };
+        // the heap is gone by now
+        assert_eq!(string, OwnedValue::String("lox".to_string()));
+        assert_eq!(function.to_string(), "<script>");
+        assert_eq!(OwnedValue::from(Value::Number(1.5)).to_string(), "1.5");
+    }
+
+    #[test]
+    fn test_format_number() {
+        let cases = [
+            (3.0, "3"),
+            (-2.5, "-2.5"),
+            (0.1 + 0.2, "0.3"),
+            (1.0 / 3.0, "0.333333"),
+            (100000.0, "100000"),
+            (999999.5, "1e+06"),
+            (1234567.0, "1.23457e+06"),
+            (1e100, "1e+100"),
+            (0.0001, "0.0001"),
+            (0.00001234, "1.234e-05"),
+            (0.0, "0"),
+            (-0.0, "-0"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+            (f64::NAN, "nan"),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(format_number(value), expected, "{:?}", value);
+        }
     }
 
     #[test]
@@ -88,5 +461,23 @@ mod tests {
         assert_eq!(value_array.get(3), Value::Nil);
         assert_eq!(value_array.get(4), Value::Bool(true));
         assert_eq!(value_array.get(5), Value::Bool(false));
+
+        assert_eq!(value_array.try_get(5), Some(Value::Bool(false)));
+        assert_eq!(value_array.try_get(6), None);
+    }
+
+    #[test]
+    fn test_value_array_iter() {
+        let mut value_array = ValueArray::with_capacity(4);
+        assert!(value_array.capacity() >= 4);
+        assert!(value_array.is_empty());
+
+        value_array.add(Value::Nil);
+        value_array.add(Value::Number(1.0));
+        assert_eq!(
+            value_array.iter().collect::<Vec<_>>(),
+            vec![Value::Nil, Value::Number(1.0)]
+        );
+        assert_eq!(value_array.iter().len(), 2);
     }
 }
@@ -1,181 +1,1870 @@
-use std::io;
+use alloc::collections::BTreeMap;
+use alloc::{
+    boxed::Box,
+    format,
+    rc::Rc,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
+    fmt, iter,
+    ops::ControlFlow,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use crate::{
-    chunk::{Chunk, OpCode},
-    compiler::Compiler,
-    debug,
-    value::Value,
+    bytecode::{self, LoadError},
+    chunk::Chunk,
+    color,
+    compiler::{CompileOptions, Compiler},
+    debug::{
+        self, Coverage, HeapDump, Instant, OpcodeCounts, Retainer, StackProfile, Timing, Trace,
+    },
+    decimal::Decimal,
+    diagnostic,
+    instruction::Instruction,
+    io,
+    object::{Heap, ObjFunction, ObjNative, ObjRef},
+    register::{self, Backend, BinaryOp, RegisterCode, RegisterInstruction},
+    stack::Stack,
+    table::Table,
+    tier::{self, HOT_LOOP_THRESHOLD},
+    value::{OwnedValue, Value},
 };
 
-pub struct VM {
-    chunk: Chunk,
+// how deep calls can be nested before it is a stack overflow
+const FRAMES_MAX: usize = 64;
+// how many values the stack holds, which is room for 256 in every frame like
+// clox has, although a frame can take more or less of it
+const STACK_MAX: usize = FRAMES_MAX * 256;
+// the values that the superinstructions push past their stack effect, when
+// they fall back on adding the generic way
+const STACK_SLACK: usize = 1;
+
+// an ongoing call to a function
+struct CallFrame {
+    function: ObjRef,
     ip: usize,
-    stack: Vec<Value>,
+    // where the function's slots start on the stack, the first slot holds
+    // the function itself and the arguments come after it
+    slots: usize,
+    // what `ip` points into when running on registers, instead of the
+    // function's chunk
+    code: Option<Rc<RegisterCode>>,
+    // how many times the call has jumped back to the start of a loop
+    back_edges: u32,
 }
 
+impl CallFrame {
+    fn function(&self) -> &ObjFunction {
+        self.function
+            .as_function()
+            .unwrap_or_else(|| panic!("ICE: Calling a non-function"))
+    }
+}
+
+pub struct VM<W: io::Write = io::Stdout> {
+    frames: Vec<CallFrame>,
+    stack: Stack,
+    // every object allocated while compiling or running is registered here
+    heap: Heap,
+    // the slot of every global in `global_values`, which is what quickened
+    // instructions use instead of looking up the name
+    globals: Table<usize>,
+    global_values: Vec<Value>,
+    // where the `print` statement writes to
+    output: W,
+    // where the diagnostics and runtime errors are written to
+    errors: Box<dyn io::Write>,
+    compile_options: CompileOptions,
+    backend: Backend,
+    // the register code of every function that has been called, when
+    // running on registers
+    register_code: BTreeMap<ObjRef, Rc<RegisterCode>>,
+    // whether functions with hot loops are optimized while they run
+    tiering: bool,
+    // the optimized copy of every function that has been tiered up, which
+    // is called instead of it. the copies are tiered up already, so they
+    // are here as their own copy
+    tiered: BTreeMap<ObjRef, ObjRef>,
+    // where the stack and each instruction are traced before running it,
+    // if anywhere
+    trace: Option<Trace>,
+    // how many times each opcode has run, when they are being counted
+    opcode_counts: Option<OpcodeCounts>,
+    // what each instruction is handed to before it runs, if anything. only
+    // the stack backend has one
+    debugger: Option<Box<dyn Debugger>>,
+    // the runtime error that stopped the last script, if one did
+    last_error: Option<RuntimeErrorReport>,
+    // whether printed values and errors are in color, for a terminal
+    color: bool,
+    // whether runtime errors are printed, besides being kept in last_error
+    report_errors: bool,
+    // set from outside the VM, like a handler of Ctrl+C, to stop the script
+    interrupt: Option<&'static AtomicBool>,
+    // how many more instructions the script can run, when it is limited
+    instruction_limit: Option<u64>,
+    instructions_left: u64,
+    // how many bytes the heap can take up, when it is limited
+    memory_limit: Option<usize>,
+    // how many instructions have run in each call stack, when they are
+    // being counted
+    stack_profile: Option<StackProfile>,
+    coverage: Option<Coverage>,
+    // how long the scripts took, when they are being timed
+    timing: Option<Timing>,
+    // how long each opcode has taken, when it is being timed
+    #[cfg(feature = "profile")]
+    profile: Option<debug::Profile>,
+    // a span for each frame, innermost last, that is exited when the frame
+    // returns
+    #[cfg(feature = "tracing")]
+    call_spans: Vec<tracing::span::EnteredSpan>,
+}
+
+/// A runtime error, along with the calls that were running when it
+/// happened. It is printed the way that the VM reports it on stderr.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct RuntimeErrorReport {
+    pub message: String,
+    /// The innermost call first, and the script last.
+    pub trace: Vec<TraceFrame>,
+}
+
+/// Where a call was when a runtime error happened: at the instruction that
+/// failed, or at the call that it was waiting on.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TraceFrame {
+    /// `None` for the script.
+    pub function: Option<String>,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for RuntimeErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n[line {}, column {}] in ", frame.line, frame.column)?;
+            match &frame.function {
+                Some(name) => write!(f, "{}()", name)?,
+                None => write!(f, "script")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What the VM shows a [`Debugger`] when it pauses before an instruction.
+pub struct Paused<'a> {
+    /// The chunk of the function that is running.
+    pub chunk: &'a Chunk,
+    /// The offset of the instruction that is about to run.
+    pub offset: usize,
+    /// `None` for the script.
+    pub function: Option<&'a str>,
+    /// How many calls are running, including the script.
+    pub depth: usize,
+    pub stack: &'a [Value],
+    /// Where the slots of the function that is running start in `stack`.
+    pub slots: usize,
+    globals: &'a Table<usize>,
+    global_values: &'a [Value],
+}
+
+impl Paused<'_> {
+    /// The globals that are defined, sorted by name.
+    pub fn globals(&self) -> Vec<(String, OwnedValue)> {
+        let mut globals = self
+            .globals
+            .iter()
+            .map(|(name, &slot)| (name.to_string(), self.global_values[slot].into()))
+            .collect::<Vec<_>>();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        globals
+    }
+
+    /// The name of the global whose value is in `slot`, as in the operand
+    /// of OP_GET_GLOBAL_CACHED.
+    pub fn global_name(&self, slot: usize) -> Option<String> {
+        self.globals
+            .iter()
+            .find(|(_, other)| **other == slot)
+            .map(|(name, _)| name.to_string())
+    }
+
+    /// The line of the instruction that is about to run.
+    pub fn line(&self) -> u32 {
+        self.chunk.get_line(self.offset)
+    }
+
+    /// The locals of the function that are in scope, in the order of their
+    /// slots, which leaves the innermost of two with the same name last.
+    pub fn locals(&self) -> Vec<(String, OwnedValue)> {
+        self.chunk
+            .locals_at(self.offset)
+            .into_iter()
+            .filter_map(|local| {
+                let value = self.stack.get(self.slots + local.slot as usize)?;
+                Some((local.name.clone(), (*value).into()))
+            })
+            .collect()
+    }
+
+    /// The value of the variable called `name` where the script is, a local
+    /// if there is one in scope or else a global.
+    pub fn variable(&self, name: &str) -> Option<OwnedValue> {
+        let local = self
+            .locals()
+            .into_iter()
+            .rev()
+            .find(|(local, _)| local == name);
+        if let Some((_, value)) = local {
+            return Some(value);
+        }
+
+        self.globals
+            .iter()
+            .find(|(global, _)| global.as_string().is_some_and(|s| s.as_str() == name))
+            .map(|(_, &slot)| self.global_values[slot].into())
+    }
+}
+
+/// Something that the VM hands every instruction to before running it, such
+/// as the interactive debugger of `clox debug`, which can wait for as long
+/// as it likes before it returns.
+pub trait Debugger {
+    /// Breaking stops the script with [`InterpretError::Interrupted`].
+    fn before_instruction(&mut self, paused: &Paused<'_>) -> ControlFlow<()>;
+
+    /// Called once the instruction at `paused.offset` has set the global
+    /// `name` from `old`, which is `None` when it was not defined yet, to
+    /// `new`. Breaking stops the script the same way.
+    fn global_written(
+        &mut self,
+        _paused: &Paused<'_>,
+        _name: &str,
+        _old: Option<Value>,
+        _new: Value,
+    ) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called once the script has stopped, with how it did.
+    fn finished(&mut self, _result: &Result<(), InterpretError>) {}
+}
+
+// named after the ones in the book
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, PartialEq, Eq)]
 pub enum InterpretError {
     CompileError,
+    // compiled bytecode that was rejected by the loader
+    LoadError,
     RuntimeError,
+    // stopped before it finished, e.g. from the debugger
+    Interrupted,
+}
+
+impl InterpretError {
+    /// The exit code of `clox` for the error, from sysexits.h, or 130 for an
+    /// interrupt like Ctrl+C.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InterpretError::CompileError | InterpretError::LoadError => 65,
+            InterpretError::RuntimeError => 70,
+            InterpretError::Interrupted => 130,
+        }
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
-    pub fn interpret(source: String) -> Result<Option<Value>, InterpretError> {
-        let chunk = Compiler::compile(source).map_err(|_| InterpretError::CompileError)?;
+    pub fn new() -> Self {
+        Self::with_output(io::stdout())
+    }
+}
+
+impl<W: io::Write> VM<W> {
+    pub fn with_output(output: W) -> Self {
+        Self {
+            frames: vec![],
+            stack: Stack::new(STACK_MAX),
+            heap: Heap::new(),
+            globals: Table::new(),
+            global_values: vec![],
+            output,
+            errors: Box::new(io::stderr()),
+            compile_options: CompileOptions::default(),
+            backend: Backend::default(),
+            register_code: BTreeMap::new(),
+            tiering: true,
+            tiered: BTreeMap::new(),
+            trace: debug::is_debug_trace_execution_enabled().then_some(Trace::Stdout),
+            opcode_counts: None,
+            debugger: None,
+            last_error: None,
+            color: false,
+            report_errors: true,
+            interrupt: None,
+            instruction_limit: None,
+            instructions_left: 0,
+            memory_limit: None,
+            stack_profile: None,
+            coverage: None,
+            timing: None,
+            #[cfg(feature = "profile")]
+            profile: None,
+            #[cfg(feature = "tracing")]
+            call_spans: vec![],
+        }
+    }
 
-        let mut vm = Self {
+    pub fn set_compile_options(&mut self, options: CompileOptions) {
+        self.compile_options = options;
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    pub fn set_tiering(&mut self, tiering: bool) {
+        self.tiering = tiering;
+    }
+
+    /// Prints the stack and each instruction as it runs. This starts out on
+    /// when DEBUG_TRACE_EXECUTION=1 is set.
+    pub fn set_trace_execution(&mut self, trace_execution: bool) {
+        self.trace = trace_execution.then_some(Trace::Stdout);
+    }
+
+    /// Traces each instruction to `trace` instead of stdout.
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    /// Counts how many times each opcode runs, across every script that the
+    /// VM runs from now on. Instructions that run on registers are not
+    /// counted, since they are not opcodes.
+    pub fn set_count_opcodes(&mut self, count_opcodes: bool) {
+        self.opcode_counts = count_opcodes.then(OpcodeCounts::new);
+    }
+
+    /// The counts since [`VM::set_count_opcodes`] turned counting on.
+    pub fn opcode_counts(&self) -> Option<&OpcodeCounts> {
+        self.opcode_counts.as_ref()
+    }
+
+    /// Counts how many instructions run in each call stack, across every
+    /// script that the VM runs from now on, on either backend.
+    pub fn set_stack_profile(&mut self, stack_profile: bool) {
+        self.stack_profile = stack_profile.then(StackProfile::new);
+    }
+
+    /// The counts since [`VM::set_stack_profile`] turned counting on.
+    pub fn stack_profile(&self) -> Option<&StackProfile> {
+        self.stack_profile.as_ref()
+    }
+
+    /// Prints values in the color of their type, and errors in red.
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    /// Whether runtime errors are printed to stderr. Either way the last one
+    /// is kept for [`VM::last_error`].
+    pub fn set_report_errors(&mut self, report_errors: bool) {
+        self.report_errors = report_errors;
+    }
+
+    /// Stops the script that is running with an "Interrupted." runtime error
+    /// once `interrupt` is set, which can be from a signal handler or another
+    /// thread. The VM clears it when it stops, and when a script starts.
+    pub fn set_interrupt(&mut self, interrupt: &'static AtomicBool) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// Stops each script with a runtime error once it has run `limit`
+    /// instructions, e.g. one that isn't trusted to ever finish.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_limit = limit;
+    }
+
+    /// Stops the script that is running with a runtime error once the
+    /// objects on the heap take up more than `limit` bytes.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.memory_limit = limit;
+    }
+
+    /// Writes the diagnostics and runtime errors to `errors` instead of
+    /// stderr.
+    pub fn set_error_output(&mut self, errors: Box<dyn io::Write>) {
+        self.errors = errors;
+    }
+
+    /// Where the scripts print.
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    pub fn output_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    /// Records which lines of the scripts run, on either backend.
+    pub fn set_coverage(&mut self, coverage: bool) {
+        self.coverage = coverage.then(Coverage::new);
+    }
+
+    /// The lines since [`VM::set_coverage`] turned recording on.
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Times compiling and running the scripts, on either backend.
+    pub fn set_timing(&mut self, timing: bool) {
+        self.timing = timing.then(Timing::new);
+    }
+
+    /// The scripts since [`VM::set_timing`] turned timing on, added up.
+    pub fn timing(&self) -> Option<&Timing> {
+        self.timing.as_ref()
+    }
+
+    /// Times each opcode that runs, and the code of each function, across
+    /// every script that the VM runs from now on. Like the opcode counts,
+    /// this leaves out the instructions that run on registers.
+    #[cfg(feature = "profile")]
+    pub fn set_profile(&mut self, profile: bool) {
+        self.profile = profile.then(debug::Profile::new);
+    }
+
+    /// The timings since [`VM::set_profile`] turned profiling on.
+    #[cfg(feature = "profile")]
+    pub fn profile(&self) -> Option<&debug::Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn interpret<S: AsRef<str>>(&mut self, source: S) -> Result<(), InterpretError> {
+        let source = source.as_ref();
+        // not every platform has a clock, so it is only read for timing
+        let start = self.timing.is_some().then(Instant::now);
+        let result = Compiler::compile_with_warnings(source, &mut self.heap, self.compile_options);
+        if let (Some(timing), Some(start)) = (&mut self.timing, start) {
+            timing.compile += start.elapsed();
+        }
+        let (Ok((_, diagnostics)) | Err(diagnostics)) = &result;
+        if self.color && !diagnostics.is_empty() {
+            let mut rendered = vec![];
+            diagnostic::render_all(&mut rendered, diagnostics, source);
+            write!(
+                self.errors,
+                "{}",
+                color::error(String::from_utf8_lossy(&rendered))
+            )
+            .expect("writable");
+        } else {
+            diagnostic::render_all(self.errors.as_mut(), diagnostics, source);
+        }
+
+        let (chunk, _) = result.map_err(|_| InterpretError::CompileError)?;
+        self.run_script(chunk)
+    }
+
+    /// Runs a script that was compiled ahead of time with
+    /// [`bytecode::serialize`], once the loader has verified it.
+    pub fn interpret_chunk_bytes(&mut self, bytes: &[u8]) -> Result<(), InterpretError> {
+        let start = self.timing.is_some().then(Instant::now);
+        let chunk = match bytecode::load(bytes, &mut self.heap) {
+            Ok(chunk) => chunk,
+            Err(error) => {
+                writeln!(self.errors, "Invalid bytecode: {}", error).expect("writable");
+                return Err(InterpretError::LoadError);
+            }
+        };
+        if let (Some(timing), Some(start)) = (&mut self.timing, start) {
+            timing.compile += start.elapsed();
+        }
+        self.run_script(chunk)
+    }
+
+    /// Runs a script that was built as a [`Chunk`] instead of compiled from
+    /// Lox, e.g. by a code generator, once it passes the same checks as
+    /// bytecode that is loaded. Its strings and functions have to be
+    /// allocated on [`VM::heap_mut`], so that they live as long as the VM,
+    /// and it is not run if they are not.
+    pub fn run_chunk(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
+        // before the verifier looks at the constants
+        let owned = if self.heap.owns_constants(&chunk) {
+            Ok(())
+        } else {
+            Err(LoadError::Malformed(
+                "A constant is not on the heap of the VM.",
+            ))
+        };
+        if let Err(error) = owned.and_then(|_| bytecode::verify_script(&chunk)) {
+            writeln!(self.errors, "Invalid bytecode: {}", error).expect("writable");
+            return Err(InterpretError::LoadError);
+        }
+        self.run_script(chunk)
+    }
+
+    /// The same as [`VM::run_chunk`], without its checks, for a host that
+    /// checked the chunk already, like the benchmarks, which time the run
+    /// alone.
+    ///
+    /// # Safety
+    ///
+    /// The constants of `chunk` have to be on the heap of the VM, as
+    /// [`Heap::owns_constants`] checks, and it has to pass
+    /// [`bytecode::verify_script`].
+    pub unsafe fn run_chunk_unchecked(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
+        self.run_script(chunk)
+    }
+
+    /// The names of the globals that are defined, sorted.
+    pub fn global_names(&self) -> Vec<String> {
+        let mut names = self
+            .globals
+            .iter()
+            .map(|(name, _)| name.to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// The objects on the heap and what refers to them, where the roots are
+    /// the globals, the stack, and the functions that have been tiered up.
+    pub fn heap_dump(&self) -> HeapDump {
+        let globals = self.globals.iter().flat_map(|(name, &slot)| {
+            let value = match self.global_values[slot] {
+                Value::Obj(obj) => Some((Retainer::Global(name), obj)),
+                _ => None,
+            };
+            iter::once((Retainer::GlobalName, name)).chain(value)
+        });
+        let stack = self
+            .stack
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, value)| match value {
+                Value::Obj(obj) => Some((Retainer::Stack(slot), *obj)),
+                _ => None,
+            });
+        let tiered = self
+            .tiered
+            .iter()
+            .map(|(&function, &copy)| (Retainer::TieredUp(function), copy));
+
+        HeapDump::new(&self.heap, globals.chain(stack).chain(tiered))
+    }
+
+    /// The runtime error that stopped the last script that the VM ran, with
+    /// the calls that were running at the time. This is what is behind an
+    /// [`InterpretError::RuntimeError`].
+    pub fn last_error(&self) -> Option<&RuntimeErrorReport> {
+        self.last_error.as_ref()
+    }
+
+    /// Hands every instruction that runs on the stack backend to
+    /// `debugger` first.
+    pub fn set_debugger(&mut self, debugger: Box<dyn Debugger>) {
+        self.debugger = Some(debugger);
+    }
+
+    /// The heap that owns the objects of the scripts that the VM runs.
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    fn run_script(&mut self, chunk: Chunk) -> Result<(), InterpretError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("run", backend = ?self.backend).entered();
+        self.last_error = None;
+        if let Some(interrupt) = self.interrupt {
+            interrupt.store(false, Ordering::Relaxed);
+        }
+        self.instructions_left = self.instruction_limit.unwrap_or(0);
+        if let Some(coverage) = &mut self.coverage {
+            coverage.add_chunk(&chunk);
+        }
+        let function = self.heap.alloc_function(ObjFunction {
+            arity: 0,
             chunk,
-            ip: 0,
-            stack: vec![],
+            name: None,
+        });
+
+        // the script is run like a call to a function without arguments
+        self.push_stack(Value::Obj(function));
+        self.call(function, 0)?;
+
+        let (start, allocations) = (
+            self.timing.is_some().then(Instant::now),
+            self.heap.allocations(),
+        );
+        let result = match self.backend {
+            Backend::Stack => self.run(),
+            Backend::Register => self.run_registers(),
+        };
+        if let (Some(timing), Some(start)) = (&mut self.timing, start) {
+            timing.execute += start.elapsed();
+            timing.allocations += self.heap.allocations() - allocations;
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.finish(result == Err(InterpretError::RuntimeError));
+        }
+        if let Some(stack_profile) = &mut self.stack_profile {
+            stack_profile.reset();
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.reset();
+        }
+        if let Some(debugger) = &mut self.debugger {
+            debugger.finished(&result);
+        }
+        #[cfg(feature = "profile")]
+        if let Some(profile) = &mut self.profile {
+            profile.stop();
+        }
+        // there is no collector yet, so the heap only grows
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            allocations = self.heap.allocations() - allocations,
+            objects = self.heap.allocations(),
+            bytes = self.heap.bytes(),
+            "heap"
+        );
+        result
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames
+            .last()
+            .unwrap_or_else(|| panic!("ICE: No function is running"))
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .unwrap_or_else(|| panic!("ICE: No function is running"))
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().function().chunk
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), InterpretError> {
+        if let Value::Obj(obj) = callee
+            && obj.as_function().is_some()
+        {
+            return self.call(obj, arg_count);
+        }
+        if let Value::Obj(obj) = callee
+            && let Some(native) = obj.as_native()
+        {
+            return self.call_native(native, arg_count);
+        }
+
+        self.runtime_error("Can only call functions and classes.");
+        Err(InterpretError::RuntimeError)
+    }
+
+    // runs `native` right away, leaving its result in place of the callee
+    // and the arguments, the way that a function returns
+    fn call_native(&mut self, native: &ObjNative, arg_count: usize) -> Result<(), InterpretError> {
+        if arg_count != native.arity {
+            self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                native.arity, arg_count
+            ));
+            return Err(InterpretError::RuntimeError);
+        }
+
+        let args = self.stack.len() - arg_count;
+        match (native.function)(&self.stack[args..]) {
+            // a native can't allocate, so an object that it returns has to be
+            // one of its arguments, or one that it held on to from this heap
+            Ok(Value::Obj(obj))
+                if !self.stack[args..].contains(&Value::Obj(obj)) && !self.heap.owns(obj) =>
+            {
+                self.runtime_error(format!(
+                    "Native function '{}' returned an object of another VM.",
+                    native.name
+                ));
+                Err(InterpretError::RuntimeError)
+            }
+            Ok(result) => {
+                self.stack.truncate(args - 1);
+                self.push_stack(result);
+                Ok(())
+            }
+            Err(message) => {
+                self.runtime_error(message);
+                Err(InterpretError::RuntimeError)
+            }
+        }
+    }
+
+    fn call(&mut self, function: ObjRef, arg_count: usize) -> Result<(), InterpretError> {
+        let arity = function
+            .as_function()
+            .unwrap_or_else(|| panic!("ICE: Calling a non-function"))
+            .arity;
+        if arg_count != arity {
+            self.runtime_error(format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            ));
+            return Err(InterpretError::RuntimeError);
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            self.runtime_error("Stack overflow.");
+            return Err(InterpretError::RuntimeError);
+        }
+
+        let slots = self.stack.len() - arg_count - 1;
+        let function = match self.backend {
+            Backend::Stack => self.tiered.get(&function).copied().unwrap_or(function),
+            Backend::Register => function,
         };
+        let code = match self.backend {
+            Backend::Stack => None,
+            Backend::Register => Some(self.register_code(function)),
+        };
+        // everything that the call pushes fits from here on, so that
+        // pushing never has to check
+        let height = match &code {
+            Some(code) => code.registers,
+            None => frame_height(function),
+        };
+        if slots + height > self.stack.capacity() {
+            self.runtime_error("Stack overflow.");
+            return Err(InterpretError::RuntimeError);
+        }
+        if let Some(code) = &code {
+            self.stack.resize(slots + code.registers, Value::Nil);
+        }
+
+        self.frames.push(CallFrame {
+            function,
+            ip: 0,
+            slots,
+            code,
+            back_edges: 0,
+        });
+        if let Some(stack_profile) = &mut self.stack_profile {
+            stack_profile.enter(function);
+        }
+        #[cfg(feature = "tracing")]
+        {
+            let name = function.as_function().and_then(ObjFunction::name);
+            let span = tracing::trace_span!("call", function = name.unwrap_or("<script>"));
+            self.call_spans.push(span.entered());
+        }
+        Ok(())
+    }
 
-        vm.run()
+    // translates the function the first time that it is called
+    fn register_code(&mut self, function: ObjRef) -> Rc<RegisterCode> {
+        let code = self.register_code.entry(function).or_insert_with(|| {
+            let callee = function
+                .as_function()
+                .unwrap_or_else(|| panic!("ICE: Calling a non-function"));
+            let code = register::translate(&callee.chunk, callee.arity).unwrap_or_else(|error| {
+                panic!(
+                    "ICE: Cannot translate {:?} to registers: {}",
+                    function, error
+                )
+            });
+            Rc::new(code)
+        });
+        Rc::clone(code)
     }
 
     fn pop_stack(&mut self) -> Value {
-        self.stack.pop().unwrap_or_else(|| {
-            panic!("Stack exhausted");
-        })
+        self.stack.pop()
     }
 
     fn push_stack(&mut self, value: Value) {
         self.stack.push(value);
     }
 
-    fn run(&mut self) -> Result<Option<Value>, InterpretError> {
-        fn read_byte(vm: &mut VM) -> u8 {
-            let instruction = vm.chunk.get_code(vm.ip);
-            vm.ip += 1;
-            instruction
+    fn peek_stack(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    fn concatenate(&mut self) -> Result<(), InterpretError> {
+        let b = self.pop_stack();
+        let a = self.pop_stack();
+        let result = self.concatenate_values(a, b)?;
+        self.push_stack(result);
+        Ok(())
+    }
+
+    fn concatenate_values(&mut self, a: Value, b: Value) -> Result<Value, InterpretError> {
+        let (Value::Obj(a), Value::Obj(b)) = (a, b) else {
+            panic!("ICE: Concatenating non-strings");
+        };
+        self.concat(&[a, b])
+    }
+
+    // a long string that concatenating makes is a rope, which only takes up
+    // its characters once they are looked at, so one that couldn't be
+    // flattened within the memory limit goes over it as soon as it's made
+    fn concat(&mut self, parts: &[ObjRef]) -> Result<Value, InterpretError> {
+        let len = parts.iter().try_fold(0usize, |len, part| {
+            let part = part.as_string().expect("ICE: Concatenating non-strings");
+            len.checked_add(part.len())
+                .filter(|&len| len <= isize::MAX as usize)
+        });
+        let Some(len) = len else {
+            self.runtime_error("String too long.");
+            return Err(InterpretError::RuntimeError);
+        };
+        if self
+            .memory_limit
+            .is_some_and(|limit| self.heap.bytes().saturating_add(len) > limit)
+        {
+            self.runtime_error("Memory limit exceeded.");
+            return Err(InterpretError::RuntimeError);
         }
+        Ok(Value::Obj(self.heap.concat(parts)))
+    }
+
+    // the OP_ADDs that OP_CONCAT_N at `ip` replaced, with the same result
+    // and error: strings are joined into a single new string instead of one
+    // for every part, and numbers are summed left to right
+    fn concatenate_n(&mut self, ip: usize, count: usize) -> Result<(), InterpretError> {
+        let start = self.stack.len() - count;
+        let operands = &self.stack[start..];
 
-        fn read_constant(vm: &mut VM) -> Value {
-            let byte = read_byte(vm);
-            vm.chunk.constants().get(byte as usize)
+        // the values that can be added to each other are all of one type
+        let first = operands[0];
+        let addable = |value: &Value| match first {
+            Value::Number(_) => value.as_number().is_ok(),
+            _ if first.as_string().is_some() => value.as_string().is_some(),
+            _ => first.as_decimal().is_some() && value.as_decimal().is_some(),
+        };
+        if let Some(failed) = operands[1..].iter().position(|value| !addable(value)) {
+            // the byte of the operand for that OP_ADD has its span, if the
+            // count was padded to one for each
+            let next = self.frame().ip;
+            self.frame_mut().ip = (ip + 2 + failed).min(next);
+            self.runtime_error("Operands must be two numbers or two strings.");
+            return Err(InterpretError::RuntimeError);
         }
 
-        loop {
-            if debug::is_debug_trace_execution_enabled() {
-                print!("          ");
-                self.stack.iter().for_each(|value| {
-                    print!("[ {:?} ]", value);
-                });
-                println!();
-                debug::disassemble_instruction(&mut io::stdout(), &self.chunk, self.ip);
+        let result = match first {
+            Value::Number(first) => Value::Number(
+                operands[1..]
+                    .iter()
+                    .filter_map(|value| value.as_number().ok())
+                    .fold(first, |sum, n| sum + n),
+            ),
+            _ if first.as_string().is_some() => {
+                let parts = operands
+                    .iter()
+                    .filter_map(|value| match value {
+                        Value::Obj(obj) => Some(*obj),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                self.concat(&parts)?
             }
+            _ => {
+                let mut decimals = operands.iter().filter_map(Value::as_decimal);
+                let first = decimals.next().expect("ICE: Adding non-decimals").clone();
+                let sum = decimals.fold(first, |sum, n| &sum + n);
+                Value::Obj(self.heap.alloc_decimal(sum))
+            }
+        };
+
+        self.stack.truncate(start);
+        self.push_stack(result);
+        Ok(())
+    }
 
-            let instruction = read_byte(self);
+    // hands the VM to the debugger, paused at `offset` of the function that is
+    // running, and stops the script if the debugger breaks
+    fn debug(
+        &mut self,
+        offset: usize,
+        f: impl FnOnce(&mut dyn Debugger, &Paused<'_>) -> ControlFlow<()>,
+    ) -> Result<(), InterpretError> {
+        // taken out of the VM while it looks at the VM
+        let Some(mut debugger) = self.debugger.take() else {
+            return Ok(());
+        };
+        let frame = self.frame();
+        let flow = f(
+            debugger.as_mut(),
+            &Paused {
+                chunk: &frame.function().chunk,
+                offset,
+                function: frame.function().name(),
+                depth: self.frames.len(),
+                stack: &self.stack,
+                slots: frame.slots,
+                globals: &self.globals,
+                global_values: &self.global_values,
+            },
+        );
+        self.debugger = Some(debugger);
 
-            let instruction: OpCode = instruction.try_into().unwrap_or_else(|_| {
-                panic!("Invalid opcode {}", instruction);
-            });
+        if flow.is_break() {
+            self.reset_stack();
+            return Err(InterpretError::Interrupted);
+        }
+        Ok(())
+    }
+
+    // tells the debugger that the instruction at `offset` set the global in
+    // `slot` from `old`
+    fn debug_global_written(
+        &mut self,
+        offset: usize,
+        slot: usize,
+        old: Option<Value>,
+    ) -> Result<(), InterpretError> {
+        if self.debugger.is_none() {
+            return Ok(());
+        }
+
+        let name = self
+            .globals
+            .iter()
+            .find(|(_, other)| **other == slot)
+            .map(|(name, _)| name.to_string())
+            .unwrap_or_else(|| panic!("ICE: No global in slot {}", slot));
+        let new = self.global_values[slot];
+        self.debug(offset, |debugger, paused| {
+            debugger.global_written(paused, &name, old, new)
+        })
+    }
+
+    /// Defines the global `name` as a native function, which scripts call
+    /// with `arity` arguments.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&[Value]) -> Result<Value, String> + 'static,
+    ) {
+        let native = self.heap.alloc_native(ObjNative {
+            name: name.to_string(),
+            arity,
+            function: Box::new(function),
+        });
+        let name = self.heap.copy_string(name);
+        self.define_global(name, Value::Obj(native));
+    }
+
+    /// The value of the global called `name`, if it is defined, copied out
+    /// of the heap so that it can outlive the VM.
+    pub fn global(&self, name: &str) -> Option<OwnedValue> {
+        self.global_value(name).map(OwnedValue::from)
+    }
+
+    // the value itself, which is only valid for as long as the VM is
+    pub(crate) fn global_value(&self, name: &str) -> Option<Value> {
+        self.globals
+            .iter()
+            .find(|(global, _)| {
+                global
+                    .as_string()
+                    .is_some_and(|global| global.as_str() == name)
+            })
+            .map(|(_, &slot)| self.global_values[slot])
+    }
+
+    /// Defines the global called `name` as `value`, the way that `var` does
+    /// at the top level of a script.
+    ///
+    /// Returns `false` and leaves the globals as they are if `value` is an
+    /// object that is not on the heap of the VM, such as one of another VM.
+    pub fn set_global(&mut self, name: &str, value: Value) -> bool {
+        if let Value::Obj(obj) = value
+            && !self.heap.owns(obj)
+        {
+            return false;
+        }
+        let name = self.heap.copy_string(name);
+        self.define_global(name, value);
+        true
+    }
+
+    /// Hands the arguments of the command line to the scripts, which get
+    /// how many there are from `argc()`, and each one from `arg(i)`, or nil
+    /// past the last one. The count is a decimal if the compile options
+    /// that are set by now say so.
+    pub fn set_args(&mut self, args: &[String]) {
+        let args = args
+            .iter()
+            .map(|arg| Value::Obj(self.heap.copy_string(arg)))
+            .collect::<Vec<_>>();
+        let argc = if self.compile_options.decimal {
+            let argc = Decimal::parse(&args.len().to_string())
+                .unwrap_or_else(|| panic!("ICE: A count is not a decimal"));
+            Value::Obj(self.heap.alloc_decimal(argc))
+        } else {
+            Value::Number(args.len() as f64)
+        };
+        self.define_native("argc", 0, move |_| Ok(argc));
+        self.define_native("arg", 1, move |values| {
+            let index = match values[0].as_decimal() {
+                Some(decimal) => decimal.to_string().parse().ok(),
+                None => values[0].as_number().ok(),
+            };
+            let index: f64 = index.ok_or("Argument index must be a number.")?;
+            // a whole number, without `f64::fract`, which needs `std`
+            let arg = (index >= 0.0 && index == index as usize as f64)
+                .then(|| args.get(index as usize))
+                .flatten();
+            Ok(arg.copied().unwrap_or(Value::Nil))
+        });
+    }
+
+    fn define_global(&mut self, name: ObjRef, value: Value) {
+        match self.globals.get(name) {
+            Some(&slot) => self.global_values[slot] = value,
+            None => {
+                self.globals.insert(name, self.global_values.len());
+                self.global_values.push(value);
+            }
+        }
+    }
+
+    fn global_name(&self, index: u32) -> ObjRef {
+        match self.chunk().constants().get(index as usize) {
+            Value::Obj(name) if name.as_string().is_some() => name,
+            _ => panic!("ICE: Variable name is not a string"),
+        }
+    }
+
+    // the slow path of the superinstructions that add, which are only
+    // emitted in place of an OP_ADD
+    fn add(&mut self) -> Result<(), InterpretError> {
+        match (self.peek_stack(1), self.peek_stack(0)) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.pop_stack();
+                self.pop_stack();
+                self.push_stack(Value::Number(a + b));
+            }
+            (a, b) if a.as_string().is_some() && b.as_string().is_some() => {
+                self.concatenate()?;
+            }
+            (a, b) => match self.decimal_binary(BinaryOp::Add, a, b) {
+                Some(result) => {
+                    self.pop_stack();
+                    self.pop_stack();
+                    self.push_stack(result?);
+                }
+                None => {
+                    self.runtime_error("Operands must be two numbers or two strings.");
+                    return Err(InterpretError::RuntimeError);
+                }
+            },
+        }
+        Ok(())
+    }
+
+    // `a op b` when both are decimals, or `None` if they are not. decimals
+    // are never mixed with other numbers, since a script compiled with them
+    // has no other numbers. dividing by zero is an error, as there is no
+    // infinity to give instead
+    fn decimal_binary(
+        &mut self,
+        op: BinaryOp,
+        a: Value,
+        b: Value,
+    ) -> Option<Result<Value, InterpretError>> {
+        let (a, b) = (a.as_decimal()?, b.as_decimal()?);
+        let result = match op {
+            BinaryOp::Add => a + b,
+            BinaryOp::Subtract => a - b,
+            BinaryOp::Multiply => a * b,
+            BinaryOp::Divide => match a.checked_div(b) {
+                Some(quotient) => quotient,
+                None => {
+                    self.runtime_error("Division by zero.");
+                    return Some(Err(InterpretError::RuntimeError));
+                }
+            },
+            BinaryOp::Equal => return Some(Ok(Value::Bool(a == b))),
+            BinaryOp::Greater => return Some(Ok(Value::Bool(a > b))),
+            BinaryOp::Less => return Some(Ok(Value::Bool(a < b))),
+            BinaryOp::GreaterEqual => return Some(Ok(Value::Bool(a >= b))),
+            BinaryOp::LessEqual => return Some(Ok(Value::Bool(a <= b))),
+        };
+        Some(Ok(Value::Obj(self.heap.alloc_decimal(result))))
+    }
+
+    fn negate(&mut self, value: Value) -> Result<Value, InterpretError> {
+        match (value.as_number(), value.as_decimal()) {
+            (Ok(num), _) => Ok(Value::Number(-num)),
+            (_, Some(decimal)) => Ok(Value::Obj(self.heap.alloc_decimal(-decimal))),
+            (Err(error), None) => {
+                self.runtime_error(error.to_string());
+                Err(InterpretError::RuntimeError)
+            }
+        }
+    }
+
+    fn run(&mut self) -> Result<(), InterpretError> {
+        // nothing that looks at each instruction can be turned on while a
+        // script runs, so the loop only checks once whether any of it is
+        let hooks = self.has_hooks();
+        loop {
+            let ip = self.frame().ip;
+            let (instruction, next) = Instruction::fetch(self.chunk(), ip);
+            self.frame_mut().ip = next;
+            if hooks {
+                self.before_instruction(ip, instruction)?;
+            }
 
             match instruction {
-                OpCode::Return => {
-                    let value = self.pop_stack();
-                    println!("{:?}", value);
-                    return Ok(Some(value));
+                Instruction::Return => {
+                    let result = self.pop_stack();
+                    let frame = self
+                        .frames
+                        .pop()
+                        .unwrap_or_else(|| panic!("ICE: No function is running"));
+                    if let Some(stack_profile) = &mut self.stack_profile {
+                        stack_profile.exit();
+                    }
+                    #[cfg(feature = "tracing")]
+                    self.call_spans.pop();
+
+                    if self.frames.is_empty() {
+                        // the script itself has returned
+                        self.pop_stack();
+                        return Ok(());
+                    }
+
+                    // discard the callee along with its arguments and locals
+                    self.stack.truncate(frame.slots);
+                    self.push_stack(result);
                 }
-                OpCode::Constant => {
-                    let constant = read_constant(self);
+                Instruction::Constant(index) => {
+                    let constant = self.chunk().constants().get(index as usize);
                     self.stack.push(constant);
                 }
-                OpCode::Negate => {
-                    let last = self.stack.last_mut().unwrap_or_else(|| {
-                        panic!("Stack exhausted");
-                    });
-                    match last {
-                        Value::Number(num) => {
-                            *num = -*num;
-                        }
-                        _ => {
-                            self.runtime_error("Operand must be a number.");
-                            return Err(InterpretError::RuntimeError);
-                        }
-                    }
+                Instruction::Negate => {
+                    let result = self.negate(self.peek_stack(0))?;
+                    self.pop_stack();
+                    self.push_stack(result);
+                }
+                Instruction::Add
+                    if matches!(
+                        (
+                            self.peek_stack(0).as_string(),
+                            self.peek_stack(1).as_string()
+                        ),
+                        (Some(_), Some(_))
+                    ) =>
+                {
+                    self.concatenate()?;
                 }
-                OpCode::Add
-                | OpCode::Subtract
-                | OpCode::Multiply
-                | OpCode::Divide
-                | OpCode::Greater
-                | OpCode::Less => {
+                Instruction::Add
+                | Instruction::Subtract
+                | Instruction::Multiply
+                | Instruction::Divide
+                | Instruction::Greater
+                | Instruction::Less
+                | Instruction::GreaterEqual
+                | Instruction::LessEqual => {
                     let b = self.pop_stack();
                     let a = self.pop_stack();
 
                     match (a, b) {
                         (Value::Number(a), Value::Number(b)) => {
+                            let quickened = match instruction {
+                                Instruction::Add => Some(Instruction::AddNumbers),
+                                Instruction::Subtract => Some(Instruction::SubtractNumbers),
+                                Instruction::Less => Some(Instruction::LessNumbers),
+                                _ => None,
+                            };
+                            if let Some(quickened) = quickened {
+                                quickened.quicken(self.chunk(), ip);
+                            }
+
                             let result = match instruction {
-                                OpCode::Add => Value::Number(a + b),
-                                OpCode::Subtract => Value::Number(a - b),
-                                OpCode::Multiply => Value::Number(a * b),
-                                OpCode::Divide => Value::Number(a / b),
-                                OpCode::Greater => Value::Bool(a > b),
-                                OpCode::Less => Value::Bool(a < b),
+                                Instruction::Add => Value::Number(a + b),
+                                Instruction::Subtract => Value::Number(a - b),
+                                Instruction::Multiply => Value::Number(a * b),
+                                Instruction::Divide => Value::Number(a / b),
+                                Instruction::Greater => Value::Bool(a > b),
+                                Instruction::Less => Value::Bool(a < b),
+                                Instruction::GreaterEqual => Value::Bool(a >= b),
+                                Instruction::LessEqual => Value::Bool(a <= b),
                                 _ => unreachable!(),
                             };
 
                             self.push_stack(result);
                         }
-                        _ => {
-                            self.runtime_error("Operands must be numbers.");
-                            return Err(InterpretError::RuntimeError);
-                        }
+                        (a, b) => match self.decimal_binary(binary_op(instruction), a, b) {
+                            Some(result) => self.push_stack(result?),
+                            None => {
+                                if instruction == Instruction::Add {
+                                    self.runtime_error(
+                                        "Operands must be two numbers or two strings.",
+                                    );
+                                } else {
+                                    self.runtime_error("Operands must be numbers.");
+                                }
+                                return Err(InterpretError::RuntimeError);
+                            }
+                        },
                     }
                 }
-                OpCode::Nil => {
+                Instruction::AddNumbers
+                | Instruction::SubtractNumbers
+                | Instruction::LessNumbers => match (self.peek_stack(1), self.peek_stack(0)) {
+                    (Value::Number(a), Value::Number(b)) => {
+                        let result = match instruction {
+                            Instruction::AddNumbers => Value::Number(a + b),
+                            Instruction::SubtractNumbers => Value::Number(a - b),
+                            _ => Value::Bool(a < b),
+                        };
+                        self.pop_stack();
+                        self.pop_stack();
+                        self.push_stack(result);
+                    }
+                    _ => {
+                        // the operands are not numbers this time, so the
+                        // instruction goes back to its generic form, which
+                        // is run instead
+                        let generic = match instruction {
+                            Instruction::AddNumbers => Instruction::Add,
+                            Instruction::SubtractNumbers => Instruction::Subtract,
+                            _ => Instruction::Less,
+                        };
+                        generic.quicken(self.chunk(), ip);
+                        self.frame_mut().ip = ip;
+                    }
+                },
+                Instruction::Nil => {
                     self.push_stack(Value::Nil);
                 }
-                OpCode::True => {
+                Instruction::True => {
                     self.push_stack(Value::Bool(true));
                 }
-                OpCode::False => {
+                Instruction::False => {
                     self.push_stack(Value::Bool(false));
                 }
-                OpCode::Not => {
+                Instruction::Zero => {
+                    self.push_stack(Value::Number(0.0));
+                }
+                Instruction::One => {
+                    self.push_stack(Value::Number(1.0));
+                }
+                Instruction::MinusOne => {
+                    self.push_stack(Value::Number(-1.0));
+                }
+                Instruction::Not => {
                     let last = self.stack.last_mut().unwrap_or_else(|| {
                         panic!("Stack exhausted");
                     });
                     *last = Value::Bool(last.is_falsey());
                 }
-                OpCode::Equal => {
+                Instruction::Equal => {
                     let b = self.pop_stack();
                     let a = self.pop_stack();
 
                     self.push_stack(Value::Bool(a == b));
                 }
+                Instruction::Print => {
+                    let value = self.pop_stack();
+                    self.print(value);
+                }
+                Instruction::Pop => {
+                    self.pop_stack();
+                }
+                Instruction::Dup => {
+                    self.push_stack(self.peek_stack(0));
+                }
+                Instruction::PopN(count) => {
+                    self.stack.truncate(self.stack.len() - count as usize);
+                }
+                Instruction::ConcatN(count) => {
+                    self.concatenate_n(ip, count as usize)?;
+                }
+                Instruction::DefineGlobal(index) => {
+                    let name = self.global_name(index);
+                    let value = self.pop_stack();
+                    let old = self.globals.get(name).map(|&slot| self.global_values[slot]);
+                    self.define_global(name, value);
+                    if self.debugger.is_some() {
+                        let slot = *self.globals.get(name).expect("ICE: Just defined");
+                        self.debug_global_written(ip, slot, old)?;
+                    }
+                }
+                Instruction::GetGlobal(index) => {
+                    let name = self.global_name(index);
+                    match self.globals.get(name) {
+                        Some(&slot) => {
+                            // globals are never undefined again, so the
+                            // instruction can go straight to the slot from
+                            // now on, if the slot fits in place of the name
+                            if let Ok(slot) = u32::try_from(slot) {
+                                Instruction::GetGlobalCached(slot).quicken(self.chunk(), ip);
+                            }
+                            self.push_stack(self.global_values[slot]);
+                        }
+                        None => {
+                            self.runtime_error(format!("Undefined variable '{}'.", name));
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    }
+                }
+                Instruction::GetGlobalCached(slot) => {
+                    self.push_stack(self.global_values[slot as usize]);
+                }
+                Instruction::SetGlobal(index) => {
+                    let name = self.global_name(index);
+                    match self.globals.get(name) {
+                        // assignment is an expression, so the value stays on the stack
+                        Some(&slot) => {
+                            if let Ok(slot) = u32::try_from(slot) {
+                                Instruction::SetGlobalCached(slot).quicken(self.chunk(), ip);
+                            }
+                            let old = self.global_values[slot];
+                            self.global_values[slot] = self.peek_stack(0);
+                            self.debug_global_written(ip, slot, Some(old))?;
+                        }
+                        None => {
+                            self.runtime_error(format!("Undefined variable '{}'.", name));
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    }
+                }
+                Instruction::SetGlobalCached(slot) => {
+                    let old = self.global_values[slot as usize];
+                    self.global_values[slot as usize] = self.peek_stack(0);
+                    self.debug_global_written(ip, slot as usize, Some(old))?;
+                }
+                Instruction::GetLocal(slot) => {
+                    let slot = self.frame().slots + slot as usize;
+                    self.push_stack(self.stack[slot]);
+                }
+                Instruction::SetLocal(slot) => {
+                    let slot = self.frame().slots + slot as usize;
+                    // assignment is an expression, so the value stays on the stack
+                    self.stack[slot] = self.peek_stack(0);
+                }
+                Instruction::JumpIfFalse(offset) => {
+                    if self.peek_stack(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                Instruction::Jump(offset) => {
+                    self.frame_mut().ip += offset as usize;
+                }
+                Instruction::Loop(offset) => {
+                    self.check_interrupt()?;
+                    self.frame_mut().ip -= offset as usize;
+                    self.back_edge(ip);
+                }
+                Instruction::Call(arg_count) => {
+                    self.check_interrupt()?;
+                    let arg_count = arg_count as usize;
+                    self.call_value(self.peek_stack(arg_count), arg_count)?;
+                }
+                Instruction::AddConstant(index) => {
+                    let b = self.chunk().constants().get(index as usize);
+                    match (self.stack.last_mut(), b) {
+                        (Some(Value::Number(a)), Value::Number(b)) => {
+                            *a += b;
+                        }
+                        _ => {
+                            self.push_stack(b);
+                            self.add()?;
+                        }
+                    }
+                }
+                Instruction::GetLocalAddConstant(slot, index) => {
+                    let a = self.stack[self.frame().slots + slot as usize];
+                    let b = self.chunk().constants().get(index as usize);
+                    match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => {
+                            self.push_stack(Value::Number(a + b));
+                        }
+                        _ => {
+                            self.push_stack(a);
+                            self.push_stack(b);
+                            self.add()?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // whether the instruction hooks, the limits or the debugger have to see
+    // each instruction before it runs
+    fn has_hooks(&self) -> bool {
+        let hooks = self.trace.is_some()
+            || self.debugger.is_some()
+            || self.opcode_counts.is_some()
+            || self.stack_profile.is_some()
+            || self.coverage.is_some()
+            || self.timing.is_some()
+            || self.instruction_limit.is_some()
+            || self.memory_limit.is_some();
+        #[cfg(feature = "profile")]
+        let hooks = hooks || self.profile.is_some();
+        hooks
+    }
+
+    // traces, counts and profiles the instruction at `ip` of the stack
+    // backend, and hands it to the debugger, before it runs
+    fn before_instruction(
+        &mut self,
+        ip: usize,
+        instruction: Instruction,
+    ) -> Result<(), InterpretError> {
+        if self.trace.is_some() {
+            let (instruction, _) = debug::disassemble_instruction_to_string(self.chunk(), ip);
+            self.trace_instruction(0, instruction);
+        }
+        if self.debugger.is_some() {
+            self.debug(ip, |debugger, paused| debugger.before_instruction(paused))?;
+        }
+
+        if let Some(counts) = &mut self.opcode_counts {
+            counts.record(instruction.opcode());
+        }
+        if let Some(stack_profile) = &mut self.stack_profile {
+            stack_profile.record();
+        }
+        if let Some(coverage) = &mut self.coverage {
+            let chunk = &self.frames[self.frames.len() - 1].function().chunk;
+            coverage.record(self.frames.len(), chunk.get_line(ip));
+        }
+        if let Some(timing) = &mut self.timing {
+            timing.record(self.stack.len());
+        }
+        self.check_limits()?;
+        #[cfg(feature = "profile")]
+        if let Some(profile) = &mut self.profile {
+            let function = self.frames.last().map(|frame| frame.function);
+            profile.start(
+                instruction.opcode(),
+                function.unwrap_or_else(|| panic!("ICE: No function is running")),
+            );
+        }
+        Ok(())
+    }
+
+    // counts the back edge of the loop at `offset`, which has just been
+    // taken, and tiers up the function once the call has taken enough
+    fn back_edge(&mut self, offset: usize) {
+        let frame = self.frame_mut();
+        frame.back_edges += 1;
+        if frame.back_edges == HOT_LOOP_THRESHOLD
+            && self.tiering
+            && !self.tiered.contains_key(&self.frame().function)
+        {
+            self.tier_up(offset);
+        }
+    }
+
+    fn tier_up(&mut self, offset: usize) {
+        let function = self.frame().function;
+        let (arity, name) = (self.frame().function().arity, self.frame().function().name);
+        let (chunk, start) = tier::optimize(&self.frame().function().chunk, offset);
+        if self.compile_options.print_code || debug::is_debug_print_code_enabled() {
+            let name = self.frame().function().name().unwrap_or("<script>");
+            debug::disassemble_chunk(&mut io::stdout(), &chunk, format!("{} (tiered up)", name));
+        }
+
+        let optimized = self.heap.alloc_function(ObjFunction { arity, chunk, name });
+        self.tiered.insert(function, optimized);
+        self.tiered.insert(optimized, optimized);
+
+        // the call carries on in the optimized code, which has the same
+        // values on the stack at the start of the loop. if the loop cannot
+        // be found, or the optimized code grows the stack past its end, it
+        // is only the later calls that run the optimized code
+        if let Some(start) = start
+            && self.frame().slots + frame_height(optimized) <= self.stack.capacity()
+        {
+            let frame = self.frame_mut();
+            frame.function = optimized;
+            frame.ip = start;
+        }
+    }
+
+    // the same as `run`, with the values in the registers of the frames
+    // instead of on top of the stack
+    fn run_registers(&mut self) -> Result<(), InterpretError> {
+        // the code and the registers of the frame that is running
+        let frame_code = |vm: &Self| {
+            let frame = vm.frame();
+            let code = frame
+                .code
+                .clone()
+                .unwrap_or_else(|| panic!("ICE: No register code"));
+            (code, frame.slots)
+        };
+        let (mut code, mut slots) = frame_code(self);
+
+        loop {
+            let ip = self.frame().ip;
+            if self.trace.is_some() {
+                let mut instruction = Vec::new();
+                register::disassemble_instruction(&mut instruction, &code, self.chunk(), ip);
+                let instruction = String::from_utf8(instruction)
+                    .unwrap_or_else(|_| panic!("ICE: Disassembly is not valid UTF-8"));
+                self.trace_instruction(slots, instruction);
+            }
+
+            let instruction = code.instructions[ip];
+            self.frame_mut().ip = ip + 1;
+            if let Some(stack_profile) = &mut self.stack_profile {
+                stack_profile.record();
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.record(self.frames.len(), code.spans[ip].line);
+            }
+            if let Some(timing) = &mut self.timing {
+                timing.record(self.stack.len());
+            }
+            self.check_interrupt()?;
+            self.check_limits()?;
+            let register = |register: u16| slots + register as usize;
+
+            match instruction {
+                RegisterInstruction::LoadConstant { dst, index } => {
+                    self.stack[register(dst)] = self.chunk().constants().get(index as usize);
+                }
+                RegisterInstruction::LoadNil { dst } => {
+                    self.stack[register(dst)] = Value::Nil;
+                }
+                RegisterInstruction::LoadBool { dst, value } => {
+                    self.stack[register(dst)] = Value::Bool(value);
+                }
+                RegisterInstruction::LoadNumber { dst, value } => {
+                    self.stack[register(dst)] = Value::Number(value as f64);
+                }
+                RegisterInstruction::Move { dst, src } => {
+                    self.stack[register(dst)] = self.stack[register(src)];
+                }
+                RegisterInstruction::Negate { dst, src } => {
+                    self.stack[register(dst)] = self.negate(self.stack[register(src)])?;
+                }
+                RegisterInstruction::Not { dst, src } => {
+                    self.stack[register(dst)] = Value::Bool(self.stack[register(src)].is_falsey());
+                }
+                RegisterInstruction::Binary { op, dst, a, b } => {
+                    let (a, b) = (self.stack[register(a)], self.stack[register(b)]);
+                    let result = match (op, a, b) {
+                        (BinaryOp::Equal, a, b) => Value::Bool(a == b),
+                        (op, Value::Number(a), Value::Number(b)) => match op {
+                            BinaryOp::Add => Value::Number(a + b),
+                            BinaryOp::Subtract => Value::Number(a - b),
+                            BinaryOp::Multiply => Value::Number(a * b),
+                            BinaryOp::Divide => Value::Number(a / b),
+                            BinaryOp::Greater => Value::Bool(a > b),
+                            BinaryOp::Less => Value::Bool(a < b),
+                            BinaryOp::GreaterEqual => Value::Bool(a >= b),
+                            BinaryOp::LessEqual => Value::Bool(a <= b),
+                            BinaryOp::Equal => unreachable!(),
+                        },
+                        (BinaryOp::Add, a, b)
+                            if a.as_string().is_some() && b.as_string().is_some() =>
+                        {
+                            self.concatenate_values(a, b)?
+                        }
+                        (op, a, b) => match self.decimal_binary(op, a, b) {
+                            Some(result) => result?,
+                            None if op == BinaryOp::Add => {
+                                self.runtime_error("Operands must be two numbers or two strings.");
+                                return Err(InterpretError::RuntimeError);
+                            }
+                            None => {
+                                self.runtime_error("Operands must be numbers.");
+                                return Err(InterpretError::RuntimeError);
+                            }
+                        },
+                    };
+                    self.stack[register(dst)] = result;
+                }
+                RegisterInstruction::AddConstant { dst, src, index } => {
+                    let a = self.stack[register(src)];
+                    let b = self.chunk().constants().get(index as usize);
+                    let result = match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                        (a, b) if a.as_string().is_some() && b.as_string().is_some() => {
+                            self.concatenate_values(a, b)?
+                        }
+                        (a, b) => match self.decimal_binary(BinaryOp::Add, a, b) {
+                            Some(result) => result?,
+                            None => {
+                                self.runtime_error("Operands must be two numbers or two strings.");
+                                return Err(InterpretError::RuntimeError);
+                            }
+                        },
+                    };
+                    self.stack[register(dst)] = result;
+                }
+                RegisterInstruction::Print { src } => {
+                    let value = self.stack[register(src)];
+                    self.print(value);
+                }
+                RegisterInstruction::DefineGlobal { name, src } => {
+                    let name = self.global_name(name);
+                    self.define_global(name, self.stack[register(src)]);
+                }
+                RegisterInstruction::GetGlobal { dst, name } => {
+                    let name = self.global_name(name);
+                    match self.globals.get(name) {
+                        Some(&slot) => {
+                            self.stack[register(dst)] = self.global_values[slot];
+                        }
+                        None => {
+                            self.runtime_error(format!("Undefined variable '{}'.", name));
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    }
+                }
+                RegisterInstruction::SetGlobal { name, src } => {
+                    let name = self.global_name(name);
+                    match self.globals.get(name) {
+                        Some(&slot) => {
+                            self.global_values[slot] = self.stack[register(src)];
+                        }
+                        None => {
+                            self.runtime_error(format!("Undefined variable '{}'.", name));
+                            return Err(InterpretError::RuntimeError);
+                        }
+                    }
+                }
+                RegisterInstruction::Jump { target } => {
+                    self.frame_mut().ip = target;
+                }
+                RegisterInstruction::JumpIfFalse { src, target } => {
+                    if self.stack[register(src)].is_falsey() {
+                        self.frame_mut().ip = target;
+                    }
+                }
+                RegisterInstruction::Call { base, arg_count } => {
+                    let base = register(base);
+                    let arg_count = arg_count as usize;
+                    // the registers above the arguments are not in use, so
+                    // the callee's frame can start right at the callee
+                    self.stack.truncate(base + arg_count + 1);
+                    self.call_value(self.stack[base], arg_count)?;
+                    (code, slots) = frame_code(self);
+                    // a native returns right away, into the caller's
+                    // registers that were truncated
+                    self.stack.resize(slots + code.registers, Value::Nil);
+                }
+                RegisterInstruction::Return { src } => {
+                    let result = self.stack[register(src)];
+                    let frame = self
+                        .frames
+                        .pop()
+                        .unwrap_or_else(|| panic!("ICE: No function is running"));
+                    if let Some(stack_profile) = &mut self.stack_profile {
+                        stack_profile.exit();
+                    }
+                    #[cfg(feature = "tracing")]
+                    self.call_spans.pop();
+
+                    if self.frames.is_empty() {
+                        // the script itself has returned
+                        self.stack.clear();
+                        return Ok(());
+                    }
+
+                    // the result replaces the callee in the caller's registers
+                    self.stack[frame.slots] = result;
+                    (code, slots) = frame_code(self);
+                    self.stack.resize(slots + code.registers, Value::Nil);
+                }
             }
         }
     }
 
+    // traces the stack from `slots` on, and then the disassembled
+    // instruction that is about to run
+    fn trace_instruction(&mut self, slots: usize, instruction: String) {
+        let mut entry = String::from("          ");
+        self.stack[slots..].iter().for_each(|value| {
+            entry.push_str(&format!("[ {} ]", value));
+        });
+        entry.push('\n');
+        entry.push_str(&instruction);
+
+        if let Some(trace) = &mut self.trace {
+            trace.record(entry);
+        }
+    }
+
     fn runtime_error<S: AsRef<str>>(&mut self, message: S) {
-        eprintln!("{}", message.as_ref());
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                // the instruction that failed, or the call that is waiting
+                // on the frame above
+                let (line, column) = match &frame.code {
+                    Some(code) => {
+                        let span = code.spans[frame.ip - 1];
+                        (span.line, span.column)
+                    }
+                    None => {
+                        let chunk = &frame.function().chunk;
+                        (chunk.get_line(frame.ip - 1), chunk.get_column(frame.ip - 1))
+                    }
+                };
+                TraceFrame {
+                    function: frame.function().name().map(str::to_string),
+                    line,
+                    column,
+                }
+            })
+            .collect();
 
-        let line = self.chunk.get_line(self.ip - 1);
-        eprintln!("[line {}] in script", line);
+        let error = RuntimeErrorReport {
+            message: message.as_ref().to_string(),
+            trace,
+        };
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            line = error.trace.first().map(|frame| frame.line),
+            "{}",
+            error.message
+        );
+        if self.report_errors {
+            if self.color {
+                writeln!(self.errors, "{}", color::error(&error)).expect("writable");
+            } else {
+                writeln!(self.errors, "{}", error).expect("writable");
+            }
+        }
+        self.last_error = Some(error);
 
         self.reset_stack();
     }
 
+    // stops the script if it has been interrupted since it was last checked.
+    // the stack backend only checks on calls and on the back edges of loops,
+    // since a script can only keep running through one of them
+    fn check_interrupt(&mut self) -> Result<(), InterpretError> {
+        match self.interrupt {
+            Some(interrupt) if interrupt.swap(false, Ordering::Relaxed) => {
+                self.runtime_error("Interrupted.");
+                Err(InterpretError::Interrupted)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // stops the script if it has gone over one of its limits, which are
+    // checked before every instruction
+    fn check_limits(&mut self) -> Result<(), InterpretError> {
+        if self.instruction_limit.is_some() {
+            if self.instructions_left == 0 {
+                self.runtime_error("Instruction limit exceeded.");
+                return Err(InterpretError::RuntimeError);
+            }
+            self.instructions_left -= 1;
+        }
+        if self
+            .memory_limit
+            .is_some_and(|limit| self.heap.bytes() > limit)
+        {
+            self.runtime_error("Memory limit exceeded.");
+            return Err(InterpretError::RuntimeError);
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, value: Value) {
+        if self.color {
+            writeln!(self.output, "{}", color::value(&value)).expect("writable");
+        } else {
+            writeln!(self.output, "{}", value).expect("writable");
+        }
+    }
+
     fn reset_stack(&mut self) {
         self.stack.clear();
+        self.frames.clear();
+        // the spans are exited from the innermost out
+        #[cfg(feature = "tracing")]
+        while self.call_spans.pop().is_some() {}
+    }
+}
+
+// how many values a frame for `function` on the stack backend takes, from
+// its own slot up to as far as its code grows the stack
+fn frame_height(function: ObjRef) -> usize {
+    let function = function
+        .as_function()
+        .unwrap_or_else(|| panic!("ICE: Calling a non-function"));
+    function.arity + 1 + function.chunk.stack_growth() + STACK_SLACK
+}
+
+// the operator of an instruction that takes two operands
+fn binary_op(instruction: Instruction) -> BinaryOp {
+    match instruction {
+        Instruction::Add => BinaryOp::Add,
+        Instruction::Subtract => BinaryOp::Subtract,
+        Instruction::Multiply => BinaryOp::Multiply,
+        Instruction::Divide => BinaryOp::Divide,
+        Instruction::Equal => BinaryOp::Equal,
+        Instruction::Greater => BinaryOp::Greater,
+        Instruction::Less => BinaryOp::Less,
+        Instruction::GreaterEqual => BinaryOp::GreaterEqual,
+        Instruction::LessEqual => BinaryOp::LessEqual,
+        _ => panic!("ICE: {:?} is not a binary operator", instruction),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        builder::{ChunkBuilder, Op},
+        chunk::{OpCode, Span},
+        compiler::OptLevel,
+        object::ObjType,
+        table::hash_string,
+    };
+
+    // the value of the global `name`, if it is defined
+    fn global<W: io::Write>(vm: &VM<W>, name: &str) -> Option<Value> {
+        let name = vm.globals.find_string(name, hash_string(name))?;
+        vm.globals.get(name).map(|&slot| vm.global_values[slot])
+    }
 
     #[test]
     fn test_vm_interpret() {
         // this whole test is just black-box testing
         //
         fn assert_error(source: &str, error: InterpretError) {
-            assert_eq!(VM::interpret(source.to_string()), Err(error));
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(format!("{};", source)), Err(error));
         }
 
         fn assert_success_with_value(source: &str, value: Value) {
-            assert_eq!(VM::interpret(source.to_string()), Ok(Some(value)));
+            // the expression is evaluated into a global, so that we can
+            // inspect its value afterwards
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(format!("var result = {};", source)), Ok(()));
+            assert_eq!(global(&vm, "result"), Some(value), "{}", source);
         }
 
         // test error
@@ -211,16 +1900,17 @@ mod tests {
         assert_success_with_value("2 <= 3", Value::Bool(true));
         assert_success_with_value("3 <= 3", Value::Bool(true));
         assert_success_with_value("4 <= 3", Value::Bool(false));
-        // the book intentionally desugarize `a <= b` to `!(a > b)`. But this
-        // means that `NaN <= 1` will return true, but according to IEEE-754,
-        // this should be false. The book intentionally make this decision to
-        // make implementation simpler
-        assert_success_with_value("(0.0 / 0.0) <= 1", Value::Bool(true));
-        // the book intentionally desugarize `a >= b` to `!(a < b)`. But this
-        // means that `NaN >= 1` will return true, but according to IEEE-754,
-        // this should be false. The book intentionally make this decision to
-        // make implementation simpler
-        assert_success_with_value("(0.0 / 0.0) >= 1", Value::Bool(true));
+        // comparisons with NaN are always false, as IEEE-754 says
+        assert_success_with_value("(0.0 / 0.0) <= 1", Value::Bool(false));
+        assert_success_with_value("(0.0 / 0.0) >= 1", Value::Bool(false));
+        // unless they are compiled the way the book does
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_compile_options(CompileOptions {
+            book_comparisons: true,
+            ..Default::default()
+        });
+        assert_eq!(vm.interpret("var result = (0.0 / 0.0) >= 1;"), Ok(()));
+        assert_eq!(global(&vm, "result"), Some(Value::Bool(true)));
         assert_success_with_value("2 == 2", Value::Bool(true));
         assert_success_with_value("2 != 2", Value::Bool(false));
         assert_success_with_value("3 == 2", Value::Bool(false));
@@ -229,8 +1919,1379 @@ mod tests {
         assert_success_with_value("false == nil", Value::Bool(false));
         assert_success_with_value("nil == nil", Value::Bool(true));
 
+        // test strings
+        let mut heap = Heap::new();
+        assert_success_with_value(
+            r#""hello""#,
+            Value::Obj(heap.alloc_string("hello".to_string())),
+        );
+        assert_success_with_value(
+            r#""hello" + " " + "world""#,
+            Value::Obj(heap.alloc_string("hello world".to_string())),
+        );
+        assert_success_with_value(r#""abc" == "abc""#, Value::Bool(true));
+        assert_success_with_value(r#""abc" != "abd""#, Value::Bool(true));
+        assert_success_with_value(r#""1" == 1"#, Value::Bool(false));
+        // a concatenated string is interned like the constants
+        assert_success_with_value(r#""a" + "b" == "ab""#, Value::Bool(true));
+        // strings can only be concatenated with other strings
+        assert_error(r#""abc" + 1"#, InterpretError::RuntimeError);
+        assert_error(r#"1 + "abc""#, InterpretError::RuntimeError);
+        assert_error(r#"-"abc""#, InterpretError::RuntimeError);
+        assert_error(r#""abc" < "abd""#, InterpretError::RuntimeError);
+
+        // test constant pools larger than a byte operand can address
+        assert_success_with_value(
+            &(0..300)
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(" + "),
+            Value::Number((0..300).sum::<i32>() as f64),
+        );
+
         // test complex expressions
         assert_success_with_value("(-1 + 2) * 3 - -4", Value::Number(7.0));
         assert_success_with_value("!(5 - 4 > 3 * 2 == !nil)", Value::Bool(true));
     }
+
+    #[test]
+    fn test_vm_heap() {
+        let mut vm = VM::new();
+        assert_eq!(vm.heap.iter().count(), 0);
+
+        // the constants, the concatenated result and the script's function
+        // are all tracked
+        vm.interpret(r#""a" + "b";"#).expect("no error");
+        assert_eq!(vm.heap.iter().count(), 4);
+    }
+
+    #[test]
+    fn test_vm_string_builder() {
+        // a loop that appends to a string only adds a rope for each time
+        // around, rather than a copy of everything so far, which would be
+        // hundreds of megabytes here
+        let source =
+            "var s = \"\"; for (var i = 0; i < 10000; i = i + 1) s = s + \"0123456789\" + \"!\";
+                      print s == s + \"\"; var t = s + \"?\"; print t == s; print \"012\" + \"3\";";
+        for backend in [Backend::Stack, Backend::Register] {
+            for level in [OptLevel::O0, OptLevel::O2] {
+                let mut vm = VM::with_output(Vec::new());
+                vm.set_backend(backend);
+                vm.set_compile_options(CompileOptions::default().opt_level(level));
+                assert_eq!(vm.interpret(source), Ok(()));
+                assert!(vm.heap.bytes() < 10_000_000, "{}", vm.heap.bytes());
+                let s = vm.global("s").expect("a global");
+                assert_eq!(s, OwnedValue::String("0123456789!".repeat(10000)));
+                assert_eq!(
+                    String::from_utf8(vm.output).expect("valid utf8"),
+                    "true\nfalse\n0123\n"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_vm_run_chunk() {
+        const SPAN: Span = Span {
+            line: 1,
+            column: 1,
+            start: 0,
+            end: 0,
+        };
+
+        // `var greeting = "hi"; print greeting;`, without the front end
+        let mut vm = VM::with_output(Vec::new());
+        let mut builder = ChunkBuilder::new();
+        let hi = Value::Obj(vm.heap_mut().alloc_string("hi".to_string()));
+        let name = Value::Obj(vm.heap_mut().alloc_string("greeting".to_string()));
+        let hi = builder.add_constant(hi);
+        let name = builder.add_constant(name);
+        let ops = [
+            Op::Constant(hi),
+            Op::Global(OpCode::DefineGlobal, name),
+            Op::Global(OpCode::GetGlobal, name),
+            Op::Simple(OpCode::Print),
+            Op::Simple(OpCode::Nil),
+            Op::Simple(OpCode::Return),
+        ];
+        ops.into_iter()
+            .for_each(|op| builder.emit(op, SPAN).expect("valid"));
+        let chunk = builder.finish().expect("valid");
+
+        assert_eq!(vm.run_chunk(chunk), Ok(()));
+        assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), "hi\n");
+
+        // one that was checked already can skip the checks
+        let mut vm = VM::with_output(Vec::new());
+        let mut builder = ChunkBuilder::new();
+        let hi = Value::Obj(vm.heap_mut().alloc_string("hi".to_string()));
+        let hi = builder.add_constant(hi);
+        for op in [
+            Op::Constant(hi),
+            Op::Simple(OpCode::Print),
+            Op::Simple(OpCode::Nil),
+            Op::Simple(OpCode::Return),
+        ] {
+            builder.emit(op, SPAN).expect("valid");
+        }
+        let chunk = builder.finish().expect("valid");
+        assert!(vm.heap.owns_constants(&chunk));
+        assert_eq!(bytecode::verify_script(&chunk), Ok(()));
+        // SAFETY: checked above
+        assert_eq!(unsafe { vm.run_chunk_unchecked(chunk) }, Ok(()));
+        assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), "hi\n");
+
+        // a chunk that would make the VM misbehave is not run
+        let mut vm = VM::with_output(Vec::new());
+        let mut builder = ChunkBuilder::new();
+        builder.emit(Op::Simple(OpCode::Pop), SPAN).expect("valid");
+        builder
+            .emit(Op::Simple(OpCode::Return), SPAN)
+            .expect("valid");
+        let chunk = builder.finish().expect("valid");
+        assert_eq!(vm.run_chunk(chunk), Err(InterpretError::LoadError));
+        assert!(vm.output.is_empty());
+
+        // and neither is one whose objects could be freed before the VM, as
+        // they are on another heap
+        let mut vm = VM::with_output(Vec::new());
+        let mut builder = ChunkBuilder::new();
+        let hi = builder.add_constant(Value::Obj(Heap::new().copy_string("hi")));
+        let ops = [
+            Op::Constant(hi),
+            Op::Simple(OpCode::Print),
+            Op::Simple(OpCode::Nil),
+            Op::Simple(OpCode::Return),
+        ];
+        ops.into_iter()
+            .for_each(|op| builder.emit(op, SPAN).expect("valid"));
+        let chunk = builder.finish().expect("valid");
+        assert_eq!(vm.run_chunk(chunk), Err(InterpretError::LoadError));
+        assert!(vm.output.is_empty());
+    }
+
+    #[test]
+    fn test_vm_print() {
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(
+            vm.interpret("print 1 + 2; print nil; print \"hi\";"),
+            Ok(())
+        );
+        assert_eq!(
+            String::from_utf8(vm.output).expect("valid utf8"),
+            "3\nnil\nhi\n"
+        );
+
+        // in color, each value is in the color of its type
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_color(true);
+        assert_eq!(vm.interpret("print 1; print \"hi\";"), Ok(()));
+        assert_eq!(
+            String::from_utf8(vm.output).expect("valid utf8"),
+            "\x1b[33m1\x1b[0m\n\x1b[32mhi\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_vm_print_expressions() {
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_compile_options(CompileOptions {
+            print_expressions: true,
+            ..Default::default()
+        });
+        for source in [
+            "1 + 2;",
+            "var a = \"hi\";",
+            // an assignment, and the expressions inside of blocks and
+            // functions, are not printed
+            "a = 3;",
+            "{ a + 1; }",
+            "fun f() { a; }",
+            "f();",
+            "a; print a;",
+        ] {
+            assert_eq!(vm.interpret(source), Ok(()), "{}", source);
+        }
+        assert_eq!(
+            String::from_utf8(vm.output).expect("valid utf8"),
+            "3\nnil\n3\n3\n"
+        );
+    }
+
+    #[test]
+    fn test_vm_results() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            for (first, source) in [
+                (1, "1 + 2;"),
+                (2, "_ * 2; \"a\";"),
+                // an error leaves the results before it
+                (4, "_1 + _2; -nil;"),
+                (5, "print _ + _4;"),
+            ] {
+                vm.set_compile_options(CompileOptions {
+                    print_expressions: true,
+                    first_result: Some(first),
+                    ..Default::default()
+                });
+                let _ = vm.interpret(source);
+            }
+            assert_eq!(vm.global_names(), ["_", "_1", "_2", "_3", "_4"]);
+            assert_eq!(
+                String::from_utf8(vm.output).expect("valid utf8"),
+                "3\n6\na\n9\n18\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_vm_opcode_counts() {
+        let mut vm = VM::with_output(Vec::new());
+        assert!(vm.opcode_counts().is_none());
+
+        vm.set_count_opcodes(true);
+        assert_eq!(vm.interpret("for (var i = 0; i < 3; i = i + 1) {}"), Ok(()));
+        assert_eq!(vm.interpret("print -1;"), Ok(()));
+
+        let counts = vm.opcode_counts().expect("counting");
+        // the comparison is quickened once it has seen numbers
+        assert_eq!(
+            counts.get(OpCode::Less) + counts.get(OpCode::LessNumbers),
+            4
+        );
+        // back to the increment and then back to the condition
+        assert_eq!(counts.get(OpCode::Loop), 6);
+        assert_eq!(counts.get(OpCode::Print), 1);
+        assert_eq!(counts.get(OpCode::Return), 2);
+        assert_eq!(counts.sorted()[0], (OpCode::Constant, 9));
+    }
+
+    #[cfg(feature = "profile")]
+    #[test]
+    fn test_vm_profile() {
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_count_opcodes(true);
+        vm.set_profile(true);
+        let source = "fun f(n) { if (n < 2) return n; return f(n - 1) + f(n - 2); } print f(10);";
+        assert_eq!(vm.interpret(source), Ok(()));
+
+        // every instruction that is counted is timed
+        let counts = vm.opcode_counts().expect("counting");
+        let profile = vm.profile().expect("profiling");
+        for &opcode in OpCode::ALL {
+            assert_eq!(profile.opcode(opcode).0, counts.get(opcode));
+        }
+
+        let mut functions = profile
+            .functions()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        functions.sort();
+        assert_eq!(functions, vec!["<script>", "f"]);
+    }
+
+    #[test]
+    fn test_vm_stack_profile() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            vm.set_stack_profile(true);
+            let source = "fun g() { return 1; } fun f() { g(); g(); } f();";
+            assert_eq!(vm.interpret(source), Ok(()));
+            // the stacks of an error are unwound, and the script that runs
+            // next starts from the top again
+            assert_eq!(
+                vm.interpret("fun h() { -nil; } h();"),
+                Err(InterpretError::RuntimeError)
+            );
+            assert_eq!(vm.interpret("g();"), Ok(()));
+
+            let stacks = vm.stack_profile().expect("counting").folded();
+            let names = stacks
+                .iter()
+                .map(|(stack, _)| stack.as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                names,
+                vec![
+                    "<script>",
+                    "<script>;f",
+                    "<script>;f;g",
+                    "<script>;g",
+                    "<script>;h"
+                ]
+            );
+            // the calls to a function from the same stack are added up
+            let count = |name: &str| {
+                stacks
+                    .iter()
+                    .find(|(stack, _)| stack == name)
+                    .map(|(_, n)| *n)
+            };
+            assert_eq!(count("<script>;f;g"), count("<script>;g").map(|n| n * 2));
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_vm_tracing() {
+        use std::sync::{Arc, Mutex};
+        use tracing::{
+            Event, Id, Metadata, Subscriber,
+            field::{Field, Visit},
+            span::{Attributes, Record},
+        };
+
+        // the spans as they are entered and exited, and the messages of the
+        // events
+        #[derive(Clone, Default)]
+        struct Recorder {
+            log: Arc<Mutex<Vec<String>>>,
+            // the name of each span, by its id
+            spans: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl Visit for Recorder {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                if field.name() == "message" || field.name() == "function" {
+                    let mut log = self.log.lock().expect("not poisoned");
+                    let last = log.last_mut().expect("an entry");
+                    last.push_str(&format!(" {:?}", value));
+                }
+            }
+
+            fn record_str(&mut self, field: &Field, value: &str) {
+                self.record_debug(field, &format_args!("{}", value));
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let name = span.metadata().name().to_string();
+                self.log.lock().expect("not poisoned").push(name.clone());
+                let mut spans = self.spans.lock().expect("not poisoned");
+                spans.push(name);
+                let id = Id::from_u64(spans.len() as u64);
+                drop(spans);
+                span.record(&mut self.clone());
+                id
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let level = event.metadata().level().to_string();
+                self.log.lock().expect("not poisoned").push(level);
+                event.record(&mut self.clone());
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, id: &Id) {
+                let spans = self.spans.lock().expect("not poisoned");
+                let name = &spans[id.into_u64() as usize - 1];
+                let exit = format!("exit {}", name);
+                self.log.lock().expect("not poisoned").push(exit);
+            }
+        }
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_report_errors(false);
+            let source = "fun f() { return 1; }\nf();\n-nil;";
+            assert_eq!(vm.interpret(source), Err(InterpretError::RuntimeError));
+        });
+        // the frames of the error are unwound, after the error
+        let log = recorder.log.lock().expect("not poisoned");
+        assert_eq!(
+            *log,
+            vec![
+                "compile",
+                "DEBUG compiled",
+                "exit compile",
+                "run",
+                "call <script>",
+                "call f",
+                "exit call",
+                "WARN Operand must be a number.",
+                "exit call",
+                "DEBUG heap",
+                "exit run",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vm_coverage() {
+        // a function is declared on the line of its closing brace, where
+        // its implicit return is as well
+        let source =
+            "fun never() {\n  return 1;\n}\nfun twice() {\n  return 2;\n}\ntwice();\ntwice();\n";
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            assert!(vm.coverage().is_none());
+            vm.set_coverage(true);
+            assert_eq!(vm.interpret(source), Ok(()));
+
+            let coverage = vm.coverage().expect("recording");
+            // the implicit return of the script is on its last line, rather
+            // than on the one after the newline at the end
+            let hits = (1..=9).map(|line| coverage.hits(line)).collect::<Vec<_>>();
+            assert_eq!(
+                hits,
+                [
+                    None,
+                    Some(0),
+                    Some(1),
+                    None,
+                    Some(2),
+                    Some(1),
+                    Some(1),
+                    Some(1),
+                    None
+                ],
+                "{:?}",
+                backend
+            );
+        }
+    }
+
+    #[test]
+    fn test_vm_timing() {
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_tiering(false);
+        assert!(vm.timing().is_none());
+        vm.set_timing(true);
+        // the constants are allocated by the compiler, and only the string
+        // that the script adds up while it runs
+        assert_eq!(vm.interpret("{ var a = \"a\"; print a + \"b\"; }"), Ok(()));
+        let timing = vm.timing().expect("timing");
+        assert_eq!(timing.allocations, 1);
+        assert_eq!(timing.instructions, 8);
+        assert_eq!(timing.peak_stack, 4);
+
+        // the scripts are added up
+        assert_eq!(vm.interpret("print 1;"), Ok(()));
+        let timing = vm.timing().expect("timing");
+        assert_eq!(timing.instructions, 12);
+        assert_eq!(timing.peak_stack, 4);
+    }
+
+    #[test]
+    fn test_vm_heap_dump() {
+        let mut vm = VM::with_output(Vec::new());
+        let source = "fun f() { return \"ab\" + \"c\"; } var s = f(); var t = f(); t = nil;";
+        assert_eq!(vm.interpret(source), Ok(()));
+
+        let dump = vm.heap_dump();
+        let find = |preview: &str| {
+            dump.objects
+                .iter()
+                .find(|object| format!("{}", object.obj) == preview)
+                .unwrap_or_else(|| panic!("no {}", preview))
+        };
+        let s = find("s").obj;
+        let f = find("<fn f>").obj;
+
+        // the first result is kept by `s`, the second is interned as the same
+        let abc = find("abc");
+        assert!(abc.reachable);
+        assert_eq!(abc.retainers, vec![Retainer::Global(s)]);
+        assert_eq!(find("ab").retainers, vec![Retainer::Object(f)]);
+        assert!(find("ab").reachable);
+        // the script has finished, and nothing refers to it
+        let script = find("<script>");
+        assert!(!script.reachable);
+        assert!(script.retainers.is_empty());
+
+        assert_eq!(
+            dump.by_type()
+                .iter()
+                .map(|&(obj_type, count, _)| (obj_type, count))
+                .collect::<Vec<_>>(),
+            vec![(ObjType::Function, 2), (ObjType::String, 6)]
+        );
+    }
+
+    #[test]
+    fn test_vm_runtime_error_trace() {
+        let source = "fun a() {\n  return -nil;\n}\nfun b() { return a(); }\nb();";
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            assert_eq!(vm.interpret(source), Err(InterpretError::RuntimeError));
+
+            let error = vm.last_error().expect("failed");
+            let frame = |function: Option<&str>, line, column| TraceFrame {
+                function: function.map(str::to_string),
+                line,
+                column,
+            };
+            assert_eq!(
+                error,
+                &RuntimeErrorReport {
+                    message: "Operand must be a number.".to_string(),
+                    trace: vec![
+                        frame(Some("a"), 2, 10),
+                        frame(Some("b"), 4, 19),
+                        frame(None, 5, 2),
+                    ],
+                }
+            );
+            assert_eq!(
+                error.to_string(),
+                "Operand must be a number.\n[line 2, column 10] in a()\n\
+                 [line 4, column 19] in b()\n[line 5, column 2] in script"
+            );
+
+            // the next script starts without an error
+            assert_eq!(vm.interpret("print 1;"), Ok(()));
+            assert_eq!(vm.last_error(), None);
+        }
+    }
+
+    #[test]
+    fn test_vm_global_written() {
+        use std::{cell::RefCell, rc::Rc};
+
+        // records every write, and stops the script at the one to `stop`
+        struct Writes(Rc<RefCell<Vec<String>>>, &'static str);
+
+        impl Debugger for Writes {
+            fn before_instruction(&mut self, _: &Paused<'_>) -> ControlFlow<()> {
+                ControlFlow::Continue(())
+            }
+
+            fn global_written(
+                &mut self,
+                paused: &Paused<'_>,
+                name: &str,
+                old: Option<Value>,
+                new: Value,
+            ) -> ControlFlow<()> {
+                let old = old.map_or("-".to_string(), |old| old.to_string());
+                let write = format!("{}:{} {} -> {}", paused.line(), name, old, new);
+                self.0.borrow_mut().push(write);
+                if name == self.1 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            }
+        }
+
+        // the assignments in the loop are quickened after the first one
+        let source = "var a = 1;\nfor (var i = 0; i < 3; i = i + 1) {\n  a = a * 2;\n}\nvar a = 0;";
+        let writes = Rc::new(RefCell::new(vec![]));
+        let mut vm = VM::with_output(Vec::new());
+        vm.set_tiering(false);
+        vm.set_debugger(Box::new(Writes(writes.clone(), "")));
+        assert_eq!(vm.interpret(source), Ok(()));
+        assert_eq!(
+            writes.take(),
+            [
+                "1:a - -> 1",
+                "3:a 1 -> 2",
+                "3:a 2 -> 4",
+                "3:a 4 -> 8",
+                "5:a 8 -> 0"
+            ]
+        );
+
+        vm.set_debugger(Box::new(Writes(writes.clone(), "b")));
+        assert_eq!(
+            vm.interpret("var b = 1;\nprint b;"),
+            Err(InterpretError::Interrupted)
+        );
+        assert_eq!(writes.take(), ["1:b - -> 1"]);
+    }
+
+    #[test]
+    fn test_vm_decimal() {
+        fn run(
+            source: &str,
+            options: CompileOptions,
+            backend: Backend,
+        ) -> (Result<(), InterpretError>, String) {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_compile_options(CompileOptions {
+                decimal: true,
+                ..options
+            });
+            vm.set_backend(backend);
+            let result = vm.interpret(source);
+            (result, String::from_utf8(vm.output).expect("valid utf8"))
+        }
+
+        let source = "print 0.1 + 0.2; print 1 / 3; print -(2.50); print 1.10 == 1.1; \
+                      var a = 10; print a - 0.01 >= 9.99; print 0.5 * a + 1 + 2; \
+                      print 100000000000000000001 - 1;";
+        let output = "0.3\n0.33333333333333333333\n-2.5\ntrue\ntrue\n8\n100000000000000000000\n";
+        for options in [
+            CompileOptions::default(),
+            CompileOptions::default().opt_level(OptLevel::O2),
+        ] {
+            for backend in [Backend::Stack, Backend::Register] {
+                assert_eq!(run(source, options, backend), (Ok(()), output.to_string()));
+                assert_eq!(
+                    run("print 1; print 1 / 0;", options, backend),
+                    (Err(InterpretError::RuntimeError), "1\n".to_string())
+                );
+                assert_eq!(
+                    run("print 1 + \"a\";", options, backend).0,
+                    Err(InterpretError::RuntimeError)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_vm_globals() {
+        fn assert_output(source: &str, output: &str) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Ok(()), "{}", source);
+            assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), output);
+        }
+
+        fn assert_error(source: &str, error: InterpretError) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Err(error), "{}", source);
+        }
+
+        assert_output("var a; print a;", "nil\n");
+        assert_output("var a = 1; var b = 2; print a + b;", "3\n");
+        assert_output("var a = 1; a = 2; print a;", "2\n");
+        // redefining a global is allowed
+        assert_output("var a = 1; var a = 2; print a;", "2\n");
+        // assignment is an expression that evaluates to the assigned value
+        assert_output("var a; var b; a = b = 3; print a; print b;", "3\n3\n");
+
+        assert_error("print a;", InterpretError::RuntimeError);
+        assert_error("a = 1;", InterpretError::RuntimeError);
+        assert_error("var a; var b; a + b = 1;", InterpretError::CompileError);
+        assert_error("var a = 1", InterpretError::CompileError);
+        assert_error("print 1", InterpretError::CompileError);
+
+        // globals persist across calls to interpret on the same VM
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(vm.interpret("var a = 1;"), Ok(()));
+        assert_eq!(vm.interpret("print a;"), Ok(()));
+        assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), "1\n");
+
+        // a host can set globals, but not to objects of another heap
+        let mut vm = VM::with_output(Vec::new());
+        let mut other = Heap::new();
+        let foreign = other.copy_string("foreign");
+        let own = vm.heap.copy_string("own");
+        assert!(!vm.set_global("a", Value::Obj(foreign)));
+        assert!(vm.set_global("b", Value::Obj(own)));
+        assert!(vm.set_global("c", Value::Number(1.0)));
+        assert_eq!(vm.interpret("print b; print c;"), Ok(()));
+        assert_eq!(vm.interpret("print a;"), Err(InterpretError::RuntimeError));
+        assert_eq!(
+            String::from_utf8(vm.output).expect("valid utf8"),
+            "own\n1\n"
+        );
+    }
+
+    #[test]
+    fn test_vm_locals() {
+        fn assert_output(source: &str, output: &str) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Ok(()), "{}", source);
+            assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), output);
+        }
+
+        fn assert_error(source: &str, error: InterpretError) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Err(error), "{}", source);
+        }
+
+        assert_output("{ var a = 1; var b = 2; print a + b; }", "3\n");
+        assert_output("{ var a = 1; a = 2; print a; }", "2\n");
+        // inner scopes shadow outer ones, until they end
+        assert_output(
+            "var a = 1; { var a = 2; { var a = 3; print a; } print a; } print a;",
+            "3\n2\n1\n",
+        );
+        // the initializer can refer to a shadowed variable of an outer scope
+        assert_output("var a = 1; { var b = a + 1; print b; }", "2\n");
+        // the locals are popped at the end of their scope
+        assert_output("{ var a = 1; } print 2;", "2\n");
+
+        assert_error("{ var a = a; }", InterpretError::CompileError);
+        assert_error("{ var a = 1; var a = 2; }", InterpretError::CompileError);
+        assert_error("{ var a = 1;", InterpretError::CompileError);
+        // locals are gone once their scope ends
+        assert_error("{ var a = 1; } print a;", InterpretError::RuntimeError);
+    }
+
+    #[test]
+    fn test_vm_control_flow() {
+        fn assert_output(source: &str, output: &str) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Ok(()), "{}", source);
+            assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), output);
+        }
+
+        fn assert_error(source: &str, error: InterpretError) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Err(error), "{}", source);
+        }
+
+        assert_output("if (true) print 1; else print 2;", "1\n");
+        assert_output("if (nil) print 1; else print 2;", "2\n");
+        assert_output("if (false) print 1; print 3;", "3\n");
+        // the condition is popped on both branches
+        assert_output("if (0) {} if (false) {} print 3;", "3\n");
+
+        assert_output("print 1 and 2;", "2\n");
+        assert_output("print nil and 2;", "nil\n");
+        assert_output("print 1 or 2;", "1\n");
+        assert_output("print false or 2;", "2\n");
+        // short-circuiting skips the right operand entirely
+        assert_output(
+            "var a = 1; false and (a = 2); true or (a = 3); print a;",
+            "1\n",
+        );
+
+        assert_output(
+            "var i = 0; while (i < 3) { print i; i = i + 1; }",
+            "0\n1\n2\n",
+        );
+        assert_output("for (var i = 0; i < 3; i = i + 1) print i;", "0\n1\n2\n");
+        assert_output("var i = 0; for (; i < 2;) i = i + 1; print i;", "2\n");
+        // more constants than a one-byte index can address, in a loop whose
+        // jumps go over more code than a one-byte offset can
+        let body = (2..300)
+            .map(|i| format!("print {};", i))
+            .collect::<String>();
+        let printed = (2..300).map(|i| format!("{}\n", i)).collect::<String>();
+        assert_output(
+            &format!("{{ var i = 0; while (i < 2) {{ {} i = i + 1; }} }}", body),
+            &printed.repeat(2),
+        );
+        // the loop variable is scoped to the loop
+        assert_error(
+            "for (var i = 0; i < 1; i = i + 1) {} print i;",
+            InterpretError::RuntimeError,
+        );
+
+        assert_error("if true print 1;", InterpretError::CompileError);
+        assert_error("while (true print 1;", InterpretError::CompileError);
+        assert_error("for (var i = 0; i < 1) {}", InterpretError::CompileError);
+    }
+
+    #[test]
+    fn test_vm_deny_warnings() {
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(vm.interpret("{ var a = 1; }"), Ok(()));
+
+        vm.set_compile_options(CompileOptions {
+            deny_warnings: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            vm.interpret("{ var a = 1; }"),
+            Err(InterpretError::CompileError)
+        );
+        assert_eq!(vm.interpret("{ var _a = 1; }"), Ok(()));
+    }
+
+    #[test]
+    fn test_vm_functions() {
+        fn assert_output(source: &str, output: &str) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Ok(()), "{}", source);
+            assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), output);
+        }
+
+        fn assert_error(source: &str, error: InterpretError) {
+            let mut vm = VM::with_output(Vec::new());
+            assert_eq!(vm.interpret(source), Err(error), "{}", source);
+        }
+
+        assert_output("fun f() { print 1; } f();", "1\n");
+        assert_output("fun f(a, b) { return a + b; } print f(1, 2);", "3\n");
+        // falling off the end of a function returns nil
+        assert_output("fun f() {} print f();", "nil\n");
+        assert_output("fun f() { return; } print f();", "nil\n");
+        assert_output("fun f() {} print f;", "<fn f>\n");
+        assert_output(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 2) + fib(n - 1); } print fib(10);",
+            "55\n",
+        );
+        // locals live in the frame of their own call
+        assert_output(
+            "fun f(n) { var a = n * 2; { var b = a + 1; return b; } } var a = 10; print f(1); print a;",
+            "3\n10\n",
+        );
+        assert_output("{ fun f() { return 2; } print f(); }", "2\n");
+
+        assert_error("fun f(a) {} f();", InterpretError::RuntimeError);
+        assert_error("fun f() {} f(1);", InterpretError::RuntimeError);
+        assert_error("var a = 1; a();", InterpretError::RuntimeError);
+        assert_error(r#""f"();"#, InterpretError::RuntimeError);
+        assert_error("fun f() { f(); } f();", InterpretError::RuntimeError);
+        assert_error("return 1;", InterpretError::CompileError);
+        assert_error("fun f( {}", InterpretError::CompileError);
+
+        // the VM can keep running after a runtime error inside a call
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(
+            vm.interpret("fun f() { return -nil; } f();"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert_eq!(vm.interpret("print 1;"), Ok(()));
+        assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), "1\n");
+
+        // like lines of the REPL, each input can use what the ones before
+        // defined, on either backend
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            for source in [
+                "var s = \"x\";",
+                "fun f(n) { var t = 0; while (t < n) t = t + 1; return t; }",
+                "print f(3000);",
+                "fun g() { return s + \"y\"; }",
+                "print g() == \"xy\";",
+                "var s = 2;",
+                "print f(s);",
+            ] {
+                assert_eq!(vm.interpret(source), Ok(()), "{}", source);
+            }
+            assert_eq!(
+                String::from_utf8(vm.output).expect("valid utf8"),
+                "3000\ntrue\n2\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_vm_natives() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            vm.define_native("twice", 1, |args| match args[0] {
+                Value::Number(n) => Ok(Value::Number(n * 2.0)),
+                _ => Err("Expected a number.".to_string()),
+            });
+            vm.set_args(&["a".to_string(), "b".to_string()]);
+
+            // the result of a native is in place of the call, like the
+            // result of a function, with the locals around it kept
+            assert_eq!(
+                vm.interpret(
+                    "fun f(x) { var y = 1; var z = twice(x) + y; return z + y; } print f(3);
+                     print argc(); print arg(1); print arg(2); print arg(-1); print twice;"
+                ),
+                Ok(())
+            );
+            assert_eq!(
+                String::from_utf8(vm.output).expect("valid utf8"),
+                "8\n2\nb\nnil\nnil\n<native fn>\n"
+            );
+
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            vm.set_args(&[]);
+            for source in ["arg(\"0\");", "arg();", "argc(1);"] {
+                assert_eq!(
+                    vm.interpret(source),
+                    Err(InterpretError::RuntimeError),
+                    "{}",
+                    source
+                );
+            }
+
+            // nor can a native hand out an object of another heap
+            let mut other = Heap::new();
+            let foreign = Value::Obj(other.copy_string("foreign"));
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            vm.set_report_errors(false);
+            vm.define_native("foreign", 0, move |_| Ok(foreign));
+            vm.define_native("same", 1, |args| Ok(args[0]));
+            assert_eq!(vm.interpret("print same(\"own\");"), Ok(()));
+            assert_eq!(
+                vm.interpret("print foreign();"),
+                Err(InterpretError::RuntimeError)
+            );
+            let error = vm.last_error().expect("an error");
+            assert_eq!(
+                error.message,
+                "Native function 'foreign' returned an object of another VM."
+            );
+            assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), "own\n");
+        }
+    }
+
+    #[test]
+    fn test_vm_interrupt() {
+        static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            vm.set_report_errors(false);
+            vm.set_interrupt(&INTERRUPT);
+            vm.define_native("interrupt", 0, |_| {
+                INTERRUPT.store(true, Ordering::Relaxed);
+                Ok(Value::Nil)
+            });
+
+            // an interrupt from before the script started is dropped
+            INTERRUPT.store(true, Ordering::Relaxed);
+            assert_eq!(
+                vm.interpret(
+                    "var i = 0;\nwhile (true) {\n  i = i + 1;\n  if (i == 100) interrupt();\n}"
+                ),
+                Err(InterpretError::Interrupted)
+            );
+            let error = vm.last_error().expect("an error");
+            assert_eq!(error.message, "Interrupted.");
+            assert!(!INTERRUPT.load(Ordering::Relaxed));
+
+            // the VM can run the next script
+            assert_eq!(vm.interpret("print i;"), Ok(()));
+            assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), "100\n");
+        }
+    }
+
+    #[test]
+    fn test_vm_stack_full() {
+        // frames of more than 256 values fill the stack before there are
+        // too many of them, with each call's arguments as the parameters of
+        // the next and temporaries under them
+        let params = (0..250)
+            .map(|i| format!("a{}", i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let nils = vec!["nil"; 250].join(", ");
+        let source = format!(
+            "fun f({0}) {{ return {1}f({0}){2}; }}\nf({3});",
+            params,
+            "nil + (".repeat(20),
+            ")".repeat(20),
+            nils
+        );
+
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            vm.set_report_errors(false);
+            assert_eq!(vm.interpret(&source), Err(InterpretError::RuntimeError));
+            let error = vm.last_error().expect("an error");
+            assert_eq!(error.message, "Stack overflow.");
+            assert!(error.trace.len() < FRAMES_MAX, "{}", error.trace.len());
+
+            // the stack is empty again for the next script
+            assert_eq!(vm.interpret("print 1;"), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_vm_limits() {
+        for backend in [Backend::Stack, Backend::Register] {
+            let mut vm = VM::with_output(Vec::new());
+            vm.set_backend(backend);
+            vm.set_report_errors(false);
+            vm.set_instruction_limit(Some(1000));
+            assert_eq!(
+                vm.interpret("while (true) {}"),
+                Err(InterpretError::RuntimeError)
+            );
+            let error = vm.last_error().expect("an error");
+            assert_eq!(error.message, "Instruction limit exceeded.");
+
+            // the limit is for each script
+            assert_eq!(
+                vm.interpret("for (var i = 0; i < 10; i = i + 1) {}"),
+                Ok(())
+            );
+            assert_eq!(
+                vm.interpret("for (var i = 0; i < 10; i = i + 1) {}"),
+                Ok(())
+            );
+
+            vm.set_instruction_limit(None);
+            vm.set_memory_limit(Some(vm.heap.bytes() + 100_000));
+            assert_eq!(
+                vm.interpret("var s = \"ab\"; while (true) s = s + s;"),
+                Err(InterpretError::RuntimeError)
+            );
+            let error = vm.last_error().expect("an error");
+            assert_eq!(error.message, "Memory limit exceeded.");
+
+            // without a limit, a string is as long as it can be
+            vm.set_memory_limit(None);
+            assert_eq!(
+                vm.interpret("var s = \"ab\"; while (true) s = s + s;"),
+                Err(InterpretError::RuntimeError)
+            );
+            let error = vm.last_error().expect("an error");
+            assert_eq!(error.message, "String too long.");
+        }
+    }
+
+    #[test]
+    fn test_vm_optimized() {
+        // optimizations must not change what a program does
+        fn assert_same_output(source: &str) {
+            let mut plain = VM::with_output(Vec::new());
+            let plain_result = plain.interpret(source);
+
+            for level in [OptLevel::O1, OptLevel::O2] {
+                let mut optimized = VM::with_output(Vec::new());
+                optimized.set_compile_options(CompileOptions::default().opt_level(level));
+                let optimized_result = optimized.interpret(source);
+
+                assert_eq!(plain_result, optimized_result, "{:?}: {}", level, source);
+                assert_eq!(plain.output, optimized.output, "{:?}: {}", level, source);
+                assert_eq!(
+                    plain.last_error, optimized.last_error,
+                    "{:?}: {}",
+                    level, source
+                );
+            }
+        }
+
+        assert_same_output("print 2 * 3 + 4; print !true; print \"a\" + \"b\";");
+        assert_same_output("print 0; print -0; print 1; print -1; print -(-1); print 1 + 1;");
+        assert_same_output("print -(1 - 2) == 1; print (0 / 0) >= 1;");
+        assert_same_output("var a = 1; print a + 2 * 3;");
+        assert_same_output("print -\"a\";");
+        assert_same_output("if (false) print 1; else print 2; while (nil) print 3;");
+        assert_same_output("if (1 > 2) print 1; if (2 > 1) print 2;");
+        assert_same_output("fun f() { return 1; print 2; } print f();");
+        assert_same_output(
+            "fun f(a) { if (true) { return a; } else { return 2; } print 3; } print f(5);",
+        );
+        assert_same_output(
+            "fun f() { for (var i = 0; i < 3; i = i + 1) { if (i == 1) return i; } } print f();",
+        );
+        assert_same_output("var a = 1; print !!(a == 1); print !!a; 1; nil; { var b; b; }");
+        assert_same_output(
+            "var i = 0; while (i < 5) { if (i < 2) { if (i == 0) print 0; else print 1; } else print i; i = i + 1; }",
+        );
+        assert_same_output("var a = 2; print -a; print -2; print -(-a);");
+        assert_same_output(
+            "{ var a = 0; for (var i = 0; i < 3; i = i + 1) a = a + 2; print a + 1; var s = \"a\"; print s + \"b\"; print s + 1; }",
+        );
+        assert_same_output("var a = \"a\"; print a + \"b\"; print a + 1;");
+        assert_same_output("fun f(a) { var b = a; b = 2; b = 3; a = b; return a; } print f(1);");
+        assert_same_output(
+            "fun f(a) { var b = 0; while (a > 0) { b = b + a; a = a - 1; } a = b; return b; } print f(4);",
+        );
+        assert_same_output("fun f(a) { if (a) return 1; else return 2; } print f(nil);");
+        assert_same_output(
+            "var a = 1; { var b = 2; var c = 3; { var d = 4; print b + d; } print c; } print a;",
+        );
+        assert_same_output(
+            "var s = \"a\"; { var t = \"b\"; print s + t + \"c\" + t + s; print 1 + 2 + -0 + 0.5; }",
+        );
+        assert_same_output("{ var a = 1; print a + 2 + \"c\"; }");
+        assert_same_output("{ var a = \"a\"; print a + \"b\" + nil; }");
+        // the error is at the `+` that fails, not the last one of the chain
+        assert_same_output("{ var a = \"a\"; print a + \"b\" + 1 +\n a + \"c\"; }");
+        assert_same_output("{ var a = 1; print a + \"b\" + a + \"c\"; }");
+        assert_same_output(
+            "{ var a = \"a\"; print a + \"b\" + a + a + a + a + \"c\" + a + a; print a + a + a + a + a + a + a + 1; }",
+        );
+    }
+
+    #[test]
+    fn test_vm_registers() {
+        // the register machine must do the same as the stack machine
+        fn assert_same_output(source: &str) {
+            for options in [
+                CompileOptions::default(),
+                CompileOptions::default().opt_level(OptLevel::O2),
+            ] {
+                let mut stack = VM::with_output(Vec::new());
+                stack.set_compile_options(options);
+                let stack_result = stack.interpret(source);
+
+                let mut registers = VM::with_output(Vec::new());
+                registers.set_compile_options(options);
+                registers.set_backend(Backend::Register);
+                let registers_result = registers.interpret(source);
+
+                assert_eq!(stack_result, registers_result, "{}", source);
+                assert_eq!(stack.output, registers.output, "{}", source);
+                assert_eq!(stack.last_error, registers.last_error, "{}", source);
+                assert_eq!(registers.stack.len(), 0, "{}", source);
+            }
+        }
+
+        assert_same_output("print 2 * 3 + 4; print !true; print \"a\" + \"b\"; print 1 == 1;");
+        assert_same_output("print 0; print -0; print 1; print -1; print -(-1); print nil;");
+        assert_same_output("var a = 1; a = a + 2; print a; print b;");
+        assert_same_output("print -\"a\";");
+        assert_same_output("print 1 < \"a\";");
+        assert_same_output("var a = \"a\"; print a + \"b\"; print a + 1;");
+        assert_same_output("{ var a = 1; var b = a; a = 2; print a; print b; b = a; print b; }");
+        assert_same_output("{ var a = 1; a = a; print a; var b = a = 3; print a + b; }");
+        assert_same_output(
+            "var i = 0; while (i < 5) { if (i < 2) { if (i == 0) print 0; else print 1; } else print i; i = i + 1; }",
+        );
+        assert_same_output(
+            "{ var a = 0; for (var i = 0; i < 3; i = i + 1) a = a + 2; print a + 1; }",
+        );
+        assert_same_output("{ var a = \"a\"; print a + \"b\" + a + \"c\"; print a + 1 + 2; }");
+        assert_same_output("{ var a = \"a\"; print a + \"b\" + a + 1 + a; }");
+        assert_same_output("print nil or (false and 1); print 1 and 2;");
+        assert_same_output(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } print fib(15);",
+        );
+        assert_same_output("fun f(a, b) { var c = a; return c - b; } print f(1, 2) * f(3, 1);");
+        assert_same_output("fun f() { return 1; print 2; } print f(); f(1);");
+        assert_same_output("fun f(n) { return f(n); } f(1);");
+        assert_same_output("var f = 1; f();");
+        assert_same_output(&format!(
+            "{{ var a = 0; if (a == 0) {{ {} }} print a; }}",
+            "a = a + 1;".repeat(10000)
+        ));
+    }
+
+    #[test]
+    fn test_vm_quickening() {
+        // the instructions of the function that is named `name`
+        fn instructions<W: io::Write>(vm: &VM<W>, name: &str) -> Vec<Instruction> {
+            let function = vm
+                .heap
+                .iter()
+                .find(|obj| obj.as_function().and_then(ObjFunction::name) == Some(name))
+                .expect("the function exists");
+            let function = function.as_function().expect("a function");
+            let mut instructions = vec![];
+            let mut offset = 0;
+            while let Some((instruction, next)) = Instruction::decode(&function.chunk, offset) {
+                instructions.push(instruction);
+                offset = next;
+            }
+            instructions
+        }
+
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(
+            vm.interpret(
+                "var a = 1; var b; fun f(x, y) { b = x + y; return a - y < b; } print f(1, 2);"
+            ),
+            Ok(())
+        );
+        let f = instructions(&vm, "f");
+        for quickened in [
+            Instruction::AddNumbers,
+            Instruction::SubtractNumbers,
+            Instruction::LessNumbers,
+        ] {
+            assert!(f.contains(&quickened), "{:?}", f);
+        }
+        // the globals have slots in the order that they are defined
+        assert!(f.contains(&Instruction::GetGlobalCached(0)), "{:?}", f);
+        assert!(f.contains(&Instruction::SetGlobalCached(1)), "{:?}", f);
+
+        // the addition goes back to its generic form for strings, and is
+        // quickened again for numbers
+        assert_eq!(
+            vm.interpret("fun g(x) { return x + x; } print g(\"a\"); print g(1);"),
+            Ok(())
+        );
+        assert!(instructions(&vm, "g").contains(&Instruction::AddNumbers));
+        assert_eq!(
+            vm.interpret("print g(\"b\"); print f(\"a\", 1);"),
+            Err(InterpretError::RuntimeError)
+        );
+        assert!(instructions(&vm, "g").contains(&Instruction::Add));
+        assert_eq!(
+            String::from_utf8(vm.output).expect("valid utf8"),
+            "true\naa\n2\nbb\n"
+        );
+    }
+
+    #[test]
+    fn test_vm_many_globals() {
+        // more globals than a byte can index, where the names that are used
+        // again are only constants once
+        let mut source = (0..300)
+            .map(|i| format!("var g{} = {};\n", i, i))
+            .collect::<String>();
+        source.push_str("fun sum() {\n  var total = 0;\n");
+        (0..300).for_each(|i| source.push_str(&format!("  total = total + g{} + g{};\n", i, i)));
+        source.push_str("  return total;\n}\nprint sum();\nprint sum();\n");
+
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(vm.interpret(&source), Ok(()));
+
+        // the slot of `late` doesn't fit in place of its name in `f`, so it
+        // is looked up by name every time
+        assert_eq!(
+            vm.interpret("var late = 1; fun f() { return late; } print f(); print f();"),
+            Ok(())
+        );
+        assert_eq!(
+            String::from_utf8(vm.output).expect("valid utf8"),
+            "89700\n89700\n1\n1\n"
+        );
+    }
+
+    #[test]
+    fn test_vm_tiering() {
+        // tiering up in the middle of a loop must not change what it does
+        fn assert_same_output(source: &str) -> VM<Vec<u8>> {
+            let mut plain = VM::with_output(Vec::new());
+            plain.set_tiering(false);
+            let plain_result = plain.interpret(source);
+            assert!(plain.tiered.is_empty());
+
+            let mut tiered = VM::with_output(Vec::new());
+            let tiered_result = tiered.interpret(source);
+            assert_eq!(plain_result, tiered_result, "{}", source);
+            assert_eq!(plain.output, tiered.output, "{}", source);
+            tiered
+        }
+
+        let vm = assert_same_output(
+            "{ var a = 0; for (var i = 0; i < 5000; i = i + 1) { a = a + 2; } print a; }",
+        );
+        // the script and its optimized copy
+        assert_eq!(vm.tiered.len(), 2);
+
+        assert_same_output(
+            "var n = 0; fun f(k) { var s = 0; while (s < k) s = s + 1; n = n + s; return s; } print f(10); print f(2000); print f(3000); print n;",
+        );
+        assert_same_output(
+            "var i = 0; var s = \"\"; while (i < 1500) { if (i == 1200) s = s + \"x\"; i = i + 1; } print s; print i;",
+        );
+        // a runtime error in the optimized code is reported the same way
+        assert_same_output("var i = 0; while (true) { i = i + 1; if (i > 1001) i = i + nil; }");
+        // the threshold is per call, so a function with a short loop that
+        // is called often is not tiered up
+        let vm = assert_same_output(
+            "fun f() { for (var i = 0; i < 3; i = i + 1) {} } for (var i = 0; i < 400; i = i + 1) f();",
+        );
+        assert!(vm.tiered.is_empty());
+    }
+
+    #[test]
+    fn test_vm_long_jumps() {
+        // bodies whose jump offsets are too large for a two-byte varint
+        fn assert_output(source: &str, expected: &str) {
+            for options in [
+                CompileOptions::default(),
+                CompileOptions::default().opt_level(OptLevel::O2),
+            ] {
+                let mut vm = VM::with_output(Vec::new());
+                vm.set_compile_options(options);
+                assert_eq!(vm.interpret(source), Ok(()));
+                assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), expected);
+            }
+        }
+
+        let body = "a = a + 1;".repeat(8000);
+        assert_output(
+            &format!(
+                "{{ var a = 0; if (a == 0) {{ {} }} else {{ a = -1; }} print a; }}",
+                body
+            ),
+            "8000\n",
+        );
+        assert_output(
+            &format!(
+                "{{ var a = 0; if (a != 0) {{ {} }} else {{ a = -1; }} print a; }}",
+                body
+            ),
+            "-1\n",
+        );
+        assert_output(
+            &format!(
+                "{{ var a = 0; for (var i = 0; i < 2; i = i + 1) {{ {} }} print a; }}",
+                body
+            ),
+            "16000\n",
+        );
+        assert_output(
+            &format!(
+                "var a = nil; print a or (true and (0{}));",
+                " + 1".repeat(20000)
+            ),
+            "20000\n",
+        );
+    }
+
+    #[test]
+    fn test_vm_dup_and_pop_n() {
+        // { var a = 1; var b = 2; print a + a; }, with the copy of `a` made
+        // by an OP_DUP and the locals popped at once
+        let mut chunk = Chunk::new();
+        [
+            Instruction::One,
+            Instruction::Constant(0),
+            Instruction::GetLocal(1),
+            Instruction::Dup,
+            Instruction::Add,
+            Instruction::Print,
+            Instruction::PopN(2),
+            Instruction::Nil,
+            Instruction::Return,
+        ]
+        .iter()
+        .for_each(|instruction| {
+            instruction.encode(
+                &mut chunk,
+                Span {
+                    line: 1,
+                    column: 1,
+                    start: 0,
+                    end: 0,
+                },
+            )
+        });
+        chunk.constants_mut().add(Value::Number(2.0));
+
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(vm.run_script(chunk), Ok(()));
+        assert_eq!(String::from_utf8(vm.output).expect("valid utf8"), "2\n");
+        // the locals were popped before the script returned
+        assert_eq!(vm.stack.len(), 0);
+    }
+
+    #[test]
+    fn test_vm_interpret_chunk_bytes() {
+        let source = "fun f(n) { if (n < 2) return n; return f(n - 1) + f(n - 2); }\nprint f(10); print \"a\" + \"b\";";
+        // the heap owns the functions, so it has to outlive the chunk
+        let mut heap = Heap::new();
+        let chunk =
+            Compiler::compile(source, &mut heap, CompileOptions::default()).expect("no error");
+        let bytes = bytecode::serialize(&chunk);
+
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(vm.interpret_chunk_bytes(&bytes), Ok(()));
+        assert_eq!(
+            String::from_utf8(vm.output).expect("valid utf8"),
+            "55\nab\n"
+        );
+
+        // a constant that is not in the chunk
+        let mut chunk = Compiler::compile("print 1;", &mut Heap::new(), CompileOptions::default())
+            .expect("no error");
+        chunk.set_code(1, 5);
+        let mut vm = VM::with_output(Vec::new());
+        assert_eq!(
+            vm.interpret_chunk_bytes(&bytecode::serialize(&chunk)),
+            Err(InterpretError::LoadError)
+        );
+    }
 }
@@ -0,0 +1,1201 @@
+// Compiles a script into a WebAssembly module, so that it can run without
+// the VM in a browser or any other WebAssembly host.
+//
+// Only numbers, booleans and nil are supported so far, along with control
+// flow and functions that are called by their name. Every value is an i64,
+// NaN-boxed the same way that the book does it: a number is the bits of its
+// f64, and the other values are quiet NaNs with a tag in their lowest bits.
+// The operators are functions in the module that check the types of their
+// operands, so that a runtime error happens where the VM would report one.
+//
+// The module imports from the host:
+//
+//   lox.print(value: i64)                            the `print` statement
+//   lox.runtime_error(ptr: i32, len: i32, line: i32) never returns
+//
+// where the message of a runtime error is UTF-8 in the exported `memory`.
+// It exports `run`, which runs the script.
+
+use std::collections::HashMap;
+
+use crate::{
+    ast::{Expr, Function, Literal, Program, Stmt},
+    diagnostic::{Diagnostic, Severity},
+    scanner::{Token, TokenKind},
+};
+
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+/// The values that are not numbers, as the host sees them.
+pub const NIL: u64 = QNAN | 1;
+pub const FALSE: u64 = QNAN | 2;
+pub const TRUE: u64 = QNAN | 3;
+// what a global holds until it is defined, which no expression can produce
+const UNDEFINED: u64 = QNAN | 4;
+// the NaN that every NaN result is turned into, since the other NaNs that
+// WebAssembly can produce might look like one of the tags
+const CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+const MAGIC: &[u8] = b"\0asm";
+const VERSION: &[u8] = &[1, 0, 0, 0];
+
+// value types
+const I32: u8 = 0x7f;
+const I64: u8 = 0x7e;
+const F64: u8 = 0x7c;
+// the block type of a block without a result
+const EMPTY: u8 = 0x40;
+
+// opcodes
+const UNREACHABLE: u8 = 0x00;
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const ELSE: u8 = 0x05;
+const END: u8 = 0x0b;
+const BR: u8 = 0x0c;
+const BR_IF: u8 = 0x0d;
+const RETURN: u8 = 0x0f;
+const CALL: u8 = 0x10;
+const DROP: u8 = 0x1a;
+const SELECT: u8 = 0x1b;
+const LOCAL_GET: u8 = 0x20;
+const LOCAL_SET: u8 = 0x21;
+const LOCAL_TEE: u8 = 0x22;
+const GLOBAL_GET: u8 = 0x23;
+const GLOBAL_SET: u8 = 0x24;
+const I32_CONST: u8 = 0x41;
+const I64_CONST: u8 = 0x42;
+const I32_EQZ: u8 = 0x45;
+const I64_EQ: u8 = 0x51;
+const I64_NE: u8 = 0x52;
+const F64_EQ: u8 = 0x61;
+const F64_NE: u8 = 0x62;
+const F64_LT: u8 = 0x63;
+const F64_GT: u8 = 0x64;
+const F64_LE: u8 = 0x65;
+const F64_GE: u8 = 0x66;
+const I32_AND: u8 = 0x71;
+const I64_AND: u8 = 0x83;
+const F64_NEG: u8 = 0x9a;
+const F64_ADD: u8 = 0xa0;
+const F64_SUB: u8 = 0xa1;
+const F64_MUL: u8 = 0xa2;
+const F64_DIV: u8 = 0xa3;
+const I64_REINTERPRET_F64: u8 = 0xbd;
+const F64_REINTERPRET_I64: u8 = 0xbf;
+
+// the imported functions come first in the index space
+const PRINT: u32 = 0;
+const RUNTIME_ERROR: u32 = 1;
+const IMPORTS: u32 = 2;
+
+// the functions that every module has, after the imports
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Helper {
+    IsNumber,
+    Box,
+    FromBool,
+    Truthy,
+    Error,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Greater,
+    Less,
+    GreaterEqual,
+    LessEqual,
+    Negate,
+    Equal,
+    Not,
+}
+
+impl Helper {
+    const ALL: &[Helper] = &[
+        Helper::IsNumber,
+        Helper::Box,
+        Helper::FromBool,
+        Helper::Truthy,
+        Helper::Error,
+        Helper::Add,
+        Helper::Subtract,
+        Helper::Multiply,
+        Helper::Divide,
+        Helper::Greater,
+        Helper::Less,
+        Helper::GreaterEqual,
+        Helper::LessEqual,
+        Helper::Negate,
+        Helper::Equal,
+        Helper::Not,
+    ];
+
+    fn index(self) -> u32 {
+        IMPORTS + self as u32
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn write_i64(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // done once the rest is only the sign, which the last byte carries
+        let sign = byte & 0x40 != 0;
+        if (value == 0 && !sign) || (value == -1 && sign) {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+    write_u32(bytes, name.len() as u32);
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+fn write_section(bytes: &mut Vec<u8>, id: u8, count: usize, contents: &[u8]) {
+    let mut section = vec![];
+    write_u32(&mut section, count as u32);
+    section.extend_from_slice(contents);
+
+    bytes.push(id);
+    write_u32(bytes, section.len() as u32);
+    bytes.extend_from_slice(&section);
+}
+
+// the code of a function that is being compiled
+#[derive(Default)]
+struct Body {
+    code: Vec<u8>,
+}
+
+impl Body {
+    fn op(&mut self, op: u8) {
+        self.code.push(op);
+    }
+
+    fn op_u32(&mut self, op: u8, operand: u32) {
+        self.code.push(op);
+        write_u32(&mut self.code, operand);
+    }
+
+    fn i32_const(&mut self, value: i32) {
+        self.code.push(I32_CONST);
+        write_i64(&mut self.code, value as i64);
+    }
+
+    fn i64_const(&mut self, value: u64) {
+        self.code.push(I64_CONST);
+        write_i64(&mut self.code, value as i64);
+    }
+
+    fn call(&mut self, function: u32) {
+        self.op_u32(CALL, function);
+    }
+
+    fn helper(&mut self, helper: Helper) {
+        self.call(helper.index());
+    }
+
+    // the start of a block, loop or if
+    fn begin(&mut self, op: u8, block_type: u8) {
+        self.code.push(op);
+        self.code.push(block_type);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    // a wasm local of the function
+    Local(u32),
+    // a function declared in the function's scope, which is called directly
+    Function { index: u32, arity: usize },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Global {
+    // the wasm global that holds the variable
+    Variable(u32),
+    // the function, and the i32 global that is set once it is declared
+    Function {
+        index: u32,
+        arity: usize,
+        defined: u32,
+    },
+}
+
+// the state of the function that is being compiled, which is set aside
+// while a function nested inside of it is compiled
+struct FunctionState {
+    body: Body,
+    // innermost last, the script's outermost scope is the globals instead
+    scopes: Vec<Vec<(String, Binding)>>,
+    // every local of the function, including the parameters
+    locals: u32,
+    params: u32,
+    // a local for keeping a value aside while an operator looks at it
+    scratch: u32,
+    is_script: bool,
+}
+
+impl FunctionState {
+    fn new(params: u32, is_script: bool) -> Self {
+        Self {
+            body: Body::default(),
+            scopes: vec![vec![]],
+            locals: params + 1,
+            params,
+            scratch: params,
+            is_script,
+        }
+    }
+
+    fn add_local(&mut self) -> u32 {
+        self.locals += 1;
+        self.locals - 1
+    }
+}
+
+// a function of the module, with the types of its parameters and results
+struct CompiledFunction {
+    params: Vec<u8>,
+    results: Vec<u8>,
+    // the locals that are not parameters, which are all i64
+    locals: u32,
+    code: Vec<u8>,
+}
+
+struct Generator {
+    function: FunctionState,
+    globals: HashMap<String, Global>,
+    // the type of every wasm global, in the order of their indices
+    global_types: Vec<(u8, u64)>,
+    // every function of the program, in the order of their indices after the
+    // helpers. the script is the first one
+    functions: Vec<Option<CompiledFunction>>,
+    // the indices are handed out in the order that the declarations are
+    // reached, which is the order of the source
+    next_function: u32,
+    // the messages of the runtime errors, laid out one after another
+    data: Vec<u8>,
+    messages: HashMap<String, (u32, u32)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Compiles `program` into the bytes of a WebAssembly module, or returns an
+/// error for every part of it that the target does not support. The program
+/// is expected to have compiled without errors for the VM.
+pub fn compile(program: &Program) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let mut generator = Generator {
+        function: FunctionState::new(0, true),
+        globals: HashMap::new(),
+        global_types: vec![],
+        functions: vec![],
+        next_function: 0,
+        data: vec![],
+        messages: HashMap::new(),
+        diagnostics: vec![],
+    };
+
+    let script = generator.reserve_function();
+    generator.declare_globals(&program.statements);
+    program
+        .statements
+        .iter()
+        .for_each(|stmt| generator.statement(stmt));
+    generator.finish_function(script, false);
+
+    if generator.diagnostics.is_empty() {
+        Ok(generator.module())
+    } else {
+        Err(generator.diagnostics)
+    }
+}
+
+impl Generator {
+    fn error<S: AsRef<str>>(&mut self, token: &Token, message: S) {
+        self.diagnostics.push(Diagnostic::at_token(
+            Severity::Error,
+            token,
+            message,
+            Some("the wasm target only supports numbers, booleans, nil and functions"),
+        ));
+        // the module is never written, the code only has to be valid to
+        // carry on with the rest of the program
+        self.function.body.op(UNREACHABLE);
+    }
+
+    fn reserve_function(&mut self) -> u32 {
+        self.functions.push(None);
+        self.next_function += 1;
+        IMPORTS + Helper::ALL.len() as u32 + self.next_function - 1
+    }
+
+    // the index that the function declared `ahead` declarations from now
+    // will have
+    fn function_index(&self, ahead: u32) -> u32 {
+        IMPORTS + Helper::ALL.len() as u32 + self.next_function + ahead
+    }
+
+    fn add_global(&mut self, value_type: u8, initial: u64) -> u32 {
+        self.global_types.push((value_type, initial));
+        self.global_types.len() as u32 - 1
+    }
+
+    // the globals are declared before the script runs, since a function can
+    // use a global that is only declared after it
+    fn declare_globals(&mut self, statements: &[Stmt]) {
+        // the functions that are declared before the one that is reached,
+        // including the ones nested inside of earlier functions
+        let mut ahead = 0;
+        for stmt in statements {
+            match stmt {
+                Stmt::Var { name, .. } => match self.globals.get(name.lexeme) {
+                    Some(Global::Variable(_)) => {}
+                    Some(Global::Function { .. }) => {
+                        self.error(name, "A global cannot be both a function and a variable.")
+                    }
+                    None => {
+                        let global = self.add_global(I64, UNDEFINED);
+                        self.globals
+                            .insert(name.lexeme.to_string(), Global::Variable(global));
+                    }
+                },
+                Stmt::Function(function) => {
+                    if self.globals.contains_key(function.name.lexeme) {
+                        self.error(&function.name, "A global function cannot be redefined.");
+                    } else {
+                        let defined = self.add_global(I32, 0);
+                        self.globals.insert(
+                            function.name.lexeme.to_string(),
+                            Global::Function {
+                                index: self.function_index(ahead),
+                                arity: function.params.len(),
+                                defined,
+                            },
+                        );
+                    }
+                    ahead += count_functions(std::slice::from_ref(stmt));
+                }
+                _ => ahead += count_functions(std::slice::from_ref(stmt)),
+            }
+        }
+    }
+
+    fn finish_function(&mut self, index: u32, has_result: bool) {
+        let function = &mut self.function;
+        if has_result {
+            // the implicit return at the end of the function
+            function.body.i64_const(NIL);
+        }
+        function.body.op(END);
+
+        let compiled = CompiledFunction {
+            params: vec![I64; function.params as usize],
+            results: if has_result { vec![I64] } else { vec![] },
+            locals: function.locals - function.params,
+            code: std::mem::take(&mut function.body.code),
+        };
+        let slot = (index - IMPORTS) as usize - Helper::ALL.len();
+        self.functions[slot] = Some(compiled);
+    }
+
+    // where the message is in the memory, adding it if it is not there yet
+    fn message(&mut self, message: &str) -> (u32, u32) {
+        if let Some(&location) = self.messages.get(message) {
+            return location;
+        }
+        let location = (self.data.len() as u32, message.len() as u32);
+        self.data.extend_from_slice(message.as_bytes());
+        self.messages.insert(message.to_string(), location);
+        location
+    }
+
+    fn runtime_error_code(&mut self, body: &mut Body, message: &str, line: usize) {
+        let (ptr, len) = self.message(message);
+        body.i32_const(ptr as i32);
+        body.i32_const(len as i32);
+        body.i32_const(line as i32);
+        body.helper(Helper::Error);
+    }
+
+    // emits a runtime error, after which the code is never reached
+    fn runtime_error(&mut self, message: &str, line: usize) {
+        let mut body = std::mem::take(&mut self.function.body);
+        self.runtime_error_code(&mut body, message, line);
+        body.op(UNREACHABLE);
+        self.function.body = body;
+    }
+
+    fn resolve(&self, name: &str) -> Option<Binding> {
+        self.function
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(local, _)| local == name)
+            .map(|&(_, binding)| binding)
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.function.is_script && self.function.scopes.len() == 1
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Expression { expr, .. } => {
+                self.expression(expr);
+                self.function.body.op(DROP);
+            }
+            Stmt::Print { expr, .. } => {
+                self.expression(expr);
+                self.function.body.call(PRINT);
+            }
+            Stmt::Var {
+                name, initializer, ..
+            } => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.function.body.i64_const(NIL),
+                }
+                if self.is_global_scope() {
+                    if let Some(Global::Variable(global)) = self.globals.get(name.lexeme) {
+                        let global = *global;
+                        self.function.body.op_u32(GLOBAL_SET, global);
+                    } else {
+                        self.function.body.op(DROP);
+                    }
+                } else {
+                    let local = self.function.add_local();
+                    self.function.body.op_u32(LOCAL_SET, local);
+                    self.declare(name.lexeme, Binding::Local(local));
+                }
+            }
+            Stmt::Function(function) => self.function_declaration(function),
+            Stmt::Block { statements, .. } => {
+                self.function.scopes.push(vec![]);
+                statements.iter().for_each(|stmt| self.statement(stmt));
+                self.function.scopes.pop();
+            }
+            Stmt::If {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.condition(condition);
+                self.function.body.begin(IF, EMPTY);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.function.body.op(ELSE);
+                    self.statement(else_branch);
+                }
+                self.function.body.op(END);
+            }
+            Stmt::While {
+                condition, body, ..
+            } => {
+                self.loop_statement(Some(condition), None, body);
+            }
+            Stmt::For {
+                initializer,
+                condition,
+                increment,
+                body,
+                ..
+            } => {
+                self.function.scopes.push(vec![]);
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                self.loop_statement(condition.as_ref(), increment.as_ref(), body);
+                self.function.scopes.pop();
+            }
+            Stmt::Return { value, .. } => {
+                match value {
+                    Some(value) => self.expression(value),
+                    None => self.function.body.i64_const(NIL),
+                }
+                self.function.body.op(RETURN);
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, binding: Binding) {
+        self.function
+            .scopes
+            .last_mut()
+            .unwrap_or_else(|| panic!("ICE: No scope to declare in"))
+            .push((name.to_string(), binding));
+    }
+
+    //   block
+    //     loop
+    //       <condition> br_if 1 (when it is falsey)
+    //       <body> <increment>
+    //       br 0
+    //     end
+    //   end
+    fn loop_statement(&mut self, condition: Option<&Expr>, increment: Option<&Expr>, body: &Stmt) {
+        self.function.body.begin(BLOCK, EMPTY);
+        self.function.body.begin(LOOP, EMPTY);
+        if let Some(condition) = condition {
+            self.condition(condition);
+            self.function.body.op(I32_EQZ);
+            self.function.body.op_u32(BR_IF, 1);
+        }
+        self.statement(body);
+        if let Some(increment) = increment {
+            self.expression(increment);
+            self.function.body.op(DROP);
+        }
+        self.function.body.op_u32(BR, 0);
+        self.function.body.op(END);
+        self.function.body.op(END);
+    }
+
+    // leaves whether `expr` is truthy as an i32
+    fn condition(&mut self, expr: &Expr) {
+        self.expression(expr);
+        self.function.body.helper(Helper::Truthy);
+    }
+
+    fn function_declaration(&mut self, function: &Function) {
+        let index = self.reserve_function();
+        let arity = function.params.len();
+
+        let mut state = FunctionState::new(arity as u32, false);
+        function.params.iter().enumerate().for_each(|(i, param)| {
+            state.scopes[0].push((param.lexeme.to_string(), Binding::Local(i as u32)))
+        });
+
+        let enclosing = std::mem::replace(&mut self.function, state);
+        function.body.iter().for_each(|stmt| self.statement(stmt));
+        self.finish_function(index, true);
+        self.function = enclosing;
+
+        if self.is_global_scope() {
+            if let Some(Global::Function { defined, .. }) = self.globals.get(function.name.lexeme) {
+                let defined = *defined;
+                self.function.body.i32_const(1);
+                self.function.body.op_u32(GLOBAL_SET, defined);
+            }
+        } else {
+            self.declare(function.name.lexeme, Binding::Function { index, arity });
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Literal { value, .. } => {
+                let value = match value {
+                    Literal::Nil => NIL,
+                    Literal::True => TRUE,
+                    Literal::False => FALSE,
+                };
+                self.function.body.i64_const(value);
+            }
+            Expr::Number { value, .. } => {
+                self.function.body.i64_const(value.to_bits());
+            }
+            Expr::String { token, .. } => {
+                self.error(token, "Strings are not supported by the wasm target yet.");
+            }
+            Expr::Variable { name } => self.variable(name),
+            Expr::Assign { name, value, .. } => self.assignment(name, value),
+            Expr::Unary { operator, operand } => {
+                self.expression(operand);
+                match operator.kind {
+                    TokenKind::Minus => {
+                        self.function.body.i32_const(operator.line as i32);
+                        self.function.body.helper(Helper::Negate);
+                    }
+                    TokenKind::Bang => self.function.body.helper(Helper::Not),
+                    _ => panic!("ICE: Unknown unary operator {:?}", operator.kind),
+                }
+            }
+            Expr::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                self.binary_operator(operator);
+            }
+            Expr::Logical {
+                operator,
+                left,
+                right,
+            } => {
+                let scratch = self.function.scratch;
+                self.expression(left);
+                self.function.body.op_u32(LOCAL_TEE, scratch);
+                self.function.body.helper(Helper::Truthy);
+                self.function.body.begin(IF, I64);
+                match operator.kind {
+                    TokenKind::And => {
+                        self.expression(right);
+                        self.function.body.op(ELSE);
+                        self.function.body.op_u32(LOCAL_GET, scratch);
+                    }
+                    _ => {
+                        self.function.body.op_u32(LOCAL_GET, scratch);
+                        self.function.body.op(ELSE);
+                        self.expression(right);
+                    }
+                }
+                self.function.body.op(END);
+            }
+            Expr::Grouping { expr, .. } => self.expression(expr),
+            Expr::Call {
+                callee,
+                paren,
+                arguments,
+                ..
+            } => self.call(callee, paren, arguments),
+        }
+    }
+
+    fn binary_operator(&mut self, operator: &Token) {
+        let helper = match operator.kind {
+            TokenKind::EqualEqual | TokenKind::BangEqual => {
+                self.function.body.helper(Helper::Equal);
+                if operator.kind == TokenKind::BangEqual {
+                    self.function.body.helper(Helper::Not);
+                }
+                return;
+            }
+            TokenKind::Plus => Helper::Add,
+            TokenKind::Minus => Helper::Subtract,
+            TokenKind::Star => Helper::Multiply,
+            TokenKind::Slash => Helper::Divide,
+            TokenKind::Greater => Helper::Greater,
+            TokenKind::Less => Helper::Less,
+            TokenKind::GreaterEqual => Helper::GreaterEqual,
+            TokenKind::LessEqual => Helper::LessEqual,
+            _ => panic!("ICE: Unknown binary operator {:?}", operator.kind),
+        };
+        self.function.body.i32_const(operator.line as i32);
+        self.function.body.helper(helper);
+    }
+
+    fn variable(&mut self, name: &Token) {
+        match self.resolve(name.lexeme) {
+            Some(Binding::Local(local)) => self.function.body.op_u32(LOCAL_GET, local),
+            Some(Binding::Function { .. }) => self.function_value(name),
+            None => match self.globals.get(name.lexeme).copied() {
+                Some(Global::Variable(global)) => {
+                    self.check_defined(global, name);
+                    self.function.body.op_u32(GLOBAL_GET, global);
+                }
+                Some(Global::Function { .. }) => self.function_value(name),
+                None => self.undefined(name),
+            },
+        }
+    }
+
+    fn function_value(&mut self, name: &Token) {
+        self.error(
+            name,
+            "Functions can only be called by their name on the wasm target.",
+        );
+    }
+
+    fn undefined(&mut self, name: &Token) {
+        self.runtime_error(&format!("Undefined variable '{}'.", name.lexeme), name.line);
+    }
+
+    // a global variable can be used before the script has run its declaration
+    fn check_defined(&mut self, global: u32, name: &Token) {
+        let mut body = std::mem::take(&mut self.function.body);
+        body.op_u32(GLOBAL_GET, global);
+        body.i64_const(UNDEFINED);
+        body.op(I64_EQ);
+        body.begin(IF, EMPTY);
+        let message = format!("Undefined variable '{}'.", name.lexeme);
+        self.runtime_error_code(&mut body, &message, name.line);
+        body.op(END);
+        self.function.body = body;
+    }
+
+    fn assignment(&mut self, name: &Token, value: &Expr) {
+        match self.resolve(name.lexeme) {
+            Some(Binding::Local(local)) => {
+                self.expression(value);
+                self.function.body.op_u32(LOCAL_TEE, local);
+            }
+            Some(Binding::Function { .. }) => self.function_value(name),
+            None => match self.globals.get(name.lexeme).copied() {
+                Some(Global::Variable(global)) => {
+                    // the value is evaluated before the variable is checked
+                    let scratch = self.function.scratch;
+                    self.expression(value);
+                    self.function.body.op_u32(LOCAL_SET, scratch);
+                    self.check_defined(global, name);
+                    self.function.body.op_u32(LOCAL_GET, scratch);
+                    self.function.body.op_u32(GLOBAL_SET, global);
+                    self.function.body.op_u32(LOCAL_GET, scratch);
+                }
+                Some(Global::Function { .. }) => self.function_value(name),
+                None => {
+                    self.expression(value);
+                    self.function.body.op(DROP);
+                    self.undefined(name);
+                }
+            },
+        }
+    }
+
+    fn call(&mut self, callee: &Expr, paren: &Token, arguments: &[Expr]) {
+        let Expr::Variable { name } = callee else {
+            self.error(
+                paren,
+                "Only functions that are called by their name are supported by the wasm target.",
+            );
+            return;
+        };
+
+        let (index, arity) = match self.resolve(name.lexeme) {
+            Some(Binding::Function { index, arity }) => (index, arity),
+            Some(Binding::Local(_)) => {
+                self.error(
+                    name,
+                    "Only functions that are called by their name are supported by the wasm target.",
+                );
+                return;
+            }
+            None => match self.globals.get(name.lexeme).copied() {
+                Some(Global::Function {
+                    index,
+                    arity,
+                    defined,
+                }) => {
+                    let mut body = std::mem::take(&mut self.function.body);
+                    body.op_u32(GLOBAL_GET, defined);
+                    body.op(I32_EQZ);
+                    body.begin(IF, EMPTY);
+                    let message = format!("Undefined variable '{}'.", name.lexeme);
+                    self.runtime_error_code(&mut body, &message, name.line);
+                    body.op(END);
+                    self.function.body = body;
+                    (index, arity)
+                }
+                Some(Global::Variable(_)) => {
+                    self.error(
+                        name,
+                        "Only functions that are called by their name are supported by the wasm target.",
+                    );
+                    return;
+                }
+                None => {
+                    self.undefined(name);
+                    return;
+                }
+            },
+        };
+
+        arguments
+            .iter()
+            .for_each(|argument| self.expression(argument));
+        if arguments.len() == arity {
+            self.function.body.call(index);
+        } else {
+            arguments.iter().for_each(|_| self.function.body.op(DROP));
+            self.runtime_error(
+                &format!("Expected {} arguments but got {}.", arity, arguments.len()),
+                paren.line,
+            );
+        }
+    }
+
+    fn helpers(&mut self) -> Vec<CompiledFunction> {
+        Helper::ALL
+            .iter()
+            .map(|&helper| {
+                let mut body = Body::default();
+                let (params, results): (&[u8], &[u8]) = match helper {
+                    Helper::IsNumber => {
+                        body.op_u32(LOCAL_GET, 0);
+                        body.i64_const(QNAN);
+                        body.op(I64_AND);
+                        body.i64_const(QNAN);
+                        body.op(I64_NE);
+                        (&[I64], &[I32])
+                    }
+                    Helper::Box => {
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op(F64_NE);
+                        body.begin(IF, I64);
+                        body.i64_const(CANONICAL_NAN);
+                        body.op(ELSE);
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op(I64_REINTERPRET_F64);
+                        body.op(END);
+                        (&[F64], &[I64])
+                    }
+                    Helper::FromBool => {
+                        body.i64_const(TRUE);
+                        body.i64_const(FALSE);
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op(SELECT);
+                        (&[I32], &[I64])
+                    }
+                    Helper::Truthy => {
+                        body.op_u32(LOCAL_GET, 0);
+                        body.i64_const(NIL);
+                        body.op(I64_NE);
+                        body.op_u32(LOCAL_GET, 0);
+                        body.i64_const(FALSE);
+                        body.op(I64_NE);
+                        body.op(I32_AND);
+                        (&[I64], &[I32])
+                    }
+                    Helper::Error => {
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op_u32(LOCAL_GET, 1);
+                        body.op_u32(LOCAL_GET, 2);
+                        body.call(RUNTIME_ERROR);
+                        body.op(UNREACHABLE);
+                        (&[I32, I32, I32], &[])
+                    }
+                    Helper::Add
+                    | Helper::Subtract
+                    | Helper::Multiply
+                    | Helper::Divide
+                    | Helper::Greater
+                    | Helper::Less
+                    | Helper::GreaterEqual
+                    | Helper::LessEqual => {
+                        let message = if helper == Helper::Add {
+                            "Operands must be two numbers or two strings."
+                        } else {
+                            "Operands must be numbers."
+                        };
+                        body.op_u32(LOCAL_GET, 0);
+                        body.helper(Helper::IsNumber);
+                        body.op_u32(LOCAL_GET, 1);
+                        body.helper(Helper::IsNumber);
+                        body.op(I32_AND);
+                        body.op(I32_EQZ);
+                        body.begin(IF, EMPTY);
+                        let (ptr, len) = self.message(message);
+                        body.i32_const(ptr as i32);
+                        body.i32_const(len as i32);
+                        body.op_u32(LOCAL_GET, 2);
+                        body.helper(Helper::Error);
+                        body.op(END);
+
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op(F64_REINTERPRET_I64);
+                        body.op_u32(LOCAL_GET, 1);
+                        body.op(F64_REINTERPRET_I64);
+                        let (op, result) = match helper {
+                            Helper::Add => (F64_ADD, Helper::Box),
+                            Helper::Subtract => (F64_SUB, Helper::Box),
+                            Helper::Multiply => (F64_MUL, Helper::Box),
+                            Helper::Divide => (F64_DIV, Helper::Box),
+                            Helper::Greater => (F64_GT, Helper::FromBool),
+                            Helper::Less => (F64_LT, Helper::FromBool),
+                            Helper::GreaterEqual => (F64_GE, Helper::FromBool),
+                            _ => (F64_LE, Helper::FromBool),
+                        };
+                        body.op(op);
+                        body.helper(result);
+                        (&[I64, I64, I32], &[I64])
+                    }
+                    Helper::Negate => {
+                        body.op_u32(LOCAL_GET, 0);
+                        body.helper(Helper::IsNumber);
+                        body.op(I32_EQZ);
+                        body.begin(IF, EMPTY);
+                        let (ptr, len) = self.message("Operand must be a number.");
+                        body.i32_const(ptr as i32);
+                        body.i32_const(len as i32);
+                        body.op_u32(LOCAL_GET, 1);
+                        body.helper(Helper::Error);
+                        body.op(END);
+
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op(F64_REINTERPRET_I64);
+                        body.op(F64_NEG);
+                        body.helper(Helper::Box);
+                        (&[I64, I32], &[I64])
+                    }
+                    Helper::Equal => {
+                        // numbers are compared as floats, so that NaN is not
+                        // equal to itself and 0 is equal to -0
+                        body.op_u32(LOCAL_GET, 0);
+                        body.helper(Helper::IsNumber);
+                        body.op_u32(LOCAL_GET, 1);
+                        body.helper(Helper::IsNumber);
+                        body.op(I32_AND);
+                        body.begin(IF, I32);
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op(F64_REINTERPRET_I64);
+                        body.op_u32(LOCAL_GET, 1);
+                        body.op(F64_REINTERPRET_I64);
+                        body.op(F64_EQ);
+                        body.op(ELSE);
+                        body.op_u32(LOCAL_GET, 0);
+                        body.op_u32(LOCAL_GET, 1);
+                        body.op(I64_EQ);
+                        body.op(END);
+                        body.helper(Helper::FromBool);
+                        (&[I64, I64], &[I64])
+                    }
+                    Helper::Not => {
+                        body.op_u32(LOCAL_GET, 0);
+                        body.helper(Helper::Truthy);
+                        body.op(I32_EQZ);
+                        body.helper(Helper::FromBool);
+                        (&[I64], &[I64])
+                    }
+                };
+                body.op(END);
+                CompiledFunction {
+                    params: params.to_vec(),
+                    results: results.to_vec(),
+                    locals: 0,
+                    code: body.code,
+                }
+            })
+            .collect()
+    }
+
+    fn module(&mut self) -> Vec<u8> {
+        let mut bodies = self.helpers();
+        let functions = std::mem::take(&mut self.functions);
+        bodies.extend(functions.into_iter().map(|function| {
+            function.unwrap_or_else(|| panic!("ICE: Function was never compiled"))
+        }));
+
+        // every distinct signature once
+        let mut types: Vec<(Vec<u8>, Vec<u8>)> =
+            vec![(vec![I64], vec![]), (vec![I32, I32, I32], vec![])];
+        let mut type_index = |params: &[u8], results: &[u8]| match types
+            .iter()
+            .position(|(p, r)| p == params && r == results)
+        {
+            Some(index) => index as u32,
+            None => {
+                types.push((params.to_vec(), results.to_vec()));
+                types.len() as u32 - 1
+            }
+        };
+        let function_types = bodies
+            .iter()
+            .map(|function| type_index(&function.params, &function.results))
+            .collect::<Vec<_>>();
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(VERSION);
+
+        let mut section = vec![];
+        types.iter().for_each(|(params, results)| {
+            section.push(0x60);
+            write_u32(&mut section, params.len() as u32);
+            section.extend_from_slice(params);
+            write_u32(&mut section, results.len() as u32);
+            section.extend_from_slice(results);
+        });
+        write_section(&mut bytes, 1, types.len(), &section);
+
+        let mut section = vec![];
+        [("print", 0), ("runtime_error", 1)]
+            .iter()
+            .for_each(|(name, type_index)| {
+                write_name(&mut section, "lox");
+                write_name(&mut section, name);
+                section.push(0x00);
+                write_u32(&mut section, *type_index);
+            });
+        write_section(&mut bytes, 2, 2, &section);
+
+        let mut section = vec![];
+        function_types
+            .iter()
+            .for_each(|&type_index| write_u32(&mut section, type_index));
+        write_section(&mut bytes, 3, function_types.len(), &section);
+
+        // enough pages of 64 KiB for the messages, and at least one
+        let mut section = vec![0x00];
+        write_u32(
+            &mut section,
+            (self.data.len() as u32).div_ceil(0x10000).max(1),
+        );
+        write_section(&mut bytes, 5, 1, &section);
+
+        let mut section = vec![];
+        self.global_types.iter().for_each(|&(value_type, initial)| {
+            // mutable
+            section.extend_from_slice(&[value_type, 0x01]);
+            if value_type == I64 {
+                section.push(I64_CONST);
+                write_i64(&mut section, initial as i64);
+            } else {
+                section.push(I32_CONST);
+                write_i64(&mut section, initial as i64);
+            }
+            section.push(END);
+        });
+        write_section(&mut bytes, 6, self.global_types.len(), &section);
+
+        let mut section = vec![];
+        write_name(&mut section, "memory");
+        section.push(0x02);
+        write_u32(&mut section, 0);
+        write_name(&mut section, "run");
+        section.push(0x00);
+        write_u32(&mut section, IMPORTS + Helper::ALL.len() as u32);
+        write_section(&mut bytes, 7, 2, &section);
+
+        let mut section = vec![];
+        bodies.iter().for_each(|function| {
+            let mut body = vec![];
+            if function.locals == 0 {
+                write_u32(&mut body, 0);
+            } else {
+                write_u32(&mut body, 1);
+                write_u32(&mut body, function.locals);
+                body.push(I64);
+            }
+            body.extend_from_slice(&function.code);
+            write_u32(&mut section, body.len() as u32);
+            section.extend_from_slice(&body);
+        });
+        write_section(&mut bytes, 10, bodies.len(), &section);
+
+        let mut section = vec![0x00, I32_CONST, 0x00, END];
+        write_u32(&mut section, self.data.len() as u32);
+        section.extend_from_slice(&self.data);
+        write_section(&mut bytes, 11, 1, &section);
+
+        bytes
+    }
+}
+
+// how many function declarations there are in `statements`, including the
+// ones nested inside of other functions
+fn count_functions(statements: &[Stmt]) -> u32 {
+    statements
+        .iter()
+        .map(|stmt| match stmt {
+            Stmt::Function(function) => 1 + count_functions(&function.body),
+            Stmt::Block { statements, .. } => count_functions(statements),
+            Stmt::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                count_functions(std::slice::from_ref(then_branch))
+                    + else_branch
+                        .as_ref()
+                        .map_or(0, |stmt| count_functions(std::slice::from_ref(stmt)))
+            }
+            Stmt::While { body, .. } => count_functions(std::slice::from_ref(body)),
+            Stmt::For {
+                initializer, body, ..
+            } => {
+                initializer
+                    .as_ref()
+                    .map_or(0, |stmt| count_functions(std::slice::from_ref(stmt)))
+                    + count_functions(std::slice::from_ref(body))
+            }
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::Parser;
+
+    use super::*;
+
+    fn compile_source(source: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+        let program = Parser::new(source).parse();
+        compile(&program)
+    }
+
+    #[test]
+    fn test_leb128() {
+        let encode_u32 = |value| {
+            let mut bytes = vec![];
+            write_u32(&mut bytes, value);
+            bytes
+        };
+        assert_eq!(encode_u32(0), vec![0x00]);
+        assert_eq!(encode_u32(127), vec![0x7f]);
+        assert_eq!(encode_u32(128), vec![0x80, 0x01]);
+        assert_eq!(encode_u32(624485), vec![0xe5, 0x8e, 0x26]);
+
+        let encode_i64 = |value| {
+            let mut bytes = vec![];
+            write_i64(&mut bytes, value);
+            bytes
+        };
+        assert_eq!(encode_i64(0), vec![0x00]);
+        assert_eq!(encode_i64(63), vec![0x3f]);
+        assert_eq!(encode_i64(64), vec![0xc0, 0x00]);
+        assert_eq!(encode_i64(-1), vec![0x7f]);
+        assert_eq!(encode_i64(-123456), vec![0xc0, 0xbb, 0x78]);
+    }
+
+    #[test]
+    fn test_wasm_compile() {
+        let module = compile_source(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+             var i = 0;
+             while (i < 10) { print fib(i) and !nil; i = i + 1; }
+             for (var j = 0; j < 3; j = j + 1) { fun f(x) { return -x; } print f(j); }",
+        )
+        .expect("supported program");
+        assert_eq!(&module[..4], MAGIC);
+        assert_eq!(&module[4..8], VERSION);
+    }
+
+    #[test]
+    fn test_wasm_unsupported() {
+        let errors = |source: &str| {
+            compile_source(source)
+                .expect_err("unsupported program")
+                .into_iter()
+                .map(|diagnostic| (diagnostic.span.line, diagnostic.message))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            errors("print \"hi\";"),
+            vec![(
+                1,
+                "Strings are not supported by the wasm target yet.".to_string()
+            )]
+        );
+        assert_eq!(
+            errors("fun f() {}\nprint f;"),
+            vec![(
+                2,
+                "Functions can only be called by their name on the wasm target.".to_string()
+            )]
+        );
+        assert_eq!(
+            errors("fun f() {}\nfun f() {}"),
+            vec![(2, "A global function cannot be redefined.".to_string())]
+        );
+    }
+}
@@ -0,0 +1,135 @@
+// Runs each script in tests/fixtures on both backends, and compares what it
+// printed, what it reported and the exit code of `clox` with the files next
+// to it:
+//
+//     name.lox     the script
+//     name.stdout  what it prints, if anything
+//     name.stderr  its diagnostics and runtime error, if it has any
+//     name.status  the exit code, if it isn't 0
+//
+// Running the tests with UPDATE_GOLDEN=1 writes the files from what the
+// scripts do instead, for a new script or one that is meant to change.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use clox::{
+    compiler::Compiler,
+    diagnostic,
+    register::Backend,
+    vm::{InterpretError, VM},
+};
+
+// what running a script did, the way `clox` would show it
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    stdout: String,
+    stderr: String,
+    status: i32,
+}
+
+fn run(source: &str, backend: Backend) -> Outcome {
+    let mut vm = VM::with_output(Vec::new());
+    vm.set_backend(backend);
+    vm.set_report_errors(false);
+
+    // the same as `VM::interpret`, with the diagnostics written here
+    let mut stderr = vec![];
+    let result = Compiler::compile_with_warnings(source, vm.heap_mut(), Default::default());
+    let (Ok((_, diagnostics)) | Err(diagnostics)) = &result;
+    diagnostic::render_all(&mut stderr, diagnostics, source);
+    let result = match result {
+        Ok((chunk, _)) => vm.run_chunk(chunk),
+        Err(_) => Err(InterpretError::CompileError),
+    };
+    let mut stderr = String::from_utf8(stderr).expect("valid utf8");
+    if let Some(error) = vm.last_error() {
+        stderr.push_str(&format!("{}\n", error));
+    }
+
+    Outcome {
+        stdout: String::from_utf8(vm.output().clone()).expect("valid utf8"),
+        stderr,
+        status: result.map_or_else(|error| error.exit_code(), |_| 0),
+    }
+}
+
+// the file next to `script` with `extension`, or what it is when it is left
+// out
+fn read_expected(script: &Path, extension: &str, default: &str) -> String {
+    fs::read_to_string(script.with_extension(extension)).unwrap_or_else(|_| default.to_string())
+}
+
+fn write_expected(script: &Path, outcome: &Outcome) {
+    let files = [
+        ("stdout", outcome.stdout.clone(), !outcome.stdout.is_empty()),
+        ("stderr", outcome.stderr.clone(), !outcome.stderr.is_empty()),
+        (
+            "status",
+            format!("{}\n", outcome.status),
+            outcome.status != 0,
+        ),
+    ];
+    for (extension, contents, needed) in files {
+        let path = script.with_extension(extension);
+        if needed {
+            fs::write(&path, contents).expect("writable");
+        } else if path.exists() {
+            fs::remove_file(&path).expect("removable");
+        }
+    }
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut scripts = fs::read_dir(dir)
+        .expect("the fixtures directory")
+        .map(|entry| entry.expect("readable").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect::<Vec<_>>();
+    scripts.sort();
+    scripts
+}
+
+#[test]
+fn test_golden() {
+    let update = env::var_os("UPDATE_GOLDEN").is_some();
+    let scripts = scripts();
+    assert!(!scripts.is_empty());
+
+    let mut failures = vec![];
+    for script in &scripts {
+        let source = fs::read_to_string(script).expect("readable");
+        let outcome = run(&source, Backend::Stack);
+        if update {
+            write_expected(script, &outcome);
+            continue;
+        }
+
+        let expected = Outcome {
+            stdout: read_expected(script, "stdout", ""),
+            stderr: read_expected(script, "stderr", ""),
+            status: read_expected(script, "status", "0")
+                .trim()
+                .parse()
+                .expect("an exit code"),
+        };
+        for (backend, outcome) in [
+            ("stack", outcome),
+            ("register", run(&source, Backend::Register)),
+        ] {
+            if outcome != expected {
+                failures.push(format!(
+                    "{} on the {} VM:\nexpected {:#?}\nbut got {:#?}",
+                    script.display(),
+                    backend,
+                    expected,
+                    outcome
+                ));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n\n"));
+}
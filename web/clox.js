@@ -0,0 +1,44 @@
+// Runs Lox in the browser with the interpreter built for WebAssembly, see
+// src/playground.rs for how to build it.
+//
+//   const clox = await load("clox.wasm", { print: (line) => show(line) });
+//   const { output, error } = clox.interpret('print "hi";');
+//
+// `print` is called with each line as the script prints it, and `output`
+// has all of them once it is done. `error` is what it reported if it
+// failed, or null.
+
+export async function load(url, { print = () => {} } = {}) {
+  const decoder = new TextDecoder();
+  let memory;
+  let onPrint = print;
+  let errors = "";
+  const text = (ptr, len) => decoder.decode(new Uint8Array(memory.buffer, ptr, len));
+
+  const { instance } = await WebAssembly.instantiateStreaming(fetch(url), {
+    env: {
+      clox_print: (ptr, len) => onPrint(text(ptr, len)),
+      clox_error: (ptr, len) => {
+        errors += text(ptr, len);
+      },
+    },
+  });
+  memory = instance.exports.memory;
+
+  return {
+    interpret(source) {
+      const bytes = new TextEncoder().encode(source);
+      const ptr = instance.exports.clox_alloc(bytes.length);
+      new Uint8Array(memory.buffer, ptr, bytes.length).set(bytes);
+
+      let output = "";
+      errors = "";
+      onPrint = (line) => {
+        output += line;
+        print(line);
+      };
+      const status = instance.exports.clox_interpret(ptr, bytes.length);
+      return { output, error: status === 0 ? null : errors };
+    },
+  };
+}